@@ -0,0 +1,144 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+//! Baselines for the data manager's hot paths: mapping cutouts to cuboids,
+//! cached reads and writes, and blosc compression of the responses.  The
+//! data is synthetic, and written to a temporary directory.
+//!
+//! Run with `cargo +nightly bench --bench data_manager`.
+#![feature(test)]
+
+extern crate test;
+
+use bossphorus::data_manager::{
+    get_cuboids_and_indices, ChunkedFileDataManager, DataManager, Vector3,
+};
+use bossphorus::upload::decompress_voxels;
+use ndarray::{Array, Array3};
+use test::Bencher;
+
+const URI: &str = "bossdb://col/exp/chan";
+
+/// The default cuboid size.
+const CUBOID_SIZE: Vector3 = Vector3 {
+    x: 512,
+    y: 512,
+    z: 16,
+};
+const ORIGIN: Vector3 = Vector3 { x: 0, y: 0, z: 0 };
+
+/// Voxels that don't all compress alike, in ZYX order.
+fn voxels(shape: (usize, usize, usize)) -> Array3<u8> {
+    Array::from_shape_fn(shape, |(z, y, x)| ((x * 7 + y * 3 + z) % 251) as u8)
+}
+
+/// A 2x2x2 block of cuboids.
+fn block() -> Array3<u8> {
+    voxels((32, 1024, 1024))
+}
+
+/// A single cuboid.
+fn cuboid() -> Vec<u8> {
+    voxels((16, 512, 512)).into_raw_vec()
+}
+
+fn destination(data: &Array3<u8>) -> Vector3 {
+    let (z, y, x) = data.dim();
+    Vector3 {
+        x: x as u64,
+        y: y as u64,
+        z: z as u64,
+    }
+}
+
+fn bench_cuboids_and_indices(b: &mut Bencher, destination: Vector3) {
+    // Off the cuboid grid, so every cuboid on the edge is partial:
+    let origin = Vector3 { x: 5, y: 5, z: 5 };
+    b.iter(|| get_cuboids_and_indices(origin, destination, CUBOID_SIZE));
+}
+
+#[bench]
+fn cuboids_and_indices_small(b: &mut Bencher) {
+    bench_cuboids_and_indices(
+        b,
+        Vector3 {
+            x: 1024,
+            y: 1024,
+            z: 32,
+        },
+    );
+}
+
+#[bench]
+fn cuboids_and_indices_large(b: &mut Bencher) {
+    // 1024 cuboids:
+    bench_cuboids_and_indices(
+        b,
+        Vector3 {
+            x: 8192,
+            y: 8192,
+            z: 64,
+        },
+    );
+}
+
+#[bench]
+fn get_data_cached(b: &mut Bencher) {
+    let dir = tempfile::tempdir().unwrap();
+    let fm =
+        ChunkedFileDataManager::new(dir.path().to_str().unwrap().to_string(), CUBOID_SIZE, false);
+    let data = block();
+    let destination = destination(&data);
+    fm.put_data(URI.to_string(), 0, ORIGIN, data.clone());
+    b.bytes = data.len() as u64;
+    b.iter(|| fm.get_data(URI.to_string(), 0, ORIGIN, destination));
+}
+
+#[bench]
+fn put_get_round_trip(b: &mut Bencher) {
+    let dir = tempfile::tempdir().unwrap();
+    let fm =
+        ChunkedFileDataManager::new(dir.path().to_str().unwrap().to_string(), CUBOID_SIZE, false);
+    let data = block();
+    let destination = destination(&data);
+    b.bytes = data.len() as u64 * 2;
+    b.iter(|| {
+        fm.put_data(URI.to_string(), 0, ORIGIN, data.clone());
+        fm.get_data(URI.to_string(), 0, ORIGIN, destination)
+    });
+}
+
+#[bench]
+fn blosc_compress_cuboid(b: &mut Bencher) {
+    let voxels = cuboid();
+    let ctx = blosc::Context::new();
+    b.bytes = voxels.len() as u64;
+    b.iter(|| {
+        let compressed: blosc::Buffer<u8> = ctx.compress(&voxels[..]);
+        compressed
+    });
+}
+
+#[bench]
+fn blosc_decompress_cuboid(b: &mut Bencher) {
+    let voxels = cuboid();
+    let compressed: blosc::Buffer<u8> = blosc::Context::new().compress(&voxels[..]);
+    let compressed: Vec<u8> = compressed.into();
+    b.bytes = voxels.len() as u64;
+    b.iter(|| decompress_voxels(&compressed, voxels.len() as u64).unwrap());
+}
@@ -0,0 +1,125 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+//! Sequential cuboid reads with and without a read-ahead buffer and
+//! kernel hints.  Each iteration first drops the cuboids from the page
+//! cache (on Linux), so reads go to the disk; the hints matter most on
+//! spinning disks, and little on SSDs or tmpfs.
+//!
+//! Run with `cargo +nightly bench --bench read`, with `TMPDIR` on the disk
+//! to measure.
+#![feature(test)]
+
+extern crate test;
+
+use bossphorus::cuboid_file::{
+    encode, read_file, read_region_with, ReadAdvice, ReadStrategy, CURRENT_VERSION,
+};
+use bossphorus::data_manager::Vector3;
+use std::fs;
+use std::path::{Path, PathBuf};
+use test::Bencher;
+
+const CUBOID_SIZE: Vector3 = Vector3 {
+    x: 512,
+    y: 512,
+    z: 16,
+};
+const NUM_CUBOIDS: usize = 8;
+
+/// Write the cuboids to read.
+fn cuboids(dir: &Path) -> Vec<PathBuf> {
+    let voxels = vec![7; 512 * 512 * 16];
+    (0..NUM_CUBOIDS)
+        .map(|i| {
+            let path = dir.join(format!("x{}_y0_z0", i));
+            fs::write(&path, encode(CURRENT_VERSION, CUBOID_SIZE, &voxels)).unwrap();
+            path
+        })
+        .collect()
+}
+
+/// Evict a file from the page cache, so the next read goes to the disk.
+#[cfg(target_os = "linux")]
+fn drop_cached(path: &Path) {
+    use std::os::unix::io::AsRawFd;
+    let file = fs::File::open(path).unwrap();
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_cached(_path: &Path) {}
+
+fn bench_whole(b: &mut Bencher, advice: ReadAdvice) {
+    let dir = tempfile::tempdir().unwrap();
+    let paths = cuboids(dir.path());
+    let strategy = ReadStrategy {
+        buffer_size: 0,
+        advice,
+    };
+    b.bytes = (NUM_CUBOIDS * 512 * 512 * 16) as u64;
+    b.iter(|| {
+        paths.iter().for_each(|path| drop_cached(path));
+        for path in &paths {
+            test::black_box(read_file(path, strategy).unwrap());
+        }
+    });
+}
+
+/// Reads a region narrower than the cuboid in x, so each row is a separate
+/// run of the file.
+fn bench_region(b: &mut Bencher, strategy: ReadStrategy) {
+    let dir = tempfile::tempdir().unwrap();
+    let paths = cuboids(dir.path());
+    let (start, stop) = Vector3::from_xyz_extents((0, 384), (0, 512), (0, 16));
+    b.bytes = (NUM_CUBOIDS * 384 * 512 * 16) as u64;
+    b.iter(|| {
+        paths.iter().for_each(|path| drop_cached(path));
+        for path in &paths {
+            test::black_box(read_region_with(path, CUBOID_SIZE, start, stop, strategy).unwrap());
+        }
+    });
+}
+
+#[bench]
+fn read_whole_normal(b: &mut Bencher) {
+    bench_whole(b, ReadAdvice::Normal);
+}
+
+#[bench]
+fn read_whole_sequential(b: &mut Bencher) {
+    bench_whole(b, ReadAdvice::Sequential);
+}
+
+#[bench]
+fn read_region_unbuffered(b: &mut Bencher) {
+    bench_region(b, ReadStrategy::default());
+}
+
+#[bench]
+fn read_region_buffered_sequential(b: &mut Bencher) {
+    bench_region(
+        b,
+        ReadStrategy {
+            buffer_size: 1 << 20,
+            advice: ReadAdvice::Sequential,
+        },
+    );
+}
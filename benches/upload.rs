@@ -0,0 +1,60 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+//! Upload throughput for cutouts aligned to the cuboid grid (written
+//! straight through) versus misaligned ones (read, merged and rewritten).
+//!
+//! Run with `cargo +nightly bench --bench upload`.
+#![feature(test)]
+
+extern crate test;
+
+use bossphorus::data_manager::{ChunkedFileDataManager, DataManager, Vector3};
+use ndarray::Array;
+use test::Bencher;
+
+const URI: &str = "bossdb://col/exp/chan";
+
+/// The default cuboid size, and 4 cuboids' worth of voxels.
+const CUBOID_SIZE: Vector3 = Vector3 {
+    x: 512,
+    y: 512,
+    z: 16,
+};
+const SHAPE: (usize, usize, usize) = (32, 512, 1024);
+
+fn bench_upload(b: &mut Bencher, origin: Vector3) {
+    let dir = tempfile::tempdir().unwrap();
+    let fm =
+        ChunkedFileDataManager::new(dir.path().to_str().unwrap().to_string(), CUBOID_SIZE, false);
+    let data = Array::from_shape_fn(SHAPE, |(z, y, x)| (x + y + z) as u8);
+    // Every cuboid exists already, as when re-ingesting:
+    fm.put_data(URI.to_string(), 0, origin, data.clone());
+    b.bytes = data.len() as u64;
+    b.iter(|| fm.put_data(URI.to_string(), 0, origin, data.clone()));
+}
+
+#[bench]
+fn upload_aligned(b: &mut Bencher) {
+    bench_upload(b, Vector3 { x: 0, y: 0, z: 0 });
+}
+
+#[bench]
+fn upload_misaligned(b: &mut Bencher) {
+    bench_upload(b, Vector3 { x: 8, y: 8, z: 4 });
+}
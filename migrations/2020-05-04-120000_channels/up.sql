@@ -0,0 +1,12 @@
+CREATE TABLE channels (
+    id INTEGER PRIMARY KEY NOT NULL,
+    collection VARCHAR(256) NOT NULL,
+    experiment VARCHAR(256) NOT NULL,
+    channel VARCHAR(256) NOT NULL,
+    datatype VARCHAR(32) NOT NULL,
+    cuboid_x BIGINT NOT NULL,
+    cuboid_y BIGINT NOT NULL,
+    cuboid_z BIGINT NOT NULL
+);
+
+CREATE UNIQUE INDEX channels_name_index on channels (collection, experiment, channel);
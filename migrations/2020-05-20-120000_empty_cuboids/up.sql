@@ -0,0 +1,4 @@
+CREATE TABLE empty_cuboids (
+    id INTEGER PRIMARY KEY NOT NULL,
+    path VARCHAR(1024) NOT NULL UNIQUE
+);
@@ -0,0 +1,5 @@
+CREATE TABLE channel_keys (
+    id INTEGER PRIMARY KEY NOT NULL,
+    hash VARCHAR(64) NOT NULL UNIQUE,
+    channel VARCHAR(1024) NOT NULL
+);
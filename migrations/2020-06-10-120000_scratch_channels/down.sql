@@ -0,0 +1 @@
+DROP TABLE IF EXISTS scratch_channels;
@@ -0,0 +1,4 @@
+CREATE TABLE scratch_channels (
+    id INTEGER PRIMARY KEY NOT NULL,
+    channel VARCHAR(1024) NOT NULL UNIQUE
+);
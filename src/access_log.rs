@@ -0,0 +1,243 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Access log module.
+///
+/// Logs every request as a line of JSON, for performance analysis, e.g.:
+///
+/// `{"time":"2020-05-11T14:03:12.511Z","method":"GET","path":"/v1/cutout/...",
+/// "status":200,"bytes":5242880,"duration_ms":41.7,"cache":"hit"}`
+///
+/// `cache` is `"hit"` if every cuboid of a cutout came from the local cache,
+/// `"miss"` if any had to be fetched, and `null` for requests that don't
+/// read cuboids.  Handlers report it through the `CacheReport` guard.
+use chrono::{SecondsFormat, Utc};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{Body, Response};
+use rocket::Outcome;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[cfg(test)]
+pub mod tests;
+
+/// Where log lines go.
+pub enum LogSink {
+    None,
+    Stdout,
+    File(Mutex<File>),
+}
+
+impl LogSink {
+    /// Parse a sink setting: `none`, `stdout`, or the path of a file to
+    /// append to.
+    pub fn parse(spec: &str) -> Result<LogSink, String> {
+        match spec {
+            "" | "none" => Ok(LogSink::None),
+            "stdout" => Ok(LogSink::Stdout),
+            path => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(|f| LogSink::File(Mutex::new(f)))
+                .map_err(|e| format!("Can't open access log {}: {}", path, e)),
+        }
+    }
+
+    fn write_line(&self, line: &str) {
+        match self {
+            LogSink::None => (),
+            LogSink::Stdout => println!("{}", line),
+            LogSink::File(file) => {
+                if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                    println!("Error writing access log: {}", e);
+                }
+            }
+        }
+    }
+}
+
+const CACHE_UNKNOWN: u8 = 0;
+const CACHE_HIT: u8 = 1;
+const CACHE_MISS: u8 = 2;
+
+/// Whether a request was served from the local cache.  Lives in the
+/// request's local cache, so handlers and the fairing see the same one.
+pub struct CacheStatus(AtomicU8);
+
+impl Default for CacheStatus {
+    fn default() -> CacheStatus {
+        CacheStatus(AtomicU8::new(CACHE_UNKNOWN))
+    }
+}
+
+impl CacheStatus {
+    /// The status of a request.
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r CacheStatus {
+        request.local_cache(CacheStatus::default)
+    }
+
+    pub fn set(&self, hit: bool) {
+        self.0
+            .store(if hit { CACHE_HIT } else { CACHE_MISS }, Ordering::Relaxed);
+    }
+
+    /// `Some(true)` for a hit, `Some(false)` for a miss, or `None` if never
+    /// set.
+    pub fn get(&self) -> Option<bool> {
+        match self.0.load(Ordering::Relaxed) {
+            CACHE_HIT => Some(true),
+            CACHE_MISS => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// Request guard for reporting whether a request was a cache hit.
+pub struct CacheReport<'a>(&'a CacheStatus);
+
+impl<'a> CacheReport<'a> {
+    pub fn record(&self, hit: bool) {
+        self.0.set(hit);
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CacheReport<'a> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<CacheReport<'a>, ()> {
+        Outcome::Success(CacheReport(CacheStatus::of(request)))
+    }
+}
+
+/// One line of the access log.
+#[derive(Serialize, Debug)]
+pub struct AccessRecord {
+    /// When the response was sent, as RFC 3339.
+    pub time: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// Size of the response body.
+    pub bytes: u64,
+    pub duration_ms: f64,
+    /// `"hit"`, `"miss"`, or `None` if the request didn't read cuboids.
+    pub cache: Option<&'static str>,
+}
+
+/// When the request arrived.
+struct RequestStart(Instant);
+
+impl AccessRecord {
+    fn write_to(&self, sink: &LogSink) {
+        match serde_json::to_string(self) {
+            Ok(line) => sink.write_line(&line),
+            Err(e) => println!("Error formatting access log: {}", e),
+        }
+    }
+}
+
+/// Fairing that writes an `AccessRecord` for every request.
+pub struct AccessLog {
+    sink: Arc<LogSink>,
+}
+
+impl AccessLog {
+    pub fn new(sink: LogSink) -> AccessLog {
+        AccessLog {
+            sink: Arc::new(sink),
+        }
+    }
+}
+
+impl Fairing for AccessLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Access Log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &rocket::Data) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let LogSink::None = *self.sink {
+            return;
+        }
+        let start = request.local_cache(|| RequestStart(Instant::now())).0;
+        let mut record = AccessRecord {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            method: request.method().as_str().to_string(),
+            path: request.uri().to_string(),
+            status: response.status().code,
+            bytes: 0,
+            duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+            cache: CacheStatus::of(request)
+                .get()
+                .map(|hit| if hit { "hit" } else { "miss" }),
+        };
+        match response.take_body() {
+            None => record.write_to(&self.sink),
+            Some(Body::Sized(body, size)) => {
+                record.bytes = size;
+                record.write_to(&self.sink);
+                response.set_raw_body(Body::Sized(body, size));
+            }
+            Some(Body::Chunked(body, chunk_size)) => {
+                let counted = CountedBody {
+                    body,
+                    record,
+                    start,
+                    sink: Arc::clone(&self.sink),
+                };
+                response.set_chunked_body(counted, chunk_size);
+            }
+        }
+    }
+}
+
+/// A streamed body, which doesn't know its size, counted as it's sent.  Its
+/// record is written once it's sent in full, or the client goes away.
+struct CountedBody<R> {
+    body: R,
+    record: AccessRecord,
+    start: Instant,
+    sink: Arc<LogSink>,
+}
+
+impl<R: Read> Read for CountedBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.body.read(buf)?;
+        self.record.bytes += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R> Drop for CountedBody<R> {
+    fn drop(&mut self) {
+        self.record.duration_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+        self.record.write_to(&self.sink);
+    }
+}
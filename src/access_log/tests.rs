@@ -0,0 +1,117 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::access_log::{AccessLog, CacheStatus, LogSink};
+use rocket::handler::Outcome;
+use rocket::http::{Method, Status};
+use rocket::local::Client;
+use rocket::response::Stream;
+use rocket::{Data, Request, Route};
+use serde_json::Value;
+use std::fs;
+use std::io::Cursor;
+
+fn hit<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+    CacheStatus::of(request).set(true);
+    Outcome::from(request, "cached")
+}
+
+fn miss<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+    CacheStatus::of(request).set(false);
+    Outcome::from(request, Stream::from(Cursor::new(vec![0u8; 1000])))
+}
+
+fn plain<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+    Outcome::from(request, Status::NoContent)
+}
+
+#[test]
+fn test_parse_sink() {
+    assert!(matches!(LogSink::parse("none"), Ok(LogSink::None)));
+    assert!(matches!(LogSink::parse(""), Ok(LogSink::None)));
+    assert!(matches!(LogSink::parse("stdout"), Ok(LogSink::Stdout)));
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("access.log");
+    assert!(matches!(
+        LogSink::parse(path.to_str().unwrap()),
+        Ok(LogSink::File(_))
+    ));
+    assert!(path.exists());
+
+    let missing = dir.path().join("missing/access.log");
+    assert!(LogSink::parse(missing.to_str().unwrap()).is_err());
+}
+
+#[test]
+fn test_logs_each_request() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("access.log");
+    let sink = LogSink::parse(path.to_str().unwrap()).unwrap();
+    let rocket = rocket::custom(rocket::Config::development())
+        .mount(
+            "/",
+            vec![
+                Route::new(Method::Get, "/hit", hit),
+                Route::new(Method::Get, "/miss", miss),
+                Route::new(Method::Post, "/plain", plain),
+            ],
+        )
+        .attach(AccessLog::new(sink));
+    let client = Client::new(rocket).unwrap();
+
+    client.get("/hit").dispatch();
+    let mut response = client.get("/miss?x=1").dispatch();
+    // Still sends the whole streamed body:
+    assert_eq!(Some(vec![0u8; 1000]), response.body_bytes());
+    client.post("/plain").dispatch();
+    client.get("/nowhere").dispatch();
+
+    let log = fs::read_to_string(&path).unwrap();
+    let records: Vec<Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(4, records.len());
+
+    let summary: Vec<(&str, &str, u64, u64, Value)> = records
+        .iter()
+        .map(|r| {
+            (
+                r["method"].as_str().unwrap(),
+                r["path"].as_str().unwrap(),
+                r["status"].as_u64().unwrap(),
+                r["bytes"].as_u64().unwrap(),
+                r["cache"].clone(),
+            )
+        })
+        .collect();
+    assert_eq!(("GET", "/hit", 200, 6, Value::from("hit")), summary[0]);
+    assert_eq!(
+        ("GET", "/miss?x=1", 200, 1000, Value::from("miss")),
+        summary[1]
+    );
+    assert_eq!(("POST", "/plain", 204, 0, Value::Null), summary[2]);
+    assert_eq!("/nowhere", summary[3].1);
+    assert_eq!(404, summary[3].2);
+
+    for record in &records {
+        assert!(record["duration_ms"].as_f64().unwrap() >= 0.0);
+        assert!(record["time"].as_str().unwrap().ends_with('Z'));
+    }
+}
@@ -0,0 +1,219 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Batch upload module.
+///
+/// Parses the record stream accepted by the `/cutout/batch` endpoint.  The
+/// body is a sequence of records, each of which is:
+///
+/// * a little-endian `u32` byte length, followed by a JSON header like
+///   `{"uri": "col/exp/chan", "res": 0, "origin": {"x": 0, "y": 0, "z": 0},
+///   "shape": {"x": 512, "y": 512, "z": 16}}`
+/// * a little-endian `u64` byte length, followed by the blosc-compressed
+///   `uint8` voxels in ZYX C-order.
+///
+/// Records are decoded one at a time as they're read off the wire, so a bad
+/// header or payload only fails its own record.
+use crate::cutout::check_channel_name;
+use crate::data_manager::{Coords, Vector3};
+use crate::upload::{check_shape, decompress_voxels};
+
+use ndarray::{Array, Array3};
+use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Read};
+
+#[cfg(test)]
+pub mod tests;
+
+/// Headers larger than this are assumed to be garbage.
+const MAX_HEADER_LEN: u32 = 64 * 1024;
+
+/// Describes where a record's voxels belong.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RecordHeader {
+    /// Channel to write, as `collection/experiment/channel`.
+    pub uri: String,
+    pub res: u8,
+    /// Where the voxels start (global coords).
+    pub origin: Coords,
+    pub shape: Vector3,
+}
+
+/// A fully decoded record, ready for `put_data`.
+pub struct Record {
+    pub header: RecordHeader,
+    pub data: Array3<u8>,
+}
+
+/// Why a record couldn't be decoded.
+#[derive(Debug)]
+pub struct RecordError {
+    /// Channel of the record, if its header could be parsed.
+    pub uri: Option<String>,
+    pub message: String,
+    /// Set if the record names a channel that would reach outside the
+    /// cache, e.g. `col/../chan`.
+    pub bad_channel: bool,
+}
+
+impl RecordError {
+    fn new(uri: Option<String>, message: String) -> RecordError {
+        RecordError {
+            uri,
+            message,
+            bad_channel: false,
+        }
+    }
+}
+
+/// Outcome of writing a single record, reported back to the client.
+#[derive(Serialize, Debug)]
+pub struct RecordResult {
+    /// Position of the record in the batch.
+    pub index: usize,
+    /// Channel of the record, if its header could be parsed.
+    pub uri: Option<String>,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/// Iterates over the records of a batch upload.
+///
+/// Yields one `Result` per record.  Once the framing itself is broken (e.g.
+/// a truncated stream) an error is yielded and iteration stops, because
+/// there's no way to find the start of the next record.
+pub struct BatchReader<R: Read> {
+    reader: R,
+    done: bool,
+    max_payload: u64,
+    max_voxels: u64,
+}
+
+impl<R: Read> BatchReader<R> {
+    pub fn new(reader: R) -> BatchReader<R> {
+        BatchReader {
+            reader,
+            done: false,
+            max_payload: u64::MAX,
+            max_voxels: u64::MAX,
+        }
+    }
+
+    /// Reject records whose shape has more than `max` voxels.
+    pub fn set_max_voxels(&mut self, max: u64) {
+        self.max_voxels = max;
+    }
+
+    /// Reject records whose payload is larger than `max` bytes.  Since the
+    /// payload length comes first, this happens before any of it is read.
+    pub fn set_max_payload(&mut self, max: u64) {
+        self.max_payload = max;
+    }
+
+    /// Read the next record's header and payload bytes.  Returns `Ok(None)`
+    /// at a clean end of stream.
+    fn read_frame(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>, String> {
+        let mut len_buf = [0u8; 4];
+        match self.reader.read_exact(&mut len_buf) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(format!("Failed to read record header length: {}", e)),
+        }
+        let header_len = u32::from_le_bytes(len_buf);
+        if header_len > MAX_HEADER_LEN {
+            return Err(format!("Record header too large: {} bytes", header_len));
+        }
+        let header = self.read_bytes(header_len as u64, "header")?;
+
+        let mut len_buf = [0u8; 8];
+        self.reader
+            .read_exact(&mut len_buf)
+            .map_err(|e| format!("Failed to read record payload length: {}", e))?;
+        let payload_len = u64::from_le_bytes(len_buf);
+        if payload_len > self.max_payload {
+            return Err(format!("Record payload too large: {} bytes", payload_len));
+        }
+        let payload = self.read_bytes(payload_len, "payload")?;
+
+        Ok(Some((header, payload)))
+    }
+
+    fn read_bytes(&mut self, len: u64, what: &str) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::new();
+        (&mut self.reader)
+            .take(len)
+            .read_to_end(&mut buf)
+            .map_err(|e| format!("Failed to read record {}: {}", what, e))?;
+        if buf.len() as u64 != len {
+            return Err(format!("Truncated record {}", what));
+        }
+        Ok(buf)
+    }
+}
+
+impl<R: Read> Iterator for BatchReader<R> {
+    type Item = Result<Record, RecordError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.read_frame() {
+            Ok(Some((header, payload))) => Some(decode_record(&header, &payload, self.max_voxels)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(RecordError::new(None, e)))
+            }
+        }
+    }
+}
+
+/// Turn a record's raw header and payload into a `Record`.
+fn decode_record(header: &[u8], payload: &[u8], max_voxels: u64) -> Result<Record, RecordError> {
+    let header: RecordHeader = serde_json::from_slice(header)
+        .map_err(|e| RecordError::new(None, format!("Invalid record header: {}", e)))?;
+    let uri = Some(header.uri.clone());
+
+    let parts: Vec<&str> = header.uri.split('/').collect();
+    if parts.len() != 3 {
+        return Err(RecordError::new(
+            uri,
+            "uri must be collection/experiment/channel".to_string(),
+        ));
+    }
+    for part in parts {
+        check_channel_name(part).map_err(|message| RecordError {
+            bad_channel: true,
+            ..RecordError::new(uri.clone(), message)
+        })?;
+    }
+
+    let shape = header.shape;
+    let voxels = check_shape(shape, max_voxels).map_err(|e| RecordError::new(uri.clone(), e))?;
+    let decompressed = decompress_voxels(payload, voxels)
+        .map_err(|e| RecordError::new(uri.clone(), e.to_string()))?;
+
+    let data = Array::from_shape_vec(shape.to_zyx_shape(), decompressed)
+        .map_err(|e| RecordError::new(uri.clone(), e.to_string()))?;
+
+    Ok(Record { header, data })
+}
@@ -0,0 +1,215 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::batch::BatchReader;
+
+/// Append one framed record to `stream`.
+fn push_record(stream: &mut Vec<u8>, header: &str, payload: &[u8]) {
+    stream.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    stream.extend_from_slice(header.as_bytes());
+    stream.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    stream.extend_from_slice(payload);
+}
+
+fn header(uri: &str, shape: (u64, u64, u64)) -> String {
+    format!(
+        r#"{{"uri": "{}", "res": 0, "origin": {{"x": 0, "y": 0, "z": 0}}, "shape": {{"x": {}, "y": {}, "z": {}}}}}"#,
+        uri, shape.0, shape.1, shape.2
+    )
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    blosc::Context::new().compress(data).into()
+}
+
+#[test]
+fn test_read_records() {
+    let voxels: Vec<u8> = (0..24).collect();
+    let mut stream = Vec::new();
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (4, 3, 2)),
+        &compress(&voxels),
+    );
+    push_record(
+        &mut stream,
+        &header("col/exp/other", (2, 2, 2)),
+        &compress(&[7; 8]),
+    );
+
+    let records: Vec<_> = BatchReader::new(&stream[..]).collect();
+    assert_eq!(2, records.len());
+
+    let first = records[0].as_ref().unwrap();
+    assert_eq!("col/exp/chan", first.header.uri);
+    assert_eq!(&[2, 3, 4], first.data.shape());
+    assert_eq!(voxels, first.data.iter().cloned().collect::<Vec<u8>>());
+
+    let second = records[1].as_ref().unwrap();
+    assert_eq!("col/exp/other", second.header.uri);
+    assert!(second.data.iter().all(|v| *v == 7));
+}
+
+#[test]
+fn test_bad_record_does_not_fail_batch() {
+    let mut stream = Vec::new();
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (2, 2, 2)),
+        &compress(&[1; 8]),
+    );
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (2, 2, 2)),
+        b"not blosc",
+    );
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (4, 4, 4)),
+        &compress(&[1; 8]),
+    );
+    push_record(&mut stream, "{not json", &compress(&[1; 8]));
+    push_record(
+        &mut stream,
+        &header("col/chan", (2, 2, 2)),
+        &compress(&[1; 8]),
+    );
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (2, 2, 2)),
+        &compress(&[2; 8]),
+    );
+
+    let records: Vec<_> = BatchReader::new(&stream[..]).collect();
+    assert_eq!(6, records.len());
+    assert!(records[0].is_ok());
+
+    let bad_blosc = records[1].as_ref().err().unwrap();
+    assert_eq!(Some("col/exp/chan".to_string()), bad_blosc.uri);
+
+    let bad_shape = records[2].as_ref().err().unwrap();
+    assert!(bad_shape.message.contains("needs 64"));
+
+    let bad_header = records[3].as_ref().err().unwrap();
+    assert_eq!(None, bad_header.uri);
+
+    assert!(records[4].is_err());
+    assert!(records[5].is_ok());
+}
+
+#[test]
+fn test_traversing_uri_rejected() {
+    let mut stream = Vec::new();
+    for uri in &["col/../chan", "col/exp/..", "./exp/chan", "col//chan"] {
+        push_record(&mut stream, &header(uri, (2, 2, 2)), &compress(&[1; 8]));
+    }
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (2, 2, 2)),
+        &compress(&[1; 8]),
+    );
+
+    let records: Vec<_> = BatchReader::new(&stream[..]).collect();
+    assert_eq!(5, records.len());
+    for record in &records[..4] {
+        let err = record.as_ref().err().unwrap();
+        assert!(err.bad_channel);
+        assert!(err.message.starts_with("Invalid channel name"));
+    }
+    assert!(records[4].is_ok());
+}
+
+#[test]
+fn test_truncated_stream_stops() {
+    let mut stream = Vec::new();
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (2, 2, 2)),
+        &compress(&[1; 8]),
+    );
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (2, 2, 2)),
+        &compress(&[1; 8]),
+    );
+    stream.truncate(stream.len() - 4);
+
+    let records: Vec<_> = BatchReader::new(&stream[..]).collect();
+    assert_eq!(2, records.len());
+    assert!(records[0].is_ok());
+    assert!(records[1]
+        .as_ref()
+        .err()
+        .unwrap()
+        .message
+        .contains("Truncated"));
+}
+
+#[test]
+fn test_oversized_payload_stops() {
+    let mut stream = Vec::new();
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (2, 2, 2)),
+        &compress(&[1; 8]),
+    );
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (2, 2, 2)),
+        &vec![0; 1024],
+    );
+
+    let mut reader = BatchReader::new(&stream[..]);
+    reader.set_max_payload(512);
+    let records: Vec<_> = reader.collect();
+    assert_eq!(2, records.len());
+    assert!(records[0].is_ok());
+    assert!(records[1]
+        .as_ref()
+        .err()
+        .unwrap()
+        .message
+        .contains("too large"));
+}
+
+#[test]
+fn test_oversized_shape_rejected() {
+    let mut stream = Vec::new();
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (1 << 20, 1 << 20, 1 << 20)),
+        &compress(&[1; 8]),
+    );
+    push_record(
+        &mut stream,
+        &header("col/exp/chan", (2, 2, 2)),
+        &compress(&[1; 8]),
+    );
+
+    let mut reader = BatchReader::new(&stream[..]);
+    reader.set_max_voxels(1024);
+    let records: Vec<_> = reader.collect();
+    assert_eq!(2, records.len());
+    assert!(records[0]
+        .as_ref()
+        .err()
+        .unwrap()
+        .message
+        .contains("voxel limit"));
+    assert!(records[1].is_ok());
+}
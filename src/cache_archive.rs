@@ -0,0 +1,373 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Cache archive module.
+///
+/// Copies a warm cache between hosts as a tar archive, so a new replica
+/// doesn't start cold.  The archive holds `MANIFEST_NAME`, a JSON
+/// `Manifest` of each cuboid's usage history, then one regular file per
+/// cuboid, named by its cube key without the leading `/` (e.g.
+/// `col/exp/chan/0/0-512_0-512_0-16`).  Imported cuboids are unpacked
+/// under whichever cache root their resolution is configured to live in on
+/// the importing host.
+use crate::cuboid_file::{self, Modes};
+use crate::data_manager::Vector3;
+use crate::db::SqliteCacheInterface;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Component, Path};
+
+#[cfg(test)]
+pub mod tests;
+
+/// Name of the manifest within an archive.
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Tar archives are written in blocks of this many bytes.
+const BLOCK_SIZE: u64 = 512;
+
+/// Usage history of an archived cuboid.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// Cube key, like the cuboid's name in the archive.
+    pub key: String,
+    pub requests: i64,
+    /// When the cuboid was first cached, in seconds since the epoch.
+    pub created: i64,
+    /// When the cuboid was last requested, in seconds since the epoch.
+    pub last_accessed: i64,
+}
+
+/// Every cuboid in an archive.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Manifest {
+    pub cuboids: Vec<ManifestEntry>,
+}
+
+/// Outcome of importing an archive.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ImportReport {
+    /// Cuboid files unpacked into the cache.
+    pub unpacked: u32,
+    /// Unpacked cuboids that weren't tracked already.
+    pub registered: u32,
+    /// Entries that aren't complete cuboids, or that couldn't be unpacked.
+    pub skipped: Vec<String>,
+}
+
+/// Reads a tar archive of cached cuboids, opening each cuboid's file only
+/// when the archive gets to it, so the archive is never held in memory.
+/// Cuboids evicted before then are left out.
+pub struct ExportReader {
+    /// The part of the archive being read.
+    current: Box<dyn Read>,
+    /// Names and paths of the cuboids still to read.
+    pending: VecDeque<(String, String)>,
+    /// Whether the end-of-archive blocks have been queued.
+    finished: bool,
+}
+
+impl ExportReader {
+    /// An archive of the given cuboids, e.g. from
+    /// `SqliteCacheInterface::find_under`.
+    ///
+    /// # Arguments
+    ///
+    /// * `cuboids` - Paths of the cuboids' files, and their rows in the DB
+    pub fn new(cuboids: Vec<(String, crate::db::models::Cuboid)>) -> io::Result<ExportReader> {
+        let manifest = Manifest {
+            cuboids: cuboids
+                .iter()
+                .map(|(_, cuboid)| ManifestEntry {
+                    key: archive_name(&cuboid.cube_key).to_string(),
+                    requests: cuboid.requests,
+                    created: cuboid.created.timestamp(),
+                    last_accessed: cuboid.last_accessed.timestamp(),
+                })
+                .collect(),
+        };
+        let json = serde_json::to_vec(&manifest)?;
+        let len = json.len() as u64;
+        let manifest_entry = entry_header(MANIFEST_NAME, len)?
+            .chain(Cursor::new(json))
+            .chain(padding(len));
+        Ok(ExportReader {
+            current: Box::new(manifest_entry),
+            pending: cuboids
+                .into_iter()
+                .map(|(filename, cuboid)| (archive_name(&cuboid.cube_key).to_string(), filename))
+                .collect(),
+            finished: false,
+        })
+    }
+
+    /// Move on to the next part of the archive.  Returns false at the end.
+    fn advance(&mut self) -> bool {
+        while let Some((name, filename)) = self.pending.pop_front() {
+            match cuboid_entry(&name, &filename) {
+                Ok(Some(entry)) => {
+                    self.current = entry;
+                    return true;
+                }
+                // Evicted since it was listed:
+                Ok(None) => (),
+                Err(e) => println!("Leaving {} out of the archive: {}", filename, e),
+            }
+        }
+        if self.finished {
+            return false;
+        }
+        self.finished = true;
+        self.current = Box::new(io::repeat(0).take(2 * BLOCK_SIZE));
+        true
+    }
+}
+
+impl Read for ExportReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() || !self.advance() {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+/// A cube key's name in the archive.
+fn archive_name(cube_key: &str) -> &str {
+    cube_key.trim_start_matches('/')
+}
+
+/// Header block of a regular file in the archive.
+fn entry_header(name: &str, len: u64) -> io::Result<Cursor<Vec<u8>>> {
+    let mut header = tar::Header::new_ustar();
+    header.set_path(name)?;
+    header.set_size(len);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_cksum();
+    Ok(Cursor::new(header.as_bytes().to_vec()))
+}
+
+/// Zeros that fill out the last block of a file `len` bytes long.
+fn padding(len: u64) -> io::Take<io::Repeat> {
+    io::repeat(0).take((BLOCK_SIZE - len % BLOCK_SIZE) % BLOCK_SIZE)
+}
+
+/// The archive entry of a cuboid's file, or `None` if it's gone.
+fn cuboid_entry(name: &str, filename: &str) -> io::Result<Option<Box<dyn Read>>> {
+    let file = match fs::File::open(filename) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    // Cuboids are replaced atomically, so the open file can't change
+    // length, but the entry must match its header regardless:
+    let body = file.chain(io::repeat(0)).take(len);
+    Ok(Some(Box::new(
+        entry_header(name, len)?.chain(body).chain(padding(len)),
+    )))
+}
+
+/// The cache root that a cuboid belongs under, from the resolution in its
+/// key (`col/exp/chan/res/...`).
+///
+/// # Arguments
+///
+/// * `key` - The cuboid's name in the archive
+/// * `default_root` - Root of resolutions without their own
+/// * `resolution_roots` - Roots of particular resolutions
+pub fn root_for<'a>(
+    key: &str,
+    default_root: &'a str,
+    resolution_roots: &'a HashMap<u8, String>,
+) -> &'a str {
+    key.split('/')
+        .nth(3)
+        .and_then(|res| res.parse::<u8>().ok())
+        .and_then(|res| resolution_roots.get(&res))
+        .map_or(default_root, String::as_str)
+}
+
+/// Whether an archive entry's name could be a cube key: relative, with no
+/// `..`, and at least `col/exp/chan/res/cuboid` deep with a numeric
+/// resolution.
+fn is_cube_key(name: &Path) -> bool {
+    let mut segments = 0;
+    for component in name.components() {
+        match component {
+            Component::Normal(_) => segments += 1,
+            _ => return false,
+        }
+    }
+    let res = name.iter().nth(3).and_then(|res| res.to_str());
+    segments >= 5 && res.map_or(false, |res| res.parse::<u8>().is_ok())
+}
+
+/// Unpack an archive made by `ExportReader` into the cache, then track its
+/// cuboids in the DB with the usage history from its manifest.  Cuboids
+/// already in the cache are overwritten, but keep their own history.
+/// Entries that aren't complete cuboids of their channel's cuboid size are
+/// skipped.  Buffered writes to the cuboids unpacked are dropped, so that
+/// they aren't written over them.  Reads the archive as it goes, holding one
+/// cuboid in memory at a time.  If the archive turns out to be unreadable partway, the cuboids
+/// it added to the cache are removed again, and nothing is tracked; those
+/// it overwrote stay overwritten.
+///
+/// # Arguments
+///
+/// * `archive` - The tar archive
+/// * `default_root` - Root of resolutions without their own
+/// * `resolution_roots` - Roots of particular resolutions
+/// * `cuboid_size_of` - The cuboid size of a channel, like `col/exp/chan`
+/// * `modes` - Permission bits for new directories and files
+/// * `db` - Tracks the cache's cuboids
+pub fn import<R: Read>(
+    archive: R,
+    default_root: &str,
+    resolution_roots: &HashMap<u8, String>,
+    cuboid_size_of: &dyn Fn(&str) -> Vector3,
+    modes: Modes,
+    db: &mut SqliteCacheInterface,
+) -> io::Result<ImportReport> {
+    let mut report = ImportReport::default();
+    let mut created = vec![];
+    let unpacked = unpack(
+        archive,
+        default_root,
+        resolution_roots,
+        cuboid_size_of,
+        modes,
+        &|filename| db.discard_buffered(filename),
+        &mut report,
+        &mut created,
+    );
+    let (manifest, unpacked) = match unpacked {
+        Ok(unpacked) => unpacked,
+        Err(e) => {
+            for filename in created {
+                if let Err(e) = fs::remove_file(&filename) {
+                    println!("Error removing {}: {}", filename, e);
+                }
+            }
+            return Err(e);
+        }
+    };
+
+    db.add_cache_root(default_root);
+    for root in resolution_roots.values() {
+        db.add_cache_root(root);
+    }
+    let history: HashMap<&str, &ManifestEntry> = manifest
+        .cuboids
+        .iter()
+        .map(|entry| (entry.key.as_str(), entry))
+        .collect();
+    let now = Utc::now().naive_utc();
+    let time = |secs: i64| NaiveDateTime::from_timestamp_opt(secs, 0).unwrap_or(now);
+    for (name, filename) in unpacked {
+        report.unpacked += 1;
+        // Cuboids left out of the manifest count as just requested:
+        let (requests, created, last_accessed) = match history.get(name.as_str()) {
+            Some(entry) => (
+                entry.requests,
+                time(entry.created),
+                time(entry.last_accessed),
+            ),
+            None => (1, now, now),
+        };
+        match db.import_cuboid(&filename, requests, created, last_accessed) {
+            Ok(true) => report.registered += 1,
+            Ok(false) => (),
+            Err(e) => println!("Error tracking {}: {}", filename, e),
+        }
+    }
+    Ok(report)
+}
+
+/// Unpack the cuboids of an archive into the cache, for `import`.  Returns
+/// the archive's manifest, and the names and paths of the cuboids unpacked.
+/// Calls `discard` with the path of each cuboid before writing it.
+/// Adds the paths of files it creates (rather than overwrites) to `created`
+/// as it goes, so that they can be removed if it fails partway.
+fn unpack<R: Read>(
+    archive: R,
+    default_root: &str,
+    resolution_roots: &HashMap<u8, String>,
+    cuboid_size_of: &dyn Fn(&str) -> Vector3,
+    modes: Modes,
+    discard: &dyn Fn(&str),
+    report: &mut ImportReport,
+    created: &mut Vec<String>,
+) -> io::Result<(Manifest, Vec<(String, String)>)> {
+    let mut manifest = Manifest::default();
+    // Names and paths of the cuboids unpacked:
+    let mut unpacked = vec![];
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        let name_str = name.to_string_lossy().into_owned();
+        if name_str == MANIFEST_NAME {
+            manifest = serde_json::from_reader(&mut entry)?;
+            continue;
+        }
+        if entry.header().entry_type() != tar::EntryType::Regular || !is_cube_key(&name) {
+            report.skipped.push(name_str);
+            continue;
+        }
+        let channel: Vec<&str> = name_str.splitn(4, '/').take(3).collect();
+        let cuboid_size = cuboid_size_of(&channel.join("/"));
+        // The header's size isn't trusted to be that of a cuboid:
+        let max_len = cuboid_file::max_file_len(cuboid_size);
+        let mut bytes = Vec::with_capacity(entry.size().min(max_len) as usize);
+        (&mut entry).take(max_len + 1).read_to_end(&mut bytes)?;
+        if !cuboid_file::is_readable(&bytes, cuboid_size) {
+            println!("Not unpacking {}: not a complete cuboid", name_str);
+            report.skipped.push(name_str);
+            continue;
+        }
+        let root = root_for(&name_str, default_root, resolution_roots);
+        let filename = format!("{}/{}", root, name_str);
+        let existed = Path::new(&filename).exists();
+        discard(&filename);
+        let written = Path::new(&filename)
+            .parent()
+            .map_or(Ok(()), |dir| cuboid_file::create_dir_all(dir, modes.dir))
+            .and_then(|_| cuboid_file::write_atomically(&filename, &bytes, modes.file));
+        match written {
+            Ok(()) => {
+                if !existed {
+                    created.push(filename.clone());
+                }
+                unpacked.push((name_str, filename));
+            }
+            Err(e) => {
+                println!("Error unpacking {}: {}", filename, e);
+                report.skipped.push(name_str);
+            }
+        }
+    }
+    Ok((manifest, unpacked))
+}
@@ -0,0 +1,318 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use super::{import, root_for, ExportReader, ImportReport};
+use crate::cuboid_file::{self, Modes, CURRENT_VERSION};
+use crate::data_manager::{ChunkedFileDataManager, Vector3};
+use crate::db::pool::ConnectionPool;
+use crate::db::SqliteCacheInterface;
+use crate::write_buffer::WriteBuffer;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use ndarray::Array;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A cache tracked by an in-memory DB.
+fn cache_db() -> SqliteCacheInterface {
+    let connection = SqliteConnection::establish(":memory:").unwrap();
+    SqliteCacheInterface::with_pool(Arc::new(ConnectionPool::from_connection(connection)))
+}
+
+/// Write a cuboid file under `root` and track it in `db`.
+fn cache(db: &mut SqliteCacheInterface, root: &str, key: &str, bytes: &[u8], requests: i64) {
+    let filename = format!("{}/{}", root, key);
+    fs::create_dir_all(Path::new(&filename).parent().unwrap()).unwrap();
+    fs::write(&filename, bytes).unwrap();
+    let time = NaiveDateTime::from_timestamp(1_600_000_000 + requests, 0);
+    assert!(db.import_cuboid(&filename, requests, time, time).unwrap());
+}
+
+/// Cuboid size of every channel in these tests.
+fn cuboid_size(_channel: &str) -> Vector3 {
+    Vector3 { x: 4, y: 4, z: 2 }
+}
+
+/// The contents of a cuboid file with every voxel `value`.
+fn cuboid_bytes(value: u8) -> Vec<u8> {
+    cuboid_file::encode(CURRENT_VERSION, cuboid_size(""), &[value; 32])
+}
+
+#[test]
+fn test_root_for() {
+    let mut roots = HashMap::new();
+    roots.insert(1, "/fast".to_string());
+    assert_eq!(
+        "/fast",
+        root_for("col/exp/chan/1/0-512_0-512_0-16", "/cache", &roots)
+    );
+    assert_eq!(
+        "/cache",
+        root_for("col/exp/chan/0/0-512_0-512_0-16", "/cache", &roots)
+    );
+    assert_eq!("/cache", root_for("col/exp/chan", "/cache", &roots));
+}
+
+#[test]
+fn test_export_import_round_trip() {
+    let from = tempfile::tempdir().unwrap();
+    let from_root = from.path().to_str().unwrap();
+    let mut from_db = cache_db();
+    from_db.add_cache_root(from_root);
+    let sevens = cuboid_bytes(7);
+    // Headerless:
+    let fives = vec![5; 32];
+    cache(
+        &mut from_db,
+        from_root,
+        "col/exp/chan/0/0-512_0-512_0-16",
+        &sevens,
+        3,
+    );
+    cache(
+        &mut from_db,
+        from_root,
+        "col/exp/chan/1/0-512_0-512_0-16",
+        &fives,
+        5,
+    );
+    cache(
+        &mut from_db,
+        from_root,
+        "col/exp/other/0/0-512_0-512_0-16",
+        &cuboid_bytes(1),
+        1,
+    );
+
+    let mut archive = vec![];
+    ExportReader::new(from_db.find_under("col/exp/chan"))
+        .unwrap()
+        .read_to_end(&mut archive)
+        .unwrap();
+    assert_eq!(0, archive.len() % 512);
+
+    // Resolution 1 lives in its own root on the importing host:
+    let to = tempfile::tempdir().unwrap();
+    let to_root = to.path().join("cache");
+    let to_root = to_root.to_str().unwrap();
+    let res1_root = to.path().join("res1");
+    let mut roots = HashMap::new();
+    roots.insert(1, res1_root.to_str().unwrap().to_string());
+    let mut to_db = cache_db();
+    let report = import(
+        &archive[..],
+        to_root,
+        &roots,
+        &cuboid_size,
+        Modes::default(),
+        &mut to_db,
+    )
+    .unwrap();
+    assert_eq!(
+        ImportReport {
+            unpacked: 2,
+            registered: 2,
+            skipped: vec![],
+        },
+        report
+    );
+
+    let res0 = format!("{}/col/exp/chan/0/0-512_0-512_0-16", to_root);
+    let res1 = res1_root.join("col/exp/chan/1/0-512_0-512_0-16");
+    assert_eq!(sevens, fs::read(&res0).unwrap());
+    assert_eq!(fives, fs::read(&res1).unwrap());
+    assert!(!Path::new(&format!("{}/col/exp/other", to_root)).exists());
+
+    // Usage history comes along:
+    let imported = to_db.find_under("col/exp/chan");
+    let history: Vec<(String, i64, i64)> = imported
+        .iter()
+        .map(|(filename, cuboid)| {
+            (
+                filename.clone(),
+                cuboid.requests,
+                cuboid.last_accessed.timestamp(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            (res0, 3, 1_600_000_003),
+            (res1.to_str().unwrap().to_string(), 5, 1_600_000_005),
+        ],
+        history
+    );
+
+    // Importing again overwrites the files, but keeps the rows:
+    let report = import(
+        &archive[..],
+        to_root,
+        &roots,
+        &cuboid_size,
+        Modes::default(),
+        &mut to_db,
+    )
+    .unwrap();
+    assert_eq!(2, report.unpacked);
+    assert_eq!(0, report.registered);
+    assert_eq!(2, to_db.find_under("col/exp/chan").len());
+}
+
+#[test]
+fn test_export_skips_evicted_cuboids() {
+    let from = tempfile::tempdir().unwrap();
+    let from_root = from.path().to_str().unwrap();
+    let mut from_db = cache_db();
+    from_db.add_cache_root(from_root);
+    cache(&mut from_db, from_root, "col/exp/chan/0/a", b"gone", 1);
+    cache(&mut from_db, from_root, "col/exp/chan/0/b", b"kept", 1);
+    let reader = ExportReader::new(from_db.find_under("col/exp/chan")).unwrap();
+    fs::remove_file(from.path().join("col/exp/chan/0/a")).unwrap();
+
+    let mut names = vec![];
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().unwrap() {
+        names.push(entry.unwrap().path().unwrap().to_str().unwrap().to_string());
+    }
+    assert_eq!(vec!["manifest.json", "col/exp/chan/0/b"], names);
+}
+
+/// A tar archive of the given entries, each a regular file.
+fn archive_of(entries: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(vec![]);
+    for (name, bytes) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, *bytes).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+#[test]
+fn test_import_skips_incomplete_cuboids() {
+    let to = tempfile::tempdir().unwrap();
+    let to_root = to.path().to_str().unwrap();
+    let complete = cuboid_bytes(3);
+    let archive = archive_of(&[
+        ("col/exp/chan/0/a", &complete),
+        ("col/exp/chan/0/partial", &complete[..20]),
+        ("col/exp/chan/0/too_long", &[0; 5000]),
+    ]);
+    let mut db = cache_db();
+    let report = import(
+        &archive[..],
+        to_root,
+        &HashMap::new(),
+        &cuboid_size,
+        Modes::default(),
+        &mut db,
+    )
+    .unwrap();
+    assert_eq!(
+        ImportReport {
+            unpacked: 1,
+            registered: 1,
+            skipped: vec![
+                "col/exp/chan/0/partial".to_string(),
+                "col/exp/chan/0/too_long".to_string()
+            ],
+        },
+        report
+    );
+    assert!(!to.path().join("col/exp/chan/0/partial").exists());
+    assert!(!to.path().join("col/exp/chan/0/too_long").exists());
+}
+
+#[test]
+fn test_failed_import_removes_new_cuboids() {
+    let to = tempfile::tempdir().unwrap();
+    let to_root = to.path().to_str().unwrap();
+    let old = to.path().join("col/exp/chan/0/old");
+    fs::create_dir_all(old.parent().unwrap()).unwrap();
+    fs::write(&old, cuboid_bytes(1)).unwrap();
+
+    // Cut off partway through its last entry:
+    let complete = cuboid_bytes(2);
+    let mut archive = archive_of(&[
+        ("col/exp/chan/0/old", &complete),
+        ("col/exp/chan/0/new", &complete),
+        ("col/exp/chan/0/cut", &complete),
+    ]);
+    archive.truncate(5 * 512 + 10);
+
+    let mut db = cache_db();
+    assert!(import(
+        &archive[..],
+        to_root,
+        &HashMap::new(),
+        &cuboid_size,
+        Modes::default(),
+        &mut db,
+    )
+    .is_err());
+    assert!(!to.path().join("col/exp/chan/0/new").exists());
+    assert!(!to.path().join("col/exp/chan/0/cut").exists());
+    // Overwritten, but not removed:
+    assert_eq!(complete, fs::read(&old).unwrap());
+    assert!(db.find_under("col/exp/chan").is_empty());
+}
+
+#[test]
+fn test_import_drops_buffered_writes() {
+    let to = tempfile::tempdir().unwrap();
+    let to_root = to.path().to_str().unwrap();
+    let size = cuboid_size("");
+    let fm = ChunkedFileDataManager::new(to_root.to_string(), size, false);
+    let buffer = Arc::new(WriteBuffer::new(Duration::from_secs(3600), 8));
+    let filename = format!("{}/col/exp/chan/0/a", to_root);
+    let voxel = Array::from_elem((1, 1, 1), 1);
+    let (start, stop) = (Vector3 { x: 0, y: 0, z: 0 }, Vector3 { x: 1, y: 1, z: 1 });
+    let buffered = buffer.write(
+        &filename,
+        &fm.cuboid_writer(size),
+        start,
+        stop,
+        voxel.view(),
+        || Ok::<_, ()>(Array::zeros(size.to_zyx_shape())),
+    );
+    assert_eq!(Ok(true), buffered);
+
+    // The imported cuboid isn't written over later:
+    let complete = cuboid_bytes(2);
+    let archive = archive_of(&[("col/exp/chan/0/a", &complete)]);
+    let mut db = cache_db();
+    db.set_write_buffer(Arc::clone(&buffer));
+    import(
+        &archive[..],
+        to_root,
+        &HashMap::new(),
+        &cuboid_size,
+        Modes::default(),
+        &mut db,
+    )
+    .unwrap();
+    assert!(buffer.is_empty());
+    assert_eq!(0, buffer.flush_all());
+    assert_eq!(complete, fs::read(&filename).unwrap());
+}
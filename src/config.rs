@@ -21,9 +21,35 @@ limitations under the License.
 /// Gets custom config values from environment variables and the
 /// Rocket.toml config file.  Values set as environment variables will
 /// override like values in the config file.
-use rocket::Rocket;
+use crate::cuboid_file::{self, Layout, Modes, ReadAdvice, ReadStrategy};
+use crate::cutout::parse_quality;
+use crate::data_manager::{
+    Coords, FillValues, MissPolicy, NotFoundChannels, RecentMisses, UpstreamErrorPolicy, Vector3,
+};
+use crate::db::pool::{JOURNAL_MODES, SYNCHRONOUS_MODES};
+use crate::db::{ConnectRetry, PinnedChannels, RemovalRetry};
+use crate::downsample::{Downsampling, SynthesisMethods};
+use crate::intern::remote::{split_scheme, DEFAULT_API_PREFIX, DEFAULT_PROTOCOL, PROTOCOLS};
+use crate::prefetch::PrefetchPolicy;
+use crate::pyramid;
+use crate::rate_limit::Limit;
+use crate::semaphore::Semaphore;
+use crate::usage_tracker::{EvictionSettings, EvictionStrategy};
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use rocket::config::{ConfigError, RocketConfig, Value};
+use rocket::{Config, Rocket};
+use serde::Serialize;
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 /// Store cuboid files off of this folder.  This is not a standard config
 /// variable because we will likely move to a separate config file as
@@ -35,15 +61,28 @@ pub const CUBOID_ROOT_PATH: &str = "uploads";
 /// a file placed next to the folder storing the cached cuboids.
 pub const DB_URL: &str = "./cache-db.sqlite";
 
+/// Size of the cuboids stored on disk.
+pub const CUBOID_SIZE: Vector3 = Vector3 {
+    x: 512,
+    y: 512,
+    z: 16,
+};
+
 /// Get the absolute path of the cuboid root folder.
 pub fn get_cuboid_root_abs_path() -> String {
-    let path = fs::canonicalize(CUBOID_ROOT_PATH);
+    abs_path(CUBOID_ROOT_PATH)
+}
+
+/// Get the absolute path of a cache root folder, creating it if needed with
+/// the configured directory mode.
+pub fn abs_path(root: &str) -> String {
+    let path = fs::canonicalize(root);
     let path_str = match path {
         Ok(p) => p,
         Err(_) => {
-            fs::create_dir_all(CUBOID_ROOT_PATH)
-                .expect(&format!("Couldn't create {}", CUBOID_ROOT_PATH));
-            return get_cuboid_root_abs_path();
+            cuboid_file::create_dir_all(root, dir_mode())
+                .expect(&format!("Couldn't create {}", root));
+            return abs_path(root);
         }
     };
     return match path_str.as_path().to_str() {
@@ -59,8 +98,17 @@ const BOSSHOST_ENV_NAME: &str = "BOSSHOST";
 const BOSSHOST_ROCKET_CFG: &str = "bosshost";
 const BOSSHOST_DEFAULT: &str = "api.bossdb.io";
 
-/// Gets the Boss host to talk to.  First checks for an environment
-/// variable.  Then checks for a value in the Rocket.toml file.
+/// Protocol to talk to the Boss host with, e.g. `http` for an on-prem
+/// BossDB that isn't served over TLS.
+pub struct BossProtocol(pub String);
+
+const BOSSPROTOCOL_ENV_NAME: &str = "BOSSPROTOCOL";
+const BOSSPROTOCOL_ROCKET_CFG: &str = "bossprotocol";
+
+/// Gets the Boss host to talk to, and the protocol to talk to it with.
+/// First checks for an environment variable.  Then checks for a value in
+/// the Rocket.toml file.  A scheme on the host (e.g. `http://boss.internal`)
+/// takes precedence over the protocol setting.
 pub fn get_boss_host(rocket: Rocket) -> Result<Rocket, Rocket> {
     let boss_host: String;
     match env::var(BOSSHOST_ENV_NAME) {
@@ -73,13 +121,65 @@ pub fn get_boss_host(rocket: Rocket) -> Result<Rocket, Rocket> {
                 .to_string();
         }
     }
-    // ToDo: make this visible to the user in a better place.
-    println!("Boss host: {}", boss_host);
-    Ok(rocket.manage(BossHost(boss_host)))
+    let (scheme, host) = split_scheme(&boss_host);
+    let protocol: String = match scheme {
+        Some(scheme) => scheme.to_string(),
+        None => match env::var(BOSSPROTOCOL_ENV_NAME) {
+            Ok(val) => val,
+            Err(_) => rocket
+                .config()
+                .get_str(BOSSPROTOCOL_ROCKET_CFG)
+                .unwrap_or(DEFAULT_PROTOCOL)
+                .to_string(),
+        },
+    };
+    Ok(rocket
+        .manage(BossHost(host.to_string()))
+        .manage(BossProtocol(protocol.to_lowercase())))
+}
+
+/// Path prefix of the Boss API, e.g. `v1`.
+pub struct BossApiPrefix(pub String);
+
+const BOSS_API_PREFIX_ENV_NAME: &str = "BOSS_API_PREFIX";
+const BOSS_API_PREFIX_ROCKET_CFG: &str = "boss_api_prefix";
+
+/// Gets the Boss API's path prefix.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_boss_api_prefix(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let prefix: String;
+    match env::var(BOSS_API_PREFIX_ENV_NAME) {
+        Ok(val) => prefix = val,
+        Err(_) => {
+            prefix = rocket
+                .config()
+                .get_str(BOSS_API_PREFIX_ROCKET_CFG)
+                .unwrap_or(DEFAULT_API_PREFIX)
+                .to_string();
+        }
+    }
+    Ok(rocket.manage(BossApiPrefix(prefix)))
 }
 
-/// Boss token used for auth.
-pub struct BossToken(pub String);
+/// Boss token used for auth.  Can be replaced while serving (e.g. with
+/// `POST /v1/admin/token`), so it's read each time it's needed.
+pub struct BossToken(RwLock<String>);
+
+impl BossToken {
+    pub fn new(token: String) -> BossToken {
+        BossToken(RwLock::new(token))
+    }
+
+    /// The current token.
+    pub fn get(&self) -> String {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Replace the token.
+    pub fn set(&self, token: String) {
+        *self.0.write().unwrap() = token;
+    }
+}
 
 const BOSSTOKEN_ENV_NAME: &str = "BOSSTOKEN";
 const BOSSTOKEN_ROCKET_CFG: &str = "bosstoken";
@@ -99,7 +199,132 @@ pub fn get_boss_token(rocket: Rocket) -> Result<Rocket, Rocket> {
                 .to_string();
         }
     }
-    Ok(rocket.manage(BossToken(boss_token)))
+    Ok(rocket.manage(BossToken::new(boss_token)))
+}
+
+/// The Boss host that uploads are written through to, if any.  May differ
+/// from the host that's read from (e.g. staging while reading production).
+pub struct BossWriteHost(pub Option<String>);
+
+const BOSS_WRITE_HOST_ENV_NAME: &str = "BOSS_WRITE_HOST";
+const BOSS_WRITE_HOST_ROCKET_CFG: &str = "boss_write_host";
+
+/// Gets the Boss host to write to, if any.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_boss_write_host(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let write_host = match env::var(BOSS_WRITE_HOST_ENV_NAME) {
+        Ok(val) => Some(val),
+        Err(_) => rocket
+            .config()
+            .get_str(BOSS_WRITE_HOST_ROCKET_CFG)
+            .ok()
+            .map(|h| h.to_string()),
+    };
+    Ok(rocket.manage(BossWriteHost(write_host.filter(|h| !h.is_empty()))))
+}
+
+/// Boss token used for writes, if it's not the token used for reads.
+pub struct BossWriteToken(pub Option<String>);
+
+const BOSS_WRITE_TOKEN_ENV_NAME: &str = "BOSS_WRITE_TOKEN";
+const BOSS_WRITE_TOKEN_ROCKET_CFG: &str = "boss_write_token";
+
+/// Gets the Boss token for writes.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.  Without
+/// either, writes use the read token, including after it's replaced.
+pub fn get_boss_write_token(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let write_token = match env::var(BOSS_WRITE_TOKEN_ENV_NAME) {
+        Ok(val) => Some(val),
+        Err(_) => rocket
+            .config()
+            .get_str(BOSS_WRITE_TOKEN_ROCKET_CFG)
+            .ok()
+            .map(|t| t.to_string()),
+    };
+    Ok(rocket.manage(BossWriteToken(write_token)))
+}
+
+/// Keep uploads in the cache as a scratch space: they're never written to
+/// the Boss DB (even with a write host), and reads see them over the Boss
+/// DB's data.
+pub struct ScratchWrites(pub bool);
+
+const SCRATCH_WRITES_ENV_NAME: &str = "SCRATCH_WRITES";
+const SCRATCH_WRITES_ROCKET_CFG: &str = "scratch_writes";
+const SCRATCH_WRITES_DEFAULT: bool = false;
+
+/// Gets whether uploads are kept local.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_scratch_writes(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let scratch_writes = setting(
+        &mut errors,
+        rocket.config(),
+        SCRATCH_WRITES_ENV_NAME,
+        SCRATCH_WRITES_ROCKET_CFG,
+        parse_flag,
+    )
+    .unwrap_or(SCRATCH_WRITES_DEFAULT);
+    Ok(report(rocket, errors).manage(ScratchWrites(scratch_writes)))
+}
+
+/// Token that maintenance endpoints require.  Without one, they're
+/// disabled.
+pub struct AdminToken(pub Option<String>);
+
+const ADMIN_TOKEN_ENV_NAME: &str = "ADMIN_TOKEN";
+const ADMIN_TOKEN_ROCKET_CFG: &str = "admin_token";
+
+/// Gets the admin token, if any.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_admin_token(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let admin_token = match env::var(ADMIN_TOKEN_ENV_NAME) {
+        Ok(val) => Some(val),
+        Err(_) => rocket
+            .config()
+            .get_str(ADMIN_TOKEN_ROCKET_CFG)
+            .ok()
+            .map(|t| t.to_string()),
+    };
+    Ok(rocket.manage(AdminToken(admin_token.filter(|t| !t.is_empty()))))
+}
+
+/// API keys that reads (cutouts, metadata and stats) require.  Without any,
+/// reads are open.  Write keys are accepted for reads too.
+pub struct ReadKeys(pub Vec<String>);
+
+/// API keys that uploads require.  Without any, uploads are open.
+pub struct WriteKeys(pub Vec<String>);
+
+const READ_API_KEYS_ENV_NAME: &str = "READ_API_KEYS";
+const READ_API_KEYS_ROCKET_CFG: &str = "read_api_keys";
+const WRITE_API_KEYS_ENV_NAME: &str = "WRITE_API_KEYS";
+const WRITE_API_KEYS_ROCKET_CFG: &str = "write_api_keys";
+
+/// Gets the comma separated API keys that clients must present to read and
+/// to write, if any.  First checks for environment variables.  Then checks
+/// for values in the Rocket.toml file.
+pub fn get_api_keys(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let read = get_keys(&rocket, READ_API_KEYS_ENV_NAME, READ_API_KEYS_ROCKET_CFG);
+    let write = get_keys(&rocket, WRITE_API_KEYS_ENV_NAME, WRITE_API_KEYS_ROCKET_CFG);
+    Ok(rocket.manage(ReadKeys(read)).manage(WriteKeys(write)))
+}
+
+/// Read one comma separated list of API keys.
+fn get_keys(rocket: &Rocket, env_name: &str, rocket_cfg: &str) -> Vec<String> {
+    let spec = match env::var(env_name) {
+        Ok(val) => val,
+        Err(_) => rocket
+            .config()
+            .get_str(rocket_cfg)
+            .unwrap_or("")
+            .to_string(),
+    };
+    spec.split(',')
+        .map(|k| k.trim())
+        .filter(|k| !k.is_empty())
+        .map(|k| k.to_string())
+        .collect()
 }
 
 /// Boss usage tracker.
@@ -109,6 +334,7 @@ pub struct UsageTracker(pub String);
 pub const NONE_TRACKER: &str = "none";
 pub const CONSOLE_TRACKER: &str = "console";
 pub const DB_TRACKER: &str = "db";
+const TRACKERS: [&str; 3] = [NONE_TRACKER, CONSOLE_TRACKER, DB_TRACKER];
 
 const USAGE_TRACKER_ENV_NAME: &str = "USAGE_TRACKER";
 const USAGE_TRACKER_ROCKET_CFG: &str = "usage_tracker";
@@ -130,3 +356,2258 @@ pub fn get_usage_tracker(rocket: Rocket) -> Result<Rocket, Rocket> {
     }
     Ok(rocket.manage(UsageTracker(usage_tracker)))
 }
+
+/// Read cached cuboids through a memory map.
+pub struct UseMmap(pub bool);
+
+const USE_MMAP_ENV_NAME: &str = "USE_MMAP";
+const USE_MMAP_ROCKET_CFG: &str = "use_mmap";
+const USE_MMAP_DEFAULT: bool = false;
+
+/// Gets whether cuboid reads should use a memory map.  First checks for an
+/// environment variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_use_mmap(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let use_mmap = setting(
+        &mut errors,
+        rocket.config(),
+        USE_MMAP_ENV_NAME,
+        USE_MMAP_ROCKET_CFG,
+        parse_flag,
+    )
+    .unwrap_or(USE_MMAP_DEFAULT);
+    Ok(report(rocket, errors).manage(UseMmap(use_mmap)))
+}
+
+/// How cached cuboids are read from disk.
+pub struct CuboidReads(pub ReadStrategy);
+
+const READ_BUFFER_SIZE_ENV_NAME: &str = "READ_BUFFER_SIZE";
+const READ_BUFFER_SIZE_ROCKET_CFG: &str = "read_buffer_size";
+const READ_ADVICE_ENV_NAME: &str = "READ_ADVICE";
+const READ_ADVICE_ROCKET_CFG: &str = "read_advice";
+
+/// Gets the bytes to read ahead at a time when reading part of a cuboid,
+/// and the hint to give the kernel about each cuboid read: `normal`,
+/// `sequential` or `willneed`.  First checks for environment variables.
+/// Then checks for values in the Rocket.toml file.
+pub fn get_cuboid_reads(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let defaults = ReadStrategy::default();
+    let mut errors = vec![];
+    let config = rocket.config();
+    let buffer_size = setting(
+        &mut errors,
+        config,
+        READ_BUFFER_SIZE_ENV_NAME,
+        READ_BUFFER_SIZE_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(defaults.buffer_size);
+    let advice = setting(
+        &mut errors,
+        config,
+        READ_ADVICE_ENV_NAME,
+        READ_ADVICE_ROCKET_CFG,
+        |val| {
+            ReadAdvice::parse(val)
+                .ok_or_else(|| "expected normal, sequential or willneed".to_string())
+        },
+    )
+    .unwrap_or(defaults.advice);
+    Ok(report(rocket, errors).manage(CuboidReads(ReadStrategy {
+        buffer_size,
+        advice,
+    })))
+}
+
+/// Whether cuboids fetched from the Boss DB host are cached.
+pub struct Writeback(pub bool);
+
+const WRITEBACK_ENV_NAME: &str = "WRITEBACK";
+const WRITEBACK_ROCKET_CFG: &str = "writeback";
+const WRITEBACK_DEFAULT: bool = true;
+
+/// Gets whether cuboids fetched upstream are written back to the cache.
+/// First checks for an environment variable.  Then checks for a value in
+/// the Rocket.toml file.
+pub fn get_writeback(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let writeback = setting(
+        &mut errors,
+        rocket.config(),
+        WRITEBACK_ENV_NAME,
+        WRITEBACK_ROCKET_CFG,
+        parse_flag,
+    )
+    .unwrap_or(WRITEBACK_DEFAULT);
+    Ok(report(rocket, errors).manage(Writeback(writeback)))
+}
+
+/// Which cuboids fetched from the Boss DB host are cached, with writeback
+/// on.  `recent_misses` is shared by every request, and only set for the
+/// `second_miss` policy.
+pub struct WritebackPolicy {
+    pub policy: String,
+    /// Seconds within which a cuboid must be missed twice to be cached.
+    pub window: u64,
+    pub recent_misses: Option<Arc<RecentMisses>>,
+}
+
+/// User string names for selecting writeback policies.
+pub const ALWAYS_WRITEBACK: &str = "always";
+pub const SECOND_MISS_WRITEBACK: &str = "second_miss";
+const WRITEBACK_POLICIES: [&str; 2] = [ALWAYS_WRITEBACK, SECOND_MISS_WRITEBACK];
+
+const WRITEBACK_POLICY_ENV_NAME: &str = "WRITEBACK_POLICY";
+const WRITEBACK_POLICY_ROCKET_CFG: &str = "writeback_policy";
+const WRITEBACK_POLICY_DEFAULT: &str = ALWAYS_WRITEBACK;
+const WRITEBACK_WINDOW_ENV_NAME: &str = "WRITEBACK_WINDOW";
+const WRITEBACK_WINDOW_ROCKET_CFG: &str = "writeback_window";
+const WRITEBACK_WINDOW_DEFAULT: u64 = 60 * 60;
+
+/// Gets the writeback policy, either `always` (cache every cuboid fetched
+/// upstream) or `second_miss` (cache a cuboid only once it's missed twice
+/// within the window), and the window in seconds.  First checks for
+/// environment variables.  Then checks for values in the Rocket.toml file.
+pub fn get_writeback_policy(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let policy = match env::var(WRITEBACK_POLICY_ENV_NAME) {
+        Ok(val) => val,
+        Err(_) => rocket
+            .config()
+            .get_str(WRITEBACK_POLICY_ROCKET_CFG)
+            .unwrap_or(WRITEBACK_POLICY_DEFAULT)
+            .to_string(),
+    }
+    .to_lowercase();
+    let mut errors = vec![];
+    let window = setting(
+        &mut errors,
+        rocket.config(),
+        WRITEBACK_WINDOW_ENV_NAME,
+        WRITEBACK_WINDOW_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(WRITEBACK_WINDOW_DEFAULT);
+    let recent_misses = if policy == SECOND_MISS_WRITEBACK {
+        Some(Arc::new(RecentMisses::new(Duration::from_secs(window))))
+    } else {
+        None
+    };
+    Ok(report(rocket, errors).manage(WritebackPolicy {
+        policy,
+        window,
+        recent_misses,
+    }))
+}
+
+/// Parse a boolean from an environment variable.  Accepts the usual
+/// spellings (`true`/`false`, `1`/`0`, `yes`/`no`).
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" => Some(true),
+        "false" | "0" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse a boolean setting, as `parse_bool` does.
+fn parse_flag(value: &str) -> Result<bool, String> {
+    parse_bool(value).ok_or_else(|| "expected true or false".to_string())
+}
+
+/// Parse a numeric setting.
+fn parse_number<T: FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|e: T::Err| e.to_string())
+}
+
+/// Settings that are set to values that can't be parsed.  The getters
+/// record them here, taking the setting's default meanwhile, and
+/// `validate` reports them and aborts the launch.
+#[derive(Default)]
+pub struct ConfigErrors(pub Mutex<Vec<String>>);
+
+/// Record the errors from reading settings, for `validate` to report.
+fn report(rocket: Rocket, errors: Vec<String>) -> Rocket {
+    if errors.is_empty() {
+        return rocket;
+    }
+    match rocket.state::<ConfigErrors>() {
+        Some(reported) => {
+            reported.0.lock().unwrap().extend(errors);
+            rocket
+        }
+        None => rocket.manage(ConfigErrors(Mutex::new(errors))),
+    }
+}
+
+/// The value of a setting, if it's set: its environment variable, or else
+/// its value in the Rocket.toml file.
+fn read_setting(config: &Config, env_name: &str, rocket_cfg: &str) -> Option<String> {
+    match env::var(env_name) {
+        Ok(val) => Some(val),
+        Err(_) => config.extras.get(rocket_cfg).map(|v| match v {
+            Value::String(val) => val.clone(),
+            v => v.to_string(),
+        }),
+    }
+}
+
+/// Parse a setting, if it's set.  Fails, naming the setting, if it's set
+/// to a value that `parse` rejects.
+fn parse_setting<T, F>(
+    config: &Config,
+    env_name: &str,
+    rocket_cfg: &str,
+    parse: F,
+) -> Result<Option<T>, String>
+where
+    F: FnOnce(&str) -> Result<T, String>,
+{
+    match read_setting(config, env_name, rocket_cfg) {
+        Some(val) => parse(val.trim())
+            .map(Some)
+            .map_err(|e| format!("Invalid {} \"{}\": {}", rocket_cfg, val, e)),
+        None => Ok(None),
+    }
+}
+
+/// Parse a setting, if it's set, as `parse_setting` does.  If it's
+/// invalid, the error is added to `errors` and it's treated as unset.
+fn setting<T, F>(
+    errors: &mut Vec<String>,
+    config: &Config,
+    env_name: &str,
+    rocket_cfg: &str,
+    parse: F,
+) -> Option<T>
+where
+    F: FnOnce(&str) -> Result<T, String>,
+{
+    parse_setting(config, env_name, rocket_cfg, parse).unwrap_or_else(|e| {
+        errors.push(e);
+        None
+    })
+}
+
+/// Max number of cuboids kept in the cache.
+pub struct MaxCuboids(pub u32);
+
+const MAX_CUBOIDS_ENV_NAME: &str = "MAX_CUBOIDS";
+const MAX_CUBOIDS_ROCKET_CFG: &str = "max_cuboids";
+const MAX_CUBOIDS_DEFAULT: u32 = 1000;
+
+/// Gets the max number of cuboids kept in the cache, past which the usage
+/// tracker evicts them.  First checks for an environment variable.  Then
+/// checks for a value in the Rocket.toml file.
+pub fn get_max_cuboids(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let (rocket, max_cuboids) = match read_max_cuboids(rocket.config()) {
+        Ok(val) => (rocket, val),
+        Err(e) => (report(rocket, vec![e]), MAX_CUBOIDS_DEFAULT),
+    };
+    Ok(rocket.manage(MaxCuboids(max_cuboids)))
+}
+
+fn read_max_cuboids(config: &Config) -> Result<u32, String> {
+    let val = parse_setting(
+        config,
+        MAX_CUBOIDS_ENV_NAME,
+        MAX_CUBOIDS_ROCKET_CFG,
+        parse_number,
+    )?;
+    Ok(val.unwrap_or(MAX_CUBOIDS_DEFAULT))
+}
+
+/// Seconds a cuboid is protected from eviction after it's created or
+/// accessed.
+pub struct MinResidency(pub u32);
+
+const MIN_RESIDENCY_ENV_NAME: &str = "MIN_RESIDENCY";
+const MIN_RESIDENCY_ROCKET_CFG: &str = "min_residency";
+const MIN_RESIDENCY_DEFAULT: u32 = 0;
+
+/// Gets the eviction grace period.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_min_residency(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let (rocket, min_residency) = match read_min_residency(rocket.config()) {
+        Ok(val) => (rocket, val),
+        Err(e) => (report(rocket, vec![e]), MIN_RESIDENCY_DEFAULT),
+    };
+    Ok(rocket.manage(MinResidency(min_residency)))
+}
+
+fn read_min_residency(config: &Config) -> Result<u32, String> {
+    let val = parse_setting(
+        config,
+        MIN_RESIDENCY_ENV_NAME,
+        MIN_RESIDENCY_ROCKET_CFG,
+        parse_number,
+    )?;
+    Ok(val.unwrap_or(MIN_RESIDENCY_DEFAULT))
+}
+
+/// How the usage tracker picks cuboids to evict.
+pub struct Eviction(pub String);
+
+/// User string names for selecting eviction strategies.
+pub const LRU_EVICTION: &str = "lru";
+pub const DECAY_EVICTION: &str = "decay";
+const EVICTIONS: [&str; 2] = [LRU_EVICTION, DECAY_EVICTION];
+
+const EVICTION_ENV_NAME: &str = "EVICTION";
+const EVICTION_ROCKET_CFG: &str = "eviction";
+const EVICTION_DEFAULT: &str = LRU_EVICTION;
+
+/// Gets the eviction strategy to use.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_eviction(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let eviction = read_eviction(rocket.config());
+    Ok(rocket.manage(Eviction(eviction)))
+}
+
+fn read_eviction(config: &Config) -> String {
+    let eviction = match env::var(EVICTION_ENV_NAME) {
+        Ok(val) => val,
+        Err(_) => config
+            .get_str(EVICTION_ROCKET_CFG)
+            .unwrap_or(EVICTION_DEFAULT)
+            .to_string(),
+    };
+    eviction.to_lowercase()
+}
+
+/// How to answer a cutout download whose `Accept` header matches none of
+/// the supported formats.
+pub struct FormatFallback(pub String);
+
+/// User string names for selecting format fallbacks.
+pub const REJECT_FALLBACK: &str = "reject";
+pub const BLOSC_FALLBACK: &str = "blosc";
+const FORMAT_FALLBACKS: [&str; 2] = [REJECT_FALLBACK, BLOSC_FALLBACK];
+
+const FORMAT_FALLBACK_ENV_NAME: &str = "FORMAT_FALLBACK";
+const FORMAT_FALLBACK_ROCKET_CFG: &str = "format_fallback";
+const FORMAT_FALLBACK_DEFAULT: &str = REJECT_FALLBACK;
+
+/// Gets the format fallback, either `reject` (with a 406 listing the
+/// supported formats) or `blosc`.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_format_fallback(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let format_fallback: String;
+    match env::var(FORMAT_FALLBACK_ENV_NAME) {
+        Ok(val) => format_fallback = val,
+        Err(_) => {
+            format_fallback = rocket
+                .config()
+                .get_str(FORMAT_FALLBACK_ROCKET_CFG)
+                .unwrap_or(FORMAT_FALLBACK_DEFAULT)
+                .to_string();
+        }
+    }
+    Ok(rocket.manage(FormatFallback(format_fallback.to_lowercase())))
+}
+
+/// Format of cutout downloads whose `Accept` header doesn't name one, e.g.
+/// with no header or `*/*`.
+pub struct DefaultFormat(pub String);
+
+/// User string names for selecting the default format.
+const DEFAULT_FORMATS: [&str; 5] = ["blosc", "jpeg", "json", "npy", "raw"];
+
+const DEFAULT_FORMAT_ENV_NAME: &str = "DEFAULT_FORMAT";
+const DEFAULT_FORMAT_ROCKET_CFG: &str = "default_format";
+const DEFAULT_FORMAT_DEFAULT: &str = "blosc";
+
+/// Gets the default download format: `blosc`, `jpeg`, `json`, `npy` or
+/// `raw`.
+/// First checks for an environment variable.  Then checks for a value in
+/// the Rocket.toml file.
+pub fn get_default_format(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let default_format: String;
+    match env::var(DEFAULT_FORMAT_ENV_NAME) {
+        Ok(val) => default_format = val,
+        Err(_) => {
+            default_format = rocket
+                .config()
+                .get_str(DEFAULT_FORMAT_ROCKET_CFG)
+                .unwrap_or(DEFAULT_FORMAT_DEFAULT)
+                .to_string();
+        }
+    }
+    Ok(rocket.manage(DefaultFormat(default_format.trim().to_lowercase())))
+}
+
+/// Quality of JPEG cutouts that don't ask for one, from 1 to 100.
+pub struct JpegQuality(pub u8);
+
+const JPEG_QUALITY_ENV_NAME: &str = "JPEG_QUALITY";
+const JPEG_QUALITY_ROCKET_CFG: &str = "jpeg_quality";
+const JPEG_QUALITY_DEFAULT: u8 = 75;
+
+/// Gets the default JPEG quality, clamped to 1-100.  First checks for an
+/// environment variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_jpeg_quality(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let quality = setting(
+        &mut errors,
+        rocket.config(),
+        JPEG_QUALITY_ENV_NAME,
+        JPEG_QUALITY_ROCKET_CFG,
+        parse_quality,
+    )
+    .unwrap_or(JPEG_QUALITY_DEFAULT);
+    Ok(report(rocket, errors).manage(JpegQuality(quality)))
+}
+
+/// Max bytes of an encoded JPEG filmstrip held in memory while it's sent;
+/// larger ones are spooled to a temp file.
+pub struct JpegSpoolSize(pub usize);
+
+const JPEG_SPOOL_SIZE_ENV_NAME: &str = "JPEG_SPOOL_SIZE";
+const JPEG_SPOOL_SIZE_ROCKET_CFG: &str = "jpeg_spool_size";
+const JPEG_SPOOL_SIZE_DEFAULT: usize = 16 * 1024 * 1024;
+
+/// Gets how large an encoded JPEG filmstrip may grow in memory before it's
+/// moved to a temp file.  First checks for an environment variable.  Then
+/// checks for a value in the Rocket.toml file.
+pub fn get_jpeg_spool_size(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let size = setting(
+        &mut errors,
+        rocket.config(),
+        JPEG_SPOOL_SIZE_ENV_NAME,
+        JPEG_SPOOL_SIZE_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(JPEG_SPOOL_SIZE_DEFAULT);
+    Ok(report(rocket, errors).manage(JpegSpoolSize(size)))
+}
+
+/// Seconds for a cuboid's score to halve under the `decay` eviction
+/// strategy.
+pub struct DecayHalfLife(pub u32);
+
+const DECAY_HALF_LIFE_ENV_NAME: &str = "DECAY_HALF_LIFE";
+const DECAY_HALF_LIFE_ROCKET_CFG: &str = "decay_half_life";
+const DECAY_HALF_LIFE_DEFAULT: u32 = 24 * 60 * 60;
+
+/// Gets the half-life of the `decay` eviction strategy.  First checks for
+/// an environment variable.  Then checks for a value in the Rocket.toml
+/// file.
+pub fn get_decay_half_life(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let (rocket, decay_half_life) = match read_decay_half_life(rocket.config()) {
+        Ok(val) => (rocket, val),
+        Err(e) => (report(rocket, vec![e]), DECAY_HALF_LIFE_DEFAULT),
+    };
+    Ok(rocket.manage(DecayHalfLife(decay_half_life)))
+}
+
+fn read_decay_half_life(config: &Config) -> Result<u32, String> {
+    let val = parse_setting(
+        config,
+        DECAY_HALF_LIFE_ENV_NAME,
+        DECAY_HALF_LIFE_ROCKET_CFG,
+        parse_number,
+    )?;
+    Ok(val.unwrap_or(DECAY_HALF_LIFE_DEFAULT))
+}
+
+/// Max seconds the usage tracker puts off cleaning the cache once it's
+/// over its limit.
+pub struct EvictionJitter(pub u32);
+
+const EVICTION_JITTER_ENV_NAME: &str = "EVICTION_JITTER";
+const EVICTION_JITTER_ROCKET_CFG: &str = "eviction_jitter";
+const EVICTION_JITTER_DEFAULT: u32 = 0;
+
+/// Gets the max random delay before cleaning, which keeps servers sharing
+/// a cache from all cleaning it at once.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_eviction_jitter(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let (rocket, eviction_jitter) = match read_eviction_jitter(rocket.config()) {
+        Ok(val) => (rocket, val),
+        Err(e) => (report(rocket, vec![e]), EVICTION_JITTER_DEFAULT),
+    };
+    Ok(rocket.manage(EvictionJitter(eviction_jitter)))
+}
+
+fn read_eviction_jitter(config: &Config) -> Result<u32, String> {
+    let val = parse_setting(
+        config,
+        EVICTION_JITTER_ENV_NAME,
+        EVICTION_JITTER_ROCKET_CFG,
+        parse_number,
+    )?;
+    Ok(val.unwrap_or(EVICTION_JITTER_DEFAULT))
+}
+
+/// Retries of evicted cuboids whose file or DB row fails to be removed.
+pub struct EvictionRetry(pub RemovalRetry);
+
+const EVICTION_RETRIES_ENV_NAME: &str = "EVICTION_RETRIES";
+const EVICTION_RETRIES_ROCKET_CFG: &str = "eviction_retries";
+const EVICTION_RETRY_BACKOFF_ENV_NAME: &str = "EVICTION_RETRY_BACKOFF";
+const EVICTION_RETRY_BACKOFF_ROCKET_CFG: &str = "eviction_retry_backoff";
+
+/// Gets how many times to retry a failed removal while evicting, and the
+/// milliseconds to wait before the first retry.  First checks for
+/// environment variables.  Then checks for values in the Rocket.toml file.
+pub fn get_eviction_retry(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let defaults = RemovalRetry::default();
+    let mut errors = vec![];
+    let config = rocket.config();
+    let retries = setting(
+        &mut errors,
+        config,
+        EVICTION_RETRIES_ENV_NAME,
+        EVICTION_RETRIES_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(defaults.retries);
+    let backoff = setting(
+        &mut errors,
+        config,
+        EVICTION_RETRY_BACKOFF_ENV_NAME,
+        EVICTION_RETRY_BACKOFF_ROCKET_CFG,
+        parse_number,
+    )
+    .map_or(defaults.backoff, Duration::from_millis);
+    Ok(report(rocket, errors).manage(EvictionRetry(RemovalRetry { retries, backoff })))
+}
+
+/// Evict cuboids whose files are empty before any others.
+pub struct EvictEmptyFirst(pub bool);
+
+const EVICT_EMPTY_FIRST_ENV_NAME: &str = "EVICT_EMPTY_FIRST";
+const EVICT_EMPTY_FIRST_ROCKET_CFG: &str = "evict_empty_first";
+pub const EVICT_EMPTY_FIRST_DEFAULT: bool = false;
+
+/// Gets whether empty cuboid files are evicted first.  First checks for an
+/// environment variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_evict_empty_first(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let evict_empty_first = setting(
+        &mut errors,
+        rocket.config(),
+        EVICT_EMPTY_FIRST_ENV_NAME,
+        EVICT_EMPTY_FIRST_ROCKET_CFG,
+        parse_flag,
+    )
+    .unwrap_or(EVICT_EMPTY_FIRST_DEFAULT);
+    Ok(report(rocket, errors).manage(EvictEmptyFirst(evict_empty_first)))
+}
+
+/// How often the usage tracker logs the size of the cache, if at all.
+pub struct CacheSizeReport(pub Option<Duration>);
+
+const CACHE_SIZE_REPORT_ENV_NAME: &str = "CACHE_SIZE_REPORT";
+const CACHE_SIZE_REPORT_ROCKET_CFG: &str = "cache_size_report";
+const CACHE_SIZE_REPORT_DEFAULT: bool = false;
+const CACHE_SIZE_REPORT_INTERVAL_ENV_NAME: &str = "CACHE_SIZE_REPORT_INTERVAL";
+const CACHE_SIZE_REPORT_INTERVAL_ROCKET_CFG: &str = "cache_size_report_interval";
+const CACHE_SIZE_REPORT_INTERVAL_DEFAULT: u64 = 15 * 60;
+
+/// Gets whether the usage tracker periodically logs the size of the cache,
+/// and the seconds between reports.  First checks for environment
+/// variables.  Then checks for values in the Rocket.toml file.
+pub fn get_cache_size_report(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let config = rocket.config();
+    let enabled = setting(
+        &mut errors,
+        config,
+        CACHE_SIZE_REPORT_ENV_NAME,
+        CACHE_SIZE_REPORT_ROCKET_CFG,
+        parse_flag,
+    )
+    .unwrap_or(CACHE_SIZE_REPORT_DEFAULT);
+    let interval: u64 = setting(
+        &mut errors,
+        config,
+        CACHE_SIZE_REPORT_INTERVAL_ENV_NAME,
+        CACHE_SIZE_REPORT_INTERVAL_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(CACHE_SIZE_REPORT_INTERVAL_DEFAULT);
+    let report_every = if enabled {
+        Some(Duration::from_secs(interval.max(1)))
+    } else {
+        None
+    };
+    Ok(report(rocket, errors).manage(CacheSizeReport(report_every)))
+}
+
+/// Times the usage tracker is restarted after failing before it's given up
+/// on, leaving the cache untracked (see `usage_tracker::is_down()`).
+pub struct TrackerRestarts(pub u32);
+
+const TRACKER_RESTARTS_ENV_NAME: &str = "USAGE_TRACKER_RESTARTS";
+const TRACKER_RESTARTS_ROCKET_CFG: &str = "usage_tracker_restarts";
+const TRACKER_RESTARTS_DEFAULT: u32 = 3;
+
+/// Gets how many times the usage tracker may be restarted.  First checks
+/// for an environment variable.  Then checks for a value in the Rocket.toml
+/// file.
+pub fn get_tracker_restarts(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let restarts = setting(
+        &mut errors,
+        rocket.config(),
+        TRACKER_RESTARTS_ENV_NAME,
+        TRACKER_RESTARTS_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(TRACKER_RESTARTS_DEFAULT);
+    Ok(report(rocket, errors).manage(TrackerRestarts(restarts)))
+}
+
+/// Free bytes on the cache's disks below which caching is paused (see
+/// `disk_guard`), and how often they're checked.  0 bytes turns the check
+/// off.
+pub struct DiskSpaceGuard {
+    pub min_free: u64,
+    pub interval: Duration,
+}
+
+const MIN_FREE_DISK_ENV_NAME: &str = "MIN_FREE_DISK";
+const MIN_FREE_DISK_ROCKET_CFG: &str = "min_free_disk";
+const MIN_FREE_DISK_DEFAULT: u64 = 0;
+const DISK_CHECK_INTERVAL_ENV_NAME: &str = "DISK_CHECK_INTERVAL";
+const DISK_CHECK_INTERVAL_ROCKET_CFG: &str = "disk_check_interval";
+const DISK_CHECK_INTERVAL_DEFAULT: u64 = 30;
+
+/// Gets the free bytes on the cache's disks below which caching is paused,
+/// and the seconds between checks.  First checks for environment
+/// variables.  Then checks for values in the Rocket.toml file.
+pub fn get_disk_space_guard(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let config = rocket.config();
+    let min_free = setting(
+        &mut errors,
+        config,
+        MIN_FREE_DISK_ENV_NAME,
+        MIN_FREE_DISK_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(MIN_FREE_DISK_DEFAULT);
+    let interval: u64 = setting(
+        &mut errors,
+        config,
+        DISK_CHECK_INTERVAL_ENV_NAME,
+        DISK_CHECK_INTERVAL_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(DISK_CHECK_INTERVAL_DEFAULT);
+    Ok(report(rocket, errors).manage(DiskSpaceGuard {
+        min_free,
+        interval: Duration::from_secs(interval.max(1)),
+    }))
+}
+
+/// How long uploads of part of a cuboid are buffered, to be written out
+/// together (see `write_buffer`), and how many cuboids may be buffered at
+/// once.  A zero window turns buffering off.
+pub struct WriteBufferSettings {
+    pub window: Duration,
+    pub max_cuboids: usize,
+}
+
+const WRITE_BUFFER_WINDOW_ENV_NAME: &str = "WRITE_BUFFER_WINDOW";
+const WRITE_BUFFER_WINDOW_ROCKET_CFG: &str = "write_buffer_window";
+const WRITE_BUFFER_WINDOW_DEFAULT: u64 = 0;
+const WRITE_BUFFER_MAX_CUBOIDS_ENV_NAME: &str = "WRITE_BUFFER_MAX_CUBOIDS";
+const WRITE_BUFFER_MAX_CUBOIDS_ROCKET_CFG: &str = "write_buffer_max_cuboids";
+const WRITE_BUFFER_MAX_CUBOIDS_DEFAULT: usize = 256;
+
+/// Gets the milliseconds that partial cuboid writes are buffered for, and
+/// the max number of cuboids buffered.  First checks for environment
+/// variables.  Then checks for values in the Rocket.toml file.
+pub fn get_write_buffer(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let config = rocket.config();
+    let window = setting(
+        &mut errors,
+        config,
+        WRITE_BUFFER_WINDOW_ENV_NAME,
+        WRITE_BUFFER_WINDOW_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(WRITE_BUFFER_WINDOW_DEFAULT);
+    let max_cuboids: usize = setting(
+        &mut errors,
+        config,
+        WRITE_BUFFER_MAX_CUBOIDS_ENV_NAME,
+        WRITE_BUFFER_MAX_CUBOIDS_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(WRITE_BUFFER_MAX_CUBOIDS_DEFAULT);
+    Ok(report(rocket, errors).manage(WriteBufferSettings {
+        window: Duration::from_millis(window),
+        max_cuboids: max_cuboids.max(1),
+    }))
+}
+
+/// Format version of newly written cuboid files (see `cuboid_file`).
+pub struct CuboidFormat(pub u16);
+
+const CUBOID_FORMAT_ENV_NAME: &str = "CUBOID_FORMAT";
+const CUBOID_FORMAT_ROCKET_CFG: &str = "cuboid_format";
+const CUBOID_FORMAT_DEFAULT: u16 = cuboid_file::CURRENT_VERSION;
+
+/// Gets the format version of newly written cuboid files.  First checks
+/// for an environment variable.  Then checks for a value in the
+/// Rocket.toml file.
+pub fn get_cuboid_format(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let version = setting(
+        &mut errors,
+        rocket.config(),
+        CUBOID_FORMAT_ENV_NAME,
+        CUBOID_FORMAT_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(CUBOID_FORMAT_DEFAULT);
+    Ok(report(rocket, errors).manage(CuboidFormat(version)))
+}
+
+/// Cache roots for particular resolutions, in place of CUBOID_ROOT_PATH
+/// (e.g. to keep res 0 on a large disk and downsampled levels on SSD).
+pub struct ResolutionRoots(pub HashMap<u8, String>);
+
+const RESOLUTION_ROOTS_ENV_NAME: &str = "RESOLUTION_ROOTS";
+const RESOLUTION_ROOTS_ROCKET_CFG: &str = "resolution_roots";
+
+/// Gets the per-resolution cache roots, as a spec like
+/// `0=/mnt/big/cache,1=/mnt/ssd/cache`.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.  Every
+/// resolution uses CUBOID_ROOT_PATH by default.
+pub fn get_resolution_roots(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let roots = setting(
+        &mut errors,
+        rocket.config(),
+        RESOLUTION_ROOTS_ENV_NAME,
+        RESOLUTION_ROOTS_ROCKET_CFG,
+        parse_resolution_roots,
+    )
+    .unwrap_or_else(HashMap::new);
+    Ok(report(rocket, errors).manage(ResolutionRoots(roots)))
+}
+
+/// Parse a resolution roots spec like `0=/mnt/big/cache,1=/mnt/ssd/cache`.
+fn parse_resolution_roots(spec: &str) -> Result<HashMap<u8, String>, String> {
+    let mut roots = HashMap::new();
+    for entry in spec.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let i = entry
+            .find('=')
+            .ok_or_else(|| format!("{} is not res=path", entry))?;
+        let res = entry[..i]
+            .trim()
+            .parse::<u8>()
+            .map_err(|_| format!("{} is not a resolution", entry[..i].trim()))?;
+        let root = entry[i + 1..].trim().trim_end_matches('/');
+        if root.is_empty() {
+            return Err(format!("No path for resolution {}", res));
+        }
+        roots.insert(res, root.to_string());
+    }
+    Ok(roots)
+}
+
+/// Cuboid sizes of channels that don't use CUBOID_SIZE, keyed by
+/// `collection/experiment/channel`.
+pub struct ChannelCuboidSizes(pub HashMap<String, Vector3>);
+
+const CHANNEL_CUBOID_SIZES_ENV_NAME: &str = "CHANNEL_CUBOID_SIZES";
+const CHANNEL_CUBOID_SIZES_ROCKET_CFG: &str = "channel_cuboid_sizes";
+
+/// Gets the per-channel cuboid sizes, as a spec like
+/// `col/exp/chan=256:256:16`.  First checks for an environment variable.
+/// Then checks for a value in the Rocket.toml file.  Every channel uses
+/// CUBOID_SIZE by default.
+pub fn get_channel_cuboid_sizes(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let sizes = setting(
+        &mut errors,
+        rocket.config(),
+        CHANNEL_CUBOID_SIZES_ENV_NAME,
+        CHANNEL_CUBOID_SIZES_ROCKET_CFG,
+        parse_channel_cuboid_sizes,
+    )
+    .unwrap_or_else(HashMap::new);
+    Ok(report(rocket, errors).manage(ChannelCuboidSizes(sizes)))
+}
+
+/// Parse a channel cuboid size spec like `col/exp/chan=256:256:16`, with
+/// sizes given as `x:y:z`.
+fn parse_channel_cuboid_sizes(spec: &str) -> Result<HashMap<String, Vector3>, String> {
+    let mut sizes = HashMap::new();
+    for entry in spec.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let i = entry
+            .find('=')
+            .ok_or_else(|| format!("{} is not channel=x:y:z", entry))?;
+        let channel = entry[..i].trim();
+        if channel.split('/').count() != 3 {
+            return Err(format!("{} is not collection/experiment/channel", channel));
+        }
+        let dims = entry[i + 1..]
+            .split(':')
+            .map(|d| d.trim().parse::<u64>().ok().filter(|d| *d > 0))
+            .collect::<Option<Vec<u64>>>()
+            .filter(|dims| dims.len() == 3)
+            .ok_or_else(|| format!("{} is not a cuboid size x:y:z", entry[i + 1..].trim()))?;
+        sizes.insert(
+            channel.to_string(),
+            Vector3 {
+                x: dims[0],
+                y: dims[1],
+                z: dims[2],
+            },
+        );
+    }
+    Ok(sizes)
+}
+
+/// Shared cap on concurrent upstream BossDB requests.
+pub struct UpstreamLimit(pub Arc<Semaphore>);
+
+const UPSTREAM_CONCURRENCY_ENV_NAME: &str = "UPSTREAM_CONCURRENCY";
+const UPSTREAM_CONCURRENCY_ROCKET_CFG: &str = "upstream_concurrency";
+const UPSTREAM_CONCURRENCY_DEFAULT: usize = 4;
+
+/// Gets the max number of concurrent upstream requests.  First checks for
+/// an environment variable.  Then checks for a value in the Rocket.toml
+/// file.
+pub fn get_upstream_limit(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let limit: usize = setting(
+        &mut errors,
+        rocket.config(),
+        UPSTREAM_CONCURRENCY_ENV_NAME,
+        UPSTREAM_CONCURRENCY_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(UPSTREAM_CONCURRENCY_DEFAULT);
+    // A limit of zero would deadlock every cache miss.
+    let limit = limit.max(1);
+    Ok(report(rocket, errors).manage(UpstreamLimit(Arc::new(Semaphore::new(limit)))))
+}
+
+/// Shared cap on cuboid files open at once.
+pub struct FileLimit(pub Arc<Semaphore>);
+
+const MAX_OPEN_CUBOIDS_ENV_NAME: &str = "MAX_OPEN_CUBOIDS";
+const MAX_OPEN_CUBOIDS_ROCKET_CFG: &str = "max_open_cuboids";
+const MAX_OPEN_CUBOIDS_DEFAULT: usize = 256;
+
+/// Gets the max number of cuboid files open at once.  First checks for an
+/// environment variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_file_limit(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let limit: usize = setting(
+        &mut errors,
+        rocket.config(),
+        MAX_OPEN_CUBOIDS_ENV_NAME,
+        MAX_OPEN_CUBOIDS_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(MAX_OPEN_CUBOIDS_DEFAULT);
+    // A limit of zero would deadlock every read.
+    let limit = limit.max(1);
+    Ok(report(rocket, errors).manage(FileLimit(Arc::new(Semaphore::new(limit)))))
+}
+
+/// Budget, in bytes, shared by the buffers of the cutouts being served at
+/// once.  `None` when unlimited.
+pub struct CutoutMemory(pub Option<Arc<Semaphore>>);
+
+const CUTOUT_MEMORY_BUDGET_ENV_NAME: &str = "CUTOUT_MEMORY_BUDGET";
+const CUTOUT_MEMORY_BUDGET_ROCKET_CFG: &str = "cutout_memory_budget";
+const CUTOUT_MEMORY_BUDGET_DEFAULT: u64 = 0;
+
+/// Gets the memory budget for cutout buffers, in bytes (0 for unlimited).
+/// First checks for an environment variable.  Then checks for a value in
+/// the Rocket.toml file.
+pub fn get_cutout_memory(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let budget = setting(
+        &mut errors,
+        rocket.config(),
+        CUTOUT_MEMORY_BUDGET_ENV_NAME,
+        CUTOUT_MEMORY_BUDGET_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(CUTOUT_MEMORY_BUDGET_DEFAULT);
+    let memory = match budget {
+        0 => None,
+        budget => Some(Arc::new(Semaphore::new(budget as usize))),
+    };
+    Ok(report(rocket, errors).manage(CutoutMemory(memory)))
+}
+
+/// Max number of cuboids a single cutout may touch.  `None` when
+/// unlimited.
+pub struct MaxRequestCuboids(pub Option<u64>);
+
+const MAX_REQUEST_CUBOIDS_ENV_NAME: &str = "MAX_REQUEST_CUBOIDS";
+const MAX_REQUEST_CUBOIDS_ROCKET_CFG: &str = "max_request_cuboids";
+const MAX_REQUEST_CUBOIDS_DEFAULT: u64 = 0;
+
+/// Gets the max number of cuboids a single cutout may touch (0 for
+/// unlimited).  First checks for an environment variable.  Then checks for
+/// a value in the Rocket.toml file.
+pub fn get_max_request_cuboids(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let max: u64 = setting(
+        &mut errors,
+        rocket.config(),
+        MAX_REQUEST_CUBOIDS_ENV_NAME,
+        MAX_REQUEST_CUBOIDS_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(MAX_REQUEST_CUBOIDS_DEFAULT);
+    Ok(report(rocket, errors).manage(MaxRequestCuboids(match max {
+        0 => None,
+        max => Some(max),
+    })))
+}
+
+/// Permission bits for the directories and files created in the cache.
+pub struct CacheModes(pub Modes);
+
+const CACHE_DIR_MODE_ENV_NAME: &str = "CACHE_DIR_MODE";
+const CACHE_DIR_MODE_ROCKET_CFG: &str = "cache_dir_mode";
+const CACHE_FILE_MODE_ENV_NAME: &str = "CACHE_FILE_MODE";
+const CACHE_FILE_MODE_ROCKET_CFG: &str = "cache_file_mode";
+
+/// Directory mode used by `abs_path()`, or `NO_MODE` to leave it to the
+/// umask.  Set by `get_cache_modes()`.
+static DIR_MODE: AtomicU32 = AtomicU32::new(NO_MODE);
+const NO_MODE: u32 = u32::MAX;
+
+/// Gets the permission bits, in octal, for the directories and files
+/// created in the cache (e.g. `2770` and `640` to share it with a group).
+/// First checks for environment variables.  Then checks for values in the
+/// Rocket.toml file.  Unset modes are left to the umask.  Only applied on
+/// Unix.
+pub fn get_cache_modes(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let config = rocket.config();
+    let modes = Modes {
+        dir: setting(
+            &mut errors,
+            config,
+            CACHE_DIR_MODE_ENV_NAME,
+            CACHE_DIR_MODE_ROCKET_CFG,
+            parse_mode,
+        )
+        .and_then(|mode| mode),
+        file: setting(
+            &mut errors,
+            config,
+            CACHE_FILE_MODE_ENV_NAME,
+            CACHE_FILE_MODE_ROCKET_CFG,
+            parse_mode,
+        )
+        .and_then(|mode| mode),
+    };
+    DIR_MODE.store(modes.dir.unwrap_or(NO_MODE), Ordering::Relaxed);
+    Ok(report(rocket, errors).manage(CacheModes(modes)))
+}
+
+/// Read one octal mode setting, ignoring it if it's invalid.
+/// Parse one octal mode setting.  An empty one is left to the umask.  A
+/// number in the Rocket.toml file is read as octal digits too.
+fn parse_mode(value: &str) -> Result<Option<u32>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    cuboid_file::parse_mode(value).map(Some)
+}
+
+/// The configured directory mode, if any.
+fn dir_mode() -> Option<u32> {
+    match DIR_MODE.load(Ordering::Relaxed) {
+        NO_MODE => None,
+        mode => Some(mode),
+    }
+}
+
+/// Channels recently reported not to exist upstream, shared by every
+/// request.
+pub struct NotFoundCache(pub Arc<NotFoundChannels>);
+
+const NOT_FOUND_TTL_ENV_NAME: &str = "NOT_FOUND_TTL";
+const NOT_FOUND_TTL_ROCKET_CFG: &str = "not_found_ttl";
+const NOT_FOUND_TTL_DEFAULT: u64 = 0;
+
+/// Gets how many seconds a channel that doesn't exist upstream is answered
+/// with a 404 without asking upstream again.  First checks for an
+/// environment variable.  Then checks for a value in the Rocket.toml file.
+/// Zero always asks upstream.
+pub fn get_not_found_ttl(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let (rocket, not_found_ttl) = match read_not_found_ttl(rocket.config()) {
+        Ok(val) => (rocket, val),
+        Err(e) => (report(rocket, vec![e]), NOT_FOUND_TTL_DEFAULT),
+    };
+    Ok(rocket.manage(NotFoundCache(Arc::new(NotFoundChannels::new(
+        Duration::from_secs(not_found_ttl),
+    )))))
+}
+
+fn read_not_found_ttl(config: &Config) -> Result<u64, String> {
+    let val = parse_setting(
+        config,
+        NOT_FOUND_TTL_ENV_NAME,
+        NOT_FOUND_TTL_ROCKET_CFG,
+        parse_number,
+    )?;
+    Ok(val.unwrap_or(NOT_FOUND_TTL_DEFAULT))
+}
+
+/// Seconds a cached cuboid is served before it's revalidated against the
+/// Boss DB host; `0` never revalidates.
+pub struct CuboidMaxAge(pub u64);
+
+const CUBOID_MAX_AGE_ENV_NAME: &str = "CUBOID_MAX_AGE";
+const CUBOID_MAX_AGE_ROCKET_CFG: &str = "cuboid_max_age";
+const CUBOID_MAX_AGE_DEFAULT: u64 = 0;
+
+/// Gets how many seconds after a cuboid is cached it's re-fetched from the
+/// Boss DB host, so changes there are picked up eventually.  First checks
+/// for an environment variable.  Then checks for a value in the Rocket.toml
+/// file.
+pub fn get_cuboid_max_age(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let max_age = setting(
+        &mut errors,
+        rocket.config(),
+        CUBOID_MAX_AGE_ENV_NAME,
+        CUBOID_MAX_AGE_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(CUBOID_MAX_AGE_DEFAULT);
+    Ok(report(rocket, errors).manage(CuboidMaxAge(max_age)))
+}
+
+/// Number of connections to the cache DB shared by the request handlers and
+/// the usage tracker.
+pub struct DbPoolSize(pub u32);
+
+const DB_POOL_SIZE_ENV_NAME: &str = "DB_POOL_SIZE";
+const DB_POOL_SIZE_ROCKET_CFG: &str = "db_pool_size";
+const DB_POOL_SIZE_DEFAULT: u32 = 4;
+
+/// Gets the number of cache DB connections.  First checks for an
+/// environment variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_db_pool_size(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let size: u32 = setting(
+        &mut errors,
+        rocket.config(),
+        DB_POOL_SIZE_ENV_NAME,
+        DB_POOL_SIZE_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(DB_POOL_SIZE_DEFAULT);
+    Ok(report(rocket, errors).manage(DbPoolSize(size.max(1))))
+}
+
+/// How long a cache DB connection waits on a locked DB, in ms.
+pub struct DbBusyTimeout(pub u32);
+
+const DB_BUSY_TIMEOUT_ENV_NAME: &str = "DB_BUSY_TIMEOUT";
+const DB_BUSY_TIMEOUT_ROCKET_CFG: &str = "db_busy_timeout";
+const DB_BUSY_TIMEOUT_DEFAULT: u32 = 5000;
+
+/// Gets the cache DB's busy timeout.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_db_busy_timeout(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let timeout = setting(
+        &mut errors,
+        rocket.config(),
+        DB_BUSY_TIMEOUT_ENV_NAME,
+        DB_BUSY_TIMEOUT_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(DB_BUSY_TIMEOUT_DEFAULT);
+    Ok(report(rocket, errors).manage(DbBusyTimeout(timeout)))
+}
+
+/// Retries of opening the cache DB at startup.
+pub struct DbConnectRetry(pub ConnectRetry);
+
+const DB_CONNECT_RETRIES_ENV_NAME: &str = "DB_CONNECT_RETRIES";
+const DB_CONNECT_RETRIES_ROCKET_CFG: &str = "db_connect_retries";
+const DB_CONNECT_RETRY_BACKOFF_ENV_NAME: &str = "DB_CONNECT_RETRY_BACKOFF";
+const DB_CONNECT_RETRY_BACKOFF_ROCKET_CFG: &str = "db_connect_retry_backoff";
+
+/// Gets how many times to retry opening the cache DB, and the milliseconds
+/// to wait before the first retry.  First checks for environment
+/// variables.  Then checks for values in the Rocket.toml file.
+pub fn get_db_connect_retry(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let defaults = ConnectRetry::default();
+    let mut errors = vec![];
+    let config = rocket.config();
+    let retries = setting(
+        &mut errors,
+        config,
+        DB_CONNECT_RETRIES_ENV_NAME,
+        DB_CONNECT_RETRIES_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(defaults.retries);
+    let backoff = setting(
+        &mut errors,
+        config,
+        DB_CONNECT_RETRY_BACKOFF_ENV_NAME,
+        DB_CONNECT_RETRY_BACKOFF_ROCKET_CFG,
+        parse_number,
+    )
+    .map_or(defaults.backoff, Duration::from_millis);
+    Ok(report(rocket, errors).manage(DbConnectRetry(ConnectRetry { retries, backoff })))
+}
+
+/// SQLite journal mode of the cache DB (e.g. `WAL`).
+pub struct DbJournalMode(pub String);
+
+const DB_JOURNAL_MODE_ENV_NAME: &str = "DB_JOURNAL_MODE";
+const DB_JOURNAL_MODE_ROCKET_CFG: &str = "db_journal_mode";
+const DB_JOURNAL_MODE_DEFAULT: &str = "WAL";
+
+/// Gets the cache DB's journal mode.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_db_journal_mode(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let journal_mode: String;
+    match env::var(DB_JOURNAL_MODE_ENV_NAME) {
+        Ok(val) => journal_mode = val,
+        Err(_) => {
+            journal_mode = rocket
+                .config()
+                .get_str(DB_JOURNAL_MODE_ROCKET_CFG)
+                .unwrap_or(DB_JOURNAL_MODE_DEFAULT)
+                .to_string();
+        }
+    }
+    Ok(rocket.manage(DbJournalMode(journal_mode.to_uppercase())))
+}
+
+/// SQLite `synchronous` setting of the cache DB (e.g. `NORMAL`).
+pub struct DbSynchronous(pub String);
+
+const DB_SYNCHRONOUS_ENV_NAME: &str = "DB_SYNCHRONOUS";
+const DB_SYNCHRONOUS_ROCKET_CFG: &str = "db_synchronous";
+const DB_SYNCHRONOUS_DEFAULT: &str = "NORMAL";
+
+/// Gets the cache DB's `synchronous` setting.  First checks for an
+/// environment variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_db_synchronous(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let synchronous: String;
+    match env::var(DB_SYNCHRONOUS_ENV_NAME) {
+        Ok(val) => synchronous = val,
+        Err(_) => {
+            synchronous = rocket
+                .config()
+                .get_str(DB_SYNCHRONOUS_ROCKET_CFG)
+                .unwrap_or(DB_SYNCHRONOUS_DEFAULT)
+                .to_string();
+        }
+    }
+    Ok(rocket.manage(DbSynchronous(synchronous.to_uppercase())))
+}
+
+/// Where the access log goes: `none`, `stdout`, or a file path.
+pub struct AccessLogSink(pub String);
+
+const ACCESS_LOG_ENV_NAME: &str = "ACCESS_LOG";
+const ACCESS_LOG_ROCKET_CFG: &str = "access_log";
+const ACCESS_LOG_DEFAULT: &str = "none";
+
+/// Gets where to write the access log.  First checks for an environment
+/// variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_access_log(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let sink: String;
+    match env::var(ACCESS_LOG_ENV_NAME) {
+        Ok(val) => sink = val,
+        Err(_) => {
+            sink = rocket
+                .config()
+                .get_str(ACCESS_LOG_ROCKET_CFG)
+                .unwrap_or(ACCESS_LOG_DEFAULT)
+                .to_string();
+        }
+    }
+    Ok(rocket.manage(AccessLogSink(sink)))
+}
+
+/// Max rate of requests across all clients, like `100` (per second) or
+/// `100/500` (with bursts of up to 500).  Empty for no limit.
+pub struct RateLimit(pub String);
+
+/// Max rate of requests from each client IP, in the same form as
+/// `RateLimit`.
+pub struct RateLimitPerIp(pub String);
+
+const RATE_LIMIT_ENV_NAME: &str = "RATE_LIMIT";
+const RATE_LIMIT_ROCKET_CFG: &str = "rate_limit";
+const RATE_LIMIT_PER_IP_ENV_NAME: &str = "RATE_LIMIT_PER_IP";
+const RATE_LIMIT_PER_IP_ROCKET_CFG: &str = "rate_limit_per_ip";
+
+/// Gets the global and per-IP request rate limits.  First checks for
+/// environment variables.  Then checks for values in the Rocket.toml file.
+pub fn get_rate_limits(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let read = |env_name: &str, rocket_cfg: &str| match env::var(env_name) {
+        Ok(val) => val,
+        Err(_) => rocket
+            .config()
+            .get_str(rocket_cfg)
+            .unwrap_or("")
+            .to_string(),
+    };
+    let global = read(RATE_LIMIT_ENV_NAME, RATE_LIMIT_ROCKET_CFG);
+    let per_ip = read(RATE_LIMIT_PER_IP_ENV_NAME, RATE_LIMIT_PER_IP_ROCKET_CFG);
+    Ok(rocket
+        .manage(RateLimit(global))
+        .manage(RateLimitPerIp(per_ip)))
+}
+
+/// Channels whose cuboids are never evicted.
+pub struct Pinned(pub PinnedChannels);
+
+const PINNED_CHANNELS_ENV_NAME: &str = "PINNED_CHANNELS";
+const PINNED_CHANNELS_ROCKET_CFG: &str = "pinned_channels";
+
+/// Gets the channels to pin, as a comma separated list of prefixes like
+/// `col/exp/chan` or `col/exp`.  First checks for an environment variable.
+/// Then checks for a value in the Rocket.toml file.  Nothing is pinned by
+/// default.
+pub fn get_pinned_channels(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let spec: String;
+    match env::var(PINNED_CHANNELS_ENV_NAME) {
+        Ok(val) => spec = val,
+        Err(_) => {
+            spec = rocket
+                .config()
+                .get_str(PINNED_CHANNELS_ROCKET_CFG)
+                .unwrap_or("")
+                .to_string();
+        }
+    }
+    let prefixes = spec
+        .split(',')
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .collect();
+    Ok(rocket.manage(Pinned(PinnedChannels::new(prefixes))))
+}
+
+/// Voxel values for regions that have no data.
+pub struct FillValue(pub FillValues);
+
+const FILL_VALUE_ENV_NAME: &str = "FILL_VALUE";
+const FILL_VALUE_ROCKET_CFG: &str = "fill_value";
+const FILL_VALUE_DEFAULT: u8 = 0;
+
+/// Gets the fill values for regions with no data.  First checks for an
+/// environment variable.  Then checks for a value in the Rocket.toml file.
+///
+/// The value is either a single voxel value, or a comma-separated list
+/// whose entries are either a default value or a per-channel override like
+/// `collection/experiment/channel=255`.
+pub fn get_fill_value(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let fill_values = setting(
+        &mut errors,
+        rocket.config(),
+        FILL_VALUE_ENV_NAME,
+        FILL_VALUE_ROCKET_CFG,
+        parse_fill_values,
+    )
+    .unwrap_or_else(|| FillValues::new(FILL_VALUE_DEFAULT));
+    Ok(report(rocket, errors).manage(FillValue(fill_values)))
+}
+
+/// Parse a fill value spec like `0,col/exp/chan=255`.
+fn parse_fill_values(spec: &str) -> Result<FillValues, String> {
+    let mut fill_values = FillValues::new(FILL_VALUE_DEFAULT);
+    for entry in spec.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let parse_value = |v: &str| {
+            v.trim()
+                .parse::<u8>()
+                .map_err(|_| format!("{} is not a voxel value (0-255)", v.trim()))
+        };
+        match entry.find('=') {
+            Some(i) => fill_values.set_channel(entry[..i].trim(), parse_value(&entry[i + 1..])?),
+            None => fill_values.set_default(parse_value(entry)?),
+        }
+    }
+    Ok(fill_values)
+}
+
+/// Fill the parts of cutouts outside the channel's extent, as given by the
+/// coordinate frame in the Boss DB host's metadata, rather than reading them.
+pub struct ClampToExtent(pub bool);
+
+const CLAMP_TO_EXTENT_ENV_NAME: &str = "CLAMP_TO_EXTENT";
+const CLAMP_TO_EXTENT_ROCKET_CFG: &str = "clamp_to_extent";
+const CLAMP_TO_EXTENT_DEFAULT: bool = false;
+
+/// Gets whether cutouts are clamped to their channel's extent.  First
+/// checks for an environment variable.  Then checks for a value in the
+/// Rocket.toml file.
+pub fn get_clamp_to_extent(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let clamp_to_extent = setting(
+        &mut errors,
+        rocket.config(),
+        CLAMP_TO_EXTENT_ENV_NAME,
+        CLAMP_TO_EXTENT_ROCKET_CFG,
+        parse_flag,
+    )
+    .unwrap_or(CLAMP_TO_EXTENT_DEFAULT);
+    Ok(report(rocket, errors).manage(ClampToExtent(clamp_to_extent)))
+}
+
+/// Refuse cutouts that reach outside their channel's coordinate frame on
+/// the Boss DB host, unless they're clamped to it.
+pub struct CheckFrame(pub bool);
+
+const CHECK_FRAME_ENV_NAME: &str = "CHECK_FRAME";
+const CHECK_FRAME_ROCKET_CFG: &str = "check_frame";
+const CHECK_FRAME_DEFAULT: bool = true;
+
+/// Gets whether cutouts are checked against their channel's coordinate
+/// frame.  First checks for an environment variable.  Then checks for a
+/// value in the Rocket.toml file.
+pub fn get_check_frame(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let check_frame = setting(
+        &mut errors,
+        rocket.config(),
+        CHECK_FRAME_ENV_NAME,
+        CHECK_FRAME_ROCKET_CFG,
+        parse_flag,
+    )
+    .unwrap_or(CHECK_FRAME_DEFAULT);
+    Ok(report(rocket, errors).manage(CheckFrame(check_frame)))
+}
+
+/// Which channels' missing resolutions are synthesized from cached higher
+/// resolutions, and how.
+pub struct SynthesizeResolutions(pub SynthesisMethods);
+
+const SYNTHESIZE_RESOLUTIONS_ENV_NAME: &str = "SYNTHESIZE_RESOLUTIONS";
+const SYNTHESIZE_RESOLUTIONS_ROCKET_CFG: &str = "synthesize_resolutions";
+
+/// Gets how to synthesize missing resolutions.  First checks for an
+/// environment variable.  Then checks for a value in the Rocket.toml file.
+///
+/// The value is a comma-separated list whose entries are either a default
+/// method or a per-channel override like `collection/experiment/channel=mode`.
+/// Methods are `none`, `mean` (for images) and `mode` (for annotations).
+pub fn get_synthesize_resolutions(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let methods = setting(
+        &mut errors,
+        rocket.config(),
+        SYNTHESIZE_RESOLUTIONS_ENV_NAME,
+        SYNTHESIZE_RESOLUTIONS_ROCKET_CFG,
+        parse_synthesis_methods,
+    )
+    .unwrap_or_else(SynthesisMethods::default);
+    Ok(report(rocket, errors).manage(SynthesizeResolutions(methods)))
+}
+
+/// Parse a synthesis spec like `mean,col/exp/anno=mode`.
+fn parse_synthesis_methods(spec: &str) -> Result<SynthesisMethods, String> {
+    let mut methods = SynthesisMethods::default();
+    for entry in spec.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let parse_method = |m: &str| match m.trim().to_lowercase().as_str() {
+            "none" => Ok(None),
+            m => Downsampling::parse(m)
+                .map(Some)
+                .ok_or_else(|| format!("{} is not none, mean or mode", m)),
+        };
+        match entry.find('=') {
+            Some(i) => methods.set_channel(entry[..i].trim(), parse_method(&entry[i + 1..])?),
+            None => methods.set_default(parse_method(entry)?),
+        }
+    }
+    Ok(methods)
+}
+
+/// Number of coarser resolutions rebuilt from each upload.
+pub struct DownsampleOnWrite(pub u8);
+
+const DOWNSAMPLE_ON_WRITE_ENV_NAME: &str = "DOWNSAMPLE_ON_WRITE";
+const DOWNSAMPLE_ON_WRITE_ROCKET_CFG: &str = "downsample_on_write";
+const DOWNSAMPLE_ON_WRITE_DEFAULT: u8 = 0;
+
+/// Gets how many coarser resolutions are rebuilt from uploads, at most
+/// `pyramid::MAX_LEVELS`.  First checks for an environment variable.  Then
+/// checks for a value in the Rocket.toml file.
+pub fn get_downsample_on_write(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let levels: u8 = setting(
+        &mut errors,
+        rocket.config(),
+        DOWNSAMPLE_ON_WRITE_ENV_NAME,
+        DOWNSAMPLE_ON_WRITE_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(DOWNSAMPLE_ON_WRITE_DEFAULT);
+    Ok(report(rocket, errors).manage(DownsampleOnWrite(levels.min(pyramid::MAX_LEVELS))))
+}
+
+/// What to do when the Boss DB fails to provide a cuboid.
+pub struct OnUpstreamError(pub UpstreamErrorPolicy);
+
+const ON_UPSTREAM_ERROR_ENV_NAME: &str = "ON_UPSTREAM_ERROR";
+const ON_UPSTREAM_ERROR_ROCKET_CFG: &str = "on_upstream_error";
+const ON_UPSTREAM_ERROR_DEFAULT: UpstreamErrorPolicy = UpstreamErrorPolicy::Fail;
+
+/// Gets the upstream error policy, either `fail` or `serve_partial`.  First
+/// checks for an environment variable.  Then checks for a value in the
+/// Rocket.toml file.
+pub fn get_on_upstream_error(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let policy = setting(
+        &mut errors,
+        rocket.config(),
+        ON_UPSTREAM_ERROR_ENV_NAME,
+        ON_UPSTREAM_ERROR_ROCKET_CFG,
+        |val| match val.to_lowercase().as_str() {
+            "serve_partial" => Ok(UpstreamErrorPolicy::ServePartial),
+            "fail" => Ok(UpstreamErrorPolicy::Fail),
+            _ => Err("expected fail or serve_partial".to_string()),
+        },
+    )
+    .unwrap_or(ON_UPSTREAM_ERROR_DEFAULT);
+    Ok(report(rocket, errors).manage(OnUpstreamError(policy)))
+}
+
+/// What the cache does on a miss when it's standalone, with no Boss DB
+/// host behind it, or `None` to fetch misses from the Boss DB host.
+pub struct Standalone(pub Option<MissPolicy>);
+
+const STANDALONE_ENV_NAME: &str = "STANDALONE";
+const STANDALONE_ROCKET_CFG: &str = "standalone";
+const STANDALONE_DEFAULT: &str = "off";
+
+/// Gets whether the cache is standalone: `off`, or `zeros` to serve misses
+/// as the fill value, or `404` to refuse cutouts that miss.  First checks
+/// for an environment variable.  Then checks for a value in the Rocket.toml
+/// file.
+pub fn get_standalone(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let on_miss = setting(
+        &mut errors,
+        rocket.config(),
+        STANDALONE_ENV_NAME,
+        STANDALONE_ROCKET_CFG,
+        |val| match val.to_lowercase().as_str() {
+            "off" => Ok(None),
+            "zeros" => Ok(Some(MissPolicy::Fill)),
+            "404" => Ok(Some(MissPolicy::NotFound)),
+            _ => Err("expected off, zeros or 404".to_string()),
+        },
+    )
+    .unwrap_or(None);
+    Ok(report(rocket, errors).manage(Standalone(on_miss)))
+}
+
+/// How cuboids are named and stored on disk.
+pub struct CuboidLayout(pub Layout);
+
+const CUBOID_LAYOUT_ENV_NAME: &str = "CUBOID_LAYOUT";
+const CUBOID_LAYOUT_ROCKET_CFG: &str = "cuboid_layout";
+const CUBOID_LAYOUT_DEFAULT: Layout = Layout::Native;
+
+/// Gets the cuboid layout, either `native` or `python` (to share a cache
+/// with the Python bossphorus).  First checks for an environment variable.
+/// Then checks for a value in the Rocket.toml file.
+pub fn get_cuboid_layout(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let layout = setting(
+        &mut errors,
+        rocket.config(),
+        CUBOID_LAYOUT_ENV_NAME,
+        CUBOID_LAYOUT_ROCKET_CFG,
+        |val| Layout::parse(val).ok_or_else(|| "expected native or python".to_string()),
+    )
+    .unwrap_or(CUBOID_LAYOUT_DEFAULT);
+    Ok(report(rocket, errors).manage(CuboidLayout(layout)))
+}
+
+/// Name channel directories in the cache by a salted hash instead of
+/// `col/exp/chan` (see `db::channel_keys`).
+pub struct HashCachePaths {
+    pub enabled: bool,
+    pub salt: String,
+}
+
+const HASH_CACHE_PATHS_ENV_NAME: &str = "HASH_CACHE_PATHS";
+const HASH_CACHE_PATHS_ROCKET_CFG: &str = "hash_cache_paths";
+const HASH_CACHE_PATHS_DEFAULT: bool = false;
+const CACHE_PATH_SALT_ENV_NAME: &str = "CACHE_PATH_SALT";
+const CACHE_PATH_SALT_ROCKET_CFG: &str = "cache_path_salt";
+const CACHE_PATH_SALT_DEFAULT: &str = "";
+
+/// Gets whether channel directories are named by a hash, and the salt of
+/// the hash.  First checks for environment variables.  Then checks for
+/// values in the Rocket.toml file.
+pub fn get_hash_cache_paths(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let enabled = setting(
+        &mut errors,
+        rocket.config(),
+        HASH_CACHE_PATHS_ENV_NAME,
+        HASH_CACHE_PATHS_ROCKET_CFG,
+        parse_flag,
+    )
+    .unwrap_or(HASH_CACHE_PATHS_DEFAULT);
+    let salt = match env::var(CACHE_PATH_SALT_ENV_NAME) {
+        Ok(val) => val,
+        Err(_) => rocket
+            .config()
+            .get_str(CACHE_PATH_SALT_ROCKET_CFG)
+            .unwrap_or(CACHE_PATH_SALT_DEFAULT)
+            .to_string(),
+    };
+    Ok(report(rocket, errors).manage(HashCachePaths { enabled, salt }))
+}
+
+/// Which regions to warm after serving a cutout.
+pub struct Prefetch(pub PrefetchPolicy);
+
+const PREFETCH_ENV_NAME: &str = "PREFETCH";
+const PREFETCH_ROCKET_CFG: &str = "prefetch";
+const PREFETCH_DEFAULT: PrefetchPolicy = PrefetchPolicy::None;
+
+/// Gets the prefetch policy: `none`, `next-z`, or `next-xy-tile`.  First
+/// checks for an environment variable.  Then checks for a value in the
+/// Rocket.toml file.
+pub fn get_prefetch(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let policy = setting(
+        &mut errors,
+        rocket.config(),
+        PREFETCH_ENV_NAME,
+        PREFETCH_ROCKET_CFG,
+        |val| {
+            PrefetchPolicy::parse(val)
+                .ok_or_else(|| "expected none, next-z or next-xy-tile".to_string())
+        },
+    )
+    .unwrap_or(PREFETCH_DEFAULT);
+    Ok(report(rocket, errors).manage(Prefetch(policy)))
+}
+
+/// How many regions ahead to prefetch.
+pub struct PrefetchDistance(pub u64);
+
+const PREFETCH_DISTANCE_ENV_NAME: &str = "PREFETCH_DISTANCE";
+const PREFETCH_DISTANCE_ROCKET_CFG: &str = "prefetch_distance";
+const PREFETCH_DISTANCE_DEFAULT: u64 = 1;
+
+/// Gets how many regions ahead to prefetch.  First checks for an
+/// environment variable.  Then checks for a value in the Rocket.toml file.
+pub fn get_prefetch_distance(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let distance = setting(
+        &mut errors,
+        rocket.config(),
+        PREFETCH_DISTANCE_ENV_NAME,
+        PREFETCH_DISTANCE_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(PREFETCH_DISTANCE_DEFAULT);
+    Ok(report(rocket, errors).manage(PrefetchDistance(distance)))
+}
+
+/// Max size, in bytes, of an upload body.
+pub struct MaxUploadSize(pub u64);
+
+const MAX_UPLOAD_SIZE_ENV_NAME: &str = "MAX_UPLOAD_SIZE";
+const MAX_UPLOAD_SIZE_ROCKET_CFG: &str = "max_upload_size";
+const MAX_UPLOAD_SIZE_DEFAULT: u64 = 256 * 1024 * 1024;
+
+/// Gets the max upload size.  First checks for an environment variable.
+/// Then checks for a value in the Rocket.toml file.
+pub fn get_max_upload_size(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let max_upload_size = setting(
+        &mut errors,
+        rocket.config(),
+        MAX_UPLOAD_SIZE_ENV_NAME,
+        MAX_UPLOAD_SIZE_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(MAX_UPLOAD_SIZE_DEFAULT);
+    Ok(report(rocket, errors).manage(MaxUploadSize(max_upload_size)))
+}
+
+/// Max number of voxels in one uploaded cutout.
+pub struct MaxUploadVoxels(pub u64);
+
+const MAX_UPLOAD_VOXELS_ENV_NAME: &str = "MAX_UPLOAD_VOXELS";
+const MAX_UPLOAD_VOXELS_ROCKET_CFG: &str = "max_upload_voxels";
+const MAX_UPLOAD_VOXELS_DEFAULT: u64 = 256 * 1024 * 1024;
+
+/// Gets the max number of voxels in an uploaded cutout.  First checks for
+/// an environment variable.  Then checks for a value in the Rocket.toml
+/// file.
+pub fn get_max_upload_voxels(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let max_upload_voxels = setting(
+        &mut errors,
+        rocket.config(),
+        MAX_UPLOAD_VOXELS_ENV_NAME,
+        MAX_UPLOAD_VOXELS_ROCKET_CFG,
+        parse_number,
+    )
+    .unwrap_or(MAX_UPLOAD_VOXELS_DEFAULT);
+    Ok(report(rocket, errors).manage(MaxUploadVoxels(max_upload_voxels)))
+}
+
+/// Where the global coordinate frame starts, at resolution 0.
+pub struct FrameOrigin(pub Coords);
+
+const FRAME_ORIGIN_ENV_NAME: &str = "FRAME_ORIGIN";
+const FRAME_ORIGIN_ROCKET_CFG: &str = "frame_origin";
+
+/// Gets where the coordinate frame starts, like `-1024:-1024:0`, for
+/// datasets whose coordinates go below zero.  Cutouts are requested in
+/// global coordinates, and cached relative to this point.  First checks
+/// for an environment variable.  Then checks for a value in the
+/// Rocket.toml file.
+pub fn get_frame_origin(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors = vec![];
+    let frame = setting(
+        &mut errors,
+        rocket.config(),
+        FRAME_ORIGIN_ENV_NAME,
+        FRAME_ORIGIN_ROCKET_CFG,
+        Coords::parse,
+    )
+    .unwrap_or_default();
+    Ok(report(rocket, errors).manage(FrameOrigin(frame)))
+}
+
+/// The settings that can be changed without a restart, by re-reading the
+/// config sources (see `reload()`).  Every other setting is read once, at
+/// launch.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Reloadable {
+    pub max_cuboids: u32,
+    pub min_residency: u32,
+    pub eviction: String,
+    pub decay_half_life: u32,
+    pub eviction_jitter: u32,
+    pub not_found_ttl: u64,
+}
+
+impl Reloadable {
+    /// Read the reloadable settings, preferring environment variables as
+    /// at launch.  Fails if any are invalid.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Rocket's config, as read from Rocket.toml
+    ///
+    pub fn read(config: &Config) -> Result<Reloadable, String> {
+        let eviction = read_eviction(config);
+        if !EVICTIONS.contains(&eviction.as_str()) {
+            return Err(format!(
+                "Unknown eviction strategy {} (expected one of {})",
+                eviction,
+                EVICTIONS.join(", ")
+            ));
+        }
+        Ok(Reloadable {
+            max_cuboids: read_max_cuboids(config)?,
+            min_residency: read_min_residency(config)?,
+            eviction,
+            decay_half_life: read_decay_half_life(config)?,
+            eviction_jitter: read_eviction_jitter(config)?,
+            not_found_ttl: read_not_found_ttl(config)?,
+        })
+    }
+
+    /// The usage tracker's share of the settings.
+    pub fn eviction_settings(&self) -> EvictionSettings {
+        EvictionSettings {
+            max_cuboids: self.max_cuboids,
+            min_residency: self.min_residency,
+            strategy: if self.eviction == DECAY_EVICTION {
+                EvictionStrategy::Decay {
+                    half_life: self.decay_half_life,
+                }
+            } else {
+                EvictionStrategy::Lru
+            },
+            jitter: self.eviction_jitter,
+        }
+    }
+}
+
+/// Re-read the reloadable settings from the config sources, the same way
+/// Rocket reads them at launch: Rocket.toml (found from the working
+/// directory up) for the active environment, with `ROCKET_` environment
+/// variables on top, or the defaults if there's no Rocket.toml.
+pub fn reload() -> Result<Reloadable, String> {
+    let rocket_config = match RocketConfig::read() {
+        Ok(config) => config,
+        Err(ConfigError::NotFound) => {
+            RocketConfig::active_default().map_err(|e| format!("{:?}", e))?
+        }
+        Err(e) => return Err(format!("Couldn't read Rocket.toml: {:?}", e)),
+    };
+    Reloadable::read(rocket_config.active())
+}
+
+/// Check the effective configuration and print it.  Attach this after all
+/// the other config fairings.
+///
+/// Misconfiguration otherwise only surfaces as a panic in the middle of a
+/// request, so every problem found is reported at once and the launch is
+/// aborted.
+pub fn validate(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let mut errors: Vec<String> = rocket
+        .state::<ConfigErrors>()
+        .map_or(vec![], |e| e.0.lock().unwrap().clone());
+
+    if let Err(e) = check_writable(CUBOID_ROOT_PATH) {
+        errors.push(format!(
+            "Cuboid root {} is not writable: {}",
+            CUBOID_ROOT_PATH, e
+        ));
+    }
+
+    let mut resolution_roots: Vec<(u8, String)> =
+        rocket.state::<ResolutionRoots>().map_or(vec![], |r| {
+            r.0.iter().map(|(res, root)| (*res, root.clone())).collect()
+        });
+    resolution_roots.sort();
+    for (res, root) in &resolution_roots {
+        if let Err(e) = check_writable(root) {
+            errors.push(format!(
+                "Cuboid root {} for resolution {} is not writable: {}",
+                root, res, e
+            ));
+        }
+    }
+
+    if CUBOID_SIZE.x == 0 || CUBOID_SIZE.y == 0 || CUBOID_SIZE.z == 0 {
+        errors.push(format!("Cuboid size {} must be positive", CUBOID_SIZE));
+    }
+
+    if let Some(HashCachePaths {
+        enabled: true,
+        salt,
+    }) = rocket.state::<HashCachePaths>()
+    {
+        if salt.is_empty() {
+            errors.push(format!(
+                "{} must be set when {} is on",
+                CACHE_PATH_SALT_ENV_NAME, HASH_CACHE_PATHS_ENV_NAME
+            ));
+        }
+    }
+
+    let usage_tracker = rocket
+        .state::<UsageTracker>()
+        .map_or(USAGE_TRACKER_DEFAULT, |t| &t.0)
+        .to_lowercase();
+    if !TRACKERS.contains(&usage_tracker.as_str()) {
+        errors.push(format!(
+            "Unknown usage tracker {} (expected one of {})",
+            usage_tracker,
+            TRACKERS.join(", ")
+        ));
+    } else if usage_tracker == DB_TRACKER {
+        if let Err(e) = SqliteConnection::establish(DB_URL) {
+            errors.push(format!("Can't open cache DB {}: {}", DB_URL, e));
+        }
+    }
+
+    let eviction = rocket
+        .state::<Eviction>()
+        .map_or(EVICTION_DEFAULT, |e| &e.0);
+    if !EVICTIONS.contains(&eviction) {
+        errors.push(format!(
+            "Unknown eviction strategy {} (expected one of {})",
+            eviction,
+            EVICTIONS.join(", ")
+        ));
+    }
+
+    let rate_limit = rocket.state::<RateLimit>().map_or("", |l| &l.0);
+    let rate_limit_per_ip = rocket.state::<RateLimitPerIp>().map_or("", |l| &l.0);
+    for spec in &[rate_limit, rate_limit_per_ip] {
+        if let Err(e) = Limit::parse(spec) {
+            errors.push(e);
+        }
+    }
+
+    let db_journal_mode = rocket
+        .state::<DbJournalMode>()
+        .map_or(DB_JOURNAL_MODE_DEFAULT, |m| &m.0);
+    if !JOURNAL_MODES.contains(&db_journal_mode) {
+        errors.push(format!(
+            "Unknown DB journal mode {} (expected one of {})",
+            db_journal_mode,
+            JOURNAL_MODES.join(", ")
+        ));
+    }
+
+    let db_synchronous = rocket
+        .state::<DbSynchronous>()
+        .map_or(DB_SYNCHRONOUS_DEFAULT, |m| &m.0);
+    if !SYNCHRONOUS_MODES.contains(&db_synchronous) {
+        errors.push(format!(
+            "Unknown DB synchronous setting {} (expected one of {})",
+            db_synchronous,
+            SYNCHRONOUS_MODES.join(", ")
+        ));
+    }
+
+    let format_fallback = rocket
+        .state::<FormatFallback>()
+        .map_or(FORMAT_FALLBACK_DEFAULT, |f| &f.0);
+    if !FORMAT_FALLBACKS.contains(&format_fallback) {
+        errors.push(format!(
+            "Unknown format fallback {} (expected one of {})",
+            format_fallback,
+            FORMAT_FALLBACKS.join(", ")
+        ));
+    }
+
+    let default_format = rocket
+        .state::<DefaultFormat>()
+        .map_or(DEFAULT_FORMAT_DEFAULT, |f| &f.0);
+    if !DEFAULT_FORMATS.contains(&default_format) {
+        errors.push(format!(
+            "Unknown default format {} (expected one of {})",
+            default_format,
+            DEFAULT_FORMATS.join(", ")
+        ));
+    }
+
+    let writeback_policy = rocket
+        .state::<WritebackPolicy>()
+        .map_or(WRITEBACK_POLICY_DEFAULT, |w| &w.policy);
+    if !WRITEBACK_POLICIES.contains(&writeback_policy) {
+        errors.push(format!(
+            "Unknown writeback policy {} (expected one of {})",
+            writeback_policy,
+            WRITEBACK_POLICIES.join(", ")
+        ));
+    }
+
+    let cuboid_format = rocket
+        .state::<CuboidFormat>()
+        .map_or(CUBOID_FORMAT_DEFAULT, |f| f.0);
+    if cuboid_format != cuboid_file::LEGACY_VERSION && cuboid_format != cuboid_file::CURRENT_VERSION
+    {
+        errors.push(format!(
+            "Unknown cuboid format {} (expected {} or {})",
+            cuboid_format,
+            cuboid_file::LEGACY_VERSION,
+            cuboid_file::CURRENT_VERSION
+        ));
+    }
+
+    let boss_protocol = rocket
+        .state::<BossProtocol>()
+        .map_or(DEFAULT_PROTOCOL, |p| &p.0);
+    if !PROTOCOLS.contains(&boss_protocol) {
+        errors.push(format!(
+            "Unknown Boss protocol {} (expected one of {})",
+            boss_protocol,
+            PROTOCOLS.join(", ")
+        ));
+    }
+    let port = if boss_protocol == "http" { 80 } else { 443 };
+
+    let boss_host = rocket
+        .state::<BossHost>()
+        .map_or(BOSSHOST_DEFAULT, |h| &h.0);
+    match (boss_host, port).to_socket_addrs() {
+        Ok(mut addrs) => {
+            if addrs.next().is_none() {
+                errors.push(format!("Boss host {} has no addresses", boss_host));
+            }
+        }
+        Err(e) => errors.push(format!("Can't resolve Boss host {}: {}", boss_host, e)),
+    }
+
+    let write_host = rocket.state::<BossWriteHost>().and_then(|h| h.0.as_ref());
+    if let Some(write_host) = write_host {
+        match (write_host.as_str(), port).to_socket_addrs() {
+            Ok(mut addrs) => {
+                if addrs.next().is_none() {
+                    errors.push(format!("Boss write host {} has no addresses", write_host));
+                }
+            }
+            Err(e) => errors.push(format!(
+                "Can't resolve Boss write host {}: {}",
+                write_host, e
+            )),
+        }
+    }
+
+    println!("Effective configuration:");
+    println!("    bosshost: {}", boss_host);
+    println!("    bossprotocol: {}", boss_protocol);
+    println!(
+        "    boss_api_prefix: {}",
+        rocket
+            .state::<BossApiPrefix>()
+            .map_or(DEFAULT_API_PREFIX, |p| p.0.as_str())
+    );
+    println!(
+        "    bosstoken: {}",
+        match rocket.state::<BossToken>().map(BossToken::get).as_deref() {
+            None | Some(BOSSTOKEN_DEFAULT) => BOSSTOKEN_DEFAULT,
+            Some(_) => "(set)",
+        }
+    );
+    println!(
+        "    boss_write_host: {}",
+        write_host.map_or("(none)", |h| h.as_str())
+    );
+    println!(
+        "    boss_write_token: {}",
+        match rocket
+            .state::<BossWriteToken>()
+            .and_then(|t| t.0.as_deref())
+        {
+            None => "(bosstoken)",
+            Some(BOSSTOKEN_DEFAULT) => BOSSTOKEN_DEFAULT,
+            Some(_) => "(set)",
+        }
+    );
+    println!(
+        "    scratch_writes: {}",
+        rocket
+            .state::<ScratchWrites>()
+            .map_or(SCRATCH_WRITES_DEFAULT, |s| s.0)
+    );
+    println!(
+        "    admin_token: {}",
+        match rocket.state::<AdminToken>().and_then(|t| t.0.as_ref()) {
+            None => "(none)",
+            Some(_) => "(set)",
+        }
+    );
+    let show_keys = |keys: Option<&Vec<String>>| match keys.map_or(0, Vec::len) {
+        0 => "(none)".to_string(),
+        n => format!("({} set)", n),
+    };
+    println!(
+        "    read_api_keys: {}",
+        show_keys(rocket.state::<ReadKeys>().map(|k| &k.0))
+    );
+    println!(
+        "    write_api_keys: {}",
+        show_keys(rocket.state::<WriteKeys>().map(|k| &k.0))
+    );
+    println!("    usage_tracker: {}", usage_tracker);
+    println!(
+        "    use_mmap: {}",
+        rocket.state::<UseMmap>().map_or(USE_MMAP_DEFAULT, |m| m.0)
+    );
+    let reads = rocket
+        .state::<CuboidReads>()
+        .map_or(ReadStrategy::default(), |r| r.0);
+    println!("    read_buffer_size: {}", reads.buffer_size);
+    println!("    read_advice: {}", reads.advice.as_str());
+    println!(
+        "    writeback: {}",
+        rocket
+            .state::<Writeback>()
+            .map_or(WRITEBACK_DEFAULT, |w| w.0)
+    );
+    let writeback_policy = rocket.state::<WritebackPolicy>();
+    println!(
+        "    writeback_policy: {}",
+        writeback_policy.map_or(WRITEBACK_POLICY_DEFAULT, |w| &w.policy)
+    );
+    println!(
+        "    writeback_window: {}",
+        writeback_policy.map_or(WRITEBACK_WINDOW_DEFAULT, |w| w.window)
+    );
+    println!("    cuboid_format: {}", cuboid_format);
+    println!(
+        "    cuboid_layout: {:?}",
+        rocket
+            .state::<CuboidLayout>()
+            .map_or(Layout::Native, |l| l.0)
+    );
+    let hash_cache_paths = rocket.state::<HashCachePaths>();
+    println!(
+        "    hash_cache_paths: {}",
+        hash_cache_paths.map_or(HASH_CACHE_PATHS_DEFAULT, |h| h.enabled)
+    );
+    println!(
+        "    cache_path_salt: {}",
+        match hash_cache_paths.map_or("", |h| h.salt.as_str()) {
+            "" => "(none)",
+            _ => "(set)",
+        }
+    );
+    if let Some(fill_value) = rocket.state::<FillValue>() {
+        println!("    fill_value: {:?}", fill_value.0);
+    }
+    println!(
+        "    clamp_to_extent: {}",
+        rocket
+            .state::<ClampToExtent>()
+            .map_or(CLAMP_TO_EXTENT_DEFAULT, |c| c.0)
+    );
+    println!(
+        "    check_frame: {}",
+        rocket
+            .state::<CheckFrame>()
+            .map_or(CHECK_FRAME_DEFAULT, |c| c.0)
+    );
+    if let Some(synthesize) = rocket.state::<SynthesizeResolutions>() {
+        println!("    synthesize_resolutions: {:?}", synthesize.0);
+    }
+    println!(
+        "    downsample_on_write: {}",
+        rocket
+            .state::<DownsampleOnWrite>()
+            .map_or(DOWNSAMPLE_ON_WRITE_DEFAULT, |d| d.0)
+    );
+    println!(
+        "    on_upstream_error: {:?}",
+        rocket
+            .state::<OnUpstreamError>()
+            .map_or(UpstreamErrorPolicy::Fail, |p| p.0)
+    );
+    println!(
+        "    standalone: {}",
+        match rocket.state::<Standalone>().and_then(|s| s.0) {
+            None => STANDALONE_DEFAULT,
+            Some(MissPolicy::Fill) => "zeros",
+            Some(MissPolicy::NotFound) => "404",
+        }
+    );
+    println!(
+        "    prefetch: {:?}",
+        rocket
+            .state::<Prefetch>()
+            .map_or(PrefetchPolicy::None, |p| p.0)
+    );
+    println!(
+        "    prefetch_distance: {}",
+        rocket
+            .state::<PrefetchDistance>()
+            .map_or(PREFETCH_DISTANCE_DEFAULT, |d| d.0)
+    );
+    println!(
+        "    max_cuboids: {}",
+        rocket
+            .state::<MaxCuboids>()
+            .map_or(MAX_CUBOIDS_DEFAULT, |m| m.0)
+    );
+    println!("    eviction: {}", eviction);
+    println!("    format_fallback: {}", format_fallback);
+    println!("    default_format: {}", default_format);
+    println!(
+        "    jpeg_quality: {}",
+        rocket
+            .state::<JpegQuality>()
+            .map_or(JPEG_QUALITY_DEFAULT, |q| q.0)
+    );
+    println!(
+        "    jpeg_spool_size: {}",
+        rocket
+            .state::<JpegSpoolSize>()
+            .map_or(JPEG_SPOOL_SIZE_DEFAULT, |s| s.0)
+    );
+    println!(
+        "    decay_half_life: {}",
+        rocket
+            .state::<DecayHalfLife>()
+            .map_or(DECAY_HALF_LIFE_DEFAULT, |h| h.0)
+    );
+    println!(
+        "    eviction_jitter: {}",
+        rocket
+            .state::<EvictionJitter>()
+            .map_or(EVICTION_JITTER_DEFAULT, |j| j.0)
+    );
+    let retry = rocket
+        .state::<EvictionRetry>()
+        .map_or(RemovalRetry::default(), |r| r.0);
+    println!("    eviction_retries: {}", retry.retries);
+    println!("    eviction_retry_backoff: {}", retry.backoff.as_millis());
+    println!(
+        "    evict_empty_first: {}",
+        rocket
+            .state::<EvictEmptyFirst>()
+            .map_or(EVICT_EMPTY_FIRST_DEFAULT, |e| e.0)
+    );
+    let size_report = rocket.state::<CacheSizeReport>().and_then(|r| r.0);
+    println!("    cache_size_report: {}", size_report.is_some());
+    println!(
+        "    cache_size_report_interval: {}",
+        size_report.map_or(CACHE_SIZE_REPORT_INTERVAL_DEFAULT, |i| i.as_secs())
+    );
+    println!(
+        "    usage_tracker_restarts: {}",
+        rocket
+            .state::<TrackerRestarts>()
+            .map_or(TRACKER_RESTARTS_DEFAULT, |r| r.0)
+    );
+    if let Some(buffer) = rocket.state::<WriteBufferSettings>() {
+        println!("    write_buffer_window: {}", buffer.window.as_millis());
+        println!("    write_buffer_max_cuboids: {}", buffer.max_cuboids);
+    }
+    println!(
+        "    min_residency: {}",
+        rocket
+            .state::<MinResidency>()
+            .map_or(MIN_RESIDENCY_DEFAULT, |r| r.0)
+    );
+    println!(
+        "    upstream_concurrency: {}",
+        rocket
+            .state::<UpstreamLimit>()
+            .map_or(UPSTREAM_CONCURRENCY_DEFAULT, |l| l.0.capacity())
+    );
+    println!(
+        "    max_open_cuboids: {}",
+        rocket
+            .state::<FileLimit>()
+            .map_or(MAX_OPEN_CUBOIDS_DEFAULT, |l| l.0.capacity())
+    );
+    println!(
+        "    cutout_memory_budget: {}",
+        rocket
+            .state::<CutoutMemory>()
+            .and_then(|m| m.0.as_ref())
+            .map_or(CUTOUT_MEMORY_BUDGET_DEFAULT, |m| m.capacity() as u64)
+    );
+    let modes = rocket
+        .state::<CacheModes>()
+        .map_or(Modes::default(), |m| m.0);
+    let show_mode = |mode: Option<u32>| mode.map_or("(umask)".to_string(), |m| format!("{:o}", m));
+    println!("    cache_dir_mode: {}", show_mode(modes.dir));
+    println!("    cache_file_mode: {}", show_mode(modes.file));
+    println!(
+        "    not_found_ttl: {}",
+        rocket
+            .state::<NotFoundCache>()
+            .map_or(NOT_FOUND_TTL_DEFAULT, |c| c.0.ttl().as_secs())
+    );
+    println!(
+        "    cuboid_max_age: {}",
+        rocket
+            .state::<CuboidMaxAge>()
+            .map_or(CUBOID_MAX_AGE_DEFAULT, |m| m.0)
+    );
+    println!(
+        "    db_pool_size: {}",
+        rocket
+            .state::<DbPoolSize>()
+            .map_or(DB_POOL_SIZE_DEFAULT, |s| s.0)
+    );
+    println!(
+        "    db_busy_timeout: {}",
+        rocket
+            .state::<DbBusyTimeout>()
+            .map_or(DB_BUSY_TIMEOUT_DEFAULT, |t| t.0)
+    );
+    let connect_retry = rocket
+        .state::<DbConnectRetry>()
+        .map_or(ConnectRetry::default(), |r| r.0);
+    println!("    db_connect_retries: {}", connect_retry.retries);
+    println!(
+        "    db_connect_retry_backoff: {}",
+        connect_retry.backoff.as_millis()
+    );
+    println!("    db_journal_mode: {}", db_journal_mode);
+    println!("    db_synchronous: {}", db_synchronous);
+    println!("    rate_limit: {}", rate_limit);
+    println!("    rate_limit_per_ip: {}", rate_limit_per_ip);
+    println!(
+        "    access_log: {}",
+        rocket
+            .state::<AccessLogSink>()
+            .map_or(ACCESS_LOG_DEFAULT, |s| s.0.as_str())
+    );
+    println!(
+        "    pinned_channels: {}",
+        rocket
+            .state::<Pinned>()
+            .map_or(String::new(), |p| p.0.prefixes().join(","))
+    );
+    println!(
+        "    max_upload_size: {}",
+        rocket
+            .state::<MaxUploadSize>()
+            .map_or(MAX_UPLOAD_SIZE_DEFAULT, |m| m.0)
+    );
+    println!(
+        "    max_upload_voxels: {}",
+        rocket
+            .state::<MaxUploadVoxels>()
+            .map_or(MAX_UPLOAD_VOXELS_DEFAULT, |m| m.0)
+    );
+    println!(
+        "    frame_origin: {}",
+        rocket
+            .state::<FrameOrigin>()
+            .map_or(Coords::default(), |f| f.0)
+    );
+    println!("    cuboid_root: {}", CUBOID_ROOT_PATH);
+    println!(
+        "    resolution_roots: {}",
+        resolution_roots
+            .iter()
+            .map(|(res, root)| format!("{}={}", res, root))
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    println!("    cuboid_size: {}", CUBOID_SIZE);
+    let mut channel_cuboid_sizes: Vec<(String, Vector3)> =
+        rocket.state::<ChannelCuboidSizes>().map_or(vec![], |s| {
+            s.0.iter()
+                .map(|(channel, size)| (channel.clone(), *size))
+                .collect()
+        });
+    channel_cuboid_sizes.sort_by(|a, b| a.0.cmp(&b.0));
+    println!(
+        "    channel_cuboid_sizes: {}",
+        channel_cuboid_sizes
+            .iter()
+            .map(|(channel, size)| format!("{}={}:{}:{}", channel, size.x, size.y, size.z))
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    println!("    db_url: {}", DB_URL);
+
+    if errors.is_empty() {
+        return Ok(rocket);
+    }
+    println!("Invalid configuration:");
+    for error in &errors {
+        println!("    {}", error);
+    }
+    Err(rocket)
+}
+
+/// Make sure files can be created in a folder, creating it if needed.
+fn check_writable(dir: &str) -> std::io::Result<()> {
+    cuboid_file::create_dir_all(dir, dir_mode())?;
+    let probe = Path::new(dir).join(".bossphorus-write-check");
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
@@ -0,0 +1,757 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Cuboid file module.
+///
+/// Cuboid files start with a small header so that future layout changes
+/// can't silently misread old files:
+///
+/// * 4 bytes of magic (`BPCF`)
+/// * a little-endian `u16` format version
+/// * a `u8` datatype code (see `DATATYPE_UINT8`)
+/// * a reserved byte
+/// * the cuboid's X, Y, and Z dimensions as little-endian `u32`s
+///
+/// followed by the voxels in ZYX C-order.  Files written before the header
+/// existed ("legacy", version 0) are just the voxels, and are told apart by
+/// their length.
+///
+/// A cuboid file may also be a blosc frame of the voxels, as written by
+/// on-disk compression.  Those are told apart from the formats above by the
+/// sizes in the blosc header, which must match the cuboid and the file, so
+/// caches holding both kinds of file can be read without migrating them.
+///
+/// Caches seeded by the Python bossphorus use a different layout (see
+/// `Layout`), whose cuboids are NumPy `.npy` files (see `npy`).  Those can be
+/// read no matter which layout is configured, as long as the file can be
+/// found.
+use crate::data_manager::Vector3;
+
+use ndarray::Array3;
+use serde::Serialize;
+use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub mod npy;
+
+#[cfg(test)]
+pub mod tests;
+
+pub const MAGIC: [u8; 4] = *b"BPCF";
+pub const HEADER_LEN: usize = 20;
+
+/// Headerless files, as written before versioning.
+pub const LEGACY_VERSION: u16 = 0;
+pub const CURRENT_VERSION: u16 = 1;
+
+pub const DATATYPE_UINT8: u8 = 1;
+
+/// How cuboids are named and stored under a channel's resolution directory
+/// (`<root>/<collection>/<experiment>/<channel>/<res>/`).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Layout {
+    /// Named by cuboid index, e.g. `x1_y2_z0` for the cuboid at voxel
+    /// (512, 1024, 0) with 512x512x16 cuboids, in this module's format.
+    Native,
+    /// Named by voxel extents like the Python `FilesystemStorageManager`,
+    /// e.g. `512-1024_1024-1536_0-16.npy`, as `.npy` files.
+    Python,
+}
+
+impl Layout {
+    /// Look up a layout by its config name (`native` or `python`).
+    pub fn parse(name: &str) -> Option<Layout> {
+        match name.to_lowercase().as_str() {
+            "native" => Some(Layout::Native),
+            "python" => Some(Layout::Python),
+            _ => None,
+        }
+    }
+
+    /// File name of a cuboid within its resolution directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `cuboid_index` - Index of the cuboid in the cuboid grid
+    /// * `cuboid_size` - Dimensions of the cuboids
+    ///
+    pub fn key(&self, cuboid_index: &Vector3, cuboid_size: Vector3) -> String {
+        match self {
+            Layout::Native => cuboid_index.to_string(),
+            Layout::Python => format!(
+                "{}-{}_{}-{}_{}-{}.npy",
+                cuboid_index.x * cuboid_size.x,
+                (cuboid_index.x + 1) * cuboid_size.x,
+                cuboid_index.y * cuboid_size.y,
+                (cuboid_index.y + 1) * cuboid_size.y,
+                cuboid_index.z * cuboid_size.z,
+                (cuboid_index.z + 1) * cuboid_size.z,
+            ),
+        }
+    }
+}
+
+/// The parsed header of a versioned cuboid file.
+#[derive(Debug, PartialEq)]
+pub struct CuboidHeader {
+    pub version: u16,
+    pub datatype: u8,
+    pub dims: Vector3,
+}
+
+/// Number of voxels in a cuboid.
+fn voxel_count(cuboid_size: Vector3) -> usize {
+    (cuboid_size.x * cuboid_size.y * cuboid_size.z) as usize
+}
+
+/// Build the contents of a cuboid file.
+///
+/// # Arguments
+///
+/// * `version` - Format version to write; `LEGACY_VERSION` omits the header
+/// * `cuboid_size` - Dimensions of the cuboid
+/// * `voxels` - The `uint8` voxels in ZYX C-order
+///
+pub fn encode(version: u16, cuboid_size: Vector3, voxels: &[u8]) -> Vec<u8> {
+    if version == LEGACY_VERSION {
+        return voxels.to_vec();
+    }
+    let mut bytes = Vec::with_capacity(HEADER_LEN + voxels.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.push(DATATYPE_UINT8);
+    bytes.push(0);
+    bytes.extend_from_slice(&(cuboid_size.x as u32).to_le_bytes());
+    bytes.extend_from_slice(&(cuboid_size.y as u32).to_le_bytes());
+    bytes.extend_from_slice(&(cuboid_size.z as u32).to_le_bytes());
+    bytes.extend_from_slice(voxels);
+    bytes
+}
+
+/// Parse the header at the start of a cuboid file, if it has one.
+pub fn decode_header(bytes: &[u8]) -> Option<CuboidHeader> {
+    if bytes.len() < HEADER_LEN || bytes[..4] != MAGIC {
+        return None;
+    }
+    let u32_at =
+        |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]) as u64;
+    Some(CuboidHeader {
+        version: u16::from_le_bytes([bytes[4], bytes[5]]),
+        datatype: bytes[6],
+        dims: Vector3 {
+            x: u32_at(8),
+            y: u32_at(12),
+            z: u32_at(16),
+        },
+    })
+}
+
+/// Could a file of this length be a complete cuboid, in either of this
+/// module's formats?
+pub fn is_complete_len(len: u64, cuboid_size: Vector3) -> bool {
+    let voxels = voxel_count(cuboid_size) as u64;
+    len == voxels || len == voxels + HEADER_LEN as u64
+}
+
+/// Length of the header at the start of a blosc frame.
+const BLOSC_HEADER_LEN: usize = 16;
+
+/// Could a file be a blosc-compressed cuboid?  Checks the sizes in the
+/// blosc header against the cuboid and the file, so that it's only asked of
+/// files whose length doesn't already make them raw cuboids.
+///
+/// # Arguments
+///
+/// * `prefix` - The start of the file; at least the blosc header
+/// * `len` - Length of the whole file
+/// * `cuboid_size` - Expected dimensions of the cuboid
+///
+pub fn is_compressed(prefix: &[u8], len: usize, cuboid_size: Vector3) -> bool {
+    if prefix.len() < BLOSC_HEADER_LEN || is_complete_len(len as u64, cuboid_size) {
+        return false;
+    }
+    let u32_at = |i: usize| {
+        u32::from_le_bytes([prefix[i], prefix[i + 1], prefix[i + 2], prefix[i + 3]]) as usize
+    };
+    // Version of the blosc format, then the typesize; cuboids are `uint8`:
+    (prefix[0] == 1 || prefix[0] == 2)
+        && prefix[3] == 1
+        && u32_at(4) == voxel_count(cuboid_size)
+        && u32_at(12) == len
+}
+
+/// Decompress a blosc-compressed cuboid file.  Returns `None` if it isn't
+/// one, or doesn't hold the whole cuboid.
+///
+/// # Arguments
+///
+/// * `bytes` - The contents of the file
+/// * `cuboid_size` - Expected dimensions of the cuboid
+///
+pub fn decompress(bytes: &[u8], cuboid_size: Vector3) -> Option<Vec<u8>> {
+    if !is_compressed(bytes, bytes.len(), cuboid_size) || blosc::validate(bytes).is_err() {
+        return None;
+    }
+    // Safety: validated above, and `u8` can hold any bytes.
+    let voxels: Vec<u8> = unsafe { blosc::decompress_bytes(bytes) }.ok()?;
+    if voxels.len() != voxel_count(cuboid_size) {
+        return None;
+    }
+    Some(voxels)
+}
+
+/// The voxels of a cuboid file in any readable format, decompressing them
+/// if need be.  Returns `None` under the same conditions as `voxels`.
+///
+/// # Arguments
+///
+/// * `bytes` - The contents of the file
+/// * `cuboid_size` - Expected dimensions of the cuboid
+///
+pub fn decode_voxels(mut bytes: Vec<u8>, cuboid_size: Vector3) -> Option<Vec<u8>> {
+    match voxels(&bytes, cuboid_size) {
+        Some(voxels) => {
+            let header_len = bytes.len() - voxels.len();
+            bytes.drain(..header_len);
+            Some(bytes)
+        }
+        None => decompress(&bytes, cuboid_size),
+    }
+}
+
+/// How much of a file to read to find a `.npy` header.
+const NPY_PROBE_LEN: u64 = 4096;
+
+/// The longest a complete cuboid file can be, in any readable format: its
+/// voxels, with at most a `.npy` header's worth more.
+pub fn max_file_len(cuboid_size: Vector3) -> u64 {
+    voxel_count(cuboid_size) as u64 + NPY_PROBE_LEN
+}
+
+/// Are these the contents of a complete cuboid file, in any readable
+/// format?
+///
+/// # Arguments
+///
+/// * `bytes` - The contents of the file
+/// * `cuboid_size` - Expected dimensions of the cuboid
+///
+pub fn is_readable(bytes: &[u8], cuboid_size: Vector3) -> bool {
+    voxels(bytes, cuboid_size).is_some() || decompress(bytes, cuboid_size).is_some()
+}
+
+/// Is a file a complete cuboid, in any readable format?  Only reads the
+/// start of the file, and only if its length alone doesn't settle it.
+///
+/// # Arguments
+///
+/// * `path` - The cuboid file
+/// * `cuboid_size` - Expected dimensions of the cuboid
+///
+pub fn is_complete(path: &Path, cuboid_size: Vector3) -> bool {
+    let len = match fs::metadata(path) {
+        Ok(meta) => meta.len(),
+        Err(_) => return false,
+    };
+    if is_complete_len(len, cuboid_size) {
+        return true;
+    }
+    let mut prefix = Vec::new();
+    let read = fs::File::open(path)
+        .and_then(|file| file.take(NPY_PROBE_LEN.min(len)).read_to_end(&mut prefix));
+    if read.is_err() {
+        return false;
+    }
+    match npy::data_offset(&prefix, cuboid_size) {
+        Some(offset) => len == (offset + voxel_count(cuboid_size)) as u64,
+        None => is_compressed(&prefix, len as usize, cuboid_size),
+    }
+}
+
+/// Find the voxels in the contents of an uncompressed cuboid file.
+/// Returns `None` if the file is partial, compressed, or is a version,
+/// datatype, or size that this reader doesn't understand.
+///
+/// # Arguments
+///
+/// * `bytes` - The contents of the file
+/// * `cuboid_size` - Expected dimensions of the cuboid
+///
+pub fn voxels(bytes: &[u8], cuboid_size: Vector3) -> Option<&[u8]> {
+    let offset = voxel_offset(bytes, bytes.len(), cuboid_size)?;
+    Some(&bytes[offset..])
+}
+
+/// Find where the voxels start in a cuboid file.  Same checks as `voxels`.
+///
+/// # Arguments
+///
+/// * `prefix` - The start of the file; enough to hold any header
+/// * `len` - Length of the whole file
+/// * `cuboid_size` - Expected dimensions of the cuboid
+///
+fn voxel_offset(prefix: &[u8], len: usize, cuboid_size: Vector3) -> Option<usize> {
+    let count = voxel_count(cuboid_size);
+    if len == count {
+        return Some(0);
+    }
+    if prefix.starts_with(&npy::MAGIC) {
+        let offset = npy::data_offset(prefix, cuboid_size)?;
+        if len != offset + count {
+            return None;
+        }
+        return Some(offset);
+    }
+    if len != count + HEADER_LEN {
+        return None;
+    }
+    let header = decode_header(prefix)?;
+    if header.version != CURRENT_VERSION
+        || header.datatype != DATATYPE_UINT8
+        || header.dims != cuboid_size
+    {
+        return None;
+    }
+    Some(HEADER_LEN)
+}
+
+/// Kernel hint about how a cuboid file is about to be read, given with
+/// `posix_fadvise` when the file is opened.  Only given on Linux.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadAdvice {
+    /// No hint.
+    Normal,
+    /// Read ahead more aggressively, e.g. for caches on spinning disks.
+    Sequential,
+    /// Start reading the whole file into the page cache right away.
+    WillNeed,
+}
+
+impl Default for ReadAdvice {
+    fn default() -> ReadAdvice {
+        ReadAdvice::Normal
+    }
+}
+
+impl ReadAdvice {
+    /// Look up a hint by its config name (`normal`, `sequential` or
+    /// `willneed`).
+    pub fn parse(name: &str) -> Option<ReadAdvice> {
+        match name.trim().to_lowercase().as_str() {
+            "normal" => Some(ReadAdvice::Normal),
+            "sequential" => Some(ReadAdvice::Sequential),
+            "willneed" => Some(ReadAdvice::WillNeed),
+            _ => None,
+        }
+    }
+
+    /// The hint's config name.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReadAdvice::Normal => "normal",
+            ReadAdvice::Sequential => "sequential",
+            ReadAdvice::WillNeed => "willneed",
+        }
+    }
+}
+
+/// How cuboid files are read.  The default reads just what's needed, with
+/// no hints, which suits SSDs; caches on spinning disks may read faster
+/// with a read-ahead buffer and `ReadAdvice::Sequential`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReadStrategy {
+    /// Bytes that `read_region` reads ahead at a time, so the rows of a
+    /// region come from a few large reads rather than one read per row.  0
+    /// reads each run separately.
+    pub buffer_size: usize,
+    pub advice: ReadAdvice,
+}
+
+/// Open a cuboid file for reading, giving the kernel the hint.
+pub fn open_for_read(path: &Path, advice: ReadAdvice) -> std::io::Result<fs::File> {
+    let file = fs::File::open(path)?;
+    advise(&file, advice);
+    Ok(file)
+}
+
+/// Give the kernel a hint about reading the whole file.  Failures are
+/// ignored, since it's only a hint.
+#[cfg(target_os = "linux")]
+fn advise(file: &fs::File, advice: ReadAdvice) {
+    use std::os::unix::io::AsRawFd;
+    let advice = match advice {
+        ReadAdvice::Normal => return,
+        ReadAdvice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        ReadAdvice::WillNeed => libc::POSIX_FADV_WILLNEED,
+    };
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise(_file: &fs::File, _advice: ReadAdvice) {}
+
+/// Read a whole cuboid file, like `fs::read` but giving the strategy's
+/// hint.
+pub fn read_file(path: &Path, strategy: ReadStrategy) -> std::io::Result<Vec<u8>> {
+    let mut file = open_for_read(path, strategy.advice)?;
+    let mut bytes = Vec::with_capacity(file.metadata()?.len() as usize);
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Read part of a cuboid file, without reading the rest of it.  Returns
+/// `None` under the same conditions as `voxels`, except that compressed
+/// files are read whole and decompressed.
+///
+/// Voxels are stored in ZYX C-order, so each row of the region is a run of
+/// the file.  Runs that touch (e.g. whole rows, or whole planes) are read
+/// together, so a region spanning the cuboid in x and y is a single read.
+///
+/// # Arguments
+///
+/// * `path` - The cuboid file
+/// * `cuboid_size` - Expected dimensions of the cuboid
+/// * `start` - Start of the region, within the cuboid
+/// * `stop` - End of the region, within the cuboid
+///
+pub fn read_region(
+    path: &Path,
+    cuboid_size: Vector3,
+    start: Vector3,
+    stop: Vector3,
+) -> Option<Array3<u8>> {
+    read_region_with(path, cuboid_size, start, stop, ReadStrategy::default())
+}
+
+/// Like `read_region`, but reading the file the given way.
+pub fn read_region_with(
+    path: &Path,
+    cuboid_size: Vector3,
+    start: Vector3,
+    stop: Vector3,
+    strategy: ReadStrategy,
+) -> Option<Array3<u8>> {
+    let file = open_for_read(path, strategy.advice).ok()?;
+    let len = file.metadata().ok()?.len();
+    let mut file = RunReader {
+        file: BufReader::with_capacity(strategy.buffer_size, file),
+        position: 0,
+    };
+    let mut prefix = vec![0; NPY_PROBE_LEN.min(len) as usize];
+    file.read_run(0, &mut prefix).ok()?;
+    let shape = Vector3::checked_shape(start, stop)?;
+    let offset = match voxel_offset(&prefix, len as usize, cuboid_size) {
+        Some(offset) => offset as u64,
+        None if is_compressed(&prefix, len as usize, cuboid_size) => {
+            let mut bytes = vec![0; len as usize];
+            file.read_run(0, &mut bytes).ok()?;
+            let cuboid = Array3::from_shape_vec(
+                cuboid_size.to_zyx_shape(),
+                decompress(&bytes, cuboid_size)?,
+            )
+            .ok()?;
+            let region = cuboid.slice(&Vector3::zyx_slice(start, stop));
+            return Some(region.to_owned());
+        }
+        None => return None,
+    };
+
+    let row_len = shape.x as usize;
+    let mut data = vec![0u8; voxel_count(shape)];
+    // The run of the file waiting to be read, and where it goes in `data`:
+    let mut run_start = 0;
+    let mut run_len = 0;
+    let mut filled = 0;
+    for z in start.z..stop.z {
+        for y in start.y..stop.y {
+            let row_start = offset + (z * cuboid_size.y + y) * cuboid_size.x + start.x;
+            if run_len > 0 && run_start + run_len as u64 == row_start {
+                run_len += row_len;
+                continue;
+            }
+            file.read_run(run_start, &mut data[filled..filled + run_len])
+                .ok()?;
+            filled += run_len;
+            run_start = row_start;
+            run_len = row_len;
+        }
+    }
+    file.read_run(run_start, &mut data[filled..filled + run_len])
+        .ok()?;
+    Array3::from_shape_vec(shape.to_zyx_shape(), data).ok()
+}
+
+/// Reads runs of a file, through a read-ahead buffer if it has one.
+struct RunReader {
+    file: BufReader<fs::File>,
+    /// Where the next read would start.
+    position: u64,
+}
+
+impl RunReader {
+    /// Read `buf.len()` bytes, starting at `position`.  Skipping ahead
+    /// within the buffer doesn't discard it.
+    fn read_run(&mut self, position: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.file
+            .seek_relative(position as i64 - self.position as i64)?;
+        self.file.read_exact(buf)?;
+        self.position = position + buf.len() as u64;
+        Ok(())
+    }
+}
+
+/// Permission bits for the directories and files created in the cache.
+/// `None` leaves them to the process umask.  Only applied on Unix.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Modes {
+    pub dir: Option<u32>,
+    pub file: Option<u32>,
+}
+
+/// Parse permission bits given in octal, like `750` or `0o2770`.
+pub fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.trim().trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{} is not an octal mode like 750", value)),
+    }
+}
+
+/// Create a directory and any missing parents, like `fs::create_dir_all`,
+/// giving each directory that's created the permission bits `mode`.
+/// Directories that already exist are left alone.
+///
+/// # Arguments
+///
+/// * `path` - The directory to create
+/// * `mode` - Permission bits for new directories, or `None` to leave them
+///   to the umask
+///
+pub fn create_dir_all<P: AsRef<Path>>(path: P, mode: Option<u32>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let missing: Vec<&Path> = path.ancestors().take_while(|dir| !dir.exists()).collect();
+    fs::create_dir_all(path)?;
+    for dir in missing.iter().rev() {
+        set_mode(dir, mode)?;
+    }
+    Ok(())
+}
+
+/// Set the permission bits of a file or directory, ignoring the umask.
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    match mode {
+        Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Distinguishes temp files of concurrent writers within this process.
+static WRITE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Suffix of in-progress writes.
+const TMP_SUFFIX: &str = ".tmp";
+
+/// Write a file by writing a temp file next to it and renaming it into
+/// place, so readers only ever see the old or the new contents in full.
+///
+/// # Arguments
+///
+/// * `filename` - Path of the file to replace
+/// * `bytes` - The new contents
+/// * `mode` - Permission bits for the file, or `None` to leave them to the
+///   umask
+///
+pub fn write_atomically(filename: &str, bytes: &[u8], mode: Option<u32>) -> std::io::Result<()> {
+    let tmp = tmp_name(filename);
+    let result = fs::File::create(&tmp)
+        .and_then(|mut file| file.write_all(bytes))
+        .and_then(|_| set_mode(Path::new(&tmp), mode))
+        .and_then(|_| fs::rename(&tmp, filename));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// A temp file name next to `filename`, unique within this process.
+fn tmp_name(filename: &str) -> String {
+    format!(
+        "{}.{}-{}{}",
+        filename,
+        process::id(),
+        WRITE_COUNTER.fetch_add(1, Ordering::Relaxed),
+        TMP_SUFFIX
+    )
+}
+
+/// Is a cuboid file complete, with every voxel zero?
+fn all_zero(bytes: &[u8], cuboid_size: Vector3) -> bool {
+    match voxels(bytes, cuboid_size) {
+        Some(voxels) => voxels.iter().all(|v| *v == 0),
+        None => {
+            decompress(bytes, cuboid_size).map_or(false, |voxels| voxels.iter().all(|v| *v == 0))
+        }
+    }
+}
+
+/// Remove a cuboid file if every voxel in it is zero, e.g. one written back
+/// from a sparse annotation channel.  Returns the number of bytes freed, or
+/// 0 if the file was kept.
+///
+/// Safe to run alongside reads and writes: the file is renamed aside before
+/// it's checked again, so a write that lands in between is put back rather
+/// than lost, unless the cuboid has been rewritten again since.  Readers see
+/// the old file or a miss.
+///
+/// # Arguments
+///
+/// * `path` - The cuboid file
+/// * `cuboid_size` - Expected dimensions of the cuboid
+///
+pub fn remove_if_zero(path: &Path, cuboid_size: Vector3) -> std::io::Result<u64> {
+    if !all_zero(&fs::read(path)?, cuboid_size) {
+        return Ok(0);
+    }
+    let tmp = tmp_name(&path.to_string_lossy());
+    fs::rename(path, &tmp)?;
+    let bytes = fs::read(&tmp)?;
+    if !all_zero(&bytes, cuboid_size) {
+        // Put it back, unless something newer has replaced it already:
+        if let Err(e) = fs::hard_link(&tmp, path) {
+            if e.kind() != std::io::ErrorKind::AlreadyExists {
+                println!("Failed to restore cuboid {}: {}", path.display(), e);
+            }
+        }
+        fs::remove_file(&tmp)?;
+        return Ok(0);
+    }
+    fs::remove_file(&tmp)?;
+    Ok(bytes.len() as u64)
+}
+
+/// Count the cuboid files directly in a directory, skipping subdirectories
+/// and in-progress writes.  A missing directory has none.
+///
+/// # Arguments
+///
+/// * `dir` - Directory of one resolution of a channel
+///
+pub fn count_cuboids(dir: &Path) -> u64 {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map_or(false, |t| t.is_file()))
+        .filter(|entry| !entry.file_name().to_string_lossy().ends_with(TMP_SUFFIX))
+        .count() as u64
+}
+
+/// Outcome of migrating a cache directory.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct MigrationReport {
+    /// Legacy cuboids rewritten with a header.
+    pub migrated: u32,
+    /// Files left alone (already versioned, or not cuboids).
+    pub skipped: u32,
+    /// Files that couldn't be read or rewritten.
+    pub failed: u32,
+}
+
+/// Rewrite every legacy cuboid under a directory in the current format.
+/// Nothing may write to the directory meanwhile: a cuboid written between
+/// being read and being replaced here would lose that write.
+///
+/// # Arguments
+///
+/// * `root` - Directory to walk (e.g. the cuboid root)
+/// * `cuboid_size` - Dimensions of the cuboids
+/// * `mode` - Permission bits for the rewritten files, or `None` to leave
+///   them to the umask
+///
+pub fn migrate_dir(
+    root: &Path,
+    cuboid_size: Vector3,
+    mode: Option<u32>,
+) -> std::io::Result<MigrationReport> {
+    let mut report = MigrationReport::default();
+    migrate_into(root, cuboid_size, mode, &mut report)?;
+    Ok(report)
+}
+
+fn migrate_into(
+    dir: &Path,
+    cuboid_size: Vector3,
+    mode: Option<u32>,
+    report: &mut MigrationReport,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            migrate_into(&path, cuboid_size, mode, report)?;
+            continue;
+        }
+        let filename = match path.to_str() {
+            Some(f) if !f.ends_with(TMP_SUFFIX) => f,
+            _ => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                report.failed += 1;
+                continue;
+            }
+        };
+        if bytes.len() != voxel_count(cuboid_size) {
+            report.skipped += 1;
+            continue;
+        }
+        match write_atomically(
+            filename,
+            &encode(CURRENT_VERSION, cuboid_size, &bytes),
+            mode,
+        ) {
+            Ok(_) => report.migrated += 1,
+            Err(err) => {
+                println!("Failed to migrate {}: {}", filename, err);
+                report.failed += 1;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// NumPy `.npy` module.
+///
+/// Just enough of the `.npy` format to read and write the cuboids of the
+/// Python bossphorus's `FilesystemStorageManager`: version 1-3 files of
+/// `uint8` in C order, whose shape is the cuboid's ZYX shape.
+///
+/// See https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html
+use crate::data_manager::Vector3;
+
+pub const MAGIC: [u8; 6] = *b"\x93NUMPY";
+
+/// The header (magic through padding) is a multiple of this many bytes.
+const ALIGNMENT: usize = 64;
+
+/// Build a version 1 `.npy` file holding a cuboid.
+///
+/// # Arguments
+///
+/// * `cuboid_size` - Dimensions of the cuboid
+/// * `voxels` - The `uint8` voxels in ZYX C-order
+///
+pub fn encode(cuboid_size: Vector3, voxels: &[u8]) -> Vec<u8> {
+    let mut dict = format!(
+        "{{'descr': '|u1', 'fortran_order': False, 'shape': ({}, {}, {}), }}",
+        cuboid_size.z, cuboid_size.y, cuboid_size.x
+    );
+    // Pad with spaces and a newline so the voxels are aligned:
+    let unpadded = MAGIC.len() + 4 + dict.len() + 1;
+    let padding = (ALIGNMENT - unpadded % ALIGNMENT) % ALIGNMENT;
+    dict.push_str(&" ".repeat(padding));
+    dict.push('\n');
+
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 4 + dict.len() + voxels.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&[1, 0]);
+    bytes.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(dict.as_bytes());
+    bytes.extend_from_slice(voxels);
+    bytes
+}
+
+/// Parse the header at the start of a `.npy` file, and return where its
+/// data starts.  Returns `None` unless the header is complete and describes
+/// a `uint8`, C-order array of the cuboid's shape.
+///
+/// # Arguments
+///
+/// * `prefix` - The start of the file, at least through the header
+/// * `cuboid_size` - Expected dimensions of the cuboid
+///
+pub fn data_offset(prefix: &[u8], cuboid_size: Vector3) -> Option<usize> {
+    if prefix.len() < 10 || prefix[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let (dict_start, dict_len) = match prefix[6] {
+        1 => (10, u16::from_le_bytes([prefix[8], prefix[9]]) as usize),
+        2 | 3 => {
+            let len = prefix.get(8..12)?;
+            (
+                12,
+                u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize,
+            )
+        }
+        _ => return None,
+    };
+    let dict = std::str::from_utf8(prefix.get(dict_start..dict_start + dict_len)?).ok()?;
+
+    let descr = field(dict, "descr")?.trim_matches(|c| c == '\'' || c == '"');
+    if !["u1", "|u1", "<u1", ">u1", "=u1"].contains(&descr) {
+        return None;
+    }
+    if field(dict, "fortran_order")? != "False" {
+        return None;
+    }
+    let shape: Vec<u64> = field(dict, "shape")?
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse().ok())
+        .collect::<Option<_>>()?;
+    if shape != [cuboid_size.z, cuboid_size.y, cuboid_size.x] {
+        return None;
+    }
+    Some(dict_start + dict_len)
+}
+
+/// Get the value of a key in a header's dict literal, as written.
+fn field<'a>(dict: &'a str, key: &str) -> Option<&'a str> {
+    let start = dict
+        .find(&format!("'{}'", key))
+        .or_else(|| dict.find(&format!("\"{}\"", key)))?;
+    let rest = dict[start + key.len() + 2..].trim_start();
+    if !rest.starts_with(':') {
+        return None;
+    }
+    let rest = rest[1..].trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')')? + 1
+    } else {
+        rest.find(|c| c == ',' || c == '}')?
+    };
+    Some(rest[..end].trim())
+}
@@ -0,0 +1,422 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::cuboid_file::{
+    create_dir_all, decode_header, decode_voxels, decompress, encode, is_complete, migrate_dir,
+    npy, parse_mode, read_file, read_region, read_region_with, remove_if_zero, voxels,
+    write_atomically, CuboidHeader, Layout, MigrationReport, ReadAdvice, ReadStrategy,
+    CURRENT_VERSION, DATATYPE_UINT8, HEADER_LEN, LEGACY_VERSION,
+};
+use crate::data_manager::Vector3;
+use ndarray::Array;
+use std::fs;
+use std::time::Instant;
+
+fn cuboid_size() -> Vector3 {
+    Vector3 { x: 4, y: 4, z: 2 }
+}
+
+#[test]
+fn test_encode_round_trip() {
+    let bytes = encode(CURRENT_VERSION, cuboid_size(), &[6; 32]);
+    assert_eq!(HEADER_LEN + 32, bytes.len());
+    assert_eq!(
+        Some(CuboidHeader {
+            version: CURRENT_VERSION,
+            datatype: DATATYPE_UINT8,
+            dims: cuboid_size(),
+        }),
+        decode_header(&bytes)
+    );
+    assert_eq!(Some(&[6; 32][..]), voxels(&bytes, cuboid_size()));
+}
+
+#[test]
+fn test_legacy_files_have_no_header() {
+    let bytes = encode(LEGACY_VERSION, cuboid_size(), &[6; 32]);
+    assert_eq!(None, decode_header(&bytes));
+    assert_eq!(Some(&[6; 32][..]), voxels(&bytes, cuboid_size()));
+}
+
+#[test]
+fn test_rejects_unreadable_files() {
+    // Partial:
+    assert_eq!(None, voxels(&[6; 10], cuboid_size()));
+
+    // Wrong dimensions for this cache:
+    let bytes = encode(CURRENT_VERSION, Vector3 { x: 2, y: 8, z: 2 }, &[6; 32]);
+    assert_eq!(None, voxels(&bytes, cuboid_size()));
+
+    // A version from the future:
+    let mut bytes = encode(CURRENT_VERSION, cuboid_size(), &[6; 32]);
+    bytes[4] = 99;
+    assert_eq!(None, voxels(&bytes, cuboid_size()));
+}
+
+#[test]
+fn test_migrate_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let res_dir = dir.path().join("col/exp/chan/0");
+    fs::create_dir_all(&res_dir).unwrap();
+    fs::write(res_dir.join("x0_y0_z0"), [1; 32]).unwrap();
+    fs::write(
+        res_dir.join("x1_y0_z0"),
+        encode(CURRENT_VERSION, cuboid_size(), &[2; 32]),
+    )
+    .unwrap();
+    fs::write(res_dir.join("x2_y0_z0.123-0.tmp"), [3; 32]).unwrap();
+
+    let report = migrate_dir(dir.path(), cuboid_size(), None).unwrap();
+    assert_eq!(
+        MigrationReport {
+            migrated: 1,
+            skipped: 2,
+            failed: 0,
+        },
+        report
+    );
+
+    let migrated = fs::read(res_dir.join("x0_y0_z0")).unwrap();
+    assert_eq!(CURRENT_VERSION, decode_header(&migrated).unwrap().version);
+    assert_eq!(Some(&[1; 32][..]), voxels(&migrated, cuboid_size()));
+
+    // Running it again is a no-op:
+    let report = migrate_dir(dir.path(), cuboid_size(), None).unwrap();
+    assert_eq!(0, report.migrated);
+}
+
+/// A `.npy` file as NumPy 1.x writes it for `np.full((2, 4, 4), 6, np.uint8)`.
+fn numpy_file() -> Vec<u8> {
+    let dict = "{'descr': '|u1', 'fortran_order': False, 'shape': (2, 4, 4), }";
+    let mut bytes = b"\x93NUMPY\x01\x00\x76\x00".to_vec();
+    bytes.extend_from_slice(dict.as_bytes());
+    bytes.extend_from_slice(&vec![b' '; 0x76 - 1 - dict.len()]);
+    bytes.push(b'\n');
+    assert_eq!(128, bytes.len());
+    bytes.extend_from_slice(&[6; 32]);
+    bytes
+}
+
+#[test]
+fn test_layout_keys() {
+    let index = Vector3 { x: 1, y: 2, z: 0 };
+    let size = Vector3 {
+        x: 512,
+        y: 512,
+        z: 16,
+    };
+    assert_eq!("x1_y2_z0", Layout::Native.key(&index, size));
+    assert_eq!(
+        "512-1024_1024-1536_0-16.npy",
+        Layout::Python.key(&index, size)
+    );
+    assert_eq!(Some(Layout::Python), Layout::parse("Python"));
+    assert_eq!(None, Layout::parse("s3"));
+}
+
+#[test]
+fn test_reads_numpy_files() {
+    let bytes = numpy_file();
+    assert_eq!(Some(128), npy::data_offset(&bytes, cuboid_size()));
+    assert_eq!(Some(&[6; 32][..]), voxels(&bytes, cuboid_size()));
+
+    // Transposed or truncated:
+    assert_eq!(None, voxels(&bytes, Vector3 { x: 2, y: 4, z: 4 }));
+    assert_eq!(None, voxels(&bytes[..100], cuboid_size()));
+
+    // Not uint8:
+    let mut wide = bytes.clone();
+    let descr = wide.windows(3).position(|w| w == b"|u1").unwrap();
+    wide[descr..descr + 3].copy_from_slice(b"<u2");
+    assert_eq!(None, voxels(&wide, cuboid_size()));
+}
+
+#[test]
+fn test_npy_round_trip() {
+    let bytes = npy::encode(cuboid_size(), &[9; 32]);
+    assert_eq!(0, (bytes.len() - 32) % 64);
+    assert_eq!(Some(&[9; 32][..]), voxels(&bytes, cuboid_size()));
+}
+
+#[test]
+fn test_is_complete() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cuboid");
+    assert!(!is_complete(&path, cuboid_size()));
+
+    fs::write(&path, numpy_file()).unwrap();
+    assert!(is_complete(&path, cuboid_size()));
+
+    fs::write(&path, &numpy_file()[..140]).unwrap();
+    assert!(!is_complete(&path, cuboid_size()));
+
+    fs::write(&path, encode(CURRENT_VERSION, cuboid_size(), &[2; 32])).unwrap();
+    assert!(is_complete(&path, cuboid_size()));
+}
+
+#[test]
+fn test_reads_compressed_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let raw = dir.path().join("raw");
+    let compressed = dir.path().join("compressed");
+    let expected: Vec<u8> = (0..32).collect();
+    fs::write(&raw, encode(LEGACY_VERSION, cuboid_size(), &expected)).unwrap();
+    let blosc: Vec<u8> = blosc::Context::new().compress(&expected[..]).into();
+    fs::write(&compressed, &blosc).unwrap();
+
+    let start = Vector3 { x: 1, y: 2, z: 1 };
+    let stop = Vector3 { x: 3, y: 4, z: 2 };
+    for path in &[&raw, &compressed] {
+        assert!(is_complete(path, cuboid_size()));
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(Some(expected.clone()), decode_voxels(bytes, cuboid_size()));
+        assert_eq!(
+            Some(Array::from_shape_vec((1, 2, 2), vec![25, 26, 29, 30]).unwrap()),
+            read_region(path, cuboid_size(), start, stop)
+        );
+    }
+
+    // Only the uncompressed file can be used in place:
+    assert_eq!(None, voxels(&blosc, cuboid_size()));
+    // Wrong size, or truncated:
+    assert_eq!(None, decompress(&blosc, Vector3 { x: 4, y: 4, z: 4 }));
+    assert_eq!(None, decompress(&blosc[..20], cuboid_size()));
+    fs::write(&compressed, &blosc[..20]).unwrap();
+    assert!(!is_complete(&compressed, cuboid_size()));
+    assert_eq!(None, read_region(&compressed, cuboid_size(), start, stop));
+}
+
+#[test]
+fn test_read_region() {
+    // Non-cubic, and every voxel distinct:
+    let size = Vector3 { x: 5, y: 3, z: 2 };
+    let cuboid = Array::from_shape_vec(size.to_zyx_shape(), (0..30).collect()).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let files = [
+        (
+            "legacy",
+            encode(LEGACY_VERSION, size, cuboid.as_slice().unwrap()),
+        ),
+        (
+            "versioned",
+            encode(CURRENT_VERSION, size, cuboid.as_slice().unwrap()),
+        ),
+        ("numpy.npy", npy::encode(size, cuboid.as_slice().unwrap())),
+    ];
+    for (name, bytes) in &files {
+        let path = dir.path().join(name);
+        fs::write(&path, bytes).unwrap();
+        for x0 in 0..=size.x {
+            for x1 in x0..=size.x {
+                for y0 in 0..=size.y {
+                    for y1 in y0..=size.y {
+                        for z0 in 0..=size.z {
+                            for z1 in z0..=size.z {
+                                let (start, stop) =
+                                    Vector3::from_xyz_extents((x0, x1), (y0, y1), (z0, z1));
+                                let expected =
+                                    Some(cuboid.slice(&Vector3::zyx_slice(start, stop)).to_owned());
+                                assert_eq!(
+                                    expected,
+                                    read_region(&path, size, start, stop),
+                                    "{} {:?}..{:?}",
+                                    name,
+                                    start,
+                                    stop
+                                );
+                                // Buffers smaller and larger than a row:
+                                for &buffer_size in &[3, 4096] {
+                                    let strategy = ReadStrategy {
+                                        buffer_size,
+                                        advice: ReadAdvice::Sequential,
+                                    };
+                                    assert_eq!(
+                                        expected,
+                                        read_region_with(&path, size, start, stop, strategy),
+                                        "{} {:?}..{:?} buffered {}",
+                                        name,
+                                        start,
+                                        stop,
+                                        buffer_size
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Partial files are misses, as with `voxels`:
+    let path = dir.path().join("partial");
+    fs::write(&path, &files[1].1[..HEADER_LEN + 5]).unwrap();
+    let all = (Vector3 { x: 0, y: 0, z: 0 }, size);
+    assert_eq!(None, read_region(&path, size, all.0, all.1));
+    assert_eq!(
+        None,
+        read_region(&dir.path().join("missing"), size, all.0, all.1)
+    );
+}
+
+#[test]
+fn test_read_advice() {
+    for advice in &[
+        ReadAdvice::Normal,
+        ReadAdvice::Sequential,
+        ReadAdvice::WillNeed,
+    ] {
+        assert_eq!(Some(*advice), ReadAdvice::parse(advice.as_str()));
+    }
+    assert_eq!(Some(ReadAdvice::WillNeed), ReadAdvice::parse("WillNeed"));
+    assert_eq!(None, ReadAdvice::parse("random"));
+}
+
+#[test]
+fn test_read_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("x0_y0_z0");
+    let bytes = encode(CURRENT_VERSION, cuboid_size(), &[3; 32]);
+    fs::write(&path, &bytes).unwrap();
+    for advice in &[ReadAdvice::Normal, ReadAdvice::Sequential] {
+        let strategy = ReadStrategy {
+            buffer_size: 0,
+            advice: *advice,
+        };
+        assert_eq!(bytes, read_file(&path, strategy).unwrap());
+    }
+    assert!(read_file(&dir.path().join("missing"), ReadStrategy::default()).is_err());
+}
+
+/// Compares reading a single z slice of a 512x512x16 cuboid with
+/// `read_region` to reading the whole file.  Run with
+/// `cargo test --release bench_single_z_slice -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_single_z_slice() {
+    let size = Vector3 {
+        x: 512,
+        y: 512,
+        z: 16,
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("x0_y0_z0");
+    fs::write(
+        &path,
+        encode(CURRENT_VERSION, size, &vec![7; 512 * 512 * 16]),
+    )
+    .unwrap();
+    let (start, stop) = Vector3::from_xyz_extents((0, 512), (0, 512), (8, 9));
+    let runs = 200;
+
+    let begin = Instant::now();
+    for _ in 0..runs {
+        let bytes = fs::read(&path).unwrap();
+        let cuboid =
+            Array::from_shape_vec(size.to_zyx_shape(), voxels(&bytes, size).unwrap().to_vec())
+                .unwrap();
+        assert_eq!(
+            512 * 512,
+            cuboid.slice(&Vector3::zyx_slice(start, stop)).len()
+        );
+    }
+    let whole = begin.elapsed() / runs;
+
+    let begin = Instant::now();
+    for _ in 0..runs {
+        assert_eq!(
+            512 * 512,
+            read_region(&path, size, start, stop).unwrap().len()
+        );
+    }
+    let region = begin.elapsed() / runs;
+
+    println!(
+        "whole cuboid: {:?}/read, one slice: {:?}/read",
+        whole, region
+    );
+}
+
+#[test]
+fn test_remove_if_zero() {
+    let dir = tempfile::tempdir().unwrap();
+    let zero = dir.path().join("zero");
+    let data = dir.path().join("data");
+    let partial = dir.path().join("partial");
+    let bytes = encode(CURRENT_VERSION, cuboid_size(), &[0; 32]);
+    fs::write(&zero, &bytes).unwrap();
+    let mut voxels = [0; 32];
+    voxels[31] = 1;
+    fs::write(&data, encode(CURRENT_VERSION, cuboid_size(), &voxels)).unwrap();
+    fs::write(&partial, &bytes[..30]).unwrap();
+
+    assert_eq!(
+        bytes.len() as u64,
+        remove_if_zero(&zero, cuboid_size()).unwrap()
+    );
+    assert!(!zero.exists());
+    assert_eq!(0, remove_if_zero(&data, cuboid_size()).unwrap());
+    assert!(data.exists());
+    // Partial files aren't cuboids of zeros:
+    assert_eq!(0, remove_if_zero(&partial, cuboid_size()).unwrap());
+    assert!(partial.exists());
+    // Nothing is left behind:
+    assert_eq!(2, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn test_parse_mode() {
+    assert_eq!(parse_mode("750"), Ok(0o750));
+    assert_eq!(parse_mode("0o2770"), Ok(0o2770));
+    assert_eq!(parse_mode("0640"), Ok(0o640));
+    assert!(parse_mode("").is_err());
+    assert!(parse_mode("789").is_err());
+    assert!(parse_mode("17777").is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_created_modes() {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = |path: &std::path::Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+    let dir = tempfile::tempdir().unwrap();
+    let existing = dir.path().join("existing");
+    fs::create_dir(&existing).unwrap();
+    fs::set_permissions(&existing, fs::Permissions::from_mode(0o700)).unwrap();
+
+    // Only the directories that get created are given the mode:
+    let nested = existing.join("a").join("b");
+    create_dir_all(&nested, Some(0o750)).unwrap();
+    assert_eq!(mode(&existing), 0o700);
+    assert_eq!(mode(&existing.join("a")), 0o750);
+    assert_eq!(mode(&nested), 0o750);
+    create_dir_all(&nested, Some(0o755)).unwrap();
+    assert_eq!(mode(&nested), 0o750);
+
+    let file = nested.join("x0_y0_z0");
+    write_atomically(file.to_str().unwrap(), b"voxels", Some(0o640)).unwrap();
+    assert_eq!(mode(&file), 0o640);
+    write_atomically(file.to_str().unwrap(), b"voxels", Some(0o604)).unwrap();
+    assert_eq!(mode(&file), 0o604);
+
+    // Migrated cuboids are given the mode too:
+    fs::write(&file, [1; 32]).unwrap();
+    migrate_dir(&nested, cuboid_size(), Some(0o640)).unwrap();
+    assert_eq!(mode(&file), 0o640);
+}
@@ -0,0 +1,462 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Cutout request module.
+///
+/// Parses and validates the path of a `/cutout/...` request once, so that
+/// every cutout endpoint agrees on what a valid cutout is.
+///
+/// Cutouts are requested in global coordinates, which may be negative, but
+/// are indexed relative to the start of the coordinate frame (see
+/// `Coords::to_frame`).
+use crate::data_manager::{Coords, Vector3};
+use ndarray::{s, Array3};
+use serde::Deserialize;
+use std::fmt;
+
+#[cfg(test)]
+pub mod tests;
+
+/// A cutout of a channel, as given in the path of a cutout endpoint:
+/// `<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CutoutRequest {
+    /// The channel, as `collection/experiment/channel`.
+    pub channel: String,
+    pub res: u8,
+    /// The start of the cutout, relative to the start of the frame.
+    pub origin: Vector3,
+    /// The (exclusive) end of the cutout, relative to the start of the
+    /// frame.
+    pub destination: Vector3,
+    /// Where the coordinate frame starts (global coords, at resolution 0).
+    pub frame: Coords,
+    /// Only every `stride`th voxel along each axis is sent, starting at
+    /// the origin.  All ones (the default) sends every voxel.
+    pub stride: Vector3,
+    /// Axes reversed before the cutout is sent.
+    pub flip: Flip,
+}
+
+/// The stride of a cutout that sends every voxel.
+pub const NO_STRIDE: Vector3 = Vector3 { x: 1, y: 1, z: 1 };
+
+/// Which axes of a cutout to reverse, e.g. for a viewer whose y axis points
+/// the other way.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Flip {
+    pub x: bool,
+    pub y: bool,
+    pub z: bool,
+}
+
+/// The flip of a cutout sent as stored.
+pub const NO_FLIP: Flip = Flip {
+    x: false,
+    y: false,
+    z: false,
+};
+
+impl fmt::Display for Flip {
+    /// The flipped axes, like `x,z`, as `parse_flip` takes them.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let axes: Vec<&str> = [(self.x, "x"), (self.y, "y"), (self.z, "z")]
+            .iter()
+            .filter(|(flipped, _)| *flipped)
+            .map(|(_, axis)| *axis)
+            .collect();
+        write!(f, "{}", axes.join(","))
+    }
+}
+
+impl CutoutRequest {
+    /// Parse a cutout from its path segments.  Fails with a message for the
+    /// client if any extents are malformed, reversed, empty, or start before
+    /// the frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - Collection name
+    /// * `experiment` - Experiment name
+    /// * `channel` - Channel name
+    /// * `res` - Resolution level
+    /// * `xs` - Global extents along x, like `0:512` or `-512:0`
+    /// * `ys` - Global extents along y
+    /// * `zs` - Global extents along z
+    /// * `frame` - Where the coordinate frame starts, at resolution 0
+    ///
+    pub fn parse(
+        collection: &str,
+        experiment: &str,
+        channel: &str,
+        res: u8,
+        xs: &str,
+        ys: &str,
+        zs: &str,
+        frame: Coords,
+    ) -> Result<CutoutRequest, String> {
+        let (xs, ys, zs) = (
+            parse_extents("x", xs)?,
+            parse_extents("y", ys)?,
+            parse_extents("z", zs)?,
+        );
+        let origin = Coords {
+            x: xs.0,
+            y: ys.0,
+            z: zs.0,
+        };
+        let destination = Coords {
+            x: xs.1,
+            y: ys.1,
+            z: zs.1,
+        };
+        CutoutRequest::global(
+            collection,
+            experiment,
+            channel,
+            res,
+            origin,
+            destination,
+            frame,
+        )
+    }
+
+    /// Build a cutout from global extents that are already numbers, e.g.
+    /// from a `CutoutQuery`.  Fails with a message for the client if any
+    /// extents are reversed, empty, or start before the frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - Collection name
+    /// * `experiment` - Experiment name
+    /// * `channel` - Channel name
+    /// * `res` - Resolution level
+    /// * `origin` - The start of the cutout (global coords)
+    /// * `destination` - The end of the cutout in global coords
+    /// * `frame` - Where the coordinate frame starts, at resolution 0
+    ///
+    pub fn global(
+        collection: &str,
+        experiment: &str,
+        channel: &str,
+        res: u8,
+        origin: Coords,
+        destination: Coords,
+        frame: Coords,
+    ) -> Result<CutoutRequest, String> {
+        let at_res = frame.at_res(res);
+        let start = origin.to_frame(at_res).ok_or_else(|| {
+            format!(
+                "The cutout starts at {}, before the coordinate frame, which starts at {} at resolution {}",
+                origin, at_res, res
+            )
+        })?;
+        // Reversed extents end before the frame, but are reported as reversed:
+        let stop = destination.to_frame(at_res).unwrap_or(start);
+        let request = CutoutRequest::new(collection, experiment, channel, res, start, stop)?;
+        Ok(CutoutRequest { frame, ..request })
+    }
+
+    /// Build a cutout from extents relative to the start of the frame.
+    /// Fails with a message for the client if any extents are reversed or
+    /// empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - Collection name
+    /// * `experiment` - Experiment name
+    /// * `channel` - Channel name
+    /// * `res` - Resolution level
+    /// * `origin` - The start of the cutout, relative to the frame
+    /// * `destination` - The end of the cutout, relative to the frame
+    ///
+    pub fn new(
+        collection: &str,
+        experiment: &str,
+        channel: &str,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Result<CutoutRequest, String> {
+        for name in &[collection, experiment, channel] {
+            check_channel_name(name)?;
+        }
+        let axes = [
+            ("x", origin.x, destination.x),
+            ("y", origin.y, destination.y),
+            ("z", origin.z, destination.z),
+        ];
+        for (axis, start, stop) in axes.iter() {
+            if start >= stop {
+                return Err(format!(
+                    "The {} extents {}:{} must not be empty or reversed",
+                    axis, start, stop
+                ));
+            }
+        }
+        Ok(CutoutRequest {
+            channel: format!("{}/{}/{}", collection, experiment, channel),
+            res,
+            origin,
+            destination,
+            frame: Coords::default(),
+            stride: NO_STRIDE,
+            flip: NO_FLIP,
+        })
+    }
+
+    /// The same cutout, subsampled to every `stride`th voxel along each
+    /// axis, e.g. for a quick preview.
+    pub fn with_stride(self, stride: Vector3) -> CutoutRequest {
+        CutoutRequest { stride, ..self }
+    }
+
+    /// The same cutout, with the given axes reversed.
+    pub fn with_flip(self, flip: Flip) -> CutoutRequest {
+        CutoutRequest { flip, ..self }
+    }
+
+    /// The channel as a URI like `bossdb://col/exp/chan`, as the data
+    /// managers take it.
+    pub fn uri(&self) -> String {
+        format!("bossdb://{}", self.channel)
+    }
+
+    /// The same region at a lower resolution level, where each level halves
+    /// x and y.  The extents are rounded outwards, so the region is always
+    /// covered.
+    ///
+    /// # Arguments
+    ///
+    /// * `res` - Resolution level, at or below this request's
+    ///
+    pub fn at_res(&self, res: u8) -> CutoutRequest {
+        let shift = (res - self.res) as u32;
+        let down = |v: u64| v >> shift;
+        let up = |v: u64| (v >> shift) + (v & ((1 << shift) - 1) != 0) as u64;
+        CutoutRequest {
+            channel: self.channel.clone(),
+            res,
+            origin: Vector3 {
+                x: down(self.origin.x),
+                y: down(self.origin.y),
+                z: self.origin.z,
+            },
+            destination: Vector3 {
+                x: up(self.destination.x),
+                y: up(self.destination.y),
+                z: self.destination.z,
+            },
+            frame: self.frame,
+            stride: self.stride,
+            flip: self.flip,
+        }
+    }
+
+    /// The start of the cutout, in global coordinates.
+    pub fn global_origin(&self) -> Coords {
+        Coords::from_frame(self.origin, self.frame.at_res(self.res))
+    }
+
+    /// The (exclusive) end of the cutout, in global coordinates.
+    pub fn global_destination(&self) -> Coords {
+        Coords::from_frame(self.destination, self.frame.at_res(self.res))
+    }
+
+    /// The size of the cutout.
+    pub fn shape(&self) -> Vector3 {
+        // Parsing checks that the extents are in order:
+        Vector3::checked_shape(self.origin, self.destination).expect("Reversed extents")
+    }
+
+    /// The size of the cutout that's sent, after subsampling by the stride.
+    pub fn strided_shape(&self) -> Vector3 {
+        let shape = self.shape();
+        let div_up = |size: u64, stride: u64| (size + stride - 1) / stride;
+        Vector3 {
+            x: div_up(shape.x, self.stride.x),
+            y: div_up(shape.y, self.stride.y),
+            z: div_up(shape.z, self.stride.z),
+        }
+    }
+
+    /// Estimated bytes held in memory while serving the cutout: the whole
+    /// cutout as read, plus the subsampled or encoded copy that's sent, at
+    /// a byte per `uint8` voxel.
+    pub fn buffer_size(&self) -> u64 {
+        // Saturates, so an absurd cutout is over any budget rather than
+        // wrapping around to a small one:
+        let voxels = |shape: Vector3| shape.x.saturating_mul(shape.y).saturating_mul(shape.z);
+        voxels(self.shape()).saturating_add(voxels(self.strided_shape()))
+    }
+
+    /// Subsample the voxels of the cutout by the stride, then reverse the
+    /// flipped axes.  The result is always in standard layout, ready to
+    /// encode.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The whole cutout, in ZYX order
+    ///
+    pub fn subsample(&self, data: Array3<u8>) -> Array3<u8> {
+        if self.stride == NO_STRIDE && self.flip == NO_FLIP {
+            return data;
+        }
+        // A stride past the end of an axis just keeps its first voxel:
+        let shape = Vector3::from_zyx_shape(data.shape());
+        let (x, y, z) = (
+            self.stride.x.min(shape.x) as isize,
+            self.stride.y.min(shape.y) as isize,
+            self.stride.z.min(shape.z) as isize,
+        );
+        // Flipped after sampling, so the voxels sent are the same either way:
+        let step = |flipped: bool| if flipped { -1 } else { 1 };
+        let (fx, fy, fz) = (step(self.flip.x), step(self.flip.y), step(self.flip.z));
+        data.slice(s![..;z, ..;y, ..;x])
+            .slice(s![..;fz, ..;fy, ..;fx])
+            .to_owned()
+    }
+}
+
+/// The JSON body of a cutout query, e.g. `{"res": 0, "origin": {"x": 0,
+/// "y": 0, "z": 0}, "destination": {"x": 512, "y": 512, "z": 16},
+/// "format": "blosc"}`.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CutoutQuery {
+    pub res: u8,
+    /// The start of the cutout (global coords).
+    pub origin: Coords,
+    /// The end of the cutout in global coords.
+    pub destination: Coords,
+    /// Name of the format to respond with, if not the default.
+    pub format: Option<String>,
+}
+
+impl CutoutQuery {
+    /// Parse a query body.  Fails with a message for the client if it's not
+    /// a valid query.
+    pub fn parse(body: &[u8]) -> Result<CutoutQuery, String> {
+        serde_json::from_slice(body).map_err(|e| format!("Malformed cutout query: {}", e))
+    }
+}
+
+/// Check one segment of a channel's name (its collection, experiment or
+/// channel), which names a directory in the cache.  Fails with a message for
+/// the client for names that are empty, or that would reach outside the
+/// channel's directory, like `..`.
+pub fn check_channel_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(format!("Invalid channel name \"{}\"", name));
+    }
+    Ok(())
+}
+
+/// Parse colon-delimited extents like `0:512` or `-512:0` into a `(start,
+/// stop)` pair, with `start` before `stop`.
+///
+/// # Arguments
+///
+/// * `axis` - Name of the axis, for error messages
+/// * `value` - The extents
+///
+pub fn parse_extents(axis: &str, value: &str) -> Result<(i64, i64), String> {
+    let bounds: Vec<&str> = value.split(':').collect();
+    let parse = |bound: &str| bound.parse::<i64>().ok();
+    let (start, stop) = match bounds.as_slice() {
+        [start, stop] => match (parse(start), parse(stop)) {
+            (Some(start), Some(stop)) => (start, stop),
+            _ => return Err(format!("Invalid {} extents {}", axis, value)),
+        },
+        _ => {
+            return Err(format!(
+                "Invalid {} extents {} (expected start:stop)",
+                axis, value
+            ))
+        }
+    };
+    if start >= stop {
+        return Err(format!(
+            "The {} extents {} must not be empty or reversed",
+            axis, value
+        ));
+    }
+    Ok((start, stop))
+}
+
+/// Clamp a JPEG quality to the encoder's range, 1 (smallest) to 100
+/// (best).
+pub fn clamp_quality(quality: i64) -> u8 {
+    quality.max(1).min(100) as u8
+}
+
+/// Parse a JPEG quality, clamped to 1-100.  Fails only if it's not an
+/// integer.
+pub fn parse_quality(value: &str) -> Result<u8, String> {
+    value
+        .parse::<i64>()
+        .map(clamp_quality)
+        .map_err(|_| format!("Invalid JPEG quality {} (expected 1 to 100)", value))
+}
+
+/// Parse the axes to flip, like `x,z`.
+pub fn parse_flip(value: &str) -> Result<Flip, String> {
+    let mut flip = NO_FLIP;
+    for axis in value.split(',') {
+        match axis {
+            "x" => flip.x = true,
+            "y" => flip.y = true,
+            "z" => flip.z = true,
+            _ => return Err(format!("Invalid flip {} (expected axes like x,y,z)", value)),
+        }
+    }
+    Ok(flip)
+}
+
+/// Parse a vector like `2:2:1` (x, y and z), each at least 1.
+fn parse_positive(value: &str) -> Option<Vector3> {
+    let parts: Vec<Option<u64>> = value.split(':').map(|s| s.parse::<u64>().ok()).collect();
+    match parts.as_slice() {
+        [Some(x), Some(y), Some(z)] if *x > 0 && *y > 0 && *z > 0 => Some(Vector3 {
+            x: *x,
+            y: *y,
+            z: *z,
+        }),
+        _ => None,
+    }
+}
+
+/// Parse a stride like `2:2:1` (along x, y and z), each at least 1.
+pub fn parse_stride(value: &str) -> Result<Vector3, String> {
+    parse_positive(value).ok_or_else(|| {
+        format!(
+            "Invalid stride {} (expected sx:sy:sz, each at least 1)",
+            value
+        )
+    })
+}
+
+/// Parse a cuboid size like `512:512:16` (x, y and z), each at least 1.
+pub fn parse_cuboid_size(value: &str) -> Result<Vector3, String> {
+    parse_positive(value).ok_or_else(|| {
+        format!(
+            "Invalid cuboid size {} (expected cx:cy:cz, each at least 1)",
+            value
+        )
+    })
+}
@@ -0,0 +1,411 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use super::{
+    parse_cuboid_size, parse_extents, parse_flip, parse_quality, parse_stride, CutoutQuery,
+    CutoutRequest, Flip, NO_FLIP, NO_STRIDE,
+};
+use crate::data_manager::{Coords, Vector3};
+use ndarray::Array;
+
+/// A frame starting at zero, as for most datasets.
+const NO_FRAME: Coords = Coords { x: 0, y: 0, z: 0 };
+
+#[test]
+fn test_parse_extents() {
+    assert_eq!(Ok((0, 512)), parse_extents("x", "0:512"));
+    assert_eq!(Ok((511, 512)), parse_extents("x", "511:512"));
+    assert_eq!(Ok((-512, 0)), parse_extents("x", "-512:0"));
+    assert_eq!(Ok((-1, 4)), parse_extents("x", "-1:4"));
+    assert_eq!(
+        Ok((0, i64::MAX)),
+        parse_extents("x", &format!("0:{}", i64::MAX))
+    );
+}
+
+#[test]
+fn test_malformed_extents() {
+    for value in &[
+        "",
+        ":",
+        "0",
+        "0:",
+        ":512",
+        "0:1:2",
+        "a:b",
+        "--1:4",
+        " 0:4",
+        "0:4 ",
+        "0.5:4",
+        "0:9223372036854775808",
+    ] {
+        assert!(parse_extents("x", value).is_err(), "{} parsed", value);
+    }
+}
+
+#[test]
+fn test_empty_or_reversed_extents() {
+    assert!(parse_extents("y", "4:4").is_err());
+    let err = parse_extents("z", "16:0").unwrap_err();
+    assert!(err.contains("z"));
+    assert!(err.contains("16:0"));
+}
+
+#[test]
+fn test_parse_cutout() {
+    let cutout = CutoutRequest::parse(
+        "col", "exp", "chan", 2, "0:512", "512:1024", "4:20", NO_FRAME,
+    )
+    .unwrap();
+    assert_eq!("col/exp/chan", cutout.channel);
+    assert_eq!("bossdb://col/exp/chan", cutout.uri());
+    assert_eq!(2, cutout.res);
+    assert_eq!(Vector3 { x: 0, y: 512, z: 4 }, cutout.origin);
+    assert_eq!(
+        Vector3 {
+            x: 512,
+            y: 1024,
+            z: 20
+        },
+        cutout.destination
+    );
+    assert_eq!(
+        Vector3 {
+            x: 512,
+            y: 512,
+            z: 16
+        },
+        cutout.shape()
+    );
+}
+
+#[test]
+fn test_invalid_cutout() {
+    assert!(CutoutRequest::parse("col", "exp", "chan", 0, "0:4", "4:0", "0:1", NO_FRAME).is_err());
+    assert!(CutoutRequest::parse("col", "exp", "chan", 0, "0:4", "0:4", "1", NO_FRAME).is_err());
+    assert!(CutoutRequest::parse("", "exp", "chan", 0, "0:4", "0:4", "0:1", NO_FRAME).is_err());
+    assert!(CutoutRequest::parse("col", "..", "chan", 0, "0:4", "0:4", "0:1", NO_FRAME).is_err());
+    assert!(CutoutRequest::parse("col", "exp", ".", 0, "0:4", "0:4", "0:1", NO_FRAME).is_err());
+}
+
+#[test]
+fn test_at_res() {
+    let cutout = CutoutRequest::parse(
+        "col", "exp", "chan", 0, "100:612", "0:1024", "4:20", NO_FRAME,
+    )
+    .unwrap();
+    assert_eq!(cutout, cutout.at_res(0));
+
+    let half = cutout.at_res(1);
+    assert_eq!(1, half.res);
+    assert_eq!(Vector3 { x: 50, y: 0, z: 4 }, half.origin);
+    assert_eq!(
+        Vector3 {
+            x: 306,
+            y: 512,
+            z: 20
+        },
+        half.destination
+    );
+
+    // Rounded outwards, and never empty:
+    let tiny = cutout.at_res(10);
+    assert_eq!(Vector3 { x: 0, y: 0, z: 4 }, tiny.origin);
+    assert_eq!(Vector3 { x: 1, y: 1, z: 20 }, tiny.destination);
+}
+
+#[test]
+fn test_parse_stride() {
+    assert_eq!(Ok(NO_STRIDE), parse_stride("1:1:1"));
+    assert_eq!(Ok(Vector3 { x: 4, y: 2, z: 1 }), parse_stride("4:2:1"));
+    for value in &["", "2", "2:2", "2:2:2:2", "0:1:1", "2:x:1", "-1:1:1"] {
+        assert!(parse_stride(value).is_err(), "{}", value);
+    }
+}
+
+#[test]
+fn test_parse_cuboid_size() {
+    assert_eq!(
+        Ok(Vector3 {
+            x: 512,
+            y: 512,
+            z: 16
+        }),
+        parse_cuboid_size("512:512:16")
+    );
+    for value in &["", "512:512", "512:0:16", "512:512:16:1", "a:b:c"] {
+        assert!(parse_cuboid_size(value).is_err(), "{}", value);
+    }
+}
+
+#[test]
+fn test_parse_flip() {
+    let x = Flip { x: true, ..NO_FLIP };
+    assert_eq!(Ok(x), parse_flip("x"));
+    let all = Flip {
+        x: true,
+        y: true,
+        z: true,
+    };
+    assert_eq!(Ok(all), parse_flip("z,x,y"));
+    assert_eq!("x,y,z", all.to_string());
+    for value in &["", "w", "x,", "x;y", "X"] {
+        assert!(parse_flip(value).is_err(), "{}", value);
+    }
+}
+
+#[test]
+fn test_parse_quality() {
+    assert_eq!(Ok(1), parse_quality("1"));
+    assert_eq!(Ok(40), parse_quality("40"));
+    assert_eq!(Ok(100), parse_quality("100"));
+    // Out of range values are clamped:
+    assert_eq!(Ok(1), parse_quality("0"));
+    assert_eq!(Ok(1), parse_quality("-20"));
+    assert_eq!(Ok(100), parse_quality("250"));
+    for value in &["", "high", "50.5", "99999999999999999999"] {
+        assert!(parse_quality(value).is_err(), "{}", value);
+    }
+}
+
+#[test]
+fn test_subsample() {
+    let cutout =
+        CutoutRequest::parse("col", "exp", "chan", 0, "0:5", "0:4", "0:3", NO_FRAME).unwrap();
+    let data = Array::from_shape_fn((3, 4, 5), |(z, y, x)| (100 * z + 10 * y + x) as u8);
+    assert_eq!(NO_STRIDE, cutout.stride);
+    assert_eq!(cutout.shape(), cutout.strided_shape());
+    assert_eq!(data, cutout.subsample(data.clone()));
+
+    // Partial steps at the end of an axis still keep a voxel:
+    let strided = cutout.with_stride(Vector3 { x: 2, y: 3, z: 5 });
+    assert_eq!(Vector3 { x: 3, y: 2, z: 1 }, strided.strided_shape());
+    let sampled = strided.subsample(data);
+    assert_eq!(&[1, 2, 3], sampled.shape());
+    assert!(sampled.is_standard_layout());
+    assert_eq!(vec![0, 2, 4, 30, 32, 34], sampled.into_raw_vec());
+}
+
+#[test]
+fn test_flip() {
+    let cutout =
+        CutoutRequest::parse("col", "exp", "chan", 0, "0:3", "0:2", "0:2", NO_FRAME).unwrap();
+    let data = Array::from_shape_fn((2, 2, 3), |(z, y, x)| (100 * z + 10 * y + x) as u8);
+    assert_eq!(NO_FLIP, cutout.flip);
+    let flipped = |flip: Flip| {
+        let data = cutout.clone().with_flip(flip).subsample(data.clone());
+        assert!(data.is_standard_layout());
+        data.into_raw_vec()
+    };
+
+    let x = Flip { x: true, ..NO_FLIP };
+    assert_eq!(
+        vec![2, 1, 0, 12, 11, 10, 102, 101, 100, 112, 111, 110],
+        flipped(x)
+    );
+    let y = Flip { y: true, ..NO_FLIP };
+    assert_eq!(
+        vec![10, 11, 12, 0, 1, 2, 110, 111, 112, 100, 101, 102],
+        flipped(y)
+    );
+    let z = Flip { z: true, ..NO_FLIP };
+    assert_eq!(
+        vec![100, 101, 102, 110, 111, 112, 0, 1, 2, 10, 11, 12],
+        flipped(z)
+    );
+
+    // Flipped after subsampling, so the same voxels are sent:
+    let strided = cutout
+        .with_stride(Vector3 { x: 2, y: 1, z: 1 })
+        .with_flip(x);
+    let sampled = strided.subsample(data);
+    assert_eq!(&[2, 2, 2], sampled.shape());
+    assert_eq!(
+        vec![2, 0, 12, 10, 102, 100, 112, 110],
+        sampled.into_raw_vec()
+    );
+}
+
+#[test]
+fn test_buffer_size() {
+    let cutout =
+        CutoutRequest::parse("col", "exp", "chan", 0, "0:5", "0:4", "0:3", NO_FRAME).unwrap();
+    assert_eq!(2 * 60, cutout.buffer_size());
+    let strided = cutout.with_stride(Vector3 { x: 2, y: 3, z: 5 });
+    assert_eq!(60 + 6, strided.buffer_size());
+
+    // Too big to count still doesn't fit any budget:
+    let huge = CutoutRequest::parse(
+        "col",
+        "exp",
+        "chan",
+        0,
+        "0:4294967296",
+        "0:4294967296",
+        "0:4294967296",
+        NO_FRAME,
+    )
+    .unwrap();
+    assert_eq!(u64::MAX, huge.buffer_size());
+}
+
+#[test]
+fn test_new_cutout() {
+    let origin = Vector3 { x: 0, y: 0, z: 4 };
+    let destination = Vector3 {
+        x: 512,
+        y: 512,
+        z: 20,
+    };
+    assert_eq!(
+        CutoutRequest::parse("col", "exp", "chan", 1, "0:512", "0:512", "4:20", NO_FRAME),
+        CutoutRequest::new("col", "exp", "chan", 1, origin, destination)
+    );
+    // Empty along z:
+    assert!(CutoutRequest::new("col", "exp", "chan", 1, origin, origin).is_err());
+    // Reversed:
+    assert!(CutoutRequest::new("col", "exp", "chan", 1, destination, origin).is_err());
+}
+
+#[test]
+fn test_parse_query() {
+    let query = CutoutQuery::parse(
+        br#"{"res": 1, "origin": {"x": 0, "y": 0, "z": 4},
+            "destination": {"x": 512, "y": 512, "z": 20}, "format": "raw"}"#,
+    )
+    .unwrap();
+    assert_eq!(1, query.res);
+    assert_eq!(Coords { x: 0, y: 0, z: 4 }, query.origin);
+    assert_eq!(Some("raw".to_string()), query.format);
+
+    let query = CutoutQuery::parse(
+        br#"{"res": 0, "origin": {"x": 0, "y": 0, "z": 0},
+            "destination": {"x": 1, "y": 1, "z": 1}}"#,
+    )
+    .unwrap();
+    assert_eq!(None, query.format);
+}
+
+#[test]
+fn test_malformed_query() {
+    for body in &[
+        &b""[..],
+        b"not json",
+        b"{\"res\": 0",
+        // Missing the destination:
+        br#"{"res": 0, "origin": {"x": 0, "y": 0, "z": 0}}"#,
+        // Out of range:
+        br#"{"res": 0, "origin": {"x": 9223372036854775808, "y": 0, "z": 0},
+            "destination": {"x": 1, "y": 1, "z": 1}}"#,
+        // A typo:
+        br#"{"res": 0, "origin": {"x": 0, "y": 0, "z": 0},
+            "destination": {"x": 1, "y": 1, "z": 1}, "fromat": "raw"}"#,
+    ] {
+        let err = CutoutQuery::parse(body).unwrap_err();
+        assert!(err.starts_with("Malformed cutout query"));
+    }
+}
+
+#[test]
+fn test_negative_origin() {
+    let frame = Coords {
+        x: -1024,
+        y: -1024,
+        z: -16,
+    };
+    let cutout = CutoutRequest::parse(
+        "col",
+        "exp",
+        "chan",
+        0,
+        "-1024:-512",
+        "-512:0",
+        "-16:0",
+        frame,
+    )
+    .unwrap();
+    // Mapped into the positive cuboid grid:
+    assert_eq!(Vector3 { x: 0, y: 512, z: 0 }, cutout.origin);
+    assert_eq!(
+        Vector3 {
+            x: 512,
+            y: 1024,
+            z: 16
+        },
+        cutout.destination
+    );
+    assert_eq!(
+        Coords {
+            x: -1024,
+            y: -512,
+            z: -16
+        },
+        cutout.global_origin()
+    );
+    assert_eq!(
+        Coords {
+            x: -512,
+            y: 0,
+            z: 0
+        },
+        cutout.global_destination()
+    );
+
+    // The frame halves in x and y along with the cutout:
+    let half = cutout.at_res(1);
+    assert_eq!(Vector3 { x: 0, y: 256, z: 0 }, half.origin);
+    assert_eq!(
+        Coords {
+            x: -512,
+            y: -256,
+            z: -16
+        },
+        half.global_origin()
+    );
+    assert_eq!(
+        Ok(half),
+        CutoutRequest::parse(
+            "col",
+            "exp",
+            "chan",
+            1,
+            "-512:-256",
+            "-256:0",
+            "-16:0",
+            frame
+        )
+    );
+}
+
+#[test]
+fn test_before_frame() {
+    let frame = Coords {
+        x: -1024,
+        y: 0,
+        z: 0,
+    };
+    let err =
+        CutoutRequest::parse("col", "exp", "chan", 0, "-1025:0", "0:4", "0:4", frame).unwrap_err();
+    assert!(err.contains("before the coordinate frame"));
+    // Without a frame, any negative extents are before it:
+    assert!(CutoutRequest::parse("col", "exp", "chan", 0, "-4:4", "0:4", "0:4", NO_FRAME).is_err());
+    // Reversed extents are still reported as reversed:
+    let err =
+        CutoutRequest::parse("col", "exp", "chan", 0, "0:4", "0:4", "4:0", frame).unwrap_err();
+    assert!(err.contains("4:0"));
+}
@@ -24,18 +24,36 @@ limitations under the License.
 /// one else should have to worry about slicing and dicing, but if you do
 /// want to, you can use `data_manager::get_cuboids_and_indices`, which is
 /// a lot prettier than my Python implementation, if I do say so myself.
+use crate::cuboid_file::{self, npy, write_atomically, Layout, Modes, ReadStrategy};
+use crate::db::channel_keys::ChannelKeys;
+use crate::db::channels::{ChannelInfo, ChannelRegistry};
+use crate::db::empty::EmptyCuboids;
+use crate::db::scratch::ScratchChannels;
+use crate::disk_guard::DiskGuard;
+use crate::downsample::{self, Downsampling, SynthesisMethods};
+use crate::etag::{self, CuboidHashes, Fnv64};
 use crate::intern;
+use crate::semaphore::{Semaphore, SemaphoreGuard};
 use crate::usage_tracker;
+use crate::write_buffer::WriteBuffer;
 
-use intern::remote::BossRemote;
-use ndarray::{s, Array, Array3};
-use std::collections::HashMap;
+use intern::remote::{self, BossRemote};
+use memmap2::Mmap;
+use ndarray::{Array, Array3, ArrayView3, Ix3, SliceInfo, SliceOrIndex};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt;
 use std::fs;
-use std::io::prelude::*;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg(test)]
+pub mod tests;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Vector3 {
     /// A vector of X, Y, and Z members.
     ///
@@ -48,12 +66,185 @@ pub struct Vector3 {
     pub z: u64,
 }
 
+/// The BossDB API gives extents as `x`, `y`, `z`, but arrays are C-ordered
+/// `z`, `y`, `x`.  Every conversion between the two goes through these, so
+/// the ordering is defined in one place.
+impl Vector3 {
+    /// Build the start and (exclusive) stop corners of a region from its
+    /// extents along each axis, as given to the BossDB API.
+    ///
+    /// # Arguments
+    ///
+    /// * `xs` - `(start, stop)` along x
+    /// * `ys` - `(start, stop)` along y
+    /// * `zs` - `(start, stop)` along z
+    ///
+    pub fn from_xyz_extents(xs: (u64, u64), ys: (u64, u64), zs: (u64, u64)) -> (Vector3, Vector3) {
+        (
+            Vector3 {
+                x: xs.0,
+                y: ys.0,
+                z: zs.0,
+            },
+            Vector3 {
+                x: xs.1,
+                y: ys.1,
+                z: zs.1,
+            },
+        )
+    }
+
+    /// Size of the region from `start` to `stop`, or `None` if it's
+    /// reversed along any axis.
+    pub fn checked_shape(start: Vector3, stop: Vector3) -> Option<Vector3> {
+        Some(Vector3 {
+            x: stop.x.checked_sub(start.x)?,
+            y: stop.y.checked_sub(start.y)?,
+            z: stop.z.checked_sub(start.z)?,
+        })
+    }
+
+    /// The region where two regions overlap, as start and (exclusive) stop,
+    /// or `None` if they don't.
+    pub fn intersection(
+        a: (Vector3, Vector3),
+        b: (Vector3, Vector3),
+    ) -> Option<(Vector3, Vector3)> {
+        let start = Vector3 {
+            x: a.0.x.max(b.0.x),
+            y: a.0.y.max(b.0.y),
+            z: a.0.z.max(b.0.z),
+        };
+        let stop = Vector3 {
+            x: a.1.x.min(b.1.x),
+            y: a.1.y.min(b.1.y),
+            z: a.1.z.min(b.1.z),
+        };
+        if start.x < stop.x && start.y < stop.y && start.z < stop.z {
+            Some((start, stop))
+        } else {
+            None
+        }
+    }
+
+    /// The ZYX shape of an array holding a region of this size.
+    pub fn to_zyx_shape(&self) -> (usize, usize, usize) {
+        (self.z as usize, self.y as usize, self.x as usize)
+    }
+
+    /// The size of the region held by an array of this ZYX shape (e.g. from
+    /// `ArrayBase::shape()`).
+    pub fn from_zyx_shape(shape: &[usize]) -> Vector3 {
+        Vector3 {
+            x: shape[2] as u64,
+            y: shape[1] as u64,
+            z: shape[0] as u64,
+        }
+    }
+
+    /// Slice of a ZYX array from `start` to (exclusive) `stop`.
+    pub fn zyx_slice(start: Vector3, stop: Vector3) -> SliceInfo<[SliceOrIndex; 3], Ix3> {
+        SliceInfo::new([
+            SliceOrIndex::from(start.z as usize..stop.z as usize),
+            SliceOrIndex::from(start.y as usize..stop.y as usize),
+            SliceOrIndex::from(start.x as usize..stop.x as usize),
+        ])
+        .unwrap()
+    }
+}
+
 impl fmt::Display for Vector3 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         return write!(f, "x{}_y{}_z{}", self.x, self.y, self.z);
     }
 }
 
+/// A point in global coordinates, which may be negative in some coordinate
+/// frames.  Cuboids are indexed by `Vector3`s relative to the start of the
+/// frame instead (see `to_frame`), so every point in it maps into the
+/// positive cuboid grid.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Coords {
+    pub x: i64,
+    pub y: i64,
+    pub z: i64,
+}
+
+impl Coords {
+    /// Parse a point like `-1024:-1024:0`.
+    pub fn parse(value: &str) -> Result<Coords, String> {
+        let parts: Vec<Option<i64>> = value.split(':').map(|p| p.trim().parse().ok()).collect();
+        match parts.as_slice() {
+            [Some(x), Some(y), Some(z)] => Ok(Coords {
+                x: *x,
+                y: *y,
+                z: *z,
+            }),
+            _ => Err(format!("Invalid coordinates {} (expected x:y:z)", value)),
+        }
+    }
+
+    /// The same point at a resolution level, where each level halves x and
+    /// y, rounding down.
+    pub fn at_res(&self, res: u8) -> Coords {
+        let shift = res.min(63);
+        Coords {
+            x: self.x >> shift,
+            y: self.y >> shift,
+            z: self.z,
+        }
+    }
+
+    /// The same point at a resolution level, like `at_res` but rounding
+    /// up, as for the (exclusive) stop of an extent.
+    pub fn at_res_rounding_up(&self, res: u8) -> Coords {
+        let shift = res.min(63);
+        Coords {
+            x: -((-self.x) >> shift),
+            y: -((-self.y) >> shift),
+            z: self.z,
+        }
+    }
+
+    /// This point relative to the start of a frame, or `None` if it's
+    /// before the start.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - Where the frame starts, at the same resolution
+    ///
+    pub fn to_frame(&self, frame: Coords) -> Option<Vector3> {
+        let along = |v: i64, start: i64| v.checked_sub(start).filter(|d| *d >= 0);
+        Some(Vector3 {
+            x: along(self.x, frame.x)? as u64,
+            y: along(self.y, frame.y)? as u64,
+            z: along(self.z, frame.z)? as u64,
+        })
+    }
+
+    /// A point given relative to the start of a frame, in global
+    /// coordinates.
+    ///
+    /// # Arguments
+    ///
+    /// * `point` - The point, relative to the frame
+    /// * `frame` - Where the frame starts, at the same resolution
+    ///
+    pub fn from_frame(point: Vector3, frame: Coords) -> Coords {
+        Coords {
+            x: frame.x + point.x as i64,
+            y: frame.y + point.y as i64,
+            z: frame.z + point.z as i64,
+        }
+    }
+}
+
+impl fmt::Display for Coords {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.x, self.y, self.z)
+    }
+}
+
 pub trait DataManager {
     /// A DataManager must be able to get and put data.
     ///
@@ -74,10 +265,109 @@ pub trait DataManager {
         data: ndarray::Array3<u8>,
     ) -> bool;
 
+    /// Get data, reporting failures instead of panicking.
+    ///
+    /// Defaults to `get_data`, for layers that can't fail gracefully.
+    fn try_get_data(
+        &self,
+        uri: String,
+        resolution: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Result<ndarray::Array3<u8>, UpstreamError> {
+        Ok(self.get_data(uri, resolution, origin, destination))
+    }
+
+    /// Get several regions of a channel, returning a result for each in
+    /// the order they were asked for.
+    ///
+    /// Defaults to calling `try_get_data` for one region at a time.  Layers
+    /// that can have many requests in flight at once (e.g. the BossDB
+    /// relay) should override this.
+    fn try_get_many(
+        &self,
+        uri: String,
+        resolution: u8,
+        extents: Vec<(Vector3, Vector3)>,
+    ) -> Vec<Result<ndarray::Array3<u8>, UpstreamError>> {
+        extents
+            .into_iter()
+            .map(|(origin, destination)| {
+                self.try_get_data(uri.clone(), resolution, origin, destination)
+            })
+            .collect()
+    }
+
+    /// Is the whole region available without going to another layer?
+    ///
+    /// Defaults to `false`, for layers that don't hold data themselves.
+    fn has_data(
+        &self,
+        _uri: String,
+        _resolution: u8,
+        _origin: Vector3,
+        _destination: Vector3,
+    ) -> bool {
+        false
+    }
+
     /// Default to returning a null data manager to catch failed requests.
     fn get_next_layer(&self) -> &dyn DataManager {
         return &NullDataManager {};
     }
+
+    /// What kind of layer this is, and its key settings, for debugging the
+    /// configured chain (see `describe_chain`).  Secrets are redacted.
+    ///
+    /// Defaults to an `unknown` layer with no settings.
+    fn describe(&self) -> LayerInfo {
+        LayerInfo::new("unknown")
+    }
+}
+
+/// One layer of a data manager chain, as described by
+/// `DataManager::describe`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct LayerInfo {
+    /// E.g. `file`, `bossdb` or `null`.
+    pub kind: &'static str,
+    pub settings: BTreeMap<&'static str, String>,
+}
+
+impl LayerInfo {
+    /// A layer with no settings.
+    pub fn new(kind: &'static str) -> LayerInfo {
+        LayerInfo {
+            kind,
+            settings: BTreeMap::new(),
+        }
+    }
+
+    /// Add a setting.
+    fn with(mut self, name: &'static str, value: String) -> LayerInfo {
+        self.settings.insert(name, value);
+        self
+    }
+}
+
+/// Describe every layer of a chain, in order, from `layer` down to the
+/// `null` layer that ends it.
+pub fn describe_chain(layer: &dyn DataManager) -> Vec<LayerInfo> {
+    let mut chain = vec![layer.describe()];
+    let mut layer = layer;
+    while chain.last().map_or(false, |info| info.kind != "null") {
+        layer = layer.get_next_layer();
+        chain.push(layer.describe());
+    }
+    chain
+}
+
+/// Shows a token only if it's the general-access one.
+fn redact_token(token: &str) -> String {
+    match token {
+        "public" => token.to_string(),
+        _ => "(set)".to_string(),
+    }
 }
 
 /// A struct placeholder for the NullDataManager.
@@ -102,6 +392,582 @@ impl DataManager for NullDataManager {
     ) -> bool {
         panic!("Failed to put data.")
     }
+
+    fn describe(&self) -> LayerInfo {
+        LayerInfo::new("null")
+    }
+}
+
+/// Voxel values used for regions that have no data.
+///
+/// Zero is ambiguous with legitimately black data, so a sentinel can be set
+/// globally or per channel to let viewers tell "no data" apart from "black".
+#[derive(Clone, Debug, Default)]
+pub struct FillValues {
+    default: u8,
+    channels: HashMap<String, u8>,
+}
+
+impl FillValues {
+    pub fn new(default: u8) -> FillValues {
+        FillValues {
+            default,
+            channels: HashMap::new(),
+        }
+    }
+
+    pub fn set_default(&mut self, value: u8) {
+        self.default = value;
+    }
+
+    /// Override the fill value of a single channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, as `collection/experiment/channel`
+    /// * `value` - Voxel value to fill with
+    ///
+    pub fn set_channel(&mut self, channel: &str, value: u8) {
+        self.channels.insert(channel.to_string(), value);
+    }
+
+    /// Get the fill value of a channel (as `collection/experiment/channel`).
+    pub fn get(&self, channel: &str) -> u8 {
+        *self.channels.get(channel).unwrap_or(&self.default)
+    }
+}
+
+/// What to do when the next layer fails to provide a cuboid.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UpstreamErrorPolicy {
+    /// Fail the whole request.
+    Fail,
+    /// Serve what's cached, leaving the failed cuboids as the fill value.
+    ServePartial,
+}
+
+/// What a cache with no next layer does with cuboids it doesn't hold.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MissPolicy {
+    /// Serve them as the fill value, as if they were empty.
+    Fill,
+    /// Report the cutout missing (see `Cutout::missing`).
+    NotFound,
+}
+
+/// Why the next layer couldn't provide a region.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpstreamError {
+    /// The channel (or its collection or experiment) doesn't exist.
+    NotFound(String),
+    /// Anything else, e.g. the next layer is down.
+    Failed(String),
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::NotFound(err) => write!(f, "not found: {}", err),
+            UpstreamError::Failed(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<String> for UpstreamError {
+    fn from(err: String) -> UpstreamError {
+        UpstreamError::Failed(err)
+    }
+}
+
+/// Channels that the next layer recently reported don't exist, shared by
+/// every request, so that asking for one again doesn't go upstream until
+/// the TTL runs out.  A TTL of zero disables it.
+pub struct NotFoundChannels {
+    ttl: Mutex<Duration>,
+    /// When each channel's entry expires.
+    expiry: Mutex<HashMap<String, Instant>>,
+}
+
+impl NotFoundChannels {
+    pub fn new(ttl: Duration) -> NotFoundChannels {
+        NotFoundChannels {
+            ttl: Mutex::new(ttl),
+            expiry: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        *self.ttl.lock().unwrap()
+    }
+
+    /// Change the TTL of channels recorded from now on.  Disabling it
+    /// forgets every channel recorded so far.
+    pub fn set_ttl(&self, ttl: Duration) {
+        *self.ttl.lock().unwrap() = ttl;
+        if ttl == Duration::from_secs(0) {
+            self.expiry.lock().unwrap().clear();
+        }
+    }
+
+    /// Remember that a channel (as `collection/experiment/channel`) doesn't
+    /// exist.
+    pub fn record(&self, channel: &str) {
+        let ttl = self.ttl();
+        if ttl == Duration::from_secs(0) {
+            return;
+        }
+        self.expiry
+            .lock()
+            .unwrap()
+            .insert(channel.to_string(), Instant::now() + ttl);
+    }
+
+    /// Is a channel (as `collection/experiment/channel`) known not to
+    /// exist?  Expired entries are forgotten.
+    pub fn contains(&self, channel: &str) -> bool {
+        let mut expiry = self.expiry.lock().unwrap();
+        match expiry.get(channel) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                expiry.remove(channel);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// Most cuboids `RecentMisses` remembers at once.
+const MAX_RECENT_MISSES: usize = 100_000;
+
+/// Cuboids recently missed in the cache, shared by every request, for
+/// caching only cuboids that are missed a second time within a window (see
+/// `ChunkedFileDataManager::set_recent_misses`).  Keeps the cache from
+/// filling up with cuboids that are only read once.
+pub struct RecentMisses {
+    window: Duration,
+    /// Most cuboids remembered at once.
+    capacity: usize,
+    first_miss: Mutex<FirstMisses>,
+}
+
+/// When each cuboid (by filename) was first missed, for `RecentMisses`.
+#[derive(Default)]
+struct FirstMisses {
+    by_filename: HashMap<String, Instant>,
+    /// The same misses, oldest first, so that old ones are forgotten
+    /// without a scan.  Entries that no longer match `by_filename`, e.g.
+    /// those already counted, are skipped when they come up.
+    in_order: VecDeque<(Instant, String)>,
+}
+
+impl RecentMisses {
+    pub fn new(window: Duration) -> RecentMisses {
+        RecentMisses {
+            window,
+            capacity: MAX_RECENT_MISSES,
+            first_miss: Mutex::new(FirstMisses::default()),
+        }
+    }
+
+    /// Record a miss of a cuboid, and tell whether it's the second within
+    /// the window, i.e. whether the cuboid is worth caching.  The first
+    /// miss is forgotten once it's counted.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Where the cuboid would be cached
+    /// * `now` - When it was missed
+    ///
+    pub fn is_second_miss(&self, filename: &str, now: Instant) -> bool {
+        let mut first_miss = self.first_miss.lock().unwrap();
+        let FirstMisses {
+            by_filename,
+            in_order,
+        } = &mut *first_miss;
+        match by_filename.get(filename) {
+            Some(first) if now.saturating_duration_since(*first) <= self.window => {
+                by_filename.remove(filename);
+                return true;
+            }
+            _ => (),
+        }
+        // Forget misses too old to count, then the oldest if still full:
+        while let Some((first, oldest)) = in_order.front() {
+            let expired = now.saturating_duration_since(*first) > self.window;
+            if !expired && in_order.len() < self.capacity {
+                break;
+            }
+            if by_filename.get(oldest) == Some(first) {
+                by_filename.remove(oldest);
+            }
+            in_order.pop_front();
+        }
+        by_filename.insert(filename.to_string(), now);
+        in_order.push_back((now, filename.to_string()));
+        false
+    }
+}
+
+/// Counts of the cutouts fetched from the Boss DB host, shared by every
+/// request's relay (see `BossDBRelayDataManager::set_upstream_stats`), to
+/// show how well the cache shields it.
+#[derive(Default)]
+pub struct UpstreamStats {
+    calls: AtomicU64,
+    failures: AtomicU64,
+    bytes: AtomicU64,
+    /// Total time spent waiting on the calls, in microseconds.
+    latency_micros: AtomicU64,
+}
+
+/// What `UpstreamStats` has counted so far.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct UpstreamTotals {
+    /// Number of cutouts requested upstream, including failed requests.
+    pub calls: u64,
+    /// Number of those requests that failed.
+    pub failures: u64,
+    /// Voxel bytes fetched.
+    pub bytes: u64,
+    /// Time spent on all the requests, in milliseconds.
+    pub total_latency_ms: f64,
+    /// Mean time per request, in milliseconds; 0 before any requests.
+    pub mean_latency_ms: f64,
+}
+
+impl UpstreamStats {
+    pub fn new() -> UpstreamStats {
+        UpstreamStats::default()
+    }
+
+    /// Count a cutout requested upstream.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - Voxel bytes fetched; 0 if the request failed
+    /// * `latency` - How long the request took
+    /// * `failed` - Whether it failed
+    pub fn record(&self, bytes: u64, latency: Duration, failed: bool) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.latency_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The counts so far.
+    pub fn totals(&self) -> UpstreamTotals {
+        let calls = self.calls.load(Ordering::Relaxed);
+        let total_latency_ms = self.latency_micros.load(Ordering::Relaxed) as f64 / 1000.0;
+        UpstreamTotals {
+            calls,
+            failures: self.failures.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            total_latency_ms,
+            mean_latency_ms: if calls == 0 {
+                0.0
+            } else {
+                total_latency_ms / calls as f64
+            },
+        }
+    }
+}
+
+/// Resolutions more than this many levels finer than a missing one aren't
+/// used to synthesize it, since each level quadruples the cuboids to read.
+const MAX_SYNTHESIS_LEVELS: u8 = 3;
+
+/// How many of a cutout's cuboids came from each layer.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CuboidSources {
+    /// Read from the local cache, including expired cuboids served because
+    /// they couldn't be revalidated.
+    pub cache: u32,
+    /// Downsampled from cached cuboids of a finer resolution.
+    pub synthesized: u32,
+    /// Fetched from the next layer.
+    pub upstream: u32,
+    /// Left as the fill value, e.g. known to be empty or failed to fetch.
+    pub filled: u32,
+}
+
+impl CuboidSources {
+    /// Number of cuboids counted.
+    pub fn total(&self) -> u32 {
+        self.cache + self.synthesized + self.upstream + self.filled
+    }
+
+    /// Fraction of the cuboids read from the local cache, from 0 to 1.  A
+    /// cutout that needed no cuboids counts as all cached.
+    pub fn cache_hit_ratio(&self) -> f64 {
+        if self.total() == 0 {
+            return 1.0;
+        }
+        self.cache as f64 / self.total() as f64
+    }
+
+    /// The layers that provided cuboids, with their counts, like
+    /// `cache=3, upstream=1`.  `none` if there weren't any.
+    pub fn summary(&self) -> String {
+        let counts = [
+            ("cache", self.cache),
+            ("synthesized", self.synthesized),
+            ("upstream", self.upstream),
+            ("filled", self.filled),
+        ];
+        let layers: Vec<String> = counts
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(layer, count)| format!("{}={}", layer, count))
+            .collect();
+        if layers.is_empty() {
+            return "none".to_string();
+        }
+        layers.join(", ")
+    }
+}
+
+/// A cutout read by the file manager.
+pub struct Cutout {
+    pub data: Array3<u8>,
+    /// Set if some cuboids couldn't be fetched and were filled instead.
+    pub partial: bool,
+    /// Set if every cuboid was read from the local cache.
+    pub cache_hit: bool,
+    /// Set if the next layer reported that the channel doesn't exist.
+    /// Nothing is cached in that case, and `data` is only the fill value.
+    pub not_found: bool,
+    /// Set if there's no next layer, some cuboids weren't cached, and
+    /// misses are to be reported (see `ChunkedFileDataManager::set_on_miss`).
+    /// Those cuboids are filled.
+    pub missing: bool,
+    /// Set if the cutout extends past the channel's extent, and the part
+    /// outside it was filled rather than read (see
+    /// `ChunkedFileDataManager::set_clamp_to_extent`).
+    pub clamped: bool,
+    /// Where each of the cuboids read came from.
+    pub sources: CuboidSources,
+}
+
+/// How many cuboids of a channel are cached at one resolution (see
+/// `ChunkedFileDataManager::cached_resolutions`).
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CachedResolution {
+    pub res: u8,
+    pub cuboids: u64,
+}
+
+/// How much of a channel's resolution pyramid is cached, in the terms of
+/// the BossDB's `downsample_status`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DownsampleStatus {
+    /// Nothing above the base resolution is cached.
+    NotDownsampled,
+    /// Some lower resolutions are cached, but with gaps in the pyramid.
+    InProgress,
+    /// Every resolution from the base up to the lowest cached one is
+    /// cached.
+    Downsampled,
+}
+
+impl DownsampleStatus {
+    /// Judge the status of a channel from its cached resolutions (see
+    /// `ChunkedFileDataManager::cached_resolutions`), in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `resolutions` - The resolutions with cuboids cached, in order
+    /// * `base` - The channel's base (full) resolution
+    ///
+    pub fn of(resolutions: &[CachedResolution], base: u8) -> DownsampleStatus {
+        let levels: Vec<u8> = resolutions
+            .iter()
+            .map(|r| r.res)
+            .filter(|res| *res >= base)
+            .collect();
+        match levels.last() {
+            Some(top) if *top > base => {
+                // Sorted and distinct, so contiguous from the base when
+                // there's one per level:
+                if levels[0] == base && levels.len() == (*top - base) as usize + 1 {
+                    DownsampleStatus::Downsampled
+                } else {
+                    DownsampleStatus::InProgress
+                }
+            }
+            _ => DownsampleStatus::NotDownsampled,
+        }
+    }
+
+    /// The status as the BossDB spells it, e.g. `DOWNSAMPLED`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DownsampleStatus::NotDownsampled => "NOT_DOWNSAMPLED",
+            DownsampleStatus::InProgress => "IN_PROGRESS",
+            DownsampleStatus::Downsampled => "DOWNSAMPLED",
+        }
+    }
+}
+
+/// Whether one cuboid of a cutout is cached (see
+/// `ChunkedFileDataManager::cuboid_coverage`).
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CuboidCoverage {
+    /// Index of the cuboid in the cuboid grid.
+    pub index: Vector3,
+    pub cached: bool,
+}
+
+/// The part of one cuboid that a cutout covers (see `cuboid_regions`).
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CuboidRegion {
+    /// Index of the cuboid in the cuboid grid.
+    pub index: Vector3,
+    /// Start of the region within the cuboid.
+    pub start: Vector3,
+    /// (Exclusive) end of the region within the cuboid.
+    pub stop: Vector3,
+}
+
+/// The same mapping as `get_cuboids_and_indices`, as a list ordered by
+/// cuboid index (by z, then y, then x), e.g. to show a client how a cutout
+/// is chunked.
+///
+/// # Arguments
+///
+/// * `coords_start` - The start of the cutout
+/// * `coords_stop` - The (exclusive) end of the cutout
+/// * `cuboid_size` - Dimensions of the cuboids
+///
+pub fn cuboid_regions(
+    coords_start: Vector3,
+    coords_stop: Vector3,
+    cuboid_size: Vector3,
+) -> Vec<CuboidRegion> {
+    let mut regions: Vec<CuboidRegion> =
+        get_cuboids_and_indices(coords_start, coords_stop, cuboid_size)
+            .into_iter()
+            .map(|(index, (start, stop))| CuboidRegion { index, start, stop })
+            .collect();
+    regions.sort_by_key(|region| (region.index.z, region.index.y, region.index.x));
+    regions
+}
+
+/// How a cached cutout compares with the same region upstream (see
+/// `ChunkedFileDataManager::diff_cutout`).
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CutoutDiff {
+    /// Set if the cache and upstream are byte-identical.
+    pub identical: bool,
+    /// Number of voxels compared.
+    pub voxels: u64,
+    /// Number of voxels that differ.
+    pub differing: u64,
+    /// Percentage of voxels that match, from 0 to 100.
+    pub match_percent: f64,
+    /// The first voxel that differs, in ZYX order, in the global frame.
+    pub first_difference: Option<Coords>,
+}
+
+impl CutoutDiff {
+    /// Compare two cutouts of the same shape.
+    ///
+    /// # Arguments
+    ///
+    /// * `cached` - The cutout as read from the cache
+    /// * `upstream` - The cutout as read from upstream
+    /// * `origin` - The start position of the cutout (relative to the frame)
+    /// * `frame` - Where the frame starts, at the cutout's resolution
+    ///
+    pub fn compare(
+        cached: &Array3<u8>,
+        upstream: &Array3<u8>,
+        origin: Vector3,
+        frame: Coords,
+    ) -> CutoutDiff {
+        let mut differing = 0;
+        let mut first_difference = None;
+        for ((z, y, x), voxel) in cached.indexed_iter() {
+            if *voxel == upstream[(z, y, x)] {
+                continue;
+            }
+            differing += 1;
+            if first_difference.is_none() {
+                let point = Vector3 {
+                    x: origin.x + x as u64,
+                    y: origin.y + y as u64,
+                    z: origin.z + z as u64,
+                };
+                first_difference = Some(Coords::from_frame(point, frame));
+            }
+        }
+        let voxels = cached.len() as u64;
+        let match_percent = if voxels == 0 {
+            100.0
+        } else {
+            100.0 * (voxels - differing) as f64 / voxels as f64
+        };
+        CutoutDiff {
+            identical: differing == 0,
+            voxels,
+            differing,
+            match_percent,
+            first_difference,
+        }
+    }
+}
+
+/// What an upload wrote to the cache (see
+/// `ChunkedFileDataManager::try_upload`).
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct UploadSummary {
+    /// Number of cuboids written, including any left in the write buffer.
+    pub cuboids: usize,
+    /// Size of the cuboid files written, not counting buffered cuboids.
+    pub bytes: u64,
+}
+
+/// Why an upload wasn't written.
+#[derive(Debug)]
+pub enum UploadError {
+    /// The channel's datatype isn't `uint8`.
+    UnsupportedChannel(String),
+    /// Writing through, and the next layer refused the upload.  Nothing
+    /// was cached.
+    Rejected(String),
+    /// Cuboids couldn't be written to the cache: `failed` of them, the
+    /// first being `filename`.  The others were written.
+    Io {
+        filename: String,
+        error: std::io::Error,
+        failed: usize,
+    },
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadError::UnsupportedChannel(uri) => write!(f, "Channel {} is not uint8", uri),
+            UploadError::Rejected(uri) => write!(f, "The next layer refused the upload to {}", uri),
+            UploadError::Io {
+                filename,
+                error,
+                failed: 1,
+            } => write!(f, "Failed to write cuboid {}: {}", filename, error),
+            UploadError::Io {
+                filename,
+                error,
+                failed,
+            } => write!(
+                f,
+                "Failed to write {} cuboids, starting with {}: {}",
+                failed, filename, error
+            ),
+        }
+    }
 }
 
 pub struct ChunkedFileDataManager {
@@ -112,9 +978,66 @@ pub struct ChunkedFileDataManager {
     /// much faster this one is than the Python version. They should have
     /// sent a poet.
     file_path: String,
+    /// Roots used instead of `file_path` for particular resolutions.
+    resolution_roots: HashMap<u8, String>,
+    /// Cuboid size of channels that the registry doesn't give one for.
     cuboid_size: Vector3,
-    next_layer: Box<dyn DataManager>,
+    next_layer: Box<dyn DataManager + Send>,
     track_usage: bool,
+    /// Where usage is sent instead of the running usage tracker, if set.
+    usage_sender: Option<Arc<Mutex<Sender<String>>>>,
+    has_next_layer: bool,
+    use_mmap: bool,
+    reads: ReadStrategy,
+    hashes: Option<Arc<CuboidHashes>>,
+    fill_values: FillValues,
+    synthesis: SynthesisMethods,
+    channels: Option<Arc<ChannelRegistry>>,
+    on_upstream_error: UpstreamErrorPolicy,
+    /// What a miss does when there's no next layer.
+    on_miss: MissPolicy,
+    not_found: Option<Arc<NotFoundChannels>>,
+    /// Cuboids known to be empty, when there's no next layer.
+    empty: Option<Arc<EmptyCuboids>>,
+    channel_keys: Option<Arc<ChannelKeys>>,
+    format_version: u16,
+    layout: Layout,
+    write_through: bool,
+    /// Keep uploads local, over the next layer's data.
+    scratch: bool,
+    /// Where the channels of scratch uploads are pinned.
+    scratch_channels: Option<Arc<ScratchChannels>>,
+    /// Number of coarser resolutions rebuilt from each upload.
+    downsample_levels: u8,
+    /// Cache cuboids fetched from the next layer.
+    writeback: bool,
+    /// If set, cutouts are read straight from the next layer in cuboids of
+    /// this size, bypassing the cache.
+    cuboid_size_override: Option<Vector3>,
+    /// Configured cuboid sizes of channels, for when the registry can't
+    /// resolve them.
+    channel_cuboid_sizes: HashMap<String, Vector3>,
+    /// Fill the parts of cutouts outside the channel's extent.
+    clamp_to_extent: bool,
+    /// If set, partial cuboid writes are coalesced here.
+    write_buffer: Option<Arc<WriteBuffer>>,
+    /// Refuse cutouts outside the channel's extent (see `check_frame`).
+    check_frame: bool,
+    /// Where the global coordinate frame starts, which cuboids are indexed
+    /// relative to.
+    frame: Coords,
+    /// If set, only cuboids missed twice within its window are cached.
+    recent_misses: Option<Arc<RecentMisses>>,
+    /// If set, nothing fetched is cached while it finds disk space low.
+    disk_guard: Option<Arc<DiskGuard>>,
+    /// Caps how many cuboid files are open at once across all managers
+    /// sharing it.
+    file_limit: Option<Arc<Semaphore>>,
+    /// Permission bits for the cuboid files and directories it creates.
+    modes: Modes,
+    /// How long a cached cuboid is served before it's revalidated against
+    /// the next layer, if ever.
+    max_age: Option<Duration>,
 }
 
 /// Get a mapping of cuboid indices to the cutout indices within it.
@@ -214,10 +1137,122 @@ pub fn get_cuboids_and_indices(
     return cuboids;
 }
 
-impl ChunkedFileDataManager {
-    /// A DataManager handles data IO from disk (and eventually cache).
-    ///
-    /// Create a new DataManager with a file_path on disk to which cuboids
+/// The number of cuboids that `get_cuboids_and_indices` maps a cutout to,
+/// without building the map, so a huge cutout can be refused cheaply.
+///
+/// # Arguments
+///
+/// * `coords_start` - The global start position
+/// * `coords_stop` - The global stop indices
+/// * `cuboid_size` - The XYZ cuboid size on disk
+///
+pub fn count_cuboids(coords_start: Vector3, coords_stop: Vector3, cuboid_size: Vector3) -> u64 {
+    let span = |start: u64, stop: u64, size: u64| (stop / size).saturating_sub(start / size);
+    span(coords_start.x, coords_stop.x, cuboid_size.x)
+        .saturating_mul(span(coords_start.y, coords_stop.y, cuboid_size.y))
+        .saturating_mul(span(coords_start.z, coords_stop.z, cuboid_size.z))
+}
+
+/// Writes whole cuboid files, with the bookkeeping every write needs
+/// (hashes, empty cuboids, usage).  Taken from a `ChunkedFileDataManager`,
+/// so that a `WriteBuffer` can write cuboids out after the request that
+/// buffered them is done.
+#[derive(Clone)]
+pub struct CuboidWriter {
+    cuboid_size: Vector3,
+    format_version: u16,
+    layout: Layout,
+    file_mode: Option<u32>,
+    file_limit: Option<Arc<Semaphore>>,
+    hashes: Option<Arc<CuboidHashes>>,
+    empty: Option<Arc<EmptyCuboids>>,
+    track_usage: bool,
+    usage_sender: Option<Arc<Mutex<Sender<String>>>>,
+}
+
+impl CuboidWriter {
+    /// Write a cuboid's file, returning its size.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - The cuboid's file, in an existing directory
+    /// * `voxels` - The whole cuboid, in ZYX order
+    ///
+    pub fn write(&self, filename: &str, voxels: &[u8]) -> std::io::Result<u64> {
+        let bytes = match self.layout {
+            Layout::Native => cuboid_file::encode(self.format_version, self.cuboid_size, voxels),
+            Layout::Python => npy::encode(self.cuboid_size, voxels),
+        };
+        {
+            let _permit = self.file_limit.as_ref().map(|limit| limit.acquire());
+            write_atomically(filename, &bytes, self.file_mode)?;
+        }
+        if let Some(hashes) = &self.hashes {
+            hashes.record(filename, etag::hash_bytes(&bytes));
+        }
+        if let Some(empty) = &self.empty {
+            empty.forget(filename);
+        }
+        // Every file written is counted against the cache's budget, so the
+        // usage tracker's rows match the files on disk:
+        send_usage(self.usage_sender.as_ref(), self.track_usage, filename);
+        Ok(bytes.len() as u64)
+    }
+}
+
+/// Tell the usage tracker that a cuboid was used: `sender` if given, or
+/// else the one started by `usage_tracker::run()` if tracking is on.
+fn send_usage(sender: Option<&Arc<Mutex<Sender<String>>>>, track_usage: bool, filename: &str) {
+    if let Some(sender) = sender {
+        let _ = sender.lock().unwrap().send(filename.to_string());
+    } else if track_usage {
+        usage_tracker::record(filename.to_string());
+    }
+}
+
+/// Copy the voxels of a region out of a cutout, in C-order, a row at a time
+/// where the rows are contiguous.
+fn region_voxels(region: ndarray::ArrayView3<u8>) -> Vec<u8> {
+    let mut voxels = Vec::with_capacity(region.len());
+    for row in region.genrows() {
+        match row.as_slice() {
+            Some(row) => voxels.extend_from_slice(row),
+            None => voxels.extend(row.iter()),
+        }
+    }
+    voxels
+}
+
+/// Convert a region within a cuboid to the same region within a cutout.
+/// Returns the region's start and (exclusive) stop.
+///
+/// # Arguments
+///
+/// * `cuboid_size` - Size of the cuboid
+/// * `cuboid_index` - Index of the cuboid
+/// * `start_ind` - Start of the region, within the cuboid
+/// * `stop_ind` - End of the region, within the cuboid
+/// * `origin` - The start position of the cutout (global coords)
+///
+fn cutout_coords(
+    cuboid_size: Vector3,
+    cuboid_index: &Vector3,
+    start_ind: &Vector3,
+    stop_ind: &Vector3,
+    origin: Vector3,
+) -> (Vector3, Vector3) {
+    let to_cutout = |ind: &Vector3| Vector3 {
+        x: (cuboid_index.x * cuboid_size.x) + ind.x - origin.x,
+        y: (cuboid_index.y * cuboid_size.y) + ind.y - origin.y,
+        z: (cuboid_index.z * cuboid_size.z) + ind.z - origin.z,
+    };
+    (to_cutout(start_ind), to_cutout(stop_ind))
+}
+
+impl ChunkedFileDataManager {
+    /// A DataManager handles data IO from disk (and eventually cache).
+    ///
+    /// Create a new DataManager with a file_path on disk to which cuboids
     /// will be written, and a default cuboid_size (e.g. 512*512*16).
     pub fn new(
         file_path: String,
@@ -226,158 +1261,1752 @@ impl ChunkedFileDataManager {
     ) -> ChunkedFileDataManager {
         return ChunkedFileDataManager {
             file_path,
+            resolution_roots: HashMap::new(),
             cuboid_size,
             next_layer: Box::new(NullDataManager {}),
+            has_next_layer: false,
+            track_usage,
+            usage_sender: None,
+            use_mmap: false,
+            reads: ReadStrategy::default(),
+            hashes: None,
+            fill_values: FillValues::default(),
+            synthesis: SynthesisMethods::default(),
+            channels: None,
+            on_upstream_error: UpstreamErrorPolicy::Fail,
+            on_miss: MissPolicy::Fill,
+            not_found: None,
+            empty: None,
+            channel_keys: None,
+            format_version: cuboid_file::CURRENT_VERSION,
+            layout: Layout::Native,
+            write_through: false,
+            scratch: false,
+            scratch_channels: None,
+            downsample_levels: 0,
+            writeback: true,
+            cuboid_size_override: None,
+            channel_cuboid_sizes: HashMap::new(),
+            clamp_to_extent: false,
+            write_buffer: None,
+            check_frame: false,
+            frame: Coords::default(),
+            recent_misses: None,
+            disk_guard: None,
+            file_limit: None,
+            modes: Modes::default(),
+            max_age: None,
+        };
+    }
+
+    pub fn new_with_layer(
+        file_path: String,
+        cuboid_size: Vector3,
+        next_layer: Box<dyn DataManager + Send>,
+        track_usage: bool,
+    ) -> ChunkedFileDataManager {
+        return ChunkedFileDataManager {
+            file_path,
+            resolution_roots: HashMap::new(),
+            cuboid_size,
+            next_layer,
+            has_next_layer: true,
             track_usage,
+            usage_sender: None,
+            use_mmap: false,
+            reads: ReadStrategy::default(),
+            hashes: None,
+            fill_values: FillValues::default(),
+            synthesis: SynthesisMethods::default(),
+            channels: None,
+            on_upstream_error: UpstreamErrorPolicy::Fail,
+            on_miss: MissPolicy::Fill,
+            not_found: None,
+            empty: None,
+            channel_keys: None,
+            format_version: cuboid_file::CURRENT_VERSION,
+            layout: Layout::Native,
+            write_through: false,
+            scratch: false,
+            scratch_channels: None,
+            downsample_levels: 0,
+            writeback: true,
+            cuboid_size_override: None,
+            channel_cuboid_sizes: HashMap::new(),
+            clamp_to_extent: false,
+            write_buffer: None,
+            check_frame: false,
+            frame: Coords::default(),
+            recent_misses: None,
+            disk_guard: None,
+            file_limit: None,
+            modes: Modes::default(),
+            max_age: None,
+        };
+    }
+
+    /// Read cached cuboids through a memory map instead of `fs::read`.
+    ///
+    /// Only the part of each cuboid that the cutout needs gets copied, so
+    /// peak allocation is bounded by the cutout rather than by the cuboids
+    /// that it touches.  Falls back to `fs::read` if a file can't be mapped.
+    pub fn set_use_mmap(&mut self, use_mmap: bool) {
+        self.use_mmap = use_mmap;
+    }
+
+    /// Read cached cuboids with a read-ahead buffer or kernel hints (see
+    /// `ReadStrategy`), e.g. for a cache on spinning disks.
+    pub fn set_read_strategy(&mut self, reads: ReadStrategy) {
+        self.reads = reads;
+    }
+
+    /// Remember content hashes of written cuboids in a shared store, so
+    /// that `cutout_etag` rarely has to hash a cuboid itself.
+    pub fn set_hashes(&mut self, hashes: Arc<CuboidHashes>) {
+        self.hashes = Some(hashes);
+    }
+
+    /// Fill regions that have no data with these values instead of zeros.
+    ///
+    /// Without a next layer, uncached cuboids are left filled rather than
+    /// fetched.
+    pub fn set_fill_values(&mut self, fill_values: FillValues) {
+        self.fill_values = fill_values;
+    }
+
+    /// Also write uploads to the next layer (e.g. a BossDB write host), not
+    /// just to the cache.
+    pub fn set_write_through(&mut self, write_through: bool) {
+        self.write_through = write_through;
+    }
+
+    /// Treat the cache as a scratch space that uploads never leave: they
+    /// aren't written through, even if that's set, and an upload that
+    /// covers only part of a cuboid that isn't cached starts from the next
+    /// layer's copy rather than zeros, so reads see local writes over the
+    /// next layer's data.  Cached cuboids are never revalidated (see
+    /// `set_max_age`), since that would replace them.
+    pub fn set_scratch(&mut self, scratch: bool) {
+        self.scratch = scratch;
+    }
+
+    /// Pin the channels of scratch uploads (see `set_scratch`), since the
+    /// cache holds the only copy of what's uploaded.
+    pub fn set_scratch_channels(&mut self, channels: Arc<ScratchChannels>) {
+        self.scratch_channels = Some(channels);
+    }
+
+    /// Rebuild this many coarser resolutions from each upload, caching the
+    /// cuboids it touches at each by downsampling the one below, so reads
+    /// of them see the uploaded data rather than the next layer's.
+    /// Annotation channels are downsampled by mode and others by mean (see
+    /// `ChannelRegistry::channel_type`), or by mean if there's no registry.
+    /// Nothing is written through for the coarser resolutions, and nothing
+    /// is fetched for them: a cuboid whose finer cuboids aren't all cached
+    /// is left as it was.
+    pub fn set_downsample_on_write(&mut self, levels: u8) {
+        self.downsample_levels = levels;
+    }
+
+    /// Choose whether cuboids fetched from the next layer are cached.
+    /// Without writeback, this is a pass-through proxy for anything that
+    /// isn't cached already, e.g. so that a one-off scan of a large dataset
+    /// doesn't evict the working set.  Nothing is prefetched either.
+    pub fn set_writeback(&mut self, writeback: bool) {
+        self.writeback = writeback;
+    }
+
+    /// Read cutouts from the next layer in cuboids of this size rather than
+    /// the channel's, e.g. to try out chunking strategies against the same
+    /// data.  The cache holds cuboids of the channel's size, so it's
+    /// neither read nor written: this turns off writeback, and cutouts have
+    /// no `ETag` or last modified time.
+    pub fn set_cuboid_size_override(&mut self, cuboid_size: Vector3) {
+        self.cuboid_size_override = Some(cuboid_size);
+        self.writeback = false;
+    }
+
+    /// Whether there's a layer (e.g. a Boss DB host) behind the cache to
+    /// read from.
+    pub fn has_next_layer(&self) -> bool {
+        self.has_next_layer
+    }
+
+    /// Choose whether to fill the parts of cutouts outside the channel's
+    /// extent (see `ChannelRegistry::extent`) with the fill value, rather
+    /// than reading them, so that reads past the edge of a dataset don't
+    /// depend on what the next layer does with them.  Cuboids that straddle
+    /// the edge are still read whole.  Needs a channel registry; cutouts of
+    /// channels whose extent can't be looked up are read as usual.
+    pub fn set_clamp_to_extent(&mut self, clamp_to_extent: bool) {
+        self.clamp_to_extent = clamp_to_extent;
+    }
+
+    /// Choose whether `check_frame` refuses cutouts that reach outside the
+    /// channel's extent, rather than reading whatever the next layer has
+    /// there.  Needs a channel registry; clamping takes precedence.
+    pub fn set_check_frame(&mut self, check_frame: bool) {
+        self.check_frame = check_frame;
+    }
+
+    /// Index cuboids relative to where the global coordinate frame starts,
+    /// as the relay does (see `BossDBRelayDataManager::set_frame`), so that
+    /// channel extents, which are global, line up with cutouts.
+    pub fn set_frame(&mut self, frame: Coords) {
+        self.frame = frame;
+    }
+
+    /// A channel's extent at a resolution, relative to the start of the
+    /// global frame, if it can be looked up.  Parts before the start of the
+    /// global frame are cut off.
+    fn extent_in_frame(&self, uri: &str, res: u8) -> Option<(Vector3, Vector3)> {
+        let boss_uri: Vec<&str> = uri.split("://").collect();
+        let (start, stop) = self.channels.as_ref()?.extent(boss_uri[1], res)?;
+        let frame = self.frame.at_res(res);
+        let relative = |point: Coords| Vector3 {
+            x: point.x.saturating_sub(frame.x).max(0) as u64,
+            y: point.y.saturating_sub(frame.y).max(0) as u64,
+            z: point.z.saturating_sub(frame.z).max(0) as u64,
+        };
+        Some((relative(start), relative(stop)))
+    }
+
+    /// Refuse a cutout that isn't wholly inside its channel's extent, with
+    /// a message for the client, if frames are checked and the cutout
+    /// isn't to be clamped instead.  Cutouts of channels whose extent can't
+    /// be looked up pass.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the cutout (relative to the frame)
+    /// * `destination` - The end position, relative to the frame
+    ///
+    pub fn check_frame(
+        &self,
+        uri: &str,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Result<(), String> {
+        if self.clamp_to_extent {
+            return Ok(());
+        }
+        self.check_upload_frame(uri, res, origin, destination)
+    }
+
+    /// Refuse an upload that isn't wholly inside its channel's extent, like
+    /// `check_frame`, but even when cutouts are clamped, since uploads
+    /// aren't.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the upload (relative to the frame)
+    /// * `destination` - The end position, relative to the frame
+    ///
+    pub fn check_upload_frame(
+        &self,
+        uri: &str,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Result<(), String> {
+        if !self.check_frame {
+            return Ok(());
+        }
+        let extent = match self.extent_in_frame(uri, res) {
+            Some(extent) => extent,
+            None => return Ok(()),
+        };
+        if Vector3::intersection((origin, destination), extent) == Some((origin, destination)) {
+            return Ok(());
+        }
+        let frame = self.frame.at_res(res);
+        Err(format!(
+            "The cutout from {} to {} is outside the coordinate frame of {}, which spans {} to {} at resolution {}",
+            Coords::from_frame(origin, frame),
+            Coords::from_frame(destination, frame),
+            uri,
+            Coords::from_frame(extent.0, frame),
+            Coords::from_frame(extent.1, frame),
+            res
+        ))
+    }
+
+    /// Coalesce uploads that write part of a cuboid in a write buffer
+    /// shared across requests, so that many small uploads to a cuboid cost
+    /// one read-modify-write of its file rather than one each.  Uploads of
+    /// whole cuboids are written straight away.  Buffered cuboids are
+    /// written out before any read of them.
+    pub fn set_write_buffer(&mut self, buffer: Arc<WriteBuffer>) {
+        self.write_buffer = Some(buffer);
+    }
+
+    /// With writeback, cache a cuboid fetched from the next layer only once
+    /// it's missed a second time within the window of a shared list of
+    /// recent misses, so the cache holds data that's actually reused.
+    /// Warming the cache still caches every cuboid.
+    pub fn set_recent_misses(&mut self, recent_misses: Arc<RecentMisses>) {
+        self.recent_misses = Some(recent_misses);
+    }
+
+    /// Pause writeback whenever a shared disk guard finds disk space low,
+    /// so cuboids fetched from the next layer are served without being
+    /// cached rather than failing to be written.
+    pub fn set_disk_guard(&mut self, disk_guard: Arc<DiskGuard>) {
+        self.disk_guard = Some(disk_guard);
+    }
+
+    /// Whether cuboids fetched from the next layer are cached right now
+    /// (see `set_writeback` and `set_disk_guard`).
+    fn writes_back(&self) -> bool {
+        self.writeback
+            && !self
+                .disk_guard
+                .as_ref()
+                .map_or(false, |guard| guard.is_low())
+    }
+
+    /// Whether to cache a cuboid fetched from the next layer after missing
+    /// it in the cache (see `set_recent_misses`).
+    fn caches_miss(&self, filename: &str) -> bool {
+        self.writes_back()
+            && self.recent_misses.as_ref().map_or(true, |misses| {
+                misses.is_second_miss(filename, Instant::now())
+            })
+    }
+
+    /// Share a limit on how many cuboid files are open at once with other
+    /// managers, so that many large cutouts at once don't run the process
+    /// out of file descriptors.
+    pub fn set_file_limit(&mut self, limit: Arc<Semaphore>) {
+        self.file_limit = Some(limit);
+    }
+
+    /// Create cuboid files and their directories with these permission
+    /// bits, e.g. to make the cache readable by a group, instead of leaving
+    /// them to the umask.
+    pub fn set_modes(&mut self, modes: Modes) {
+        self.modes = modes;
+    }
+
+    /// Wait for a permit to open a cuboid file, if open files are limited.
+    fn file_permit(&self) -> Option<SemaphoreGuard<'_>> {
+        self.file_limit.as_ref().map(|limit| limit.acquire())
+    }
+
+    /// Serve cuboids that aren't cached by downsampling a cached higher
+    /// resolution, for the channels that have a method set, rather than
+    /// fetching them from the next layer.
+    pub fn set_synthesis(&mut self, synthesis: SynthesisMethods) {
+        self.synthesis = synthesis;
+    }
+
+    /// Re-fetch cached cuboids from the next layer once they're older than
+    /// `max_age`, so changes upstream are picked up eventually.  A cuboid's
+    /// age is the time since its file was last written.  If the next layer
+    /// fails to provide it, the cached copy is served.  `None` (the default)
+    /// serves cached cuboids forever.
+    pub fn set_max_age(&mut self, max_age: Option<Duration>) {
+        self.max_age = max_age;
+    }
+
+    /// Has a cached cuboid outlived the max age (see `set_max_age`)?  Only
+    /// a manager with a next layer can revalidate it.
+    fn is_expired(&self, filename: &str) -> bool {
+        let max_age = match self.max_age {
+            Some(max_age) if self.has_next_layer && !self.scratch => max_age,
+            _ => return false,
+        };
+        fs::metadata(filename)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map_or(false, |age| age > max_age)
+    }
+
+    /// Choose what happens when the next layer fails to provide a cuboid.
+    pub fn set_on_upstream_error(&mut self, policy: UpstreamErrorPolicy) {
+        self.on_upstream_error = policy;
+    }
+
+    /// Choose what happens to a cuboid that isn't cached when there's no
+    /// next layer to fetch it from.  Cuboids recorded as empty (see
+    /// `set_empty_cuboids`) are still filled.
+    pub fn set_on_miss(&mut self, policy: MissPolicy) {
+        self.on_miss = policy;
+    }
+
+    /// Remember channels that the next layer reports don't exist in a
+    /// shared list, so that they aren't looked up again for a while.
+    pub fn set_not_found(&mut self, not_found: Arc<NotFoundChannels>) {
+        self.not_found = Some(not_found);
+    }
+
+    /// Choose the on-disk format of newly written cuboids (see
+    /// `cuboid_file`).  Files in either format can always be read.
+    pub fn set_format_version(&mut self, version: u16) {
+        self.format_version = version;
+    }
+
+    /// Choose how cuboids are named and stored (see `cuboid_file::Layout`),
+    /// e.g. to share a cache with the Python bossphorus.
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    /// Give channels that don't use the default cuboid size their own (see
+    /// `ChannelRegistry::set_cuboid_sizes`), for when the registry can't
+    /// resolve them.
+    ///
+    /// # Arguments
+    ///
+    /// * `sizes` - Cuboid sizes, keyed by `collection/experiment/channel`
+    pub fn set_channel_cuboid_sizes(&mut self, sizes: HashMap<String, Vector3>) {
+        self.channel_cuboid_sizes = sizes;
+    }
+
+    /// Store some resolutions under their own roots instead of the default
+    /// one, e.g. to put them on faster disks.
+    pub fn set_resolution_roots(&mut self, roots: HashMap<u8, String>) {
+        self.resolution_roots = roots;
+    }
+
+    /// Remember which cuboids are empty, so that they're served from the
+    /// fill value without looking for their files again.  Only cuboids that
+    /// can't be fetched from a next layer are recorded; writing a cuboid
+    /// drops it from the record.
+    pub fn set_empty_cuboids(&mut self, empty: Arc<EmptyCuboids>) {
+        self.empty = Some(empty);
+    }
+
+    /// Name each channel's directory by a hash of the channel (see
+    /// `db::channel_keys`), so that cache paths don't reveal which datasets
+    /// are cached.  Cuboids cached under plain names aren't found anymore.
+    pub fn set_channel_keys(&mut self, keys: Arc<ChannelKeys>) {
+        self.channel_keys = Some(keys);
+    }
+
+    /// Resolve channel datatypes through a shared registry.  Without one,
+    /// every channel is assumed to be `uint8`.
+    pub fn set_channels(&mut self, channels: Arc<ChannelRegistry>) {
+        self.channels = Some(channels);
+    }
+
+    /// Get what's known about a channel, if there's a registry and it can
+    /// resolve the channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    ///
+    pub fn channel_info(&self, uri: &str) -> Option<ChannelInfo> {
+        let boss_uri: Vec<&str> = uri.split("://").collect();
+        self.channels.as_ref()?.get(boss_uri[1])
+    }
+
+    /// Cuboid size of a channel: the override (see
+    /// `set_cuboid_size_override`) if there is one, then the one registered
+    /// for it, if there's a registry and it can resolve the channel, then
+    /// the one configured for it (see `set_channel_cuboid_sizes`), or else
+    /// the default.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    ///
+    pub fn cuboid_size_of(&self, uri: &str) -> Vector3 {
+        if let Some(cuboid_size) = self.cuboid_size_override {
+            return cuboid_size;
+        }
+        if let Some(info) = self.channel_info(uri) {
+            return info.cuboid_size;
+        }
+        let channel = uri.splitn(2, "://").nth(1).unwrap_or(uri);
+        match self.channel_cuboid_sizes.get(channel) {
+            Some(cuboid_size) => *cuboid_size,
+            None => self.cuboid_size,
+        }
+    }
+
+    /// Can this manager store the channel's voxels?  Only single-byte
+    /// datatypes are supported, and unresolved channels are assumed to be
+    /// `uint8`.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    ///
+    pub fn supports_channel(&self, uri: &str) -> bool {
+        match self.channel_info(uri) {
+            Some(info) => info.element_size() == Some(1),
+            None => true,
+        }
+    }
+
+    /// Directory of a channel under a cache root: `col/exp/chan`, or its
+    /// hash (see `set_channel_keys`).
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    ///
+    fn channel_dir(&self, uri: &str) -> String {
+        let boss_uri: Vec<&str> = uri.split("://").collect();
+        match &self.channel_keys {
+            Some(keys) => keys.key(boss_uri[1]),
+            None => boss_uri[1].to_string(),
+        }
+    }
+
+    /// Path of a cuboid file on disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `cuboid_index` - Index of the cuboid in the cuboid grid
+    ///
+    fn cuboid_filename(&self, uri: &str, res: u8, cuboid_index: &Vector3) -> String {
+        format!(
+            "{}/{}/{}/{}",
+            self.resolution_roots.get(&res).unwrap_or(&self.file_path),
+            self.channel_dir(uri),
+            res,
+            self.layout.key(cuboid_index, self.cuboid_size_of(uri))
+        )
+    }
+
+    /// The resolutions of a channel that have any cuboids cached, in order,
+    /// with how many each has.  Looks for numeric resolution directories
+    /// under the channel in the cuboid root and every resolution root.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    ///
+    pub fn cached_resolutions(&self, uri: &str) -> Vec<CachedResolution> {
+        let channel_dir = self.channel_dir(uri);
+        let mut resolutions: Vec<u8> = std::iter::once(&self.file_path)
+            .chain(self.resolution_roots.values())
+            .filter_map(|root| fs::read_dir(format!("{}/{}", root, channel_dir)).ok())
+            .flat_map(|entries| entries.filter_map(|entry| entry.ok()))
+            .filter(|entry| entry.file_type().map_or(false, |t| t.is_dir()))
+            .filter_map(|entry| entry.file_name().to_str()?.parse::<u8>().ok())
+            .collect();
+        resolutions.sort();
+        resolutions.dedup();
+        resolutions
+            .into_iter()
+            .map(|res| {
+                // Only the root that a resolution is configured to live in
+                // counts for it:
+                let root = self.resolution_roots.get(&res).unwrap_or(&self.file_path);
+                let dir = format!("{}/{}/{}", root, channel_dir, res);
+                CachedResolution {
+                    res,
+                    cuboids: cuboid_file::count_cuboids(Path::new(&dir)),
+                }
+            })
+            .filter(|resolution| resolution.cuboids > 0)
+            .collect()
+    }
+
+    /// Compute a strong `ETag` for a cutout.
+    ///
+    /// The tag covers the cutout's extents and the content hash of every
+    /// cuboid that it touches, so it changes whenever any of those cuboids
+    /// is rewritten.  Returns `None` unless every cuboid is cached locally
+    /// and hasn't expired (see `set_max_age`), since otherwise the cutout's
+    /// content isn't known without a fetch.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the cutout (global coords)
+    /// * `destination` - The end position in global coords
+    /// * `format` - Name of the representation (e.g. `blosc`)
+    ///
+    pub fn cutout_etag(
+        &self,
+        uri: &str,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+        format: &str,
+    ) -> Option<String> {
+        if self.cuboid_size_override.is_some() {
+            return None;
+        }
+        self.flush_buffered(uri, res, origin, destination);
+        let cuboids = get_cuboids_and_indices(origin, destination, self.cuboid_size_of(uri));
+        let mut indices: Vec<&Vector3> = cuboids.keys().collect();
+        indices.sort_by_key(|i| (i.z, i.y, i.x));
+
+        let mut hasher = Fnv64::new();
+        hasher.write(format!("{}|{}|{}|{}|{}", uri, res, origin, destination, format).as_bytes());
+        for cuboid_index in indices {
+            let filename = self.cuboid_filename(uri, res, cuboid_index);
+            if self.is_expired(&filename) {
+                // It's about to be revalidated:
+                return None;
+            }
+            let hash = match &self.hashes {
+                Some(hashes) => hashes.get(&filename)?,
+                None => etag::hash_bytes(&fs::read(&filename).ok()?),
+            };
+            hasher.write(cuboid_index.to_string().as_bytes());
+            hasher.write(&hash.to_le_bytes());
+        }
+        Some(format!("\"{:016x}\"", hasher.finish()))
+    }
+
+    /// When a cutout last changed: the newest modification time of the
+    /// cuboid files it touches, which are rewritten whenever they change.
+    /// Returns `None` unless every cuboid is cached locally, the same as
+    /// `cutout_etag`.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the cutout (global coords)
+    /// * `destination` - The end position in global coords
+    ///
+    pub fn cutout_last_modified(
+        &self,
+        uri: &str,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Option<SystemTime> {
+        if self.cuboid_size_override.is_some() {
+            return None;
+        }
+        self.flush_buffered(uri, res, origin, destination);
+        let cuboids = get_cuboids_and_indices(origin, destination, self.cuboid_size_of(uri));
+        let mut newest = None;
+        for cuboid_index in cuboids.keys() {
+            let filename = self.cuboid_filename(uri, res, cuboid_index);
+            if self.is_expired(&filename) {
+                return None;
+            }
+            let modified = fs::metadata(&filename).and_then(|m| m.modified()).ok()?;
+            newest = newest.max(Some(modified));
+        }
+        newest
+    }
+
+    /// Is a cuboid cached on disk?  Empty or partial files don't count.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `cuboid_index` - Index of the cuboid in the cuboid grid
+    ///
+    pub fn has_cuboid(&self, uri: &str, res: u8, cuboid_index: &Vector3) -> bool {
+        let filename = self.cuboid_filename(uri, res, cuboid_index);
+        if let Some(buffer) = &self.write_buffer {
+            buffer.flush(&filename);
+        }
+        cuboid_file::is_complete(Path::new(&filename), self.cuboid_size_of(uri))
+    }
+
+    /// Fraction of a cutout's voxels that are cached on disk, from 0 to 1.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the cutout (global coords)
+    /// * `destination` - The end position in global coords
+    ///
+    pub fn cache_coverage(&self, uri: &str, res: u8, origin: Vector3, destination: Vector3) -> f64 {
+        let cuboids = get_cuboids_and_indices(origin, destination, self.cuboid_size_of(uri));
+        let mut total: u64 = 0;
+        let mut cached: u64 = 0;
+        for (cuboid_index, (start_ind, stop_ind)) in &cuboids {
+            let voxels = (stop_ind.x - start_ind.x)
+                * (stop_ind.y - start_ind.y)
+                * (stop_ind.z - start_ind.z);
+            total += voxels;
+            if self.has_cuboid(uri, res, cuboid_index) {
+                cached += voxels;
+            }
+        }
+        if total == 0 {
+            return 0.0;
+        }
+        cached as f64 / total as f64
+    }
+
+    /// Which of a cutout's cuboids are cached on disk, ordered by z, then
+    /// y, then x.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the cutout (global coords)
+    /// * `destination` - The end position in global coords
+    ///
+    pub fn cuboid_coverage(
+        &self,
+        uri: &str,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Vec<CuboidCoverage> {
+        let cuboids = get_cuboids_and_indices(origin, destination, self.cuboid_size_of(uri));
+        let mut coverage: Vec<CuboidCoverage> = cuboids
+            .keys()
+            .map(|index| CuboidCoverage {
+                index: *index,
+                cached: self.has_cuboid(uri, res, index),
+            })
+            .collect();
+        coverage.sort_by_key(|c| (c.index.z, c.index.y, c.index.x));
+        coverage
+    }
+
+    /// Compare a cached cutout with the same region fetched straight from
+    /// the next layer, bypassing the cache, to catch stale or corrupt
+    /// cuboids.  The cached side is read as it is on disk, expired or not,
+    /// without counting as a use of it.  Returns `None` if any cuboid of
+    /// the cutout isn't cached.  Fails if there's no next layer to compare
+    /// against.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the cutout (global coords)
+    /// * `destination` - The end position in global coords
+    ///
+    pub fn diff_cutout(
+        &self,
+        uri: &str,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Result<Option<CutoutDiff>, UpstreamError> {
+        if !self.has_next_layer {
+            return Err(UpstreamError::Failed(
+                "There's no upstream to compare against".to_string(),
+            ));
+        }
+        let cached = match self.read_cached(uri, res, origin, destination) {
+            Some(cached) => cached,
+            None => return Ok(None),
+        };
+        let boss_uri: Vec<&str> = uri.split("://").collect();
+        let upstream = self.get_next_layer().try_get_data(
+            boss_uri[boss_uri.len() - 1].to_string(),
+            res,
+            origin,
+            destination,
+        )?;
+        if upstream.shape() != cached.shape() {
+            return Err(UpstreamError::Failed(format!(
+                "Upstream sent a cutout of shape {:?} instead of {:?}",
+                upstream.shape(),
+                cached.shape()
+            )));
+        }
+        Ok(Some(CutoutDiff::compare(
+            &cached,
+            &upstream,
+            origin,
+            self.frame.at_res(res),
+        )))
+    }
+
+    /// Is a region known to be all zeros without reading any of it?  That's
+    /// when every cuboid of it is recorded as empty (see
+    /// `set_empty_cuboids`) and the channel's fill value is zero.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the cutout (global coords)
+    /// * `destination` - The end position in global coords
+    ///
+    pub fn known_zero(&self, uri: &str, res: u8, origin: Vector3, destination: Vector3) -> bool {
+        let empty = match &self.empty {
+            Some(empty)
+                if self.cuboid_size_override.is_none() && self.on_miss == MissPolicy::Fill =>
+            {
+                empty
+            }
+            _ => return false,
+        };
+        let boss_uri: Vec<&str> = uri.split("://").collect();
+        if self.fill_values.get(boss_uri[1]) != 0 {
+            return false;
+        }
+        self.flush_buffered(uri, res, origin, destination);
+        let cuboids = get_cuboids_and_indices(origin, destination, self.cuboid_size_of(uri));
+        !cuboids.is_empty()
+            && cuboids
+                .keys()
+                .all(|cuboid_index| empty.contains(&self.cuboid_filename(uri, res, cuboid_index)))
+    }
+
+    /// Read a cached cuboid.  Returns `None` if the file is missing, or if
+    /// it's empty or partial (e.g. left behind by a crash mid-write), so
+    /// that callers treat it as a cache miss and fetch it cleanly.  Files
+    /// in a format this reader doesn't understand are treated the same way.
+    /// Compressed cuboids are decompressed.
+    fn read_cuboid(&self, filename: &str, cuboid_size: Vector3) -> Option<Array3<u8>> {
+        let data = {
+            let _permit = self.file_permit();
+            cuboid_file::read_file(Path::new(filename), self.reads).ok()?
         };
+        let data = cuboid_file::decode_voxels(data, cuboid_size)?;
+        Array::from_shape_vec(cuboid_size.to_zyx_shape(), data).ok()
+    }
+
+    /// Get data from a specified cutout region, noting whether any of it
+    /// had to be filled in because the next layer failed.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the cutout (global coords)
+    /// * `destination` - The end position in global coords
+    ///
+    /// # Returns
+    ///
+    /// * The cutout
+    ///
+    pub fn get_cutout(
+        &self,
+        uri: String,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Cutout {
+        self.flush_buffered(&uri, res, origin, destination);
+        if self.clamp_to_extent {
+            if let Some(extent) = self.extent_in_frame(&uri, res) {
+                if Vector3::intersection((origin, destination), extent)
+                    != Some((origin, destination))
+                {
+                    return self.get_clamped_cutout(uri, res, origin, destination, extent);
+                }
+            }
+        }
+        self.read_cutout(uri, res, origin, destination)
+    }
+
+    /// Get a cutout that extends past its channel's extent, reading only
+    /// the cuboids that overlap the extent and filling the rest.
+    fn get_clamped_cutout(
+        &self,
+        uri: String,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+        extent: (Vector3, Vector3),
+    ) -> Cutout {
+        let boss_uri: Vec<&str> = uri.split("://").collect();
+        let fill = self.fill_values.get(boss_uri[1]);
+        let shape = Vector3::checked_shape(origin, destination).expect("Reversed extents");
+        let mut data: Array3<u8> = Array::from_elem(shape.to_zyx_shape(), fill);
+
+        // The part of the cutout inside the extent:
+        let (inside_start, inside_stop) = match Vector3::intersection((origin, destination), extent)
+        {
+            Some(inside) => inside,
+            None => {
+                return Cutout {
+                    data,
+                    partial: false,
+                    cache_hit: true,
+                    not_found: false,
+                    missing: false,
+                    clamped: true,
+                    sources: CuboidSources::default(),
+                }
+            }
+        };
+        // Whole cuboids are read, so round out to the cuboid grid:
+        let size = self.cuboid_size_of(&uri);
+        let cuboids = (
+            Vector3 {
+                x: inside_start.x / size.x * size.x,
+                y: inside_start.y / size.y * size.y,
+                z: inside_start.z / size.z * size.z,
+            },
+            Vector3 {
+                x: (inside_stop.x + size.x - 1) / size.x * size.x,
+                y: (inside_stop.y + size.y - 1) / size.y * size.y,
+                z: (inside_stop.z + size.z - 1) / size.z * size.z,
+            },
+        );
+        let (read_start, read_stop) =
+            Vector3::intersection((origin, destination), cuboids).unwrap();
+        let read = self.read_cutout(uri, res, read_start, read_stop);
+        // Offsets of the inside part, in the cutout and in what was read:
+        let offset = |from: Vector3, to: Vector3| Vector3::checked_shape(from, to).unwrap();
+        data.slice_mut(&Vector3::zyx_slice(
+            offset(origin, inside_start),
+            offset(origin, inside_stop),
+        ))
+        .assign(&read.data.slice(&Vector3::zyx_slice(
+            offset(read_start, inside_start),
+            offset(read_start, inside_stop),
+        )));
+        Cutout {
+            data,
+            clamped: true,
+            ..read
+        }
+    }
+
+    /// Read a cutout, without regard to the channel's extent.
+    fn read_cutout(&self, uri: String, res: u8, origin: Vector3, destination: Vector3) -> Cutout {
+        let size = self.cuboid_size_of(&uri);
+        let cuboids = get_cuboids_and_indices(origin, destination, size);
+
+        let boss_uri: Vec<&str> = uri.split("://").collect();
+
+        let shape = Vector3::checked_shape(origin, destination).expect("Reversed extents");
+        let mut large_array: Array3<u8> =
+            Array::from_elem(shape.to_zyx_shape(), self.fill_values.get(boss_uri[1]));
+
+        let mut partial = false;
+        let mut cache_hit = true;
+        let mut missing = false;
+        let mut sources = CuboidSources::default();
+        // Cuboids that aren't cached or have expired, to be fetched from
+        // the next layer, each marked if it has an expired copy to fall
+        // back on and if it's to be cached:
+        let mut misses = Vec::new();
+        for (cuboid_index, (start_ind, stop_ind)) in &cuboids {
+            if self.cuboid_size_override.is_some() {
+                // The cache's cuboids are a different size:
+                cache_hit = false;
+                if self.has_next_layer {
+                    misses.push((cuboid_index, start_ind, stop_ind, false, false));
+                } else {
+                    missing |= self.on_miss == MissPolicy::NotFound;
+                    sources.filled += 1;
+                }
+                continue;
+            }
+            let filename = self.cuboid_filename(&uri, res, cuboid_index);
+            if let Some(empty) = &self.empty {
+                if empty.contains(&filename) {
+                    // Leave it filled.
+                    missing |= self.on_miss == MissPolicy::NotFound;
+                    sources.filled += 1;
+                    continue;
+                }
+            }
+
+            // An expired cuboid is still read, to fall back on if it can't
+            // be revalidated:
+            let expired = self.is_expired(&filename);
+
+            if self.use_mmap {
+                if let Some(mmap) = self.map_cuboid(&filename, size) {
+                    self.record_usage(&filename);
+                    let view = ArrayView3::from_shape(
+                        size.to_zyx_shape(),
+                        cuboid_file::voxels(&mmap, size).unwrap(),
+                    )
+                    .unwrap();
+
+                    // Copy only the needed region straight out of the map:
+                    self.insert_cuboid(
+                        &mut large_array,
+                        view,
+                        cuboid_index,
+                        start_ind,
+                        stop_ind,
+                        origin,
+                    );
+                    if expired {
+                        cache_hit = false;
+                        misses.push((cuboid_index, start_ind, stop_ind, true, self.writes_back()));
+                    } else {
+                        sources.cache += 1;
+                    }
+                    continue;
+                }
+            }
+
+            // Get existing data, reading only the part of the cuboid that's
+            // needed:
+            let region = {
+                let _permit = self.file_permit();
+                cuboid_file::read_region_with(
+                    Path::new(&filename),
+                    size,
+                    *start_ind,
+                    *stop_ind,
+                    self.reads,
+                )
+            };
+            if let Some(region) = region {
+                self.record_usage(&filename);
+                let (cutout_start, cutout_stop) =
+                    cutout_coords(size, cuboid_index, start_ind, stop_ind, origin);
+                large_array
+                    .slice_mut(&Vector3::zyx_slice(cutout_start, cutout_stop))
+                    .assign(&region);
+                if expired {
+                    cache_hit = false;
+                    misses.push((cuboid_index, start_ind, stop_ind, true, self.writes_back()));
+                } else {
+                    sources.cache += 1;
+                }
+                continue;
+            }
+
+            if let Some(cuboid) = self.synthesize_cuboid(&uri, res, cuboid_index) {
+                self.insert_cuboid(
+                    &mut large_array,
+                    cuboid.view(),
+                    cuboid_index,
+                    start_ind,
+                    stop_ind,
+                    origin,
+                );
+                sources.synthesized += 1;
+            } else {
+                cache_hit = false;
+                // Without writeback, or until it's missed again, this
+                // cuboid won't be in the cache.  If it is cached, writing
+                // it records its usage.
+                let cache = self.has_next_layer && self.caches_miss(&filename);
+                if self.has_next_layer {
+                    misses.push((cuboid_index, start_ind, stop_ind, false, cache));
+                    continue;
+                }
+                // Otherwise there's nowhere to fetch this cuboid from, so
+                // leave it filled.
+                missing |= self.on_miss == MissPolicy::NotFound;
+                sources.filled += 1;
+                // Unless misses are refused, in which case this one is
+                // refused every time:
+                if let (Some(empty), MissPolicy::Fill) = (&self.empty, self.on_miss) {
+                    empty.record(&filename);
+                }
+            }
+        }
+
+        if misses.is_empty() {
+            return Cutout {
+                data: large_array,
+                partial,
+                cache_hit,
+                not_found: false,
+                missing,
+                clamped: false,
+                sources,
+            };
+        }
+        if let Some(not_found) = &self.not_found {
+            if not_found.contains(boss_uri[1]) {
+                sources.filled += misses.len() as u32;
+                return Cutout {
+                    data: large_array,
+                    partial,
+                    cache_hit,
+                    not_found: true,
+                    missing,
+                    clamped: false,
+                    sources,
+                };
+            }
+        }
+
+        // TODO: These are cache misses.
+        // Right now, we just pass to the next layer, but we can
+        // certainly be smarter about this.
+        let extents = misses
+            .iter()
+            .map(|(cuboid_index, _, _, _, _)| {
+                (
+                    Vector3 {
+                        x: cuboid_index.x * size.x,
+                        y: cuboid_index.y * size.y,
+                        z: cuboid_index.z * size.z,
+                    },
+                    Vector3 {
+                        x: (1 + cuboid_index.x) * size.x,
+                        y: (1 + cuboid_index.y) * size.y,
+                        z: (1 + cuboid_index.z) * size.z,
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+        // Fetch every miss at once, so that a layer that can overlap its
+        // requests doesn't wait on each in turn:
+        let fetched =
+            self.get_next_layer()
+                .try_get_many(boss_uri[1].to_string(), res, extents.clone());
+
+        let mut not_found = false;
+        for ((cuboid_index, start_ind, stop_ind, stale, cache), (fetched, (cuboid_origin, _))) in
+            misses.into_iter().zip(fetched.into_iter().zip(extents))
+        {
+            let array = match (fetched, self.on_upstream_error) {
+                (Ok(fetched), _) => fetched,
+                (Err(UpstreamError::NotFound(err)), _) => {
+                    // Don't cache anything, so that the channel is found
+                    // once it's created.
+                    println!("Channel of {} not found: {}", uri, err);
+                    not_found = true;
+                    sources.filled += 1;
+                    continue;
+                }
+                (Err(err), _) if stale => {
+                    // Keep serving the cached copy until it can be
+                    // revalidated.
+                    println!("Serving an expired cuboid of {}: {}", uri, err);
+                    sources.cache += 1;
+                    continue;
+                }
+                (Err(err), UpstreamErrorPolicy::ServePartial) => {
+                    // Leave this cuboid filled, and don't cache it.
+                    println!("Serving partial cutout of {}: {}", uri, err);
+                    partial = true;
+                    sources.filled += 1;
+                    continue;
+                }
+                (Err(err), UpstreamErrorPolicy::Fail) => panic!("{}", err),
+            };
+
+            self.insert_cuboid(
+                &mut large_array,
+                array.view(),
+                cuboid_index,
+                start_ind,
+                stop_ind,
+                origin,
+            );
+            sources.upstream += 1;
+
+            // Put this cuboid into storage for next time:
+            // TODO: We should be abstracting cache management; just
+            //       dumping data back into the datamanager is ugly
+            //       and will be impossible to maintain.
+            if cache {
+                self.put_data(uri.clone(), res, cuboid_origin, array);
+            }
+        }
+
+        if not_found {
+            if let Some(channels) = &self.not_found {
+                channels.record(boss_uri[1]);
+            }
+        }
+        Cutout {
+            data: large_array,
+            partial,
+            cache_hit,
+            not_found,
+            missing,
+            clamped: false,
+            sources,
+        }
+    }
+
+    /// Write uploaded data to the cache and, if writing through, to the next
+    /// layer first.  Nothing is cached if the next layer refuses it.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the data (global coords)
+    /// * `data` - The voxels, in ZYX order
+    ///
+    pub fn upload(&self, uri: String, res: u8, origin: Vector3, data: Array3<u8>) -> bool {
+        match self.try_upload(&uri, res, origin, data) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Refusing to write {}: {}", uri, e);
+                false
+            }
+        }
     }
 
-    pub fn new_with_layer(
-        file_path: String,
-        cuboid_size: Vector3,
-        next_layer: Box<dyn DataManager>,
-        track_usage: bool,
-    ) -> ChunkedFileDataManager {
-        return ChunkedFileDataManager {
-            file_path,
-            cuboid_size,
-            next_layer,
-            track_usage,
+    /// Like `upload`, but reporting what was written, or why it wasn't.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the data (global coords)
+    /// * `data` - The voxels, in ZYX order
+    ///
+    pub fn try_upload(
+        &self,
+        uri: &str,
+        res: u8,
+        origin: Vector3,
+        data: Array3<u8>,
+    ) -> Result<UploadSummary, UploadError> {
+        if !self.supports_channel(uri) {
+            return Err(UploadError::UnsupportedChannel(uri.to_string()));
+        }
+        if self.write_through && !self.scratch && self.has_next_layer {
+            let boss_uri: Vec<&str> = uri.split("://").collect();
+            if !self
+                .get_next_layer()
+                .put_data(boss_uri[1].to_string(), res, origin, data.clone())
+            {
+                return Err(UploadError::Rejected(uri.to_string()));
+            }
+        }
+        let destination = Vector3 {
+            x: origin.x + data.len_of(ndarray::Axis(2)) as u64,
+            y: origin.y + data.len_of(ndarray::Axis(1)) as u64,
+            z: origin.z + data.len_of(ndarray::Axis(0)) as u64,
         };
+        let summary = self.write_cuboids(uri, res, origin, data)?;
+        self.write_downsampled(uri, res, origin, destination)?;
+        Ok(summary)
     }
-}
-
-impl DataManager for ChunkedFileDataManager {
-    /// TODO: `has_data`
-    // fn has_data(&self) -> bool {
-    //     return true;
-    // }
 
-    /// Get data from a specified cutout region.
+    /// Rebuild the cuboids of the coarser resolutions that an upload
+    /// touched (see `set_downsample_on_write`).
     ///
     /// # Arguments
     ///
-    /// * `origin` - The start position of the cutout (global coords)
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level of the upload
+    /// * `origin` - The start position of the upload (global coords)
     /// * `destination` - The end position in global coords
     ///
-    /// # Returns
+    fn write_downsampled(
+        &self,
+        uri: &str,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Result<(), UploadError> {
+        if self.downsample_levels == 0 {
+            return Ok(());
+        }
+        let boss_uri: Vec<&str> = uri.split("://").collect();
+        let method = self
+            .channels
+            .as_ref()
+            .and_then(|channels| channels.channel_type(boss_uri[1]))
+            .map_or(Downsampling::Mean, |kind| {
+                Downsampling::for_channel_type(&kind)
+            });
+        // Nothing is coarser than the last level:
+        let first = match res.checked_add(1) {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let size = self.cuboid_size_of(uri);
+        let (mut start, mut stop) = (origin, destination);
+        for level in first..=res.saturating_add(self.downsample_levels) {
+            // Each level halves x and y, rounding outwards:
+            start = Vector3 {
+                x: start.x / 2,
+                y: start.y / 2,
+                z: start.z,
+            };
+            stop = Vector3 {
+                x: (stop.x + 1) / 2,
+                y: (stop.y + 1) / 2,
+                z: stop.z,
+            };
+            // Every cuboid that the region touches, even partly, is rebuilt
+            // whole from the 2x2 cuboids below it:
+            for z in start.z / size.z..(stop.z + size.z - 1) / size.z {
+                for y in start.y / size.y..(stop.y + size.y - 1) / size.y {
+                    for x in start.x / size.x..(stop.x + size.x - 1) / size.x {
+                        let cuboid_origin = Vector3 {
+                            x: x * size.x,
+                            y: y * size.y,
+                            z: z * size.z,
+                        };
+                        let source_origin = Vector3 {
+                            x: cuboid_origin.x * 2,
+                            y: cuboid_origin.y * 2,
+                            z: cuboid_origin.z,
+                        };
+                        let source_destination = Vector3 {
+                            x: source_origin.x + size.x * 2,
+                            y: source_origin.y + size.y * 2,
+                            z: source_origin.z + size.z,
+                        };
+                        let source = match self.read_cached(
+                            uri,
+                            level - 1,
+                            source_origin,
+                            source_destination,
+                        ) {
+                            Some(source) => source,
+                            None => continue,
+                        };
+                        let cuboid = downsample::halve_xy(source.view(), method);
+                        self.write_cuboids(uri, level, cuboid_origin, cuboid)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a region from the cache alone, without going to the next layer,
+    /// e.g. to downsample it.  Returns `None` if any of its cuboids isn't
+    /// cached but could be fetched from the next layer, since what's read
+    /// wouldn't be the channel's data.  Without a next layer, cuboids that
+    /// aren't cached are the fill value.
     ///
-    /// * 3D Array
+    /// # Arguments
     ///
-    fn get_data(
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the region (global coords)
+    /// * `destination` - The end position in global coords
+    ///
+    fn read_cached(
         &self,
-        uri: String,
+        uri: &str,
         res: u8,
         origin: Vector3,
         destination: Vector3,
-    ) -> ndarray::Array3<u8> {
-        let cuboids = get_cuboids_and_indices(origin, destination, self.cuboid_size);
-
+    ) -> Option<Array3<u8>> {
+        self.flush_buffered(uri, res, origin, destination);
         let boss_uri: Vec<&str> = uri.split("://").collect();
+        let size = self.cuboid_size_of(uri);
+        let shape = Vector3::checked_shape(origin, destination)?;
+        let mut data = Array::from_elem(shape.to_zyx_shape(), self.fill_values.get(boss_uri[1]));
+        for (cuboid_index, (start_ind, stop_ind)) in
+            get_cuboids_and_indices(origin, destination, size)
+        {
+            let filename = self.cuboid_filename(uri, res, &cuboid_index);
+            match self.read_cuboid(&filename, size) {
+                Some(cuboid) => {
+                    let (cutout_start, cutout_stop) =
+                        cutout_coords(size, &cuboid_index, &start_ind, &stop_ind, origin);
+                    data.slice_mut(&Vector3::zyx_slice(cutout_start, cutout_stop))
+                        .assign(&cuboid.slice(&Vector3::zyx_slice(start_ind, stop_ind)));
+                }
+                None if self.has_next_layer => return None,
+                None => (),
+            }
+        }
+        Some(data)
+    }
+
+    /// Write data to the cuboid files.  A cuboid that can't be written
+    /// doesn't stop the others from being written; the failures are
+    /// reported together at the end.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the data (global coords)
+    /// * `data` - The voxels, in ZYX order
+    ///
+    fn write_cuboids(
+        &self,
+        uri: &str,
+        res: u8,
+        origin: Vector3,
+        data: Array3<u8>,
+    ) -> Result<UploadSummary, UploadError> {
+        if !self.supports_channel(uri) {
+            return Err(UploadError::UnsupportedChannel(uri.to_string()));
+        }
+        if let (true, Some(scratch_channels)) = (self.scratch, &self.scratch_channels) {
+            let boss_uri: Vec<&str> = uri.split("://").collect();
+            if let Err(err) = scratch_channels.record(boss_uri[1]) {
+                println!("{}", err);
+            }
+        }
 
-        let mut large_array: Array3<u8> = Array::zeros((
-            (destination.z - origin.z) as usize,
-            (destination.y - origin.y) as usize,
-            (destination.x - origin.x) as usize,
-        ));
+        let size = self.cuboid_size_of(uri);
+        let cuboids = get_cuboids_and_indices(
+            origin,
+            Vector3 {
+                x: origin.x + data.len_of(ndarray::Axis(2)) as u64,
+                y: origin.y + data.len_of(ndarray::Axis(1)) as u64,
+                z: origin.z + data.len_of(ndarray::Axis(0)) as u64,
+            },
+            size,
+        );
 
+        let writer = self.cuboid_writer(size);
+        let mut summary = UploadSummary::default();
+        // The first failure, and how many there were:
+        let mut failure = None;
+        let mut failures = 0;
         for (cuboid_index, (start_ind, stop_ind)) in &cuboids {
-            let filename = format!(
-                "{}/{}/{}/{}",
-                self.file_path, boss_uri[1], res, cuboid_index
-            );
+            let filename = self.cuboid_filename(uri, res, cuboid_index);
+            let failed = |error| UploadError::Io {
+                filename: filename.clone(),
+                error,
+                failed: 1,
+            };
+            let dir_path: Vec<&str> = filename.split("/").collect();
+            let dir_path_str = dir_path[..dir_path.len() - 1].join("/");
+
+            // Get the coordinates of this cuboid out of the cutout volume:
+            let (cutout_start, cutout_stop) =
+                cutout_coords(size, cuboid_index, start_ind, stop_ind, origin);
+            let upload = data.slice(&Vector3::zyx_slice(cutout_start, cutout_stop));
+            let whole = *start_ind == (Vector3 { x: 0, y: 0, z: 0 }) && *stop_ind == size;
+            // The existing data that part of a cuboid is merged over:
+            let existing = || -> Result<Array3<u8>, UploadError> {
+                if let Some(cached) = self.read_cuboid(&filename, size) {
+                    return Ok(cached);
+                }
+                let base = self
+                    .scratch_base(uri, res, cuboid_index, size)
+                    .map_err(|err| failed(std::io::Error::new(std::io::ErrorKind::Other, err)))?;
+                cuboid_file::create_dir_all(&dir_path_str, self.modes.dir).map_err(failed)?;
+                Ok(base.unwrap_or_else(|| Array::zeros(size.to_zyx_shape())))
+            };
+
+            // The bytes written, none if buffered:
+            let written = || -> Result<u64, UploadError> {
+                if let Some(buffer) = &self.write_buffer {
+                    if whole {
+                        // Nothing buffered is left to merge with:
+                        buffer.discard(&filename);
+                    } else if buffer
+                        .write(&filename, &writer, *start_ind, *stop_ind, upload, &existing)?
+                    {
+                        return Ok(0);
+                    }
+                }
 
-            if self.track_usage {
-                let mutex = usage_tracker::get_sender();
-                let tx = mutex.lock().unwrap();
-                if !tx.send(filename.to_string()).is_ok() {
-                    // ToDo: log some kind of error that the usage manager went down.
+                let voxels = if whole {
+                    // The upload covers the whole cuboid, so there's nothing
+                    // to keep from the existing file:
+                    cuboid_file::create_dir_all(&dir_path_str, self.modes.dir).map_err(failed)?;
+                    region_voxels(upload)
+                } else {
+                    let mut array = existing()?;
+                    // Write cuboid to the array:
+                    array
+                        .slice_mut(&Vector3::zyx_slice(*start_ind, *stop_ind))
+                        .assign(&upload);
+                    array.into_raw_vec()
+                };
+
+                // Write cuboid to disk:
+                writer.write(&filename, &voxels).map_err(failed)
+            };
+            match written() {
+                Ok(bytes) => {
+                    summary.bytes += bytes;
+                    summary.cuboids += 1;
+                }
+                Err(e) => {
+                    failures += 1;
+                    failure.get_or_insert(e);
                 }
             }
+        }
+        match failure {
+            None => Ok(summary),
+            Some(UploadError::Io {
+                filename, error, ..
+            }) => Err(UploadError::Io {
+                filename,
+                error,
+                failed: failures,
+            }),
+            Some(e) => Err(e),
+        }
+    }
 
-            let filepath = Path::new(&filename);
+    /// The next layer's copy of a cuboid, for a scratch upload to merge over
+    /// (see `set_scratch`), or `None` if uploads aren't merged over it.
+    /// Fails if the copy can't be fetched, rather than merging over zeros
+    /// and hiding the next layer's data.
+    fn scratch_base(
+        &self,
+        uri: &str,
+        res: u8,
+        cuboid_index: &Vector3,
+        size: Vector3,
+    ) -> Result<Option<Array3<u8>>, String> {
+        if !self.scratch || !self.has_next_layer {
+            return Ok(None);
+        }
+        let origin = Vector3 {
+            x: cuboid_index.x * size.x,
+            y: cuboid_index.y * size.y,
+            z: cuboid_index.z * size.z,
+        };
+        let destination = Vector3 {
+            x: origin.x + size.x,
+            y: origin.y + size.y,
+            z: origin.z + size.z,
+        };
+        let boss_uri: Vec<&str> = uri.split("://").collect();
+        match self
+            .get_next_layer()
+            .try_get_many(boss_uri[1].to_string(), res, vec![(origin, destination)])
+            .pop()
+        {
+            Some(Ok(base)) => Ok(Some(base)),
+            // A channel of the scratch space alone has nothing upstream:
+            Some(Err(UpstreamError::NotFound(_))) => Ok(None),
+            Some(Err(err)) => Err(format!(
+                "Failed to fetch {} {} to write over: {}",
+                uri, cuboid_index, err
+            )),
+            None => Err(format!(
+                "Failed to fetch {} {} to write over",
+                uri, cuboid_index
+            )),
+        }
+    }
 
-            // Get the coordinates of this cuboid out of the cutout volume:
-            let z_start = ((cuboid_index.z * self.cuboid_size.z) + start_ind.z) - origin.z;
-            let z_stop = ((cuboid_index.z * self.cuboid_size.z) + stop_ind.z) - origin.z;
-            let y_start = ((cuboid_index.y * self.cuboid_size.y) + start_ind.y) - origin.y;
-            let y_stop = ((cuboid_index.y * self.cuboid_size.y) + stop_ind.y) - origin.y;
-            let x_start = ((cuboid_index.x * self.cuboid_size.x) + start_ind.x) - origin.x;
-            let x_stop = ((cuboid_index.x * self.cuboid_size.x) + stop_ind.x) - origin.x;
-
-            let array: Array3<u8>;
-            // Get existing data:
-            if filepath.exists() {
-                let data = fs::read(&filename).unwrap();
-                array = Array::from_shape_vec(
-                    (
-                        self.cuboid_size.z as usize,
-                        self.cuboid_size.y as usize,
-                        self.cuboid_size.x as usize,
-                    ),
-                    data,
-                )
-                .unwrap();
-            } else {
-                // TODO: This is a cache miss.
-                // Right now, we just pass to the next layer, but we can
-                // certainly be smarter about this.
-
-                let z_cuboid_start = cuboid_index.z * self.cuboid_size.z;
-                let z_cuboid_stop = (1 + cuboid_index.z) * self.cuboid_size.z;
-                let y_cuboid_start = cuboid_index.y * self.cuboid_size.y;
-                let y_cuboid_stop = (1 + cuboid_index.y) * self.cuboid_size.y;
-                let x_cuboid_start = cuboid_index.x * self.cuboid_size.x;
-                let x_cuboid_stop = (1 + cuboid_index.x) * self.cuboid_size.x;
-
-                array = self.get_next_layer().get_data(
-                    boss_uri[1].to_string(),
-                    res,
+    /// Fetch and cache every cuboid of a region that isn't cached yet.
+    /// Cuboids that are already cached aren't touched, so warming doesn't
+    /// count as a request for them.  Returns the number of cuboids written.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the region (global coords)
+    /// * `destination` - The end position in global coords
+    ///
+    pub fn warm(&self, uri: &str, res: u8, origin: Vector3, destination: Vector3) -> usize {
+        if !self.has_next_layer || !self.writes_back() || !self.supports_channel(uri) {
+            return 0;
+        }
+
+        // Every cuboid that the region touches, even partly:
+        let size = self.cuboid_size_of(uri);
+        let mut missing = Vec::new();
+        for z in origin.z / size.z..(destination.z + size.z - 1) / size.z {
+            for y in origin.y / size.y..(destination.y + size.y - 1) / size.y {
+                for x in origin.x / size.x..(destination.x + size.x - 1) / size.x {
+                    let cuboid_index = Vector3 { x, y, z };
+                    if !self.has_cuboid(uri, res, &cuboid_index) {
+                        missing.push(cuboid_index);
+                    }
+                }
+            }
+        }
+        if missing.is_empty() {
+            return 0;
+        }
+
+        let extents: Vec<(Vector3, Vector3)> = missing
+            .iter()
+            .map(|i| {
+                (
                     Vector3 {
-                        x: x_cuboid_start,
-                        y: y_cuboid_start,
-                        z: z_cuboid_start,
+                        x: i.x * size.x,
+                        y: i.y * size.y,
+                        z: i.z * size.z,
                     },
                     Vector3 {
-                        x: x_cuboid_stop,
-                        y: y_cuboid_stop,
-                        z: z_cuboid_stop,
+                        x: (i.x + 1) * size.x,
+                        y: (i.y + 1) * size.y,
+                        z: (i.z + 1) * size.z,
                     },
-                );
+                )
+            })
+            .collect();
+        let boss_uri: Vec<&str> = uri.split("://").collect();
+        let fetched =
+            self.get_next_layer()
+                .try_get_many(boss_uri[1].to_string(), res, extents.clone());
 
-                // Put this cuboid into storage for next time:
-                // TODO: We should be abstracting cache management; just
-                //       dumping data back into the datamanager is ugly
-                //       and will be impossible to maintain.
-                self.put_data(
-                    uri.clone(),
-                    res,
-                    Vector3 {
-                        x: x_cuboid_start,
-                        y: y_cuboid_start,
-                        z: z_cuboid_start,
-                    },
-                    array.clone(),
-                );
+        let mut written = 0;
+        for ((cuboid_index, (cuboid_origin, _)), fetched) in
+            missing.iter().zip(extents).zip(fetched)
+        {
+            match fetched {
+                Ok(array) => {
+                    if self.put_data(uri.to_string(), res, cuboid_origin, array) {
+                        written += 1;
+                    }
+                }
+                Err(err) => println!("Failed to prefetch {} {}: {}", uri, cuboid_index, err),
+            }
+        }
+        written
+    }
+
+    /// Build a cuboid that isn't cached by downsampling the closest higher
+    /// resolution whose covering cuboids are all cached.  Returns `None` if
+    /// the channel has no synthesis method, or no such resolution is within
+    /// `MAX_SYNTHESIS_LEVELS`.  The result isn't cached, so a later fetch
+    /// from the next layer can still replace it.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level of the cuboid
+    /// * `cuboid_index` - Index of the cuboid in the cuboid grid
+    ///
+    fn synthesize_cuboid(&self, uri: &str, res: u8, cuboid_index: &Vector3) -> Option<Array3<u8>> {
+        let boss_uri: Vec<&str> = uri.split("://").collect();
+        let method = self.synthesis.get(boss_uri[1])?;
+        let size = self.cuboid_size_of(uri);
+        for source_res in (res.saturating_sub(MAX_SYNTHESIS_LEVELS)..res).rev() {
+            // Each level halves x and y:
+            let factor = 1 << (res - source_res);
+            let origin = Vector3 {
+                x: cuboid_index.x * size.x * factor,
+                y: cuboid_index.y * size.y * factor,
+                z: cuboid_index.z * size.z,
+            };
+            let destination = Vector3 {
+                x: origin.x + size.x * factor,
+                y: origin.y + size.y * factor,
+                z: origin.z + size.z,
+            };
+            if !self.has_data(uri.to_string(), source_res, origin, destination) {
+                continue;
             }
+            let mut data = self
+                .get_cutout(uri.to_string(), source_res, origin, destination)
+                .data;
+            for _ in source_res..res {
+                data = downsample::halve_xy(data.view(), method);
+            }
+            return Some(data);
+        }
+        None
+    }
+
+    /// Send usage of the cuboids to `sender` rather than to the usage
+    /// tracker started by `usage_tracker::run()`, so that a test can drive
+    /// a tracker of its own.  Usage is sent whether or not tracking is on.
+    #[cfg(test)]
+    pub(crate) fn set_usage_sender(&mut self, sender: Arc<Mutex<Sender<String>>>) {
+        self.usage_sender = Some(sender);
+    }
+
+    /// Tell the usage tracker that a cuboid was used, if tracking is on.
+    fn record_usage(&self, filename: &str) {
+        send_usage(self.usage_sender.as_ref(), self.track_usage, filename);
+    }
 
-            let new_data = array.slice(s![
-                start_ind.z as usize..stop_ind.z as usize,
-                start_ind.y as usize..stop_ind.y as usize,
-                start_ind.x as usize..stop_ind.x as usize
-            ]);
+    /// Writes cuboids of a size as this manager does.
+    pub(crate) fn cuboid_writer(&self, cuboid_size: Vector3) -> CuboidWriter {
+        CuboidWriter {
+            cuboid_size,
+            format_version: self.format_version,
+            layout: self.layout,
+            file_mode: self.modes.file,
+            file_limit: self.file_limit.clone(),
+            hashes: self.hashes.clone(),
+            empty: self.empty.clone(),
+            track_usage: self.track_usage,
+            usage_sender: self.usage_sender.clone(),
+        }
+    }
 
-            // Insert data cutout into large array
-            large_array
-                .slice_mut(s![
-                    z_start as usize..z_stop as usize,
-                    y_start as usize..y_stop as usize,
-                    x_start as usize..x_stop as usize,
-                ])
-                .assign(&new_data);
+    /// Write out the buffered cuboids of a region, if there are any, so
+    /// that reading it sees them.
+    fn flush_buffered(&self, uri: &str, res: u8, origin: Vector3, destination: Vector3) {
+        let buffer = match &self.write_buffer {
+            Some(buffer) if !buffer.is_empty() => buffer,
+            _ => return,
+        };
+        let cuboids = get_cuboids_and_indices(origin, destination, self.cuboid_size_of(uri));
+        for cuboid_index in cuboids.keys() {
+            buffer.flush(&self.cuboid_filename(uri, res, cuboid_index));
         }
+    }
+
+    /// Copy the part of a cuboid that a cutout needs into the cutout.
+    ///
+    /// # Arguments
+    ///
+    /// * `large_array` - The cutout being assembled
+    /// * `cuboid` - The whole cuboid
+    /// * `cuboid_index` - Index of the cuboid in the cuboid grid
+    /// * `start_ind` - Start of the needed region, within the cuboid
+    /// * `stop_ind` - End of the needed region, within the cuboid
+    /// * `origin` - The start position of the cutout (global coords)
+    ///
+    fn insert_cuboid(
+        &self,
+        large_array: &mut Array3<u8>,
+        cuboid: ArrayView3<u8>,
+        cuboid_index: &Vector3,
+        start_ind: &Vector3,
+        stop_ind: &Vector3,
+        origin: Vector3,
+    ) {
+        // Get the coordinates of this cuboid out of the cutout volume:
+        let cuboid_size = Vector3::from_zyx_shape(cuboid.shape());
+        let (cutout_start, cutout_stop) =
+            cutout_coords(cuboid_size, cuboid_index, start_ind, stop_ind, origin);
+
+        let new_data = cuboid.slice(&Vector3::zyx_slice(*start_ind, *stop_ind));
+
+        // Insert data cutout into large array
+        large_array
+            .slice_mut(&Vector3::zyx_slice(cutout_start, cutout_stop))
+            .assign(&new_data);
+    }
+
+    /// Map a cuboid file into memory.  Returns `None` if the file can't be
+    /// mapped or isn't a full cuboid in a readable format.
+    fn map_cuboid(&self, filename: &str, cuboid_size: Vector3) -> Option<Mmap> {
+        // The map outlives the file, so the permit is only needed to map it:
+        let _permit = self.file_permit();
+        let file = fs::File::open(filename).ok()?;
+        // Safety: cuboid files are only ever replaced wholesale by
+        // `put_data`, never truncated in place.
+        let mmap = unsafe { Mmap::map(&file) }.ok()?;
+        cuboid_file::voxels(&mmap, cuboid_size)?;
+        Some(mmap)
+    }
+}
+
+impl DataManager for ChunkedFileDataManager {
+    /// Is every cuboid of the region cached on disk?
+    fn has_data(&self, uri: String, res: u8, origin: Vector3, destination: Vector3) -> bool {
+        let cuboids = get_cuboids_and_indices(origin, destination, self.cuboid_size_of(&uri));
+        cuboids
+            .keys()
+            .all(|cuboid_index| self.has_cuboid(&uri, res, cuboid_index))
+    }
 
-        return large_array;
+    /// Get data from a specified cutout region.
+    ///
+    /// # Arguments
+    ///
+    /// * `origin` - The start position of the cutout (global coords)
+    /// * `destination` - The end position in global coords
+    ///
+    /// # Returns
+    ///
+    /// * 3D Array
+    ///
+    fn get_data(
+        &self,
+        uri: String,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> ndarray::Array3<u8> {
+        self.get_cutout(uri, res, origin, destination).data
     }
 
     /// Upload data (write to the files).
@@ -393,97 +3022,37 @@ impl DataManager for ChunkedFileDataManager {
     /// * Boolean of success
     ///
     fn put_data(&self, uri: String, res: u8, origin: Vector3, data: ndarray::Array3<u8>) -> bool {
-        let cuboids = get_cuboids_and_indices(
-            origin,
-            Vector3 {
-                x: origin.x + data.len_of(ndarray::Axis(2)) as u64,
-                y: origin.y + data.len_of(ndarray::Axis(1)) as u64,
-                z: origin.z + data.len_of(ndarray::Axis(0)) as u64,
-            },
-            self.cuboid_size,
-        );
-        let boss_uri: Vec<&str> = uri.split("://").collect();
-
-        for (cuboid_index, (start_ind, stop_ind)) in &cuboids {
-            let filename = format!(
-                "{}/{}/{}/{}",
-                self.file_path, boss_uri[1], res, cuboid_index
-            );
-
-            let filepath = Path::new(&filename);
-            let mut array: Array3<u8>;
-            // Get existing data:
-            if filepath.exists() && fs::read(&filepath).unwrap().len() > 0 {
-                let read_data = fs::read(&filepath).unwrap();
-                array = Array::from_shape_vec(
-                    (
-                        self.cuboid_size.z as usize,
-                        self.cuboid_size.y as usize,
-                        self.cuboid_size.x as usize,
-                    ),
-                    read_data,
-                )
-                .unwrap();
-            } else {
-                let dir_path: Vec<&str> = filename.split("/").collect();
-                let dir_path_str = dir_path[..dir_path.len() - 1].join("/");
-                match fs::create_dir_all(&dir_path_str) {
-                    Ok(a) => a,
-                    _ => unreachable!(), // Failed to create file somehow...
-                };
-                match fs::File::create(&filepath) {
-                    Err(why) => panic!(
-                        "couldn't create {}: {}",
-                        filepath.display(),
-                        why.to_string()
-                    ),
-                    Ok(file) => file,
-                };
-                array = Array::zeros((
-                    self.cuboid_size.z as usize,
-                    self.cuboid_size.y as usize,
-                    self.cuboid_size.x as usize,
-                ));
-            }
-
-            // Get the coordinates of this cuboid out of the cutout volume:
-            let z_start = ((cuboid_index.z * self.cuboid_size.z) + start_ind.z) - origin.z;
-            let z_stop = ((cuboid_index.z * self.cuboid_size.z) + stop_ind.z) - origin.z;
-            let y_start = ((cuboid_index.y * self.cuboid_size.y) + start_ind.y) - origin.y;
-            let y_stop = ((cuboid_index.y * self.cuboid_size.y) + stop_ind.y) - origin.y;
-            let x_start = ((cuboid_index.x * self.cuboid_size.x) + start_ind.x) - origin.x;
-            let x_stop = ((cuboid_index.x * self.cuboid_size.x) + stop_ind.x) - origin.x;
-
-            // Write cuboid to the array:
-            array
-                .slice_mut(s![
-                    start_ind.z as usize..stop_ind.z as usize,
-                    start_ind.y as usize..stop_ind.y as usize,
-                    start_ind.x as usize..stop_ind.x as usize
-                ])
-                .assign(&data.slice(s![
-                    z_start as usize..z_stop as usize,
-                    y_start as usize..y_stop as usize,
-                    x_start as usize..x_stop as usize,
-                ]));
-
-            // Write cuboid to disk:
-            let mut file = fs::File::create(&filepath).unwrap();
-            match file.write_all(&array.into_raw_vec()) {
-                Err(why) => println!(
-                    "Failed to write cuboid {}: {}",
-                    cuboid_index,
-                    why.to_string()
-                ),
-                Ok(_) => (),
+        match self.write_cuboids(&uri, res, origin, data) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Refusing to write {}: {}", uri, e);
+                false
             }
         }
-        return true;
     }
 
     fn get_next_layer(&self) -> &dyn DataManager {
         return self.next_layer.as_ref();
     }
+
+    fn describe(&self) -> LayerInfo {
+        let size = self.cuboid_size;
+        let mut roots: Vec<_> = self.resolution_roots.iter().collect();
+        roots.sort();
+        let roots: Vec<String> = roots
+            .into_iter()
+            .map(|(res, root)| format!("{}={}", res, root))
+            .collect();
+        LayerInfo::new("file")
+            .with("root", self.file_path.clone())
+            .with("resolution_roots", roots.join(","))
+            .with("cuboid_size", format!("{}:{}:{}", size.x, size.y, size.z))
+            .with("layout", format!("{:?}", self.layout).to_lowercase())
+            .with("writeback", self.writeback.to_string())
+            .with("write_through", self.write_through.to_string())
+            .with("scratch", self.scratch.to_string())
+            .with("hashed_paths", self.channel_keys.is_some().to_string())
+    }
 }
 
 pub struct BossDBRelayDataManager {
@@ -505,6 +3074,17 @@ pub struct BossDBRelayDataManager {
     token: String,
     host: String,
     protocol: String,
+    /// Path prefix of the BossDB API, e.g. `v1`.
+    api_prefix: String,
+    /// Caps concurrent upstream requests across all relays sharing it.
+    upstream_limit: Option<Arc<Semaphore>>,
+    /// Host and token that writes go to, if writes are relayed at all.
+    /// May differ from the host that's read from.
+    write_target: Option<(String, String)>,
+    /// Where the coordinate frame starts upstream, at resolution 0.
+    frame: Coords,
+    /// Counts every cutout fetched, if set.
+    stats: Option<Arc<UpstreamStats>>,
 }
 
 impl BossDBRelayDataManager {
@@ -522,12 +3102,78 @@ impl BossDBRelayDataManager {
             protocol,
             host,
             token,
+            api_prefix: remote::DEFAULT_API_PREFIX.to_string(),
+            upstream_limit: None,
+            write_target: None,
+            frame: Coords::default(),
+            stats: None,
         }
     }
+
+    /// Count every cutout fetched upstream in stats shared with other
+    /// relays.
+    pub fn set_upstream_stats(&mut self, stats: Arc<UpstreamStats>) {
+        self.stats = Some(stats);
+    }
+
+    /// Share a limit on concurrent upstream requests with other relays, so
+    /// that a burst of cache misses doesn't overwhelm the BossDB.
+    pub fn set_upstream_limit(&mut self, limit: Arc<Semaphore>) {
+        self.upstream_limit = Some(limit);
+    }
+
+    /// Relay to a BossDB whose API isn't under `/v1/`.  See
+    /// `BossRemote::set_api_prefix`.
+    pub fn set_api_prefix(&mut self, prefix: &str) {
+        self.api_prefix = prefix.to_string();
+    }
+
+    /// Relay writes to a BossDB, e.g. a staging instance while reads come
+    /// from production.  Without a write target, writes are refused.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The API root of the BossDB instance to write to
+    /// * `token` - The token to use for writes
+    ///
+    pub fn set_write_target(&mut self, host: String, token: String) {
+        self.write_target = Some((host, token));
+    }
+
+    /// Relay to a BossDB whose coordinate frame doesn't start at zero (e.g.
+    /// one with negative coordinates).  Regions are given to the relay
+    /// relative to the start of the frame, and converted back to global
+    /// coordinates upstream.
+    pub fn set_frame(&mut self, frame: Coords) {
+        self.frame = frame;
+    }
+
+    /// The remote that reads are relayed to.
+    pub fn read_remote(&self) -> BossRemote {
+        let mut remote = BossRemote::new(
+            self.protocol.to_string(),
+            self.host.to_string(),
+            self.token.to_string(),
+        );
+        remote.set_api_prefix(&self.api_prefix);
+        remote
+    }
+
+    /// The remote that writes are relayed to, if any.
+    pub fn write_remote(&self) -> Option<BossRemote> {
+        let (host, token) = self.write_target.as_ref()?;
+        let mut remote = BossRemote::new(
+            self.protocol.to_string(),
+            host.to_string(),
+            token.to_string(),
+        );
+        remote.set_api_prefix(&self.api_prefix);
+        Some(remote)
+    }
 }
 
 impl DataManager for BossDBRelayDataManager {
-    /// Get data from the upstream BossDB.
+    /// Get data from the upstream BossDB.  Panics if the upstream fails.
     fn get_data(
         &self,
         uri: String,
@@ -535,32 +3181,115 @@ impl DataManager for BossDBRelayDataManager {
         origin: Vector3,
         destination: Vector3,
     ) -> ndarray::Array3<u8> {
-        let remote = BossRemote::new(
-            self.protocol.to_string(),
-            self.host.to_string(),
-            self.token.to_string(),
-        );
+        match self.try_get_data(uri, res, origin, destination) {
+            Ok(data) => data,
+            Err(err) => panic!("{}", err),
+        }
+    }
 
-        let data = remote
-            .get_cutout(
-                format!("bossdb://{}", uri),
-                res,
-                (origin.x, destination.x),
-                (origin.y, destination.y),
-                (origin.z, destination.z),
-            )
-            .unwrap();
-        return data;
+    /// Get data from the upstream BossDB.
+    fn try_get_data(
+        &self,
+        uri: String,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Result<ndarray::Array3<u8>, UpstreamError> {
+        self.try_get_many(uri, res, vec![(origin, destination)])
+            .pop()
+            .unwrap()
     }
 
-    /// Unimplemented. Don't do this, I think.
-    fn put_data(
+    /// Get several regions from the upstream BossDB, with all of their
+    /// requests in flight at once (up to the upstream limit).  Blocks the
+    /// calling thread, but not one thread per request.
+    fn try_get_many(
         &self,
-        _uri: String,
-        _resolution: u8,
-        _origin: Vector3,
-        _data: ndarray::Array3<u8>,
-    ) -> bool {
-        panic!("Putting data with the BossDB relay is currently not supported.")
+        uri: String,
+        res: u8,
+        extents: Vec<(Vector3, Vector3)>,
+    ) -> Vec<Result<ndarray::Array3<u8>, UpstreamError>> {
+        let remote = self.read_remote();
+        let runtime = remote::runtime();
+        let frame = self.frame.at_res(res);
+
+        let tasks: Vec<_> = extents
+            .into_iter()
+            .map(|(origin, destination)| {
+                // Each request holds a permit until it finishes, so wait for
+                // one before starting it:
+                let permit = self
+                    .upstream_limit
+                    .as_ref()
+                    .map(|limit| limit.acquire_owned());
+                let remote = remote.clone();
+                let stats = self.stats.clone();
+                let boss_uri = format!("bossdb://{}", uri);
+                let start = Coords::from_frame(origin, frame);
+                let stop = Coords::from_frame(destination, frame);
+                runtime.spawn(async move {
+                    let _permit = permit;
+                    let started = Instant::now();
+                    let result = remote
+                        .get_cutout_async(
+                            boss_uri,
+                            res,
+                            (start.x, stop.x),
+                            (start.y, stop.y),
+                            (start.z, stop.z),
+                        )
+                        .await;
+                    if let Some(stats) = stats {
+                        let bytes = result.as_ref().map_or(0, |data| data.len() as u64);
+                        stats.record(bytes, started.elapsed(), result.is_err());
+                    }
+                    result
+                })
+            })
+            .collect();
+
+        runtime.handle().block_on(async {
+            let mut results = Vec::with_capacity(tasks.len());
+            for task in tasks {
+                results.push(task.await.unwrap_or_else(|e| Err(e.to_string().into())));
+            }
+            results
+        })
+    }
+
+    /// Write data to the upstream write target.  Returns false if there
+    /// isn't one, or if the write fails.
+    fn put_data(&self, uri: String, res: u8, origin: Vector3, data: ndarray::Array3<u8>) -> bool {
+        let remote = match self.write_remote() {
+            Some(remote) => remote,
+            None => {
+                println!("Refusing to relay a write to {}: no write host", uri);
+                return false;
+            }
+        };
+        let _permit = self.upstream_limit.as_ref().map(|limit| limit.acquire());
+        let origin = Coords::from_frame(origin, self.frame.at_res(res));
+        match remote.post_cutout(format!("bossdb://{}", uri), res, origin, &data) {
+            Ok(_) => true,
+            Err(err) => {
+                println!("Failed to relay a write to {}: {}", uri, err);
+                false
+            }
+        }
+    }
+
+    fn describe(&self) -> LayerInfo {
+        let (write_host, write_token) = match &self.write_target {
+            Some((host, token)) => (host.clone(), redact_token(token)),
+            None => ("(none)".to_string(), "(none)".to_string()),
+        };
+        LayerInfo::new("bossdb")
+            .with("protocol", self.protocol.clone())
+            .with("host", self.host.clone())
+            .with("api_prefix", self.api_prefix.clone())
+            .with("token", redact_token(&self.token))
+            .with("write_host", write_host)
+            .with("write_token", write_token)
+            .with("frame_origin", self.frame.to_string())
     }
 }
@@ -0,0 +1,2211 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::cuboid_file::{npy, voxels, Layout, Modes, CURRENT_VERSION, LEGACY_VERSION};
+use crate::data_manager::{
+    count_cuboids, describe_chain, get_cuboids_and_indices, BossDBRelayDataManager,
+    CachedResolution, ChunkedFileDataManager, Coords, CuboidCoverage, CuboidSources, DataManager,
+    DownsampleStatus, FillValues, MissPolicy, NotFoundChannels, RecentMisses, UploadError,
+    UpstreamError, UpstreamErrorPolicy, Vector3,
+};
+use crate::db::channel_keys::ChannelKeys;
+use crate::db::channels::{ChannelRegistry, ChannelSource};
+use crate::db::empty::EmptyCuboids;
+use crate::db::pool::ConnectionPool;
+use crate::db::scratch::ScratchChannels;
+use crate::db::PinnedChannels;
+use crate::disk_guard::tests::mock_guard;
+use crate::downsample::{self, Downsampling, SynthesisMethods};
+use crate::intern::remote::BossRemote;
+use crate::semaphore::Semaphore;
+use crate::write_buffer::WriteBuffer;
+use ndarray::{s, Array, Array3, ShapeBuilder};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upstream layer that serves a constant value everywhere.
+pub(crate) struct ConstantDataManager(pub u8);
+
+impl DataManager for ConstantDataManager {
+    fn get_data(
+        &self,
+        _uri: String,
+        _resolution: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Array3<u8> {
+        Array::from_elem(
+            (
+                (destination.z - origin.z) as usize,
+                (destination.y - origin.y) as usize,
+                (destination.x - origin.x) as usize,
+            ),
+            self.0,
+        )
+    }
+
+    fn put_data(&self, _uri: String, _resolution: u8, _origin: Vector3, _data: Array3<u8>) -> bool {
+        false
+    }
+}
+
+/// Upstream layer that's down.
+struct FailingDataManager;
+
+impl DataManager for FailingDataManager {
+    fn get_data(
+        &self,
+        _uri: String,
+        _resolution: u8,
+        _origin: Vector3,
+        _destination: Vector3,
+    ) -> Array3<u8> {
+        panic!("upstream is down")
+    }
+
+    fn try_get_data(
+        &self,
+        _uri: String,
+        _resolution: u8,
+        _origin: Vector3,
+        _destination: Vector3,
+    ) -> Result<Array3<u8>, UpstreamError> {
+        Err(UpstreamError::Failed("upstream is down".to_string()))
+    }
+
+    fn put_data(&self, _uri: String, _resolution: u8, _origin: Vector3, _data: Array3<u8>) -> bool {
+        false
+    }
+}
+
+/// Upstream layer where no channel exists, counting the requests for it.
+struct MissingDataManager {
+    calls: Arc<Mutex<usize>>,
+}
+
+impl DataManager for MissingDataManager {
+    fn get_data(
+        &self,
+        _uri: String,
+        _resolution: u8,
+        _origin: Vector3,
+        _destination: Vector3,
+    ) -> Array3<u8> {
+        panic!("no such channel")
+    }
+
+    fn try_get_data(
+        &self,
+        uri: String,
+        _resolution: u8,
+        _origin: Vector3,
+        _destination: Vector3,
+    ) -> Result<Array3<u8>, UpstreamError> {
+        *self.calls.lock().unwrap() += 1;
+        Err(UpstreamError::NotFound(uri))
+    }
+
+    fn put_data(&self, _uri: String, _resolution: u8, _origin: Vector3, _data: Array3<u8>) -> bool {
+        false
+    }
+}
+
+/// Upstream layer that records each batch of regions it's asked for.
+struct RecordingDataManager {
+    batches: Arc<Mutex<Vec<usize>>>,
+}
+
+impl DataManager for RecordingDataManager {
+    fn get_data(
+        &self,
+        _uri: String,
+        _resolution: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Array3<u8> {
+        ConstantDataManager(2).get_data(String::new(), 0, origin, destination)
+    }
+
+    fn try_get_many(
+        &self,
+        uri: String,
+        resolution: u8,
+        extents: Vec<(Vector3, Vector3)>,
+    ) -> Vec<Result<Array3<u8>, UpstreamError>> {
+        self.batches.lock().unwrap().push(extents.len());
+        extents
+            .into_iter()
+            .map(|(origin, destination)| {
+                Ok(self.get_data(uri.clone(), resolution, origin, destination))
+            })
+            .collect()
+    }
+
+    fn put_data(&self, _uri: String, _resolution: u8, _origin: Vector3, _data: Array3<u8>) -> bool {
+        false
+    }
+}
+
+fn cuboid_size() -> Vector3 {
+    Vector3 { x: 4, y: 4, z: 2 }
+}
+
+fn file_manager(dir: &tempfile::TempDir) -> ChunkedFileDataManager {
+    ChunkedFileDataManager::new(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        false,
+    )
+}
+
+#[test]
+fn test_fill_value_for_uncached_regions() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = file_manager(&dir);
+    let mut fill_values = FillValues::new(7);
+    fill_values.set_channel("col/exp/marked", 255);
+    fm.set_fill_values(fill_values);
+
+    // Cache the first of two cuboids along x:
+    let cached = Array::from_elem((2, 4, 4), 1);
+    assert!(fm.put_data(
+        "bossdb://col/exp/chan".to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        cached
+    ));
+
+    let data = fm.get_data(
+        "bossdb://col/exp/chan".to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Vector3 { x: 8, y: 4, z: 2 },
+    );
+    assert!(data.slice(s![.., .., ..4]).iter().all(|v| *v == 1));
+    assert!(data.slice(s![.., .., 4..]).iter().all(|v| *v == 7));
+
+    let data = fm.get_data(
+        "bossdb://col/exp/marked".to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Vector3 { x: 8, y: 4, z: 2 },
+    );
+    assert!(data.iter().all(|v| *v == 255));
+}
+
+#[test]
+fn test_miss_without_next_layer() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = file_manager(&dir);
+    let uri = "bossdb://col/exp/chan".to_string();
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 8, y: 4, z: 2 };
+    assert!(fm.put_data(uri.clone(), 0, origin, Array::from_elem((2, 4, 4), 1)));
+
+    // By default the uncached cuboid is filled:
+    let cutout = fm.get_cutout(uri.clone(), 0, origin, destination);
+    assert!(!cutout.missing);
+    assert!(!cutout.cache_hit);
+    assert_eq!(1, cutout.sources.filled);
+    assert!(cutout.data.slice(s![.., .., 4..]).iter().all(|v| *v == 0));
+
+    // Or reported:
+    fm.set_on_miss(MissPolicy::NotFound);
+    assert!(fm.get_cutout(uri.clone(), 0, origin, destination).missing);
+    assert!(!fm.get_cutout(uri, 0, origin, cuboid_size()).missing);
+}
+
+#[test]
+fn test_empty_cuboid_file_is_refetched() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(9)),
+        false,
+    );
+
+    // Left behind by a crash between creating and writing the cuboid:
+    let cuboid_dir = dir.path().join("col/exp/chan/0");
+    fs::create_dir_all(&cuboid_dir).unwrap();
+    let cuboid_file = cuboid_dir.join("x0_y0_z0");
+    fs::write(&cuboid_file, b"").unwrap();
+
+    let data = fm.get_data(
+        "bossdb://col/exp/chan".to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        cuboid_size(),
+    );
+    assert!(data.iter().all(|v| *v == 9));
+    let written = fs::read(&cuboid_file).unwrap();
+    assert_eq!(32, voxels(&written, cuboid_size()).unwrap().len());
+}
+
+#[test]
+fn test_put_data_over_partial_cuboid_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = file_manager(&dir);
+
+    let cuboid_dir = dir.path().join("col/exp/chan/0");
+    fs::create_dir_all(&cuboid_dir).unwrap();
+    let cuboid_file = cuboid_dir.join("x0_y0_z0");
+    fs::write(&cuboid_file, [5; 10]).unwrap();
+
+    // Used to panic trying to reshape the partial file:
+    assert!(fm.put_data(
+        "bossdb://col/exp/chan".to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Array::from_elem((2, 4, 4), 3),
+    ));
+    let written = fs::read(&cuboid_file).unwrap();
+    assert_eq!(&[3; 32][..], voxels(&written, cuboid_size()).unwrap());
+}
+
+#[test]
+fn test_put_data_whole_and_partial_cuboids() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = file_manager(&dir);
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 8, y: 4, z: 2 };
+    fm.put_data(uri.to_string(), 0, origin, Array::from_elem((2, 4, 8), 5));
+
+    // Covers part of the first cuboid and all of the second:
+    let numbered = Array::from_shape_fn((2, 4, 6), |(z, y, x)| (100 * z + 10 * y + x) as u8);
+    fm.put_data(
+        uri.to_string(),
+        0,
+        Vector3 { x: 2, y: 0, z: 0 },
+        numbered.clone(),
+    );
+
+    let data = fm.get_data(uri.to_string(), 0, origin, destination);
+    assert!(data.slice(s![.., .., ..2]).iter().all(|v| *v == 5));
+    assert_eq!(numbered, data.slice(s![.., .., 2..]));
+
+    // A whole cuboid taken from a transposed array keeps its voxel order:
+    let transposed = numbered.slice(s![.., .., 2..]).to_owned();
+    let mut fortran = Array::zeros((2, 4, 4).f());
+    fortran.assign(&transposed);
+    fm.put_data(uri.to_string(), 0, Vector3 { x: 4, y: 0, z: 0 }, fortran);
+    let data = fm.get_data(
+        uri.to_string(),
+        0,
+        Vector3 { x: 4, y: 0, z: 0 },
+        destination,
+    );
+    assert_eq!(transposed, data);
+}
+
+#[test]
+fn test_reads_legacy_and_versioned_cuboids() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = file_manager(&dir);
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+
+    fm.set_format_version(LEGACY_VERSION);
+    fm.put_data(uri.to_string(), 0, origin, Array::from_elem((2, 4, 4), 4));
+    let cuboid_file = dir.path().join("col/exp/chan/0/x0_y0_z0");
+    assert_eq!(32, fs::read(&cuboid_file).unwrap().len());
+
+    fm.set_format_version(CURRENT_VERSION);
+    fm.put_data(
+        uri.to_string(),
+        0,
+        Vector3 { x: 4, y: 0, z: 0 },
+        Array::from_elem((2, 4, 4), 5),
+    );
+
+    for use_mmap in &[false, true] {
+        fm.set_use_mmap(*use_mmap);
+        let data = fm.get_data(uri.to_string(), 0, origin, Vector3 { x: 8, y: 4, z: 2 });
+        assert!(data.slice(s![.., .., ..4]).iter().all(|v| *v == 4));
+        assert!(data.slice(s![.., .., 4..]).iter().all(|v| *v == 5));
+    }
+}
+
+#[test]
+fn test_reads_compressed_and_uncompressed_cuboids() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = file_manager(&dir);
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+
+    fm.set_format_version(LEGACY_VERSION);
+    fm.put_data(uri.to_string(), 0, origin, Array::from_elem((2, 4, 4), 4));
+    let compressed: Vec<u8> = blosc::Context::new().compress(&[5u8; 32][..]).into();
+    fs::write(dir.path().join("col/exp/chan/0/x1_y0_z0"), compressed).unwrap();
+
+    assert!(fm.has_data(uri.to_string(), 0, origin, Vector3 { x: 8, y: 4, z: 2 }));
+    for use_mmap in &[false, true] {
+        fm.set_use_mmap(*use_mmap);
+        let data = fm.get_data(uri.to_string(), 0, origin, Vector3 { x: 8, y: 4, z: 2 });
+        assert!(data.slice(s![.., .., ..4]).iter().all(|v| *v == 4));
+        assert!(data.slice(s![.., .., 4..]).iter().all(|v| *v == 5));
+    }
+}
+
+#[test]
+fn test_cache_coverage() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = file_manager(&dir);
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 8, y: 4, z: 2 };
+
+    assert_eq!(0.0, fm.cache_coverage(uri, 0, origin, destination));
+
+    fm.put_data(uri.to_string(), 0, origin, Array::from_elem((2, 4, 4), 1));
+    assert_eq!(0.5, fm.cache_coverage(uri, 0, origin, destination));
+    assert!(!fm.has_data(uri.to_string(), 0, origin, destination));
+
+    fm.put_data(uri.to_string(), 0, origin, Array::from_elem((2, 4, 8), 1));
+    assert_eq!(1.0, fm.cache_coverage(uri, 0, origin, destination));
+    assert!(fm.has_data(uri.to_string(), 0, origin, destination));
+}
+
+#[test]
+fn test_count_cuboids() {
+    let size = Vector3 { x: 4, y: 4, z: 2 };
+    let extents = [
+        (Vector3 { x: 0, y: 0, z: 0 }, Vector3 { x: 8, y: 8, z: 2 }),
+        (Vector3 { x: 1, y: 2, z: 1 }, Vector3 { x: 13, y: 8, z: 6 }),
+        (Vector3 { x: 4, y: 4, z: 2 }, Vector3 { x: 5, y: 5, z: 3 }),
+        (Vector3 { x: 4, y: 4, z: 2 }, Vector3 { x: 4, y: 8, z: 4 }),
+    ];
+    for &(start, stop) in &extents {
+        assert_eq!(
+            get_cuboids_and_indices(start, stop, size).len() as u64,
+            count_cuboids(start, stop, size)
+        );
+    }
+    // Without building a map of them all:
+    let huge = Vector3 {
+        x: u64::MAX,
+        y: u64::MAX,
+        z: u64::MAX,
+    };
+    assert_eq!(
+        u64::MAX,
+        count_cuboids(Vector3 { x: 0, y: 0, z: 0 }, huge, size)
+    );
+}
+
+#[test]
+fn test_cuboid_coverage() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = file_manager(&dir);
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 2, y: 0, z: 0 };
+    let destination = Vector3 { x: 8, y: 8, z: 2 };
+    let cuboid = |x, y, cached| CuboidCoverage {
+        index: Vector3 { x, y, z: 0 },
+        cached,
+    };
+
+    assert_eq!(
+        vec![
+            cuboid(0, 0, false),
+            cuboid(1, 0, false),
+            cuboid(0, 1, false),
+            cuboid(1, 1, false)
+        ],
+        fm.cuboid_coverage(uri, 0, origin, destination)
+    );
+
+    fm.put_data(
+        uri.to_string(),
+        0,
+        Vector3 { x: 4, y: 0, z: 0 },
+        Array::from_elem((2, 4, 4), 1),
+    );
+    assert_eq!(
+        vec![
+            cuboid(0, 0, false),
+            cuboid(1, 0, true),
+            cuboid(0, 1, false),
+            cuboid(1, 1, false)
+        ],
+        fm.cuboid_coverage(uri, 0, origin, destination)
+    );
+    assert!(fm
+        .cuboid_coverage(uri, 1, origin, destination)
+        .iter()
+        .all(|c| !c.cached));
+}
+
+#[test]
+fn test_diff_cutout_against_upstream() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(7)),
+        false,
+    );
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 8, y: 4, z: 2 };
+
+    // Fetched from upstream, so the cache matches it:
+    fm.get_data(uri.to_string(), 0, origin, destination);
+    let diff = fm
+        .diff_cutout(uri, 0, origin, destination)
+        .unwrap()
+        .unwrap();
+    assert!(diff.identical);
+    assert_eq!(
+        (64, 0, None),
+        (diff.voxels, diff.differing, diff.first_difference)
+    );
+    assert_eq!(100.0, diff.match_percent);
+
+    // A stale cuboid:
+    let mut stale = Array::from_elem((2, 4, 4), 7);
+    stale[[1, 2, 3]] = 0;
+    stale[[1, 3, 3]] = 0;
+    fm.put_data(uri.to_string(), 0, Vector3 { x: 4, y: 0, z: 0 }, stale);
+    let diff = fm
+        .diff_cutout(uri, 0, origin, destination)
+        .unwrap()
+        .unwrap();
+    assert!(!diff.identical);
+    assert_eq!(2, diff.differing);
+    assert_eq!(Some(Coords { x: 7, y: 2, z: 1 }), diff.first_difference);
+    assert_eq!(100.0 * 62.0 / 64.0, diff.match_percent);
+
+    // Even once it's expired, it's compared rather than fetched again:
+    fm.set_max_age(Some(Duration::from_secs(0)));
+    let diff = fm
+        .diff_cutout(uri, 0, origin, destination)
+        .unwrap()
+        .unwrap();
+    assert_eq!(2, diff.differing);
+
+    // Reported in the global frame:
+    fm.set_frame(Coords { x: -4, y: 0, z: 10 });
+    let diff = fm
+        .diff_cutout(uri, 0, origin, destination)
+        .unwrap()
+        .unwrap();
+    assert_eq!(Some(Coords { x: 3, y: 2, z: 11 }), diff.first_difference);
+
+    // Nothing to compare unless it's all cached:
+    let destination = Vector3 { x: 12, y: 4, z: 2 };
+    assert_eq!(Ok(None), fm.diff_cutout(uri, 0, origin, destination));
+}
+
+#[test]
+fn test_cutout_sources() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(7)),
+        false,
+    );
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+
+    // One cuboid cached, and its neighbour not:
+    fm.get_data(uri.to_string(), 0, origin, cuboid_size());
+    let cutout = fm.get_cutout(uri.to_string(), 0, origin, Vector3 { x: 8, y: 4, z: 2 });
+    assert_eq!(
+        CuboidSources {
+            cache: 1,
+            upstream: 1,
+            ..CuboidSources::default()
+        },
+        cutout.sources
+    );
+    assert_eq!(0.5, cutout.sources.cache_hit_ratio());
+    assert_eq!("cache=1, upstream=1", cutout.sources.summary());
+}
+
+#[test]
+fn test_downsample_on_write() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(9)),
+        false,
+    );
+    fm.set_downsample_on_write(1);
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+
+    let upload = Array::from_shape_fn((2, 8, 8), |(z, y, x)| (z * 64 + y * 8 + x) as u8);
+    fm.try_upload(uri, 0, origin, upload.clone()).unwrap();
+
+    // Served from the cache rather than upstream:
+    let cutout = fm.get_cutout(uri.to_string(), 1, origin, cuboid_size());
+    assert_eq!(
+        downsample::halve_xy(upload.view(), Downsampling::Mean),
+        cutout.data
+    );
+    assert!(cutout.cache_hit);
+}
+
+#[test]
+fn test_downsample_on_write_reads_only_the_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(FailingDataManager),
+        false,
+    );
+    fm.set_downsample_on_write(1);
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+
+    // One of the four cuboids below the coarser one:
+    fm.try_upload(uri, 0, origin, Array::from_elem((2, 4, 4), 1))
+        .unwrap();
+    assert!(!Path::new(&fm.cuboid_filename(uri, 1, &origin)).exists());
+
+    // All four:
+    fm.try_upload(uri, 0, origin, Array::from_elem((2, 8, 8), 1))
+        .unwrap();
+    assert!(Path::new(&fm.cuboid_filename(uri, 1, &origin)).exists());
+
+    // Nothing is coarser than the last level, rather than wrapping around
+    // to the first:
+    let top = "bossdb://col/exp/top";
+    fm.try_upload(top, 255, origin, Array::from_elem((2, 8, 8), 1))
+        .unwrap();
+    assert!(Path::new(&fm.cuboid_filename(top, 255, &origin)).exists());
+    assert!(!Path::new(&fm.cuboid_filename(top, 0, &origin)).exists());
+}
+
+#[test]
+fn test_failed_cuboid_doesnt_stop_upload() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = file_manager(&dir);
+    let uri = "bossdb://col/exp/chan";
+    // A directory where the first cuboid's file would go:
+    let blocked = fm.cuboid_filename(uri, 0, &Vector3 { x: 0, y: 0, z: 0 });
+    fs::create_dir_all(format!("{}/x", blocked)).unwrap();
+
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    match fm.try_upload(uri, 0, origin, Array::from_elem((2, 4, 12), 3)) {
+        Err(UploadError::Io {
+            filename, failed, ..
+        }) => {
+            assert_eq!(blocked, filename);
+            assert_eq!(1, failed);
+        }
+        result => panic!("Expected a failed write, got {:?}", result),
+    }
+    // The cuboids after it were written anyway:
+    let destination = Vector3 { x: 12, y: 4, z: 2 };
+    let data = fm.get_data(
+        uri.to_string(),
+        0,
+        Vector3 { x: 4, y: 0, z: 0 },
+        destination,
+    );
+    assert!(data.iter().all(|v| *v == 3));
+}
+
+#[test]
+fn test_scratch_writes_shadow_upstream() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(7)),
+        false,
+    );
+    // The upstream layer rejects writes, so writing through would fail:
+    fm.set_write_through(true);
+    fm.set_scratch(true);
+    fm.set_max_age(Some(Duration::from_secs(0)));
+    let uri = "bossdb://col/exp/chan";
+
+    // Parts of two cuboids, neither of them cached:
+    let summary = fm
+        .try_upload(
+            uri,
+            0,
+            Vector3 { x: 2, y: 2, z: 0 },
+            Array::from_elem((2, 2, 6), 1),
+        )
+        .unwrap();
+    assert_eq!(2, summary.cuboids);
+
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 8, y: 4, z: 2 };
+    for _ in 0..2 {
+        let data = fm.get_data(uri.to_string(), 0, origin, destination);
+        let mut expected = Array::from_elem((2, 4, 8), 7);
+        expected.slice_mut(s![.., 2..4, 2..8]).fill(1);
+        assert_eq!(expected, data);
+    }
+}
+
+#[test]
+fn test_scratch_write_fails_without_upstream_copy() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(FailingDataManager),
+        false,
+    );
+    fm.set_scratch(true);
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 2, y: 0, z: 0 };
+    match fm.try_upload(uri, 0, origin, Array::from_elem((2, 4, 2), 1)) {
+        Err(UploadError::Io { failed: 1, .. }) => (),
+        result => panic!("Expected a failed write, got {:?}", result),
+    }
+    // Rather than zeros under the upload:
+    let cuboid = Vector3 { x: 0, y: 0, z: 0 };
+    assert!(!Path::new(&fm.cuboid_filename(uri, 0, &cuboid)).exists());
+}
+
+#[test]
+fn test_scratch_channels_are_pinned() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(7)),
+        false,
+    );
+    let db_url = dir.path().join("cache.db").to_str().unwrap().to_string();
+    let pool = Arc::new(ConnectionPool::new(&db_url, 1).unwrap());
+    let pinned = PinnedChannels::default();
+    fm.set_scratch_channels(Arc::new(ScratchChannels::new(pool, pinned.clone())));
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let upload = Array::from_elem((2, 4, 4), 1);
+
+    // Only scratch uploads pin their channel:
+    fm.try_upload("bossdb://col/exp/kept", 0, origin, upload.clone())
+        .unwrap();
+    assert!(pinned.prefixes().is_empty());
+    fm.set_scratch(true);
+    fm.try_upload("bossdb://col/exp/scratch", 0, origin, upload)
+        .unwrap();
+    assert_eq!(vec!["col/exp/scratch".to_string()], pinned.prefixes());
+}
+
+#[test]
+fn test_diff_cutout_with_upstream_down() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = failing_upstream_manager(&dir);
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let cuboid = Array::from_elem((2, 4, 4), 7);
+    fm.put_data("bossdb://col/exp/chan".to_string(), 0, origin, cuboid);
+    assert_eq!(
+        Err(UpstreamError::Failed("upstream is down".to_string())),
+        fm.diff_cutout("bossdb://col/exp/chan", 0, origin, cuboid_size())
+    );
+
+    // Nor is there anything to compare against without a next layer:
+    let standalone = ChunkedFileDataManager::new(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        false,
+    );
+    assert!(standalone
+        .diff_cutout("bossdb://col/exp/chan", 0, origin, cuboid_size())
+        .is_err());
+}
+
+fn failing_upstream_manager(dir: &tempfile::TempDir) -> ChunkedFileDataManager {
+    let fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(FailingDataManager),
+        false,
+    );
+    fm.put_data(
+        "bossdb://col/exp/chan".to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Array::from_elem((2, 4, 4), 1),
+    );
+    fm
+}
+
+#[test]
+fn test_serve_partial_on_upstream_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = failing_upstream_manager(&dir);
+    fm.set_on_upstream_error(UpstreamErrorPolicy::ServePartial);
+    fm.set_fill_values(FillValues::new(7));
+
+    let cutout = fm.get_cutout(
+        "bossdb://col/exp/chan".to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Vector3 { x: 8, y: 4, z: 2 },
+    );
+    assert!(cutout.partial);
+    assert!(cutout.data.slice(s![.., .., ..4]).iter().all(|v| *v == 1));
+    assert!(cutout.data.slice(s![.., .., 4..]).iter().all(|v| *v == 7));
+
+    // The failed cuboid mustn't be cached:
+    assert!(!fm.has_cuboid("bossdb://col/exp/chan", 0, &Vector3 { x: 1, y: 0, z: 0 }));
+}
+
+#[test]
+fn test_expired_cuboid_is_refetched() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(2)),
+        false,
+    );
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    // Cached before upstream changed to 2s:
+    fm.put_data(uri.to_string(), 0, origin, Array::from_elem((2, 4, 4), 1));
+
+    // Fresh, so served from the cache:
+    fm.set_max_age(Some(Duration::from_secs(3600)));
+    let cutout = fm.get_cutout(uri.to_string(), 0, origin, cuboid_size());
+    assert!(cutout.cache_hit);
+    assert!(cutout.data.iter().all(|v| *v == 1));
+
+    // Expired, so revalidated, and the new data cached:
+    fm.set_max_age(Some(Duration::from_millis(1)));
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(None, fm.cutout_etag(uri, 0, origin, cuboid_size(), "blosc"));
+    let cutout = fm.get_cutout(uri.to_string(), 0, origin, cuboid_size());
+    assert!(!cutout.cache_hit);
+    assert!(cutout.data.iter().all(|v| *v == 2));
+    fm.set_max_age(None);
+    let cutout = fm.get_cutout(uri.to_string(), 0, origin, cuboid_size());
+    assert!(cutout.cache_hit);
+    assert!(cutout.data.iter().all(|v| *v == 2));
+}
+
+#[test]
+fn test_expired_cuboid_served_when_upstream_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = failing_upstream_manager(&dir);
+    fm.set_max_age(Some(Duration::from_millis(1)));
+    std::thread::sleep(Duration::from_millis(20));
+
+    // Even when failing on upstream errors:
+    let cutout = fm.get_cutout(
+        "bossdb://col/exp/chan".to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        cuboid_size(),
+    );
+    assert!(!cutout.partial);
+    assert!(cutout.data.iter().all(|v| *v == 1));
+}
+
+#[test]
+#[should_panic(expected = "upstream is down")]
+fn test_fail_on_upstream_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = failing_upstream_manager(&dir);
+    fm.get_cutout(
+        "bossdb://col/exp/chan".to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Vector3 { x: 8, y: 4, z: 2 },
+    );
+}
+
+#[test]
+fn test_misses_are_fetched_together() {
+    let dir = tempfile::tempdir().unwrap();
+    let batches = Arc::new(Mutex::new(Vec::new()));
+    let fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(RecordingDataManager {
+            batches: Arc::clone(&batches),
+        }),
+        false,
+    );
+    let uri = "bossdb://col/exp/chan";
+    fm.put_data(
+        uri.to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Array::from_elem((2, 4, 4), 1),
+    );
+
+    let data = fm.get_data(
+        uri.to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Vector3 { x: 12, y: 8, z: 2 },
+    );
+    assert_eq!(vec![5], *batches.lock().unwrap());
+    assert!(data.slice(s![.., ..4, ..4]).iter().all(|v| *v == 1));
+    assert!(data.slice(s![.., 4.., ..]).iter().all(|v| *v == 2));
+
+    // Everything is cached now:
+    fm.get_data(
+        uri.to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Vector3 { x: 12, y: 8, z: 2 },
+    );
+    assert_eq!(vec![5], *batches.lock().unwrap());
+}
+
+#[test]
+fn test_cuboid_size_override_passes_through() {
+    let dir = tempfile::tempdir().unwrap();
+    let batches = Arc::new(Mutex::new(Vec::new()));
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(RecordingDataManager {
+            batches: Arc::clone(&batches),
+        }),
+        false,
+    );
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 16, y: 8, z: 2 };
+    fm.put_data(uri.to_string(), 0, origin, Array::from_elem((2, 4, 4), 1));
+
+    fm.set_cuboid_size_override(Vector3 { x: 8, y: 8, z: 2 });
+    assert_eq!(None, fm.cutout_etag(uri, 0, origin, destination, "blosc"));
+    let cutout = fm.get_cutout(uri.to_string(), 0, origin, destination);
+    assert!(!cutout.cache_hit);
+    // Two 8x8x2 cuboids, with the cached 4x4x2 one ignored:
+    assert_eq!(vec![2], *batches.lock().unwrap());
+    assert!(cutout.data.iter().all(|v| *v == 2));
+
+    // Nothing was cached:
+    let cached: Vec<_> = fs::read_dir(dir.path().join("col/exp/chan/0"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(vec!["x0_y0_z0"], cached);
+}
+
+#[test]
+fn test_warm_fetches_only_missing_cuboids() {
+    let dir = tempfile::tempdir().unwrap();
+    let batches = Arc::new(Mutex::new(Vec::new()));
+    let fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(RecordingDataManager {
+            batches: Arc::clone(&batches),
+        }),
+        false,
+    );
+    let uri = "bossdb://col/exp/chan";
+    fm.put_data(
+        uri.to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Array::from_elem((2, 4, 4), 1),
+    );
+
+    // Partly covers the cuboids at x = 0..3:
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 10, y: 4, z: 2 };
+    assert_eq!(2, fm.warm(uri, 0, origin, destination));
+    assert_eq!(vec![2], *batches.lock().unwrap());
+    assert_eq!(
+        1.0,
+        fm.cache_coverage(uri, 0, origin, Vector3 { x: 12, y: 4, z: 2 })
+    );
+
+    // The cached cuboid wasn't overwritten:
+    let data = fm.get_data(uri.to_string(), 0, origin, Vector3 { x: 4, y: 4, z: 2 });
+    assert!(data.iter().all(|v| *v == 1));
+
+    assert_eq!(0, fm.warm(uri, 0, origin, destination));
+    assert_eq!(vec![2], *batches.lock().unwrap());
+}
+
+#[test]
+fn test_python_layout() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = file_manager(&dir);
+    fm.set_layout(Layout::Python);
+    let uri = "bossdb://col/exp/chan";
+
+    // Seeded by the Python tooling:
+    let res_dir = dir.path().join("col/exp/chan/0");
+    fs::create_dir_all(&res_dir).unwrap();
+    fs::write(
+        res_dir.join("0-4_0-4_0-2.npy"),
+        npy::encode(cuboid_size(), &[6; 32]),
+    )
+    .unwrap();
+
+    fm.put_data(
+        uri.to_string(),
+        0,
+        Vector3 { x: 4, y: 0, z: 0 },
+        Array::from_elem((2, 4, 4), 7),
+    );
+    let written = fs::read(res_dir.join("4-8_0-4_0-2.npy")).unwrap();
+    assert_eq!(Some(&[7; 32][..]), voxels(&written, cuboid_size()));
+
+    for use_mmap in &[false, true] {
+        fm.set_use_mmap(*use_mmap);
+        let data = fm.get_data(
+            uri.to_string(),
+            0,
+            Vector3 { x: 0, y: 0, z: 0 },
+            Vector3 { x: 8, y: 4, z: 2 },
+        );
+        assert!(data.slice(s![.., .., ..4]).iter().all(|v| *v == 6));
+        assert!(data.slice(s![.., .., 4..]).iter().all(|v| *v == 7));
+    }
+    assert!(fm.has_data(
+        uri.to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Vector3 { x: 8, y: 4, z: 2 },
+    ));
+
+    // The native layout doesn't see them:
+    fm.set_layout(Layout::Native);
+    assert_eq!(
+        0.0,
+        fm.cache_coverage(
+            uri,
+            0,
+            Vector3 { x: 0, y: 0, z: 0 },
+            Vector3 { x: 8, y: 4, z: 2 }
+        )
+    );
+}
+
+#[test]
+fn test_resolution_roots() {
+    let dir = tempfile::tempdir().unwrap();
+    let res1_dir = tempfile::tempdir().unwrap();
+    let mut fm = file_manager(&dir);
+    let mut roots = HashMap::new();
+    roots.insert(1, res1_dir.path().to_str().unwrap().to_string());
+    fm.set_resolution_roots(roots);
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+
+    for res in 0..2 {
+        fm.put_data(
+            uri.to_string(),
+            res,
+            origin,
+            Array::from_elem((2, 4, 4), res),
+        );
+    }
+    assert!(dir.path().join("col/exp/chan/0/x0_y0_z0").exists());
+    assert!(!dir.path().join("col/exp/chan/1").exists());
+    assert!(res1_dir.path().join("col/exp/chan/1/x0_y0_z0").exists());
+    assert!(!res1_dir.path().join("col/exp/chan/0").exists());
+
+    for res in 0..2 {
+        let data = fm.get_data(uri.to_string(), res, origin, cuboid_size());
+        assert!(data.iter().all(|v| *v == res));
+    }
+}
+
+#[test]
+fn test_hashed_cache_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = dir.path().join("cache.db").to_str().unwrap().to_string();
+    let pool = Arc::new(ConnectionPool::new(&db_url, 1).unwrap());
+    let keys = Arc::new(ChannelKeys::new(pool, "salt"));
+    let mut fm = file_manager(&dir);
+    fm.set_channel_keys(Arc::clone(&keys));
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+
+    let data = Array::from_shape_fn((2, 4, 4), |(z, y, x)| (x + y * 4 + z * 16) as u8);
+    assert!(fm.put_data(uri.to_string(), 0, origin, data.clone()));
+    assert_eq!(data, fm.get_data(uri.to_string(), 0, origin, cuboid_size()));
+
+    // Only the hash is on disk, and it maps back to the channel:
+    let hash = keys.key("col/exp/chan");
+    assert!(dir.path().join(&hash).join("0/x0_y0_z0").exists());
+    assert!(!dir.path().join("col").exists());
+    assert_eq!(Some("col/exp/chan".to_string()), keys.channel(&hash));
+    assert_eq!(1, fm.cached_resolutions(uri)[0].cuboids);
+}
+
+#[test]
+fn test_cached_resolutions() {
+    let dir = tempfile::tempdir().unwrap();
+    let res2_dir = tempfile::tempdir().unwrap();
+    let mut fm = file_manager(&dir);
+    let mut roots = HashMap::new();
+    roots.insert(2, res2_dir.path().to_str().unwrap().to_string());
+    fm.set_resolution_roots(roots);
+    let uri = "bossdb://col/exp/chan";
+    assert_eq!(Vec::<CachedResolution>::new(), fm.cached_resolutions(uri));
+
+    // Two cuboids at 0, and one at 2, in its own root:
+    let data = Array::from_elem((2, 4, 8), 1);
+    fm.put_data(uri.to_string(), 0, Vector3 { x: 0, y: 0, z: 0 }, data);
+    let data = Array::from_elem((2, 4, 4), 1);
+    fm.put_data(uri.to_string(), 2, Vector3 { x: 0, y: 0, z: 0 }, data);
+    // Not cuboids, or not resolutions:
+    fs::create_dir_all(dir.path().join("col/exp/chan/1")).unwrap();
+    fs::create_dir_all(dir.path().join("col/exp/chan/thumbnails")).unwrap();
+    fs::write(dir.path().join("col/exp/chan/0/x2_y0_z0.1-0.tmp"), b"").unwrap();
+    // Another channel:
+    fm.put_data(
+        "bossdb://col/exp/other".to_string(),
+        3,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Array::from_elem((2, 4, 4), 1),
+    );
+
+    assert_eq!(
+        vec![
+            CachedResolution { res: 0, cuboids: 2 },
+            CachedResolution { res: 2, cuboids: 1 },
+        ],
+        fm.cached_resolutions(uri)
+    );
+}
+
+#[test]
+fn test_downsample_status_of_cached_levels() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = file_manager(&dir);
+    let uri = "bossdb://col/exp/chan";
+    let status = || DownsampleStatus::of(&fm.cached_resolutions(uri), 0);
+    let cache_level = |res: u8| {
+        let data = Array::from_elem((2, 4, 4), 1);
+        fm.put_data(uri.to_string(), res, Vector3 { x: 0, y: 0, z: 0 }, data);
+    };
+
+    assert_eq!(DownsampleStatus::NotDownsampled, status());
+    cache_level(0);
+    assert_eq!(DownsampleStatus::NotDownsampled, status());
+    // A gap at 1:
+    cache_level(2);
+    assert_eq!(DownsampleStatus::InProgress, status());
+    cache_level(1);
+    assert_eq!(DownsampleStatus::Downsampled, status());
+    assert_eq!("DOWNSAMPLED", status().as_str());
+}
+
+#[test]
+fn test_downsample_status_without_base() {
+    let levels = |levels: &[u8]| -> Vec<CachedResolution> {
+        levels
+            .iter()
+            .map(|res| CachedResolution {
+                res: *res,
+                cuboids: 1,
+            })
+            .collect()
+    };
+    assert_eq!(
+        DownsampleStatus::InProgress,
+        DownsampleStatus::of(&levels(&[1, 2]), 0)
+    );
+    // Relative to the base resolution:
+    assert_eq!(
+        DownsampleStatus::Downsampled,
+        DownsampleStatus::of(&levels(&[0, 1, 2]), 1)
+    );
+    assert_eq!(
+        DownsampleStatus::NotDownsampled,
+        DownsampleStatus::of(&levels(&[0, 1]), 1)
+    );
+}
+
+/// Upstream layer that serves every resolution of a pyramid, downsampling
+/// each level from the one before it, and records the resolutions it's
+/// asked for.
+struct PyramidDataManager {
+    method: Downsampling,
+    requests: Arc<Mutex<Vec<u8>>>,
+}
+
+impl PyramidDataManager {
+    /// Reference downsample, one voxel at a time.
+    fn value(&self, res: u8, x: u64, y: u64, z: u64) -> u8 {
+        if res == 0 {
+            return match self.method {
+                Downsampling::Mean => ((x * 7 + y * 13 + z * 29) % 256) as u8,
+                Downsampling::Mode => ((x * y + z) % 3) as u8,
+            };
+        }
+        let block: Vec<u8> = [(0, 0), (1, 0), (0, 1), (1, 1)]
+            .iter()
+            .map(|(dx, dy)| self.value(res - 1, 2 * x + dx, 2 * y + dy, z))
+            .collect();
+        match self.method {
+            Downsampling::Mean => {
+                let sum: u32 = block.iter().map(|v| *v as u32).sum();
+                ((sum as f64) / 4.0).round() as u8
+            }
+            Downsampling::Mode => {
+                let count = |v: u8| block.iter().filter(|b| **b == v).count();
+                *block
+                    .iter()
+                    .min_by_key(|v| (std::cmp::Reverse(count(**v)), **v))
+                    .unwrap()
+            }
+        }
+    }
+}
+
+impl DataManager for PyramidDataManager {
+    fn get_data(
+        &self,
+        _uri: String,
+        resolution: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Array3<u8> {
+        self.requests.lock().unwrap().push(resolution);
+        let shape = Vector3::checked_shape(origin, destination).unwrap();
+        Array3::from_shape_fn(shape.to_zyx_shape(), |(z, y, x)| {
+            self.value(
+                resolution,
+                origin.x + x as u64,
+                origin.y + y as u64,
+                origin.z + z as u64,
+            )
+        })
+    }
+
+    fn put_data(&self, _uri: String, _resolution: u8, _origin: Vector3, _data: Array3<u8>) -> bool {
+        false
+    }
+}
+
+#[test]
+fn test_synthesized_resolutions_match_upstream() {
+    let uri = "bossdb://col/exp/chan";
+    for method in &[Downsampling::Mean, Downsampling::Mode] {
+        let dir = tempfile::tempdir().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let upstream = PyramidDataManager {
+            method: *method,
+            requests: Arc::clone(&requests),
+        };
+        let mut fm = ChunkedFileDataManager::new_with_layer(
+            dir.path().to_str().unwrap().to_string(),
+            cuboid_size(),
+            Box::new(PyramidDataManager {
+                method: *method,
+                requests: Arc::clone(&requests),
+            }),
+            false,
+        );
+        fm.set_synthesis(SynthesisMethods::new(Some(*method)));
+
+        // Cache 4x4 cuboids of res 0:
+        let origin = Vector3 { x: 0, y: 0, z: 0 };
+        fm.get_data(uri.to_string(), 0, origin, Vector3 { x: 16, y: 16, z: 2 });
+        requests.lock().unwrap().clear();
+
+        for (res, destination) in &[
+            (1, Vector3 { x: 8, y: 8, z: 2 }),
+            (2, Vector3 { x: 4, y: 4, z: 2 }),
+        ] {
+            let synthesized = fm.get_cutout(uri.to_string(), *res, origin, *destination);
+            assert!(synthesized.cache_hit);
+            assert!(requests.lock().unwrap().is_empty());
+            assert_eq!(
+                upstream.get_data(uri.to_string(), *res, origin, *destination),
+                synthesized.data
+            );
+            requests.lock().unwrap().clear();
+        }
+
+        // Nothing cached to synthesize from, so fetched:
+        fm.get_data(
+            uri.to_string(),
+            1,
+            Vector3 { x: 8, y: 0, z: 0 },
+            Vector3 { x: 12, y: 4, z: 2 },
+        );
+        assert_eq!(vec![1], *requests.lock().unwrap());
+    }
+}
+
+#[test]
+fn test_synthesis_off_for_channel() {
+    let dir = tempfile::tempdir().unwrap();
+    let batches = Arc::new(Mutex::new(Vec::new()));
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(RecordingDataManager {
+            batches: Arc::clone(&batches),
+        }),
+        false,
+    );
+    let mut synthesis = SynthesisMethods::new(Some(Downsampling::Mean));
+    synthesis.set_channel("col/exp/chan", None);
+    fm.set_synthesis(synthesis);
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+
+    fm.get_data(uri.to_string(), 0, origin, Vector3 { x: 8, y: 8, z: 2 });
+    fm.get_data(uri.to_string(), 1, origin, cuboid_size());
+    assert_eq!(vec![4, 1], *batches.lock().unwrap());
+}
+
+#[test]
+fn test_describe_chain() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_str().unwrap().to_string();
+    let kinds = |fm: &ChunkedFileDataManager| -> Vec<&str> {
+        describe_chain(fm).iter().map(|layer| layer.kind).collect()
+    };
+    assert_eq!(vec!["file", "null"], kinds(&file_manager(&dir)));
+
+    let mut relay = BossDBRelayDataManager::new(
+        "https".to_string(),
+        "prod.example.com".to_string(),
+        "secret".to_string(),
+    );
+    relay.set_write_target("staging.example.com".to_string(), "public".to_string());
+    let mut fm =
+        ChunkedFileDataManager::new_with_layer(root.clone(), cuboid_size(), Box::new(relay), false);
+    let mut roots = HashMap::new();
+    roots.insert(1, "/mnt/ssd".to_string());
+    roots.insert(0, "/mnt/big".to_string());
+    fm.set_resolution_roots(roots);
+    fm.set_layout(Layout::Python);
+    assert_eq!(vec!["file", "bossdb", "null"], kinds(&fm));
+
+    let chain = describe_chain(&fm);
+    let file = &chain[0].settings;
+    assert_eq!(root, file["root"]);
+    assert_eq!("0=/mnt/big,1=/mnt/ssd", file["resolution_roots"]);
+    assert_eq!("4:4:2", file["cuboid_size"]);
+    assert_eq!("python", file["layout"]);
+    let relay = &chain[1].settings;
+    assert_eq!("prod.example.com", relay["host"]);
+    assert_eq!("(set)", relay["token"]);
+    assert_eq!("staging.example.com", relay["write_host"]);
+    assert_eq!("public", relay["write_token"]);
+    assert!(!serde_json::to_string(&chain).unwrap().contains("secret"));
+}
+
+#[test]
+fn test_relay_reads_and_writes_to_their_own_hosts() {
+    let cutout_url = |remote: BossRemote| {
+        remote.cutout_url(
+            "bossdb://col/exp/chan".to_string(),
+            0,
+            (0, 4),
+            (0, 4),
+            (0, 2),
+        )
+    };
+    let mut relay = BossDBRelayDataManager::new(
+        "https".to_string(),
+        "prod.example.com".to_string(),
+        "public".to_string(),
+    );
+    assert!(relay.write_remote().is_none());
+    assert!(!relay.put_data(
+        "col/exp/chan".to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Array::zeros((2, 4, 4)),
+    ));
+
+    relay.set_api_prefix("v2");
+    relay.set_write_target("staging.example.com".to_string(), "secret".to_string());
+    assert_eq!(
+        "https://prod.example.com/v2/cutout/col/exp/chan/0/0:4/0:4/0:2/",
+        cutout_url(relay.read_remote())
+    );
+    assert_eq!(
+        "https://staging.example.com/v2/cutout/col/exp/chan/0/0:4/0:4/0:2/",
+        cutout_url(relay.write_remote().unwrap())
+    );
+}
+
+/// Upstream layer that records the channels written to it, and accepts
+/// writes if told to.
+struct WritableDataManager {
+    accept: bool,
+    writes: Arc<Mutex<Vec<String>>>,
+}
+
+impl DataManager for WritableDataManager {
+    fn get_data(
+        &self,
+        _uri: String,
+        _resolution: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> Array3<u8> {
+        ConstantDataManager(0).get_data(String::new(), 0, origin, destination)
+    }
+
+    fn put_data(&self, uri: String, _resolution: u8, _origin: Vector3, _data: Array3<u8>) -> bool {
+        self.writes.lock().unwrap().push(uri);
+        self.accept
+    }
+}
+
+#[test]
+fn test_upload_writes_through() {
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    for &(write_through, accept) in &[(false, true), (true, true), (true, false)] {
+        let dir = tempfile::tempdir().unwrap();
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let mut fm = ChunkedFileDataManager::new_with_layer(
+            dir.path().to_str().unwrap().to_string(),
+            cuboid_size(),
+            Box::new(WritableDataManager {
+                accept,
+                writes: Arc::clone(&writes),
+            }),
+            false,
+        );
+        fm.set_write_through(write_through);
+
+        let uploaded = fm.upload(uri.to_string(), 0, origin, Array::from_elem((2, 4, 4), 3));
+        assert_eq!(accept, uploaded);
+        assert_eq!(accept, fm.has_cuboid(uri, 0, &origin));
+        if write_through {
+            assert_eq!(vec!["col/exp/chan".to_string()], *writes.lock().unwrap());
+        } else {
+            assert!(writes.lock().unwrap().is_empty());
+        }
+    }
+}
+
+/// A non-cubic volume, so that any transposed axis changes the shape.
+const VOLUME: Vector3 = Vector3 { x: 5, y: 3, z: 2 };
+
+/// A distinct value for every voxel of `VOLUME`.
+fn voxel_value(x: u64, y: u64, z: u64) -> u8 {
+    (x + VOLUME.x * (y + VOLUME.y * z)) as u8
+}
+
+/// `VOLUME`, with every voxel set to `voxel_value`.
+fn labeled_volume(size: Vector3) -> Array3<u8> {
+    Array::from_shape_fn(size.to_zyx_shape(), |(z, y, x)| {
+        voxel_value(x as u64, y as u64, z as u64)
+    })
+}
+
+#[test]
+fn test_zyx_shape() {
+    assert_eq!((2, 3, 5), VOLUME.to_zyx_shape());
+    let array = labeled_volume(VOLUME);
+    assert_eq!(&[2, 3, 5], array.shape());
+    assert_eq!(VOLUME, Vector3::from_zyx_shape(array.shape()));
+    // C-order: x varies fastest.
+    assert_eq!(
+        (0..30).collect::<Vec<u8>>(),
+        array.iter().cloned().collect::<Vec<u8>>()
+    );
+}
+
+#[test]
+fn test_from_xyz_extents() {
+    let (start, stop) = Vector3::from_xyz_extents((1, 6), (2, 5), (3, 5));
+    assert_eq!(Vector3 { x: 1, y: 2, z: 3 }, start);
+    assert_eq!(Vector3 { x: 6, y: 5, z: 5 }, stop);
+    assert_eq!(Some(VOLUME), Vector3::checked_shape(start, stop));
+
+    for (xs, ys, zs) in &[
+        ((1, 0), (0, 1), (0, 1)),
+        ((0, 1), (1, 0), (0, 1)),
+        ((0, 1), (0, 1), (1, 0)),
+    ] {
+        let (start, stop) = Vector3::from_xyz_extents(*xs, *ys, *zs);
+        assert_eq!(None, Vector3::checked_shape(start, stop));
+    }
+}
+
+#[test]
+fn test_coords_in_frame() {
+    let frame = Coords::parse("-1024:-1024:-16").unwrap();
+    assert_eq!(
+        Coords {
+            x: -1024,
+            y: -1024,
+            z: -16
+        },
+        frame
+    );
+    assert_eq!("-1024:-1024:-16", frame.to_string());
+    for bad in &["", "0:0", "0:0:0:0", "a:0:0", "0.5:0:0"] {
+        assert!(Coords::parse(bad).is_err(), "{} parsed", bad);
+    }
+
+    // A negative point maps into the positive grid, and back:
+    let point = Coords {
+        x: -512,
+        y: 0,
+        z: 0,
+    };
+    let in_frame = point.to_frame(frame).unwrap();
+    assert_eq!(
+        Vector3 {
+            x: 512,
+            y: 1024,
+            z: 16
+        },
+        in_frame
+    );
+    assert_eq!(point, Coords::from_frame(in_frame, frame));
+    // Points before the frame don't:
+    assert_eq!(
+        None,
+        Coords {
+            x: -1025,
+            y: 0,
+            z: 0
+        }
+        .to_frame(frame)
+    );
+    assert_eq!(None, Coords { x: 0, y: 0, z: -17 }.to_frame(frame));
+
+    // x and y halve at each level, rounding down; z doesn't:
+    assert_eq!(
+        Coords {
+            x: -256,
+            y: -256,
+            z: -16
+        },
+        frame.at_res(2)
+    );
+    assert_eq!(
+        Coords { x: -1, y: 0, z: -1 },
+        Coords { x: -1, y: 1, z: -1 }.at_res(1)
+    );
+}
+
+#[test]
+fn test_zyx_slice_of_every_region() {
+    let array = labeled_volume(VOLUME);
+    let mut regions = 0;
+    for (x0, x1) in (0..=VOLUME.x).flat_map(|a| (a..=VOLUME.x).map(move |b| (a, b))) {
+        for (y0, y1) in (0..=VOLUME.y).flat_map(|a| (a..=VOLUME.y).map(move |b| (a, b))) {
+            for (z0, z1) in (0..=VOLUME.z).flat_map(|a| (a..=VOLUME.z).map(move |b| (a, b))) {
+                let (start, stop) = Vector3::from_xyz_extents((x0, x1), (y0, y1), (z0, z1));
+                let region = array.slice(&Vector3::zyx_slice(start, stop));
+                let shape = Vector3::checked_shape(start, stop).unwrap();
+                assert_eq!(Vector3::from_zyx_shape(region.shape()), shape);
+                for ((z, y, x), value) in region.indexed_iter() {
+                    assert_eq!(
+                        voxel_value(x as u64 + x0, y as u64 + y0, z as u64 + z0),
+                        *value
+                    );
+                }
+                regions += 1;
+            }
+        }
+    }
+    // Every start <= stop along each of the axes:
+    assert_eq!(21 * 10 * 6, regions);
+}
+
+#[test]
+fn test_cutouts_of_non_cubic_cuboids() {
+    // Cuboids are 1 x 3 x 2 (x, y, z), so the volume is 5 x 1 x 1 cuboids.
+    let size = Vector3 { x: 1, y: 3, z: 2 };
+    for &use_mmap in &[false, true] {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fm =
+            ChunkedFileDataManager::new(dir.path().to_str().unwrap().to_string(), size, false);
+        fm.set_use_mmap(use_mmap);
+        let uri = "bossdb://col/exp/chan".to_string();
+        let origin = Vector3 { x: 0, y: 0, z: 0 };
+        assert!(fm.put_data(uri.clone(), 0, origin, labeled_volume(VOLUME)));
+
+        let written = fs::read(dir.path().join("col/exp/chan/0/x2_y0_z0")).unwrap();
+        let expected: Vec<u8> = labeled_volume(VOLUME)
+            .slice(s![.., .., 2..3])
+            .iter()
+            .cloned()
+            .collect();
+        assert_eq!(&expected[..], voxels(&written, size).unwrap());
+
+        for x0 in 0..VOLUME.x {
+            for x1 in x0 + 1..=VOLUME.x {
+                let (start, stop) = Vector3::from_xyz_extents((x0, x1), (0, 3), (0, 2));
+                let cutout = fm.get_cutout(uri.clone(), 0, start, stop);
+                assert_eq!(
+                    labeled_volume(VOLUME).slice(&Vector3::zyx_slice(start, stop)),
+                    cutout.data
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_mmap_reads_match_buffered_reads() {
+    // 2 x 2 x 2 cuboids of 4 x 4 x 2:
+    let volume = Vector3 { x: 8, y: 8, z: 4 };
+    let data = Array::from_shape_fn(volume.to_zyx_shape(), |(z, y, x)| {
+        (x + 8 * y + 64 * z) as u8
+    });
+    // Partial cuboids at the start of a region are read in part:
+    let regions = [
+        ((0, 8), (0, 8), (0, 4)),
+        ((1, 4), (2, 8), (1, 2)),
+        ((3, 8), (0, 4), (0, 4)),
+        ((5, 8), (7, 8), (3, 4)),
+    ];
+    for &version in &[LEGACY_VERSION, CURRENT_VERSION] {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fm = file_manager(&dir);
+        fm.set_format_version(version);
+        let uri = "bossdb://col/exp/chan".to_string();
+        assert!(fm.put_data(uri.clone(), 0, Vector3 { x: 0, y: 0, z: 0 }, data.clone()));
+
+        for &(xs, ys, zs) in &regions {
+            let (start, stop) = Vector3::from_xyz_extents(xs, ys, zs);
+            fm.set_use_mmap(false);
+            let buffered = fm.get_cutout(uri.clone(), 0, start, stop);
+            fm.set_use_mmap(true);
+            let mapped = fm.get_cutout(uri.clone(), 0, start, stop);
+            assert!(buffered.cache_hit && mapped.cache_hit);
+            assert_eq!(data.slice(&Vector3::zyx_slice(start, stop)), mapped.data);
+            assert_eq!(buffered.data, mapped.data);
+        }
+    }
+}
+
+/// Channel source where every channel is `uint8`.
+struct Uint8ChannelSource;
+
+impl ChannelSource for Uint8ChannelSource {
+    fn get_datatype(&self, _channel: &str) -> Result<String, String> {
+        Ok("uint8".to_string())
+    }
+}
+
+/// Channel source where every channel is `uint8`, 6x4x2 voxels.
+struct SmallChannelSource;
+
+impl ChannelSource for SmallChannelSource {
+    fn get_datatype(&self, _channel: &str) -> Result<String, String> {
+        Ok("uint8".to_string())
+    }
+
+    fn get_extent(&self, _channel: &str) -> Result<(Coords, Coords), String> {
+        Ok((Coords { x: 0, y: 0, z: 0 }, Coords { x: 6, y: 4, z: 2 }))
+    }
+}
+
+#[test]
+fn test_clamp_to_extent() {
+    let dir = tempfile::tempdir().unwrap();
+    let batches = Arc::new(Mutex::new(Vec::new()));
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(RecordingDataManager {
+            batches: Arc::clone(&batches),
+        }),
+        false,
+    );
+    let registry = ChannelRegistry::new(":memory:", Box::new(SmallChannelSource), cuboid_size());
+    fm.set_channels(Arc::new(registry));
+    fm.set_fill_values(FillValues::new(9));
+    fm.set_clamp_to_extent(true);
+    let uri = "bossdb://col/exp/chan";
+
+    // Past the extent in x and y:
+    let cutout = fm.get_cutout(
+        uri.to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Vector3 { x: 12, y: 8, z: 2 },
+    );
+    assert!(cutout.clamped);
+    assert_eq!((2, 8, 12), cutout.data.dim());
+    // Only the two cuboids that overlap the extent are fetched:
+    assert_eq!(vec![2], *batches.lock().unwrap());
+    assert!(cutout.data.slice(s![.., ..4, ..6]).iter().all(|v| *v == 2));
+    assert!(cutout.data.slice(s![.., ..4, 6..]).iter().all(|v| *v == 9));
+    assert!(cutout.data.slice(s![.., 4.., ..]).iter().all(|v| *v == 9));
+
+    // Entirely outside it, nothing is fetched:
+    let cutout = fm.get_cutout(
+        uri.to_string(),
+        0,
+        Vector3 { x: 8, y: 0, z: 0 },
+        Vector3 { x: 12, y: 4, z: 2 },
+    );
+    assert!(cutout.clamped);
+    assert!(cutout.data.iter().all(|v| *v == 9));
+    assert_eq!(vec![2], *batches.lock().unwrap());
+
+    // Inside it, as usual:
+    let cutout = fm.get_cutout(
+        uri.to_string(),
+        0,
+        Vector3 { x: 0, y: 0, z: 0 },
+        Vector3 { x: 4, y: 4, z: 2 },
+    );
+    assert!(!cutout.clamped);
+    assert!(cutout.cache_hit);
+}
+
+/// Channel source where every channel is `uint8`, in a frame that starts at
+/// 4:4:2 and stops at 12:12:4.
+struct OffsetFrameSource;
+
+impl ChannelSource for OffsetFrameSource {
+    fn get_datatype(&self, _channel: &str) -> Result<String, String> {
+        Ok("uint8".to_string())
+    }
+
+    fn get_extent(&self, _channel: &str) -> Result<(Coords, Coords), String> {
+        Ok((Coords { x: 4, y: 4, z: 2 }, Coords { x: 12, y: 12, z: 4 }))
+    }
+}
+
+#[test]
+fn test_check_frame_with_nonzero_origin() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(3)),
+        false,
+    );
+    let registry = ChannelRegistry::new(":memory:", Box::new(OffsetFrameSource), cuboid_size());
+    fm.set_channels(Arc::new(registry));
+    let uri = "bossdb://col/exp/chan";
+    let v = |x, y, z| Vector3 { x, y, z };
+
+    // Off unless turned on:
+    assert!(fm.check_frame(uri, 0, v(0, 0, 0), v(4, 4, 2)).is_ok());
+    fm.set_check_frame(true);
+
+    // Cuboids indexed from 0:0:0, as the frame is global:
+    assert!(fm.check_frame(uri, 0, v(4, 4, 2), v(12, 12, 4)).is_ok());
+    let err = fm.check_frame(uri, 0, v(0, 4, 2), v(8, 8, 4)).unwrap_err();
+    assert!(err.contains("spans 4:4:2 to 12:12:4"), "{}", err);
+    assert!(fm.check_frame(uri, 0, v(4, 4, 2), v(16, 12, 4)).is_err());
+    // Each level halves x and y:
+    assert!(fm.check_frame(uri, 1, v(2, 2, 2), v(6, 6, 4)).is_ok());
+    assert!(fm.check_frame(uri, 1, v(2, 2, 2), v(8, 6, 4)).is_err());
+
+    // Cuboids indexed from where the channel's frame starts:
+    fm.set_frame(Coords { x: 4, y: 4, z: 2 });
+    assert!(fm.check_frame(uri, 0, v(0, 0, 0), v(8, 8, 2)).is_ok());
+    let err = fm.check_frame(uri, 0, v(4, 0, 0), v(12, 8, 2)).unwrap_err();
+    assert!(err.contains("from 8:4:2 to 16:12:4"), "{}", err);
+
+    // Clamped cutouts are filled rather than refused:
+    fm.set_clamp_to_extent(true);
+    assert!(fm.check_frame(uri, 0, v(4, 0, 0), v(12, 8, 2)).is_ok());
+    // But uploads aren't clamped:
+    assert!(fm
+        .check_upload_frame(uri, 0, v(4, 0, 0), v(12, 8, 2))
+        .is_err());
+    assert!(fm
+        .check_upload_frame(uri, 0, v(0, 0, 0), v(8, 8, 2))
+        .is_ok());
+}
+
+#[test]
+fn test_per_channel_cuboid_size() {
+    let small = Vector3 { x: 2, y: 2, z: 1 };
+    for &use_mmap in &[false, true] {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fm = ChunkedFileDataManager::new_with_layer(
+            dir.path().to_str().unwrap().to_string(),
+            cuboid_size(),
+            Box::new(ConstantDataManager(3)),
+            false,
+        );
+        fm.set_use_mmap(use_mmap);
+        let mut registry =
+            ChannelRegistry::new(":memory:", Box::new(Uint8ChannelSource), cuboid_size());
+        let mut sizes = HashMap::new();
+        sizes.insert("col/exp/small".to_string(), small);
+        registry.set_cuboid_sizes(sizes);
+        fm.set_channels(Arc::new(registry));
+
+        let uri = "bossdb://col/exp/small".to_string();
+        let origin = Vector3 { x: 0, y: 0, z: 0 };
+        assert_eq!(small, fm.cuboid_size_of(&uri));
+        assert_eq!(cuboid_size(), fm.cuboid_size_of("bossdb://col/exp/chan"));
+
+        // Fetched and cached as 2 x 2 x 1 cuboids:
+        let cutout = fm.get_cutout(uri.clone(), 0, origin, cuboid_size());
+        assert!(!cutout.cache_hit);
+        assert!(cutout.data.iter().all(|v| *v == 3));
+        let written = fs::read(dir.path().join("col/exp/small/0/x1_y1_z1")).unwrap();
+        assert_eq!(4, voxels(&written, small).unwrap().len());
+
+        // Written and read back on the same grid:
+        assert!(fm.put_data(uri.clone(), 0, origin, labeled_volume(cuboid_size())));
+        let (start, stop) = Vector3::from_xyz_extents((1, 4), (1, 4), (0, 2));
+        let cutout = fm.get_cutout(uri.clone(), 0, start, stop);
+        assert!(cutout.cache_hit);
+        assert_eq!(
+            labeled_volume(cuboid_size()).slice(&Vector3::zyx_slice(start, stop)),
+            cutout.data
+        );
+    }
+}
+
+#[test]
+fn test_configured_cuboid_size_without_registry() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = file_manager(&dir);
+    let small = Vector3 { x: 2, y: 2, z: 1 };
+    let mut sizes = HashMap::new();
+    sizes.insert("col/exp/small".to_string(), small);
+    fm.set_channel_cuboid_sizes(sizes);
+
+    assert_eq!(small, fm.cuboid_size_of("bossdb://col/exp/small"));
+    assert_eq!(cuboid_size(), fm.cuboid_size_of("bossdb://col/exp/chan"));
+}
+
+fn missing_channel_manager(
+    dir: &tempfile::TempDir,
+    ttl: Duration,
+) -> (ChunkedFileDataManager, Arc<Mutex<usize>>) {
+    let calls = Arc::new(Mutex::new(0));
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(MissingDataManager {
+            calls: Arc::clone(&calls),
+        }),
+        false,
+    );
+    fm.set_not_found(Arc::new(NotFoundChannels::new(ttl)));
+    (fm, calls)
+}
+
+#[test]
+fn test_missing_channel_is_not_cached() {
+    let dir = tempfile::tempdir().unwrap();
+    let (fm, calls) = missing_channel_manager(&dir, Duration::from_secs(0));
+    let uri = "bossdb://col/exp/missing".to_string();
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+
+    for _ in 0..2 {
+        let cutout = fm.get_cutout(uri.clone(), 0, origin, cuboid_size());
+        assert!(cutout.not_found);
+        assert!(!cutout.partial);
+    }
+    // Asked upstream each time, and nothing was written:
+    assert_eq!(2, *calls.lock().unwrap());
+    assert!(!dir.path().join("col").exists());
+}
+
+#[test]
+fn test_missing_channel_remembered_for_ttl() {
+    let dir = tempfile::tempdir().unwrap();
+    let (fm, calls) = missing_channel_manager(&dir, Duration::from_secs(3600));
+    let uri = "bossdb://col/exp/missing".to_string();
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+
+    assert!(
+        fm.get_cutout(uri.clone(), 0, origin, cuboid_size())
+            .not_found
+    );
+    assert!(
+        fm.get_cutout(uri.clone(), 1, origin, cuboid_size())
+            .not_found
+    );
+    assert_eq!(1, *calls.lock().unwrap());
+
+    // Other channels are still asked for:
+    let other = "bossdb://col/exp/other".to_string();
+    assert!(fm.get_cutout(other, 0, origin, cuboid_size()).not_found);
+    assert_eq!(2, *calls.lock().unwrap());
+}
+
+#[test]
+fn test_not_found_channels_expire() {
+    let channels = NotFoundChannels::new(Duration::from_millis(10));
+    channels.record("col/exp/missing");
+    assert!(channels.contains("col/exp/missing"));
+    assert!(!channels.contains("col/exp/other"));
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(!channels.contains("col/exp/missing"));
+}
+
+#[test]
+fn test_not_found_ttl_change() {
+    let channels = NotFoundChannels::new(Duration::from_secs(0));
+    channels.record("col/exp/missing");
+    assert!(!channels.contains("col/exp/missing"));
+
+    channels.set_ttl(Duration::from_secs(60));
+    assert_eq!(Duration::from_secs(60), channels.ttl());
+    channels.record("col/exp/missing");
+    assert!(channels.contains("col/exp/missing"));
+
+    // Disabling it forgets what was recorded:
+    channels.set_ttl(Duration::from_secs(0));
+    assert!(!channels.contains("col/exp/missing"));
+}
+
+#[test]
+fn test_no_writeback() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(5)),
+        false,
+    );
+    fm.set_writeback(false);
+    let uri = "bossdb://col/exp/chan".to_string();
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 8, y: 8, z: 2 };
+
+    let cutout = fm.get_cutout(uri.clone(), 0, origin, destination);
+    assert!(cutout.data.iter().all(|v| *v == 5));
+    assert!(!cutout.cache_hit);
+    assert_eq!(0, fm.warm(&uri, 0, origin, destination));
+    assert!(!dir.path().join("col").exists());
+
+    // Still fetched from upstream next time:
+    assert!(!fm.get_cutout(uri, 0, origin, destination).cache_hit);
+}
+
+#[test]
+fn test_low_disk_pauses_writeback() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_str().unwrap().to_string();
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        root.clone(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(5)),
+        false,
+    );
+    let (guard, free) = mock_guard(vec![root]);
+    let guard = Arc::new(guard);
+    fm.set_disk_guard(Arc::clone(&guard));
+    let uri = "bossdb://col/exp/chan".to_string();
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 4, y: 4, z: 2 };
+    let cached = dir.path().join("col/exp/chan/0/x0_y0_z0");
+
+    free.store(10, Ordering::SeqCst);
+    guard.check();
+    let cutout = fm.get_cutout(uri.clone(), 0, origin, destination);
+    assert!(cutout.data.iter().all(|v| *v == 5));
+    assert!(!cached.exists());
+    assert_eq!(0, fm.warm(&uri, 0, origin, destination));
+
+    // Cached again once there's room:
+    free.store(1000, Ordering::SeqCst);
+    guard.check();
+    assert!(!fm.get_cutout(uri.clone(), 0, origin, destination).cache_hit);
+    assert!(cached.exists());
+    assert!(fm.get_cutout(uri, 0, origin, destination).cache_hit);
+}
+
+#[test]
+fn test_writeback_on_second_miss() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(5)),
+        false,
+    );
+    fm.set_recent_misses(Arc::new(RecentMisses::new(Duration::from_secs(60))));
+    let uri = "bossdb://col/exp/chan".to_string();
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 4, y: 4, z: 2 };
+    let cached = dir.path().join("col/exp/chan/0/x0_y0_z0");
+
+    // A one-off read isn't cached:
+    let cutout = fm.get_cutout(uri.clone(), 0, origin, destination);
+    assert!(cutout.data.iter().all(|v| *v == 5));
+    assert!(!cached.exists());
+
+    // The second miss is:
+    assert!(!fm.get_cutout(uri.clone(), 0, origin, destination).cache_hit);
+    assert!(cached.exists());
+    assert!(fm.get_cutout(uri.clone(), 0, origin, destination).cache_hit);
+
+    // Warming caches on the first miss:
+    let next = Vector3 { x: 4, y: 0, z: 0 };
+    assert_eq!(1, fm.warm(&uri, 0, next, Vector3 { x: 8, y: 4, z: 2 }));
+}
+
+#[test]
+fn test_recent_misses_window() {
+    let misses = RecentMisses::new(Duration::from_secs(60));
+    let start = Instant::now();
+    assert!(!misses.is_second_miss("a", start));
+    assert!(!misses.is_second_miss("b", start));
+    assert!(misses.is_second_miss("a", start + Duration::from_secs(60)));
+    // Counted, so it starts over:
+    assert!(!misses.is_second_miss("a", start + Duration::from_secs(61)));
+    // Too late to count:
+    assert!(!misses.is_second_miss("b", start + Duration::from_secs(61)));
+    assert!(misses.is_second_miss("b", start + Duration::from_secs(62)));
+}
+
+#[test]
+fn test_recent_misses_capacity() {
+    let misses = RecentMisses {
+        capacity: 2,
+        ..RecentMisses::new(Duration::from_secs(60))
+    };
+    let now = Instant::now();
+    assert!(!misses.is_second_miss("a", now));
+    assert!(!misses.is_second_miss("b", now));
+    assert!(!misses.is_second_miss("c", now));
+    // The oldest was forgotten to make room:
+    assert!(!misses.is_second_miss("a", now));
+    assert!(misses.is_second_miss("c", now));
+    assert_eq!(2, misses.first_miss.lock().unwrap().in_order.len());
+}
+
+#[test]
+fn test_file_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    let limit = Arc::new(Semaphore::new(1));
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let root = dir.path().to_str().unwrap().to_string();
+            let limit = Arc::clone(&limit);
+            std::thread::spawn(move || {
+                let mut fm = ChunkedFileDataManager::new_with_layer(
+                    root,
+                    cuboid_size(),
+                    Box::new(ConstantDataManager(i)),
+                    false,
+                );
+                fm.set_file_limit(limit);
+                fm.set_use_mmap(i % 2 == 0);
+                let uri = format!("bossdb://col/exp/chan{}", i);
+                let origin = Vector3 { x: 0, y: 0, z: 0 };
+                let destination = Vector3 {
+                    x: 2 * cuboid_size().x,
+                    y: cuboid_size().y,
+                    z: cuboid_size().z,
+                };
+                // Fetched, written back, then read back from the files:
+                fm.get_cutout(uri.clone(), 0, origin, destination);
+                let cutout = fm.get_cutout(uri, 0, origin, destination);
+                assert!(cutout.cache_hit);
+                assert!(cutout.data.iter().all(|v| *v == i));
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    // Every permit was given back:
+    assert!(limit.try_acquire().is_some());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_modes() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("cache");
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        root.to_str().unwrap().to_string(),
+        cuboid_size(),
+        Box::new(ConstantDataManager(3)),
+        false,
+    );
+    fm.set_modes(Modes {
+        dir: Some(0o2750),
+        file: Some(0o640),
+    });
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    fm.get_cutout(
+        "bossdb://col/exp/chan".to_string(),
+        0,
+        origin,
+        cuboid_size(),
+    );
+
+    // Every directory and cuboid written back has the configured mode:
+    let mut pending = vec![root];
+    let mut files = 0;
+    while let Some(path) = pending.pop() {
+        let meta = fs::metadata(&path).unwrap();
+        if meta.is_dir() {
+            assert_eq!(meta.permissions().mode() & 0o7777, 0o2750, "{:?}", path);
+            pending.extend(fs::read_dir(&path).unwrap().map(|e| e.unwrap().path()));
+        } else {
+            assert_eq!(meta.permissions().mode() & 0o7777, 0o640, "{:?}", path);
+            files += 1;
+        }
+    }
+    assert_eq!(files, 1);
+}
+
+#[test]
+fn test_empty_cuboids() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = file_manager(&dir);
+    let empty = Arc::new(EmptyCuboids::new(Arc::new(
+        ConnectionPool::new(dir.path().join("cache.db").to_str().unwrap(), 1).unwrap(),
+    )));
+    fm.set_empty_cuboids(Arc::clone(&empty));
+    let uri = "bossdb://col/exp/chan".to_string();
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let filename = fm.cuboid_filename(&uri, 0, &origin);
+
+    // Never ingested, so it's recorded as empty:
+    let cutout = fm.get_cutout(uri.clone(), 0, origin, cuboid_size());
+    assert!(cutout.data.iter().all(|v| *v == 0));
+    assert!(empty.contains(&filename));
+
+    // Writing it makes it a real cuboid again:
+    let data = Array::from_elem(cuboid_size().to_zyx_shape(), 7);
+    assert!(fm.put_data(uri.clone(), 0, origin, data));
+    assert!(!empty.contains(&filename));
+    let cutout = fm.get_cutout(uri, 0, origin, cuboid_size());
+    assert!(cutout.data.iter().all(|v| *v == 7));
+}
+
+#[test]
+fn test_known_zero() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = file_manager(&dir);
+    let uri = "bossdb://col/exp/chan".to_string();
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 {
+        x: 2 * cuboid_size().x,
+        y: cuboid_size().y,
+        z: cuboid_size().z,
+    };
+    // Nothing is known without a record of empty cuboids:
+    assert!(!fm.known_zero(&uri, 0, origin, destination));
+
+    fm.set_empty_cuboids(Arc::new(EmptyCuboids::new(Arc::new(
+        ConnectionPool::new(dir.path().join("cache.db").to_str().unwrap(), 1).unwrap(),
+    ))));
+    assert!(!fm.known_zero(&uri, 0, origin, destination));
+    fm.get_cutout(uri.clone(), 0, origin, destination);
+    assert!(fm.known_zero(&uri, 0, origin, destination));
+
+    // Not once part of it is written:
+    let data = Array::from_elem(cuboid_size().to_zyx_shape(), 7);
+    assert!(fm.put_data(uri.clone(), 0, origin, data));
+    assert!(!fm.known_zero(&uri, 0, origin, destination));
+
+    // Nor when missing cuboids are filled with something else:
+    let mut fill_values = FillValues::new(0);
+    fill_values.set_channel("col/exp/other", 3);
+    fm.set_fill_values(fill_values);
+    let other = "bossdb://col/exp/other".to_string();
+    fm.get_cutout(other.clone(), 0, origin, destination);
+    assert!(!fm.known_zero(&other, 0, origin, destination));
+}
+
+/// Upload 15 overlapping parts of a cuboid, each from a voxel column to the
+/// far corner, so that column ends up with the last value written to it.
+/// Returns how many cuboid files were written meanwhile.
+fn upload_corners(fm: &mut ChunkedFileDataManager) -> usize {
+    let (tx, rx) = mpsc::channel();
+    fm.set_usage_sender(Arc::new(Mutex::new(tx)));
+    for y in 0..4 {
+        for x in 0..4 {
+            if x == 0 && y == 0 {
+                // The whole cuboid, which isn't buffered.
+                continue;
+            }
+            let part = Array::from_elem((2, 4 - y, 4 - x), (y * 4 + x) as u8);
+            let origin = Vector3 {
+                x: x as u64,
+                y: y as u64,
+                z: 0,
+            };
+            let summary = fm
+                .try_upload("bossdb://col/exp/chan", 0, origin, part)
+                .unwrap();
+            assert_eq!(1, summary.cuboids);
+        }
+    }
+    rx.try_iter().count()
+}
+
+#[test]
+fn test_write_buffer_coalesces_small_writes() {
+    let uri = "bossdb://col/exp/chan";
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let expected = Array::from_shape_fn((2, 4, 4), |(_, y, x)| (y * 4 + x) as u8);
+
+    // Each upload reads and rewrites the cuboid:
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        false,
+    );
+    assert_eq!(15, upload_corners(&mut fm));
+    assert_eq!(
+        expected,
+        fm.get_cutout(uri.to_string(), 0, origin, cuboid_size())
+            .data
+    );
+
+    // Buffered, the cuboid is written once:
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        false,
+    );
+    let buffer = Arc::new(WriteBuffer::new(Duration::from_secs(3600), 8));
+    fm.set_write_buffer(Arc::clone(&buffer));
+    assert_eq!(0, upload_corners(&mut fm));
+    assert_eq!(1, buffer.len());
+    assert!(!std::path::Path::new(&fm.cuboid_filename(uri, 0, &origin)).exists());
+    assert_eq!(1, buffer.flush_all());
+    assert_eq!(
+        expected,
+        fm.get_cutout(uri.to_string(), 0, origin, cuboid_size())
+            .data
+    );
+
+    // Reads see buffered writes:
+    let column = Array::from_elem((2, 1, 1), 99);
+    assert!(fm.upload(uri.to_string(), 0, Vector3 { x: 3, y: 3, z: 0 }, column));
+    assert_eq!(1, buffer.len());
+    let cutout = fm.get_cutout(uri.to_string(), 0, origin, cuboid_size());
+    assert_eq!(0, buffer.len());
+    assert_eq!(99, cutout.data[[1, 3, 3]]);
+    assert_eq!(expected[[1, 3, 2]], cutout.data[[1, 3, 2]]);
+}
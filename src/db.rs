@@ -17,23 +17,40 @@ limitations under the License.
 */
 
 /// SQL database module.
+pub mod channel_keys;
+pub mod channels;
+pub mod empty;
 pub mod models;
+pub mod pool;
 pub mod schema;
+pub mod scratch;
 
 extern crate chrono;
 extern crate diesel;
 use super::config;
-use super::usage_tracker::UsageTracker;
+use super::cuboid_file;
+use super::data_manager::Vector3;
+use super::etag::CuboidHashes;
+use super::usage_tracker::{build_strategy, EvictionSettings, UsageTracker};
+use super::write_buffer::WriteBuffer;
+use channel_keys::ChannelKeys;
 use chrono::prelude::*;
 use diesel::prelude::*;
+use diesel::sqlite::Sqlite;
 use models::{CacheRoot, Cuboid, NewCacheRoot, NewCuboid};
+use pool::{ConnectionPool, PooledConnection, Pragmas};
+use serde::Serialize;
 use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
 use std::fs;
+use std::hash::{BuildHasher, Hasher};
 use std::option::Option;
 use std::path::Path;
 use std::rc::Rc;
 use std::result::Result;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[cfg(test)]
 pub mod tests;
@@ -49,13 +66,28 @@ pub trait Selection {
 }
 
 pub trait LeastRecentlyUsed {
-    /// Find the `num` least recently used cuboids in the cache.  This is one
-    /// selection strategy.
+    /// Find the `num` least recently used cuboids in the cache, skipping
+    /// pinned ones.  This is one selection strategy.
     ///
     /// # Arguments:
     ///
     /// * `num` - How many cuboids to retrieve.
     fn find_lru(&self, num: u32) -> Vec<Cuboid>;
+
+    /// Find the `num` least recently used cuboids that were neither created
+    /// nor accessed after `cutoff`.
+    ///
+    /// # Arguments:
+    ///
+    /// * `num` - How many cuboids to retrieve.
+    /// * `cutoff` - Cuboids touched after this time are left alone.
+    fn find_lru_before(&self, num: u32, cutoff: NaiveDateTime) -> Vec<Cuboid>;
+}
+
+pub trait CuboidCatalog {
+    /// List every cuboid in the cache that isn't pinned.  Used by
+    /// strategies that rank cuboids by something the DB can't sort on.
+    fn all_cuboids(&self) -> Vec<Cuboid>;
 }
 
 /// A primitive way of managing the size of the cuboid cache.  Just limit the
@@ -104,6 +136,8 @@ pub struct MaxCountLruStrategy {
     max_cuboids: u32,
     /// Current number of cuboids stored in the cache.
     num_cuboids: u32,
+    /// Cuboids accessed within this many seconds are never evicted.
+    min_residency: u32,
     /// Find cuboids based on least recently used.
     finder: Rc<RefCell<dyn LeastRecentlyUsed>>,
 }
@@ -150,6 +184,14 @@ impl Selection for MaxCountLruStrategy {
         if num_to_remove <= 0 {
             return Vec::<Cuboid>::new();
         }
+        if self.min_residency > 0 {
+            let cutoff =
+                Utc::now().naive_utc() - chrono::Duration::seconds(self.min_residency as i64);
+            return self
+                .finder
+                .borrow()
+                .find_lru_before(num_to_remove as u32, cutoff);
+        }
         self.finder.borrow().find_lru(num_to_remove as u32)
     }
 }
@@ -164,17 +206,194 @@ impl MaxCountLruStrategy {
         MaxCountLruStrategy {
             max_cuboids,
             num_cuboids,
+            min_residency: 0,
             finder,
         }
     }
+
+    /// Protect recently used cuboids from eviction.  Guards against evicting
+    /// a cuboid that was just written but hasn't been read yet.
+    ///
+    /// # Arguments:
+    ///
+    /// * `seconds` - Grace period since creation or last access (0 disables)
+    pub fn set_min_residency(&mut self, seconds: u32) {
+        self.min_residency = seconds;
+    }
+}
+
+/// A complete cache management strategy, as used by `SimpleCacheManager`.
+pub trait CacheStrategy: Scheduling + Selection + LimitNumCuboids {}
+
+impl<T: Scheduling + Selection + LimitNumCuboids> CacheStrategy for T {}
+
+/// Score a cuboid by its request count, decayed exponentially by the time
+/// since it was last accessed.  A cuboid's score halves every `half_life`
+/// seconds that it goes unused, so it balances frequency and recency.
+///
+/// # Arguments:
+///
+/// * `requests` - Number of requests for the cuboid
+/// * `last_accessed` - When the cuboid was last requested
+/// * `now` - Time to score at
+/// * `half_life` - Seconds for the score to halve
+pub fn decayed_score(
+    requests: i64,
+    last_accessed: NaiveDateTime,
+    now: NaiveDateTime,
+    half_life: u32,
+) -> f64 {
+    let age = (now - last_accessed).num_milliseconds().max(0) as f64 / 1000.0;
+    requests as f64 * (-age / half_life.max(1) as f64).exp2()
+}
+
+/// Limit the maximum number of cuboids in the cache by evicting the ones
+/// with the lowest time-decayed request count (see `decayed_score`).
+pub struct MaxCountDecayStrategy {
+    /// Max number of cuboids stored in the cache.
+    max_cuboids: u32,
+    /// Current number of cuboids stored in the cache.
+    num_cuboids: u32,
+    /// Seconds for a cuboid's score to halve while it goes unused.
+    half_life: u32,
+    /// Cuboids accessed within this many seconds are never evicted.
+    min_residency: u32,
+    /// Lists the cuboids to score.
+    catalog: Rc<RefCell<dyn CuboidCatalog>>,
+}
+
+impl Scheduling for MaxCountDecayStrategy {
+    fn ready_for_cleaning(&self) -> bool {
+        self.size() > self.get_max_cuboids()
+    }
+}
+
+impl LimitNumCuboids for MaxCountDecayStrategy {
+    fn get_max_cuboids(&self) -> u32 {
+        self.max_cuboids
+    }
+
+    fn set_max_cuboids(&mut self, max: u32) {
+        self.max_cuboids = max;
+    }
+
+    fn size(&self) -> u32 {
+        self.num_cuboids
+    }
+
+    fn set_size(&mut self, num: u32) {
+        self.num_cuboids = num;
+    }
+
+    fn add(&mut self, num: u32) {
+        self.num_cuboids += num;
+    }
+
+    fn sub(&mut self, num: u32) {
+        if num > self.num_cuboids {
+            self.num_cuboids = 0;
+        } else {
+            self.num_cuboids -= num;
+        }
+    }
+}
+
+impl Selection for MaxCountDecayStrategy {
+    fn select_cuboids_for_removal(&self) -> Vec<Cuboid> {
+        let num_to_remove = self.size() as i64 - self.get_max_cuboids() as i64;
+        if num_to_remove <= 0 {
+            return Vec::<Cuboid>::new();
+        }
+        let now = Utc::now().naive_utc();
+        let cutoff = now - chrono::Duration::seconds(self.min_residency as i64);
+        let mut scored: Vec<(f64, Cuboid)> = self
+            .catalog
+            .borrow()
+            .all_cuboids()
+            .into_iter()
+            .filter(|c| self.min_residency == 0 || (c.last_accessed < cutoff && c.created < cutoff))
+            .map(|c| {
+                (
+                    decayed_score(c.requests, c.last_accessed, now, self.half_life),
+                    c,
+                )
+            })
+            .collect();
+        scored.sort_by(|a, b| {
+            a.0.partial_cmp(&b.0)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.1.last_accessed.cmp(&b.1.last_accessed))
+        });
+        scored
+            .into_iter()
+            .take(num_to_remove as usize)
+            .map(|(_, c)| c)
+            .collect()
+    }
+}
+
+impl MaxCountDecayStrategy {
+    pub fn new(
+        max_cuboids: u32,
+        half_life: u32,
+        catalog: Rc<RefCell<dyn CuboidCatalog>>,
+    ) -> MaxCountDecayStrategy {
+        MaxCountDecayStrategy {
+            max_cuboids,
+            num_cuboids: 0,
+            half_life,
+            min_residency: 0,
+            catalog,
+        }
+    }
+
+    /// Protect recently used cuboids from eviction.  See
+    /// `MaxCountLruStrategy::set_min_residency`.
+    ///
+    /// # Arguments:
+    ///
+    /// * `seconds` - Grace period since creation or last access (0 disables)
+    pub fn set_min_residency(&mut self, seconds: u32) {
+        self.min_residency = seconds;
+    }
 }
 
 /// Do simple cache management with cache data backed by SQLite.
 pub struct SimpleCacheManager {
     /// All DB accesses use this object.
     db: Rc<RefCell<SqliteCacheInterface>>,
-    /// Cache management strategy implementation (keep no more than _n_ files; remove least recently used).
-    strategy: MaxCountLruStrategy,
+    /// Cache management strategy implementation (e.g. keep no more than _n_ files; remove least recently used).
+    strategy: Box<dyn CacheStrategy>,
+    /// Max random delay between the strategy being ready for cleaning and
+    /// cleaning, so that servers sharing a cache don't all clean at once.
+    jitter: Duration,
+    /// When the pending cleaning is due, once the strategy is ready.
+    clean_after: Option<Instant>,
+    /// Evict cuboids with empty files before asking the strategy.
+    empty_first: bool,
+    /// When the cache was last scanned for empty cuboids.
+    empty_scanned: Option<Instant>,
+}
+
+/// How often cleaning looks for cuboids with empty files to evict first
+/// (see `SimpleCacheManager::set_evict_empty_first`), since looking means
+/// checking every cached file.
+const EMPTY_SCAN_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A random delay between zero and `max`, inclusive, to the millisecond.
+pub fn random_jitter(max: Duration) -> Duration {
+    let millis = max.as_millis() as u64;
+    if millis == 0 {
+        return Duration::from_millis(0);
+    }
+    // Every RandomState is keyed differently, which is random enough here.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_nanos()),
+    );
+    Duration::from_millis(hasher.finish() % (millis + 1))
 }
 
 impl UsageTracker for SimpleCacheManager {
@@ -182,21 +401,112 @@ impl UsageTracker for SimpleCacheManager {
         if self.db.borrow_mut().log_request(key) {
             // Added a new cuboid, so check if time to start cleaning cache.
             self.strategy.add(1);
-            if self.strategy.ready_for_cleaning() {
-                let cuboids = self.strategy.select_cuboids_for_removal();
-                let num_removed = self.db.borrow_mut().clean_cache(cuboids);
-                self.strategy.sub(num_removed);
-            }
+            self.clean_if_ready();
         }
     }
+
+    fn idle(&mut self) {
+        let retried = self.db.borrow_mut().retry_removals();
+        self.strategy.sub(retried);
+        // Cleaning put off by the jitter is due whether or not more cuboids
+        // are added:
+        if self.clean_after.is_some() {
+            self.clean_if_ready();
+        }
+    }
+
+    fn cache_stats(&mut self) -> Option<CacheStats> {
+        Some(self.db.borrow_mut().stats())
+    }
+
+    fn reconfigure(&mut self, settings: &EvictionSettings) {
+        let mut strategy = build_strategy(settings, Rc::clone(&self.db));
+        strategy.set_size(self.strategy.size());
+        self.strategy = strategy;
+        self.set_jitter(Duration::from_secs(settings.jitter as u64));
+        // The cache may be over a lowered limit already:
+        self.clean_if_ready();
+    }
+
+    fn shed(&mut self, fraction: f64) -> u32 {
+        let count = self.db.borrow_mut().num_cuboids();
+        let unwanted = (count as f64 * fraction.max(0.0).min(1.0)).ceil() as u32;
+        let evicted = self.db.borrow_mut().evict_to(count - unwanted.min(count));
+        self.strategy.sub(evicted);
+        evicted
+    }
 }
 
 impl SimpleCacheManager {
-    pub fn new(
+    pub fn new<S: CacheStrategy + 'static>(
+        db: Rc<RefCell<SqliteCacheInterface>>,
+        strategy: S,
+    ) -> SimpleCacheManager {
+        SimpleCacheManager::with_strategy(db, Box::new(strategy))
+    }
+
+    /// Constructor for a strategy picked at runtime.
+    pub fn with_strategy(
         db: Rc<RefCell<SqliteCacheInterface>>,
-        strategy: MaxCountLruStrategy,
+        strategy: Box<dyn CacheStrategy>,
     ) -> SimpleCacheManager {
-        SimpleCacheManager { db, strategy }
+        SimpleCacheManager {
+            db,
+            strategy,
+            jitter: Duration::from_secs(0),
+            clean_after: None,
+            empty_first: false,
+            empty_scanned: None,
+        }
+    }
+
+    /// When cleaning, first evict every cuboid whose file is empty (see
+    /// `SqliteCacheInterface::find_empty`), whatever the strategy thinks
+    /// of it, since it holds no data.  The strategy only picks more if
+    /// that isn't enough.  Since that checks every cached file, it's done
+    /// at most once per `EMPTY_SCAN_INTERVAL`; other cleanings leave it to
+    /// the strategy.
+    pub fn set_evict_empty_first(&mut self, empty_first: bool) {
+        self.empty_first = empty_first;
+    }
+
+    /// Put off cleaning by a random delay of up to `jitter` once the
+    /// strategy is ready for it, so servers sharing a cache spread out their
+    /// cleaning.  The cache may go over its limit meanwhile.
+    pub fn set_jitter(&mut self, jitter: Duration) {
+        self.jitter = jitter;
+        self.clean_after = None;
+    }
+
+    fn clean_if_ready(&mut self) {
+        if !self.strategy.ready_for_cleaning() {
+            self.clean_after = None;
+            return;
+        }
+        let now = Instant::now();
+        let jitter = self.jitter;
+        let due = *self
+            .clean_after
+            .get_or_insert_with(|| now + random_jitter(jitter));
+        if now < due {
+            return;
+        }
+        self.clean_after = None;
+        let scan_due = self
+            .empty_scanned
+            .map_or(true, |scanned| scanned.elapsed() >= EMPTY_SCAN_INTERVAL);
+        if self.empty_first && scan_due {
+            self.empty_scanned = Some(now);
+            let empty = self.db.borrow_mut().find_empty();
+            let num_removed = self.db.borrow_mut().clean_cache(empty);
+            self.strategy.sub(num_removed);
+            if !self.strategy.ready_for_cleaning() {
+                return;
+            }
+        }
+        let cuboids = self.strategy.select_cuboids_for_removal();
+        let num_removed = self.db.borrow_mut().clean_cache(cuboids);
+        self.strategy.sub(num_removed);
     }
 }
 
@@ -213,34 +523,390 @@ impl FileRemover for RealFileRemover {
         Ok(())
     }
 }
+
+/// How many times to retry removing an evicted cuboid's file or DB row
+/// after a transient failure, e.g. `EBUSY` or a locked DB, and how long to
+/// wait before the first retry.  The wait doubles with each retry.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RemovalRetry {
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RemovalRetry {
+    fn default() -> RemovalRetry {
+        RemovalRetry {
+            retries: 2,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RemovalRetry {
+    /// Run `op` until it succeeds or the retries run out, returning its
+    /// last result.  Sleeps in between, so it's only for threads that can
+    /// wait, e.g. at startup.
+    ///
+    /// # Arguments:
+    ///
+    /// * `what` - What `op` does, for logging failures
+    /// * `op` - The removal
+    pub fn run<T, E: std::fmt::Display>(
+        &self,
+        what: &str,
+        mut op: impl FnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut wait = self.backoff;
+        let mut retries = 0;
+        loop {
+            match op() {
+                Err(err) if retries < self.retries => {
+                    println!("Failed to {} ({}); retrying in {:?}", what, err, wait);
+                    std::thread::sleep(wait);
+                    wait *= 2;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// How long to wait before retry number `retry` (from 0).
+    fn wait_before(&self, retry: u32) -> Duration {
+        self.backoff * 2u32.saturating_pow(retry)
+    }
+}
+
+/// A cuboid whose removal failed transiently, to try again once `due`.
+struct PendingRemoval {
+    id: i64,
+    cube_key: String,
+    filename: String,
+    due: Instant,
+    /// Retries so far.
+    retries: u32,
+}
+
+/// Why a cuboid couldn't be removed.
+struct RemovalFailure {
+    message: String,
+    /// Whether trying again later might work.
+    transient: bool,
+}
+
+impl RemovalFailure {
+    fn file(filename: &str, err: std::io::Error) -> RemovalFailure {
+        use std::io::ErrorKind::*;
+        RemovalFailure {
+            transient: !matches!(err.kind(), NotFound | PermissionDenied | InvalidInput),
+            message: format!("Error removing {}: {}", filename, err),
+        }
+    }
+
+    fn entry(cube_key: &str, err: diesel::result::Error) -> RemovalFailure {
+        let transient = match &err {
+            diesel::result::Error::DatabaseError(_, info) => {
+                let message = info.message();
+                message.contains("locked") || message.contains("busy")
+            }
+            _ => false,
+        };
+        RemovalFailure {
+            transient,
+            message: format!("Error removing {} from DB: {}", cube_key, err),
+        }
+    }
+}
+
+/// How many times to retry opening the DB and bringing its schema up to
+/// date at startup, e.g. while a server that's shutting down still holds a
+/// lock on it, and how long to wait before the first retry.  The wait
+/// doubles with each retry.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ConnectRetry {
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl Default for ConnectRetry {
+    fn default() -> ConnectRetry {
+        ConnectRetry {
+            retries: 5,
+            backoff: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Channels whose cuboids are never evicted (the "cold tier").  Cheap to
+/// clone; clones share the same set, so a channel pinned through one (e.g.
+/// by the REST API) is honored by all (e.g. the usage tracker's).
+#[derive(Clone, Default)]
+pub struct PinnedChannels(Arc<RwLock<Vec<String>>>);
+
+impl PinnedChannels {
+    /// Pin every channel matching the given prefixes.
+    ///
+    /// # Arguments:
+    ///
+    /// * `prefixes` - Like `col/exp/chan`, or `col/exp` to pin a whole experiment
+    pub fn new(prefixes: Vec<String>) -> PinnedChannels {
+        let pinned = PinnedChannels::default();
+        for prefix in prefixes {
+            pinned.pin(&prefix);
+        }
+        pinned
+    }
+
+    /// Pin a channel prefix.  Returns false if it was already pinned.
+    ///
+    /// # Arguments:
+    ///
+    /// * `prefix` - Like `col/exp/chan`
+    pub fn pin(&self, prefix: &str) -> bool {
+        let prefix = prefix.trim_matches('/').to_string();
+        if prefix.is_empty() {
+            return false;
+        }
+        let mut prefixes = self.0.write().unwrap();
+        if prefixes.contains(&prefix) {
+            return false;
+        }
+        prefixes.push(prefix);
+        true
+    }
+
+    /// Currently pinned prefixes.
+    pub fn prefixes(&self) -> Vec<String> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+/// `LIKE` pattern matching the cube keys under a prefix like `col/exp`.
+/// Wildcards in the prefix are escaped with `\`.
+fn key_pattern(prefix: &str) -> String {
+    let escaped = prefix
+        .trim_matches('/')
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("/{}/%", escaped)
+}
+
 /// Provides an API for maintaining cache metadata via SQLite.
 pub struct SqliteCacheInterface {
-    /// The connection to the DB.
-    connection: SqliteConnection,
+    /// Connections to the DB, possibly shared with other threads.
+    pool: Arc<ConnectionPool>,
     /// id of the cache root in the `cache_roots` table.  Need for inserts into
     /// `cuboids` table.
     cache_root_id: i32,
     /// Store all cache roots encountered during execution.
     cache_root_map: HashMap<i32, String>,
-    /// The byte length of the CUBOID_ROOT_PATH.
-    path_len: usize,
+    /// Roots that logged cuboids may be under, with their ids, starting
+    /// with CUBOID_ROOT_PATH.
+    roots: Vec<(String, i32)>,
     /// Removes cuboids from the file system.
     file: Rc<dyn FileRemover>,
+    /// Cuboids of these channels are never selected for removal.
+    pinned: PinnedChannels,
+    /// Hashed channel directories, if channels are cached under hashed
+    /// names rather than `col/exp/chan`.
+    channel_keys: Option<Arc<ChannelKeys>>,
+    /// Partly written cuboids, which are dropped along with the cuboids
+    /// they're of.
+    write_buffer: Option<Arc<WriteBuffer>>,
+    /// Retries of failed removals in `clean_cache`.
+    retry: RemovalRetry,
+    /// Removals to retry, in `retry_removals`.
+    pending: Vec<PendingRemoval>,
 }
 
 impl LeastRecentlyUsed for SqliteCacheInterface {
     fn find_lru(&self, num: u32) -> Vec<Cuboid> {
         use schema::cuboids::dsl::*;
-        cuboids
+        self.unpinned()
+            .order(last_accessed)
+            .limit(num as i64)
+            .load::<Cuboid>(&*self.connection())
+            .expect("Error getting LRU cuboids")
+    }
+
+    fn find_lru_before(&self, num: u32, cutoff: NaiveDateTime) -> Vec<Cuboid> {
+        use schema::cuboids::dsl::*;
+        self.unpinned()
+            .filter(last_accessed.lt(cutoff))
+            .filter(created.lt(cutoff))
             .order(last_accessed)
             .limit(num as i64)
-            .load::<Cuboid>(&self.connection)
+            .load::<Cuboid>(&*self.connection())
             .expect("Error getting LRU cuboids")
     }
 }
 
+impl CuboidCatalog for SqliteCacheInterface {
+    fn all_cuboids(&self) -> Vec<Cuboid> {
+        self.unpinned()
+            .load::<Cuboid>(&*self.connection())
+            .expect("Error getting cuboids")
+    }
+}
+
+/// How to break down usage statistics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsageGrouping {
+    Collection,
+    Channel,
+}
+
+impl UsageGrouping {
+    /// Parse `collection` or `channel`.
+    pub fn parse(name: &str) -> Option<UsageGrouping> {
+        match name {
+            "collection" => Some(UsageGrouping::Collection),
+            "channel" => Some(UsageGrouping::Channel),
+            _ => None,
+        }
+    }
+
+    /// Number of leading cube key segments that name a group.
+    fn depth(self) -> usize {
+        match self {
+            UsageGrouping::Collection => 1,
+            UsageGrouping::Channel => 3,
+        }
+    }
+}
+
+/// Requests for a single cuboid.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CuboidUsage {
+    pub key: String,
+    pub requests: i64,
+}
+
+/// Requests for every cuboid of a collection or channel.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct GroupUsage {
+    /// Like `col`, or `col/exp/chan` when grouped by channel.
+    pub name: String,
+    pub cuboids: i64,
+    pub requests: i64,
+}
+
+/// Summary of the cuboids accessed within a window.  Request counts are
+/// totals since each cuboid was cached, not just within the window.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct UsageStats {
+    pub cuboids: i64,
+    pub total_requests: i64,
+    /// Most requested cuboids first.
+    pub top: Vec<CuboidUsage>,
+    /// Most requested groups first.
+    pub groups: Vec<GroupUsage>,
+}
+
+/// How big the cache is.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// Cuboids tracked in the DB, other than `empty` ones.
+    pub cuboids: i64,
+    /// Total size of their files.  Files that have gone missing count as
+    /// empty.
+    pub bytes: u64,
+    /// Cuboids whose files are empty, e.g. left by an interrupted write.
+    /// They hold no data, so they aren't counted as cached.
+    pub empty: i64,
+}
+
+/// A cached cuboid that failed verification.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CorruptCuboid {
+    pub path: String,
+    /// Why it failed, e.g. a wrong length.
+    pub reason: String,
+}
+
+/// Outcome of verifying a page of cached cuboids.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    /// Number of cuboids checked.
+    pub checked: u32,
+    pub corrupt: Vec<CorruptCuboid>,
+    /// Cuboids in the DB whose files are gone.
+    pub missing: Vec<String>,
+    /// Bad cuboids removed from the cache.
+    pub evicted: u32,
+    /// Where the next page starts, or `None` once every cuboid is checked.
+    pub next: Option<i64>,
+}
+
+/// Outcome of compacting a page of cached cuboids.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct CompactReport {
+    /// Number of cuboids checked.
+    pub checked: u32,
+    /// All-zero cuboids removed from the cache.
+    pub removed: u32,
+    /// Bytes of cuboid files freed.
+    pub reclaimed: u64,
+    /// Where the next page starts, or `None` once every cuboid is checked.
+    pub next: Option<i64>,
+}
+
+/// SQL for the first `depth` segments of a cube key, e.g. `col/exp` for
+/// depth 2.  Keys look like `/col/exp/chan/res/...`.
+fn key_prefix_sql(depth: usize) -> String {
+    // Each segment ends at the first `/` of what's left after the previous
+    // ones.
+    let mut rest = "substr(cube_key, 2)".to_string();
+    let mut ends = vec![];
+    for _ in 0..depth {
+        let end = format!("instr({}, '/')", rest);
+        rest = format!("substr({}, {} + 1)", rest, end);
+        ends.push(end);
+    }
+    format!("substr(cube_key, 2, {} - 1)", ends.join(" + "))
+}
+
 diesel_migrations::embed_migrations!();
 
+/// Bring the DB's schema up to date.
+///
+/// # Arguments
+///
+/// * `pool` - Connections to the Sqlite DB
+pub fn run_migrations(pool: &ConnectionPool) {
+    embedded_migrations::run(&*pool.get()).expect("Error running database migrations");
+}
+
+/// Open a pool of connections to a DB and bring its schema up to date,
+/// retrying both while the DB is locked.
+///
+/// # Arguments
+///
+/// * `db_url` - Connection string for the Sqlite DB
+/// * `size` - Number of connections to open (at least one)
+/// * `pragmas` - Settings for every connection
+/// * `retry` - How often to retry, and how long to wait in between
+///
+pub fn open_pool(
+    db_url: &str,
+    size: u32,
+    pragmas: &Pragmas,
+    retry: ConnectRetry,
+) -> Result<ConnectionPool, String> {
+    let removal = RemovalRetry {
+        retries: retry.retries,
+        backoff: retry.backoff,
+    };
+    removal.run(&format!("open {}", db_url), || {
+        let pool = ConnectionPool::with_pragmas(db_url, size, pragmas)
+            .map_err(|e| format!("Error connecting to {}: {}", db_url, e))?;
+        embedded_migrations::run(&*pool.get())
+            .map_err(|e| format!("Error running database migrations: {}", e))?;
+        Ok(pool)
+    })
+}
+
 impl SqliteCacheInterface {
     /// Constructor.
     ///
@@ -249,39 +915,512 @@ impl SqliteCacheInterface {
     /// * `db_url` - Connection string for the Sqlite DB
     /// * `strategy` - Logic for managing size of cache.
     pub fn new(db_url: &str) -> SqliteCacheInterface {
-        let connection =
-            SqliteConnection::establish(db_url).expect(&format!("Error connecting to {}", db_url));
-        embedded_migrations::run(&connection).expect("Error running database migrations");
-        SqliteCacheInterface::init(connection, Rc::new(RealFileRemover {}))
+        let pool = open_pool(db_url, 1, &Pragmas::default(), ConnectRetry::default())
+            .unwrap_or_else(|e| panic!("{}", e));
+        SqliteCacheInterface::init(Arc::new(pool), Rc::new(RealFileRemover {}))
+    }
+
+    /// Constructor that shares an existing pool of connections, so several
+    /// interfaces (possibly on different threads) can use the same DB.
+    ///
+    /// # Arguments:
+    ///
+    /// * `pool` - Connections to the Sqlite DB
+    pub fn with_pool(pool: Arc<ConnectionPool>) -> SqliteCacheInterface {
+        run_migrations(&pool);
+        SqliteCacheInterface::init(pool, Rc::new(RealFileRemover {}))
     }
 
     /// Completes setup of the manager.  Called directly by the `new()` constructor.
     ///
     /// # Arguments:
     ///
-    /// * `connection` - Open Sqlite connection
+    /// * `pool` - Connections to the Sqlite DB
     /// * `file_remover` - Used to remove cuboids from the file system.
-    fn init(
-        connection: SqliteConnection,
-        file_remover: Rc<dyn FileRemover>,
-    ) -> SqliteCacheInterface {
-        let cache_root_id = SqliteCacheInterface::get_cache_root_id(&connection);
+    fn init(pool: Arc<ConnectionPool>, file_remover: Rc<dyn FileRemover>) -> SqliteCacheInterface {
+        let cache_root_id = SqliteCacheInterface::get_cache_root_id(
+            &pool.get(),
+            &config::get_cuboid_root_abs_path(),
+        );
         let mut cache_root_map = HashMap::new();
         cache_root_map.insert(cache_root_id, config::CUBOID_ROOT_PATH.to_string());
-        let path_len = config::CUBOID_ROOT_PATH.len();
+        let roots = vec![(config::CUBOID_ROOT_PATH.to_string(), cache_root_id)];
         let file = file_remover;
 
         return SqliteCacheInterface {
-            connection,
+            pool,
             cache_root_id,
             cache_root_map,
-            path_len,
+            roots,
             file,
+            pinned: PinnedChannels::default(),
+            channel_keys: None,
+            write_buffer: None,
+            retry: RemovalRetry::default(),
+            pending: Vec::new(),
+        };
+    }
+
+    /// Never select cuboids of these channels for removal.
+    ///
+    /// # Arguments
+    ///
+    /// * `pinned` - Pinned channel prefixes, possibly shared
+    pub fn set_pinned(&mut self, pinned: PinnedChannels) {
+        self.pinned = pinned;
+    }
+
+    /// Look up channel prefixes through these hashed channel directories.
+    pub fn set_channel_keys(&mut self, keys: Arc<ChannelKeys>) {
+        self.channel_keys = Some(keys);
+    }
+
+    /// `LIKE` patterns matching the cube keys under a prefix like `col/exp`.
+    /// Keys look like `/col/exp/chan/res/...`, or `/<hash>/res/...` for
+    /// hashed channel directories, in which case there's a pattern for each
+    /// recorded channel under the prefix.
+    fn key_patterns(&self, prefix: &str) -> Vec<String> {
+        match &self.channel_keys {
+            Some(keys) => keys
+                .keys_under(prefix)
+                .iter()
+                .map(|hash| key_pattern(hash))
+                .collect(),
+            None => vec![key_pattern(prefix)],
+        }
+    }
+
+    /// Drop a shared write buffer's copies of cuboids removed from the
+    /// cache, so they aren't written back afterwards.
+    pub fn set_write_buffer(&mut self, buffer: Arc<WriteBuffer>) {
+        self.write_buffer = Some(buffer);
+    }
+
+    /// Retry failed removals this way when cleaning the cache.
+    pub fn set_removal_retry(&mut self, retry: RemovalRetry) {
+        self.retry = retry;
+    }
+
+    /// Query for every cuboid that isn't pinned.
+    fn unpinned(&self) -> schema::cuboids::BoxedQuery<'static, Sqlite> {
+        use schema::cuboids::dsl::*;
+        let mut query = cuboids.into_boxed();
+        for prefix in self.pinned.prefixes() {
+            for pattern in self.key_patterns(&prefix) {
+                query = query.filter(cube_key.not_like(pattern).escape('\\'));
+            }
+        }
+        query
+    }
+
+    /// Check out a connection from the pool.
+    fn connection(&self) -> PooledConnection<'_> {
+        self.pool.get()
+    }
+
+    /// Find every cuboid last accessed before the given time, except pinned
+    /// ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `cutoff` - Cuboids accessed at or after this time are skipped
+    pub fn find_accessed_before(&self, cutoff: NaiveDateTime) -> Vec<Cuboid> {
+        use schema::cuboids::dsl::*;
+        self.unpinned()
+            .filter(last_accessed.lt(cutoff))
+            .order(last_accessed)
+            .load::<Cuboid>(&*self.connection())
+            .expect("Error getting cuboids")
+    }
+
+    /// Remove every cuboid last accessed before the given time from the
+    /// cache.  Returns the number of cuboids successfully removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `cutoff` - Cuboids accessed at or after this time are kept
+    pub fn purge_older_than(&mut self, cutoff: NaiveDateTime) -> u32 {
+        let unwanted = self.find_accessed_before(cutoff);
+        self.clean_cache(unwanted)
+    }
+
+    /// Find every cuboid whose file is empty, e.g. left by an interrupted
+    /// write, except pinned ones.  Stats every file, so it's slow for a
+    /// large cache.
+    pub fn find_empty(&mut self) -> Vec<Cuboid> {
+        let all = self
+            .unpinned()
+            .load::<Cuboid>(&*self.connection())
+            .expect("Error getting cuboids");
+        all.into_iter()
+            .filter(|cuboid| {
+                self.get_cache_root_path_from_map(cuboid.cache_root)
+                    .and_then(|root_path| {
+                        fs::metadata(format!("{}{}", root_path, cuboid.cube_key)).ok()
+                    })
+                    .map_or(false, |m| m.len() == 0)
+            })
+            .collect()
+    }
+
+    /// Number of cuboids in the cache.
+    pub fn num_cuboids(&self) -> u32 {
+        use schema::cuboids::dsl::*;
+        cuboids
+            .count()
+            .get_result::<i64>(&*self.connection())
+            .expect("Error counting cuboids") as u32
+    }
+
+    /// Every cached cuboid under a prefix like `col/exp/chan`, pinned or
+    /// not, with the path of its file, in the order they were cached.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - Leading segments of the cube keys
+    pub fn find_under(&mut self, prefix: &str) -> Vec<(String, Cuboid)> {
+        use schema::cuboids::dsl::*;
+        let patterns = self.key_patterns(prefix);
+        if patterns.is_empty() {
+            return Vec::new();
+        }
+        let mut query = cuboids.into_boxed();
+        for pattern in patterns {
+            query = query.or_filter(cube_key.like(pattern).escape('\\'));
+        }
+        let found = query
+            .order(id)
+            .load::<Cuboid>(&*self.connection())
+            .expect("Error getting cuboids");
+        found
+            .into_iter()
+            .filter_map(|cuboid| {
+                let root_path = self.get_cache_root_path_from_map(cuboid.cache_root)?;
+                Some((format!("{}{}", root_path, cuboid.cube_key), cuboid))
+            })
+            .collect()
+    }
+
+    /// Count the cached cuboids and add up the size of their files.  Stats
+    /// every file, so it's slow for a large cache.
+    pub fn stats(&mut self) -> CacheStats {
+        use schema::cuboids::dsl::*;
+        let rows = cuboids
+            .select((cache_root, cube_key))
+            .load::<(i32, String)>(&*self.connection())
+            .expect("Error getting cuboids");
+        let mut stats = CacheStats::default();
+        for (root_id, key) in rows {
+            let len = self
+                .get_cache_root_path_from_map(root_id)
+                .and_then(|root_path| fs::metadata(format!("{}{}", root_path, key)).ok())
+                .map(|m| m.len());
+            if len == Some(0) {
+                stats.empty += 1;
+            } else {
+                stats.cuboids += 1;
+                stats.bytes += len.unwrap_or(0);
+            }
+        }
+        stats
+    }
+
+    /// Track a cuboid copied into the cache from elsewhere, keeping its
+    /// usage history.  Returns false if it was already tracked, in which
+    /// case its row is left alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Path of the cuboid's file, under a known cache root
+    /// * `num_requests` - Requests for the cuboid so far
+    /// * `created_at` - When it was first cached
+    /// * `accessed_at` - When it was last requested
+    pub fn import_cuboid(
+        &mut self,
+        filename: &str,
+        num_requests: i64,
+        created_at: NaiveDateTime,
+        accessed_at: NaiveDateTime,
+    ) -> QueryResult<bool> {
+        use diesel::result::{DatabaseErrorKind, Error};
+        use schema::cuboids::dsl::*;
+        let (root_id, remainder) = self.split_root(filename);
+        let connection = self.connection();
+        let new_cuboid = NewCuboid {
+            cache_root: root_id,
+            cube_key: remainder.to_string(),
+            requests: num_requests,
         };
+        match diesel::insert_into(cuboids)
+            .values(&new_cuboid)
+            .execute(&*connection)
+        {
+            Ok(_) => (),
+            Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => return Ok(false),
+            Err(err) => return Err(err),
+        }
+        diesel::update(
+            cuboids
+                .filter(cube_key.eq(remainder))
+                .filter(cache_root.eq(root_id)),
+        )
+        .set((created.eq(created_at), last_accessed.eq(accessed_at)))
+        .execute(&*connection)?;
+        Ok(true)
+    }
+
+    /// Summarize usage of the cuboids accessed since the given time.
+    ///
+    /// # Arguments
+    ///
+    /// * `since` - Cuboids last accessed before this time are skipped
+    /// * `top` - Number of most requested cuboids to list
+    /// * `grouping` - Whether to break down by collection or channel
+    pub fn usage_stats(
+        &self,
+        since: NaiveDateTime,
+        top: i64,
+        grouping: UsageGrouping,
+    ) -> UsageStats {
+        use diesel::dsl::sql;
+        use diesel::sql_types::{BigInt, Text};
+        use schema::cuboids::dsl::*;
+
+        let connection = self.connection();
+        let (num_cuboids, total_requests) = cuboids
+            .filter(last_accessed.ge(since))
+            .select((
+                sql::<BigInt>("count(*)"),
+                sql::<BigInt>("coalesce(sum(requests), 0)"),
+            ))
+            .first::<(i64, i64)>(&*connection)
+            .expect("Error summing requests");
+        let top_cuboids = cuboids
+            .filter(last_accessed.ge(since))
+            .select((cube_key, requests))
+            .order((requests.desc(), cube_key))
+            .limit(top)
+            .load::<(String, i64)>(&*connection)
+            .expect("Error getting most requested cuboids");
+        let group = key_prefix_sql(grouping.depth());
+        let groups = cuboids
+            .filter(last_accessed.ge(since))
+            .select((
+                sql::<Text>(&group),
+                sql::<BigInt>("count(*)"),
+                sql::<BigInt>("sum(requests)"),
+            ))
+            .group_by(sql::<Text>(&group))
+            .order((sql::<BigInt>("sum(requests)").desc(), sql::<Text>(&group)))
+            .load::<(String, i64, i64)>(&*connection)
+            .expect("Error grouping requests");
+
+        UsageStats {
+            cuboids: num_cuboids,
+            total_requests,
+            top: top_cuboids
+                .into_iter()
+                .map(|(key, count)| CuboidUsage {
+                    key,
+                    requests: count,
+                })
+                .collect(),
+            groups: groups
+                .into_iter()
+                .map(|(name, count, total)| GroupUsage {
+                    name,
+                    cuboids: count,
+                    requests: total,
+                })
+                .collect(),
+        }
+    }
+
+    /// Check that cached cuboids are intact: that each file exists, is a
+    /// complete cuboid, and (if its hash is known) still matches its hash.
+    /// Checks one page of cuboids, in the order they were cached, so large
+    /// caches can be verified a page at a time.
+    ///
+    /// # Arguments
+    ///
+    /// * `after` - Only check cuboids after this one (the previous page's `next`)
+    /// * `limit` - Max number of cuboids to check
+    /// * `hashes` - Remembered hashes of cuboid files
+    /// * `evict` - Remove bad cuboids from the cache
+    pub fn verify(
+        &mut self,
+        after: i64,
+        limit: i64,
+        hashes: &CuboidHashes,
+        evict: bool,
+    ) -> VerifyReport {
+        use schema::cuboids::dsl::*;
+        let page = cuboids
+            .filter(id.gt(after))
+            .order(id)
+            .limit(limit)
+            .load::<Cuboid>(&*self.connection())
+            .expect("Error getting cuboids");
+
+        let mut report = VerifyReport::default();
+        // Cuboid sizes of the channels seen so far:
+        let mut sizes = HashMap::new();
+        if page.len() as i64 == limit {
+            report.next = page.last().map(|cuboid| cuboid.id);
+        }
+        for cuboid in page {
+            report.checked += 1;
+            let root_path = match self.get_cache_root_path_from_map(cuboid.cache_root) {
+                Some(root_path) => root_path,
+                None => continue,
+            };
+            let filename = format!("{}{}", root_path, cuboid.cube_key);
+            let path = Path::new(&filename);
+            let reason = if !path.exists() {
+                if evict && self.remove_cuboid_entry(cuboid.id).is_ok() {
+                    self.discard_buffered(&filename);
+                    report.evicted += 1;
+                }
+                report.missing.push(filename);
+                continue;
+            } else if !cuboid_file::is_complete(
+                path,
+                self.cuboid_size_of(&cuboid.cube_key, &mut sizes),
+            ) {
+                "not a complete cuboid"
+            } else if hashes.verify(&filename) == Some(false) {
+                "contents don't match their hash"
+            } else {
+                continue;
+            };
+            if evict
+                && self.remove_cuboid_file(&filename).is_ok()
+                && self.remove_cuboid_entry(cuboid.id).is_ok()
+            {
+                report.evicted += 1;
+            }
+            report.corrupt.push(CorruptCuboid {
+                path: filename,
+                reason: reason.to_string(),
+            });
+        }
+        report
+    }
+
+    /// Reclaim space by removing cached cuboids whose voxels are all zero,
+    /// e.g. from writeback of sparse annotation channels.  They're read as
+    /// misses from then on, so are fetched again if they're ever needed.
+    /// Pinned cuboids are left alone.  Compacts one page of cuboids, in the
+    /// order they were cached, like `verify`.
+    ///
+    /// # Arguments
+    ///
+    /// * `after` - Only check cuboids after this one (the previous page's `next`)
+    /// * `limit` - Max number of cuboids to check
+    pub fn compact(&mut self, after: i64, limit: i64) -> CompactReport {
+        use schema::cuboids::dsl::*;
+        let page = self
+            .unpinned()
+            .filter(id.gt(after))
+            .order(id)
+            .limit(limit)
+            .load::<Cuboid>(&*self.connection())
+            .expect("Error getting cuboids");
+
+        let mut report = CompactReport::default();
+        // Cuboid sizes of the channels seen so far:
+        let mut sizes = HashMap::new();
+        if page.len() as i64 == limit {
+            report.next = page.last().map(|cuboid| cuboid.id);
+        }
+        for cuboid in page {
+            report.checked += 1;
+            let root_path = match self.get_cache_root_path_from_map(cuboid.cache_root) {
+                Some(root_path) => root_path,
+                None => continue,
+            };
+            let filename = format!("{}{}", root_path, cuboid.cube_key);
+            let size = self.cuboid_size_of(&cuboid.cube_key, &mut sizes);
+            match cuboid_file::remove_if_zero(Path::new(&filename), size) {
+                Ok(0) => {}
+                Ok(freed) => {
+                    report.removed += 1;
+                    report.reclaimed += freed;
+                    if let Err(err) = self.remove_cuboid_entry(cuboid.id) {
+                        println!("Failed to remove {} from DB: {}", filename, err);
+                    }
+                }
+                // Missing files are left to `verify`:
+                Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => println!("Failed to compact {}: {}", filename, err),
+            }
+        }
+        report
+    }
+
+    /// Cuboid size of the channel that a cuboid belongs to, as registered in
+    /// the `channels` table, or the default size for unregistered channels.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The cuboid's `cube_key`, like `/col/exp/chan/0/...`
+    /// * `sizes` - Sizes already looked up, by channel
+    fn cuboid_size_of(&self, key: &str, sizes: &mut HashMap<String, Vector3>) -> Vector3 {
+        let parts: Vec<&str> = key.trim_start_matches('/').splitn(4, '/').collect();
+        if parts.len() < 4 {
+            return config::CUBOID_SIZE;
+        }
+        let name = parts[..3].join("/");
+        if let Some(size) = sizes.get(&name) {
+            return *size;
+        }
+        use schema::channels::dsl::*;
+        let size = channels
+            .select((cuboid_x, cuboid_y, cuboid_z))
+            .filter(collection.eq(parts[0]))
+            .filter(experiment.eq(parts[1]))
+            .filter(channel.eq(parts[2]))
+            .first::<(i64, i64, i64)>(&*self.connection())
+            .map(|(x, y, z)| Vector3 {
+                x: x as u64,
+                y: y as u64,
+                z: z as u64,
+            })
+            .unwrap_or(config::CUBOID_SIZE);
+        sizes.insert(name, size);
+        size
+    }
+
+    /// Remove least recently used cuboids until the cache holds at most
+    /// `target` cuboids.  Returns the number of cuboids removed.  Stops
+    /// early if a round removes nothing (e.g. the files can't be deleted),
+    /// so it always terminates.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Max number of cuboids to leave in the cache
+    pub fn evict_to(&mut self, target: u32) -> u32 {
+        let mut evicted = 0;
+        loop {
+            let count = self.num_cuboids();
+            if count <= target {
+                break;
+            }
+            let unwanted = self.find_lru(count - target);
+            let removed = self.clean_cache(unwanted);
+            if removed == 0 {
+                break;
+            }
+            evicted += removed;
+        }
+        evicted
     }
 
     /// Remove the given list of cuboids from the cache.  Returns the number of
-    /// cuboids successfully removed.
+    /// cuboids successfully removed.  Removals that fail transiently are
+    /// left for `retry_removals` (see `set_removal_retry`), rather than
+    /// waiting here.  A cuboid whose file is already gone has its row
+    /// removed anyway, so the count of cuboids doesn't drift.
     ///
     /// # Arguments
     ///
@@ -290,22 +1429,27 @@ impl SqliteCacheInterface {
         let mut remove_count: u32 = 0;
 
         for cuboid in unwanted.iter() {
+            if self.pending.iter().any(|pending| pending.id == cuboid.id) {
+                continue;
+            }
             let root_path = self.get_cache_root_path_from_map(cuboid.cache_root);
             if root_path.is_some() {
-                let root_path = root_path.unwrap();
-                if self
-                    .remove_cuboid_file(&format!("{}{}", root_path, cuboid.cube_key))
-                    .is_err()
-                {
-                    // ToDo: write to log.
-                    println!("Error removing {}{}", root_path, cuboid.cube_key);
-                    continue;
-                }
-                if self.remove_cuboid_entry(cuboid.id).is_ok() {
-                    remove_count += 1;
-                } else {
-                    // ToDo: write to log.
-                    println!("Error removing {} from DB", cuboid.cube_key);
+                let filename = format!("{}{}", root_path.unwrap(), cuboid.cube_key);
+                match self.remove_cuboid(cuboid.id, &cuboid.cube_key, &filename) {
+                    Ok(()) => remove_count += 1,
+                    Err(failure) => {
+                        // ToDo: write to log.
+                        println!("{}", failure.message);
+                        if failure.transient && self.retry.retries > 0 {
+                            self.pending.push(PendingRemoval {
+                                id: cuboid.id,
+                                cube_key: cuboid.cube_key.clone(),
+                                filename,
+                                due: Instant::now() + self.retry.wait_before(0),
+                                retries: 0,
+                            });
+                        }
+                    }
                 }
             }
         }
@@ -313,31 +1457,104 @@ impl SqliteCacheInterface {
         remove_count
     }
 
-    /// Looks up the id of the cache root
+    /// Retry the removals left by `clean_cache` that are due, giving up on
+    /// those out of retries.  Returns the number of cuboids removed.
+    pub fn retry_removals(&mut self) -> u32 {
+        let now = Instant::now();
+        let (due, waiting) = self.pending.drain(..).partition(|p| p.due <= now);
+        self.pending = waiting;
+        let mut remove_count = 0;
+        for mut pending in due {
+            match self.remove_cuboid(pending.id, &pending.cube_key, &pending.filename) {
+                Ok(()) => remove_count += 1,
+                Err(failure) => {
+                    pending.retries += 1;
+                    if failure.transient && pending.retries < self.retry.retries {
+                        println!("{}; retrying", failure.message);
+                        pending.due = now + self.retry.wait_before(pending.retries);
+                        self.pending.push(pending);
+                    } else {
+                        println!("{}; giving up", failure.message);
+                    }
+                }
+            }
+        }
+        remove_count
+    }
+
+    /// Remove a cuboid's file, then its row.
+    fn remove_cuboid(&self, id: i64, cube_key: &str, filename: &str) -> Result<(), RemovalFailure> {
+        match self.remove_cuboid_file(filename) {
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(RemovalFailure::file(filename, err)),
+            Ok(()) => (),
+        }
+        self.remove_cuboid_entry(id)
+            .map_err(|err| RemovalFailure::entry(cube_key, err))
+    }
+
+    /// Looks up the id of a cache root, adding it if it's new.
     ///
     /// # Arguments
     ///
     /// * `connection` - Open connection to the DB
-    fn get_cache_root_id(connection: &SqliteConnection) -> i32 {
+    /// * `abs_path` - Absolute path of the cache root
+    fn get_cache_root_id(connection: &SqliteConnection, abs_path: &str) -> i32 {
         use schema::cache_roots::dsl::*;
-        let row: Result<CacheRoot, diesel::result::Error> = cache_roots
-            .filter(path.eq(config::get_cuboid_root_abs_path()))
-            .get_result(connection);
+        let row: Result<CacheRoot, diesel::result::Error> =
+            cache_roots.filter(path.eq(abs_path)).get_result(connection);
         match row {
             Ok(row) => row.id,
             Err(_) => {
                 let row = NewCacheRoot {
-                    path: config::get_cuboid_root_abs_path(),
+                    path: abs_path.to_string(),
                 };
                 diesel::insert_into(cache_roots)
                     .values(row)
                     .execute(connection)
                     .expect("Could not update database");
-                SqliteCacheInterface::get_cache_root_id(connection)
+                SqliteCacheInterface::get_cache_root_id(connection, abs_path)
             }
         }
     }
 
+    /// Track cuboids stored under another cache root (e.g. one for a
+    /// particular resolution), besides CUBOID_ROOT_PATH.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The root, as it appears at the start of cuboid filenames
+    pub fn add_cache_root(&mut self, root: &str) {
+        let root = root.trim_end_matches('/').to_string();
+        if self.roots.iter().any(|(r, _)| *r == root) {
+            return;
+        }
+        let root_id =
+            SqliteCacheInterface::get_cache_root_id(&self.connection(), &config::abs_path(&root));
+        self.cache_root_map.insert(root_id, root.clone());
+        self.roots.push((root, root_id));
+    }
+
+    /// Split a cuboid filename into the id of its cache root and its key
+    /// under that root.  Filenames under no known root are assumed to be
+    /// under CUBOID_ROOT_PATH.
+    fn split_root<'a>(&self, filename: &'a str) -> (i32, &'a str) {
+        let under =
+            |root: &str| filename.starts_with(root) && filename[root.len()..].starts_with('/');
+        match self
+            .roots
+            .iter()
+            .filter(|(root, _)| under(root))
+            .max_by_key(|(root, _)| root.len())
+        {
+            Some((root, root_id)) => (*root_id, &filename[root.len()..]),
+            None => (
+                self.cache_root_id,
+                &filename[config::CUBOID_ROOT_PATH.len()..],
+            ),
+        }
+    }
+
     /// Get the cache root path from the internal hashmap.  If it doesn't
     /// exist in the hashmap, load the path from the DB and add it to the
     /// hashmap.
@@ -353,11 +1570,11 @@ impl SqliteCacheInterface {
         }
 
         // Get path from the DB and add to `cache_root_map`.
-        match cache_roots
+        let row = cache_roots
             .select(path)
             .filter(id.eq(root_id))
-            .get_result::<String>(&self.connection)
-        {
+            .get_result::<String>(&*self.connection());
+        match row {
             Ok(root_path) => {
                 self.cache_root_map.insert(root_id, root_path.to_string());
                 Some(root_path)
@@ -367,19 +1584,28 @@ impl SqliteCacheInterface {
     }
 
     fn log_request(&self, key: String) -> bool {
+        use diesel::result::{DatabaseErrorKind, Error};
         use schema::cuboids::dsl::*;
 
         // Strip off the root folder because the root, itself, is stored in
         // the `cache_roots` table.
-        let (_root, remainder) = &key.split_at(self.path_len);
+        let (root_id, remainder) = self.split_root(&key);
+        let connection = self.connection();
 
-        match diesel::update(cuboids.filter(cube_key.eq(remainder)))
+        let update = || {
+            diesel::update(
+                cuboids
+                    .filter(cube_key.eq(remainder))
+                    .filter(cache_root.eq(root_id)),
+            )
             .set((
                 requests.eq(requests + 1),
                 last_accessed.eq(Utc::now().naive_utc().to_string()),
             ))
-            .execute(&self.connection)
-        {
+            .execute(&*connection)
+        };
+
+        match update() {
             Err(err) => {
                 println!("Error updating DB: {}", err);
                 false
@@ -389,15 +1615,23 @@ impl SqliteCacheInterface {
                     return false;
                 }
                 let new_request = NewCuboid {
-                    cache_root: self.cache_root_id,
+                    cache_root: root_id,
                     cube_key: remainder.to_string(),
                     requests: 1,
                 };
                 match diesel::insert_into(cuboids)
                     .values(&new_request)
-                    .execute(&self.connection)
+                    .execute(&*connection)
                 {
                     Ok(_) => true,
+                    // Another connection inserted it first, so count this
+                    // request against its row instead:
+                    Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                        if let Err(err) = update() {
+                            println!("Error updating DB: {}", err);
+                        }
+                        false
+                    }
                     Err(err) => {
                         println!("insert failed: {}", err);
                         false
@@ -414,7 +1648,7 @@ impl SqliteCacheInterface {
     /// * `cuboid_id` - Cuboid's id in the DB
     fn remove_cuboid_entry(&self, cuboid_id: i64) -> QueryResult<()> {
         use schema::cuboids::dsl::*;
-        diesel::delete(cuboids.filter(id.eq(cuboid_id))).execute(&self.connection)?;
+        diesel::delete(cuboids.filter(id.eq(cuboid_id))).execute(&*self.connection())?;
         Ok(())
     }
 
@@ -427,6 +1661,19 @@ impl SqliteCacheInterface {
         let path = Path::new(cuboid_path);
         //fs::remove_file(path)?;
         self.file.remove(path)?;
+        self.discard_buffered(cuboid_path);
         Ok(())
     }
+
+    /// Drop the write buffer's copy of a removed or replaced cuboid, if
+    /// there is one.
+    ///
+    /// # Arguments
+    ///
+    /// * `cuboid_path` - Full path to the cuboid
+    pub(crate) fn discard_buffered(&self, cuboid_path: &str) {
+        if let Some(buffer) = &self.write_buffer {
+            buffer.discard(cuboid_path);
+        }
+    }
 }
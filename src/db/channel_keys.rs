@@ -0,0 +1,137 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Hashed channel directories.
+///
+/// Names each channel's directory in the cache by a salted hash of
+/// `col/exp/chan`, so that paths on shared storage (and the cube keys in
+/// the `cuboids` table, which are derived from them) don't reveal which
+/// datasets are cached.  Every hash handed out is recorded with its channel
+/// in the `channel_keys` table, so an operator can still tell the
+/// directories apart.
+use super::pool::ConnectionPool;
+use super::run_migrations;
+use super::schema;
+use diesel::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Length of a channel's hash, in hex digits.
+const HASH_LEN: usize = 32;
+
+/// Maps channels to the hashed names of their directories.
+pub struct ChannelKeys {
+    /// Connections to the DB, possibly shared with other threads.
+    pool: Arc<ConnectionPool>,
+    salt: String,
+    /// Hash of every channel seen, so that lookups don't touch the DB.
+    known: Mutex<HashMap<String, String>>,
+}
+
+impl ChannelKeys {
+    /// Constructor.
+    ///
+    /// # Arguments:
+    ///
+    /// * `pool` - Connections to the Sqlite DB
+    /// * `salt` - Secret mixed into every hash, so that known dataset names
+    ///   can't simply be hashed and looked for
+    pub fn new(pool: Arc<ConnectionPool>, salt: &str) -> ChannelKeys {
+        run_migrations(&pool);
+        ChannelKeys {
+            pool,
+            salt: salt.to_string(),
+            known: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The hashed name of a channel's directory, recording it on first use.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - Like `col/exp/chan`
+    pub fn key(&self, channel: &str) -> String {
+        use schema::channel_keys::dsl;
+        if let Some(hash) = self.known.lock().unwrap().get(channel) {
+            return hash.clone();
+        }
+        let hash = self.hash(channel);
+        if let Err(err) = diesel::insert_or_ignore_into(dsl::channel_keys)
+            .values((dsl::hash.eq(&hash), dsl::channel.eq(channel)))
+            .execute(&*self.pool.get())
+        {
+            println!(
+                "Failed to record the directory {} of channel {}: {}",
+                hash, channel, err
+            );
+        }
+        self.known
+            .lock()
+            .unwrap()
+            .insert(channel.to_string(), hash.clone());
+        hash
+    }
+
+    /// The channel whose directory has a hashed name, if it's been recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `hash` - Name of a channel's directory
+    pub fn channel(&self, hash: &str) -> Option<String> {
+        use schema::channel_keys::dsl;
+        dsl::channel_keys
+            .select(dsl::channel)
+            .filter(dsl::hash.eq(hash))
+            .first::<String>(&*self.pool.get())
+            .ok()
+    }
+
+    /// The hashed names of the directories of every recorded channel under
+    /// a prefix.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - Like `col/exp/chan`, or `col/exp` for a whole experiment
+    pub fn keys_under(&self, prefix: &str) -> Vec<String> {
+        use schema::channel_keys::dsl;
+        let prefix = prefix.trim_matches('/');
+        let under = format!("{}/", prefix);
+        dsl::channel_keys
+            .select((dsl::channel, dsl::hash))
+            .load::<(String, String)>(&*self.pool.get())
+            .expect("Error getting channel keys")
+            .into_iter()
+            .filter(|(channel, _)| channel == prefix || channel.starts_with(&under))
+            .map(|(_, hash)| hash)
+            .collect()
+    }
+
+    fn hash(&self, channel: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(channel.as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()[..HASH_LEN]
+            .to_string()
+    }
+}
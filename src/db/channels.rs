@@ -0,0 +1,438 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Channel registry.
+///
+/// Records each channel's datatype and cuboid size in the `channels` table,
+/// so datatype resolution happens in one place instead of being threaded
+/// through every call.  Channels are looked up from the upstream metadata
+/// on first access and remembered from then on.
+use super::embedded_migrations;
+use super::models::{Channel, NewChannel};
+use super::schema;
+use crate::data_manager::{Coords, Vector3};
+use crate::intern::remote::{BossRemote, ChannelGeometry};
+use diesel::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// How long a channel whose extent, type or geometry couldn't be looked up goes
+/// before it's looked up again, so that every request for it doesn't wait
+/// on the Boss DB host failing again.
+const LOOKUP_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// What the data managers need to know about a channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelInfo {
+    /// Voxel datatype, as named by the BossDB (e.g. `uint8`).
+    pub datatype: String,
+    pub cuboid_size: Vector3,
+}
+
+impl ChannelInfo {
+    /// Size of a voxel in bytes, or `None` for an unknown datatype.
+    pub fn element_size(&self) -> Option<usize> {
+        match self.datatype.as_str() {
+            "uint8" => Some(1),
+            "uint16" => Some(2),
+            "uint32" => Some(4),
+            "uint64" => Some(8),
+            _ => None,
+        }
+    }
+}
+
+/// Somewhere to look up the datatype of a channel that isn't registered yet.
+pub trait ChannelSource {
+    /// Get the datatype of a channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, as `collection/experiment/channel`
+    fn get_datatype(&self, channel: &str) -> Result<String, String>;
+
+    /// Get the extent of a channel at resolution 0, as the start and
+    /// (exclusive) stop of its coordinate frame.  Sources that can't tell
+    /// fail.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, as `collection/experiment/channel`
+    fn get_extent(&self, _channel: &str) -> Result<(Coords, Coords), String> {
+        Err("Extents are unknown".to_string())
+    }
+
+    /// Get the type of a channel, `image` or `annotation`.  Sources that
+    /// can't tell fail.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, as `collection/experiment/channel`
+    fn get_type(&self, _channel: &str) -> Result<String, String> {
+        Err("Channel types are unknown".to_string())
+    }
+
+    /// Get the voxel size and hierarchy of a channel.  Sources that can't
+    /// tell fail.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, as `collection/experiment/channel`
+    fn get_geometry(&self, _channel: &str) -> Result<ChannelGeometry, String> {
+        Err("Channel geometries are unknown".to_string())
+    }
+
+    /// Authenticate lookups with a different token from now on.  Sources
+    /// that don't authenticate ignore it.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The new token
+    fn set_token(&self, _token: &str) {}
+}
+
+/// Looks up channel datatypes in the upstream BossDB's metadata.
+pub struct BossChannelSource {
+    /// Cloned for each lookup, so a new token doesn't wait on lookups in
+    /// flight.
+    remote: RwLock<BossRemote>,
+}
+
+impl BossChannelSource {
+    pub fn new(protocol: String, host: String, token: String) -> BossChannelSource {
+        BossChannelSource {
+            remote: RwLock::new(BossRemote::new(protocol, host, token)),
+        }
+    }
+
+    /// Look up channels in a BossDB whose API isn't under `/v1/`.
+    pub fn set_api_prefix(&mut self, prefix: &str) {
+        self.remote.write().unwrap().set_api_prefix(prefix);
+    }
+
+    fn remote(&self) -> BossRemote {
+        self.remote.read().unwrap().clone()
+    }
+}
+
+impl ChannelSource for BossChannelSource {
+    fn get_datatype(&self, channel: &str) -> Result<String, String> {
+        self.remote()
+            .get_channel_datatype(format!("bossdb://{}", channel))
+    }
+
+    fn get_extent(&self, channel: &str) -> Result<(Coords, Coords), String> {
+        let frame = self
+            .remote()
+            .get_coord_frame(format!("bossdb://{}", channel))?;
+        Ok((frame.start, frame.stop))
+    }
+
+    fn get_type(&self, channel: &str) -> Result<String, String> {
+        self.remote()
+            .get_channel_type(format!("bossdb://{}", channel))
+    }
+
+    fn get_geometry(&self, channel: &str) -> Result<ChannelGeometry, String> {
+        self.remote()
+            .get_channel_geometry(format!("bossdb://{}", channel))
+    }
+
+    fn set_token(&self, token: &str) {
+        self.remote.write().unwrap().set_token(token);
+    }
+}
+
+/// Registered channels, backed by the `channels` table.
+pub struct ChannelRegistry {
+    connection: Mutex<SqliteConnection>,
+    /// Channels already read from the DB.
+    known: Mutex<HashMap<String, ChannelInfo>>,
+    /// Extents of channels at resolution 0, as looked up so far, or when
+    /// the lookup last failed.  These aren't recorded in the DB, so they're
+    /// looked up again on restart.
+    extents: Mutex<HashMap<String, Result<(Coords, Coords), Instant>>>,
+    /// Types of channels, as looked up so far, or when the lookup last
+    /// failed.  Also not recorded.
+    types: Mutex<HashMap<String, Result<String, Instant>>>,
+    /// Geometries of channels, as looked up so far, or when the lookup
+    /// last failed.  Also not recorded.
+    geometries: Mutex<HashMap<String, Result<ChannelGeometry, Instant>>>,
+    source: Box<dyn ChannelSource + Send + Sync>,
+    /// Cuboid size recorded for newly registered channels.
+    cuboid_size: Vector3,
+    /// Configured cuboid sizes of particular channels, which take
+    /// precedence over both the default and what's recorded.
+    cuboid_sizes: HashMap<String, Vector3>,
+}
+
+impl ChannelRegistry {
+    /// Constructor.
+    ///
+    /// # Arguments:
+    ///
+    /// * `db_url` - Connection string for the Sqlite DB
+    /// * `source` - Where to look up channels that aren't registered yet
+    /// * `cuboid_size` - Cuboid size recorded for new channels
+    pub fn new(
+        db_url: &str,
+        source: Box<dyn ChannelSource + Send + Sync>,
+        cuboid_size: Vector3,
+    ) -> ChannelRegistry {
+        let connection =
+            SqliteConnection::establish(db_url).expect(&format!("Error connecting to {}", db_url));
+        embedded_migrations::run(&connection).expect("Error running database migrations");
+        ChannelRegistry::init(connection, source, cuboid_size)
+    }
+
+    /// Completes setup of the registry.  Called directly by the `new()`
+    /// constructor.
+    pub(super) fn init(
+        connection: SqliteConnection,
+        source: Box<dyn ChannelSource + Send + Sync>,
+        cuboid_size: Vector3,
+    ) -> ChannelRegistry {
+        ChannelRegistry {
+            connection: Mutex::new(connection),
+            known: Mutex::new(HashMap::new()),
+            extents: Mutex::new(HashMap::new()),
+            types: Mutex::new(HashMap::new()),
+            geometries: Mutex::new(HashMap::new()),
+            source,
+            cuboid_size,
+            cuboid_sizes: HashMap::new(),
+        }
+    }
+
+    /// Give some channels their own cuboid size, e.g. to match their native
+    /// chunk size upstream.  Channels that are already registered with a
+    /// different size are updated on their next lookup.
+    ///
+    /// # Arguments
+    ///
+    /// * `sizes` - Cuboid sizes, keyed by `collection/experiment/channel`
+    pub fn set_cuboid_sizes(&mut self, sizes: HashMap<String, Vector3>) {
+        self.cuboid_sizes = sizes;
+    }
+
+    /// Look up channels with a different token from now on (see
+    /// `ChannelSource::set_token`).
+    pub fn set_token(&self, token: &str) {
+        self.source.set_token(token);
+    }
+
+    /// Get a channel's info, registering it from the upstream metadata on
+    /// first access.  Returns `None` if the channel isn't registered and
+    /// the upstream lookup fails; nothing is recorded in that case, so the
+    /// lookup is retried next time.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, as `collection/experiment/channel`
+    pub fn get(&self, channel: &str) -> Option<ChannelInfo> {
+        if let Some(info) = self.known.lock().unwrap().get(channel) {
+            return Some(info.clone());
+        }
+
+        let parts: Vec<&str> = channel.split('/').collect();
+        if parts.len() != 3 {
+            return None;
+        }
+        let info = match self.find(parts[0], parts[1], parts[2]) {
+            Some(info) => match self.cuboid_sizes.get(channel) {
+                Some(size) if *size != info.cuboid_size => {
+                    self.update_cuboid_size(parts[0], parts[1], parts[2], *size);
+                    ChannelInfo {
+                        cuboid_size: *size,
+                        ..info
+                    }
+                }
+                _ => info,
+            },
+            None => {
+                let datatype = match self.source.get_datatype(channel) {
+                    Ok(datatype) => datatype,
+                    Err(err) => {
+                        println!("Failed to look up channel {}: {}", channel, err);
+                        return None;
+                    }
+                };
+                self.insert(parts[0], parts[1], parts[2], &datatype)?
+            }
+        };
+
+        self.known
+            .lock()
+            .unwrap()
+            .insert(channel.to_string(), info.clone());
+        Some(info)
+    }
+
+    /// Get a channel's extent at a resolution, in global coordinates,
+    /// looking it up upstream on first access.  Each level halves x and y,
+    /// rounding outwards, and z too if the channel's hierarchy is isotropic
+    /// (see `ChannelGeometry::extent_at`); if its geometry can't be looked
+    /// up, it's taken to be anisotropic.  Returns `None` if the lookup
+    /// fails, as it goes on doing without another lookup for
+    /// `LOOKUP_RETRY_AFTER`.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, as `collection/experiment/channel`
+    /// * `res` - Resolution level
+    pub fn extent(&self, channel: &str, res: u8) -> Option<(Coords, Coords)> {
+        let known = self.extents.lock().unwrap().get(channel).cloned();
+        let (start, stop) = match known {
+            Some(Ok(extent)) => extent,
+            Some(Err(failed)) if failed.elapsed() < LOOKUP_RETRY_AFTER => return None,
+            _ => {
+                let extent = self.source.get_extent(channel);
+                if let Err(err) = &extent {
+                    println!("Failed to look up the extent of {}: {}", channel, err);
+                }
+                let extent = extent.map_err(|_| Instant::now());
+                self.extents
+                    .lock()
+                    .unwrap()
+                    .insert(channel.to_string(), extent);
+                extent.ok()?
+            }
+        };
+        Some(match self.geometry(channel) {
+            Some(geometry) => geometry.extent_at(res, start, stop),
+            None => (start.at_res(res), stop.at_res_rounding_up(res)),
+        })
+    }
+
+    /// Get a channel's type (`image` or `annotation`), looking it up
+    /// upstream on first access.  Returns `None` if the lookup fails, as it
+    /// goes on doing without another lookup for `LOOKUP_RETRY_AFTER`.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, as `collection/experiment/channel`
+    pub fn channel_type(&self, channel: &str) -> Option<String> {
+        match self.types.lock().unwrap().get(channel) {
+            Some(Ok(kind)) => return Some(kind.clone()),
+            Some(Err(failed)) if failed.elapsed() < LOOKUP_RETRY_AFTER => return None,
+            _ => (),
+        }
+        let kind = self.source.get_type(channel);
+        if let Err(err) = &kind {
+            println!("Failed to look up the type of {}: {}", channel, err);
+        }
+        let kind = kind.map_err(|_| Instant::now());
+        self.types
+            .lock()
+            .unwrap()
+            .insert(channel.to_string(), kind.clone());
+        kind.ok()
+    }
+
+    /// Get a channel's voxel size and hierarchy, looking them up upstream
+    /// on first access.  Returns `None` if the lookup fails, as it goes on
+    /// doing without another lookup for `LOOKUP_RETRY_AFTER`.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, as `collection/experiment/channel`
+    pub fn geometry(&self, channel: &str) -> Option<ChannelGeometry> {
+        match self.geometries.lock().unwrap().get(channel) {
+            Some(Ok(geometry)) => return Some(geometry.clone()),
+            Some(Err(failed)) if failed.elapsed() < LOOKUP_RETRY_AFTER => return None,
+            _ => (),
+        }
+        let geometry = self.source.get_geometry(channel);
+        if let Err(err) = &geometry {
+            println!("Failed to look up the geometry of {}: {}", channel, err);
+        }
+        let geometry = geometry.map_err(|_| Instant::now());
+        self.geometries
+            .lock()
+            .unwrap()
+            .insert(channel.to_string(), geometry.clone());
+        geometry.ok()
+    }
+
+    fn find(&self, col: &str, exp: &str, chan: &str) -> Option<ChannelInfo> {
+        use schema::channels::dsl::*;
+        let row = channels
+            .filter(collection.eq(col))
+            .filter(experiment.eq(exp))
+            .filter(channel.eq(chan))
+            .first::<Channel>(&*self.connection.lock().unwrap())
+            .ok()?;
+        Some(ChannelInfo {
+            datatype: row.datatype,
+            cuboid_size: Vector3 {
+                x: row.cuboid_x as u64,
+                y: row.cuboid_y as u64,
+                z: row.cuboid_z as u64,
+            },
+        })
+    }
+
+    fn insert(&self, col: &str, exp: &str, chan: &str, dtype: &str) -> Option<ChannelInfo> {
+        use schema::channels::dsl::*;
+        let size = *self
+            .cuboid_sizes
+            .get(&format!("{}/{}/{}", col, exp, chan))
+            .unwrap_or(&self.cuboid_size);
+        let row = NewChannel {
+            collection: col.to_string(),
+            experiment: exp.to_string(),
+            channel: chan.to_string(),
+            datatype: dtype.to_string(),
+            cuboid_x: size.x as i64,
+            cuboid_y: size.y as i64,
+            cuboid_z: size.z as i64,
+        };
+        if let Err(err) = diesel::insert_into(channels)
+            .values(&row)
+            .execute(&*self.connection.lock().unwrap())
+        {
+            // Most likely another thread registered it first.
+            println!("insert failed: {}", err);
+            return self.find(col, exp, chan);
+        }
+        Some(ChannelInfo {
+            datatype: dtype.to_string(),
+            cuboid_size: size,
+        })
+    }
+
+    fn update_cuboid_size(&self, col: &str, exp: &str, chan: &str, size: Vector3) {
+        use schema::channels::dsl::*;
+        if let Err(err) = diesel::update(
+            channels
+                .filter(collection.eq(col))
+                .filter(experiment.eq(exp))
+                .filter(channel.eq(chan)),
+        )
+        .set((
+            cuboid_x.eq(size.x as i64),
+            cuboid_y.eq(size.y as i64),
+            cuboid_z.eq(size.z as i64),
+        ))
+        .execute(&*self.connection.lock().unwrap())
+        {
+            println!("update failed: {}", err);
+        }
+    }
+}
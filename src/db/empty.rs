@@ -0,0 +1,123 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Negative cache of cuboids.
+///
+/// Records cuboids known to be empty, in the `empty_cuboids` table, so that
+/// a cache with nowhere to fetch missing cuboids from (e.g. an air-gapped
+/// one) can serve them straight from the fill value instead of looking for
+/// their files every time.  An entry is dropped as soon as its cuboid is
+/// written.
+use super::pool::ConnectionPool;
+use super::run_migrations;
+use super::schema;
+use diesel::prelude::*;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// Max number of cuboids recorded as empty.  Past it, more aren't
+/// recorded, so they're looked for on disk as usual.
+pub const MAX_EMPTY_CUBOIDS: usize = 1 << 20;
+
+/// Cuboids known to be empty, by file path.
+pub struct EmptyCuboids {
+    pool: Arc<ConnectionPool>,
+    /// Every entry, so that lookups don't touch the DB.  Held while an
+    /// entry is written to the DB, so the DB sees changes in the same order.
+    known: Mutex<HashSet<String>>,
+    capacity: usize,
+}
+
+impl EmptyCuboids {
+    /// Constructor.
+    ///
+    /// # Arguments:
+    ///
+    /// * `pool` - Connections to the Sqlite DB
+    pub fn new(pool: Arc<ConnectionPool>) -> EmptyCuboids {
+        EmptyCuboids::with_capacity(pool, MAX_EMPTY_CUBOIDS)
+    }
+
+    /// Constructor, recording at most `capacity` cuboids.
+    ///
+    /// # Arguments:
+    ///
+    /// * `pool` - Connections to the Sqlite DB
+    /// * `capacity` - Max number of cuboids recorded as empty
+    pub fn with_capacity(pool: Arc<ConnectionPool>, capacity: usize) -> EmptyCuboids {
+        use schema::empty_cuboids::dsl::*;
+        run_migrations(&pool);
+        let known = empty_cuboids
+            .select(path)
+            .limit(capacity as i64)
+            .load::<String>(&*pool.get())
+            .expect("Error loading empty cuboids");
+        EmptyCuboids {
+            pool,
+            known: Mutex::new(known.into_iter().collect()),
+            capacity,
+        }
+    }
+
+    /// Is a cuboid known to be empty?
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Path of the cuboid's file
+    pub fn contains(&self, filename: &str) -> bool {
+        self.known.lock().unwrap().contains(filename)
+    }
+
+    /// Record that a cuboid is empty, unless `capacity` cuboids already
+    /// are.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Path of the cuboid's file
+    pub fn record(&self, filename: &str) {
+        use schema::empty_cuboids::dsl::*;
+        let mut known = self.known.lock().unwrap();
+        if known.len() >= self.capacity || !known.insert(filename.to_string()) {
+            return;
+        }
+        if let Err(err) = diesel::insert_or_ignore_into(empty_cuboids)
+            .values(path.eq(filename))
+            .execute(&*self.pool.get())
+        {
+            println!("Failed to record empty cuboid {}: {}", filename, err);
+        }
+    }
+
+    /// Forget that a cuboid is empty, e.g. because it was just written.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Path of the cuboid's file
+    pub fn forget(&self, filename: &str) {
+        use schema::empty_cuboids::dsl::*;
+        let mut known = self.known.lock().unwrap();
+        if !known.remove(filename) {
+            return;
+        }
+        if let Err(err) =
+            diesel::delete(empty_cuboids.filter(path.eq(filename))).execute(&*self.pool.get())
+        {
+            println!("Failed to forget empty cuboid {}: {}", filename, err);
+        }
+    }
+}
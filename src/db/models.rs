@@ -20,6 +20,7 @@ use super::schema;
 use chrono::prelude::*;
 use diesel::*;
 use schema::cache_roots;
+use schema::channels;
 use schema::cuboids;
 
 #[derive(Identifiable, Queryable)]
@@ -34,6 +35,30 @@ pub struct NewCacheRoot {
     pub path: String,
 }
 
+#[derive(Debug, Identifiable, PartialEq, Queryable)]
+pub struct Channel {
+    pub id: i32,
+    pub collection: String,
+    pub experiment: String,
+    pub channel: String,
+    pub datatype: String,
+    pub cuboid_x: i64,
+    pub cuboid_y: i64,
+    pub cuboid_z: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "channels"]
+pub struct NewChannel {
+    pub collection: String,
+    pub experiment: String,
+    pub channel: String,
+    pub datatype: String,
+    pub cuboid_x: i64,
+    pub cuboid_y: i64,
+    pub cuboid_z: i64,
+}
+
 #[derive(Debug, Identifiable, Insertable, PartialEq, Queryable)]
 pub struct Cuboid {
     pub id: i64,
@@ -0,0 +1,189 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Connection pool.
+///
+/// A fixed set of SQLite connections shared between threads, so that reads
+/// (e.g. picking cuboids to evict) don't have to wait for one connection
+/// that's busy logging requests.  By default the DB is switched to WAL mode,
+/// which lets readers run alongside the (single) writer, and each connection
+/// waits on a locked DB instead of failing right away (see `Pragmas`).
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+
+/// Journal modes that SQLite accepts.
+pub const JOURNAL_MODES: [&str; 6] = ["DELETE", "TRUNCATE", "PERSIST", "MEMORY", "WAL", "OFF"];
+
+/// Settings of `PRAGMA synchronous` that SQLite accepts.
+pub const SYNCHRONOUS_MODES: [&str; 4] = ["OFF", "NORMAL", "FULL", "EXTRA"];
+
+/// SQLite settings applied to every connection of a pool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pragmas {
+    /// How long a connection waits on a locked DB before giving up, in ms.
+    pub busy_timeout: u32,
+    /// One of `JOURNAL_MODES`.
+    pub journal_mode: String,
+    /// One of `SYNCHRONOUS_MODES`.  `NORMAL` is safe in WAL mode, and
+    /// doesn't sync on every commit.
+    pub synchronous: String,
+}
+
+impl Default for Pragmas {
+    fn default() -> Pragmas {
+        Pragmas {
+            busy_timeout: 5000,
+            journal_mode: "WAL".to_string(),
+            synchronous: "NORMAL".to_string(),
+        }
+    }
+}
+
+pub struct ConnectionPool {
+    /// Connections that aren't checked out.
+    idle: Mutex<Vec<SqliteConnection>>,
+    /// Signalled whenever a connection is returned.
+    returned: Condvar,
+}
+
+/// A checked-out connection, returned to the pool when dropped.
+pub struct PooledConnection<'a> {
+    pool: &'a ConnectionPool,
+    connection: Option<SqliteConnection>,
+}
+
+impl ConnectionPool {
+    /// Open a pool of connections to a DB, with the default `Pragmas`.
+    ///
+    /// # Arguments
+    ///
+    /// * `db_url` - Connection string for the Sqlite DB
+    /// * `size` - Number of connections to open (at least one)
+    ///
+    pub fn new(db_url: &str, size: u32) -> ConnectionResult<ConnectionPool> {
+        ConnectionPool::with_pragmas(db_url, size, &Pragmas::default())
+    }
+
+    /// Open a pool of connections to a DB.
+    ///
+    /// # Arguments
+    ///
+    /// * `db_url` - Connection string for the Sqlite DB
+    /// * `size` - Number of connections to open (at least one)
+    /// * `pragmas` - Settings for every connection
+    ///
+    pub fn with_pragmas(
+        db_url: &str,
+        size: u32,
+        pragmas: &Pragmas,
+    ) -> ConnectionResult<ConnectionPool> {
+        let journal_mode = checked(&pragmas.journal_mode, &JOURNAL_MODES)?;
+        let synchronous = checked(&pragmas.synchronous, &SYNCHRONOUS_MODES)?;
+        let pragma = |connection: &SqliteConnection, sql: String| {
+            connection
+                .execute(&sql)
+                .map(|_| ())
+                .map_err(|e| ConnectionError::BadConnection(e.to_string()))
+        };
+
+        let mut idle = Vec::new();
+        for _ in 0..size.max(1) {
+            let connection = SqliteConnection::establish(db_url)?;
+            pragma(
+                &connection,
+                format!("PRAGMA busy_timeout = {};", pragmas.busy_timeout),
+            )?;
+            pragma(
+                &connection,
+                format!("PRAGMA synchronous = {};", synchronous),
+            )?;
+            idle.push(connection);
+        }
+        // Persistent, so only needs setting once.  In-memory DBs can't use
+        // WAL, and quietly stay as they are.
+        pragma(&idle[0], format!("PRAGMA journal_mode = {};", journal_mode))?;
+        Ok(ConnectionPool {
+            idle: Mutex::new(idle),
+            returned: Condvar::new(),
+        })
+    }
+
+    /// Wrap an already open connection in a pool of one, e.g. for an
+    /// in-memory DB that other connections couldn't see.
+    pub fn from_connection(connection: SqliteConnection) -> ConnectionPool {
+        ConnectionPool {
+            idle: Mutex::new(vec![connection]),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Block until a connection is free, then check it out.
+    pub fn get(&self) -> PooledConnection<'_> {
+        let mut idle = self.idle.lock().unwrap();
+        loop {
+            if let Some(connection) = idle.pop() {
+                return PooledConnection {
+                    pool: self,
+                    connection: Some(connection),
+                };
+            }
+            idle = self.returned.wait(idle).unwrap();
+        }
+    }
+}
+
+/// Match a pragma value against the values SQLite accepts, since it's
+/// spliced into the statement.
+fn checked(value: &str, allowed: &[&'static str]) -> ConnectionResult<&'static str> {
+    allowed
+        .iter()
+        .find(|a| a.eq_ignore_ascii_case(value))
+        .cloned()
+        .ok_or_else(|| {
+            ConnectionError::BadConnection(format!(
+                "{} is not one of {}",
+                value,
+                allowed.join(", ")
+            ))
+        })
+}
+
+impl<'a> Deref for PooledConnection<'a> {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &SqliteConnection {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl<'a> DerefMut for PooledConnection<'a> {
+    fn deref_mut(&mut self) -> &mut SqliteConnection {
+        self.connection.as_mut().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledConnection<'a> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.idle.lock().unwrap().push(connection);
+            self.pool.returned.notify_one();
+        }
+    }
+}
@@ -23,6 +23,27 @@ table! {
     }
 }
 
+table! {
+    channel_keys (id) {
+        id -> Integer,
+        hash -> Text,
+        channel -> Text,
+    }
+}
+
+table! {
+    channels (id) {
+        id -> Integer,
+        collection -> Text,
+        experiment -> Text,
+        channel -> Text,
+        datatype -> Text,
+        cuboid_x -> BigInt,
+        cuboid_y -> BigInt,
+        cuboid_z -> BigInt,
+    }
+}
+
 table! {
     cuboids (id) {
         id -> BigInt,
@@ -34,6 +55,27 @@ table! {
     }
 }
 
+table! {
+    empty_cuboids (id) {
+        id -> Integer,
+        path -> Text,
+    }
+}
+
+table! {
+    scratch_channels (id) {
+        id -> Integer,
+        channel -> Text,
+    }
+}
+
 joinable!(cuboids -> cache_roots (cache_root));
 
-allow_tables_to_appear_in_same_query!(cache_roots, cuboids,);
+allow_tables_to_appear_in_same_query!(
+    cache_roots,
+    channel_keys,
+    channels,
+    cuboids,
+    empty_cuboids,
+    scratch_channels,
+);
@@ -0,0 +1,78 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Channels written to as a scratch space.
+///
+/// A cache whose uploads never leave it (see
+/// `ChunkedFileDataManager::set_scratch`) holds the only copy of what's
+/// uploaded, so the channels uploaded to are pinned (see `PinnedChannels`)
+/// to keep their cuboids from being evicted, purged or compacted away.  They
+/// are recorded in the `scratch_channels` table so they stay pinned across
+/// restarts.
+use super::pool::ConnectionPool;
+use super::run_migrations;
+use super::schema;
+use super::PinnedChannels;
+use diesel::prelude::*;
+use std::sync::Arc;
+
+/// Channels uploaded to, each pinned.
+pub struct ScratchChannels {
+    pool: Arc<ConnectionPool>,
+    pinned: PinnedChannels,
+}
+
+impl ScratchChannels {
+    /// Constructor.  Pins every channel already recorded.
+    ///
+    /// # Arguments:
+    ///
+    /// * `pool` - Connections to the Sqlite DB
+    /// * `pinned` - Where to pin the channels, shared with eviction
+    pub fn new(pool: Arc<ConnectionPool>, pinned: PinnedChannels) -> ScratchChannels {
+        use schema::scratch_channels::dsl::*;
+        run_migrations(&pool);
+        let recorded = scratch_channels
+            .select(channel)
+            .load::<String>(&*pool.get())
+            .expect("Error loading scratch channels");
+        for recorded in recorded {
+            pinned.pin(&recorded);
+        }
+        ScratchChannels { pool, pinned }
+    }
+
+    /// Pin a channel that's about to be uploaded to, and record it unless
+    /// it already was.  It's pinned even if it can't be recorded, but
+    /// only until the server restarts.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The channel, as `collection/experiment/channel`
+    pub fn record(&self, name: &str) -> Result<(), String> {
+        use schema::scratch_channels::dsl::*;
+        if !self.pinned.pin(name) {
+            return Ok(());
+        }
+        diesel::insert_or_ignore_into(scratch_channels)
+            .values(channel.eq(name))
+            .execute(&*self.pool.get())
+            .map(|_| ())
+            .map_err(|err| format!("Failed to record scratch channel {}: {}", name, err))
+    }
+}
@@ -16,13 +16,21 @@ limitations under the License.
 
 */
 
+use crate::db::pool::ConnectionPool;
 use crate::db::{FileRemover, SqliteCacheInterface};
 use diesel::prelude::*;
 use std::cell::RefCell;
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 
+pub mod channel_keys;
+pub mod channels;
+pub mod empty;
+pub mod max_count_decay_strategy;
 pub mod max_count_lru_strategy;
+pub mod pool;
+pub mod scratch;
 pub mod simple_cache_manager;
 pub mod sqlite;
 
@@ -49,6 +57,25 @@ impl MockFileRemover {
     }
 }
 
+/// Fails to remove files with the given errors, in order, then succeeds.
+struct FlakyFileRemover {
+    failures: RefCell<Vec<std::io::ErrorKind>>,
+    calls: Rc<RefCell<Vec<String>>>,
+}
+
+impl FileRemover for FlakyFileRemover {
+    fn remove(&self, path: &Path) -> std::io::Result<()> {
+        self.calls
+            .borrow_mut()
+            .push(path.to_str().unwrap().to_string());
+        let mut failures = self.failures.borrow_mut();
+        if failures.is_empty() {
+            return Ok(());
+        }
+        Err(std::io::Error::new(failures.remove(0), "flaky"))
+    }
+}
+
 struct SqlCacheInterfaceTestItems {
     sql_mgr: SqliteCacheInterface,
     remove_calls: Rc<RefCell<Vec<String>>>,
@@ -60,7 +87,26 @@ fn setup_db() -> SqlCacheInterfaceTestItems {
     embedded_migrations::run(&connection).unwrap();
     let remove_calls = Rc::new(RefCell::new(Vec::<String>::new()));
     let clone = Rc::clone(&remove_calls);
-    let sql_mgr = SqliteCacheInterface::init(connection, Rc::new(MockFileRemover::new(clone)));
+    let pool = Arc::new(ConnectionPool::from_connection(connection));
+    let sql_mgr = SqliteCacheInterface::init(pool, Rc::new(MockFileRemover::new(clone)));
+
+    SqlCacheInterfaceTestItems {
+        sql_mgr,
+        remove_calls,
+    }
+}
+
+/// Like `setup_db`, but removing files with a `FlakyFileRemover`.
+fn setup_flaky_db(failures: Vec<std::io::ErrorKind>) -> SqlCacheInterfaceTestItems {
+    let connection = SqliteConnection::establish(":memory:").unwrap();
+    embedded_migrations::run(&connection).unwrap();
+    let remove_calls = Rc::new(RefCell::new(Vec::<String>::new()));
+    let remover = FlakyFileRemover {
+        failures: RefCell::new(failures),
+        calls: Rc::clone(&remove_calls),
+    };
+    let pool = Arc::new(ConnectionPool::from_connection(connection));
+    let sql_mgr = SqliteCacheInterface::init(pool, Rc::new(remover));
 
     SqlCacheInterfaceTestItems {
         sql_mgr,
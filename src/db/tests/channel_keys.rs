@@ -0,0 +1,47 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::db::channel_keys::ChannelKeys;
+use crate::db::pool::ConnectionPool;
+use std::sync::Arc;
+
+fn pool(db_url: &str) -> Arc<ConnectionPool> {
+    Arc::new(ConnectionPool::new(db_url, 1).unwrap())
+}
+
+#[test]
+fn test_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = dir.path().join("cache.db").to_str().unwrap().to_string();
+    let channel = "col/exp/chan";
+    let keys = ChannelKeys::new(pool(&db_url), "salt");
+    let hash = keys.key(channel);
+    assert_eq!(32, hash.len());
+    assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_eq!(hash, keys.key(channel));
+    assert_ne!(hash, keys.key("col/exp/other"));
+    assert_eq!(None, keys.channel("0123"));
+
+    // Hashes are stable across restarts, and recorded with their channel:
+    let reopened = ChannelKeys::new(pool(&db_url), "salt");
+    assert_eq!(Some(channel.to_string()), reopened.channel(&hash));
+    assert_eq!(hash, reopened.key(channel));
+
+    // But depend on the salt:
+    assert_ne!(hash, ChannelKeys::new(pool(&db_url), "pepper").key(channel));
+}
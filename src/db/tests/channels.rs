@@ -0,0 +1,271 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::data_manager::{Coords, Vector3};
+use crate::db::channels::{ChannelRegistry, ChannelSource};
+use crate::intern::remote::ChannelGeometry;
+use diesel::prelude::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct MockChannelSource {
+    /// Number of lookups made.
+    calls: Arc<AtomicUsize>,
+}
+
+impl ChannelSource for MockChannelSource {
+    fn get_datatype(&self, channel: &str) -> Result<String, String> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        match channel {
+            "col/exp/chan" => Ok("uint16".to_string()),
+            _ => Err("no such channel".to_string()),
+        }
+    }
+
+    fn get_extent(&self, channel: &str) -> Result<(Coords, Coords), String> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        match channel {
+            "col/exp/chan" => Ok((Coords { x: 1, y: 0, z: 0 }, Coords { x: 99, y: 50, z: 7 })),
+            "col/exp/negative" => Ok((
+                Coords {
+                    x: -3,
+                    y: -4,
+                    z: -1,
+                },
+                Coords { x: 5, y: 4, z: 3 },
+            )),
+            _ => Err("no such channel".to_string()),
+        }
+    }
+
+    fn get_type(&self, channel: &str) -> Result<String, String> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        match channel {
+            "col/exp/chan" => Ok("annotation".to_string()),
+            _ => Err("no such channel".to_string()),
+        }
+    }
+
+    fn get_geometry(&self, channel: &str) -> Result<ChannelGeometry, String> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        match channel {
+            "col/exp/chan" => Ok(geometry("anisotropic")),
+            "col/exp/negative" => Ok(geometry("isotropic")),
+            _ => Err("no such channel".to_string()),
+        }
+    }
+}
+
+fn cuboid_size() -> Vector3 {
+    Vector3 { x: 8, y: 8, z: 4 }
+}
+
+fn setup_db() -> SqliteConnection {
+    let connection = SqliteConnection::establish(":memory:").unwrap();
+    super::embedded_migrations::run(&connection).unwrap();
+    connection
+}
+
+fn setup_registry(connection: SqliteConnection) -> (ChannelRegistry, Arc<AtomicUsize>) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let source = MockChannelSource {
+        calls: Arc::clone(&calls),
+    };
+    (
+        ChannelRegistry::init(connection, Box::new(source), cuboid_size()),
+        calls,
+    )
+}
+
+#[test]
+fn test_channel_registered_on_first_access() {
+    let (registry, calls) = setup_registry(setup_db());
+
+    let info = registry.get("col/exp/chan").unwrap();
+    assert_eq!("uint16", info.datatype);
+    assert_eq!(cuboid_size(), info.cuboid_size);
+    assert_eq!(Some(2), info.element_size());
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+
+    // Served from the registry from now on:
+    assert_eq!(Some(info), registry.get("col/exp/chan"));
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_channel_read_from_db() {
+    use crate::db::schema::channels::dsl::*;
+
+    let connection = setup_db();
+    diesel::insert_into(channels)
+        .values((
+            collection.eq("col"),
+            experiment.eq("exp"),
+            channel.eq("other"),
+            datatype.eq("uint64"),
+            cuboid_x.eq(16),
+            cuboid_y.eq(16),
+            cuboid_z.eq(2),
+        ))
+        .execute(&connection)
+        .unwrap();
+    let (registry, calls) = setup_registry(connection);
+
+    let info = registry.get("col/exp/other").unwrap();
+    assert_eq!("uint64", info.datatype);
+    assert_eq!(Vector3 { x: 16, y: 16, z: 2 }, info.cuboid_size);
+    assert_eq!(0, calls.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_failed_lookup_not_recorded() {
+    let (registry, calls) = setup_registry(setup_db());
+    assert_eq!(None, registry.get("col/exp/missing"));
+    assert_eq!(None, registry.get("col/exp/missing"));
+    assert_eq!(2, calls.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_extent() {
+    let (registry, calls) = setup_registry(setup_db());
+    assert_eq!(
+        Some((Coords { x: 1, y: 0, z: 0 }, Coords { x: 99, y: 50, z: 7 })),
+        registry.extent("col/exp/chan", 0)
+    );
+    // Halved in x and y, rounding outwards:
+    assert_eq!(
+        Some((Coords { x: 0, y: 0, z: 0 }, Coords { x: 25, y: 13, z: 7 })),
+        registry.extent("col/exp/chan", 2)
+    );
+    // The extent and the geometry:
+    assert_eq!(2, calls.load(Ordering::SeqCst));
+
+    // Also outwards before the origin, and in z too for an isotropic
+    // channel:
+    assert_eq!(
+        Some((
+            Coords {
+                x: -2,
+                y: -2,
+                z: -1
+            },
+            Coords { x: 3, y: 2, z: 2 }
+        )),
+        registry.extent("col/exp/negative", 1)
+    );
+
+    // A failed lookup isn't retried straight away:
+    assert_eq!(None, registry.extent("col/exp/missing", 0));
+    assert_eq!(None, registry.extent("col/exp/missing", 0));
+    assert_eq!(5, calls.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_channel_type() {
+    let (registry, calls) = setup_registry(setup_db());
+    assert_eq!(
+        Some("annotation".to_string()),
+        registry.channel_type("col/exp/chan")
+    );
+    assert_eq!(
+        Some("annotation".to_string()),
+        registry.channel_type("col/exp/chan")
+    );
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+
+    // A failed lookup isn't retried straight away:
+    assert_eq!(None, registry.channel_type("col/exp/missing"));
+    assert_eq!(None, registry.channel_type("col/exp/missing"));
+    assert_eq!(2, calls.load(Ordering::SeqCst));
+}
+
+fn geometry(hierarchy_method: &str) -> ChannelGeometry {
+    ChannelGeometry {
+        voxel_size: [4.0, 4.0, 40.0],
+        voxel_unit: "nanometers".to_string(),
+        num_hierarchy_levels: 5,
+        hierarchy_method: hierarchy_method.to_string(),
+    }
+}
+
+#[test]
+fn test_geometry() {
+    let (registry, calls) = setup_registry(setup_db());
+    assert_eq!(
+        Some(geometry("anisotropic")),
+        registry.geometry("col/exp/chan")
+    );
+    assert_eq!(
+        Some(geometry("anisotropic")),
+        registry.geometry("col/exp/chan")
+    );
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+
+    // A failed lookup isn't retried straight away:
+    assert_eq!(None, registry.geometry("col/exp/missing"));
+    assert_eq!(None, registry.geometry("col/exp/missing"));
+    assert_eq!(2, calls.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_voxel_size_at() {
+    assert_eq!([4.0, 4.0, 40.0], geometry("anisotropic").voxel_size_at(0));
+    assert_eq!([16.0, 16.0, 40.0], geometry("anisotropic").voxel_size_at(2));
+    assert_eq!([16.0, 16.0, 160.0], geometry("isotropic").voxel_size_at(2));
+}
+
+#[test]
+fn test_configured_cuboid_size() {
+    let (mut registry, _) = setup_registry(setup_db());
+    let size = Vector3 { x: 4, y: 4, z: 2 };
+    let mut sizes = HashMap::new();
+    sizes.insert("col/exp/chan".to_string(), size);
+    registry.set_cuboid_sizes(sizes);
+
+    assert_eq!(size, registry.get("col/exp/chan").unwrap().cuboid_size);
+}
+
+#[test]
+fn test_configured_cuboid_size_updates_registered_channel() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = dir.path().join("cache.db").to_str().unwrap().to_string();
+    let open = || {
+        let connection = SqliteConnection::establish(&db_url).unwrap();
+        super::embedded_migrations::run(&connection).unwrap();
+        connection
+    };
+    let (registry, _) = setup_registry(open());
+    assert_eq!(
+        cuboid_size(),
+        registry.get("col/exp/chan").unwrap().cuboid_size
+    );
+
+    // Restart with a size configured for the channel:
+    let size = Vector3 { x: 32, y: 32, z: 1 };
+    let mut sizes = HashMap::new();
+    sizes.insert("col/exp/chan".to_string(), size);
+    let (mut registry, calls) = setup_registry(open());
+    registry.set_cuboid_sizes(sizes);
+    assert_eq!(size, registry.get("col/exp/chan").unwrap().cuboid_size);
+    assert_eq!(0, calls.load(Ordering::SeqCst));
+
+    // The new size is recorded:
+    let (registry, _) = setup_registry(open());
+    assert_eq!(size, registry.get("col/exp/chan").unwrap().cuboid_size);
+}
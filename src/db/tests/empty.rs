@@ -0,0 +1,68 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::db::empty::EmptyCuboids;
+use crate::db::pool::ConnectionPool;
+use std::sync::Arc;
+
+fn pool(db_url: &str) -> Arc<ConnectionPool> {
+    Arc::new(ConnectionPool::new(db_url, 1).unwrap())
+}
+
+#[test]
+fn test_record_and_forget() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = dir.path().join("cache.db").to_str().unwrap().to_string();
+    let key = "/cache/col/exp/chan/0/x0_y0_z0";
+    let empty = EmptyCuboids::new(pool(&db_url));
+    assert!(!empty.contains(key));
+
+    empty.record(key);
+    empty.record(key);
+    assert!(empty.contains(key));
+    // Entries are remembered across restarts:
+    assert!(EmptyCuboids::new(pool(&db_url)).contains(key));
+
+    empty.forget(key);
+    assert!(!empty.contains(key));
+    assert!(!EmptyCuboids::new(pool(&db_url)).contains(key));
+}
+
+#[test]
+fn test_capacity() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = dir.path().join("cache.db").to_str().unwrap().to_string();
+    let empty = EmptyCuboids::with_capacity(pool(&db_url), 2);
+    empty.record("a");
+    empty.record("b");
+    empty.record("c");
+    assert!(empty.contains("a") && empty.contains("b"));
+    assert!(!empty.contains("c"));
+
+    // Room is made by forgetting one:
+    empty.forget("a");
+    empty.record("c");
+    assert!(empty.contains("c"));
+
+    // Only as many are loaded on restart:
+    let reopened = EmptyCuboids::with_capacity(pool(&db_url), 1);
+    assert_eq!(
+        1,
+        ["b", "c"].iter().filter(|k| reopened.contains(k)).count()
+    );
+}
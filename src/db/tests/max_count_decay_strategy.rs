@@ -0,0 +1,103 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::db::models::Cuboid;
+use crate::db::{decayed_score, CuboidCatalog, LimitNumCuboids, MaxCountDecayStrategy, Selection};
+use chrono::prelude::*;
+use chrono::Duration;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const HOUR: u32 = 60 * 60;
+
+struct MockCatalog {
+    cuboids: Vec<Cuboid>,
+}
+
+impl CuboidCatalog for MockCatalog {
+    fn all_cuboids(&self) -> Vec<Cuboid> {
+        self.cuboids
+            .iter()
+            .map(|c| Cuboid {
+                id: c.id,
+                cache_root: c.cache_root,
+                cube_key: c.cube_key.clone(),
+                requests: c.requests,
+                created: c.created,
+                last_accessed: c.last_accessed,
+            })
+            .collect()
+    }
+}
+
+fn cuboid(id: i64, requests: i64, hours_ago: i64) -> Cuboid {
+    let timestamp = Utc::now().naive_utc() - Duration::hours(hours_ago);
+    Cuboid {
+        id,
+        cache_root: 1,
+        cube_key: format!("cube/{}", id),
+        requests,
+        created: timestamp - Duration::hours(1),
+        last_accessed: timestamp,
+    }
+}
+
+#[test]
+fn test_decayed_score_halves_every_half_life() {
+    let now = Utc.ymd(2020, 4, 19).and_hms(12, 0, 0).naive_utc();
+    assert_eq!(8.0, decayed_score(8, now, now, HOUR));
+    assert_eq!(4.0, decayed_score(8, now - Duration::hours(1), now, HOUR));
+    assert_eq!(1.0, decayed_score(8, now - Duration::hours(3), now, HOUR));
+
+    // Accesses "from the future" (clock skew) aren't boosted:
+    assert_eq!(8.0, decayed_score(8, now + Duration::hours(1), now, HOUR));
+}
+
+#[test]
+fn test_select_lowest_decayed_score() {
+    let catalog = MockCatalog {
+        cuboids: vec![
+            // Hot last month: 1000 requests, but 30 half-lives ago.
+            cuboid(1, 1000, 30),
+            // Recently warm: 4 requests an hour ago.
+            cuboid(2, 4, 1),
+            // Barely used, but just now.
+            cuboid(3, 1, 0),
+        ],
+    };
+    let mut strat = MaxCountDecayStrategy::new(2, HOUR, Rc::new(RefCell::new(catalog)));
+    strat.set_size(3);
+
+    let actual = strat.select_cuboids_for_removal();
+    assert_eq!(1, actual.len());
+    assert_eq!(1, actual[0].id);
+}
+
+#[test]
+fn test_select_respects_min_residency() {
+    let catalog = MockCatalog {
+        cuboids: vec![cuboid(1, 1, 0), cuboid(2, 100, 5)],
+    };
+    let mut strat = MaxCountDecayStrategy::new(0, HOUR, Rc::new(RefCell::new(catalog)));
+    strat.set_size(2);
+    strat.set_min_residency(2 * HOUR);
+
+    let actual = strat.select_cuboids_for_removal();
+    assert_eq!(1, actual.len());
+    assert_eq!(2, actual[0].id);
+}
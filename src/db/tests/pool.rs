@@ -0,0 +1,204 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use super::MockFileRemover;
+use crate::config;
+use crate::db::pool::{ConnectionPool, Pragmas};
+use crate::db::{self, ConnectRetry, CuboidCatalog, LeastRecentlyUsed, SqliteCacheInterface};
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text};
+use diesel::sqlite::SqliteConnection;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Open an interface on a shared pool.  Interfaces aren't `Send`, so each
+/// thread makes its own.
+fn interface(pool: &Arc<ConnectionPool>) -> SqliteCacheInterface {
+    let calls = Rc::new(RefCell::new(Vec::<String>::new()));
+    SqliteCacheInterface::init(Arc::clone(pool), Rc::new(MockFileRemover::new(calls)))
+}
+
+#[test]
+fn test_connections_are_reused() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = dir.path().join("cache.db");
+    let pool = ConnectionPool::new(url.to_str().unwrap(), 2).unwrap();
+
+    let first = pool.get();
+    let second = pool.get();
+    drop(first);
+    // Would block forever if the first connection hadn't been returned.
+    let _third = pool.get();
+    drop(second);
+}
+
+#[test]
+fn test_concurrent_readers_and_writer() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = dir.path().join("cache.db");
+    let pool = Arc::new(ConnectionPool::new(url.to_str().unwrap(), 4).unwrap());
+    db::run_migrations(&pool);
+    // Create the cache root up front, so the threads don't race to insert it.
+    drop(interface(&pool));
+
+    const NUM_KEYS: u32 = 200;
+    let writer = {
+        let pool = Arc::clone(&pool);
+        thread::spawn(move || {
+            let sql_mgr = interface(&pool);
+            for i in 0..NUM_KEYS {
+                assert!(sql_mgr.log_request(format!("{}/key{}", config::CUBOID_ROOT_PATH, i)));
+            }
+        })
+    };
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                let sql_mgr = interface(&pool);
+                let mut last = 0;
+                for _ in 0..NUM_KEYS {
+                    let count = sql_mgr.num_cuboids();
+                    // Readers see the writer's progress, never going back.
+                    assert!(count >= last);
+                    assert!(sql_mgr.find_lru(count).len() as u32 >= count);
+                    last = count;
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert_eq!(NUM_KEYS, interface(&pool).num_cuboids());
+}
+
+#[test]
+fn test_pragmas_applied_to_every_connection() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = dir.path().join("cache.db");
+    let pragmas = Pragmas {
+        busy_timeout: 1234,
+        journal_mode: "wal".to_string(),
+        synchronous: "full".to_string(),
+    };
+    let pool = ConnectionPool::with_pragmas(url.to_str().unwrap(), 2, &pragmas).unwrap();
+
+    let first = pool.get();
+    let second = pool.get();
+    for connection in &[&first, &second] {
+        let busy_timeout = diesel::select(sql::<BigInt>("timeout FROM pragma_busy_timeout"))
+            .get_result::<i64>(&***connection)
+            .unwrap();
+        assert_eq!(1234, busy_timeout);
+        // FULL:
+        let synchronous = diesel::select(sql::<BigInt>("synchronous FROM pragma_synchronous"))
+            .get_result::<i64>(&***connection)
+            .unwrap();
+        assert_eq!(2, synchronous);
+        let journal_mode = diesel::select(sql::<Text>("journal_mode FROM pragma_journal_mode"))
+            .get_result::<String>(&***connection)
+            .unwrap();
+        assert_eq!("wal", journal_mode);
+    }
+}
+
+#[test]
+fn test_unknown_pragma_value_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = dir.path().join("cache.db");
+    let pragmas = Pragmas {
+        synchronous: "NORMAL; DROP TABLE cuboids".to_string(),
+        ..Pragmas::default()
+    };
+    assert!(ConnectionPool::with_pragmas(url.to_str().unwrap(), 1, &pragmas).is_err());
+}
+
+#[test]
+fn test_concurrent_logging() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = dir.path().join("cache.db");
+    let pool = Arc::new(ConnectionPool::new(url.to_str().unwrap(), 4).unwrap());
+    db::run_migrations(&pool);
+    drop(interface(&pool));
+
+    // Every thread logs the same keys, so they contend for the same rows:
+    const NUM_THREADS: u32 = 4;
+    const NUM_KEYS: u32 = 50;
+    let writers: Vec<_> = (0..NUM_THREADS)
+        .map(|_| {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                let sql_mgr = interface(&pool);
+                for i in 0..NUM_KEYS {
+                    sql_mgr.log_request(format!("{}/key{}", config::CUBOID_ROOT_PATH, i));
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let sql_mgr = interface(&pool);
+    assert_eq!(NUM_KEYS, sql_mgr.num_cuboids());
+    let requests: i64 = sql_mgr
+        .all_cuboids()
+        .iter()
+        .map(|cuboid| cuboid.requests)
+        .sum();
+    assert_eq!((NUM_THREADS * NUM_KEYS) as i64, requests);
+}
+
+#[test]
+fn test_open_retries_while_locked() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = dir.path().join("cache.db");
+    let url = url.to_str().unwrap().to_string();
+    // Another process still holding the DB, like a server shutting down:
+    let holder = SqliteConnection::establish(&url).unwrap();
+    holder.execute("BEGIN EXCLUSIVE").unwrap();
+    let pragmas = Pragmas {
+        busy_timeout: 10,
+        ..Pragmas::default()
+    };
+
+    let no_retries = ConnectRetry {
+        retries: 0,
+        backoff: Duration::from_millis(10),
+    };
+    assert!(db::open_pool(&url, 1, &pragmas, no_retries).is_err());
+
+    let release = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        holder.execute("COMMIT").unwrap();
+    });
+    let retry = ConnectRetry {
+        retries: 8,
+        backoff: Duration::from_millis(20),
+    };
+    let pool = db::open_pool(&url, 1, &pragmas, retry).unwrap();
+    release.join().unwrap();
+    assert_eq!(0, interface(&Arc::new(pool)).num_cuboids());
+}
@@ -0,0 +1,42 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::db::pool::ConnectionPool;
+use crate::db::scratch::ScratchChannels;
+use crate::db::PinnedChannels;
+use std::sync::Arc;
+
+fn pool(db_url: &str) -> Arc<ConnectionPool> {
+    Arc::new(ConnectionPool::new(db_url, 1).unwrap())
+}
+
+#[test]
+fn test_recorded_channels_stay_pinned() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = dir.path().join("cache.db").to_str().unwrap().to_string();
+    let pinned = PinnedChannels::default();
+    let scratch = ScratchChannels::new(pool(&db_url), pinned.clone());
+    scratch.record("col/exp/chan").unwrap();
+    scratch.record("col/exp/chan").unwrap();
+    assert_eq!(vec!["col/exp/chan".to_string()], pinned.prefixes());
+
+    // Channels are pinned again on restart:
+    let pinned = PinnedChannels::default();
+    ScratchChannels::new(pool(&db_url), pinned.clone());
+    assert_eq!(vec!["col/exp/chan".to_string()], pinned.prefixes());
+}
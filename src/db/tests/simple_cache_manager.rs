@@ -18,10 +18,17 @@ limitations under the License.
 
 use super::SqlCacheInterfaceTestItems;
 use crate::config;
-use crate::db::{LimitNumCuboids, MaxCountLruStrategy, SimpleCacheManager};
-use crate::usage_tracker::UsageTracker;
+use crate::data_manager::tests::ConstantDataManager;
+use crate::data_manager::{ChunkedFileDataManager, DataManager, Vector3};
+use crate::db::{random_jitter, CacheStats, MaxCountLruStrategy, SimpleCacheManager};
+use crate::usage_tracker::{EvictionSettings, EvictionStrategy, SizeReport, UsageTracker};
+use ndarray::Array3;
 use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
 use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
 const MAX_COUNT: u32 = 10;
 
@@ -31,13 +38,18 @@ struct TestItems {
 }
 
 fn setup() -> TestItems {
+    setup_with_residency(0)
+}
+
+fn setup_with_residency(min_residency: u32) -> TestItems {
     let SqlCacheInterfaceTestItems {
         sql_mgr,
         remove_calls,
     } = super::setup_db();
     let db = Rc::new(RefCell::new(sql_mgr));
     let clone = Rc::clone(&db);
-    let strat = MaxCountLruStrategy::new(MAX_COUNT, clone);
+    let mut strat = MaxCountLruStrategy::new(MAX_COUNT, clone);
+    strat.set_min_residency(min_residency);
     TestItems {
         cache_mgr: SimpleCacheManager::new(db, strat),
         remove_calls,
@@ -72,3 +84,271 @@ fn test_cache_management() {
     assert_eq!(exp_removes, remove_calls.borrow().len());
     assert_eq!(MAX_COUNT, cache_mgr.strategy.size());
 }
+
+#[test]
+fn test_fresh_cuboids_protected_by_min_residency() {
+    let TestItems {
+        mut cache_mgr,
+        remove_calls,
+    } = setup_with_residency(3600);
+
+    let key = "coll/exp/chan";
+    for i in 0..(MAX_COUNT + 5) {
+        cache_mgr.log_request(format!("{}/{}/{}", config::CUBOID_ROOT_PATH, key, i));
+    }
+
+    // Every cuboid was just created, so none may be evicted yet.
+    assert_eq!(0, remove_calls.borrow().len());
+    assert_eq!(MAX_COUNT + 5, cache_mgr.strategy.size());
+}
+
+#[test]
+fn test_reconfigure() {
+    let TestItems {
+        mut cache_mgr,
+        remove_calls,
+    } = setup();
+
+    let key = "coll/exp/chan";
+    let requests: Vec<String> = (0..MAX_COUNT)
+        .map(|i| format!("{}/{}/{}", config::CUBOID_ROOT_PATH, key, i))
+        .collect();
+    for req in &requests {
+        cache_mgr.log_request(req.to_string());
+    }
+    assert_eq!(0, remove_calls.borrow().len());
+
+    // Lowering the limit evicts down to it right away, oldest first:
+    cache_mgr.reconfigure(&EvictionSettings {
+        max_cuboids: MAX_COUNT - 3,
+        min_residency: 0,
+        strategy: EvictionStrategy::Decay { half_life: 60 },
+        jitter: 0,
+    });
+    assert_eq!(requests[..3].to_vec(), *remove_calls.borrow());
+    assert_eq!(MAX_COUNT - 3, cache_mgr.strategy.size());
+    assert_eq!(MAX_COUNT - 3, cache_mgr.strategy.get_max_cuboids());
+
+    // Raising it keeps the count:
+    cache_mgr.reconfigure(&EvictionSettings {
+        max_cuboids: MAX_COUNT,
+        ..EvictionSettings::default()
+    });
+    assert_eq!(3, remove_calls.borrow().len());
+    assert_eq!(MAX_COUNT - 3, cache_mgr.strategy.size());
+}
+
+#[test]
+fn test_random_jitter_in_bounds() {
+    assert_eq!(
+        Duration::from_secs(0),
+        random_jitter(Duration::from_secs(0))
+    );
+    let max = Duration::from_millis(50);
+    let samples: Vec<Duration> = (0..1000).map(|_| random_jitter(max)).collect();
+    assert!(samples.iter().all(|jitter| *jitter <= max));
+    // Not stuck on one value:
+    assert!(samples.iter().any(|jitter| *jitter != samples[0]));
+}
+
+#[test]
+fn test_jitter_puts_off_cleaning() {
+    let TestItems {
+        mut cache_mgr,
+        remove_calls,
+    } = setup();
+    cache_mgr.reconfigure(&EvictionSettings {
+        max_cuboids: MAX_COUNT,
+        jitter: 3600,
+        ..EvictionSettings::default()
+    });
+
+    let key = "coll/exp/chan";
+    for i in 0..(MAX_COUNT + 5) {
+        cache_mgr.log_request(format!("{}/{}/{}", config::CUBOID_ROOT_PATH, key, i));
+    }
+    // Cleaning is due at most an hour after the cache went over its limit:
+    let due = cache_mgr.clean_after.unwrap();
+    assert!(due <= Instant::now() + Duration::from_secs(3600));
+    if due > Instant::now() {
+        assert_eq!(0, remove_calls.borrow().len());
+    }
+
+    // Once it's due, it happens even if nothing more is added:
+    cache_mgr.clean_after = Some(Instant::now());
+    cache_mgr.idle();
+    assert_eq!(None, cache_mgr.clean_after);
+    assert_eq!(5, remove_calls.borrow().len());
+    assert_eq!(MAX_COUNT, cache_mgr.strategy.size());
+}
+
+#[test]
+fn test_size_report() {
+    let TestItems { mut cache_mgr, .. } = setup();
+    let root = tempfile::tempdir().unwrap();
+    let root = root.path().to_str().unwrap().to_string();
+    cache_mgr.db.borrow_mut().add_cache_root(&root);
+    let cache = |cache_mgr: &mut SimpleCacheManager, name: &str, len: usize| {
+        let filename = format!("{}/coll/exp/chan/0/{}", root, name);
+        fs::create_dir_all(format!("{}/coll/exp/chan/0", root)).unwrap();
+        fs::write(&filename, vec![0; len]).unwrap();
+        cache_mgr.log_request(filename);
+    };
+
+    let start = Instant::now();
+    let interval = Duration::from_secs(60);
+    let mut report = SizeReport::new(interval, start);
+    cache(&mut cache_mgr, "a", 100);
+    assert_eq!(None, report.poll(start, &mut cache_mgr));
+    assert_eq!(
+        Some(CacheStats {
+            cuboids: 1,
+            bytes: 100,
+            empty: 0,
+        }),
+        report.poll(start + interval, &mut cache_mgr)
+    );
+
+    // Each report reflects the cache as it is then, with empty files
+    // counted apart:
+    cache(&mut cache_mgr, "b", 50);
+    cache(&mut cache_mgr, "c", 0);
+    assert_eq!(None, report.poll(start + interval, &mut cache_mgr));
+    assert_eq!(
+        Some(CacheStats {
+            cuboids: 2,
+            bytes: 150,
+            empty: 1,
+        }),
+        report.poll(start + 2 * interval, &mut cache_mgr)
+    );
+}
+
+#[test]
+fn test_empty_cuboids_evicted_first() {
+    for &empty_first in &[true, false] {
+        let TestItems {
+            mut cache_mgr,
+            remove_calls,
+        } = setup();
+        cache_mgr.set_evict_empty_first(empty_first);
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path().to_str().unwrap().to_string();
+        cache_mgr.db.borrow_mut().add_cache_root(&root);
+        fs::create_dir_all(format!("{}/coll/exp/chan/0", root)).unwrap();
+        let filename = |i: u32| format!("{}/coll/exp/chan/0/{}", root, i);
+
+        // Full cuboids, then empty ones, which are the most recently used:
+        for i in 0..MAX_COUNT + 2 {
+            let len = if i < MAX_COUNT - 1 { 100 } else { 0 };
+            fs::write(filename(i), vec![0; len]).unwrap();
+            cache_mgr.log_request(filename(i));
+        }
+
+        let removed = remove_calls.borrow().clone();
+        if empty_first {
+            // Every empty cuboid so far goes once the cache is over its
+            // limit, which leaves room for the last:
+            assert_eq!(vec![filename(MAX_COUNT - 1), filename(MAX_COUNT)], removed);
+
+            // The cache isn't scanned again straight away, so the next
+            // cleaning leaves a new empty cuboid to the strategy:
+            for i in MAX_COUNT + 2..MAX_COUNT + 4 {
+                fs::write(filename(i), vec![]).unwrap();
+                cache_mgr.log_request(filename(i));
+            }
+            assert_eq!(filename(0), remove_calls.borrow()[2]);
+        } else {
+            assert_eq!(vec![filename(0), filename(1)], removed);
+        }
+    }
+}
+
+#[test]
+fn test_shed() {
+    let TestItems {
+        mut cache_mgr,
+        remove_calls,
+    } = setup();
+    let requests: Vec<String> = (0..8)
+        .map(|i| format!("{}/coll/exp/chan/{}", config::CUBOID_ROOT_PATH, i))
+        .collect();
+    for req in &requests {
+        cache_mgr.log_request(req.to_string());
+    }
+    assert!(remove_calls.borrow().is_empty());
+
+    // Under its limit, but the least recently used quarter goes anyway:
+    assert_eq!(2, cache_mgr.shed(0.25));
+    assert_eq!(requests[..2].to_vec(), *remove_calls.borrow());
+    assert_eq!(6, cache_mgr.strategy.size());
+
+    assert_eq!(6, cache_mgr.shed(1.0));
+    assert_eq!(0, cache_mgr.shed(0.5));
+    assert_eq!(0, cache_mgr.strategy.size());
+}
+
+/// Count the files under `dir`, however deep.
+fn count_files(dir: &Path) -> u32 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                count_files(&path)
+            } else {
+                1
+            }
+        })
+        .sum()
+}
+
+#[test]
+fn test_rows_match_files_written() {
+    let TestItems {
+        mut cache_mgr,
+        remove_calls,
+    } = setup();
+    let root = tempfile::tempdir().unwrap();
+    let root_path = root.path().to_str().unwrap().to_string();
+    cache_mgr.db.borrow_mut().add_cache_root(&root_path);
+
+    let size = Vector3 { x: 8, y: 8, z: 2 };
+    let mut fm = ChunkedFileDataManager::new_with_layer(
+        root_path,
+        size,
+        Box::new(ConstantDataManager(3)),
+        false,
+    );
+    let (tx, rx) = mpsc::channel();
+    fm.set_usage_sender(Arc::new(Mutex::new(tx)));
+    let uri = "bossdb://coll/exp/chan".to_string();
+    let region = |x: u64, y: u64| {
+        (
+            Vector3 { x, y, z: 0 },
+            Vector3 {
+                x: x + 16,
+                y: y + 8,
+                z: 2,
+            },
+        )
+    };
+
+    // An upload, a cutout that caches what it misses, a warmed region,
+    // and a second upload over the first:
+    let (origin, _) = region(0, 0);
+    assert!(fm.put_data(uri.clone(), 0, origin, Array3::zeros((2, 8, 16))));
+    let (origin, destination) = region(0, 8);
+    fm.get_data(uri.clone(), 0, origin, destination);
+    let (origin, destination) = region(16, 0);
+    assert_eq!(2, fm.warm(&uri, 0, origin, destination));
+    let (origin, _) = region(0, 0);
+    assert!(fm.put_data(uri.clone(), 0, origin, Array3::ones((2, 8, 16))));
+
+    for filename in rx.try_iter() {
+        cache_mgr.log_request(filename);
+    }
+    assert!(remove_calls.borrow().is_empty());
+    assert_eq!(6, count_files(root.path()));
+    assert_eq!(6, cache_mgr.db.borrow().num_cuboids());
+}
@@ -18,10 +18,25 @@ limitations under the License.
 
 use super::SqlCacheInterfaceTestItems;
 use crate::config;
+use crate::cuboid_file;
+use crate::data_manager::Vector3;
+use crate::db::channel_keys::ChannelKeys;
 use crate::db::models::Cuboid;
-use crate::db::{schema, LeastRecentlyUsed};
+use crate::db::{
+    schema, CuboidUsage, GroupUsage, LeastRecentlyUsed, LimitNumCuboids, MaxCountDecayStrategy,
+    MaxCountLruStrategy, PinnedChannels, RemovalRetry, Selection, SqliteCacheInterface,
+    UsageGrouping,
+};
+use crate::etag::{self, CuboidHashes};
 use chrono::prelude::*;
 use diesel::prelude::*;
+use std::cell::RefCell;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[test]
 fn test_log_new_request() {
@@ -36,7 +51,7 @@ fn test_log_new_request() {
         cuboids
             .select((cube_key, requests))
             .filter(cube_key.eq(key))
-            .first::<(String, i64)>(&sql_mgr.connection)
+            .first::<(String, i64)>(&*sql_mgr.connection())
     );
 }
 
@@ -59,7 +74,7 @@ fn test_log_repeated_request() {
         cuboids
             .select((cube_key, requests))
             .filter(cube_key.eq(key))
-            .first::<(String, i64)>(&sql_mgr.connection)
+            .first::<(String, i64)>(&*sql_mgr.connection())
     );
 }
 
@@ -89,7 +104,7 @@ fn test_find_lru() {
     for row in &exp_rows {
         diesel::insert_into(cuboids)
             .values(row)
-            .execute(&sql_mgr.connection)
+            .execute(&*sql_mgr.connection())
             .unwrap();
     }
 
@@ -103,6 +118,45 @@ fn test_find_lru() {
     }
 }
 
+#[test]
+fn test_find_lru_before() {
+    use schema::cuboids::dsl::*;
+
+    let SqlCacheInterfaceTestItems { sql_mgr, .. } = super::setup_db();
+    let key = "/my_key";
+    let rows_to_insert = 4;
+    let rows: Vec<Cuboid> = (0..rows_to_insert)
+        .map(|i| {
+            // Generate rows from most recently accessed to least.
+            let timestamp = Utc.ymd(2020, 4, 19).and_hms(23 - i, 0, 0).naive_utc();
+            Cuboid {
+                id: (i + 1) as i64,
+                cache_root: sql_mgr.cache_root_id,
+                cube_key: format!("{}/{}", key, i),
+                requests: i as i64,
+                created: timestamp,
+                last_accessed: timestamp,
+            }
+        })
+        .collect();
+
+    for row in &rows {
+        diesel::insert_into(cuboids)
+            .values(row)
+            .execute(&*sql_mgr.connection())
+            .unwrap();
+    }
+
+    // Only the two rows touched before 21:30 are eligible.
+    let cutoff = Utc.ymd(2020, 4, 19).and_hms(21, 30, 0).naive_utc();
+    let actual = sql_mgr.find_lru_before(rows_to_insert, cutoff);
+
+    assert_eq!(2, actual.len());
+    for row in rows.iter().rev().zip(actual.iter()) {
+        assert_eq!(row.0, row.1);
+    }
+}
+
 #[test]
 fn test_get_cache_root_path_from_map_new_lookup() {
     use schema::cache_roots::dsl::*;
@@ -112,7 +166,7 @@ fn test_get_cache_root_path_from_map_new_lookup() {
     let cache_root_id = 100;
     diesel::insert_into(cache_roots)
         .values(&(id.eq(cache_root_id), path.eq(root)))
-        .execute(&sql_mgr.connection)
+        .execute(&*sql_mgr.connection())
         .expect("Could not add cache root");
     let actual = sql_mgr.get_cache_root_path_from_map(cache_root_id).unwrap();
     assert_eq!(root, actual);
@@ -150,7 +204,7 @@ fn test_remove_cuboid_entry() {
     let results = cuboids
         .select(id)
         .filter(id.eq(row[0].id))
-        .first::<i64>(&sql_mgr.connection);
+        .first::<i64>(&*sql_mgr.connection());
     assert_eq!(false, results.is_ok());
 }
 
@@ -178,3 +232,620 @@ fn test_clean_cache() {
     assert_eq!(1, remove_calls.borrow().len());
     assert_eq!(full_key1, remove_calls.borrow()[0]);
 }
+
+/// Retry straight away, so tests don't wait.
+const NO_BACKOFF: RemovalRetry = RemovalRetry {
+    retries: 2,
+    backoff: Duration::from_millis(0),
+};
+
+#[test]
+fn test_clean_cache_retries_failed_removal() {
+    let SqlCacheInterfaceTestItems {
+        mut sql_mgr,
+        remove_calls,
+    } = super::setup_flaky_db(vec![ErrorKind::Other]);
+    sql_mgr.set_removal_retry(NO_BACKOFF);
+    let key = format!("{}/busy_key", config::CUBOID_ROOT_PATH);
+    assert!(sql_mgr.log_request(key.clone()));
+
+    // Left for later, rather than retried straight away:
+    let row = sql_mgr.find_lru(1);
+    assert_eq!(0, sql_mgr.clean_cache(row));
+    assert_eq!(vec![key.clone()], *remove_calls.borrow());
+    // Nor removed twice meanwhile:
+    let row = sql_mgr.find_lru(1);
+    assert_eq!(0, sql_mgr.clean_cache(row));
+    assert_eq!(1, remove_calls.borrow().len());
+
+    assert_eq!(1, sql_mgr.retry_removals());
+    assert_eq!(vec![key.clone(), key], *remove_calls.borrow());
+    assert_eq!(0, sql_mgr.num_cuboids());
+    assert_eq!(0, sql_mgr.retry_removals());
+}
+
+#[test]
+fn test_clean_cache_gives_up_after_retries() {
+    let SqlCacheInterfaceTestItems {
+        mut sql_mgr,
+        remove_calls,
+    } = super::setup_flaky_db(vec![ErrorKind::Other; 3]);
+    sql_mgr.set_removal_retry(NO_BACKOFF);
+    assert!(sql_mgr.log_request(format!("{}/stuck_key", config::CUBOID_ROOT_PATH)));
+
+    let row = sql_mgr.find_lru(1);
+    assert_eq!(0, sql_mgr.clean_cache(row));
+    assert_eq!(0, sql_mgr.retry_removals());
+    assert_eq!(0, sql_mgr.retry_removals());
+    assert_eq!(3, remove_calls.borrow().len());
+    assert_eq!(0, sql_mgr.retry_removals());
+    assert_eq!(3, remove_calls.borrow().len());
+    // Left for the next clean:
+    assert_eq!(1, sql_mgr.num_cuboids());
+}
+
+#[test]
+fn test_clean_cache_skips_permanent_failures() {
+    let SqlCacheInterfaceTestItems {
+        mut sql_mgr,
+        remove_calls,
+    } = super::setup_flaky_db(vec![ErrorKind::PermissionDenied]);
+    sql_mgr.set_removal_retry(NO_BACKOFF);
+    assert!(sql_mgr.log_request(format!("{}/locked_key", config::CUBOID_ROOT_PATH)));
+
+    let row = sql_mgr.find_lru(1);
+    assert_eq!(0, sql_mgr.clean_cache(row));
+    // Not retried:
+    assert_eq!(0, sql_mgr.retry_removals());
+    assert_eq!(1, remove_calls.borrow().len());
+    assert_eq!(1, sql_mgr.num_cuboids());
+}
+
+#[test]
+fn test_clean_cache_removes_rows_of_missing_files() {
+    let SqlCacheInterfaceTestItems {
+        mut sql_mgr,
+        remove_calls,
+    } = super::setup_flaky_db(vec![ErrorKind::NotFound]);
+    sql_mgr.set_removal_retry(NO_BACKOFF);
+    assert!(sql_mgr.log_request(format!("{}/gone_key", config::CUBOID_ROOT_PATH)));
+
+    let row = sql_mgr.find_lru(1);
+    assert_eq!(1, sql_mgr.clean_cache(row));
+    assert_eq!(1, remove_calls.borrow().len());
+    assert_eq!(0, sql_mgr.num_cuboids());
+}
+
+#[test]
+fn test_purge_older_than() {
+    use schema::cuboids::dsl::*;
+
+    let SqlCacheInterfaceTestItems {
+        mut sql_mgr,
+        remove_calls,
+    } = super::setup_db();
+    let root = config::CUBOID_ROOT_PATH;
+    let key = "/my_key";
+    let rows: Vec<Cuboid> = (0..4)
+        .map(|i| {
+            // Generate rows from most recently accessed to least.
+            let timestamp = Utc.ymd(2020, 4, 19).and_hms(23 - i, 0, 0).naive_utc();
+            Cuboid {
+                id: (i + 1) as i64,
+                cache_root: sql_mgr.cache_root_id,
+                cube_key: format!("{}/{}", key, i),
+                requests: i as i64,
+                created: timestamp,
+                last_accessed: timestamp,
+            }
+        })
+        .collect();
+
+    for row in &rows {
+        diesel::insert_into(cuboids)
+            .values(row)
+            .execute(&*sql_mgr.connection())
+            .unwrap();
+    }
+
+    // Only the two rows accessed before 21:30 are removed.
+    let cutoff = Utc.ymd(2020, 4, 19).and_hms(21, 30, 0).naive_utc();
+    assert_eq!(2, sql_mgr.purge_older_than(cutoff));
+    assert_eq!(
+        vec![format!("{}{}/3", root, key), format!("{}{}/2", root, key)],
+        *remove_calls.borrow()
+    );
+
+    let remaining = cuboids
+        .select(cube_key)
+        .order(cube_key)
+        .load::<String>(&*sql_mgr.connection())
+        .unwrap();
+    assert_eq!(vec![format!("{}/0", key), format!("{}/1", key)], remaining);
+}
+
+#[test]
+fn test_evict_to() {
+    use schema::cuboids::dsl::*;
+
+    let SqlCacheInterfaceTestItems {
+        mut sql_mgr,
+        remove_calls,
+    } = super::setup_db();
+    let root = config::CUBOID_ROOT_PATH;
+    let key = "/my_key";
+    for i in 0..4 {
+        // Generate rows from most recently accessed to least.
+        let timestamp = Utc.ymd(2020, 4, 19).and_hms(23 - i, 0, 0).naive_utc();
+        diesel::insert_into(cuboids)
+            .values(Cuboid {
+                id: (i + 1) as i64,
+                cache_root: sql_mgr.cache_root_id,
+                cube_key: format!("{}/{}", key, i),
+                requests: 1,
+                created: timestamp,
+                last_accessed: timestamp,
+            })
+            .execute(&*sql_mgr.connection())
+            .unwrap();
+    }
+
+    // Already small enough:
+    assert_eq!(0, sql_mgr.evict_to(10));
+    assert_eq!(0, sql_mgr.evict_to(4));
+
+    assert_eq!(3, sql_mgr.evict_to(1));
+    assert_eq!(1, sql_mgr.num_cuboids());
+    assert_eq!(
+        vec![
+            format!("{}{}/3", root, key),
+            format!("{}{}/2", root, key),
+            format!("{}{}/1", root, key)
+        ],
+        *remove_calls.borrow()
+    );
+
+    assert_eq!(1, sql_mgr.evict_to(0));
+    assert_eq!(0, sql_mgr.num_cuboids());
+}
+
+#[test]
+fn test_extra_cache_root() {
+    use schema::cuboids::dsl::*;
+
+    let SqlCacheInterfaceTestItems {
+        mut sql_mgr,
+        remove_calls,
+    } = super::setup_db();
+    let dir = tempfile::tempdir().unwrap();
+    let extra = dir.path().to_str().unwrap();
+    sql_mgr.add_cache_root(extra);
+    let key = "/col/exp/chan/1/x0_y0_z0";
+
+    assert!(sql_mgr.log_request(format!("{}{}", config::CUBOID_ROOT_PATH, key)));
+    assert!(sql_mgr.log_request(format!("{}{}", extra, key)));
+    assert!(!sql_mgr.log_request(format!("{}{}", extra, key)));
+
+    let rows = cuboids
+        .select((cache_root, requests))
+        .filter(cube_key.eq(key))
+        .order(cache_root)
+        .load::<(i32, i64)>(&*sql_mgr.connection())
+        .unwrap();
+    assert_eq!(2, rows.len());
+    assert_eq!((sql_mgr.cache_root_id, 1), rows[0]);
+    assert_ne!(sql_mgr.cache_root_id, rows[1].0);
+    assert_eq!(2, rows[1].1);
+
+    assert_eq!(2, sql_mgr.evict_to(0));
+    let mut removed = remove_calls.borrow().clone();
+    removed.sort();
+    let mut expected = vec![
+        format!("{}{}", config::CUBOID_ROOT_PATH, key),
+        format!("{}{}", extra, key),
+    ];
+    expected.sort();
+    assert_eq!(expected, removed);
+}
+
+#[test]
+fn test_usage_stats() {
+    use schema::cuboids::dsl::*;
+
+    let SqlCacheInterfaceTestItems { sql_mgr, .. } = super::setup_db();
+    let recent = Utc.ymd(2020, 4, 19).and_hms(12, 0, 0).naive_utc();
+    let old = Utc.ymd(2020, 4, 1).and_hms(12, 0, 0).naive_utc();
+    let rows = vec![
+        ("/col1/exp/chan_a/0/x0_y0_z0", 5, recent),
+        ("/col1/exp/chan_a/0/x1_y0_z0", 1, recent),
+        ("/col1/exp/chan_b/0/x0_y0_z0", 3, recent),
+        ("/col2/exp/chan/1/0-4_0-4_0-2.npy", 7, recent),
+        ("/col2/exp/chan/0/x0_y0_z0", 100, old),
+    ];
+    for (i, (key, count, accessed)) in rows.into_iter().enumerate() {
+        diesel::insert_into(cuboids)
+            .values(Cuboid {
+                id: (i + 1) as i64,
+                cache_root: sql_mgr.cache_root_id,
+                cube_key: key.to_string(),
+                requests: count,
+                created: old,
+                last_accessed: accessed,
+            })
+            .execute(&*sql_mgr.connection())
+            .unwrap();
+    }
+    let since = Utc.ymd(2020, 4, 18).and_hms(0, 0, 0).naive_utc();
+
+    let stats = sql_mgr.usage_stats(since, 2, UsageGrouping::Collection);
+    assert_eq!(4, stats.cuboids);
+    assert_eq!(16, stats.total_requests);
+    assert_eq!(
+        vec![
+            CuboidUsage {
+                key: "/col2/exp/chan/1/0-4_0-4_0-2.npy".to_string(),
+                requests: 7
+            },
+            CuboidUsage {
+                key: "/col1/exp/chan_a/0/x0_y0_z0".to_string(),
+                requests: 5
+            },
+        ],
+        stats.top
+    );
+    assert_eq!(
+        vec![
+            GroupUsage {
+                name: "col1".to_string(),
+                cuboids: 3,
+                requests: 9
+            },
+            GroupUsage {
+                name: "col2".to_string(),
+                cuboids: 1,
+                requests: 7
+            },
+        ],
+        stats.groups
+    );
+
+    let stats = sql_mgr.usage_stats(since, 10, UsageGrouping::Channel);
+    assert_eq!(4, stats.top.len());
+    let names: Vec<(String, i64)> = stats
+        .groups
+        .into_iter()
+        .map(|g| (g.name, g.requests))
+        .collect();
+    assert_eq!(
+        vec![
+            ("col2/exp/chan".to_string(), 7),
+            ("col1/exp/chan_a".to_string(), 6),
+            ("col1/exp/chan_b".to_string(), 3),
+        ],
+        names
+    );
+
+    let stats = sql_mgr.usage_stats(Utc::now().naive_utc(), 10, UsageGrouping::Channel);
+    assert_eq!(0, stats.cuboids);
+    assert_eq!(0, stats.total_requests);
+    assert!(stats.top.is_empty());
+    assert!(stats.groups.is_empty());
+}
+
+#[test]
+fn test_verify() {
+    use schema::cuboids::dsl::*;
+
+    let SqlCacheInterfaceTestItems {
+        mut sql_mgr,
+        remove_calls,
+    } = super::setup_db();
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_str().unwrap();
+    sql_mgr.add_cache_root(root);
+    let (root_id, _) = sql_mgr.split_root(&format!("{}/key", root));
+    let hashes = CuboidHashes::new();
+
+    let size = config::CUBOID_SIZE;
+    let full = cuboid_file::encode(
+        cuboid_file::CURRENT_VERSION,
+        size,
+        &vec![1; (size.x * size.y * size.z) as usize],
+    );
+    let files: Vec<(&str, Option<&[u8]>)> = vec![
+        ("/good", Some(&full)),
+        ("/truncated", Some(&full[..100])),
+        ("/missing", None),
+        ("/changed", Some(&full)),
+    ];
+    let now = Utc::now().naive_utc();
+    for (i, (key, contents)) in files.iter().enumerate() {
+        if let Some(contents) = contents {
+            fs::write(format!("{}{}", root, key), contents).unwrap();
+        }
+        diesel::insert_into(cuboids)
+            .values(Cuboid {
+                id: (i + 1) as i64,
+                cache_root: root_id,
+                cube_key: key.to_string(),
+                requests: 1,
+                created: now,
+                last_accessed: now,
+            })
+            .execute(&*sql_mgr.connection())
+            .unwrap();
+    }
+    let good = format!("{}/good", root);
+    hashes.record(&good, etag::hash_bytes(&full));
+    let changed = format!("{}/changed", root);
+    hashes.record(&changed, etag::hash_bytes(b"something else"));
+
+    let first = sql_mgr.verify(0, 3, &hashes, false);
+    assert_eq!(3, first.checked);
+    assert_eq!(Some(3), first.next);
+    assert_eq!(vec![format!("{}/missing", root)], first.missing);
+    assert_eq!(
+        vec![format!("{}/truncated", root)],
+        first
+            .corrupt
+            .iter()
+            .map(|c| c.path.clone())
+            .collect::<Vec<_>>()
+    );
+
+    let second = sql_mgr.verify(3, 3, &hashes, false);
+    assert_eq!(1, second.checked);
+    assert_eq!(None, second.next);
+    assert_eq!(changed, second.corrupt[0].path);
+    assert_eq!(4, sql_mgr.num_cuboids());
+
+    let evicted = sql_mgr.verify(0, 10, &hashes, true);
+    assert_eq!(3, evicted.evicted);
+    assert_eq!(1, sql_mgr.num_cuboids());
+    assert_eq!(
+        vec![format!("{}/truncated", root), changed],
+        *remove_calls.borrow()
+    );
+}
+
+#[test]
+fn test_verify_uses_channel_cuboid_size() {
+    let SqlCacheInterfaceTestItems { mut sql_mgr, .. } = super::setup_db();
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_str().unwrap();
+    sql_mgr.add_cache_root(root);
+    let (root_id, _) = sql_mgr.split_root(&format!("{}/key", root));
+    {
+        use schema::channels::dsl::*;
+        diesel::insert_into(channels)
+            .values((
+                collection.eq("col"),
+                experiment.eq("exp"),
+                channel.eq("small"),
+                datatype.eq("uint8"),
+                cuboid_x.eq(4),
+                cuboid_y.eq(4),
+                cuboid_z.eq(2),
+            ))
+            .execute(&*sql_mgr.connection())
+            .unwrap();
+    }
+
+    let small = cuboid_file::encode(
+        cuboid_file::CURRENT_VERSION,
+        Vector3 { x: 4, y: 4, z: 2 },
+        &vec![1; 32],
+    );
+    let now = Utc::now().naive_utc();
+    // The same small cuboid is complete in the registered channel, but not
+    // in an unregistered one with the default size:
+    for (i, key) in ["/col/exp/small/0/x0_y0_z0", "/col/exp/other/0/x0_y0_z0"]
+        .iter()
+        .enumerate()
+    {
+        let path = format!("{}{}", root, key);
+        fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+        fs::write(&path, &small).unwrap();
+        use schema::cuboids::dsl::*;
+        diesel::insert_into(cuboids)
+            .values(Cuboid {
+                id: (i + 1) as i64,
+                cache_root: root_id,
+                cube_key: key.to_string(),
+                requests: 1,
+                created: now,
+                last_accessed: now,
+            })
+            .execute(&*sql_mgr.connection())
+            .unwrap();
+    }
+
+    let report = sql_mgr.verify(0, 10, &CuboidHashes::new(), false);
+    assert_eq!(2, report.checked);
+    assert_eq!(
+        vec![format!("{}/col/exp/other/0/x0_y0_z0", root)],
+        report
+            .corrupt
+            .iter()
+            .map(|c| c.path.clone())
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_compact() {
+    use schema::cuboids::dsl::*;
+
+    let SqlCacheInterfaceTestItems { mut sql_mgr, .. } = super::setup_db();
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_str().unwrap();
+    sql_mgr.add_cache_root(root);
+    let (root_id, _) = sql_mgr.split_root(&format!("{}/key", root));
+    sql_mgr.set_pinned(PinnedChannels::new(vec!["col/pinned".to_string()]));
+
+    let size = config::CUBOID_SIZE;
+    let voxels = (size.x * size.y * size.z) as usize;
+    let zero = cuboid_file::encode(cuboid_file::CURRENT_VERSION, size, &vec![0; voxels]);
+    let data = cuboid_file::encode(cuboid_file::CURRENT_VERSION, size, &vec![1; voxels]);
+    let files: Vec<(&str, Option<&[u8]>)> = vec![
+        ("/col/exp/chan/0/zero", Some(&zero)),
+        ("/col/exp/chan/0/data", Some(&data)),
+        ("/col/exp/chan/0/missing", None),
+        ("/col/pinned/chan/0/zero", Some(&zero)),
+    ];
+    let now = Utc::now().naive_utc();
+    for (i, (key, contents)) in files.iter().enumerate() {
+        let path = format!("{}{}", root, key);
+        if let Some(contents) = contents {
+            fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+        }
+        diesel::insert_into(cuboids)
+            .values(Cuboid {
+                id: (i + 1) as i64,
+                cache_root: root_id,
+                cube_key: key.to_string(),
+                requests: 1,
+                created: now,
+                last_accessed: now,
+            })
+            .execute(&*sql_mgr.connection())
+            .unwrap();
+    }
+
+    let first = sql_mgr.compact(0, 2);
+    assert_eq!(2, first.checked);
+    assert_eq!(1, first.removed);
+    assert_eq!(zero.len() as u64, first.reclaimed);
+    assert_eq!(Some(2), first.next);
+    assert!(!Path::new(&format!("{}/col/exp/chan/0/zero", root)).exists());
+    assert!(Path::new(&format!("{}/col/exp/chan/0/data", root)).exists());
+
+    // The pinned cuboid isn't even checked:
+    let second = sql_mgr.compact(2, 2);
+    assert_eq!(1, second.checked);
+    assert_eq!(0, second.removed);
+    assert_eq!(None, second.next);
+    assert!(Path::new(&format!("{}/col/pinned/chan/0/zero", root)).exists());
+    assert_eq!(3, sql_mgr.num_cuboids());
+}
+
+/// Insert cuboids for a pinned experiment, a channel whose name only matches
+/// the pin if `_` were a wildcard, and an unpinned channel.  The pinned ones
+/// are the least recently used.  Returns the keys of the unpinned cuboids.
+fn insert_pinned_and_unpinned(sql_mgr: &SqliteCacheInterface) -> Vec<String> {
+    use schema::cuboids::dsl::*;
+
+    let prefixes = [
+        "/col/pinned_exp/chan",
+        "/col/pinnedXexp/chan",
+        "/col/exp/chan",
+    ];
+    let mut unpinned = Vec::new();
+    for (i, prefix) in prefixes.iter().enumerate() {
+        for j in 0..2 {
+            let timestamp = Utc
+                .ymd(2020, 4, 19)
+                .and_hms((2 * i + j) as u32, 0, 0)
+                .naive_utc();
+            let key = format!("{}/0/x{}_y0_z0", prefix, j);
+            diesel::insert_into(cuboids)
+                .values(Cuboid {
+                    id: (2 * i + j + 1) as i64,
+                    cache_root: sql_mgr.cache_root_id,
+                    cube_key: key.clone(),
+                    requests: 1,
+                    created: timestamp,
+                    last_accessed: timestamp,
+                })
+                .execute(&*sql_mgr.connection())
+                .unwrap();
+            if i > 0 {
+                unpinned.push(key);
+            }
+        }
+    }
+    unpinned
+}
+
+#[test]
+fn test_pinned_cuboids_never_selected() {
+    let SqlCacheInterfaceTestItems { mut sql_mgr, .. } = super::setup_db();
+    let unpinned = insert_pinned_and_unpinned(&sql_mgr);
+    sql_mgr.set_pinned(PinnedChannels::new(vec!["col/pinned_exp".to_string()]));
+    let sql_mgr = Rc::new(RefCell::new(sql_mgr));
+
+    // Far over the limit, so every eligible cuboid is selected.
+    let mut lru = MaxCountLruStrategy::new(0, sql_mgr.clone());
+    lru.set_size(6);
+    let selected: Vec<String> = lru
+        .select_cuboids_for_removal()
+        .into_iter()
+        .map(|c| c.cube_key)
+        .collect();
+    assert_eq!(unpinned, selected);
+
+    let mut decay = MaxCountDecayStrategy::new(0, 3600, sql_mgr.clone());
+    decay.set_size(6);
+    let mut selected: Vec<String> = decay
+        .select_cuboids_for_removal()
+        .into_iter()
+        .map(|c| c.cube_key)
+        .collect();
+    selected.sort();
+    let mut expected = unpinned.clone();
+    expected.sort();
+    assert_eq!(expected, selected);
+}
+
+#[test]
+fn test_pinned_and_found_through_channel_keys() {
+    let SqlCacheInterfaceTestItems {
+        mut sql_mgr,
+        remove_calls,
+    } = super::setup_db();
+    let keys = Arc::new(ChannelKeys::new(Arc::clone(&sql_mgr.pool), "salt"));
+    let root = config::CUBOID_ROOT_PATH;
+    let path = |channel: &str| format!("{}/{}/0/x0_y0_z0", root, keys.key(channel));
+    for channel in &["col/exp/chan", "col/exp/other", "col/exp2/chan"] {
+        sql_mgr.log_request(path(channel));
+    }
+    sql_mgr.set_channel_keys(Arc::clone(&keys));
+    sql_mgr.set_pinned(PinnedChannels::new(vec!["col/exp".to_string()]));
+
+    let found: Vec<String> = sql_mgr
+        .find_under("col/exp/chan")
+        .into_iter()
+        .map(|(found, _)| found)
+        .collect();
+    assert_eq!(vec![path("col/exp/chan")], found);
+    assert_eq!(2, sql_mgr.find_under("col/exp").len());
+    assert!(sql_mgr.find_under("col/exp3").is_empty());
+
+    assert_eq!(1, sql_mgr.evict_to(0));
+    assert_eq!(vec![path("col/exp2/chan")], *remove_calls.borrow());
+}
+
+#[test]
+fn test_pinned_at_runtime_survive_evict_to() {
+    let SqlCacheInterfaceTestItems {
+        mut sql_mgr,
+        remove_calls,
+    } = super::setup_db();
+    let unpinned = insert_pinned_and_unpinned(&sql_mgr);
+    let pinned = PinnedChannels::default();
+    sql_mgr.set_pinned(pinned.clone());
+
+    // Pinning through a clone is seen by the interface.
+    assert!(pinned.pin("col/pinned_exp/chan"));
+    assert!(!pinned.pin("/col/pinned_exp/chan/"));
+
+    assert_eq!(4, sql_mgr.evict_to(0));
+    assert_eq!(2, sql_mgr.num_cuboids());
+    let root = config::CUBOID_ROOT_PATH;
+    let expected: Vec<String> = unpinned
+        .iter()
+        .map(|key| format!("{}{}", root, key))
+        .collect();
+    assert_eq!(expected, *remove_calls.borrow());
+}
@@ -0,0 +1,179 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Disk guard module.
+///
+/// Watches the free space on the disks holding the cache, so that the
+/// server stops caching before a full disk makes cuboid writes fail.  While
+/// space is low, fetched cuboids are served without being cached, and the
+/// usage tracker is asked to evict some of the cache every check until
+/// there's room again.
+use crate::usage_tracker;
+use serde::Serialize;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(test)]
+pub mod tests;
+
+/// Fraction of the cache evicted at each check that finds space low.
+pub const SHED_FRACTION: f64 = 0.1;
+
+/// Wrap free space lookups for use in testing.
+pub trait FreeSpace: Send + Sync {
+    /// Bytes available to unprivileged users on the filesystem holding
+    /// `path`.
+    fn free_bytes(&self, path: &Path) -> io::Result<u64>;
+}
+
+/// Looks up free space with `statvfs(3)`.
+pub struct Statvfs;
+
+impl FreeSpace for Statvfs {
+    fn free_bytes(&self, path: &Path) -> io::Result<u64> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+    }
+}
+
+/// The guard's view of the disks, as of its last check.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DiskStatus {
+    /// Free bytes on the fullest disk holding a cache root.
+    pub free_bytes: u64,
+    /// Free bytes below which caching is paused.
+    pub min_free_bytes: u64,
+    /// Whether fetched cuboids are being served without being cached.
+    pub caching_paused: bool,
+}
+
+pub struct DiskGuard {
+    /// Directories whose disks are watched.
+    roots: Vec<String>,
+    min_free: u64,
+    source: Box<dyn FreeSpace>,
+    /// Whether space was low at the last check.
+    low: AtomicBool,
+    /// Free bytes on the fullest disk at the last check.
+    free: AtomicU64,
+}
+
+impl DiskGuard {
+    /// Watch the disks holding some cache roots with `statvfs(3)`.
+    ///
+    /// # Arguments
+    ///
+    /// * `roots` - Cache root directories
+    /// * `min_free` - Free bytes below which caching is paused
+    ///
+    pub fn new(roots: Vec<String>, min_free: u64) -> DiskGuard {
+        DiskGuard::with_source(roots, min_free, Box::new(Statvfs))
+    }
+
+    /// Watch the disks holding some cache roots, looking up free space
+    /// with `source`.
+    pub fn with_source(roots: Vec<String>, min_free: u64, source: Box<dyn FreeSpace>) -> DiskGuard {
+        DiskGuard {
+            roots,
+            min_free,
+            source,
+            low: AtomicBool::new(false),
+            free: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    /// Look up the free space on every root's disk, pausing caching if the
+    /// fullest has less than the minimum and resuming it once they all
+    /// have enough.  Roots that can't be checked (e.g. that don't exist
+    /// yet) are skipped.  Returns whether space is low.
+    pub fn check(&self) -> bool {
+        let free = self
+            .roots
+            .iter()
+            .filter_map(|root| match self.source.free_bytes(Path::new(root)) {
+                Ok(free) => Some(free),
+                Err(err) => {
+                    println!("Failed to check free space on {}: {}", root, err);
+                    None
+                }
+            })
+            .min()
+            .unwrap_or(u64::MAX);
+        self.free.store(free, Ordering::Relaxed);
+        let low = free < self.min_free;
+        let was_low = self.low.swap(low, Ordering::Relaxed);
+        if low && !was_low {
+            println!(
+                "Disk space low ({} bytes free); pausing caching until {} are",
+                free, self.min_free
+            );
+        } else if was_low && !low {
+            println!(
+                "Disk space recovered ({} bytes free); resuming caching",
+                free
+            );
+        }
+        low
+    }
+
+    /// Whether space was low at the last check, so caching is paused.
+    pub fn is_low(&self) -> bool {
+        self.low.load(Ordering::Relaxed)
+    }
+
+    /// The disks as of the last check.
+    pub fn status(&self) -> DiskStatus {
+        DiskStatus {
+            free_bytes: self.free.load(Ordering::Relaxed),
+            min_free_bytes: self.min_free,
+            caching_paused: self.is_low(),
+        }
+    }
+}
+
+/// Check a guard's disks every `interval` in a background thread, asking
+/// the usage tracker to evict `SHED_FRACTION` of the cache whenever space
+/// is low.
+///
+/// # Arguments
+///
+/// * `guard` - The guard to check
+/// * `interval` - Time between checks
+///
+pub fn run(guard: Arc<DiskGuard>, interval: Duration) {
+    if guard.check() {
+        usage_tracker::shed(SHED_FRACTION);
+    }
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if guard.check() {
+            usage_tracker::shed(SHED_FRACTION);
+        }
+    });
+}
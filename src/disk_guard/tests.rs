@@ -0,0 +1,87 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::disk_guard::{DiskGuard, DiskStatus, FreeSpace};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Reports however many bytes the test sets as free on every disk, except
+/// for roots that don't exist.
+pub struct MockFreeSpace(pub Arc<AtomicU64>);
+
+impl FreeSpace for MockFreeSpace {
+    fn free_bytes(&self, path: &Path) -> io::Result<u64> {
+        if path.ends_with("missing") {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No such directory"));
+        }
+        Ok(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// A guard pausing caching below 100 free bytes, and the free bytes it
+/// sees.
+pub fn mock_guard(roots: Vec<String>) -> (DiskGuard, Arc<AtomicU64>) {
+    let free = Arc::new(AtomicU64::new(1000));
+    let guard = DiskGuard::with_source(roots, 100, Box::new(MockFreeSpace(Arc::clone(&free))));
+    (guard, free)
+}
+
+#[test]
+fn test_low_space_pauses_caching_until_recovered() {
+    let (guard, free) = mock_guard(vec!["/cache".to_string()]);
+    assert!(!guard.check());
+    assert!(!guard.is_low());
+
+    free.store(99, Ordering::SeqCst);
+    assert!(guard.check());
+    assert_eq!(
+        DiskStatus {
+            free_bytes: 99,
+            min_free_bytes: 100,
+            caching_paused: true,
+        },
+        guard.status()
+    );
+
+    // Nothing changes between checks:
+    free.store(100, Ordering::SeqCst);
+    assert!(guard.is_low());
+    assert!(!guard.check());
+    assert!(!guard.status().caching_paused);
+}
+
+#[test]
+fn test_unchecked_roots_skipped() {
+    let (guard, free) = mock_guard(vec!["/cache".to_string(), "/missing".to_string()]);
+    free.store(10, Ordering::SeqCst);
+    assert!(guard.check());
+    assert_eq!(10, guard.status().free_bytes);
+
+    let (guard, _) = mock_guard(vec!["/missing".to_string()]);
+    assert!(!guard.check());
+}
+
+#[test]
+fn test_statvfs() {
+    let dir = tempfile::tempdir().unwrap();
+    let guard = DiskGuard::new(vec![dir.path().to_str().unwrap().to_string()], 1);
+    assert!(!guard.check());
+    assert!(guard.status().free_bytes > 0);
+}
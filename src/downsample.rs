@@ -0,0 +1,141 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Downsampling module.
+///
+/// Builds a lower resolution from a higher one the way the BossDB does by
+/// default for anisotropic data: each level halves x and y and leaves z
+/// alone.
+use ndarray::{Array3, ArrayView3};
+use std::collections::HashMap;
+
+#[cfg(test)]
+pub mod tests;
+
+/// How a 2x2 block of voxels is reduced to one voxel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Downsampling {
+    /// The rounded mean, for image channels.
+    Mean,
+    /// The most common value (the smallest one, on a tie), for annotation
+    /// channels.
+    Mode,
+}
+
+impl Downsampling {
+    /// Parse `mean` or `mode`.
+    pub fn parse(name: &str) -> Option<Downsampling> {
+        match name {
+            "mean" => Some(Downsampling::Mean),
+            "mode" => Some(Downsampling::Mode),
+            _ => None,
+        }
+    }
+
+    /// The method suited to a type of channel, as named by the BossDB:
+    /// mode for `annotation` channels, and mean for anything else.
+    pub fn for_channel_type(kind: &str) -> Downsampling {
+        match kind {
+            "annotation" => Downsampling::Mode,
+            _ => Downsampling::Mean,
+        }
+    }
+
+    /// Reduce a block of voxels to one.
+    fn reduce(self, block: &mut [u8]) -> u8 {
+        match self {
+            Downsampling::Mean => {
+                let sum: u32 = block.iter().map(|v| *v as u32).sum();
+                let n = block.len() as u32;
+                ((sum + n / 2) / n) as u8
+            }
+            Downsampling::Mode => {
+                block.sort();
+                let mut best = (0, block[0]);
+                let mut i = 0;
+                while i < block.len() {
+                    let run = block[i..].iter().take_while(|v| **v == block[i]).count();
+                    if run > best.0 {
+                        best = (run, block[i]);
+                    }
+                    i += run;
+                }
+                best.1
+            }
+        }
+    }
+}
+
+/// Which channels may have a missing resolution synthesized from a cached
+/// higher resolution, and how.  Set globally or per channel, since image
+/// and annotation channels need different methods.
+#[derive(Clone, Debug, Default)]
+pub struct SynthesisMethods {
+    default: Option<Downsampling>,
+    channels: HashMap<String, Option<Downsampling>>,
+}
+
+impl SynthesisMethods {
+    pub fn new(default: Option<Downsampling>) -> SynthesisMethods {
+        SynthesisMethods {
+            default,
+            channels: HashMap::new(),
+        }
+    }
+
+    pub fn set_default(&mut self, method: Option<Downsampling>) {
+        self.default = method;
+    }
+
+    /// Override the method of a single channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel, as `collection/experiment/channel`
+    /// * `method` - How to downsample, or `None` to never synthesize
+    ///
+    pub fn set_channel(&mut self, channel: &str, method: Option<Downsampling>) {
+        self.channels.insert(channel.to_string(), method);
+    }
+
+    /// Get the method of a channel (as `collection/experiment/channel`), or
+    /// `None` if its resolutions shouldn't be synthesized.
+    pub fn get(&self, channel: &str) -> Option<Downsampling> {
+        *self.channels.get(channel).unwrap_or(&self.default)
+    }
+}
+
+/// Halve a ZYX array in x and y.  An odd last row or column is dropped.
+///
+/// # Arguments
+///
+/// * `data` - Voxels in ZYX order
+/// * `method` - How each 2x2 block is reduced
+///
+pub fn halve_xy(data: ArrayView3<u8>, method: Downsampling) -> Array3<u8> {
+    let (z_len, y_len, x_len) = data.dim();
+    Array3::from_shape_fn((z_len, y_len / 2, x_len / 2), |(z, y, x)| {
+        let mut block = [
+            data[[z, 2 * y, 2 * x]],
+            data[[z, 2 * y, 2 * x + 1]],
+            data[[z, 2 * y + 1, 2 * x]],
+            data[[z, 2 * y + 1, 2 * x + 1]],
+        ];
+        method.reduce(&mut block)
+    })
+}
@@ -0,0 +1,45 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::downsample::{halve_xy, Downsampling, SynthesisMethods};
+use ndarray::{arr3, Array3};
+
+#[test]
+fn test_halve_xy_mean() {
+    let data = arr3(&[[[0, 1, 10, 10, 7], [2, 2, 10, 11, 7], [9, 9, 9, 9, 9]]]);
+    let expected: Array3<u8> = arr3(&[[[1, 10]]]);
+    assert_eq!(expected, halve_xy(data.view(), Downsampling::Mean));
+}
+
+#[test]
+fn test_halve_xy_mode() {
+    let data = arr3(&[[[3, 3, 1, 2], [3, 5, 2, 1]], [[4, 4, 0, 0], [0, 0, 6, 7]]]);
+    let expected: Array3<u8> = arr3(&[[[3, 1]], [[0, 0]]]);
+    assert_eq!(expected, halve_xy(data.view(), Downsampling::Mode));
+}
+
+#[test]
+fn test_synthesis_methods() {
+    let mut methods = SynthesisMethods::new(Some(Downsampling::Mean));
+    methods.set_channel("col/exp/anno", Some(Downsampling::Mode));
+    methods.set_channel("col/exp/raw", None);
+    assert_eq!(Some(Downsampling::Mean), methods.get("col/exp/chan"));
+    assert_eq!(Some(Downsampling::Mode), methods.get("col/exp/anno"));
+    assert_eq!(None, methods.get("col/exp/raw"));
+    assert_eq!(None, SynthesisMethods::default().get("col/exp/chan"));
+}
@@ -0,0 +1,187 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// ETag module.
+///
+/// Cutout ETags are built from the content hashes of the cuboids that make
+/// up the cutout.  Hashing a cuboid means reading all of it, so hashes are
+/// remembered in a `CuboidHashes` store and only recomputed when the file
+/// on disk changes underneath them.
+///
+/// Cutouts also carry a `Last-Modified` time, the newest modification time
+/// of their cuboid files, for clients that validate by time instead.
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[cfg(test)]
+pub mod tests;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Incremental 64-bit FNV-1a hash.  Unlike the std `DefaultHasher`, the
+/// output is guaranteed to be stable across Rust releases, which matters
+/// since ETags outlive the process.
+pub struct Fnv64(u64);
+
+impl Fnv64 {
+    pub fn new() -> Fnv64 {
+        Fnv64(FNV_OFFSET_BASIS)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hash a cuboid's contents.
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv64::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// Does an `If-None-Match` header value match the given ETag?
+///
+/// # Arguments
+///
+/// * `header` - Raw header value, possibly a comma-separated list or `*`
+/// * `etag` - The quoted ETag of the current representation
+///
+pub fn if_none_match(header: &str, etag: &str) -> bool {
+    header.split(',').map(|t| t.trim()).any(|t| {
+        let t = t.trim_start_matches("W/");
+        t == "*" || t == etag
+    })
+}
+
+/// Format a time as an HTTP date (RFC 7231), like
+/// `Sun, 19 Apr 2020 12:00:00 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// Has a resource gone unmodified since the time in an `If-Modified-Since`
+/// header?  An unparseable header never matches.
+///
+/// # Arguments
+///
+/// * `header` - Raw header value, an HTTP date
+/// * `modified` - When the resource last changed
+///
+pub fn not_modified_since(header: &str, modified: SystemTime) -> bool {
+    match DateTime::parse_from_rfc2822(header.trim()) {
+        // HTTP dates only go down to seconds:
+        Ok(since) => DateTime::<Utc>::from(modified).timestamp() <= since.timestamp(),
+        Err(_) => false,
+    }
+}
+
+struct CuboidHash {
+    len: u64,
+    modified: SystemTime,
+    hash: u64,
+}
+
+/// Remembers content hashes of cuboid files.
+///
+/// Entries are validated against the file's length and modification time,
+/// so a cuboid rewritten behind our back (e.g. by another process) is simply
+/// rehashed on next use.
+pub struct CuboidHashes {
+    entries: Mutex<HashMap<String, CuboidHash>>,
+}
+
+impl CuboidHashes {
+    pub fn new() -> CuboidHashes {
+        CuboidHashes {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remember the hash of a cuboid that was just written.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the cuboid file
+    /// * `hash` - Hash of the bytes that were written to it
+    ///
+    pub fn record(&self, path: &str, hash: u64) {
+        if let Some((len, modified)) = file_signature(path) {
+            self.entries.lock().unwrap().insert(
+                path.to_string(),
+                CuboidHash {
+                    len,
+                    modified,
+                    hash,
+                },
+            );
+        }
+    }
+
+    /// Check a cuboid's contents against its remembered hash.  Returns
+    /// `None` if there's nothing to check against, e.g. because the file is
+    /// unknown or was rewritten since.
+    pub fn verify(&self, path: &str) -> Option<bool> {
+        let (len, modified) = file_signature(path)?;
+        let expected = match self.entries.lock().unwrap().get(path) {
+            Some(entry) if entry.len == len && entry.modified == modified => entry.hash,
+            _ => return None,
+        };
+        Some(hash_bytes(&fs::read(path).ok()?) == expected)
+    }
+
+    /// Get the hash of a cuboid, hashing the file if it's unknown or has
+    /// changed.  Returns `None` if the cuboid doesn't exist.
+    pub fn get(&self, path: &str) -> Option<u64> {
+        let (len, modified) = file_signature(path)?;
+        if let Some(entry) = self.entries.lock().unwrap().get(path) {
+            if entry.len == len && entry.modified == modified {
+                return Some(entry.hash);
+            }
+        }
+
+        let hash = hash_bytes(&fs::read(path).ok()?);
+        self.entries.lock().unwrap().insert(
+            path.to_string(),
+            CuboidHash {
+                len,
+                modified,
+                hash,
+            },
+        );
+        Some(hash)
+    }
+}
+
+fn file_signature(path: &str) -> Option<(u64, SystemTime)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.len(), meta.modified().ok()?))
+}
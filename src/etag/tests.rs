@@ -0,0 +1,152 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::data_manager::{ChunkedFileDataManager, DataManager, Vector3};
+use crate::etag::{hash_bytes, http_date, if_none_match, not_modified_since, CuboidHashes};
+use ndarray::Array;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const URI: &str = "bossdb://col/exp/chan";
+
+fn cuboid_size() -> Vector3 {
+    Vector3 { x: 4, y: 4, z: 2 }
+}
+
+fn origin() -> Vector3 {
+    Vector3 { x: 0, y: 0, z: 0 }
+}
+
+fn write_cuboid(fm: &ChunkedFileDataManager, value: u8) {
+    let data = Array::from_elem((2, 4, 4), value);
+    assert!(fm.put_data(URI.to_string(), 0, origin(), data));
+}
+
+#[test]
+fn test_if_none_match() {
+    assert!(if_none_match("\"abc\"", "\"abc\""));
+    assert!(if_none_match("\"xyz\", \"abc\"", "\"abc\""));
+    assert!(if_none_match("W/\"abc\"", "\"abc\""));
+    assert!(if_none_match("*", "\"abc\""));
+    assert!(!if_none_match("\"xyz\"", "\"abc\""));
+    assert!(!if_none_match("abc", "\"abc\""));
+}
+
+#[test]
+fn test_http_dates() {
+    let time = UNIX_EPOCH + Duration::from_secs(1_587_297_600);
+    assert_eq!("Sun, 19 Apr 2020 12:00:00 GMT", http_date(time));
+
+    assert!(not_modified_since("Sun, 19 Apr 2020 12:00:00 GMT", time));
+    // Fractions of a second are below the resolution of the header:
+    assert!(not_modified_since(
+        "Sun, 19 Apr 2020 12:00:00 GMT",
+        time + Duration::from_millis(500)
+    ));
+    assert!(not_modified_since("Sun, 19 Apr 2020 13:00:00 +0100", time));
+    assert!(!not_modified_since("Sun, 19 Apr 2020 11:59:59 GMT", time));
+    assert!(!not_modified_since("", time));
+    assert!(!not_modified_since("2020-04-19T12:00:00Z", time));
+}
+
+#[test]
+fn test_cuboid_hashes_rehash_changed_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cuboid");
+    let path = path.to_str().unwrap();
+    let hashes = CuboidHashes::new();
+
+    assert_eq!(hashes.get(path), None);
+
+    fs::write(path, b"first").unwrap();
+    assert_eq!(hashes.get(path), Some(hash_bytes(b"first")));
+
+    // A rewrite that didn't go through `record` is picked up from disk:
+    fs::write(path, b"second write").unwrap();
+    assert_eq!(hashes.get(path), Some(hash_bytes(b"second write")));
+}
+
+#[test]
+fn test_cutout_etag_missing_cuboid() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = ChunkedFileDataManager::new(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        false,
+    );
+    assert_eq!(
+        fm.cutout_etag(URI, 0, origin(), cuboid_size(), "blosc"),
+        None
+    );
+}
+
+#[test]
+fn test_cutout_etag_changes_with_content() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fm = ChunkedFileDataManager::new(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        false,
+    );
+    fm.set_hashes(Arc::new(CuboidHashes::new()));
+
+    write_cuboid(&fm, 1);
+    let first = fm
+        .cutout_etag(URI, 0, origin(), cuboid_size(), "blosc")
+        .unwrap();
+    assert_eq!(
+        fm.cutout_etag(URI, 0, origin(), cuboid_size(), "blosc"),
+        Some(first.clone())
+    );
+    assert_ne!(
+        fm.cutout_etag(URI, 0, origin(), cuboid_size(), "jpeg"),
+        Some(first.clone())
+    );
+
+    write_cuboid(&fm, 2);
+    let second = fm
+        .cutout_etag(URI, 0, origin(), cuboid_size(), "blosc")
+        .unwrap();
+    assert_ne!(first, second);
+}
+
+#[test]
+fn test_cutout_last_modified() {
+    let dir = tempfile::tempdir().unwrap();
+    let fm = ChunkedFileDataManager::new(
+        dir.path().to_str().unwrap().to_string(),
+        cuboid_size(),
+        false,
+    );
+    assert_eq!(
+        fm.cutout_last_modified(URI, 0, origin(), cuboid_size()),
+        None
+    );
+
+    let before = SystemTime::now() - Duration::from_secs(1);
+    write_cuboid(&fm, 1);
+    let modified = fm
+        .cutout_last_modified(URI, 0, origin(), cuboid_size())
+        .unwrap();
+    assert!(before <= modified && modified <= SystemTime::now());
+
+    // Not every cuboid of a bigger cutout is cached:
+    let destination = Vector3 { x: 8, y: 4, z: 2 };
+    assert_eq!(fm.cutout_last_modified(URI, 0, origin(), destination), None);
+}
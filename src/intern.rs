@@ -18,17 +18,62 @@ limitations under the License.
 
 pub mod remote {
     /// This module is intended to begin to mirror the intern Python library.
+    use crate::data_manager::{Coords, UpstreamError, Vector3};
+    use lazy_static::lazy_static;
     use ndarray::{Array, Array3};
-    use reqwest::blocking::Client;
+    use reqwest::{Client, StatusCode};
+    use serde::Serialize;
+    use tokio::runtime::{Builder, Runtime};
 
+    lazy_static! {
+        /// Runs every upstream request, so that in-flight requests are
+        /// cheap tasks rather than blocked OS threads.
+        static ref RUNTIME: Runtime = Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .thread_name("boss-remote")
+            .build()
+            .expect("Failed to start the BossRemote runtime");
+    }
+
+    /// The runtime shared by all BossRemotes.  Spawn onto it to have many
+    /// requests in flight at once; don't `block_on` it from one of its own
+    /// tasks.
+    pub fn runtime() -> &'static Runtime {
+        &RUNTIME
+    }
+
+    #[derive(Clone)]
     pub struct BossRemote {
         /// A BossRemote analog to Python's `intern.remote.boss.BossRemote`.
         protocol: String,
         host: String,
+        /// Path prefix of the API, e.g. `v1`.
+        api_prefix: String,
         token: String,
         client: Client,
     }
 
+    /// API version of BossDB's public deployment.
+    pub const DEFAULT_API_PREFIX: &str = "v1";
+
+    /// Protocols a BossDB can be reached over.
+    pub const PROTOCOLS: [&str; 2] = ["https", "http"];
+
+    /// Protocol of BossDB's public deployment.
+    pub const DEFAULT_PROTOCOL: &str = "https";
+
+    /// Split the scheme, if any, off a host like `http://boss.internal`.
+    pub fn split_scheme(host: &str) -> (Option<&str>, &str) {
+        match host.find("://") {
+            Some(i) => (Some(&host[..i]), &host[i + 3..]),
+            None => (None, host),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests;
+
     /// Parse a URI and return a collection, experiment, and channel.
     ///
     /// # Arguments
@@ -49,6 +94,68 @@ pub mod remote {
         );
     }
 
+    /// How a channel's voxels map to physical space, from the coordinate
+    /// frame and hierarchy of its experiment.
+    #[derive(Clone, Debug, PartialEq, Serialize)]
+    pub struct ChannelGeometry {
+        /// Size of a voxel at resolution 0, in x, y, z order.
+        pub voxel_size: [f64; 3],
+        /// Unit of `voxel_size`, e.g. `nanometers`.
+        pub voxel_unit: String,
+        /// How many resolutions the experiment's hierarchy has.
+        pub num_hierarchy_levels: u8,
+        /// `anisotropic` (only x and y are downsampled) or `isotropic`.
+        pub hierarchy_method: String,
+    }
+
+    /// A coordinate frame on the Boss DB host: the voxels that the
+    /// channels of the experiments using it may hold, at resolution 0.
+    #[derive(Clone, Debug, PartialEq, Serialize)]
+    pub struct CoordFrame {
+        pub name: String,
+        /// Where the frame starts, which may be negative.  Voxels before
+        /// it can't be read.
+        pub start: Coords,
+        /// Where the frame stops (exclusive).
+        pub stop: Coords,
+    }
+
+    impl ChannelGeometry {
+        /// The size of a voxel at a resolution level.
+        ///
+        /// # Arguments
+        ///
+        /// * `res` - Resolution level
+        pub fn voxel_size_at(&self, res: u8) -> [f64; 3] {
+            let scale = 2f64.powi(res.into());
+            let [x, y, z] = self.voxel_size;
+            if self.hierarchy_method == "isotropic" {
+                [x * scale, y * scale, z * scale]
+            } else {
+                [x * scale, y * scale, z]
+            }
+        }
+
+        /// The start and (exclusive) stop of a region at resolution 0, at a
+        /// resolution level, rounding outwards.  Like the voxel size, z is
+        /// only scaled in an isotropic hierarchy.
+        ///
+        /// # Arguments
+        ///
+        /// * `res` - Resolution level
+        /// * `start` - Where the region starts at resolution 0
+        /// * `stop` - Where it stops
+        pub fn extent_at(&self, res: u8, start: Coords, stop: Coords) -> (Coords, Coords) {
+            let (mut start_at, mut stop_at) = (start.at_res(res), stop.at_res_rounding_up(res));
+            if self.hierarchy_method == "isotropic" {
+                let shift = res.min(63);
+                start_at.z = start.z >> shift;
+                stop_at.z = -((-stop.z) >> shift);
+            }
+            (start_at, stop_at)
+        }
+    }
+
     impl BossRemote {
         /// A BossRemote handles its own authentication, etc.
         ///
@@ -62,17 +169,280 @@ pub mod remote {
             let br = BossRemote {
                 protocol,
                 host,
+                api_prefix: DEFAULT_API_PREFIX.to_string(),
                 token,
                 client: Client::new(),
             };
             return br;
         }
 
+        /// Use a deployment whose API lives under a different path, e.g.
+        /// `v2` or `boss/v1`.  An empty prefix means the API is at the root
+        /// of the host.
+        pub fn set_api_prefix(&mut self, prefix: &str) {
+            self.api_prefix = prefix.trim_matches('/').to_string();
+        }
+
+        /// Authenticate with a different token from now on, e.g. after
+        /// the old one is rotated.
+        pub fn set_token(&mut self, token: &str) {
+            self.token = token.to_string();
+        }
+
         fn build_url(&self, suffix: String) -> String {
-            format!("{}://{}/v1/{}/", self.protocol, self.host, suffix)
+            if self.api_prefix.is_empty() {
+                return format!("{}://{}/{}/", self.protocol, self.host, suffix);
+            }
+            format!(
+                "{}://{}/{}/{}/",
+                self.protocol, self.host, self.api_prefix, suffix
+            )
+        }
+
+        /// URL of a cutout, for reading or writing it.
+        ///
+        /// # Arguments
+        ///
+        /// * `boss_uri` - A URI like `bossdb://col/exp/chan`
+        /// * `res` - Resolution level
+        /// * `xs` - Extents
+        /// * `ys` - Extents
+        /// * `zs` - Extents
+        ///
+        pub fn cutout_url(
+            &self,
+            boss_uri: String,
+            res: u8,
+            xs: (i64, i64),
+            ys: (i64, i64),
+            zs: (i64, i64),
+        ) -> String {
+            let (col, exp, chan) = parse_bossdb_uri(boss_uri);
+            self.build_url(format!(
+                "cutout/{col}/{exp}/{chan}/{res}/{xs_start}:{xs_stop}/{ys_start}:{ys_stop}/{zs_start}:{zs_stop}",
+                col=col, exp=exp, chan=chan, res=res,
+                xs_start = xs.0, xs_stop = xs.1,
+                ys_start = ys.0, ys_stop = ys.1,
+                zs_start = zs.0, zs_stop = zs.1,
+            ))
+        }
+
+        /// Get the datatype of a channel (e.g. `uint8`) from the bosslike
+        /// remote's channel metadata.
+        ///
+        /// # Arguments
+        ///
+        /// * `boss_uri` - String
+        ///
+        /// # Returns
+        ///
+        /// * The datatype string
+        ///
+        pub fn get_channel_datatype(&self, boss_uri: String) -> Result<String, String> {
+            runtime()
+                .handle()
+                .block_on(self.get_channel_datatype_async(boss_uri))
+        }
+
+        /// Async version of `get_channel_datatype`.  Must run on `runtime()`.
+        pub async fn get_channel_datatype_async(&self, boss_uri: String) -> Result<String, String> {
+            let (col, exp, chan) = parse_bossdb_uri(boss_uri);
+            let (url, metadata) = self
+                .get_metadata_async(format!(
+                    "collection/{}/experiment/{}/channel/{}",
+                    col, exp, chan
+                ))
+                .await?;
+            match metadata["datatype"].as_str() {
+                Some(datatype) => Ok(datatype.to_string()),
+                None => Err(format!("{}: no datatype in channel metadata", url)),
+            }
+        }
+
+        /// Get the type of a channel (`image` or `annotation`) from the
+        /// bosslike remote's channel metadata.
+        ///
+        /// # Arguments
+        ///
+        /// * `boss_uri` - String
+        ///
+        /// # Returns
+        ///
+        /// * The type string
+        ///
+        pub fn get_channel_type(&self, boss_uri: String) -> Result<String, String> {
+            runtime()
+                .handle()
+                .block_on(self.get_channel_type_async(boss_uri))
+        }
+
+        /// Async version of `get_channel_type`.  Must run on `runtime()`.
+        pub async fn get_channel_type_async(&self, boss_uri: String) -> Result<String, String> {
+            let (col, exp, chan) = parse_bossdb_uri(boss_uri);
+            let (url, metadata) = self
+                .get_metadata_async(format!(
+                    "collection/{}/experiment/{}/channel/{}",
+                    col, exp, chan
+                ))
+                .await?;
+            match metadata["type"].as_str() {
+                Some(kind) => Ok(kind.to_string()),
+                None => Err(format!("{}: no type in channel metadata", url)),
+            }
+        }
+
+        /// Get the extent of a channel at resolution 0 from the coordinate
+        /// frame of its experiment.
+        ///
+        /// # Arguments
+        ///
+        /// * `boss_uri` - String
+        ///
+        /// # Returns
+        ///
+        /// * The start and (exclusive) stop of the frame
+        ///
+        pub fn get_channel_extent(&self, boss_uri: String) -> Result<(Coords, Coords), String> {
+            runtime()
+                .handle()
+                .block_on(self.get_channel_extent_async(boss_uri))
+        }
+
+        /// Async version of `get_channel_extent`.  Must run on `runtime()`.
+        pub async fn get_channel_extent_async(
+            &self,
+            boss_uri: String,
+        ) -> Result<(Coords, Coords), String> {
+            let frame = self.get_coord_frame_async(boss_uri).await?;
+            Ok((frame.start, frame.stop))
+        }
+
+        /// Get the coordinate frame of a channel's experiment.
+        ///
+        /// # Arguments
+        ///
+        /// * `boss_uri` - String
+        ///
+        /// # Returns
+        ///
+        /// * The frame, at resolution 0
+        ///
+        pub fn get_coord_frame(&self, boss_uri: String) -> Result<CoordFrame, String> {
+            runtime()
+                .handle()
+                .block_on(self.get_coord_frame_async(boss_uri))
+        }
+
+        /// Async version of `get_coord_frame`.  Must run on `runtime()`.
+        pub async fn get_coord_frame_async(&self, boss_uri: String) -> Result<CoordFrame, String> {
+            let (col, exp, _) = parse_bossdb_uri(boss_uri);
+            let (url, experiment) = self
+                .get_metadata_async(format!("collection/{}/experiment/{}", col, exp))
+                .await?;
+            let name = match experiment["coord_frame"].as_str() {
+                Some(name) => name.to_string(),
+                None => return Err(format!("{}: no coord_frame in experiment metadata", url)),
+            };
+            let (url, frame) = self.get_metadata_async(format!("coord/{}", name)).await?;
+            let bound = |name: &str| {
+                frame[name]
+                    .as_i64()
+                    .ok_or_else(|| format!("{}: no {} in coordinate frame", url, name))
+            };
+            let start = Coords {
+                x: bound("x_start")?,
+                y: bound("y_start")?,
+                z: bound("z_start")?,
+            };
+            let stop = Coords {
+                x: bound("x_stop")?,
+                y: bound("y_stop")?,
+                z: bound("z_stop")?,
+            };
+            if stop.x < start.x || stop.y < start.y || stop.z < start.z {
+                return Err(format!("{}: coordinate frame stops before it starts", url));
+            }
+            Ok(CoordFrame { name, start, stop })
         }
 
-        /// Get a cutout from the bosslike remote.
+        /// Get the voxel size and hierarchy of a channel from its experiment
+        /// and coordinate frame.
+        ///
+        /// # Arguments
+        ///
+        /// * `boss_uri` - String
+        ///
+        /// # Returns
+        ///
+        /// * The channel's geometry
+        ///
+        pub fn get_channel_geometry(&self, boss_uri: String) -> Result<ChannelGeometry, String> {
+            runtime()
+                .handle()
+                .block_on(self.get_channel_geometry_async(boss_uri))
+        }
+
+        /// Async version of `get_channel_geometry`.  Must run on `runtime()`.
+        pub async fn get_channel_geometry_async(
+            &self,
+            boss_uri: String,
+        ) -> Result<ChannelGeometry, String> {
+            let (col, exp, _) = parse_bossdb_uri(boss_uri);
+            let (url, experiment) = self
+                .get_metadata_async(format!("collection/{}/experiment/{}", col, exp))
+                .await?;
+            let frame = match experiment["coord_frame"].as_str() {
+                Some(frame) => frame,
+                None => return Err(format!("{}: no coord_frame in experiment metadata", url)),
+            };
+            let num_hierarchy_levels =
+                experiment["num_hierarchy_levels"].as_u64().ok_or_else(|| {
+                    format!("{}: no num_hierarchy_levels in experiment metadata", url)
+                })?;
+            let hierarchy_method = experiment["hierarchy_method"]
+                .as_str()
+                .unwrap_or("anisotropic")
+                .to_string();
+            let (url, frame) = self.get_metadata_async(format!("coord/{}", frame)).await?;
+            let size = |name: &str| {
+                frame[name]
+                    .as_f64()
+                    .ok_or_else(|| format!("{}: no {} in coordinate frame", url, name))
+            };
+            Ok(ChannelGeometry {
+                voxel_size: [
+                    size("x_voxel_size")?,
+                    size("y_voxel_size")?,
+                    size("z_voxel_size")?,
+                ],
+                voxel_unit: frame["voxel_unit"].as_str().unwrap_or("").to_string(),
+                num_hierarchy_levels: num_hierarchy_levels.min(u8::MAX.into()) as u8,
+                hierarchy_method,
+            })
+        }
+
+        /// Get a metadata document, returning its URL for error messages.
+        async fn get_metadata_async(
+            &self,
+            suffix: String,
+        ) -> Result<(String, serde_json::Value), String> {
+            let url = self.build_url(suffix);
+            let resp = self
+                .client
+                .get(&url)
+                .header("Authorization", format!("token {}", self.token))
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if !resp.status().is_success() {
+                return Err(format!("{}: {:?}", url, resp.status()));
+            }
+            let body = resp.text().await.map_err(|e| e.to_string())?;
+            let metadata = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+            Ok((url, metadata))
+        }
+
+        /// Get a cutout from the bosslike remote, blocking until it arrives.
         ///
         /// # Arguments
         ///
@@ -90,43 +460,103 @@ pub mod remote {
             &self,
             boss_uri: String,
             res: u8,
-            xs: (u64, u64),
-            ys: (u64, u64),
-            zs: (u64, u64),
-        ) -> Result<Array3<u8>, reqwest::Error> {
-            let (col, exp, chan) = parse_bossdb_uri(boss_uri);
-            let url = self.build_url(format!(
-                "cutout/{col}/{exp}/{chan}/{res}/{xs_start}:{xs_stop}/{ys_start}:{ys_stop}/{zs_start}:{zs_stop}",
-                col=col, exp=exp, chan=chan, res=res,
-                xs_start = xs.0, xs_stop = xs.1,
-                ys_start = ys.0, ys_stop = ys.1,
-                zs_start = zs.0, zs_stop = zs.1,
-            ));
-            let mut resp = self
+            xs: (i64, i64),
+            ys: (i64, i64),
+            zs: (i64, i64),
+        ) -> Result<Array3<u8>, UpstreamError> {
+            runtime()
+                .handle()
+                .block_on(self.get_cutout_async(boss_uri, res, xs, ys, zs))
+        }
+
+        /// Async version of `get_cutout`.  Must run on `runtime()`.
+        pub async fn get_cutout_async(
+            &self,
+            boss_uri: String,
+            res: u8,
+            xs: (i64, i64),
+            ys: (i64, i64),
+            zs: (i64, i64),
+        ) -> Result<Array3<u8>, UpstreamError> {
+            let url = self.cutout_url(boss_uri, res, xs, ys, zs);
+            let resp = self
                 .client
                 .get(&url)
                 .header("Authorization", format!("token {}", self.token))
-                .send()?;
-            if resp.status().is_success() {
-                let mut buf = Vec::new();
-                std::io::copy(&mut resp, &mut buf).unwrap();
-                // decompress:
-                let decompressed: Vec<u8> = match unsafe { blosc::decompress_bytes(&buf[..]) } {
-                    Ok(a) => a,
-                    _ => unreachable!(),
-                };
-                return Ok(Array::from_shape_vec(
-                    (
-                        (zs.1 - zs.0) as usize,
-                        (ys.1 - ys.0) as usize,
-                        (xs.1 - xs.0) as usize,
-                    ),
-                    decompressed,
-                )
-                .unwrap());
-            } else {
-                panic!(format!("{}: {:?}", url, resp.status()))
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if resp.status() == StatusCode::NOT_FOUND {
+                return Err(UpstreamError::NotFound(format!(
+                    "{}: {:?}",
+                    url,
+                    resp.status()
+                )));
+            }
+            if !resp.status().is_success() {
+                return Err(format!("{}: {:?}", url, resp.status()).into());
             }
+            let buf = resp.bytes().await.map_err(|e| format!("{}: {}", url, e))?;
+            // decompress:
+            let decompressed: Vec<u8> = match unsafe { blosc::decompress_bytes(&buf[..]) } {
+                Ok(a) => a,
+                Err(_) => return Err(format!("{}: failed to decompress cutout", url).into()),
+            };
+            let len = |(start, stop): (i64, i64)| stop.checked_sub(start).filter(|l| *l >= 0);
+            let shape = match (len(xs), len(ys), len(zs)) {
+                (Some(x), Some(y), Some(z)) => Vector3 {
+                    x: x as u64,
+                    y: y as u64,
+                    z: z as u64,
+                },
+                _ => return Err(format!("{}: extents must not be reversed", url).into()),
+            };
+            Ok(Array::from_shape_vec(shape.to_zyx_shape(), decompressed)
+                .map_err(|e| format!("{}: {}", url, e))?)
+        }
+
+        /// Write a cutout to the bosslike remote, blocking until it's
+        /// accepted.
+        ///
+        /// # Arguments
+        ///
+        /// * `boss_uri` - A URI like `bossdb://col/exp/chan`
+        /// * `res` - Resolution level
+        /// * `origin` - Where the cutout starts, in global coordinates
+        /// * `data` - The voxels, in ZYX order
+        ///
+        pub fn post_cutout(
+            &self,
+            boss_uri: String,
+            res: u8,
+            origin: Coords,
+            data: &Array3<u8>,
+        ) -> Result<(), String> {
+            let shape = Vector3::from_zyx_shape(data.shape());
+            let url = self.cutout_url(
+                boss_uri,
+                res,
+                (origin.x, origin.x + shape.x as i64),
+                (origin.y, origin.y + shape.y as i64),
+                (origin.z, origin.z + shape.z as i64),
+            );
+            let voxels: Vec<u8> = data.iter().cloned().collect();
+            let compressed: Vec<u8> = blosc::Context::new().compress(&voxels[..]).into();
+            runtime().handle().block_on(async {
+                let resp = self
+                    .client
+                    .post(&url)
+                    .header("Authorization", format!("token {}", self.token))
+                    .header("Content-Type", "application/blosc")
+                    .body(compressed)
+                    .send()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                if !resp.status().is_success() {
+                    return Err(format!("{}: {:?}", url, resp.status()));
+                }
+                Ok(())
+            })
         }
     }
 }
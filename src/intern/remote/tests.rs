@@ -0,0 +1,91 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use super::{split_scheme, BossRemote};
+
+fn remote() -> BossRemote {
+    BossRemote::new(
+        "https".to_string(),
+        "boss.example.com".to_string(),
+        "public".to_string(),
+    )
+}
+
+#[test]
+fn test_default_api_prefix() {
+    assert_eq!(
+        "https://boss.example.com/v1/cutout/col/exp/chan/",
+        remote().build_url("cutout/col/exp/chan".to_string())
+    );
+}
+
+#[test]
+fn test_custom_api_prefix() {
+    let mut remote = remote();
+    remote.set_api_prefix("/boss/v2/");
+    assert_eq!(
+        "https://boss.example.com/boss/v2/collection/col/",
+        remote.build_url("collection/col".to_string())
+    );
+
+    remote.set_api_prefix("");
+    assert_eq!(
+        "https://boss.example.com/collection/col/",
+        remote.build_url("collection/col".to_string())
+    );
+}
+
+#[test]
+fn test_cutout_url() {
+    assert_eq!(
+        "https://boss.example.com/v1/cutout/col/exp/chan/1/0:512/512:1024/0:16/",
+        remote().cutout_url(
+            "bossdb://col/exp/chan".to_string(),
+            1,
+            (0, 512),
+            (512, 1024),
+            (0, 16)
+        )
+    );
+}
+
+#[test]
+fn test_http() {
+    let remote = BossRemote::new(
+        "http".to_string(),
+        "boss.internal".to_string(),
+        "public".to_string(),
+    );
+    assert_eq!(
+        "http://boss.internal/v1/collection/col/",
+        remote.build_url("collection/col".to_string())
+    );
+}
+
+#[test]
+fn test_split_scheme() {
+    assert_eq!(
+        (Some("http"), "boss.internal"),
+        split_scheme("http://boss.internal")
+    );
+    assert_eq!(
+        (Some("https"), "boss.internal:8443"),
+        split_scheme("https://boss.internal:8443")
+    );
+    assert_eq!((None, "api.bossdb.io"), split_scheme("api.bossdb.io"));
+}
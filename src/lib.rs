@@ -1,12 +1,25 @@
-
 #[macro_use]
 extern crate diesel;
 
 #[macro_use]
 extern crate diesel_migrations;
 
+pub mod access_log;
+pub mod batch;
+pub mod cache_archive;
 pub mod config;
+pub mod cuboid_file;
+pub mod cutout;
 pub mod data_manager;
 pub mod db;
+pub mod disk_guard;
+pub mod downsample;
+pub mod etag;
 pub mod intern;
+pub mod prefetch;
+pub mod pyramid;
+pub mod rate_limit;
+pub mod semaphore;
+pub mod upload;
 pub mod usage_tracker;
+pub mod write_buffer;
@@ -21,26 +21,69 @@ limitations under the License.
 #[macro_use]
 extern crate rocket;
 
+use bossphorus::access_log::{AccessLog, CacheReport, LogSink};
+use bossphorus::batch::{BatchReader, Record, RecordResult};
+use bossphorus::cache_archive::{self, ExportReader, ImportReport};
 use bossphorus::config;
+use bossphorus::cuboid_file::{self, MigrationReport};
+use bossphorus::cutout::{
+    check_channel_name, parse_cuboid_size, parse_flip, parse_quality, parse_stride, CutoutQuery,
+    CutoutRequest, NO_FLIP, NO_STRIDE,
+};
 use bossphorus::data_manager::{
-    BossDBRelayDataManager, ChunkedFileDataManager, DataManager, Vector3,
+    self, BossDBRelayDataManager, CachedResolution, ChunkedFileDataManager, Coords, CuboidCoverage,
+    CuboidRegion, CuboidSources, Cutout, CutoutDiff, DownsampleStatus, LayerInfo, UploadError,
+    UploadSummary, UpstreamError, UpstreamStats, UpstreamTotals, Vector3,
+};
+use bossphorus::db::channel_keys::ChannelKeys;
+use bossphorus::db::channels::{BossChannelSource, ChannelRegistry};
+use bossphorus::db::empty::EmptyCuboids;
+use bossphorus::db::pool::{ConnectionPool, Pragmas};
+use bossphorus::db::scratch::ScratchChannels;
+use bossphorus::db::{
+    self, CompactReport, PinnedChannels, RemovalRetry, SqliteCacheInterface, UsageGrouping,
+    UsageStats, VerifyReport,
+};
+use bossphorus::disk_guard::{self, DiskGuard, DiskStatus};
+use bossphorus::etag::{self, CuboidHashes};
+use bossphorus::prefetch::Prefetcher;
+use bossphorus::pyramid::{self, PyramidBody};
+use bossphorus::rate_limit::{Limit, RateLimit, RateLimiter, Throttle};
+use bossphorus::semaphore::OwnedSemaphoreGuard;
+use bossphorus::upload::{
+    check_shape, decompress_voxels, gunzip, raw_voxels, read_limited, BodyError, Slab, SlabReader,
+    VoxelError,
+};
+use bossphorus::usage_tracker::{
+    self, EvictionSettings, EvictionStrategy, UsageTrackerConfig, UsageTrackerType,
 };
-use bossphorus::usage_tracker::{self, UsageTrackerType};
+use bossphorus::write_buffer::{self, WriteBuffer};
 
 // Data-types:
+use chrono::DateTime;
 use image::{DynamicImage, ImageBuffer};
-use ndarray::Array;
+use ndarray::{Array, Array3};
 
 use rocket::data::Data;
 use rocket::fairing::AdHoc;
-use rocket::http::RawStr;
-use rocket::response::{status, Stream};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, RawStr, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, content, status, Responder, Response, Stream};
+use rocket::Outcome;
 use rocket::Request;
 use rocket::Rocket;
 use rocket::State;
 use rocket_contrib::json::Json;
 use serde_derive::{Deserialize, Serialize};
-use std::io::{Cursor, Read};
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tempfile::SpooledTempFile;
+
+#[cfg(test)]
+mod tests;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ChannelMetadata {
@@ -78,47 +121,145 @@ struct ExperimentMetadata {
     creator: String,
 }
 
-/// Convert a colon-delimited extents variable into a `Vec<u64>` of len=2.
-///
-/// # Arguments:
-///
-/// * `string_value` - A string that contains two integers separated by a colon
-///
-/// # Returns:
-///
-/// * Vec<u64>[2]
-///
-fn colon_delim_str_to_extents(string_value: &RawStr) -> Vec<u64> {
-    string_value
-        .split(":")
-        .map(|t| t.parse::<u64>().unwrap())
-        .collect()
+/// What a viewer needs to set up a channel: its voxel size, extent, and
+/// resolutions.  Fields from upstream metadata are `None` when it couldn't
+/// be reached.
+#[derive(Serialize, Debug)]
+struct ChannelInfo {
+    /// `None` if the channel isn't registered and can't be looked up.
+    datatype: Option<String>,
+    voxel_size: Option<[f64; 3]>,
+    voxel_unit: Option<String>,
+    hierarchy_method: Option<String>,
+    /// Start and (exclusive) stop at resolution 0.
+    extent: Option<(Coords, Coords)>,
+    resolutions: Vec<ResolutionInfo>,
+}
+
+/// One resolution of a `ChannelInfo`.
+#[derive(Serialize, Debug)]
+struct ResolutionInfo {
+    res: u8,
+    voxel_size: Option<[f64; 3]>,
+    extent: Option<(Coords, Coords)>,
+    /// How many of its cuboids are cached.
+    cached_cuboids: u64,
 }
 
+/// Base resolution of every channel served.
+const BASE_RESOLUTION: u8 = 0;
+
 /// Get the metadata dictionary for a channel.
 ///
-/// This endpoint returns the JSONified `ChannelMetadata` for a channel.
+/// This endpoint returns the JSONified `ChannelMetadata` for a channel.  Its
+/// `downsample_status` reflects which resolutions are cached (see
+/// `DownsampleStatus`), since that's all that can be served without going
+/// upstream.
 ///
 #[get("/collection/<collection>/experiment/<experiment>/channel/<channel>")]
 fn get_channel_metadata(
     collection: &RawStr,
     experiment: &RawStr,
     channel: &RawStr,
-) -> Json<ChannelMetadata> {
-    Json(ChannelMetadata {
+    _reader: Reader,
+    channels: State<Arc<ChannelRegistry>>,
+    fm: FileManager,
+) -> Result<Json<ChannelMetadata>, status::Custom<String>> {
+    let uri = channel_uri(collection, experiment, channel)?;
+    let datatype = channels
+        .get(&format!("{}/{}/{}", collection, experiment, channel))
+        .map_or("uint8".to_string(), |info| info.datatype);
+    let downsample_status = DownsampleStatus::of(&fm.0.cached_resolutions(&uri), BASE_RESOLUTION);
+    Ok(Json(ChannelMetadata {
         name: channel.to_string(),
         description: "".to_string(),
         experiment: experiment.to_string(),
         collection: collection.to_string(),
         default_time_sample: 0,
         _type: "image".to_string(),
-        base_resolution: 0,
-        datatype: "uint8".to_string(),
+        base_resolution: BASE_RESOLUTION as u64,
+        datatype,
         creator: "bossphorus_cache".to_string(),
         sources: vec![],
-        downsample_status: "DOWNSAMPLED".to_string(),
+        downsample_status: downsample_status.as_str().to_string(),
         related: vec![],
-    })
+    }))
+}
+
+/// Get the voxel size, extent, datatype and resolutions of a channel, e.g.
+/// for a viewer setting up a layer.
+///
+/// Geometry comes from the Boss DB host's experiment and coordinate frame,
+/// and is cached after the first lookup.  If the host can't be reached, the
+/// geometry (and the datatype, unless the channel is registered) is left
+/// out and only the cached resolutions are listed.
+///
+#[get("/collection/<collection>/experiment/<experiment>/channel/<channel>/info")]
+fn get_channel_info(
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    _reader: Reader,
+    channels: State<Arc<ChannelRegistry>>,
+    fm: FileManager,
+) -> Result<Json<ChannelInfo>, status::Custom<String>> {
+    let uri = channel_uri(collection, experiment, channel)?;
+    let name = format!("{}/{}/{}", collection, experiment, channel);
+    Ok(Json(channel_info(
+        &channels,
+        &name,
+        fm.0.cached_resolutions(&uri),
+    )))
+}
+
+/// The `ChannelInfo` of a channel (see `get_channel_info`).
+///
+/// # Arguments
+///
+/// * `channels` - Where the channel's datatype and geometry are looked up
+/// * `name` - The channel, as `collection/experiment/channel`
+/// * `cached` - How much of each resolution is cached
+///
+fn channel_info(
+    channels: &ChannelRegistry,
+    name: &str,
+    cached: Vec<CachedResolution>,
+) -> ChannelInfo {
+    let datatype = channels.get(name).map(|info| info.datatype);
+    let geometry = channels.geometry(name);
+    let cached_cuboids = |res: u8| {
+        cached
+            .iter()
+            .find(|resolution| resolution.res == res)
+            .map_or(0, |resolution| resolution.cuboids)
+    };
+    let levels: Vec<u8> = match &geometry {
+        Some(geometry) => (0..geometry.num_hierarchy_levels).collect(),
+        None => cached.iter().map(|resolution| resolution.res).collect(),
+    };
+    let resolutions = levels
+        .into_iter()
+        .map(|res| ResolutionInfo {
+            res,
+            voxel_size: geometry
+                .as_ref()
+                .map(|geometry| geometry.voxel_size_at(res)),
+            extent: geometry.as_ref().and_then(|_| channels.extent(name, res)),
+            cached_cuboids: cached_cuboids(res),
+        })
+        .collect();
+    ChannelInfo {
+        datatype,
+        voxel_size: geometry.as_ref().map(|geometry| geometry.voxel_size),
+        voxel_unit: geometry
+            .as_ref()
+            .map(|geometry| geometry.voxel_unit.clone()),
+        hierarchy_method: geometry
+            .as_ref()
+            .map(|geometry| geometry.hierarchy_method.clone()),
+        extent: geometry.as_ref().and_then(|_| channels.extent(name, 0)),
+        resolutions,
+    }
 }
 
 /// Get the metadata dictionary for an experiment.
@@ -126,7 +267,11 @@ fn get_channel_metadata(
 /// This endpoint returns the JSONified `ExperimentMetadata` for an Experiment.
 ///
 #[get("/collection/<collection>/experiment/<experiment>")]
-fn get_experiment_metadata(collection: &RawStr, experiment: &RawStr) -> Json<ExperimentMetadata> {
+fn get_experiment_metadata(
+    collection: &RawStr,
+    experiment: &RawStr,
+    _reader: Reader,
+) -> Json<ExperimentMetadata> {
     Json(ExperimentMetadata {
         name: experiment.to_string(),
         description: "".to_string(),
@@ -139,55 +284,782 @@ fn get_experiment_metadata(collection: &RawStr, experiment: &RawStr) -> Json<Exp
     })
 }
 
-/// This retrieves the data from the DataManager and returns the ndarray.
+/// Request guard holding the file data manager, backed by the BossDB relay,
+/// that serves a single request.  It's assembled from the Rocket-managed
+/// configuration.
+struct FileManager(ChunkedFileDataManager);
+
+impl<'a, 'r> FromRequest<'a, 'r> for FileManager {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<FileManager, ()> {
+        let bosshost = request.guard::<State<config::BossHost>>()?;
+        let bossprotocol = request.guard::<State<config::BossProtocol>>()?;
+        let bosstoken = request.guard::<State<config::BossToken>>()?;
+        let api_prefix = request.guard::<State<config::BossApiPrefix>>()?;
+        let write_host = request.guard::<State<config::BossWriteHost>>()?;
+        let write_token = request.guard::<State<config::BossWriteToken>>()?;
+        let tracking_enabled = request.guard::<State<TrackingUsage>>()?;
+        let use_mmap = request.guard::<State<config::UseMmap>>()?;
+        let cuboid_reads = request.guard::<State<config::CuboidReads>>()?;
+        let writeback = request.guard::<State<config::Writeback>>()?;
+        let writeback_policy = request.guard::<State<config::WritebackPolicy>>()?;
+        let cuboid_format = request.guard::<State<config::CuboidFormat>>()?;
+        let cuboid_layout = request.guard::<State<config::CuboidLayout>>()?;
+        let resolution_roots = request.guard::<State<config::ResolutionRoots>>()?;
+        let channel_cuboid_sizes = request.guard::<State<config::ChannelCuboidSizes>>()?;
+        let fill_value = request.guard::<State<config::FillValue>>()?;
+        let clamp_to_extent = request.guard::<State<config::ClampToExtent>>()?;
+        let check_frame = request.guard::<State<config::CheckFrame>>()?;
+        let synthesize = request.guard::<State<config::SynthesizeResolutions>>()?;
+        let downsample_on_write = request.guard::<State<config::DownsampleOnWrite>>()?;
+        let on_upstream_error = request.guard::<State<config::OnUpstreamError>>()?;
+        let standalone = request.guard::<State<config::Standalone>>()?;
+        let upstream_limit = request.guard::<State<config::UpstreamLimit>>()?;
+        let upstream_stats = request.guard::<State<Arc<UpstreamStats>>>()?;
+        let file_limit = request.guard::<State<config::FileLimit>>()?;
+        let cache_modes = request.guard::<State<config::CacheModes>>()?;
+        let not_found = request.guard::<State<config::NotFoundCache>>()?;
+        let max_age = request.guard::<State<config::CuboidMaxAge>>()?;
+        let scratch_writes = request.guard::<State<config::ScratchWrites>>()?;
+        let frame = request.guard::<State<config::FrameOrigin>>()?;
+        let hashes = request.guard::<State<Arc<CuboidHashes>>>()?;
+        let channels = request.guard::<State<Arc<ChannelRegistry>>>()?;
+        let disk_guard = request.guard::<State<Option<Arc<DiskGuard>>>>()?;
+        let channel_keys = request.guard::<State<Option<Arc<ChannelKeys>>>>()?;
+        let write_buffer = request.guard::<State<Option<Arc<WriteBuffer>>>>()?;
+        let empty = request.guard::<State<Option<Arc<EmptyCuboids>>>>()?;
+        let scratch_channels = request.guard::<State<Option<Arc<ScratchChannels>>>>()?;
+
+        let mut fm = match standalone.0 {
+            Some(on_miss) => {
+                let mut fm = ChunkedFileDataManager::new(
+                    config::CUBOID_ROOT_PATH.to_string(),
+                    config::CUBOID_SIZE,
+                    tracking_enabled.0,
+                );
+                fm.set_on_miss(on_miss);
+                fm
+            }
+            None => {
+                let bosstoken = bosstoken.get();
+                let mut relay = BossDBRelayDataManager::new(
+                    bossprotocol.0.to_string(),
+                    bosshost.0.to_string(),
+                    bosstoken.clone(),
+                );
+                relay.set_upstream_limit(Arc::clone(&upstream_limit.0));
+                relay.set_upstream_stats(Arc::clone(&upstream_stats));
+                relay.set_api_prefix(&api_prefix.0);
+                relay.set_frame(frame.0);
+                if let Some(write_host) = &write_host.0 {
+                    let write_token = write_token.0.clone().unwrap_or(bosstoken);
+                    relay.set_write_target(write_host.to_string(), write_token);
+                }
+                ChunkedFileDataManager::new_with_layer(
+                    config::CUBOID_ROOT_PATH.to_string(),
+                    config::CUBOID_SIZE,
+                    Box::new(relay),
+                    tracking_enabled.0,
+                )
+            }
+        };
+        fm.set_use_mmap(use_mmap.0);
+        fm.set_read_strategy(cuboid_reads.0);
+        fm.set_writeback(writeback.0);
+        if let Some(recent_misses) = &writeback_policy.recent_misses {
+            fm.set_recent_misses(Arc::clone(recent_misses));
+        }
+        fm.set_file_limit(Arc::clone(&file_limit.0));
+        fm.set_modes(cache_modes.0);
+        fm.set_format_version(cuboid_format.0);
+        fm.set_layout(cuboid_layout.0);
+        if let Some(channel_keys) = channel_keys.inner() {
+            fm.set_channel_keys(Arc::clone(channel_keys));
+        }
+        fm.set_resolution_roots(resolution_roots.0.clone());
+        fm.set_channel_cuboid_sizes(channel_cuboid_sizes.0.clone());
+        fm.set_hashes(Arc::clone(&hashes));
+        // With no Boss DB host, there are no channels to look up:
+        if standalone.0.is_none() {
+            fm.set_channels(Arc::clone(&channels));
+            fm.set_check_frame(check_frame.0);
+        }
+        if let Some(disk_guard) = disk_guard.inner() {
+            fm.set_disk_guard(Arc::clone(disk_guard));
+        }
+        if let Some(write_buffer) = write_buffer.inner() {
+            fm.set_write_buffer(Arc::clone(write_buffer));
+        }
+        if let Some(empty) = empty.inner() {
+            fm.set_empty_cuboids(Arc::clone(empty));
+        }
+        fm.set_fill_values(fill_value.0.clone());
+        fm.set_clamp_to_extent(clamp_to_extent.0);
+        fm.set_frame(frame.0);
+        fm.set_synthesis(synthesize.0.clone());
+        fm.set_downsample_on_write(downsample_on_write.0);
+        fm.set_on_upstream_error(on_upstream_error.0);
+        fm.set_not_found(Arc::clone(&not_found.0));
+        if max_age.0 > 0 {
+            fm.set_max_age(Some(Duration::from_secs(max_age.0)));
+        }
+        fm.set_write_through(write_host.0.is_some());
+        fm.set_scratch(scratch_writes.0);
+        if let Some(scratch_channels) = scratch_channels.inner() {
+            fm.set_scratch_channels(Arc::clone(scratch_channels));
+        }
+        Outcome::Success(FileManager(fm))
+    }
+}
+
+/// The `If-None-Match` header of a request, if it sent one.
+struct IfNoneMatch(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfNoneMatch {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<IfNoneMatch, ()> {
+        let header = request.headers().get_one("If-None-Match");
+        Outcome::Success(IfNoneMatch(header.map(|h| h.to_string())))
+    }
+}
+
+/// The `If-Modified-Since` header of a request, if it sent one.
+struct IfModifiedSince(Option<String>);
+
+impl IfModifiedSince {
+    /// Can the client's copy be reused, given when the resource last
+    /// changed?
+    fn unmodified(&self, last_modified: SystemTime) -> bool {
+        self.0.as_ref().map_or(false, |header| {
+            etag::not_modified_since(header, last_modified)
+        })
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfModifiedSince {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<IfModifiedSince, ()> {
+        let header = request.headers().get_one("If-Modified-Since");
+        Outcome::Success(IfModifiedSince(header.map(|h| h.to_string())))
+    }
+}
+
+/// Request guard for maintenance endpoints.  The request must carry the
+/// configured admin token as `Authorization: Token <token>`.  Without an
+/// admin token configured, every request is forbidden.
+struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+        let admin_token = request.guard::<State<config::AdminToken>>()?;
+        let presented = presented_token(request, &["token"]);
+        match (&admin_token.0, presented) {
+            (Some(expected), Some(presented)) if presented == expected => Outcome::Success(Admin),
+            _ => Outcome::Failure((Status::Forbidden, ())),
+        }
+    }
+}
+
+/// The token a request carries as `Authorization: <scheme> <token>`, for
+/// any of the given schemes (compared case-insensitively).
+fn presented_token<'r>(request: &'r Request, schemes: &[&str]) -> Option<&'r str> {
+    let header = request.headers().get_one("Authorization")?;
+    let (scheme, token) = header.split_at(header.find(' ')?);
+    if schemes.iter().any(|s| scheme.eq_ignore_ascii_case(s)) {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// Schemes an API key can be presented with.
+const API_KEY_SCHEMES: [&str; 2] = ["bearer", "token"];
+
+/// Whether a request carries one of the given API keys.
+fn has_api_key(request: &Request, keys: &[&String]) -> bool {
+    presented_token(request, &API_KEY_SCHEMES)
+        .map_or(false, |presented| keys.iter().any(|key| *key == presented))
+}
+
+/// Request guard for routes that read data.  With read API keys configured,
+/// the request must carry one of them, or a write API key, as
+/// `Authorization: Bearer <key>` (or `Token <key>`); otherwise it fails
+/// with 401.
+struct Reader;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Reader {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Reader, ()> {
+        let read_keys = request.guard::<State<config::ReadKeys>>()?;
+        let write_keys = request.guard::<State<config::WriteKeys>>()?;
+        if read_keys.0.is_empty() {
+            return Outcome::Success(Reader);
+        }
+        let keys: Vec<&String> = read_keys.0.iter().chain(write_keys.0.iter()).collect();
+        if has_api_key(request, &keys) {
+            Outcome::Success(Reader)
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// Request guard for routes that write data.  With write API keys
+/// configured, the request must carry one of them, the same way as for
+/// `Reader`; otherwise it fails with 401.
+struct Writer;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Writer {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Writer, ()> {
+        let write_keys = request.guard::<State<config::WriteKeys>>()?;
+        let keys: Vec<&String> = write_keys.0.iter().collect();
+        if keys.is_empty() || has_api_key(request, &keys) {
+            Outcome::Success(Writer)
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// The `Content-Encoding` header of a request, if any.
+struct ContentEncoding(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ContentEncoding {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ContentEncoding, ()> {
+        let header = request.headers().get_one("Content-Encoding");
+        Outcome::Success(ContentEncoding(header.map(|h| h.trim().to_lowercase())))
+    }
+}
+
+/// Does a request's `Accept` header name a format outright, rather than
+/// leaving it to the server with no header or a wildcard like `*/*`?
+fn names_format(request: &Request) -> bool {
+    request.accept().map_or(false, |accept| {
+        accept.preferred().media_type().specificity() == 2
+    })
+}
+
+/// Request guard for the cutout routes of a single format, which forwards
+/// requests whose `Accept` header names no format to `download_default`.
+/// Without it, Rocket would match those requests to every format's route,
+/// so the lowest ranked one (blosc) would always win.
+struct ExplicitAccept;
+
+impl<'a, 'r> FromRequest<'a, 'r> for ExplicitAccept {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ExplicitAccept, ()> {
+        if names_format(request) {
+            Outcome::Success(ExplicitAccept)
+        } else {
+            Outcome::Forward(())
+        }
+    }
+}
+
+/// Request guard for `download_default`, which forwards requests whose
+/// `Accept` header names a format, so that unsupported ones reach the
+/// format fallback.
+struct ImplicitAccept;
+
+impl<'a, 'r> FromRequest<'a, 'r> for ImplicitAccept {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ImplicitAccept, ()> {
+        if names_format(request) {
+            Outcome::Forward(())
+        } else {
+            Outcome::Success(ImplicitAccept)
+        }
+    }
+}
+
+/// A cutout response tagged with its `ETag` and `Last-Modified` time.
+/// Without a body, this is a `304 Not Modified`.  Partial cutouts (see
+/// `UpstreamErrorPolicy`) are flagged with an `X-Partial-Data` header, and
+/// ones that extend past the channel's extent, which was filled (see
+/// `config::ClampToExtent`), with an `X-Clamped` header.
 ///
-/// The data can then be converted to an appropriate output format.
-fn _fetch_data_to_ndarray(
-    collection: &RawStr,
-    experiment: &RawStr,
-    channel: &RawStr,
+/// Where a cutout's cuboids came from is summarized in an `X-Data-Source`
+/// header, like `cache=3, upstream=1`, and the fraction of them read from
+/// the local cache in an `X-Cache-Hit-Ratio` header.
+///
+/// A cutout that's all zeros can be sent compactly, for clients that ask
+/// for it with `?compact_zeros=true`: an empty body with an `X-All-Zeros:
+/// true` header and the cutout's shape in an `X-Shape` header (as `z,y,x`),
+/// for the client to expand itself.
+struct ETagged<R> {
+    etag: Option<String>,
+    last_modified: Option<SystemTime>,
+    body: Option<R>,
+    partial: bool,
+    clamped: bool,
+    /// Where the cutout's cuboids came from, if it was read.
+    sources: Option<CuboidSources>,
+    /// Shape of an all-zero cutout sent compactly.
+    zeros: Option<Vector3>,
+}
+
+impl<R> ETagged<R> {
+    /// A `304 Not Modified`, with whichever validators matched.
+    fn not_modified(etag: Option<String>, last_modified: Option<SystemTime>) -> ETagged<R> {
+        ETagged {
+            etag,
+            last_modified,
+            body: None,
+            partial: false,
+            clamped: false,
+            sources: None,
+            zeros: None,
+        }
+    }
+
+    /// An all-zero cutout, sent compactly.
+    fn zeros(etag: Option<String>, shape: Vector3, partial: bool) -> ETagged<R> {
+        ETagged {
+            etag,
+            last_modified: None,
+            body: None,
+            partial,
+            clamped: false,
+            sources: None,
+            zeros: Some(shape),
+        }
+    }
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for ETagged<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = match (self.zeros, self.body) {
+            (Some(shape), _) => Response::build()
+                .raw_header("X-All-Zeros", "true")
+                .raw_header("X-Shape", format!("{},{},{}", shape.z, shape.y, shape.x))
+                .finalize(),
+            (None, Some(body)) => body.respond_to(request)?,
+            (None, None) => Response::build().status(Status::NotModified).finalize(),
+        };
+        if let Some(etag) = self.etag {
+            response.set_raw_header("ETag", etag);
+        }
+        if let Some(last_modified) = self.last_modified {
+            response.set_raw_header("Last-Modified", etag::http_date(last_modified));
+        }
+        if self.partial {
+            response.set_raw_header("X-Partial-Data", "true");
+        }
+        if self.clamped {
+            response.set_raw_header("X-Clamped", "true");
+        }
+        if let Some(sources) = self.sources {
+            response.set_raw_header("X-Data-Source", sources.summary());
+            response.set_raw_header(
+                "X-Cache-Hit-Ratio",
+                format!("{}", sources.cache_hit_ratio()),
+            );
+        }
+        Ok(response)
+    }
+}
+
+/// Check whether the client already holds the current version of a cutout,
+/// by its `If-None-Match` header or, without one, its `If-Modified-Since`
+/// header (RFC 7232).
+///
+/// Returns a `304 Not Modified` response if so.  This happens before any
+/// cuboid data is read, so a match skips reading, compressing, and sending
+/// the cutout entirely.
+fn check_not_modified<R>(
+    fm: &FileManager,
+    if_none_match: &IfNoneMatch,
+    if_modified_since: &IfModifiedSince,
+    uri: &str,
     res: u8,
     origin: Vector3,
     destination: Vector3,
-    bosshost: State<config::BossHost>,
-    bosstoken: State<config::BossToken>,
-    tracking_enabled: State<TrackingUsage>,
-) -> ndarray::Array3<u8> {
-    // TODO: Confirm that shape is positive
-    // if origin.x >= destination.x || origin.y >= destination.y || origin.z >= destination.z {
-    //     // Error
-    // }
+    format: &str,
+) -> Option<ETagged<R>> {
+    if let Some(header) = &if_none_match.0 {
+        let etag = fm.0.cutout_etag(uri, res, origin, destination, format)?;
+        if etag::if_none_match(header, &etag) {
+            return Some(ETagged::not_modified(Some(etag), None));
+        }
+        return None;
+    }
+    if if_modified_since.0.is_none() {
+        return None;
+    }
+    let last_modified = fm.0.cutout_last_modified(uri, res, origin, destination)?;
+    if if_modified_since.unmodified(last_modified) {
+        return Some(ETagged::not_modified(None, Some(last_modified)));
+    }
+    None
+}
 
+/// This retrieves the data from the DataManager and returns the cutout.
+///
+/// The data can then be converted to an appropriate output format.
+fn _fetch_data_to_ndarray(cutout: &CutoutRequest, fm: &FileManager) -> Cutout {
     // Perform the data-read:
-    let fm = ChunkedFileDataManager::new_with_layer(
-        config::CUBOID_ROOT_PATH.to_string(),
-        Vector3 {
-            x: 512,
-            y: 512,
-            z: 16,
-        },
-        Box::new(BossDBRelayDataManager::new(
-            "https".to_string(),
-            bosshost.0.to_string(),
-            bosstoken.0.to_string(),
+    fm.0.get_cutout(cutout.uri(), cutout.res, cutout.origin, cutout.destination)
+}
+
+/// The response to a cutout request with malformed or invalid extents.
+fn bad_cutout(message: String) -> status::Custom<String> {
+    status::Custom(Status::BadRequest, message)
+}
+
+/// Fewest voxels in a cuboid size given with `?cuboid_size`.  Every
+/// cuboid is a request upstream, so tiny ones would turn one cutout into a
+/// flood of them.
+const MIN_OVERRIDE_CUBOID_VOXELS: u64 = 1 << 12;
+
+/// Most cuboids an overridden cutout may touch, whatever
+/// `config::MaxRequestCuboids` is, since none of them can come from the
+/// cache.
+const MAX_OVERRIDE_CUBOIDS: u64 = 1 << 12;
+
+/// Apply a `?cuboid_size=cx:cy:cz` override to a cutout (see
+/// `ChunkedFileDataManager::set_cuboid_size_override`), unless it's the
+/// channel's own cuboid size.  Overridden cutouts aren't cached, so asking
+/// for both with `?nocache=false` is refused, rather than caching cuboids
+/// of the wrong size, as is an override with no Boss DB host to read from,
+/// or one whose cuboids are smaller than `MIN_OVERRIDE_CUBOID_VOXELS` or
+/// more than `MAX_OVERRIDE_CUBOIDS`.
+fn override_cuboid_size(
+    fm: &mut FileManager,
+    request: &CutoutRequest,
+    cuboid_size: Option<&RawStr>,
+    nocache: Option<bool>,
+) -> Result<(), status::Custom<String>> {
+    let cuboid_size = match cuboid_size {
+        Some(cuboid_size) => parse_cuboid_size(cuboid_size).map_err(bad_cutout)?,
+        None => return Ok(()),
+    };
+    if cuboid_size == fm.0.cuboid_size_of(&request.uri()) {
+        return Ok(());
+    }
+    if nocache == Some(false) {
+        return Err(bad_cutout(
+            "A cuboid size other than the cache's can't be cached; drop nocache=false".to_string(),
+        ));
+    }
+    if !fm.0.has_next_layer() {
+        return Err(bad_cutout(
+            "A cuboid size other than the cache's needs a Boss DB host to read from".to_string(),
+        ));
+    }
+    let voxels = cuboid_size
+        .x
+        .saturating_mul(cuboid_size.y)
+        .saturating_mul(cuboid_size.z);
+    if voxels < MIN_OVERRIDE_CUBOID_VOXELS {
+        return Err(bad_cutout(format!(
+            "Cuboid size has {} voxels, fewer than the {} allowed",
+            voxels, MIN_OVERRIDE_CUBOID_VOXELS
+        )));
+    }
+    let count = data_manager::count_cuboids(request.origin, request.destination, cuboid_size);
+    if count > MAX_OVERRIDE_CUBOIDS {
+        return Err(bad_cutout(format!(
+            "Cutout touches {} cuboids of that size, more than the {} allowed",
+            count, MAX_OVERRIDE_CUBOIDS
+        )));
+    }
+    fm.0.set_cuboid_size_override(cuboid_size);
+    Ok(())
+}
+
+/// The response to a cutout of a channel that doesn't exist upstream.
+fn channel_not_found(uri: &str) -> status::Custom<String> {
+    status::Custom(Status::NotFound, format!("Channel {} not found", uri))
+}
+
+/// The response to a cutout that a standalone cache doesn't hold all of,
+/// when misses are to be reported.
+fn cutout_missing(uri: &str) -> status::Custom<String> {
+    status::Custom(
+        Status::NotFound,
+        format!("Part of the cutout of {} isn't cached", uri),
+    )
+}
+
+/// Reserve room in the memory budget for a cutout's buffers, released
+/// when the returned permit is dropped.  Fails with 503 while other
+/// requests have the budget exhausted, and with 400 if the cutout could
+/// never fit.
+///
+/// # Arguments
+///
+/// * `memory` - The budget, if there is one
+/// * `bytes` - Estimated size of the cutout's buffers
+///
+fn reserve_memory(
+    memory: &config::CutoutMemory,
+    bytes: u64,
+) -> Result<Option<OwnedSemaphoreGuard>, status::Custom<String>> {
+    let budget = match &memory.0 {
+        Some(budget) => budget,
+        None => return Ok(None),
+    };
+    if bytes > budget.capacity() as u64 {
+        return Err(bad_cutout(format!(
+            "Cutout needs {} bytes, more than the {} byte memory budget",
+            bytes,
+            budget.capacity()
+        )));
+    }
+    budget
+        .try_acquire_many_owned(bytes as usize)
+        .map(Some)
+        .ok_or_else(|| {
+            status::Custom(
+                Status::ServiceUnavailable,
+                "Too many cutouts in flight; try again shortly".to_string(),
+            )
+        })
+}
+
+/// Refuse a cutout that touches more cuboids than a single request may,
+/// with 413, before anything is read.  However few voxels it has, each
+/// cuboid is a file to open or a request upstream.
+///
+/// # Arguments
+///
+/// * `max_cuboids` - The cap, if there is one
+/// * `count` - Number of cuboids the cutout touches
+///
+fn check_cuboid_count(
+    max_cuboids: &config::MaxRequestCuboids,
+    count: u64,
+) -> Result<(), status::Custom<String>> {
+    match max_cuboids.0 {
+        Some(max) if count > max => Err(status::Custom(
+            Status::PayloadTooLarge,
+            format!(
+                "Cutout touches {} cuboids, more than the {} allowed per request",
+                count, max
+            ),
         )),
-        tracking_enabled.0,
-    );
+        _ => Ok(()),
+    }
+}
+
+/// Most cuboids a cutout may touch for its cuboids to be listed, e.g. by
+/// `cutout_coverage`.  The list is built in memory, a few dozen bytes per
+/// cuboid, whatever `config::MaxRequestCuboids` is.
+const MAX_LISTED_CUBOIDS: u64 = 1 << 16;
 
-    let result = fm.get_data(
-        format!("bossdb://{}/{}/{}", collection, experiment, channel),
+/// Refuse to list the cuboids of a cutout touching more than
+/// `MAX_LISTED_CUBOIDS` of them, with 400.
+///
+/// # Arguments
+///
+/// * `count` - Number of cuboids the cutout touches
+///
+fn check_listed_count(count: u64) -> Result<(), status::Custom<String>> {
+    if count > MAX_LISTED_CUBOIDS {
+        return Err(bad_cutout(format!(
+            "Cutout touches {} cuboids, more than the {} that can be listed",
+            count, MAX_LISTED_CUBOIDS
+        )));
+    }
+    Ok(())
+}
+
+/// Serve a parsed cutout: check the channel, answer `If-None-Match`, fetch
+/// the cutout, and encode it.
+///
+/// # Arguments
+///
+/// * `request` - The cutout
+/// * `compact_zeros` - Send an all-zero cutout compactly (see `ETagged`)
+/// * `format` - Name of the format, as used in the cutout's `ETag`
+/// * `encode` - Encodes the voxels into the response body
+///
+fn serve_cutout<R>(
+    request: &CutoutRequest,
+    fm: FileManager,
+    if_none_match: &IfNoneMatch,
+    if_modified_since: &IfModifiedSince,
+    prefetcher: &Prefetcher,
+    cache_report: &CacheReport,
+    memory: &config::CutoutMemory,
+    max_cuboids: &config::MaxRequestCuboids,
+    compact_zeros: bool,
+    format: &str,
+    encode: impl FnOnce(Array3<u8>) -> R,
+) -> Result<ETagged<R>, status::Custom<String>> {
+    let (res, origin, destination) = (request.res, request.origin, request.destination);
+    let uri = request.uri();
+    if !fm.0.supports_channel(&uri) {
+        return Err(status::Custom(
+            Status::BadRequest,
+            format!("Channel {} is not uint8", uri),
+        ));
+    }
+    fm.0.check_frame(&uri, res, origin, destination)
+        .map_err(bad_cutout)?;
+    check_cuboid_count(
+        max_cuboids,
+        data_manager::count_cuboids(origin, destination, fm.0.cuboid_size_of(&uri)),
+    )?;
+    if compact_zeros && fm.0.known_zero(&uri, res, origin, destination) {
+        // Nothing to read:
+        cache_report.record(true);
+        return Ok(ETagged::zeros(None, request.strided_shape(), false));
+    }
+    // Subsampled, flipped and compact responses are different
+    // representations of the cutout:
+    let mut format = format.to_string();
+    if request.stride != NO_STRIDE {
+        let stride = request.stride;
+        format = format!("{}+stride={}:{}:{}", format, stride.x, stride.y, stride.z);
+    }
+    if request.flip != NO_FLIP {
+        format = format!("{}+flip={}", format, request.flip);
+    }
+    if compact_zeros {
+        format = format!("{}+compact-zeros", format);
+    }
+    if let Some(response) = check_not_modified(
+        &fm,
+        if_none_match,
+        if_modified_since,
+        &uri,
         res,
         origin,
         destination,
-    );
-    return result;
+        &format,
+    ) {
+        // Only possible when every cuboid is cached:
+        cache_report.record(true);
+        return Ok(response);
+    }
+
+    // Held until the cutout is encoded:
+    let _permit = reserve_memory(memory, request.buffer_size())?;
+    let cutout = _fetch_data_to_ndarray(request, &fm);
+    cache_report.record(cutout.cache_hit);
+    if cutout.not_found {
+        return Err(channel_not_found(&uri));
+    }
+    if cutout.missing {
+        return Err(cutout_missing(&uri));
+    }
+
+    let etag = fm.0.cutout_etag(&uri, res, origin, destination, &format);
+    let last_modified = fm.0.cutout_last_modified(&uri, res, origin, destination);
+    prefetcher.after_cutout(fm.0, uri, res, origin, destination);
+    if compact_zeros && cutout.data.iter().all(|v| *v == 0) {
+        return Ok(ETagged {
+            last_modified,
+            clamped: cutout.clamped,
+            sources: Some(cutout.sources),
+            ..ETagged::zeros(etag, request.strided_shape(), cutout.partial)
+        });
+    }
+    Ok(ETagged {
+        etag,
+        last_modified,
+        body: Some(encode(request.subsample(cutout.data))),
+        partial: cutout.partial,
+        clamped: cutout.clamped,
+        sources: Some(cutout.sources),
+        zeros: None,
+    })
+}
+
+/// Compress voxels with blosc.
+fn encode_blosc(data: Array3<u8>) -> Stream<Cursor<Vec<u8>>> {
+    let ndarray_data = data.into_raw_vec();
+
+    let ctx = blosc::Context::new();
+    let compressed: blosc::Buffer<u8> = ctx.compress(&ndarray_data[..]);
+    let cur: Cursor<Vec<u8>> = Cursor::new(compressed.into());
+    Stream::from(cur)
+}
+
+/// Encode voxels as a JPEG filmstrip (see `download_jpeg`), at a quality
+/// from 1 to 100.  Filmstrips over `spool_size` bytes are written to a temp
+/// file as they're encoded, so a big one isn't held in memory twice.
+fn spool_jpeg(ndarray_data: Array3<u8>, quality: u8, spool_size: usize) -> SpooledTempFile {
+    // DynamicImage::from, with the z slices stacked vertically:
+    let shape = Vector3::from_zyx_shape(ndarray_data.shape());
+    let image_buffer = ImageBuffer::from_raw(
+        shape.x as u32,
+        (shape.y * shape.z) as u32,
+        ndarray_data.into_raw_vec(),
+    )
+    .unwrap();
+
+    let mut spool = SpooledTempFile::new(spool_size);
+    {
+        // The encoder writes a few bytes at a time:
+        let mut writer = std::io::BufWriter::new(&mut spool);
+        DynamicImage::ImageLuma8(image_buffer)
+            .write_to(&mut writer, image::ImageOutputFormat::Jpeg(quality))
+            .unwrap();
+        writer.flush().unwrap();
+    }
+    spool.seek(SeekFrom::Start(0)).unwrap();
+    spool
+}
+
+/// Encode voxels as a JPEG filmstrip, to stream from memory or a temp file
+/// (see `spool_jpeg`).
+fn encode_jpeg(
+    ndarray_data: Array3<u8>,
+    quality: u8,
+    spool_size: usize,
+) -> Stream<SpooledTempFile> {
+    Stream::from(spool_jpeg(ndarray_data, quality, spool_size))
+}
+
+/// A response body with the shape of the voxels it holds in an `X-Shape`
+/// header (as `z,y,x`), for bodies that don't describe it themselves.
+struct Shaped<R> {
+    shape: Vector3,
+    body: R,
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for Shaped<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = self.body.respond_to(request)?;
+        response.set_raw_header(
+            "X-Shape",
+            format!("{},{},{}", self.shape.z, self.shape.y, self.shape.x),
+        );
+        Ok(response)
+    }
 }
 
 /// Download a 3D cutout of data.
 ///
-/// This endpoint returns data in blosc-compressed format.
+/// This endpoint returns data in blosc-compressed format, with its shape in
+/// an `X-Shape` header (as `z,y,x`).
+///
+/// For a quick, low-fidelity preview, `?stride=sx:sy:sz` sends only every
+/// `sx`th voxel along x (and so on), starting at the origin of the cutout.
+/// The default stride is `1:1:1`.
+///
+/// `?flip=x,y,z` reverses any of the axes before the cutout is sent (after
+/// subsampling).  By default, none are.
+///
+/// For experimenting with chunking strategies, `?cuboid_size=cx:cy:cz`
+/// reads the cutout from the Boss DB host in cuboids of that size, without
+/// reading or writing the cache (see `override_cuboid_size`).
 #[get(
-    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>",
+    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>?<nocache>&<compact_zeros>&<stride>&<flip>&<cuboid_size>",
     format = "application/blosc",
     rank = 1
 )]
@@ -199,45 +1071,53 @@ fn download_blosc(
     xs: &RawStr,
     ys: &RawStr,
     zs: &RawStr,
-    bosshost: State<config::BossHost>,
-    bosstoken: State<config::BossToken>,
-    tracking_enabled: State<TrackingUsage>,
-) -> Result<Stream<Cursor<Vec<u8>>>, String> {
+    nocache: Option<bool>,
+    compact_zeros: Option<bool>,
+    stride: Option<&RawStr>,
+    flip: Option<&RawStr>,
+    cuboid_size: Option<&RawStr>,
+    _accept: ExplicitAccept,
+    _reader: Reader,
+    mut fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    if_none_match: IfNoneMatch,
+    if_modified_since: IfModifiedSince,
+    prefetcher: State<Prefetcher>,
+    cache_report: CacheReport,
+    memory: State<config::CutoutMemory>,
+    max_cuboids: State<config::MaxRequestCuboids>,
+) -> Result<ETagged<Shaped<Stream<Cursor<Vec<u8>>>>>, status::Custom<String>> {
+    // The request can override whether fetched cuboids are cached:
+    if let Some(nocache) = nocache {
+        fm.0.set_writeback(!nocache);
+    }
     // Parse out the extents:
-    let x_extents: Vec<u64> = colon_delim_str_to_extents(xs);
-    let y_extents: Vec<u64> = colon_delim_str_to_extents(ys);
-    let z_extents: Vec<u64> = colon_delim_str_to_extents(zs);
-
-    // Try to convert to origin-and-shape:
-    let origin = Vector3 {
-        x: x_extents[0],
-        y: y_extents[0],
-        z: z_extents[0],
-    };
-    let destination = Vector3 {
-        x: x_extents[1],
-        y: y_extents[1],
-        z: z_extents[1],
-    };
-
-    let ndarray_data = _fetch_data_to_ndarray(
-        collection,
-        experiment,
-        channel,
-        res,
-        origin,
-        destination,
-        bosshost,
-        bosstoken,
-        tracking_enabled,
+    let mut request =
+        CutoutRequest::parse(collection, experiment, channel, res, xs, ys, zs, frame.0)
+            .map_err(bad_cutout)?;
+    if let Some(stride) = stride {
+        request = request.with_stride(parse_stride(stride).map_err(bad_cutout)?);
+    }
+    if let Some(flip) = flip {
+        request = request.with_flip(parse_flip(flip).map_err(bad_cutout)?);
+    }
+    override_cuboid_size(&mut fm, &request, cuboid_size, nocache)?;
+    serve_cutout(
+        &request,
+        fm,
+        &if_none_match,
+        &if_modified_since,
+        &prefetcher,
+        &cache_report,
+        &memory,
+        &max_cuboids,
+        compact_zeros.unwrap_or(false),
+        "blosc",
+        |data| Shaped {
+            shape: Vector3::from_zyx_shape(data.shape()),
+            body: encode_blosc(data),
+        },
     )
-    .into_raw_vec();
-
-    let ctx = blosc::Context::new();
-    let compressed: blosc::Buffer<u8> = ctx.compress(&ndarray_data[..]);
-    let cur: Cursor<Vec<u8>> = Cursor::new(compressed.into());
-    let response = Stream::from(cur);
-    Ok(response)
 }
 
 /// Download a 3D cutout of data.
@@ -252,8 +1132,14 @@ fn download_blosc(
 /// This option returns a single JPEG encoded image, where each slice in the
 /// z-dimension is concatenated in the y-dimension. This only works for `uint8`
 /// data channels.
+///
+/// `?quality=<1-100>` trades size for fidelity, e.g. for previews over a
+/// slow link; values outside the range are clamped.  The default is the
+/// configured `jpeg_quality`.
+///
+/// `?flip=x,y,z` reverses axes as for blosc cutouts.
 #[get(
-    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>",
+    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>?<nocache>&<compact_zeros>&<quality>&<flip>",
     format = "image/jpeg",
     rank = 2
 )]
@@ -265,71 +1151,99 @@ fn download_jpeg(
     xs: &RawStr,
     ys: &RawStr,
     zs: &RawStr,
-    bosshost: State<config::BossHost>,
-    bosstoken: State<config::BossToken>,
-    tracking_enabled: State<TrackingUsage>,
-) -> Result<Stream<Cursor<Vec<u8>>>, String> {
+    nocache: Option<bool>,
+    compact_zeros: Option<bool>,
+    quality: Option<&RawStr>,
+    flip: Option<&RawStr>,
+    _accept: ExplicitAccept,
+    _reader: Reader,
+    mut fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    if_none_match: IfNoneMatch,
+    if_modified_since: IfModifiedSince,
+    prefetcher: State<Prefetcher>,
+    cache_report: CacheReport,
+    memory: State<config::CutoutMemory>,
+    max_cuboids: State<config::MaxRequestCuboids>,
+    jpeg_quality: State<config::JpegQuality>,
+    jpeg_spool: State<config::JpegSpoolSize>,
+) -> Result<ETagged<Stream<SpooledTempFile>>, status::Custom<String>> {
+    // The request can override whether fetched cuboids are cached:
+    if let Some(nocache) = nocache {
+        fm.0.set_writeback(!nocache);
+    }
     // Parse out the extents:
-    let x_extents: Vec<u64> = colon_delim_str_to_extents(xs);
-    let y_extents: Vec<u64> = colon_delim_str_to_extents(ys);
-    let z_extents: Vec<u64> = colon_delim_str_to_extents(zs);
-
-    // Try to convert to origin-and-shape:
-    let origin = Vector3 {
-        x: x_extents[0],
-        y: y_extents[0],
-        z: z_extents[0],
-    };
-    let destination = Vector3 {
-        x: x_extents[1],
-        y: y_extents[1],
-        z: z_extents[1],
+    let mut request =
+        CutoutRequest::parse(collection, experiment, channel, res, xs, ys, zs, frame.0)
+            .map_err(bad_cutout)?;
+    if let Some(flip) = flip {
+        request = request.with_flip(parse_flip(flip).map_err(bad_cutout)?);
+    }
+    let quality = match quality {
+        Some(quality) => parse_quality(quality).map_err(bad_cutout)?,
+        None => jpeg_quality.0,
     };
-
-    // TODO: Confirm that shape is positive
-    // if origin.x >= destination.x || origin.y >= destination.y || origin.z >= destination.z {
-    //     // Error
-    // }
-
-    // Perform the data-read:
-
-    let ndarray_data = _fetch_data_to_ndarray(
-        collection,
-        experiment,
-        channel,
-        res,
-        origin,
-        destination,
-        bosshost,
-        bosstoken,
-        tracking_enabled,
-    );
-
-    // DynamicImage::from
-    let image_buffer = ImageBuffer::from_raw(
-        ndarray_data.shape()[2] as u32,
-        (ndarray_data.shape()[1] * ndarray_data.shape()[0]) as u32,
-        ndarray_data.into_raw_vec(),
+    serve_cutout(
+        &request,
+        fm,
+        &if_none_match,
+        &if_modified_since,
+        &prefetcher,
+        &cache_report,
+        &memory,
+        &max_cuboids,
+        compact_zeros.unwrap_or(false),
+        "jpeg",
+        |data| encode_jpeg(data, quality, jpeg_spool.0),
     )
-    .unwrap();
+}
 
-    let mut cur: Cursor<Vec<u8>> = Cursor::new(vec![1, 2, 3, 4]);
-    DynamicImage::ImageLuma8(image_buffer)
-        .write_to(&mut cur, image::ImageFormat::Jpeg)
-        .unwrap();
+/// Uncompressed `uint8` voxels in ZYX C-order, with their shape in an
+/// `X-Shape` header (as `z,y,x`) and their datatype in an `X-Dtype` header,
+/// so that clients without blosc can rebuild the array.
+struct RawVoxels {
+    shape: Vector3,
+    voxels: Vec<u8>,
+}
 
-    cur.set_position(0);
+impl RawVoxels {
+    fn new(data: Array3<u8>) -> RawVoxels {
+        // The shape always describes exactly the bytes sent:
+        let shape = Vector3::from_zyx_shape(data.shape());
+        let voxels = if data.is_standard_layout() {
+            data.into_raw_vec()
+        } else {
+            data.iter().cloned().collect()
+        };
+        RawVoxels { shape, voxels }
+    }
+}
 
-    let response = Stream::from(cur);
-    Ok(response)
+impl<'r> Responder<'r> for RawVoxels {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::Binary)
+            .raw_header(
+                "X-Shape",
+                format!("{},{},{}", self.shape.z, self.shape.y, self.shape.x),
+            )
+            .raw_header("X-Dtype", "uint8")
+            .sized_body(Cursor::new(self.voxels))
+            .ok()
+    }
 }
 
-#[post(
-    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>",
-    data = "<data>"
+/// Download a 3D cutout of data.
+///
+/// This endpoint returns the voxels uncompressed (see `RawVoxels`), for
+/// clients in languages without blosc bindings.  `?flip=x,y,z` reverses
+/// axes as for blosc cutouts.
+#[get(
+    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>?<nocache>&<compact_zeros>&<flip>",
+    format = "application/octet-stream",
+    rank = 3
 )]
-fn upload(
-    data: Data,
+fn download_raw(
     collection: &RawStr,
     experiment: &RawStr,
     channel: &RawStr,
@@ -337,79 +1251,1741 @@ fn upload(
     xs: &RawStr,
     ys: &RawStr,
     zs: &RawStr,
-    bosshost: State<config::BossHost>,
-    bosstoken: State<config::BossToken>,
-    tracking_enabled: State<TrackingUsage>,
-) -> status::Created<String> {
+    nocache: Option<bool>,
+    compact_zeros: Option<bool>,
+    flip: Option<&RawStr>,
+    _accept: ExplicitAccept,
+    _reader: Reader,
+    mut fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    if_none_match: IfNoneMatch,
+    if_modified_since: IfModifiedSince,
+    prefetcher: State<Prefetcher>,
+    cache_report: CacheReport,
+    memory: State<config::CutoutMemory>,
+    max_cuboids: State<config::MaxRequestCuboids>,
+) -> Result<ETagged<RawVoxels>, status::Custom<String>> {
+    // The request can override whether fetched cuboids are cached:
+    if let Some(nocache) = nocache {
+        fm.0.set_writeback(!nocache);
+    }
     // Parse out the extents:
-    let x_extents: Vec<u64> = colon_delim_str_to_extents(xs);
-    let y_extents: Vec<u64> = colon_delim_str_to_extents(ys);
-    let z_extents: Vec<u64> = colon_delim_str_to_extents(zs);
-
-    // Try to convert to origin-and-shape:
-    let origin = Vector3 {
-        x: x_extents[0],
-        y: y_extents[0],
-        z: z_extents[0],
-    };
-    let shape = Vector3 {
-        x: x_extents[1] - x_extents[0],
-        y: y_extents[1] - y_extents[0],
-        z: z_extents[1] - z_extents[0],
-    };
-    let shape_dimension = (shape.z as usize, shape.y as usize, shape.x as usize);
-
-    // TODO: Assert that shape is positive
+    let mut request =
+        CutoutRequest::parse(collection, experiment, channel, res, xs, ys, zs, frame.0)
+            .map_err(bad_cutout)?;
+    if let Some(flip) = flip {
+        request = request.with_flip(parse_flip(flip).map_err(bad_cutout)?);
+    }
+    serve_cutout(
+        &request,
+        fm,
+        &if_none_match,
+        &if_modified_since,
+        &prefetcher,
+        &cache_report,
+        &memory,
+        &max_cuboids,
+        compact_zeros.unwrap_or(false),
+        "raw",
+        RawVoxels::new,
+    )
+}
 
-    // Create a vector that'll carry the contents of the file:
-    let mut vec: Vec<u8> = Vec::new();
-    data.open().read_to_end(&mut vec).unwrap();
+/// `uint8` voxels as a NumPy `.npy` file, which describes its own shape.
+struct NpyVoxels(Vec<u8>);
 
-    // Decompress the data and rewrap it in an ndarray.
-    // This is unsafe because the bytes are coming directly over the wire.
-    let decompressed: Vec<u8> = unsafe { blosc::decompress_bytes(&vec[..]) }.unwrap();
+impl NpyVoxels {
+    fn new(data: Array3<u8>) -> NpyVoxels {
+        let raw = RawVoxels::new(data);
+        NpyVoxels(cuboid_file::npy::encode(raw.shape, &raw.voxels))
+    }
+}
 
-    // Reshape the flat vec into a 3D ndarray:
-    let array = Array::from_shape_vec(shape_dimension, decompressed).unwrap();
-
-    // Perform the data-write:
-    let fm = ChunkedFileDataManager::new_with_layer(
-        config::CUBOID_ROOT_PATH.to_string(),
-        Vector3 {
-            x: 512,
-            y: 512,
-            z: 16,
-        },
-        Box::new(BossDBRelayDataManager::new(
-            "https".to_string(),
-            bosshost.0.to_string(),
-            bosstoken.0.to_string(),
-        )),
-        tracking_enabled.0,
-    );
-    let result = fm.put_data(
-        format!("bossdb://{}/{}/{}", collection, experiment, channel),
-        res,
-        origin,
-        array,
-    );
+impl<'r> Responder<'r> for NpyVoxels {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        Response::build()
+            .raw_header("Content-Type", "application/x-npy")
+            .sized_body(Cursor::new(self.0))
+            .ok()
+    }
+}
 
-    status::Created(format!("{}", result), Some("{}".to_string()))
+/// Download a 3D cutout of data.
+///
+/// This endpoint returns the voxels as a NumPy `.npy` file, which
+/// `numpy.load` reads without any other dependencies.  `?flip=x,y,z`
+/// reverses axes as for blosc cutouts.
+#[get(
+    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>?<nocache>&<compact_zeros>&<flip>",
+    format = "application/x-npy",
+    rank = 4
+)]
+fn download_npy(
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    res: u8,
+    xs: &RawStr,
+    ys: &RawStr,
+    zs: &RawStr,
+    nocache: Option<bool>,
+    compact_zeros: Option<bool>,
+    flip: Option<&RawStr>,
+    _accept: ExplicitAccept,
+    _reader: Reader,
+    mut fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    if_none_match: IfNoneMatch,
+    if_modified_since: IfModifiedSince,
+    prefetcher: State<Prefetcher>,
+    cache_report: CacheReport,
+    memory: State<config::CutoutMemory>,
+    max_cuboids: State<config::MaxRequestCuboids>,
+) -> Result<ETagged<NpyVoxels>, status::Custom<String>> {
+    // The request can override whether fetched cuboids are cached:
+    if let Some(nocache) = nocache {
+        fm.0.set_writeback(!nocache);
+    }
+    // Parse out the extents:
+    let mut request =
+        CutoutRequest::parse(collection, experiment, channel, res, xs, ys, zs, frame.0)
+            .map_err(bad_cutout)?;
+    if let Some(flip) = flip {
+        request = request.with_flip(parse_flip(flip).map_err(bad_cutout)?);
+    }
+    serve_cutout(
+        &request,
+        fm,
+        &if_none_match,
+        &if_modified_since,
+        &prefetcher,
+        &cache_report,
+        &memory,
+        &max_cuboids,
+        compact_zeros.unwrap_or(false),
+        "npy",
+        NpyVoxels::new,
+    )
 }
 
-#[get("/")]
-fn index() -> String {
-    return format!("Bossphorus v0.0.1");
+/// Blosc-compressed `uint8` voxels, base64-encoded into a JSON document
+/// with their shape (as `[z, y, x]`), for browser clients that would rather
+/// not handle a binary body.  To decode one, base64-decode `data_base64`,
+/// blosc-decompress the result, and read it as an array of `shape` in C
+/// (ZYX) order.
+#[derive(Serialize, Debug)]
+struct JsonVoxels {
+    shape: [u64; 3],
+    dtype: &'static str,
+    order: &'static str,
+    data_base64: String,
 }
 
-#[catch(404)]
-fn not_found(_req: &Request) { /* .. */
+impl JsonVoxels {
+    fn new(data: Array3<u8>) -> JsonVoxels {
+        let raw = RawVoxels::new(data);
+        let compressed: blosc::Buffer<u8> = blosc::Context::new().compress(&raw.voxels[..]);
+        JsonVoxels {
+            shape: [raw.shape.z, raw.shape.y, raw.shape.x],
+            dtype: "uint8",
+            order: "C",
+            data_base64: base64::encode(compressed),
+        }
+    }
 }
 
-/// Is usage tracking enabled?
-pub struct TrackingUsage(pub bool);
+impl<'r> Responder<'r> for JsonVoxels {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        Json(self).respond_to(request)
+    }
+}
 
-/// Start the usage tracker if it's turned on.  If tracker started, the
+/// Download a 3D cutout of data.
+///
+/// This endpoint returns the voxels as JSON (see `JsonVoxels`), so that
+/// browser clients can fetch them without binary handling, at the cost of a
+/// third more bytes than blosc.  `?flip=x,y,z` reverses axes as for blosc
+/// cutouts.
+#[get(
+    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>?<nocache>&<compact_zeros>&<flip>",
+    format = "application/json",
+    rank = 5
+)]
+fn download_json(
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    res: u8,
+    xs: &RawStr,
+    ys: &RawStr,
+    zs: &RawStr,
+    nocache: Option<bool>,
+    compact_zeros: Option<bool>,
+    flip: Option<&RawStr>,
+    _accept: ExplicitAccept,
+    _reader: Reader,
+    mut fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    if_none_match: IfNoneMatch,
+    if_modified_since: IfModifiedSince,
+    prefetcher: State<Prefetcher>,
+    cache_report: CacheReport,
+    memory: State<config::CutoutMemory>,
+    max_cuboids: State<config::MaxRequestCuboids>,
+) -> Result<ETagged<JsonVoxels>, status::Custom<String>> {
+    // The request can override whether fetched cuboids are cached:
+    if let Some(nocache) = nocache {
+        fm.0.set_writeback(!nocache);
+    }
+    // Parse out the extents:
+    let mut request =
+        CutoutRequest::parse(collection, experiment, channel, res, xs, ys, zs, frame.0)
+            .map_err(bad_cutout)?;
+    if let Some(flip) = flip {
+        request = request.with_flip(parse_flip(flip).map_err(bad_cutout)?);
+    }
+    serve_cutout(
+        &request,
+        fm,
+        &if_none_match,
+        &if_modified_since,
+        &prefetcher,
+        &cache_report,
+        &memory,
+        &max_cuboids,
+        compact_zeros.unwrap_or(false),
+        "json",
+        JsonVoxels::new,
+    )
+}
+
+/// Names of the formats a cutout can be queried in, or served in by
+/// default (see `config::DefaultFormat`).
+const FORMAT_NAMES: [&str; 5] = ["blosc", "jpeg", "json", "npy", "raw"];
+
+/// A cutout in whichever format a query asked for.
+enum QueriedCutout {
+    Blosc(Shaped<Stream<Cursor<Vec<u8>>>>),
+    Jpeg(Stream<SpooledTempFile>),
+    Json(JsonVoxels),
+    Npy(NpyVoxels),
+    Raw(RawVoxels),
+}
+
+impl QueriedCutout {
+    /// Encode voxels in the named format, one of `FORMAT_NAMES`.  JPEGs
+    /// are encoded at `quality` (see `spool_jpeg`).
+    fn encode(format: &str, data: Array3<u8>, quality: u8, spool_size: usize) -> QueriedCutout {
+        match format {
+            "jpeg" => QueriedCutout::Jpeg(encode_jpeg(data, quality, spool_size)),
+            "json" => QueriedCutout::Json(JsonVoxels::new(data)),
+            "npy" => QueriedCutout::Npy(NpyVoxels::new(data)),
+            "raw" => QueriedCutout::Raw(RawVoxels::new(data)),
+            _ => QueriedCutout::Blosc(Shaped {
+                shape: Vector3::from_zyx_shape(data.shape()),
+                body: encode_blosc(data),
+            }),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for QueriedCutout {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        match self {
+            QueriedCutout::Blosc(body) => Response::build_from(body.respond_to(request)?)
+                .raw_header("Content-Type", "application/blosc")
+                .ok(),
+            QueriedCutout::Jpeg(body) => Response::build_from(body.respond_to(request)?)
+                .header(ContentType::JPEG)
+                .ok(),
+            QueriedCutout::Json(body) => body.respond_to(request),
+            QueriedCutout::Npy(body) => body.respond_to(request),
+            QueriedCutout::Raw(body) => body.respond_to(request),
+        }
+    }
+}
+
+/// Max size of a cutout query body, in bytes.
+const MAX_QUERY_LEN: u64 = 64 * 1024;
+
+/// Download a 3D cutout of data, described by a JSON body (see
+/// `CutoutQuery`) rather than by the path, for extents that are unwieldy in
+/// a URL.  The `format` is `blosc` (the default), `jpeg`, `json`, `npy` or
+/// `raw`, as in the other cutout endpoints.  `?quality=` sets the JPEG
+/// quality, as in `download_jpeg`.
+#[post(
+    "/cutout/<collection>/<experiment>/<channel>/query?<nocache>&<compact_zeros>&<quality>",
+    data = "<data>"
+)]
+fn query_cutout(
+    data: Data,
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    nocache: Option<bool>,
+    compact_zeros: Option<bool>,
+    quality: Option<&RawStr>,
+    _reader: Reader,
+    mut fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    if_none_match: IfNoneMatch,
+    if_modified_since: IfModifiedSince,
+    prefetcher: State<Prefetcher>,
+    cache_report: CacheReport,
+    memory: State<config::CutoutMemory>,
+    max_cuboids: State<config::MaxRequestCuboids>,
+    jpeg_quality: State<config::JpegQuality>,
+    jpeg_spool: State<config::JpegSpoolSize>,
+) -> Result<ETagged<QueriedCutout>, status::Custom<String>> {
+    // The request can override whether fetched cuboids are cached:
+    if let Some(nocache) = nocache {
+        fm.0.set_writeback(!nocache);
+    }
+    let body = match read_limited(data.open(), MAX_QUERY_LEN) {
+        Ok(body) => body,
+        Err(BodyError::TooLarge(limit)) => {
+            return Err(status::Custom(
+                Status::PayloadTooLarge,
+                format!("Query exceeds the {} byte limit", limit),
+            ))
+        }
+        Err(BodyError::Io(e)) => return Err(bad_cutout(e)),
+    };
+    let query = CutoutQuery::parse(&body).map_err(bad_cutout)?;
+    let request = CutoutRequest::global(
+        collection,
+        experiment,
+        channel,
+        query.res,
+        query.origin,
+        query.destination,
+        frame.0,
+    )
+    .map_err(bad_cutout)?;
+
+    let format = query.format.as_ref().map_or("blosc", String::as_str);
+    if !FORMAT_NAMES.contains(&format) {
+        return Err(bad_cutout(format!(
+            "Unknown format {} (expected one of {})",
+            format,
+            FORMAT_NAMES.join(", ")
+        )));
+    }
+    let quality = match quality {
+        Some(quality) => parse_quality(quality).map_err(bad_cutout)?,
+        None => jpeg_quality.0,
+    };
+    serve_cutout(
+        &request,
+        fm,
+        &if_none_match,
+        &if_modified_since,
+        &prefetcher,
+        &cache_report,
+        &memory,
+        &max_cuboids,
+        compact_zeros.unwrap_or(false),
+        format,
+        |data| QueriedCutout::encode(format, data, quality, jpeg_spool.0),
+    )
+}
+
+/// Download a 3D cutout of data in the configured default format (see
+/// `config::DefaultFormat`), for a client whose `Accept` header names no
+/// format.
+///
+/// Rocket matches such requests to every format's route, so those routes
+/// take an `ExplicitAccept` guard to forward them here; this route, ranked
+/// after them, takes an `ImplicitAccept` guard so that requests for an
+/// unsupported format go on to the format fallback, ranked last.  The
+/// response has the same `Content-Type` and `ETag` as if its format had
+/// been asked for.  `?stride=`, `?quality=`, `?flip=` and `?cuboid_size=`
+/// apply as in the other cutout endpoints.
+#[get(
+    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>?<nocache>&<compact_zeros>&<stride>&<quality>&<flip>&<cuboid_size>",
+    rank = 6
+)]
+fn download_default(
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    res: u8,
+    xs: &RawStr,
+    ys: &RawStr,
+    zs: &RawStr,
+    nocache: Option<bool>,
+    compact_zeros: Option<bool>,
+    stride: Option<&RawStr>,
+    quality: Option<&RawStr>,
+    flip: Option<&RawStr>,
+    cuboid_size: Option<&RawStr>,
+    _accept: ImplicitAccept,
+    _reader: Reader,
+    mut fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    if_none_match: IfNoneMatch,
+    if_modified_since: IfModifiedSince,
+    prefetcher: State<Prefetcher>,
+    cache_report: CacheReport,
+    memory: State<config::CutoutMemory>,
+    max_cuboids: State<config::MaxRequestCuboids>,
+    default_format: State<config::DefaultFormat>,
+    jpeg_quality: State<config::JpegQuality>,
+    jpeg_spool: State<config::JpegSpoolSize>,
+) -> Result<ETagged<QueriedCutout>, status::Custom<String>> {
+    // The request can override whether fetched cuboids are cached:
+    if let Some(nocache) = nocache {
+        fm.0.set_writeback(!nocache);
+    }
+    // Parse out the extents:
+    let mut request =
+        CutoutRequest::parse(collection, experiment, channel, res, xs, ys, zs, frame.0)
+            .map_err(bad_cutout)?;
+    if let Some(stride) = stride {
+        request = request.with_stride(parse_stride(stride).map_err(bad_cutout)?);
+    }
+    if let Some(flip) = flip {
+        request = request.with_flip(parse_flip(flip).map_err(bad_cutout)?);
+    }
+    let quality = match quality {
+        Some(quality) => parse_quality(quality).map_err(bad_cutout)?,
+        None => jpeg_quality.0,
+    };
+    override_cuboid_size(&mut fm, &request, cuboid_size, nocache)?;
+    let format = default_format.0.as_str();
+    serve_cutout(
+        &request,
+        fm,
+        &if_none_match,
+        &if_modified_since,
+        &prefetcher,
+        &cache_report,
+        &memory,
+        &max_cuboids,
+        compact_zeros.unwrap_or(false),
+        format,
+        |data| QueriedCutout::encode(format, data, quality, jpeg_spool.0),
+    )
+}
+
+/// Formats a cutout can be downloaded in, listed to clients that accept
+/// none of them.
+const CUTOUT_FORMATS: [&str; 5] = [
+    "application/blosc",
+    "image/jpeg",
+    "application/json",
+    "application/x-npy",
+    "application/octet-stream",
+];
+
+/// A blosc cutout served to a client that didn't ask for blosc, marked with
+/// an `X-Format-Fallback` header so the client can tell.
+struct BloscFallback<R>(R);
+
+impl<'r, R: Responder<'r>> Responder<'r> for BloscFallback<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = self.0.respond_to(request)?;
+        response.set_raw_header("X-Format-Fallback", "application/blosc");
+        Ok(response)
+    }
+}
+
+/// Download a 3D cutout of data in blosc format, for a client whose
+/// `Accept` header matches none of the supported formats.  Only mounted
+/// when the format fallback is `blosc`.
+#[get(
+    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>?<nocache>&<compact_zeros>&<stride>&<flip>&<cuboid_size>",
+    rank = 7
+)]
+fn download_fallback(
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    res: u8,
+    xs: &RawStr,
+    ys: &RawStr,
+    zs: &RawStr,
+    nocache: Option<bool>,
+    compact_zeros: Option<bool>,
+    stride: Option<&RawStr>,
+    flip: Option<&RawStr>,
+    cuboid_size: Option<&RawStr>,
+    reader: Reader,
+    fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    if_none_match: IfNoneMatch,
+    if_modified_since: IfModifiedSince,
+    prefetcher: State<Prefetcher>,
+    cache_report: CacheReport,
+    memory: State<config::CutoutMemory>,
+    max_cuboids: State<config::MaxRequestCuboids>,
+) -> Result<BloscFallback<ETagged<Shaped<Stream<Cursor<Vec<u8>>>>>>, status::Custom<String>> {
+    download_blosc(
+        collection,
+        experiment,
+        channel,
+        res,
+        xs,
+        ys,
+        zs,
+        nocache,
+        compact_zeros,
+        stride,
+        flip,
+        cuboid_size,
+        ExplicitAccept,
+        reader,
+        fm,
+        frame,
+        if_none_match,
+        if_modified_since,
+        prefetcher,
+        cache_report,
+        memory,
+        max_cuboids,
+    )
+    .map(BloscFallback)
+}
+
+/// Reject a cutout download whose `Accept` header matches none of the
+/// supported formats, listing them.  Without this, the request wouldn't
+/// match any route and would get a bare 404.  Mounted unless the format
+/// fallback is `blosc`.
+#[get(
+    "/cutout/<_collection>/<_experiment>/<_channel>/<_res>/<_xs>/<_ys>/<_zs>",
+    rank = 7
+)]
+fn download_not_acceptable(
+    _collection: &RawStr,
+    _experiment: &RawStr,
+    _channel: &RawStr,
+    _res: u8,
+    _xs: &RawStr,
+    _ys: &RawStr,
+    _zs: &RawStr,
+) -> status::Custom<String> {
+    status::Custom(
+        Status::NotAcceptable,
+        format!(
+            "No supported format is acceptable; supported formats are {}",
+            CUTOUT_FORMATS.join(", ")
+        ),
+    )
+}
+
+/// A multi-resolution pyramid, as built by `PyramidBody`.
+struct Pyramid {
+    content_type: String,
+    body: Vec<u8>,
+}
+
+impl<'r> Responder<'r> for Pyramid {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        Response::build()
+            .raw_header("Content-Type", self.content_type)
+            .sized_body(Cursor::new(self.body))
+            .ok()
+    }
+}
+
+/// Download a cutout at several resolution levels at once, e.g. for a
+/// viewer's initial load.
+///
+/// The extents are at resolution 0; `levels` (default 1) is how many levels
+/// to return, starting from 0.  Each level halves x and y, with extents
+/// rounded outwards.  Levels that aren't cached are fetched (or
+/// synthesized, if configured) the same way as any other cutout.  See the
+/// `pyramid` module for the multipart format.
+#[get("/pyramid/<collection>/<experiment>/<channel>/<xs>/<ys>/<zs>?<levels>")]
+fn download_pyramid(
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    xs: &RawStr,
+    ys: &RawStr,
+    zs: &RawStr,
+    levels: Option<u8>,
+    _reader: Reader,
+    fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    cache_report: CacheReport,
+    memory: State<config::CutoutMemory>,
+    max_cuboids: State<config::MaxRequestCuboids>,
+) -> Result<Pyramid, status::Custom<String>> {
+    let levels = levels.unwrap_or(1);
+    if levels == 0 || levels > pyramid::MAX_LEVELS {
+        return Err(status::Custom(
+            Status::BadRequest,
+            format!("levels must be from 1 to {}", pyramid::MAX_LEVELS),
+        ));
+    }
+    // Parse out the extents:
+    let request = CutoutRequest::parse(collection, experiment, channel, 0, xs, ys, zs, frame.0)
+        .map_err(bad_cutout)?;
+
+    let uri = request.uri();
+    if !fm.0.supports_channel(&uri) {
+        return Err(status::Custom(
+            Status::BadRequest,
+            format!("Channel {} is not uint8", uri),
+        ));
+    }
+    let cuboid_size = fm.0.cuboid_size_of(&uri);
+    let count = (0..levels)
+        .map(|res| {
+            let level = request.at_res(res);
+            data_manager::count_cuboids(level.origin, level.destination, cuboid_size)
+        })
+        .fold(0u64, u64::saturating_add);
+    check_cuboid_count(&max_cuboids, count)?;
+
+    let ctx = blosc::Context::new();
+    let mut body = PyramidBody::new();
+    let mut cache_hit = true;
+    for res in 0..levels {
+        let level = request.at_res(res);
+        // Held until the level is compressed:
+        let _permit = reserve_memory(&memory, level.buffer_size())?;
+        let cutout = _fetch_data_to_ndarray(&level, &fm);
+        cache_hit &= cutout.cache_hit;
+        if cutout.not_found {
+            cache_report.record(cache_hit);
+            return Err(channel_not_found(&uri));
+        }
+        if cutout.missing {
+            cache_report.record(cache_hit);
+            return Err(cutout_missing(&uri));
+        }
+        let compressed: blosc::Buffer<u8> = ctx.compress(&cutout.data.into_raw_vec()[..]);
+        body.add_level(
+            res,
+            level.global_origin(),
+            level.global_destination(),
+            compressed.as_ref(),
+        );
+    }
+    cache_report.record(cache_hit);
+    Ok(Pyramid {
+        content_type: body.content_type(),
+        body: body.finish(),
+    })
+}
+
+/// How much of a cutout is cached locally, reported in the
+/// `X-Cache-Coverage` header as a fraction from 0 to 1.
+struct CacheCoverage(f64);
+
+impl<'r> Responder<'r> for CacheCoverage {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        let status = if self.0 >= 1.0 {
+            Status::Ok
+        } else if self.0 > 0.0 {
+            Status::NoContent
+        } else {
+            Status::NotFound
+        };
+        Response::build()
+            .status(status)
+            .raw_header("X-Cache-Coverage", format!("{}", self.0))
+            .ok()
+    }
+}
+
+/// Check whether a cutout is cached, without downloading it.
+///
+/// Responds with 200 if every cuboid of the cutout is cached, 204 if only
+/// some of them are, and 404 if none are.  Clients can use this to decide
+/// between bossphorus and going directly to the BossDB.
+#[head("/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>")]
+fn cutout_cached(
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    res: u8,
+    xs: &RawStr,
+    ys: &RawStr,
+    zs: &RawStr,
+    _reader: Reader,
+    fm: FileManager,
+    frame: State<config::FrameOrigin>,
+) -> Result<CacheCoverage, status::Custom<String>> {
+    // Parse out the extents:
+    let request = CutoutRequest::parse(collection, experiment, channel, res, xs, ys, zs, frame.0)
+        .map_err(bad_cutout)?;
+    let (origin, destination) = (request.origin, request.destination);
+
+    let uri = request.uri();
+    Ok(CacheCoverage(fm.0.cache_coverage(
+        &uri,
+        res,
+        origin,
+        destination,
+    )))
+}
+
+/// List each cuboid of a cutout, by its index in the cuboid grid, with
+/// whether it's cached, e.g. `[{"index": {"x": 0, "y": 0, "z": 0},
+/// "cached": true}, ...]`.  A finer-grained version of the `HEAD` coverage
+/// check, for clients deciding which parts to fetch from bossphorus and
+/// which from the BossDB directly.  Cutouts touching more than
+/// `MAX_LISTED_CUBOIDS` cuboids get 400.
+#[get("/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>/coverage")]
+fn cutout_coverage(
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    res: u8,
+    xs: &RawStr,
+    ys: &RawStr,
+    zs: &RawStr,
+    _reader: Reader,
+    fm: FileManager,
+    frame: State<config::FrameOrigin>,
+) -> Result<Json<Vec<CuboidCoverage>>, status::Custom<String>> {
+    // Parse out the extents:
+    let request = CutoutRequest::parse(collection, experiment, channel, res, xs, ys, zs, frame.0)
+        .map_err(bad_cutout)?;
+    let uri = request.uri();
+    check_listed_count(data_manager::count_cuboids(
+        request.origin,
+        request.destination,
+        fm.0.cuboid_size_of(&uri),
+    ))?;
+    Ok(Json(fm.0.cuboid_coverage(
+        &uri,
+        res,
+        request.origin,
+        request.destination,
+    )))
+}
+
+/// Show how a cutout is split into cuboids, without reading any data: each
+/// cuboid it touches, by its index in the cuboid grid, with the region of
+/// the cuboid that it covers, e.g. `[{"index": {"x": 0, "y": 0, "z": 0},
+/// "start": {"x": 256, "y": 0, "z": 0}, "stop": {"x": 512, "y": 512, "z":
+/// 16}}, ...]`.  The extents are global, as for a cutout.  The cuboid size
+/// defaults to the configured one; `?cuboid_size=cx:cy:cz` overrides it,
+/// e.g. to match a channel with its own.  Cutouts touching more than
+/// `MAX_LISTED_CUBOIDS` cuboids get 400.
+#[get("/debug/cuboids/<res>/<xs>/<ys>/<zs>?<cuboid_size>")]
+fn debug_cuboids(
+    res: u8,
+    xs: &RawStr,
+    ys: &RawStr,
+    zs: &RawStr,
+    cuboid_size: Option<&RawStr>,
+    _reader: Reader,
+    frame: State<config::FrameOrigin>,
+) -> Result<Json<Vec<CuboidRegion>>, status::Custom<String>> {
+    // Any channel name will do, since only the extents are used:
+    let request = CutoutRequest::parse("debug", "debug", "debug", res, xs, ys, zs, frame.0)
+        .map_err(bad_cutout)?;
+    let cuboid_size = match cuboid_size {
+        Some(size) => parse_cuboid_size(size).map_err(bad_cutout)?,
+        None => config::CUBOID_SIZE,
+    };
+    check_listed_count(data_manager::count_cuboids(
+        request.origin,
+        request.destination,
+        cuboid_size,
+    ))?;
+    Ok(Json(data_manager::cuboid_regions(
+        request.origin,
+        request.destination,
+        cuboid_size,
+    )))
+}
+
+/// List the resolutions of a channel that are cached locally, in order, with
+/// how many cuboids each has, e.g. for a viewer building a multiscale
+/// source.  A channel with nothing cached has an empty list.
+///
+#[get("/cutout/<collection>/<experiment>/<channel>/resolutions")]
+fn cached_resolutions(
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    _reader: Reader,
+    fm: FileManager,
+) -> Result<Json<Vec<CachedResolution>>, status::Custom<String>> {
+    let uri = channel_uri(collection, experiment, channel)?;
+    Ok(Json(fm.0.cached_resolutions(&uri)))
+}
+
+/// The URI of a channel named in a request's path, e.g.
+/// `bossdb://col/exp/chan`.  Fails with 400 for names that would reach
+/// outside the channel's directory in the cache.
+fn channel_uri(
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+) -> Result<String, status::Custom<String>> {
+    for name in &[collection, experiment, channel] {
+        check_channel_name(name).map_err(bad_cutout)?;
+    }
+    Ok(format!(
+        "bossdb://{}/{}/{}",
+        collection, experiment, channel
+    ))
+}
+
+/// Compare a cached cutout with the same region on the Boss DB host, to
+/// catch stale or corrupt cache entries.  Responds with a JSON report (see
+/// `CutoutDiff`) of whether they're byte-identical, and if not, how many
+/// voxels differ and where the first one is.  Every cuboid of the cutout
+/// must be cached, and there must be a Boss DB host to compare with (409
+/// otherwise).  Requires the admin token.
+///
+#[get("/verify/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>")]
+fn verify_cutout(
+    _admin: Admin,
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    res: u8,
+    xs: &RawStr,
+    ys: &RawStr,
+    zs: &RawStr,
+    fm: FileManager,
+    frame: State<config::FrameOrigin>,
+) -> Result<Json<CutoutDiff>, status::Custom<String>> {
+    // Parse out the extents:
+    let request = CutoutRequest::parse(collection, experiment, channel, res, xs, ys, zs, frame.0)
+        .map_err(bad_cutout)?;
+    let (origin, destination) = (request.origin, request.destination);
+
+    let uri = request.uri();
+    if !fm.0.has_next_layer() {
+        return Err(status::Custom(
+            Status::Conflict,
+            "There's no upstream to compare against".to_string(),
+        ));
+    }
+    match fm.0.diff_cutout(&uri, res, origin, destination) {
+        Ok(Some(diff)) => Ok(Json(diff)),
+        Ok(None) => Err(status::Custom(
+            Status::Conflict,
+            format!("The cutout isn't fully cached in {}", uri),
+        )),
+        Err(UpstreamError::NotFound(_)) => Err(channel_not_found(&uri)),
+        Err(UpstreamError::Failed(err)) => Err(status::Custom(
+            Status::BadGateway,
+            format!("Couldn't fetch the cutout from upstream: {}", err),
+        )),
+    }
+}
+
+/// Body of a failed upload, so ingest clients can tell what went wrong.
+#[derive(Serialize, Debug)]
+struct UploadFailure {
+    /// What failed: `extents`, `shape`, `channel`, `body`, `too_large`,
+    /// `encoding`, `decompression`, `upstream` or `io`.
+    error: &'static str,
+    message: String,
+}
+
+/// The response to a failed upload.
+type UploadResponse<T> = Result<T, status::Custom<Json<UploadFailure>>>;
+
+fn upload_failure(
+    status: Status,
+    error: &'static str,
+    message: String,
+) -> status::Custom<Json<UploadFailure>> {
+    status::Custom(status, Json(UploadFailure { error, message }))
+}
+
+/// Read and decode an upload's body into voxels of the given shape,
+/// holding it to the limits.
+///
+/// # Arguments
+///
+/// * `body` - The request body
+/// * `encoding` - Its `Content-Encoding`, if any
+/// * `raw` - Whether the voxels are uncompressed rather than blosc
+/// * `shape` - The shape of the cutout being uploaded
+/// * `max_upload_size` - Max size of the body, in bytes
+/// * `max_upload_voxels` - Max number of voxels in the cutout
+fn decode_upload<R: std::io::Read>(
+    body: R,
+    encoding: Option<&str>,
+    raw: bool,
+    shape: Vector3,
+    max_upload_size: u64,
+    max_upload_voxels: u64,
+) -> UploadResponse<Array3<u8>> {
+    // Check the shape before allocating anything for it:
+    let voxels = check_shape(shape, max_upload_voxels)
+        .map_err(|e| upload_failure(Status::BadRequest, "shape", e))?;
+
+    // Read the file, refusing to buffer more than the limit:
+    let vec: Vec<u8> = match read_limited(body, max_upload_size) {
+        Ok(vec) => vec,
+        Err(BodyError::TooLarge(limit)) => {
+            return Err(upload_failure(
+                Status::PayloadTooLarge,
+                "too_large",
+                format!("Upload exceeds the {} byte limit", limit),
+            ))
+        }
+        Err(BodyError::Io(e)) => return Err(upload_failure(Status::BadRequest, "body", e)),
+    };
+
+    // Strip any gzip layer.  Raw voxels can't be any bigger than the shape,
+    // and a blosc payload is held to the same limit as the body itself:
+    let vec = match encoding {
+        None | Some("identity") => vec,
+        Some("gzip") => {
+            let limit = if raw { voxels } else { max_upload_size };
+            gunzip(&vec, limit)
+                .map_err(|e| upload_failure(Status::BadRequest, "decompression", e))?
+        }
+        Some(other) => {
+            return Err(upload_failure(
+                Status::UnsupportedMediaType,
+                "encoding",
+                format!("Unsupported Content-Encoding {}", other),
+            ))
+        }
+    };
+
+    // Decompress the data, checking it matches the shape, and rewrap it
+    // in an ndarray:
+    let decompressed = if raw {
+        raw_voxels(vec, voxels)
+    } else {
+        decompress_voxels(&vec[..], voxels)
+    };
+    let decompressed = decompressed.map_err(|e| {
+        let error = match e {
+            VoxelError::Corrupt => "decompression",
+            VoxelError::WrongLength { .. } => "shape",
+        };
+        upload_failure(Status::BadRequest, error, e.to_string())
+    })?;
+
+    // Reshape the flat vec into a 3D ndarray:
+    Ok(Array::from_shape_vec(shape.to_zyx_shape(), decompressed).unwrap())
+}
+
+/// Write an upload to the cache (and upstream, if writing through).
+///
+/// # Arguments
+///
+/// * `fm` - The file manager
+/// * `uri` - A URI like `bossdb://col/exp/chan`
+/// * `res` - Resolution level
+/// * `origin` - The start position of the upload (global coords)
+/// * `data` - The voxels, in ZYX order
+fn write_upload(
+    fm: &ChunkedFileDataManager,
+    uri: &str,
+    res: u8,
+    origin: Vector3,
+    data: Array3<u8>,
+) -> UploadResponse<UploadSummary> {
+    fm.try_upload(uri, res, origin, data).map_err(|e| {
+        let (status, error) = match e {
+            UploadError::UnsupportedChannel(_) => (Status::BadRequest, "channel"),
+            UploadError::Rejected(_) => (Status::BadGateway, "upstream"),
+            UploadError::Io { .. } => (Status::InternalServerError, "io"),
+        };
+        upload_failure(status, error, e.to_string())
+    })
+}
+
+/// Upload a cutout.
+///
+/// The body is blosc-compressed `uint8` voxels in ZYX C-order, or, with
+/// `?raw=true`, the voxels themselves.  Either may be wrapped in gzip with
+/// `Content-Encoding: gzip`, for clients (e.g. browsers) that can't produce
+/// blosc.  Responds with the number of cuboids and bytes written, or with
+/// an `UploadFailure` saying what went wrong.
+///
+#[post(
+    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>?<raw>",
+    data = "<data>"
+)]
+fn upload(
+    data: Data,
+    encoding: ContentEncoding,
+    location: &Origin,
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    res: u8,
+    xs: &RawStr,
+    ys: &RawStr,
+    zs: &RawStr,
+    raw: Option<bool>,
+    _writer: Writer,
+    fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    max_upload_size: State<config::MaxUploadSize>,
+    max_upload_voxels: State<config::MaxUploadVoxels>,
+) -> UploadResponse<status::Created<Json<UploadSummary>>> {
+    // Parse out the extents:
+    let request = CutoutRequest::parse(collection, experiment, channel, res, xs, ys, zs, frame.0)
+        .map_err(|e| upload_failure(Status::BadRequest, "extents", e))?;
+    let (origin, shape) = (request.origin, request.shape());
+
+    // Refuse before reading the body if it can't be written:
+    let uri = request.uri();
+    if !fm.0.supports_channel(&uri) {
+        return Err(upload_failure(
+            Status::BadRequest,
+            "channel",
+            UploadError::UnsupportedChannel(uri).to_string(),
+        ));
+    }
+    fm.0.check_upload_frame(&uri, res, origin, request.destination)
+        .map_err(|e| upload_failure(Status::BadRequest, "extents", e))?;
+
+    let array = decode_upload(
+        data.open(),
+        encoding.0.as_ref().map(String::as_str),
+        raw.unwrap_or(false),
+        shape,
+        max_upload_size.0,
+        max_upload_voxels.0,
+    )?;
+    let summary = write_upload(&fm.0, &uri, res, origin, array)?;
+    Ok(status::Created(
+        location.path().to_string(),
+        Some(Json(summary)),
+    ))
+}
+
+/// Upload a cutout as a stream of chunks, each holding some of its z
+/// planes (see `upload::SlabReader` for the wire format).  The cutout is
+/// written a cuboid-thick slab at a time as the chunks arrive, so it's
+/// never all held in memory; each chunk is held to the upload size limit
+/// and each slab to the voxel limit, rather than the whole cutout.
+///
+/// If the stream turns out to be bad partway through, the slabs before the
+/// problem stay written, and the failure says how many planes they hold.
+///
+#[post(
+    "/cutout/<collection>/<experiment>/<channel>/<res>/<xs>/<ys>/<zs>/stream",
+    data = "<data>"
+)]
+fn upload_stream(
+    data: Data,
+    location: &Origin,
+    collection: &RawStr,
+    experiment: &RawStr,
+    channel: &RawStr,
+    res: u8,
+    xs: &RawStr,
+    ys: &RawStr,
+    zs: &RawStr,
+    _writer: Writer,
+    fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    max_upload_size: State<config::MaxUploadSize>,
+    max_upload_voxels: State<config::MaxUploadVoxels>,
+) -> UploadResponse<status::Created<Json<UploadSummary>>> {
+    let request = CutoutRequest::parse(collection, experiment, channel, res, xs, ys, zs, frame.0)
+        .map_err(|e| upload_failure(Status::BadRequest, "extents", e))?;
+    let (origin, shape) = (request.origin, request.shape());
+
+    let uri = request.uri();
+    if !fm.0.supports_channel(&uri) {
+        return Err(upload_failure(
+            Status::BadRequest,
+            "channel",
+            UploadError::UnsupportedChannel(uri).to_string(),
+        ));
+    }
+    fm.0.check_upload_frame(&uri, res, origin, request.destination)
+        .map_err(|e| upload_failure(Status::BadRequest, "extents", e))?;
+
+    // Check the biggest slab's shape before reading anything:
+    let cuboid_z = fm.0.cuboid_size_of(&uri).z;
+    let slab_shape = Vector3 {
+        z: shape.z.min(cuboid_z),
+        ..shape
+    };
+    check_shape(slab_shape, max_upload_voxels.0)
+        .map_err(|e| upload_failure(Status::BadRequest, "shape", e))?;
+
+    let mut reader = SlabReader::new(data.open(), shape, origin.z, cuboid_z);
+    reader.set_max_chunk(max_upload_size.0);
+    reader.set_max_voxels(max_upload_voxels.0);
+    let mut summary = UploadSummary::default();
+    let mut planes = 0;
+    for slab in reader {
+        let Slab { z, data } = slab.map_err(|e| {
+            upload_failure(
+                Status::BadRequest,
+                "body",
+                format!("{} ({} planes were written)", e, planes),
+            )
+        })?;
+        planes += data.dim().0;
+        let slab_origin = Vector3 {
+            z: origin.z + z,
+            ..origin
+        };
+        let written = write_upload(&fm.0, &uri, res, slab_origin, data)?;
+        summary.cuboids += written.cuboids;
+        summary.bytes += written.bytes;
+    }
+    Ok(status::Created(
+        location.path().to_string(),
+        Some(Json(summary)),
+    ))
+}
+
+/// Upload many cutouts in one request.
+///
+/// The body is a stream of length-prefixed records (see the `batch` module
+/// for the wire format).  Each record is decoded and written on its own, so
+/// a bad record is reported without failing the rest of the batch.  The
+/// response is a 400 if any record names a channel that would reach outside
+/// the cache (e.g. `col/../chan`); those records aren't written.
+#[post("/cutout/batch", data = "<data>")]
+fn upload_batch(
+    data: Data,
+    _writer: Writer,
+    fm: FileManager,
+    frame: State<config::FrameOrigin>,
+    max_upload_size: State<config::MaxUploadSize>,
+    max_upload_voxels: State<config::MaxUploadVoxels>,
+) -> status::Custom<Json<Vec<RecordResult>>> {
+    let mut reader = BatchReader::new(data.open());
+    reader.set_max_payload(max_upload_size.0);
+    reader.set_max_voxels(max_upload_voxels.0);
+    let mut bad_channel = false;
+    let results = reader
+        .enumerate()
+        .map(|(index, record)| match record {
+            Ok(Record { header, data }) => {
+                let at_res = frame.0.at_res(header.res);
+                match header.origin.to_frame(at_res) {
+                    Some(origin) => {
+                        let uri = format!("bossdb://{}", header.uri);
+                        let (z, y, x) = data.dim();
+                        let destination = Vector3 {
+                            x: origin.x + x as u64,
+                            y: origin.y + y as u64,
+                            z: origin.z + z as u64,
+                        };
+                        match fm.0.check_upload_frame(&uri, header.res, origin, destination) {
+                            Ok(()) => RecordResult {
+                                index,
+                                success: fm.0.upload(uri, header.res, origin, data),
+                                uri: Some(header.uri),
+                                error: None,
+                            },
+                            Err(e) => RecordResult {
+                                index,
+                                error: Some(e),
+                                uri: Some(header.uri),
+                                success: false,
+                            },
+                        }
+                    }
+                    None => RecordResult {
+                        index,
+                        error: Some(format!(
+                            "The record starts at {}, before the coordinate frame, which starts at {} at resolution {}",
+                            header.origin, at_res, header.res
+                        )),
+                        uri: Some(header.uri),
+                        success: false,
+                    },
+                }
+            }
+            Err(err) => {
+                bad_channel |= err.bad_channel;
+                RecordResult {
+                    index,
+                    uri: err.uri,
+                    success: false,
+                    error: Some(err.message),
+                }
+            }
+        })
+        .collect();
+
+    let status = if bad_channel {
+        Status::BadRequest
+    } else {
+        Status::Ok
+    };
+    status::Custom(status, Json(results))
+}
+
+/// Result of purging old cuboids from the cache.
+#[derive(Serialize, Debug)]
+struct PurgeResult {
+    removed: u32,
+}
+
+/// Remove every cached cuboid last accessed before a time.
+///
+/// `older_than` is an RFC 3339 timestamp (e.g. `2020-04-19T00:00:00Z`).
+/// Both the cuboid files and their usage-tracking rows are removed.  Pinned
+/// channels are left alone.  Requires the admin token.
+///
+#[delete("/cache?<older_than>")]
+fn purge_cache(
+    _admin: Admin,
+    pool: State<Arc<ConnectionPool>>,
+    pinned: State<config::Pinned>,
+    channel_keys: State<Option<Arc<ChannelKeys>>>,
+    write_buffer: State<Option<Arc<WriteBuffer>>>,
+    older_than: &RawStr,
+) -> Result<Json<PurgeResult>, status::BadRequest<String>> {
+    let older_than = older_than.url_decode_lossy();
+    let cutoff = match DateTime::parse_from_rfc3339(&older_than) {
+        Ok(cutoff) => cutoff.naive_utc(),
+        Err(e) => {
+            return Err(status::BadRequest(Some(format!(
+                "older_than must be an RFC 3339 timestamp: {}",
+                e
+            ))))
+        }
+    };
+    let mut db = cache_db(&pool, &channel_keys);
+    db.set_pinned(pinned.0.clone());
+    if let Some(buffer) = write_buffer.inner() {
+        db.set_write_buffer(Arc::clone(buffer));
+    }
+    Ok(Json(PurgeResult {
+        removed: db.purge_older_than(cutoff),
+    }))
+}
+
+/// An interface to the cache DB that finds channels' cuboids by their
+/// hashed directories, if cache paths are hashed.
+fn cache_db(pool: &Arc<ConnectionPool>, keys: &Option<Arc<ChannelKeys>>) -> SqliteCacheInterface {
+    let mut db = SqliteCacheInterface::with_pool(Arc::clone(pool));
+    if let Some(keys) = keys {
+        db.set_channel_keys(Arc::clone(keys));
+    }
+    db
+}
+
+/// Result of evicting cuboids down to a target count.
+#[derive(Serialize, Debug)]
+struct EvictResult {
+    evicted: u32,
+}
+
+/// Evict least recently used cuboids until the cache holds at most
+/// `target` cuboids, regardless of the automatic eviction strategy.  Pinned
+/// channels are left alone, so more may remain.  Requires the admin token.
+///
+#[post("/cache/evict?<target>")]
+fn evict_cache(
+    _admin: Admin,
+    pool: State<Arc<ConnectionPool>>,
+    pinned: State<config::Pinned>,
+    channel_keys: State<Option<Arc<ChannelKeys>>>,
+    write_buffer: State<Option<Arc<WriteBuffer>>>,
+    target: &RawStr,
+) -> Result<Json<EvictResult>, status::BadRequest<String>> {
+    let target = match target.parse::<u32>() {
+        Ok(target) => target,
+        Err(_) => {
+            return Err(status::BadRequest(Some(format!(
+                "target must be a non-negative number of cuboids, not {}",
+                target
+            ))))
+        }
+    };
+    let mut db = cache_db(&pool, &channel_keys);
+    db.set_pinned(pinned.0.clone());
+    if let Some(buffer) = write_buffer.inner() {
+        db.set_write_buffer(Arc::clone(buffer));
+    }
+    Ok(Json(EvictResult {
+        evicted: db.evict_to(target),
+    }))
+}
+
+/// Result of pinning a channel.
+#[derive(Serialize, Debug)]
+struct PinResult {
+    /// Every pinned channel prefix, including the new one.
+    pinned: Vec<String>,
+}
+
+/// Pin a channel so its cuboids are never evicted, until the server
+/// restarts.  To pin permanently, add it to the `PINNED_CHANNELS` setting.
+/// Requires the admin token.
+///
+#[post("/cache/pin/<collection>/<experiment>/<channel>")]
+fn pin_channel(
+    _admin: Admin,
+    pinned: State<config::Pinned>,
+    collection: String,
+    experiment: String,
+    channel: String,
+) -> Json<PinResult> {
+    pinned
+        .0
+        .pin(&format!("{}/{}/{}", collection, experiment, channel));
+    Json(PinResult {
+        pinned: pinned.0.prefixes(),
+    })
+}
+
+/// Parse a window like `90s`, `30m`, `24h` or `7d`.
+fn parse_window(window: &str) -> Option<chrono::Duration> {
+    let (count, unit) = window.split_at(window.char_indices().last()?.0);
+    let count = count.parse::<u32>().ok()? as i64;
+    match unit {
+        "s" => Some(chrono::Duration::seconds(count)),
+        "m" => Some(chrono::Duration::minutes(count)),
+        "h" => Some(chrono::Duration::hours(count)),
+        "d" => Some(chrono::Duration::days(count)),
+        _ => None,
+    }
+}
+
+/// Summarize how the cache has been used, for capacity planning.
+///
+/// Covers the cuboids accessed within `window` (e.g. `24h` or `7d`): their
+/// total requests, the `top` (default 10) most requested, and a breakdown
+/// by `group_by` (`collection`, the default, or `channel`).  Request counts
+/// are since each cuboid was cached, not just within the window.
+///
+#[get("/stats/usage?<window>&<group_by>&<top>")]
+fn usage_stats(
+    _reader: Reader,
+    pool: State<Arc<ConnectionPool>>,
+    window: &RawStr,
+    group_by: Option<&RawStr>,
+    top: Option<&RawStr>,
+) -> Result<Json<UsageStats>, status::BadRequest<String>> {
+    let now = chrono::Utc::now().naive_utc();
+    let since = match parse_window(window).and_then(|w| now.checked_sub_signed(w)) {
+        Some(since) => since,
+        None => {
+            return Err(status::BadRequest(Some(format!(
+                "window must be a duration like 90s, 30m, 24h or 7d, not {}",
+                window
+            ))))
+        }
+    };
+    let grouping =
+        match group_by.map_or(Some(UsageGrouping::Collection), |g| UsageGrouping::parse(g)) {
+            Some(grouping) => grouping,
+            None => {
+                return Err(status::BadRequest(Some(
+                    "group_by must be collection or channel".to_string(),
+                )))
+            }
+        };
+    let top = match top.map_or(Ok(10), |t| t.parse::<u32>()) {
+        Ok(top) => top,
+        Err(_) => {
+            return Err(status::BadRequest(Some(
+                "top must be a non-negative number of cuboids".to_string(),
+            )))
+        }
+    };
+    let db = SqliteCacheInterface::with_pool(Arc::clone(&pool));
+    Ok(Json(db.usage_stats(since, top as i64, grouping)))
+}
+
+/// Count the cutouts fetched from the Boss DB host since the server
+/// started: requests made, requests failed, voxel bytes fetched, and time
+/// spent on them.  Against the cache's hit rate, shows how well the cache
+/// shields the Boss DB.
+#[get("/stats/upstream")]
+fn upstream_stats(_reader: Reader, stats: State<Arc<UpstreamStats>>) -> Json<UpstreamTotals> {
+    Json(stats.totals())
+}
+
+/// The counts of `/stats/upstream` in Prometheus' text format, to scrape.
+#[get("/metrics")]
+fn metrics(_reader: Reader, stats: State<Arc<UpstreamStats>>) -> content::Plain<String> {
+    let totals = stats.totals();
+    let series = [
+        (
+            "bossphorus_upstream_requests_total",
+            "Cutouts requested from the Boss DB host.",
+            totals.calls as f64,
+        ),
+        (
+            "bossphorus_upstream_failures_total",
+            "Cutout requests to the Boss DB host that failed.",
+            totals.failures as f64,
+        ),
+        (
+            "bossphorus_upstream_bytes_total",
+            "Voxel bytes fetched from the Boss DB host.",
+            totals.bytes as f64,
+        ),
+        (
+            "bossphorus_upstream_latency_seconds_total",
+            "Time spent on cutout requests to the Boss DB host.",
+            totals.total_latency_ms / 1000.0,
+        ),
+    ];
+    let mut body = String::new();
+    for (name, help, value) in series.iter() {
+        body.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+            name, help, name, name, value
+        ));
+    }
+    content::Plain(body)
+}
+
+/// Check that cached cuboids are intact, and report the corrupt and missing
+/// ones.  With `evict=true`, they're also removed from the cache.  Checks at
+/// most `limit` (default 1000) cuboids per request; to continue, pass the
+/// report's `next` as `after`.  Requires the admin token.
+///
+#[post("/cache/verify?<after>&<limit>&<evict>")]
+fn verify_cache(
+    _admin: Admin,
+    pool: State<Arc<ConnectionPool>>,
+    hashes: State<Arc<CuboidHashes>>,
+    write_buffer: State<Option<Arc<WriteBuffer>>>,
+    after: Option<&RawStr>,
+    limit: Option<&RawStr>,
+    evict: Option<bool>,
+) -> Result<Json<VerifyReport>, status::BadRequest<String>> {
+    let after = match after.map_or(Ok(0), |a| a.parse::<i64>()) {
+        Ok(after) => after,
+        Err(_) => {
+            return Err(status::BadRequest(Some(
+                "after must be the next value of a previous report".to_string(),
+            )))
+        }
+    };
+    let limit = match limit.map_or(Ok(1000), |l| l.parse::<u32>()) {
+        Ok(limit) if limit > 0 => limit,
+        _ => {
+            return Err(status::BadRequest(Some(
+                "limit must be a positive number of cuboids".to_string(),
+            )))
+        }
+    };
+    let mut db = SqliteCacheInterface::with_pool(Arc::clone(&pool));
+    if let Some(buffer) = write_buffer.inner() {
+        db.set_write_buffer(Arc::clone(buffer));
+    }
+    Ok(Json(db.verify(
+        after,
+        limit as i64,
+        &hashes,
+        evict.unwrap_or(false),
+    )))
+}
+
+/// Reclaim space by removing cached cuboids whose voxels are all zero
+/// (see `SqliteCacheInterface::compact`), reporting the bytes reclaimed.
+/// Compacts at most `limit` (default 1000) cuboids per request; to
+/// continue, pass the report's `next` as `after`.  Safe to run while
+/// serving cutouts.  Requires the admin token.
+///
+#[post("/cache/compact?<after>&<limit>")]
+fn compact_cache(
+    _admin: Admin,
+    pool: State<Arc<ConnectionPool>>,
+    pinned: State<config::Pinned>,
+    channel_keys: State<Option<Arc<ChannelKeys>>>,
+    after: Option<&RawStr>,
+    limit: Option<&RawStr>,
+) -> Result<Json<CompactReport>, status::BadRequest<String>> {
+    let after = match after.map_or(Ok(0), |a| a.parse::<i64>()) {
+        Ok(after) => after,
+        Err(_) => {
+            return Err(status::BadRequest(Some(
+                "after must be the next value of a previous report".to_string(),
+            )))
+        }
+    };
+    let limit = match limit.map_or(Ok(1000), |l| l.parse::<u32>()) {
+        Ok(limit) if limit > 0 => limit,
+        _ => {
+            return Err(status::BadRequest(Some(
+                "limit must be a positive number of cuboids".to_string(),
+            )))
+        }
+    };
+    let mut db = cache_db(&pool, &channel_keys);
+    db.set_pinned(pinned.0.clone());
+    Ok(Json(db.compact(after, limit as i64)))
+}
+
+/// A tar archive of cached cuboids, streamed as it's read.
+struct CacheArchive(ExportReader);
+
+impl<'r> Responder<'r> for CacheArchive {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        Response::build()
+            .raw_header("Content-Type", "application/x-tar")
+            .streamed_body(self.0)
+            .ok()
+    }
+}
+
+/// Export the cached cuboids under `prefix` (e.g. `col/exp/chan`, or just
+/// `col`) as a tar archive, with a manifest of their usage history, for
+/// warming another host's cache with `/cache/import`.  The archive is
+/// streamed, reading each cuboid as it's sent.  Requires the admin token.
+///
+#[get("/cache/export?<prefix>")]
+fn export_cache(
+    _admin: Admin,
+    pool: State<Arc<ConnectionPool>>,
+    channel_keys: State<Option<Arc<ChannelKeys>>>,
+    write_buffer: State<Option<Arc<WriteBuffer>>>,
+    prefix: &RawStr,
+) -> Result<CacheArchive, status::Custom<String>> {
+    let prefix = prefix.url_decode_lossy();
+    if prefix.trim_matches('/').is_empty() {
+        return Err(status::Custom(
+            Status::BadRequest,
+            "prefix must name a collection, experiment or channel".to_string(),
+        ));
+    }
+    // Buffered cuboids aren't in the cache until they're written out:
+    if let Some(buffer) = write_buffer.inner() {
+        buffer.flush_all();
+    }
+    let mut db = cache_db(&pool, &channel_keys);
+    ExportReader::new(db.find_under(&prefix))
+        .map(CacheArchive)
+        .map_err(|e| {
+            status::Custom(
+                Status::InternalServerError,
+                format!("Failed to export {}: {}", prefix, e),
+            )
+        })
+}
+
+/// Unpack an archive from `/cache/export` into the cache, putting each
+/// cuboid under the root configured for its resolution, and track its
+/// cuboids with their usage history.  The archive is read as it arrives.
+/// Cuboids that are already cached are overwritten; entries that aren't
+/// complete cuboids of their channel's cuboid size are skipped.  Requires
+/// the admin token.
+///
+#[post("/cache/import", data = "<data>")]
+fn import_cache(
+    _admin: Admin,
+    fm: FileManager,
+    pool: State<Arc<ConnectionPool>>,
+    resolution_roots: State<config::ResolutionRoots>,
+    cache_modes: State<config::CacheModes>,
+    write_buffer: State<Option<Arc<WriteBuffer>>>,
+    data: Data,
+) -> Result<Json<ImportReport>, status::Custom<String>> {
+    let mut db = SqliteCacheInterface::with_pool(Arc::clone(&pool));
+    if let Some(buffer) = write_buffer.inner() {
+        db.set_write_buffer(Arc::clone(buffer));
+    }
+    cache_archive::import(
+        data.open(),
+        config::CUBOID_ROOT_PATH,
+        &resolution_roots.0,
+        &|channel| fm.0.cuboid_size_of(&format!("bossdb://{}", channel)),
+        cache_modes.0,
+        &mut db,
+    )
+    .map(Json)
+    .map_err(|e| status::Custom(Status::BadRequest, format!("Invalid archive: {}", e)))
+}
+
+/// Rewrite every legacy (headerless) cuboid in the cache in the current
+/// format.  Each file is replaced atomically, so reads are safe meanwhile,
+/// but an upload to a cuboid that's being migrated can be lost, so stop
+/// uploads to the cache first.  Safe to run again.  Requires the admin
+/// token.
+///
+#[post("/cache/migrate")]
+fn migrate_cache(
+    _admin: Admin,
+    resolution_roots: State<config::ResolutionRoots>,
+    cache_modes: State<config::CacheModes>,
+    write_buffer: State<Option<Arc<WriteBuffer>>>,
+) -> Result<Json<MigrationReport>, status::Custom<String>> {
+    // Write out buffered cuboids, in the current format, rather than
+    // migrating their files from under them:
+    if let Some(buffer) = write_buffer.inner() {
+        buffer.flush_all();
+    }
+    let mut report = MigrationReport::default();
+    let roots = std::iter::once(config::CUBOID_ROOT_PATH)
+        .chain(resolution_roots.0.values().map(String::as_str));
+    for root in roots {
+        let root_report =
+            cuboid_file::migrate_dir(Path::new(root), config::CUBOID_SIZE, cache_modes.0.file)
+                .map_err(|e| {
+                    status::Custom(
+                        Status::InternalServerError,
+                        format!("Failed to migrate cache under {}: {}", root, e),
+                    )
+                })?;
+        report.migrated += root_report.migrated;
+        report.skipped += root_report.skipped;
+        report.failed += root_report.failed;
+    }
+    Ok(Json(report))
+}
+
+/// Re-read the config sources and apply the settings that can change
+/// without a restart (see `config::Reloadable`): the usage tracker's
+/// eviction settings and the TTL of channels not found upstream.  Every
+/// other setting needs a restart.  Responds with the settings applied, or
+/// applies nothing if any are invalid.  Requires the admin token.
+///
+#[post("/admin/reload")]
+fn reload_config(
+    _admin: Admin,
+    not_found: State<config::NotFoundCache>,
+) -> Result<Json<config::Reloadable>, status::Custom<String>> {
+    let reloaded = config::reload().map_err(|e| status::Custom(Status::InternalServerError, e))?;
+    // Nothing to update if the usage tracker isn't running:
+    usage_tracker::reconfigure(reloaded.eviction_settings());
+    not_found
+        .0
+        .set_ttl(Duration::from_secs(reloaded.not_found_ttl));
+    Ok(Json(reloaded))
+}
+
+/// Body of a request to replace the Boss token.
+#[derive(Deserialize, Debug)]
+struct TokenUpdate {
+    token: String,
+}
+
+/// Replace the token used with the Boss DB host, e.g. to rotate it,
+/// without a restart.  Requests upstream use it as soon as this returns,
+/// including writes, unless they have a token of their own (see
+/// `BOSS_WRITE_TOKEN`).  Requests already in flight finish with the old
+/// one.  Requires the admin token.
+///
+#[post("/admin/token", format = "json", data = "<update>")]
+fn rotate_token(
+    _admin: Admin,
+    boss_token: State<config::BossToken>,
+    channels: State<Arc<ChannelRegistry>>,
+    update: Json<TokenUpdate>,
+) -> Result<status::NoContent, status::BadRequest<String>> {
+    let token = update.into_inner().token;
+    if token.trim().is_empty() || token.trim() != token {
+        return Err(status::BadRequest(Some(
+            "token must be non-empty, without surrounding whitespace".to_string(),
+        )));
+    }
+    channels.set_token(&token);
+    boss_token.set(token);
+    println!("Replaced the Boss token");
+    Ok(status::NoContent)
+}
+
+#[get("/")]
+fn index() -> String {
+    return format!("Bossphorus v0.0.1");
+}
+
+/// The server's health, as reported by `/health`.
+#[derive(Serialize, Debug)]
+struct Health {
+    /// `ok`, or `degraded` while caching is paused for lack of disk space
+    /// or once the usage tracker is down.
+    status: &'static str,
+    /// Free space on the cache's disks, if it's being watched.
+    disk: Option<DiskStatus>,
+    /// `running`, `down`, or `off` if usage isn't tracked.
+    usage_tracker: &'static str,
+}
+
+/// Report whether the server is healthy.  It's `degraded`, but still
+/// serving, while the disk guard has caching paused (see `disk_guard`) or
+/// once the usage tracker has gone down, leaving the cache to grow
+/// unchecked.
+#[get("/health")]
+fn health(
+    disk_guard: State<Option<Arc<DiskGuard>>>,
+    tracking: Option<State<TrackingUsage>>,
+) -> Json<Health> {
+    let disk = disk_guard.as_ref().map(|guard| guard.status());
+    let usage_tracker = match tracking {
+        Some(tracking) if tracking.0 => {
+            if usage_tracker::is_down() {
+                "down"
+            } else {
+                "running"
+            }
+        }
+        _ => "off",
+    };
+    let degraded =
+        disk.as_ref().map_or(false, |disk| disk.caching_paused) || usage_tracker == "down";
+    Json(Health {
+        status: if degraded { "degraded" } else { "ok" },
+        disk,
+        usage_tracker,
+    })
+}
+
+/// List the layers of the data manager chain that requests are served by,
+/// in order, with their key settings (tokens redacted), to check that it's
+/// wired as configured.  Requires the admin token.
+///
+#[get("/debug/chain")]
+fn debug_chain(_admin: Admin, fm: FileManager) -> Json<Vec<LayerInfo>> {
+    Json(data_manager::describe_chain(&fm.0))
+}
+
+#[catch(404)]
+fn not_found(_req: &Request) { /* .. */
+}
+
+/// Tells clients refused by `Reader` or `Writer` how to authenticate.
+struct Unauthorized;
+
+impl<'r> Responder<'r> for Unauthorized {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        Response::build()
+            .status(Status::Unauthorized)
+            .raw_header("WWW-Authenticate", "Bearer")
+            .sized_body(Cursor::new("A valid API key is required"))
+            .ok()
+    }
+}
+
+#[catch(401)]
+fn unauthorized(_req: &Request) -> Unauthorized {
+    Unauthorized
+}
+
+/// Tells a client throttled by the rate limiter how many seconds to wait
+/// before trying again.
+struct TooManyRequests(Duration);
+
+impl<'r> Responder<'r> for TooManyRequests {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        // Whole seconds, rounded up, so a client that waits is let in:
+        let wait = self.0.as_secs() + if self.0.subsec_nanos() > 0 { 1 } else { 0 };
+        Response::build()
+            .status(Status::TooManyRequests)
+            .raw_header("Retry-After", wait.max(1).to_string())
+            .sized_body(Cursor::new("Too many requests; slow down"))
+            .ok()
+    }
+}
+
+#[catch(429)]
+fn too_many_requests(req: &Request) -> TooManyRequests {
+    TooManyRequests(Throttle::of(req).unwrap_or(Duration::from_secs(1)))
+}
+
+/// Is usage tracking enabled?
+pub struct TrackingUsage(pub bool);
+
+/// Start the usage tracker if it's turned on.  If tracker started, the
 /// TrackingUsage state variable is set to true.
 fn start_usage_tracker(rocket: Rocket) -> Result<Rocket, Rocket> {
     let mgr = rocket.state::<config::UsageTracker>();
@@ -420,7 +2996,49 @@ fn start_usage_tracker(rocket: Rocket) -> Result<Rocket, Rocket> {
             if let UsageTrackerType::None = kind {
                 false
             } else {
-                usage_tracker::run(kind);
+                let defaults = EvictionSettings::default();
+                let eviction = EvictionSettings {
+                    max_cuboids: rocket
+                        .state::<config::MaxCuboids>()
+                        .map_or(defaults.max_cuboids, |m| m.0),
+                    min_residency: rocket.state::<config::MinResidency>().map_or(0, |r| r.0),
+                    strategy: match rocket.state::<config::Eviction>() {
+                        Some(e) if e.0 == config::DECAY_EVICTION => EvictionStrategy::Decay {
+                            half_life: rocket.state::<config::DecayHalfLife>().map_or(0, |h| h.0),
+                        },
+                        _ => EvictionStrategy::Lru,
+                    },
+                    jitter: rocket.state::<config::EvictionJitter>().map_or(0, |j| j.0),
+                };
+                usage_tracker::run(
+                    kind,
+                    UsageTrackerConfig {
+                        eviction,
+                        db_pool: rocket.state::<Arc<ConnectionPool>>().map(Arc::clone),
+                        pinned: rocket
+                            .state::<config::Pinned>()
+                            .map_or(PinnedChannels::default(), |p| p.0.clone()),
+                        channel_keys: rocket
+                            .state::<Option<Arc<ChannelKeys>>>()
+                            .and_then(|k| k.clone()),
+                        write_buffer: rocket
+                            .state::<Option<Arc<WriteBuffer>>>()
+                            .and_then(|b| b.clone()),
+                        extra_roots: rocket
+                            .state::<config::ResolutionRoots>()
+                            .map_or(vec![], |r| r.0.values().cloned().collect()),
+                        removal_retry: rocket
+                            .state::<config::EvictionRetry>()
+                            .map_or(RemovalRetry::default(), |r| r.0),
+                        evict_empty_first: rocket
+                            .state::<config::EvictEmptyFirst>()
+                            .map_or(config::EVICT_EMPTY_FIRST_DEFAULT, |e| e.0),
+                        size_report: rocket.state::<config::CacheSizeReport>().and_then(|r| r.0),
+                        max_restarts: rocket
+                            .state::<config::TrackerRestarts>()
+                            .map_or(UsageTrackerConfig::default().max_restarts, |r| r.0),
+                    },
+                );
                 true
             }
         }
@@ -428,26 +3046,434 @@ fn start_usage_tracker(rocket: Rocket) -> Result<Rocket, Rocket> {
     Ok(rocket.manage(TrackingUsage(tracking)))
 }
 
+/// Mount the route for cutout downloads in no supported format, as set by
+/// the format fallback.
+fn mount_format_fallback(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let blosc = match rocket.state::<config::FormatFallback>() {
+        Some(fallback) => fallback.0 == config::BLOSC_FALLBACK,
+        None => return Err(rocket),
+    };
+    if blosc {
+        Ok(rocket.mount("/v1", routes![download_fallback]))
+    } else {
+        Ok(rocket.mount("/v1", routes![download_not_acceptable]))
+    }
+}
+
+/// Open the pool of cache DB connections and bring the schema up to date.
+fn start_db_pool(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let (size, pragmas, retry) = match (
+        rocket.state::<config::DbPoolSize>(),
+        rocket.state::<config::DbBusyTimeout>(),
+        rocket.state::<config::DbJournalMode>(),
+        rocket.state::<config::DbSynchronous>(),
+        rocket.state::<config::DbConnectRetry>(),
+    ) {
+        (Some(size), Some(busy_timeout), Some(journal_mode), Some(synchronous), Some(retry)) => (
+            size.0,
+            Pragmas {
+                busy_timeout: busy_timeout.0,
+                journal_mode: journal_mode.0.clone(),
+                synchronous: synchronous.0.clone(),
+            },
+            retry.0,
+        ),
+        _ => return Err(rocket),
+    };
+    match db::open_pool(config::DB_URL, size, &pragmas, retry) {
+        Ok(pool) => Ok(rocket.manage(Arc::new(pool))),
+        Err(e) => {
+            println!("{}", e);
+            Err(rocket)
+        }
+    }
+}
+
+/// Start logging requests, if an access log is configured.
+fn start_access_log(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let sink = match rocket.state::<config::AccessLogSink>() {
+        Some(sink) => LogSink::parse(&sink.0),
+        None => return Err(rocket),
+    };
+    match sink {
+        Ok(LogSink::None) => Ok(rocket),
+        Ok(sink) => Ok(rocket.attach(AccessLog::new(sink))),
+        Err(e) => {
+            println!("{}", e);
+            Err(rocket)
+        }
+    }
+}
+
+/// Attach the rate limiter if any limit is configured.
+fn start_rate_limit(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let parse = |spec: Option<&String>| Limit::parse(spec.map_or("", |s| s.as_str()));
+    let global = parse(rocket.state::<config::RateLimit>().map(|l| &l.0));
+    let per_ip = parse(rocket.state::<config::RateLimitPerIp>().map(|l| &l.0));
+    match (global, per_ip) {
+        (Ok(global), Ok(per_ip)) => {
+            let limiter = RateLimiter::new(global, per_ip);
+            if limiter.is_limited() {
+                Ok(rocket.attach(RateLimit(limiter)))
+            } else {
+                Ok(rocket)
+            }
+        }
+        // Already reported by config::validate:
+        _ => Err(rocket),
+    }
+}
+
+/// Start the prefetcher with the configured policy.
+fn start_prefetcher(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let prefetcher = match (
+        rocket.state::<config::Prefetch>(),
+        rocket.state::<config::PrefetchDistance>(),
+    ) {
+        (Some(policy), Some(distance)) => Prefetcher::new(policy.0, distance.0),
+        _ => return Err(rocket),
+    };
+    Ok(rocket.manage(prefetcher))
+}
+
+/// Start watching the free space on the cache's disks, if a minimum is
+/// configured.
+fn start_disk_guard(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let guard = match rocket.state::<config::DiskSpaceGuard>() {
+        Some(settings) if settings.min_free > 0 => {
+            let mut roots = vec![config::CUBOID_ROOT_PATH.to_string()];
+            if let Some(resolution_roots) = rocket.state::<config::ResolutionRoots>() {
+                roots.extend(resolution_roots.0.values().cloned());
+            }
+            let guard = Arc::new(DiskGuard::new(roots, settings.min_free));
+            disk_guard::run(Arc::clone(&guard), settings.interval);
+            Some(guard)
+        }
+        Some(_) => None,
+        None => return Err(rocket),
+    };
+    Ok(rocket.manage(guard))
+}
+
+/// Open the record of hashed channel directories, if cache paths are
+/// hashed.
+fn start_channel_keys(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let keys = match (
+        rocket.state::<config::HashCachePaths>(),
+        rocket.state::<Arc<ConnectionPool>>(),
+    ) {
+        (Some(settings), Some(pool)) if settings.enabled => {
+            Some(Arc::new(ChannelKeys::new(Arc::clone(pool), &settings.salt)))
+        }
+        (Some(_), Some(_)) => None,
+        _ => return Err(rocket),
+    };
+    Ok(rocket.manage(keys))
+}
+
+/// Open the record of empty cuboids, if there's no upstream to fetch
+/// missing cuboids from.
+fn start_empty_cuboids(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let empty = match (
+        rocket.state::<config::Standalone>(),
+        rocket.state::<Arc<ConnectionPool>>(),
+    ) {
+        (Some(config::Standalone(Some(_))), Some(pool)) => {
+            Some(Arc::new(EmptyCuboids::new(Arc::clone(pool))))
+        }
+        (Some(_), Some(_)) => None,
+        _ => return Err(rocket),
+    };
+    Ok(rocket.manage(empty))
+}
+
+/// Open the record of channels uploaded to, pinning them, if uploads are
+/// kept in the cache as a scratch space.
+fn start_scratch_channels(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let scratch = match (
+        rocket.state::<config::ScratchWrites>(),
+        rocket.state::<Arc<ConnectionPool>>(),
+        rocket.state::<config::Pinned>(),
+    ) {
+        (Some(config::ScratchWrites(true)), Some(pool), Some(pinned)) => Some(Arc::new(
+            ScratchChannels::new(Arc::clone(pool), pinned.0.clone()),
+        )),
+        (Some(_), Some(_), Some(_)) => None,
+        _ => return Err(rocket),
+    };
+    Ok(rocket.manage(scratch))
+}
+
+/// Start writing out buffered uploads as their windows pass, if partial
+/// cuboid writes are buffered.
+fn start_write_buffer(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let buffer = match rocket.state::<config::WriteBufferSettings>() {
+        Some(settings) if settings.window > Duration::from_secs(0) => {
+            let buffer = Arc::new(WriteBuffer::new(settings.window, settings.max_cuboids));
+            write_buffer::run(Arc::clone(&buffer));
+            Some(buffer)
+        }
+        Some(_) => None,
+        None => return Err(rocket),
+    };
+    Ok(rocket.manage(buffer))
+}
+
+/// Open the channel registry, which looks up unknown channels on the
+/// configured Boss host.
+fn start_channel_registry(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let source = match (
+        rocket.state::<config::BossProtocol>(),
+        rocket.state::<config::BossHost>(),
+        rocket.state::<config::BossToken>(),
+        rocket.state::<config::BossApiPrefix>(),
+    ) {
+        (Some(protocol), Some(host), Some(token), Some(prefix)) => {
+            let mut source =
+                BossChannelSource::new(protocol.0.to_string(), host.0.to_string(), token.get());
+            source.set_api_prefix(&prefix.0);
+            source
+        }
+        _ => return Err(rocket),
+    };
+    let mut registry = ChannelRegistry::new(config::DB_URL, Box::new(source), config::CUBOID_SIZE);
+    if let Some(sizes) = rocket.state::<config::ChannelCuboidSizes>() {
+        registry.set_cuboid_sizes(sizes.0.clone());
+    }
+    Ok(rocket.manage(Arc::new(registry)))
+}
+
 fn main() {
     rocket::ignite()
         .mount(
             "/v1",
             routes![
                 index,
+                health,
+                debug_chain,
                 get_channel_metadata,
+                get_channel_info,
                 get_experiment_metadata,
                 upload,
+                upload_stream,
+                upload_batch,
+                purge_cache,
+                evict_cache,
+                pin_channel,
+                migrate_cache,
+                verify_cache,
+                verify_cutout,
+                compact_cache,
+                export_cache,
+                import_cache,
+                reload_config,
+                rotate_token,
+                usage_stats,
+                upstream_stats,
+                metrics,
                 download_blosc,
-                download_jpeg
+                download_jpeg,
+                download_raw,
+                download_npy,
+                download_json,
+                download_default,
+                download_pyramid,
+                query_cutout,
+                cutout_cached,
+                cutout_coverage,
+                cached_resolutions,
+                debug_cuboids
             ],
         )
+        .manage(Arc::new(CuboidHashes::new()))
+        .manage(Arc::new(UpstreamStats::new()))
         .attach(AdHoc::on_attach("Boss Host", config::get_boss_host))
         .attach(AdHoc::on_attach("Boss Token", config::get_boss_token))
+        .attach(AdHoc::on_attach(
+            "Boss API Prefix",
+            config::get_boss_api_prefix,
+        ))
+        .attach(AdHoc::on_attach(
+            "Boss Write Host",
+            config::get_boss_write_host,
+        ))
+        .attach(AdHoc::on_attach(
+            "Boss Write Token",
+            config::get_boss_write_token,
+        ))
+        .attach(AdHoc::on_attach(
+            "Scratch Writes",
+            config::get_scratch_writes,
+        ))
+        .attach(AdHoc::on_attach("Admin Token", config::get_admin_token))
+        .attach(AdHoc::on_attach("API Keys", config::get_api_keys))
+        .attach(AdHoc::on_attach("Use Mmap", config::get_use_mmap))
+        .attach(AdHoc::on_attach("Cuboid Reads", config::get_cuboid_reads))
+        .attach(AdHoc::on_attach("Writeback", config::get_writeback))
+        .attach(AdHoc::on_attach(
+            "Writeback Policy",
+            config::get_writeback_policy,
+        ))
+        .attach(AdHoc::on_attach("Cuboid Format", config::get_cuboid_format))
+        .attach(AdHoc::on_attach("Cuboid Layout", config::get_cuboid_layout))
+        .attach(AdHoc::on_attach(
+            "Hash Cache Paths",
+            config::get_hash_cache_paths,
+        ))
+        .attach(AdHoc::on_attach(
+            "Resolution Roots",
+            config::get_resolution_roots,
+        ))
+        .attach(AdHoc::on_attach(
+            "Channel Cuboid Sizes",
+            config::get_channel_cuboid_sizes,
+        ))
+        .attach(AdHoc::on_attach("Fill Value", config::get_fill_value))
+        .attach(AdHoc::on_attach(
+            "Clamp To Extent",
+            config::get_clamp_to_extent,
+        ))
+        .attach(AdHoc::on_attach("Check Frame", config::get_check_frame))
+        .attach(AdHoc::on_attach(
+            "Synthesize Resolutions",
+            config::get_synthesize_resolutions,
+        ))
+        .attach(AdHoc::on_attach(
+            "Downsample On Write",
+            config::get_downsample_on_write,
+        ))
+        .attach(AdHoc::on_attach(
+            "Format Fallback",
+            config::get_format_fallback,
+        ))
+        .attach(AdHoc::on_attach(
+            "Default Format",
+            config::get_default_format,
+        ))
+        .attach(AdHoc::on_attach("JPEG Quality", config::get_jpeg_quality))
+        .attach(AdHoc::on_attach(
+            "JPEG Spool Size",
+            config::get_jpeg_spool_size,
+        ))
+        .attach(AdHoc::on_attach(
+            "On Upstream Error",
+            config::get_on_upstream_error,
+        ))
+        .attach(AdHoc::on_attach("Standalone", config::get_standalone))
+        .attach(AdHoc::on_attach("Prefetch", config::get_prefetch))
+        .attach(AdHoc::on_attach(
+            "Prefetch Distance",
+            config::get_prefetch_distance,
+        ))
+        .attach(AdHoc::on_attach(
+            "Max Upload Size",
+            config::get_max_upload_size,
+        ))
+        .attach(AdHoc::on_attach(
+            "Max Upload Voxels",
+            config::get_max_upload_voxels,
+        ))
+        .attach(AdHoc::on_attach("Frame Origin", config::get_frame_origin))
+        .attach(AdHoc::on_attach(
+            "Upstream Limit",
+            config::get_upstream_limit,
+        ))
+        .attach(AdHoc::on_attach("Max Open Cuboids", config::get_file_limit))
+        .attach(AdHoc::on_attach(
+            "Max Request Cuboids",
+            config::get_max_request_cuboids,
+        ))
+        .attach(AdHoc::on_attach(
+            "Cutout Memory Budget",
+            config::get_cutout_memory,
+        ))
+        .attach(AdHoc::on_attach("Cache Modes", config::get_cache_modes))
+        .attach(AdHoc::on_attach("Not Found TTL", config::get_not_found_ttl))
+        .attach(AdHoc::on_attach(
+            "Cuboid Max Age",
+            config::get_cuboid_max_age,
+        ))
         .attach(AdHoc::on_attach(
             "Usage Tracker Config",
             config::get_usage_tracker,
         ))
+        .attach(AdHoc::on_attach("Max Cuboids", config::get_max_cuboids))
+        .attach(AdHoc::on_attach("Min Residency", config::get_min_residency))
+        .attach(AdHoc::on_attach(
+            "Eviction Jitter",
+            config::get_eviction_jitter,
+        ))
+        .attach(AdHoc::on_attach("Eviction", config::get_eviction))
+        .attach(AdHoc::on_attach(
+            "Eviction Retry",
+            config::get_eviction_retry,
+        ))
+        .attach(AdHoc::on_attach(
+            "Evict Empty First",
+            config::get_evict_empty_first,
+        ))
+        .attach(AdHoc::on_attach(
+            "Cache Size Report",
+            config::get_cache_size_report,
+        ))
+        .attach(AdHoc::on_attach(
+            "Usage Tracker Restarts",
+            config::get_tracker_restarts,
+        ))
+        .attach(AdHoc::on_attach(
+            "Disk Space Guard",
+            config::get_disk_space_guard,
+        ))
+        .attach(AdHoc::on_attach("Write Buffer", config::get_write_buffer))
+        .attach(AdHoc::on_attach(
+            "Decay Half Life",
+            config::get_decay_half_life,
+        ))
+        .attach(AdHoc::on_attach("DB Pool Size", config::get_db_pool_size))
+        .attach(AdHoc::on_attach(
+            "DB Busy Timeout",
+            config::get_db_busy_timeout,
+        ))
+        .attach(AdHoc::on_attach(
+            "DB Connect Retry",
+            config::get_db_connect_retry,
+        ))
+        .attach(AdHoc::on_attach(
+            "DB Journal Mode",
+            config::get_db_journal_mode,
+        ))
+        .attach(AdHoc::on_attach(
+            "DB Synchronous",
+            config::get_db_synchronous,
+        ))
+        .attach(AdHoc::on_attach("Access Log", config::get_access_log))
+        .attach(AdHoc::on_attach("Rate Limits", config::get_rate_limits))
+        .attach(AdHoc::on_attach(
+            "Pinned Channels",
+            config::get_pinned_channels,
+        ))
+        .attach(AdHoc::on_attach("Validate Config", config::validate))
+        .attach(AdHoc::on_attach(
+            "Format Fallback Mount",
+            mount_format_fallback,
+        ))
+        .attach(AdHoc::on_attach("Cache DB Pool Start", start_db_pool))
+        .attach(AdHoc::on_attach("Channel Keys Start", start_channel_keys))
+        .attach(AdHoc::on_attach("Write Buffer Start", start_write_buffer))
         .attach(AdHoc::on_attach("Usage Tracker Start", start_usage_tracker))
-        .register(catchers![not_found])
+        .attach(AdHoc::on_attach("Disk Guard Start", start_disk_guard))
+        .attach(AdHoc::on_attach("Empty Cuboids Start", start_empty_cuboids))
+        .attach(AdHoc::on_attach(
+            "Scratch Channels Start",
+            start_scratch_channels,
+        ))
+        .attach(AdHoc::on_attach(
+            "Channel Registry Start",
+            start_channel_registry,
+        ))
+        .attach(AdHoc::on_attach("Prefetcher Start", start_prefetcher))
+        .attach(AdHoc::on_attach("Access Log Start", start_access_log))
+        .attach(AdHoc::on_attach("Rate Limit Start", start_rate_limit))
+        .register(catchers![not_found, unauthorized, too_many_requests])
         .launch();
 }
@@ -0,0 +1,168 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Prefetch module.
+///
+/// Viewers tend to walk through a volume in a predictable order (e.g. a
+/// raster scan of tiles, or down through z), so after a cutout is served,
+/// the cuboids that they're likely to ask for next can be warmed in the
+/// background.
+use crate::data_manager::{ChunkedFileDataManager, Vector3};
+use crate::semaphore::Semaphore;
+
+use std::sync::Arc;
+use std::thread;
+
+#[cfg(test)]
+pub mod tests;
+
+/// Which regions to warm after a cutout.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PrefetchPolicy {
+    /// Don't prefetch.
+    None,
+    /// The slabs after the cutout in z, with the same XY extent.
+    NextZ,
+    /// The tiles after the cutout in x, as in a raster scan.
+    NextXyTile,
+}
+
+impl PrefetchPolicy {
+    /// Look up a policy by its config name (`none`, `next-z`, or
+    /// `next-xy-tile`).
+    pub fn parse(name: &str) -> Option<PrefetchPolicy> {
+        match name.to_lowercase().as_str() {
+            "none" => Some(PrefetchPolicy::None),
+            "next-z" => Some(PrefetchPolicy::NextZ),
+            "next-xy-tile" => Some(PrefetchPolicy::NextXyTile),
+            _ => None,
+        }
+    }
+
+    /// Predict the regions that will be requested after a cutout, nearest
+    /// first.
+    ///
+    /// # Arguments
+    ///
+    /// * `origin` - The start position of the cutout (global coords)
+    /// * `destination` - The end position in global coords
+    /// * `distance` - How many regions to look ahead
+    ///
+    pub fn next_regions(
+        &self,
+        origin: Vector3,
+        destination: Vector3,
+        distance: u64,
+    ) -> Vec<(Vector3, Vector3)> {
+        let step = match self {
+            PrefetchPolicy::None => return Vec::new(),
+            PrefetchPolicy::NextZ => Vector3 {
+                x: 0,
+                y: 0,
+                z: destination.z.saturating_sub(origin.z),
+            },
+            PrefetchPolicy::NextXyTile => Vector3 {
+                x: destination.x.saturating_sub(origin.x),
+                y: 0,
+                z: 0,
+            },
+        };
+        if step.x == 0 && step.z == 0 {
+            return Vec::new();
+        }
+        let shift = |v: Vector3, k: u64| -> Option<Vector3> {
+            Some(Vector3 {
+                x: v.x.checked_add(step.x.checked_mul(k)?)?,
+                y: v.y,
+                z: v.z.checked_add(step.z.checked_mul(k)?)?,
+            })
+        };
+        let mut regions = Vec::new();
+        for k in 1..=distance {
+            match (shift(origin, k), shift(destination, k)) {
+                (Some(start), Some(stop)) => regions.push((start, stop)),
+                _ => break,
+            }
+        }
+        regions
+    }
+}
+
+/// Warms predicted regions on a background thread after each cutout.
+///
+/// At most one prefetch runs at a time, and a cutout that arrives while one
+/// is running isn't followed by another, so prefetching can't pile up
+/// threads or crowd requests out of the upstream limit.
+pub struct Prefetcher {
+    policy: PrefetchPolicy,
+    distance: u64,
+    running: Arc<Semaphore>,
+}
+
+impl Prefetcher {
+    /// Create a prefetcher.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - Which regions to warm
+    /// * `distance` - How many regions to look ahead
+    ///
+    pub fn new(policy: PrefetchPolicy, distance: u64) -> Prefetcher {
+        Prefetcher {
+            policy,
+            distance,
+            running: Arc::new(Semaphore::new(1)),
+        }
+    }
+
+    /// Start warming the regions predicted to follow a cutout, using the
+    /// file manager that served it.  Returns whether a prefetch started.
+    ///
+    /// # Arguments
+    ///
+    /// * `fm` - The file manager that served the cutout
+    /// * `uri` - A URI like `bossdb://col/exp/chan`
+    /// * `res` - Resolution level
+    /// * `origin` - The start position of the cutout (global coords)
+    /// * `destination` - The end position in global coords
+    ///
+    pub fn after_cutout(
+        &self,
+        fm: ChunkedFileDataManager,
+        uri: String,
+        res: u8,
+        origin: Vector3,
+        destination: Vector3,
+    ) -> bool {
+        let regions = self.policy.next_regions(origin, destination, self.distance);
+        if regions.is_empty() {
+            return false;
+        }
+        let permit = match self.running.try_acquire_owned() {
+            Some(permit) => permit,
+            None => return false,
+        };
+        thread::spawn(move || {
+            let _permit = permit;
+            for (origin, destination) in regions {
+                fm.warm(&uri, res, origin, destination);
+            }
+        });
+        true
+    }
+}
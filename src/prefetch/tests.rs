@@ -0,0 +1,126 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::data_manager::Vector3;
+use crate::prefetch::PrefetchPolicy;
+
+fn tile() -> (Vector3, Vector3) {
+    (
+        Vector3 {
+            x: 512,
+            y: 1024,
+            z: 16,
+        },
+        Vector3 {
+            x: 1024,
+            y: 1536,
+            z: 32,
+        },
+    )
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(Some(PrefetchPolicy::None), PrefetchPolicy::parse("none"));
+    assert_eq!(Some(PrefetchPolicy::NextZ), PrefetchPolicy::parse("next-z"));
+    assert_eq!(
+        Some(PrefetchPolicy::NextXyTile),
+        PrefetchPolicy::parse("Next-XY-Tile")
+    );
+    assert_eq!(None, PrefetchPolicy::parse("next-t"));
+}
+
+#[test]
+fn test_next_z() {
+    let (origin, destination) = tile();
+    let regions = PrefetchPolicy::NextZ.next_regions(origin, destination, 2);
+    assert_eq!(
+        vec![
+            (
+                Vector3 {
+                    x: 512,
+                    y: 1024,
+                    z: 32
+                },
+                Vector3 {
+                    x: 1024,
+                    y: 1536,
+                    z: 48
+                }
+            ),
+            (
+                Vector3 {
+                    x: 512,
+                    y: 1024,
+                    z: 48
+                },
+                Vector3 {
+                    x: 1024,
+                    y: 1536,
+                    z: 64
+                }
+            ),
+        ],
+        regions
+    );
+}
+
+#[test]
+fn test_next_xy_tile() {
+    let (origin, destination) = tile();
+    let regions = PrefetchPolicy::NextXyTile.next_regions(origin, destination, 1);
+    assert_eq!(
+        vec![(
+            Vector3 {
+                x: 1024,
+                y: 1024,
+                z: 16
+            },
+            Vector3 {
+                x: 1536,
+                y: 1536,
+                z: 32
+            }
+        )],
+        regions
+    );
+}
+
+#[test]
+fn test_no_regions() {
+    let (origin, destination) = tile();
+    assert!(PrefetchPolicy::None
+        .next_regions(origin, destination, 4)
+        .is_empty());
+    assert!(PrefetchPolicy::NextZ
+        .next_regions(origin, destination, 0)
+        .is_empty());
+
+    // Would run off the end of the coordinate space:
+    let far = Vector3 {
+        x: 0,
+        y: 0,
+        z: u64::MAX - 8,
+    };
+    let end = Vector3 {
+        x: 4,
+        y: 4,
+        z: u64::MAX,
+    };
+    assert!(PrefetchPolicy::NextZ.next_regions(far, end, 1).is_empty());
+}
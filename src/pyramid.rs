@@ -0,0 +1,119 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Pyramid module.
+///
+/// Builds the body of a `/pyramid` response: one cutout per resolution
+/// level, as a `multipart/mixed` body (RFC 2046) with a boundary picked at
+/// random for each body, given in its content type.  Each part is:
+///
+/// * `--<boundary>\r\n`
+/// * a `Content-Type: application/blosc` header
+/// * a `Content-Length` header, the size of the part's body in bytes
+/// * an `X-Resolution` header, the part's resolution level
+/// * an `X-Extents` header, the part's global extents at that level, like
+///   `0:256/0:256/0:16` (x/y/z)
+/// * an `X-Shape` header, the shape of the voxels, as `z,y,x`
+/// * a blank line, then the blosc-compressed `uint8` voxels in ZYX C-order
+///   and `\r\n`
+///
+/// and the body ends with `--<boundary>--\r\n`.  Parts are in order of
+/// resolution, starting from 0.  Since the voxels are binary, clients
+/// should read each part's body by its `Content-Length` rather than by
+/// searching for the boundary.
+use crate::data_manager::Coords;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+#[cfg(test)]
+pub mod tests;
+
+/// Max number of levels in one response.
+pub const MAX_LEVELS: u8 = 8;
+
+/// Builds a pyramid body a level at a time.
+pub struct PyramidBody {
+    boundary: String,
+    body: Vec<u8>,
+}
+
+impl PyramidBody {
+    pub fn new() -> PyramidBody {
+        PyramidBody {
+            boundary: random_boundary(),
+            body: vec![],
+        }
+    }
+
+    /// Content type of the body, which gives its boundary.
+    pub fn content_type(&self) -> String {
+        format!("multipart/mixed; boundary={}", self.boundary)
+    }
+
+    /// Add a level.
+    ///
+    /// # Arguments
+    ///
+    /// * `res` - Resolution level
+    /// * `origin` - Start of the level's global extents, at that level
+    /// * `destination` - End of the level's global extents, at that level
+    /// * `blosc` - The blosc-compressed voxels
+    ///
+    pub fn add_level(&mut self, res: u8, origin: Coords, destination: Coords, blosc: &[u8]) {
+        let headers = format!(
+            "--{}\r\nContent-Type: application/blosc\r\nContent-Length: {}\r\n\
+             X-Resolution: {}\r\nX-Extents: {}:{}/{}:{}/{}:{}\r\nX-Shape: {},{},{}\r\n\r\n",
+            self.boundary,
+            blosc.len(),
+            res,
+            origin.x,
+            destination.x,
+            origin.y,
+            destination.y,
+            origin.z,
+            destination.z,
+            destination.z - origin.z,
+            destination.y - origin.y,
+            destination.x - origin.x,
+        );
+        self.body.extend_from_slice(headers.as_bytes());
+        self.body.extend_from_slice(blosc);
+        self.body.extend_from_slice(b"\r\n");
+    }
+
+    /// Close the body.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.body
+            .extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        self.body
+    }
+}
+
+impl Default for PyramidBody {
+    fn default() -> PyramidBody {
+        PyramidBody::new()
+    }
+}
+
+/// A boundary that a client can't predict, so that it can't plant one in
+/// the data.
+fn random_boundary() -> String {
+    // Every RandomState is keyed differently, which is random enough here.
+    let random = || RandomState::new().build_hasher().finish();
+    format!("bossphorus-pyramid-{:016x}{:016x}", random(), random())
+}
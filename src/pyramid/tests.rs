@@ -0,0 +1,109 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use super::PyramidBody;
+use crate::data_manager::Coords;
+use std::collections::HashMap;
+
+/// A part of a pyramid body: its headers and body.
+type Part = (HashMap<String, String>, Vec<u8>);
+
+/// The boundary given in a pyramid's content type.
+fn boundary(pyramid: &PyramidBody) -> String {
+    let content_type = pyramid.content_type();
+    let prefix = "multipart/mixed; boundary=";
+    assert!(content_type.starts_with(prefix));
+    content_type[prefix.len()..].to_string()
+}
+
+/// Parse a pyramid body the way a client would, reading each part's body
+/// by its `Content-Length`.
+fn parse(mut body: &[u8], boundary: &str) -> Vec<Part> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = vec![];
+    loop {
+        assert!(body.starts_with(delimiter.as_bytes()));
+        body = &body[delimiter.len()..];
+        if body == b"--\r\n" {
+            return parts;
+        }
+        body = &body[2..];
+        let mut headers = HashMap::new();
+        loop {
+            let end = body.windows(2).position(|w| w == b"\r\n").unwrap();
+            let line = std::str::from_utf8(&body[..end]).unwrap().to_string();
+            body = &body[end + 2..];
+            if line.is_empty() {
+                break;
+            }
+            let colon = line.find(": ").unwrap();
+            headers.insert(line[..colon].to_string(), line[colon + 2..].to_string());
+        }
+        let len: usize = headers["Content-Length"].parse().unwrap();
+        parts.push((headers, body[..len].to_vec()));
+        assert_eq!(b"\r\n", &body[len..len + 2]);
+        body = &body[len + 2..];
+    }
+}
+
+#[test]
+fn test_pyramid_body() {
+    let mut pyramid = PyramidBody::new();
+    pyramid.add_level(
+        0,
+        Coords { x: 0, y: 0, z: 0 },
+        Coords { x: 4, y: 2, z: 1 },
+        b"level zero",
+    );
+    // Binary data that happens to contain the boundary:
+    let boundary = boundary(&pyramid);
+    let tricky = format!("\r\n--{}--\r\n", boundary).into_bytes();
+    pyramid.add_level(
+        1,
+        Coords { x: 0, y: 0, z: 0 },
+        Coords { x: 2, y: 1, z: 1 },
+        &tricky,
+    );
+    let parts = parse(&pyramid.finish(), &boundary);
+
+    assert_eq!(2, parts.len());
+    let (headers, body) = &parts[0];
+    assert_eq!("application/blosc", headers["Content-Type"]);
+    assert_eq!("0", headers["X-Resolution"]);
+    assert_eq!("0:4/0:2/0:1", headers["X-Extents"]);
+    assert_eq!("1,2,4", headers["X-Shape"]);
+    assert_eq!(b"level zero", &body[..]);
+    let (headers, body) = &parts[1];
+    assert_eq!("1", headers["X-Resolution"]);
+    assert_eq!("1,1,2", headers["X-Shape"]);
+    assert_eq!(tricky, *body);
+}
+
+#[test]
+fn test_empty_pyramid_body() {
+    let pyramid = PyramidBody::new();
+    let boundary = boundary(&pyramid);
+    assert!(parse(&pyramid.finish(), &boundary).is_empty());
+}
+
+#[test]
+fn test_boundary_differs_per_body() {
+    let boundary = boundary(&PyramidBody::new());
+    assert!(boundary.len() > 16);
+    assert_ne!(boundary, super::random_boundary());
+}
@@ -0,0 +1,275 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Rate limit module.
+///
+/// Token buckets that cap how fast clients can make requests, across all
+/// clients and for each client IP, to protect the upstream BossDB and the
+/// cache from abusive clients.  The `RateLimit` fairing takes a token for
+/// every request as it arrives, and records in the request whether it's
+/// throttled (see `Throttle`).  A throttled request is routed to a route of
+/// its own that fails with 429, whatever route it was for, so that its
+/// handler never runs.
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::handler::Outcome;
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::{Data, Request, Rocket, Route};
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+pub mod tests;
+
+/// Max number of per-IP buckets kept.  Past it, the least recently used
+/// are dropped, as if their clients were new.
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// Where throttled requests are routed.
+const THROTTLED_PATH: &str = "/__throttled";
+
+/// A sustained rate, and how big a burst above it is allowed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Limit {
+    /// Requests per second.
+    pub rate: f64,
+    /// Max requests at once, after a quiet spell.
+    pub burst: u32,
+}
+
+impl Limit {
+    /// Parse a limit like `10` (requests per second, with bursts of as
+    /// many) or `10/50` (with bursts of up to 50).  Empty, or a rate of 0,
+    /// means no limit.
+    pub fn parse(spec: &str) -> Result<Option<Limit>, String> {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            return Ok(None);
+        }
+        let invalid = || {
+            format!(
+                "Invalid rate limit {} (expected a rate per second, optionally with /burst)",
+                spec
+            )
+        };
+        let mut parts = spec.splitn(2, '/');
+        let rate: f64 = parts
+            .next()
+            .and_then(|r| r.trim().parse().ok())
+            .filter(|r: &f64| r.is_finite() && *r >= 0.0)
+            .ok_or_else(invalid)?;
+        if rate == 0.0 {
+            return Ok(None);
+        }
+        let burst = match parts.next() {
+            Some(burst) => burst
+                .trim()
+                .parse()
+                .ok()
+                .filter(|b| *b > 0)
+                .ok_or_else(invalid)?,
+            None => rate.ceil() as u32,
+        };
+        Ok(Some(Limit { rate, burst }))
+    }
+}
+
+/// A bucket of up to `burst` tokens, refilled at `rate` per second.
+pub struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(limit: Limit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// Check that there's a token without taking it, or fail with how
+    /// long until there's one.
+    pub fn check(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - self.tokens) / self.limit.rate,
+        ))
+    }
+
+    /// Take a token, or fail with how long until there's one.
+    pub fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.check(now)?;
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// The bucket of each client IP, with the IPs in the order their buckets
+/// were last used, so that the least recently used are found without a
+/// scan.
+#[derive(Default)]
+struct IpBuckets {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    by_use: BTreeSet<(Instant, IpAddr)>,
+}
+
+impl IpBuckets {
+    /// Take a token from a client's bucket, making it one if it's new.
+    fn take(&mut self, limit: Limit, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        match self.buckets.get(&ip) {
+            Some(bucket) => {
+                self.by_use.remove(&(bucket.updated, ip));
+            }
+            None => {
+                // Buckets unused for long enough to refill are as good as
+                // new, so they can go; if that's not enough, so can the
+                // least recently used.
+                let refilled = Duration::from_secs_f64(limit.burst as f64 / limit.rate);
+                while let Some(&(updated, oldest)) = self.by_use.iter().next() {
+                    if self.buckets.len() < MAX_IDLE_BUCKETS
+                        && now.saturating_duration_since(updated) < refilled
+                    {
+                        break;
+                    }
+                    self.by_use.remove(&(updated, oldest));
+                    self.buckets.remove(&oldest);
+                }
+            }
+        }
+        let bucket = self
+            .buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(limit, now));
+        let taken = bucket.take(now);
+        self.by_use.insert((bucket.updated, ip));
+        taken
+    }
+}
+
+/// Limits on requests across all clients, and for each client IP.
+pub struct RateLimiter {
+    global: Option<Mutex<TokenBucket>>,
+    per_ip: Option<(Limit, Mutex<IpBuckets>)>,
+}
+
+impl RateLimiter {
+    /// # Arguments
+    ///
+    /// * `global` - Limit on all requests together, if any
+    /// * `per_ip` - Limit on the requests of each client IP, if any
+    ///
+    pub fn new(global: Option<Limit>, per_ip: Option<Limit>) -> RateLimiter {
+        let now = Instant::now();
+        RateLimiter {
+            global: global.map(|limit| Mutex::new(TokenBucket::new(limit, now))),
+            per_ip: per_ip.map(|limit| (limit, Mutex::new(IpBuckets::default()))),
+        }
+    }
+
+    /// Does it limit anything?
+    pub fn is_limited(&self) -> bool {
+        self.global.is_some() || self.per_ip.is_some()
+    }
+
+    /// Take a token for a request, or fail with how long until the client
+    /// may try again.  A request throttled by either limit takes a token
+    /// from neither, so a client's throttled requests don't count against
+    /// the global limit, and requests throttled globally don't count
+    /// against their client's.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The client's IP, if known; unknown clients share a bucket
+    /// * `now` - When the request arrived
+    ///
+    pub fn check(&self, ip: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+        let mut global = self.global.as_ref().map(|bucket| bucket.lock().unwrap());
+        if let Some(bucket) = global.as_mut() {
+            bucket.check(now)?;
+        }
+        if let Some((limit, buckets)) = &self.per_ip {
+            let ip = ip.unwrap_or_else(|| IpAddr::from([0, 0, 0, 0]));
+            buckets.lock().unwrap().take(*limit, ip, now)?;
+        }
+        if let Some(bucket) = global.as_mut() {
+            bucket.take(now)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a request was throttled, and if so, how long until the client
+/// may try again.  Lives in the request's local cache, so the fairing and
+/// the request guards see the same one.
+#[derive(Default)]
+pub struct Throttle(Option<Duration>);
+
+impl Throttle {
+    /// How long a request's client has to wait, if it was throttled.
+    pub fn of(request: &Request) -> Option<Duration> {
+        request.local_cache(Throttle::default).0
+    }
+}
+
+/// Handles every throttled request, failing with 429 so that the catcher
+/// for it can say how long to wait (see `Throttle::of`).
+fn refuse_throttled<'r>(_request: &'r Request, _data: Data) -> Outcome<'r> {
+    Outcome::failure(Status::TooManyRequests)
+}
+
+/// Fairing that applies a `RateLimiter` to every request, whatever route
+/// it's for.
+pub struct RateLimit(pub RateLimiter);
+
+impl Fairing for RateLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate Limit",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let route = Route::new(Method::Get, THROTTLED_PATH, refuse_throttled);
+        Ok(rocket.mount("/", vec![route]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let verdict = self.0.check(request.client_ip(), Instant::now());
+        request.local_cache(|| Throttle(verdict.err()));
+        if verdict.is_err() {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(THROTTLED_PATH).unwrap());
+        }
+    }
+}
@@ -0,0 +1,136 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::rate_limit::{Limit, RateLimiter, TokenBucket, MAX_IDLE_BUCKETS};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+fn limit(rate: f64, burst: u32) -> Limit {
+    Limit { rate, burst }
+}
+
+#[test]
+fn test_parse_limit() {
+    assert_eq!(Ok(Some(limit(10.0, 10))), Limit::parse("10"));
+    assert_eq!(Ok(Some(limit(10.0, 50))), Limit::parse(" 10 / 50 "));
+    assert_eq!(Ok(Some(limit(0.5, 1))), Limit::parse("0.5"));
+    for spec in &["", "0", "0/10"] {
+        assert_eq!(Ok(None), Limit::parse(spec), "{}", spec);
+    }
+    for spec in &["fast", "-1", "10/0", "10/x", "10/5/5", "inf"] {
+        assert!(Limit::parse(spec).is_err(), "{}", spec);
+    }
+}
+
+#[test]
+fn test_bucket_throttles_bursts_then_refills() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(limit(2.0, 3), start);
+    for _ in 0..3 {
+        assert_eq!(Ok(()), bucket.take(start));
+    }
+    let wait = bucket.take(start).unwrap_err();
+    assert_eq!(Duration::from_millis(500), wait);
+
+    // Refills at the rate:
+    assert_eq!(Ok(()), bucket.take(start + wait));
+    assert!(bucket.take(start + wait).is_err());
+    // But never past the burst:
+    let later = start + Duration::from_secs(60);
+    for _ in 0..3 {
+        assert_eq!(Ok(()), bucket.take(later));
+    }
+    assert!(bucket.take(later).is_err());
+}
+
+#[test]
+fn test_per_ip_limits_are_separate() {
+    let limiter = RateLimiter::new(None, Some(limit(1.0, 2)));
+    let now = Instant::now();
+    let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+    assert!(limiter.check(Some(a), now).is_ok());
+    assert!(limiter.check(Some(a), now).is_ok());
+    assert!(limiter.check(Some(a), now).is_err());
+    // Another client isn't held back:
+    assert!(limiter.check(Some(b), now).is_ok());
+    // Unknown clients share a bucket:
+    assert!(limiter.check(None, now).is_ok());
+    assert!(limiter.check(None, now).is_ok());
+    assert!(limiter.check(None, now).is_err());
+}
+
+#[test]
+fn test_global_limit_covers_all_clients() {
+    let limiter = RateLimiter::new(Some(limit(1.0, 2)), Some(limit(1.0, 5)));
+    let now = Instant::now();
+    let ip = |last: u8| Some(IpAddr::from([10, 0, 0, last]));
+    assert!(limiter.check(ip(1), now).is_ok());
+    assert!(limiter.check(ip(2), now).is_ok());
+    assert!(limiter.check(ip(3), now).is_err());
+    assert!(limiter.check(ip(3), now + Duration::from_secs(1)).is_ok());
+    assert!(!RateLimiter::new(None, None).is_limited());
+}
+
+#[test]
+fn test_globally_throttled_requests_keep_their_tokens() {
+    let limiter = RateLimiter::new(Some(limit(1.0, 1)), Some(limit(1.0, 1)));
+    let now = Instant::now();
+    let (a, b) = (
+        Some(IpAddr::from([10, 0, 0, 1])),
+        Some(IpAddr::from([10, 0, 0, 2])),
+    );
+    assert!(limiter.check(a, now).is_ok());
+    assert!(limiter.check(b, now).is_err());
+    // Its client's token wasn't spent on the throttled request:
+    let later = now + Duration::from_secs(1);
+    assert!(limiter.check(b, later).is_ok());
+}
+
+/// The number of client IPs with a bucket.
+fn tracked_ips(limiter: &RateLimiter) -> usize {
+    limiter
+        .per_ip
+        .as_ref()
+        .unwrap()
+        .1
+        .lock()
+        .unwrap()
+        .buckets
+        .len()
+}
+
+#[test]
+fn test_idle_buckets_are_dropped() {
+    let limiter = RateLimiter::new(None, Some(limit(1.0, 2)));
+    let now = Instant::now();
+    let ip = |n: usize| Some(IpAddr::from([10, 0, (n >> 8) as u8, n as u8]));
+    assert!(limiter.check(ip(0), now).is_ok());
+    assert!(limiter.check(ip(0), now).is_ok());
+    assert!(limiter.check(ip(1), now).is_ok());
+
+    // Refilled buckets go when another client shows up:
+    let refilled = now + Duration::from_secs(2);
+    assert!(limiter.check(ip(2), refilled).is_ok());
+    assert_eq!(1, tracked_ips(&limiter));
+
+    // And there are never too many, even if none are idle:
+    for n in 0..(MAX_IDLE_BUCKETS + 10) {
+        assert!(limiter.check(ip(n), refilled).is_ok());
+    }
+    assert_eq!(MAX_IDLE_BUCKETS, tracked_ips(&limiter));
+}
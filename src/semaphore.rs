@@ -0,0 +1,159 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Semaphore module.
+///
+/// A plain counting semaphore for capping how many threads may do some
+/// expensive thing at once (e.g. talk to the upstream BossDB).  The standard
+/// library doesn't ship one.
+use std::sync::{Arc, Condvar, Mutex};
+
+#[cfg(test)]
+pub mod tests;
+
+pub struct Semaphore {
+    /// Total number of permits.
+    capacity: usize,
+    /// Number of permits currently available.
+    permits: Mutex<usize>,
+    /// Signalled whenever a permit is returned.
+    available: Condvar,
+}
+
+/// Holds a permit until dropped.
+pub struct SemaphoreGuard<'a> {
+    sem: &'a Semaphore,
+    permits: usize,
+}
+
+/// Holds a permit until dropped, without borrowing the semaphore, so it
+/// can be moved into another thread or task.
+pub struct OwnedSemaphoreGuard {
+    sem: Arc<Semaphore>,
+    permits: usize,
+}
+
+impl Semaphore {
+    /// Create a semaphore with the given number of permits.
+    ///
+    /// # Arguments
+    ///
+    /// * `permits` - How many holders are allowed at once
+    ///
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            capacity: permits,
+            permits: Mutex::new(permits),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Total number of permits, whether or not they're held.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Block until a permit is available, then take it.
+    pub fn acquire(&self) -> SemaphoreGuard<'_> {
+        self.take();
+        SemaphoreGuard {
+            sem: self,
+            permits: 1,
+        }
+    }
+
+    /// Block until a permit is available, then take it, keeping the
+    /// semaphore alive for as long as the permit is held.
+    pub fn acquire_owned(self: &Arc<Self>) -> OwnedSemaphoreGuard {
+        self.take();
+        OwnedSemaphoreGuard {
+            sem: Arc::clone(self),
+            permits: 1,
+        }
+    }
+
+    /// Take a permit only if one is available right now.
+    pub fn try_acquire(&self) -> Option<SemaphoreGuard<'_>> {
+        if !self.try_take(1) {
+            return None;
+        }
+        Some(SemaphoreGuard {
+            sem: self,
+            permits: 1,
+        })
+    }
+
+    /// Take a permit only if one is available right now, keeping the
+    /// semaphore alive for as long as the permit is held.
+    pub fn try_acquire_owned(self: &Arc<Self>) -> Option<OwnedSemaphoreGuard> {
+        self.try_acquire_many_owned(1)
+    }
+
+    /// Take `n` permits at once, only if they're all available right now,
+    /// keeping the semaphore alive for as long as they're held.  Useful
+    /// when the permits stand for something divisible, like bytes of a
+    /// memory budget.  Asking for more than the capacity always fails.
+    pub fn try_acquire_many_owned(self: &Arc<Self>, n: usize) -> Option<OwnedSemaphoreGuard> {
+        if !self.try_take(n) {
+            return None;
+        }
+        Some(OwnedSemaphoreGuard {
+            sem: Arc::clone(self),
+            permits: n,
+        })
+    }
+
+    fn take(&self) {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.available.wait(permits).unwrap();
+        }
+        *permits -= 1;
+    }
+
+    fn try_take(&self, n: usize) -> bool {
+        let mut permits = self.permits.lock().unwrap();
+        if *permits < n {
+            return false;
+        }
+        *permits -= n;
+        true
+    }
+
+    fn release(&self, n: usize) {
+        let mut permits = self.permits.lock().unwrap();
+        *permits += n;
+        if n == 1 {
+            self.available.notify_one();
+        } else {
+            self.available.notify_all();
+        }
+    }
+}
+
+impl<'a> Drop for SemaphoreGuard<'a> {
+    fn drop(&mut self) {
+        self.sem.release(self.permits);
+    }
+}
+
+impl Drop for OwnedSemaphoreGuard {
+    fn drop(&mut self) {
+        self.sem.release(self.permits);
+    }
+}
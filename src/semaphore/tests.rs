@@ -0,0 +1,84 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::semaphore::Semaphore;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn test_try_acquire_respects_permits() {
+    let sem = Semaphore::new(1);
+    let first = sem.try_acquire();
+    assert!(first.is_some());
+    assert!(sem.try_acquire().is_none());
+    drop(first);
+    assert!(sem.try_acquire().is_some());
+}
+
+#[test]
+fn test_owned_permit_outlives_borrow() {
+    let sem = Arc::new(Semaphore::new(1));
+    let permit = sem.acquire_owned();
+    assert!(sem.try_acquire().is_none());
+    let handle = thread::spawn(move || drop(permit));
+    handle.join().unwrap();
+    assert!(sem.try_acquire().is_some());
+}
+
+#[test]
+fn test_acquire_limits_concurrency() {
+    let sem = Arc::new(Semaphore::new(2));
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_seen = Arc::new(AtomicUsize::new(0));
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let sem = Arc::clone(&sem);
+            let running = Arc::clone(&running);
+            let max_seen = Arc::clone(&max_seen);
+            thread::spawn(move || {
+                let _permit = sem.acquire();
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_seen.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(10));
+                running.fetch_sub(1, Ordering::SeqCst);
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(max_seen.load(Ordering::SeqCst) <= 2);
+}
+
+#[test]
+fn test_try_acquire_many_takes_all_or_nothing() {
+    let sem = Arc::new(Semaphore::new(10));
+    let first = sem.try_acquire_many_owned(6).unwrap();
+    assert!(sem.try_acquire_many_owned(5).is_none());
+    let second = sem.try_acquire_many_owned(4).unwrap();
+    assert!(sem.try_acquire().is_none());
+    drop(first);
+    assert!(sem.try_acquire_many_owned(6).is_some());
+    drop(second);
+    assert!(sem.try_acquire_many_owned(10).is_some());
+    assert!(sem.try_acquire_many_owned(11).is_none());
+}
@@ -0,0 +1,1276 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use super::{
+    check_cuboid_count, encode_blosc, encode_jpeg, override_cuboid_size, reserve_memory,
+    spool_jpeg, BloscFallback, ETagged, FileManager, IfModifiedSince, IfNoneMatch, JsonVoxels,
+    QueriedCutout, RawVoxels, Reader, Shaped, UploadResponse, Writer,
+};
+use bossphorus::access_log::CacheReport;
+use bossphorus::config::{
+    self, AdminToken, BossToken, ConfigErrors, CutoutMemory, DefaultFormat, FrameOrigin,
+    MaxRequestCuboids, ReadKeys, WriteKeys,
+};
+use bossphorus::cuboid_file::npy;
+use bossphorus::cutout::CutoutRequest;
+use bossphorus::data_manager::{
+    BossDBRelayDataManager, ChunkedFileDataManager, Coords, CuboidSources, MissPolicy,
+    UploadSummary, UpstreamStats, Vector3,
+};
+use bossphorus::db::channels::{BossChannelSource, ChannelRegistry, ChannelSource};
+use bossphorus::db::empty::EmptyCuboids;
+use bossphorus::db::pool::ConnectionPool;
+use bossphorus::disk_guard::{DiskGuard, FreeSpace};
+use bossphorus::intern::remote::{BossRemote, ChannelGeometry};
+use bossphorus::prefetch::{PrefetchPolicy, Prefetcher};
+use bossphorus::rate_limit::{Limit, RateLimit, RateLimiter};
+use bossphorus::semaphore::Semaphore;
+use bossphorus::upload::decompress_voxels;
+use ndarray::{Array, Array3};
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Header, RawStr, Status};
+use rocket::local::Client;
+use rocket::response::{status, Stream};
+use rocket::State;
+use rocket_contrib::json::Json;
+use std::io::{Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CUTOUT: &str = "/v1/cutout/col/exp/chan/0/0:4/0:4/0:2";
+
+#[get("/fallback")]
+fn fallback() -> BloscFallback<&'static str> {
+    BloscFallback("voxels")
+}
+
+/// A 2x3x4 (ZYX) array whose voxels are numbered in C-order.
+fn numbered() -> Array3<u8> {
+    Array::from_shape_vec((2, 3, 4), (0..24).collect()).unwrap()
+}
+
+#[get("/raw")]
+fn raw() -> RawVoxels {
+    RawVoxels::new(numbered())
+}
+
+#[get("/json")]
+fn json() -> JsonVoxels {
+    JsonVoxels::new(numbered())
+}
+
+#[get("/zeros")]
+fn zeros() -> ETagged<&'static str> {
+    ETagged::zeros(None, Vector3 { x: 4, y: 3, z: 2 }, false)
+}
+
+#[get("/raw/transposed")]
+fn raw_transposed() -> RawVoxels {
+    RawVoxels::new(numbered().reversed_axes())
+}
+
+#[get("/strided")]
+fn strided() -> Shaped<rocket::response::Stream<std::io::Cursor<Vec<u8>>>> {
+    let request = CutoutRequest::parse(
+        "col",
+        "exp",
+        "chan",
+        0,
+        "0:4",
+        "0:3",
+        "0:2",
+        Coords::default(),
+    )
+    .unwrap()
+    .with_stride(Vector3 { x: 2, y: 2, z: 1 });
+    let data = request.subsample(numbered());
+    Shaped {
+        shape: Vector3::from_zyx_shape(data.shape()),
+        body: encode_blosc(data),
+    }
+}
+
+/// When the `/modified` cutout last changed: Sun, 19 Apr 2020 12:00:00 GMT.
+fn last_modified() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_587_297_600)
+}
+
+#[get("/modified")]
+fn modified(if_modified_since: IfModifiedSince) -> ETagged<&'static str> {
+    if if_modified_since.unmodified(last_modified()) {
+        return ETagged::not_modified(None, Some(last_modified()));
+    }
+    ETagged {
+        etag: None,
+        last_modified: Some(last_modified()),
+        body: Some("voxels"),
+        partial: false,
+        clamped: false,
+        sources: None,
+        zeros: None,
+    }
+}
+
+#[get("/sources")]
+fn sources() -> ETagged<&'static str> {
+    ETagged {
+        etag: None,
+        last_modified: None,
+        body: Some("voxels"),
+        partial: false,
+        clamped: false,
+        sources: Some(CuboidSources {
+            cache: 3,
+            upstream: 1,
+            ..CuboidSources::default()
+        }),
+        zeros: None,
+    }
+}
+
+#[get("/default")]
+fn default_format(format: State<DefaultFormat>) -> QueriedCutout {
+    QueriedCutout::encode(&format.0, numbered(), 75, 1024)
+}
+
+fn client() -> Client {
+    let rocket = rocket::custom(rocket::Config::development()).mount(
+        "/v1",
+        routes![
+            super::download_blosc,
+            super::download_jpeg,
+            super::download_raw,
+            super::download_npy,
+            super::download_json,
+            super::download_default,
+            super::download_not_acceptable,
+            fallback,
+            raw,
+            raw_transposed,
+            json,
+            strided,
+            modified,
+            sources,
+            zeros
+        ],
+    );
+    Client::new(rocket).unwrap()
+}
+
+#[test]
+fn test_unknown_accept_is_not_acceptable() {
+    let client = client();
+    for accept in &["text/csv", "text/html", "image/png"] {
+        let mut response = client
+            .get(CUTOUT)
+            .header(Header::new("Accept", *accept))
+            .dispatch();
+        assert_eq!(Status::NotAcceptable, response.status());
+        let body = response.body_string().unwrap();
+        assert!(body.contains("application/blosc"));
+        assert!(body.contains("image/jpeg"));
+    }
+}
+
+#[test]
+fn test_known_accept_is_not_rejected() {
+    let client = client();
+    for accept in &[
+        "application/blosc",
+        "image/jpeg",
+        "application/x-npy",
+        "application/json",
+        "application/octet-stream",
+    ] {
+        // There's no state to serve it with here, but it reached a download
+        // route rather than the fallback:
+        let response = client
+            .get(CUTOUT)
+            .header(Header::new("Accept", *accept))
+            .dispatch();
+        assert_ne!(Status::NotAcceptable, response.status());
+        assert_ne!(Status::NotFound, response.status());
+    }
+}
+
+#[test]
+fn test_no_accept_gets_default_format() {
+    let client = client();
+    // Without the default route, the format routes would forward these on
+    // to the 406:
+    for accept in &[None, Some("*/*")] {
+        let mut request = client.get(CUTOUT);
+        if let Some(accept) = accept {
+            request.add_header(Header::new("Accept", *accept));
+        }
+        let response = request.dispatch();
+        assert_ne!(Status::NotAcceptable, response.status());
+        assert_ne!(Status::NotFound, response.status());
+    }
+
+    let rocket = rocket::custom(rocket::Config::development())
+        .mount("/v1", routes![default_format])
+        .manage(DefaultFormat("npy".to_string()));
+    let client = Client::new(rocket).unwrap();
+    let mut response = client.get("/v1/default").dispatch();
+    assert_eq!(
+        Some("application/x-npy"),
+        response.headers().get_one("Content-Type")
+    );
+    let body = response.body_bytes().unwrap();
+    let shape = Vector3 { x: 4, y: 3, z: 2 };
+    let offset = npy::data_offset(&body, shape).unwrap();
+    assert_eq!(numbered().into_raw_vec(), body[offset..].to_vec());
+}
+
+#[test]
+fn test_blosc_fallback_is_marked() {
+    let client = client();
+    let mut response = client.get("/v1/fallback").dispatch();
+    assert_eq!(
+        Some("application/blosc"),
+        response.headers().get_one("X-Format-Fallback")
+    );
+    assert_eq!(Some("voxels".to_string()), response.body_string());
+}
+
+/// Rebuild an array from a raw cutout response's headers and body.
+fn rebuild(path: &str) -> Array3<u8> {
+    let client = client();
+    let mut response = client.get(path).dispatch();
+    assert_eq!(Some("uint8"), response.headers().get_one("X-Dtype"));
+    let shape: Vec<usize> = response
+        .headers()
+        .get_one("X-Shape")
+        .unwrap()
+        .split(',')
+        .map(|n| n.parse().unwrap())
+        .collect();
+    let voxels = response.body_bytes().unwrap();
+    Array::from_shape_vec((shape[0], shape[1], shape[2]), voxels).unwrap()
+}
+
+#[test]
+fn test_raw_voxels_round_trip() {
+    assert_eq!(numbered(), rebuild("/v1/raw"));
+    // Arrays that aren't in C-order are sent in C-order:
+    assert_eq!(numbered().reversed_axes(), rebuild("/v1/raw/transposed"));
+}
+
+#[test]
+fn test_json_voxels_round_trip() {
+    let client = client();
+    let mut response = client.get("/v1/json").dispatch();
+    assert_eq!(
+        Some("application/json"),
+        response.headers().get_one("Content-Type")
+    );
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!("uint8", body["dtype"]);
+    assert_eq!("C", body["order"]);
+    let shape: Vec<usize> = body["shape"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|n| n.as_u64().unwrap() as usize)
+        .collect();
+    let compressed = base64::decode(body["data_base64"].as_str().unwrap()).unwrap();
+    let voxels = decompress_voxels(&compressed, shape.iter().product::<usize>() as u64).unwrap();
+    let data = Array::from_shape_vec((shape[0], shape[1], shape[2]), voxels).unwrap();
+    assert_eq!(numbered(), data);
+}
+
+#[test]
+fn test_compact_zeros() {
+    let client = client();
+    let mut response = client.get("/v1/zeros").dispatch();
+    assert_eq!(Status::Ok, response.status());
+    assert_eq!(Some("true"), response.headers().get_one("X-All-Zeros"));
+    assert_eq!(Some("2,3,4"), response.headers().get_one("X-Shape"));
+    assert!(response.body_bytes().map_or(true, |body| body.is_empty()));
+}
+
+#[test]
+fn test_data_source_headers() {
+    let client = client();
+    let response = client.get("/v1/sources").dispatch();
+    assert_eq!(
+        Some("cache=3, upstream=1"),
+        response.headers().get_one("X-Data-Source")
+    );
+    assert_eq!(
+        Some("0.75"),
+        response.headers().get_one("X-Cache-Hit-Ratio")
+    );
+
+    // Not read from any layer:
+    let response = client.get("/v1/modified").dispatch();
+    assert_eq!(None, response.headers().get_one("X-Data-Source"));
+}
+
+#[test]
+fn test_strided_blosc() {
+    let client = client();
+    let mut response = client.get("/v1/strided").dispatch();
+    assert_eq!(Some("2,2,2"), response.headers().get_one("X-Shape"));
+    let voxels = decompress_voxels(&response.body_bytes().unwrap(), 8).unwrap();
+    // Every other voxel along x and y of each z slice:
+    assert_eq!(vec![0, 2, 8, 10, 12, 14, 20, 22], voxels);
+}
+
+#[get("/read")]
+fn read(_reader: Reader) -> &'static str {
+    "read"
+}
+
+#[post("/write")]
+fn write(_writer: Writer) -> &'static str {
+    "written"
+}
+
+/// A client whose reads and writes require the given API keys.
+fn keyed_client(read_keys: &[&str], write_keys: &[&str]) -> Client {
+    let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect();
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(ReadKeys(keys(read_keys)))
+        .manage(WriteKeys(keys(write_keys)))
+        .mount("/v1", routes![read, write])
+        .register(catchers![super::unauthorized]);
+    Client::new(rocket).unwrap()
+}
+
+#[test]
+fn test_open_without_api_keys() {
+    let client = keyed_client(&[], &[]);
+    assert_eq!(Status::Ok, client.get("/v1/read").dispatch().status());
+    assert_eq!(Status::Ok, client.post("/v1/write").dispatch().status());
+}
+
+#[test]
+fn test_api_keys() {
+    let client = keyed_client(&["reader"], &["writer"]);
+    let status = |write: bool, auth: Option<&str>| {
+        let mut request = if write {
+            client.post("/v1/write")
+        } else {
+            client.get("/v1/read")
+        };
+        if let Some(auth) = auth {
+            request.add_header(Header::new("Authorization", auth.to_string()));
+        }
+        request.dispatch().status()
+    };
+    // Missing or wrong keys:
+    for auth in &[
+        None,
+        Some("Bearer nope"),
+        Some("Basic reader"),
+        Some("reader"),
+    ] {
+        assert_eq!(Status::Unauthorized, status(false, *auth), "{:?}", auth);
+        assert_eq!(Status::Unauthorized, status(true, *auth), "{:?}", auth);
+    }
+    assert_eq!(Status::Ok, status(false, Some("Bearer reader")));
+    assert_eq!(Status::Ok, status(false, Some("token reader")));
+    // Write keys can read, but read keys can't write:
+    assert_eq!(Status::Ok, status(false, Some("Bearer writer")));
+    assert_eq!(Status::Ok, status(true, Some("Bearer writer")));
+    assert_eq!(Status::Unauthorized, status(true, Some("Bearer reader")));
+
+    let response = client.get("/v1/read").dispatch();
+    assert_eq!(
+        Some("Bearer"),
+        response.headers().get_one("WWW-Authenticate")
+    );
+}
+
+#[test]
+fn test_read_and_write_keys_are_independent() {
+    // Only uploads need a key:
+    let client = keyed_client(&[], &["writer"]);
+    assert_eq!(Status::Ok, client.get("/v1/read").dispatch().status());
+    assert_eq!(
+        Status::Unauthorized,
+        client.post("/v1/write").dispatch().status()
+    );
+}
+
+#[test]
+fn test_if_modified_since() {
+    let client = client();
+    let mut response = client.get("/v1/modified").dispatch();
+    assert_eq!(Status::Ok, response.status());
+    assert_eq!(
+        Some("Sun, 19 Apr 2020 12:00:00 GMT"),
+        response.headers().get_one("Last-Modified")
+    );
+    assert_eq!(Some("voxels".to_string()), response.body_string());
+
+    // Unmodified since the client's copy:
+    for since in &[
+        "Sun, 19 Apr 2020 12:00:00 GMT",
+        "Mon, 20 Apr 2020 00:00:00 GMT",
+    ] {
+        let response = client
+            .get("/v1/modified")
+            .header(Header::new("If-Modified-Since", *since))
+            .dispatch();
+        assert_eq!(Status::NotModified, response.status(), "{}", since);
+        assert_eq!(
+            Some("Sun, 19 Apr 2020 12:00:00 GMT"),
+            response.headers().get_one("Last-Modified")
+        );
+    }
+
+    // Modified since, or an unreadable date:
+    for since in &["Sun, 19 Apr 2020 11:59:59 GMT", "yesterday"] {
+        let response = client
+            .get("/v1/modified")
+            .header(Header::new("If-Modified-Since", *since))
+            .dispatch();
+        assert_eq!(Status::Ok, response.status(), "{}", since);
+    }
+}
+
+#[get("/budgeted/<bytes>")]
+fn budgeted(
+    bytes: u64,
+    memory: State<CutoutMemory>,
+) -> Result<&'static str, status::Custom<String>> {
+    let _permit = reserve_memory(&memory, bytes)?;
+    Ok("voxels")
+}
+
+#[test]
+fn test_oversubscribed_memory_budget() {
+    let budget = Arc::new(Semaphore::new(1000));
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(CutoutMemory(Some(Arc::clone(&budget))))
+        .mount("/v1", routes![budgeted]);
+    let client = Client::new(rocket).unwrap();
+    let status = |bytes: u64| {
+        client
+            .get(format!("/v1/budgeted/{}", bytes))
+            .dispatch()
+            .status()
+    };
+    assert_eq!(Status::Ok, status(1000));
+
+    // Another request holds most of the budget:
+    let held = budget.try_acquire_many_owned(600).unwrap();
+    assert_eq!(Status::ServiceUnavailable, status(500));
+    assert_eq!(Status::Ok, status(400));
+    drop(held);
+    assert_eq!(Status::Ok, status(500));
+
+    // Could never fit:
+    assert_eq!(Status::BadRequest, status(1001));
+}
+
+#[test]
+fn test_unlimited_memory_budget() {
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(CutoutMemory(None))
+        .mount("/v1", routes![budgeted]);
+    let client = Client::new(rocket).unwrap();
+    let response = client.get(format!("/v1/budgeted/{}", u64::MAX)).dispatch();
+    assert_eq!(Status::Ok, response.status());
+}
+
+#[get("/capped/<count>")]
+fn capped(
+    count: u64,
+    max_cuboids: State<MaxRequestCuboids>,
+) -> Result<&'static str, status::Custom<String>> {
+    check_cuboid_count(&max_cuboids, count)?;
+    Ok("voxels")
+}
+
+#[test]
+fn test_max_request_cuboids() {
+    let status = |max_cuboids: Option<u64>, count: u64| {
+        let rocket = rocket::custom(rocket::Config::development())
+            .manage(MaxRequestCuboids(max_cuboids))
+            .mount("/v1", routes![capped]);
+        let client = Client::new(rocket).unwrap();
+        let response = client.get(format!("/v1/capped/{}", count)).dispatch();
+        response.status()
+    };
+    assert_eq!(Status::Ok, status(Some(100), 100));
+    assert_eq!(Status::PayloadTooLarge, status(Some(100), 101));
+    assert_eq!(Status::Ok, status(None, u64::MAX));
+}
+
+#[test]
+fn test_malformed_setting_is_reported() {
+    let read = |value: &str| {
+        let config = rocket::Config::build(rocket::config::Environment::Development)
+            .extra("max_request_cuboids", value)
+            .finalize()
+            .unwrap();
+        let rocket = rocket::custom(config).attach(AdHoc::on_attach(
+            "Max request cuboids",
+            config::get_max_request_cuboids,
+        ));
+        let max_cuboids = rocket.state::<MaxRequestCuboids>().unwrap().0;
+        let errors = rocket
+            .state::<ConfigErrors>()
+            .map_or(vec![], |e| e.0.lock().unwrap().clone());
+        (max_cuboids, errors)
+    };
+    assert_eq!((Some(100), vec![]), read("100"));
+    let (max_cuboids, errors) = read("lots");
+    assert_eq!(None, max_cuboids);
+    assert_eq!(1, errors.len());
+    assert!(errors[0].contains("max_request_cuboids"));
+}
+
+#[test]
+fn test_malformed_reload_is_rejected() {
+    let read = |max_cuboids: &str| {
+        let config = rocket::Config::build(rocket::config::Environment::Development)
+            .extra("max_cuboids", max_cuboids)
+            .extra("eviction_jitter", 5)
+            .finalize()
+            .unwrap();
+        config::Reloadable::read(&config)
+    };
+    let reloaded = read("50").unwrap();
+    assert_eq!((50, 5), (reloaded.max_cuboids, reloaded.eviction_jitter));
+    assert!(read("fifty").unwrap_err().contains("max_cuboids"));
+}
+
+/// A noisy volume, so that JPEG quality matters.
+fn noise(shape: (usize, usize, usize)) -> Array3<u8> {
+    Array::from_shape_fn(shape, |(z, y, x)| {
+        ((x * 7919 + y * 104_729 + z * 1_299_709) % 251) as u8
+    })
+}
+
+#[get("/jpeg/<quality>")]
+fn jpeg(quality: u8) -> rocket::response::Stream<tempfile::SpooledTempFile> {
+    encode_jpeg(noise((4, 32, 32)), quality, 1024 * 1024)
+}
+
+#[test]
+fn test_jpeg_quality_changes_size() {
+    let rocket = rocket::custom(rocket::Config::development()).mount("/v1", routes![jpeg]);
+    let client = Client::new(rocket).unwrap();
+    let size = |quality: u8| {
+        let mut response = client.get(format!("/v1/jpeg/{}", quality)).dispatch();
+        assert_eq!(Status::Ok, response.status());
+        let body = response.body_bytes().unwrap();
+        // Still a JPEG:
+        assert_eq!(&[0xFF, 0xD8], &body[..2]);
+        body.len()
+    };
+    let (low, default, high) = (size(10), size(75), size(95));
+    assert!(low < default, "{} < {}", low, default);
+    assert!(default < high, "{} < {}", default, high);
+}
+
+#[test]
+fn test_large_jpeg_is_spooled_to_disk() {
+    let spool_size = 64 * 1024;
+    // Small filmstrips stay in memory:
+    assert!(!spool_jpeg(noise((1, 16, 16)), 95, spool_size).is_rolled());
+
+    let mut spool = spool_jpeg(noise((32, 128, 128)), 95, spool_size);
+    // Only the first `spool_size` bytes were ever buffered in memory:
+    assert!(spool.is_rolled());
+    let mut body = vec![];
+    spool.read_to_end(&mut body).unwrap();
+    assert!(body.len() > spool_size);
+    let image = image::load_from_memory(&body).unwrap();
+    assert_eq!((128, 32 * 128), image::GenericImageView::dimensions(&image));
+}
+
+#[test]
+fn test_debug_cuboids() {
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(ReadKeys(vec![]))
+        .manage(WriteKeys(vec![]))
+        .manage(FrameOrigin(Coords::default()))
+        .mount("/v1", routes![super::debug_cuboids]);
+    let client = Client::new(rocket).unwrap();
+    let mut response = client
+        .get("/v1/debug/cuboids/0/256:1024/0:512/8:16?cuboid_size=512:512:16")
+        .dispatch();
+    assert_eq!(Status::Ok, response.status());
+    let regions: serde_json::Value =
+        serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let vector = |x: u64, y: u64, z: u64| serde_json::json!({"x": x, "y": y, "z": z});
+    assert_eq!(
+        serde_json::json!([
+            {"index": vector(0, 0, 0), "start": vector(256, 0, 8), "stop": vector(512, 512, 16)},
+            {"index": vector(1, 0, 0), "start": vector(0, 0, 8), "stop": vector(512, 512, 16)},
+        ]),
+        regions
+    );
+
+    let response = client
+        .get("/v1/debug/cuboids/0/0:512/0:512/0:16?cuboid_size=512:0:16")
+        .dispatch();
+    assert_eq!(Status::BadRequest, response.status());
+
+    // Too many cuboids to list:
+    let response = client
+        .get("/v1/debug/cuboids/0/0:512/0:512/0:16?cuboid_size=1:1:1")
+        .dispatch();
+    assert_eq!(Status::BadRequest, response.status());
+}
+
+/// A route without guards.
+#[get("/open")]
+fn open() -> &'static str {
+    "open"
+}
+
+/// A client whose requests are rate limited, with open reads and writes.
+fn limited_client(global: Option<Limit>, per_ip: Option<Limit>) -> Client {
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(ReadKeys(vec![]))
+        .manage(WriteKeys(vec![]))
+        .attach(RateLimit(RateLimiter::new(global, per_ip)))
+        .mount("/v1", routes![read, write, open])
+        .register(catchers![super::too_many_requests]);
+    Client::new(rocket).unwrap()
+}
+
+#[test]
+fn test_bursts_are_throttled() {
+    let client = limited_client(
+        Some(Limit {
+            rate: 0.1,
+            burst: 2,
+        }),
+        None,
+    );
+    assert_eq!(Status::Ok, client.get("/v1/read").dispatch().status());
+    assert_eq!(Status::Ok, client.post("/v1/write").dispatch().status());
+
+    let response = client.get("/v1/read").dispatch();
+    assert_eq!(Status::TooManyRequests, response.status());
+    // A token every 10 seconds:
+    let wait: u64 = response
+        .headers()
+        .get_one("Retry-After")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(wait > 0 && wait <= 10, "{}", wait);
+    assert_eq!(
+        Status::TooManyRequests,
+        client.post("/v1/write").dispatch().status()
+    );
+    // Whatever the route's guards:
+    let response = client.get("/v1/open").dispatch();
+    assert_eq!(Status::TooManyRequests, response.status());
+    assert!(response.headers().get_one("Retry-After").is_some());
+}
+
+#[test]
+fn test_per_ip_bursts_are_throttled() {
+    let client = limited_client(
+        None,
+        Some(Limit {
+            rate: 0.1,
+            burst: 1,
+        }),
+    );
+    let status = |ip: [u8; 4]| {
+        client
+            .get("/v1/read")
+            .remote(SocketAddr::from((ip, 8000)))
+            .dispatch()
+            .status()
+    };
+    assert_eq!(Status::Ok, status([10, 0, 0, 1]));
+    assert_eq!(Status::TooManyRequests, status([10, 0, 0, 1]));
+    assert_eq!(Status::Ok, status([10, 0, 0, 2]));
+}
+
+/// Where `/upload` writes its cuboids.
+struct UploadRoot(String);
+
+/// Uploads a 4x4x2 cutout at the origin the way `upload` does, with 4x4x2
+/// cuboids.
+#[post("/upload?<raw>", data = "<data>")]
+fn upload_to(
+    data: rocket::Data,
+    raw: bool,
+    root: State<UploadRoot>,
+) -> UploadResponse<status::Created<Json<UploadSummary>>> {
+    let size = Vector3 { x: 4, y: 4, z: 2 };
+    let array = super::decode_upload(data.open(), None, raw, size, 1024, 1024)?;
+    let fm = ChunkedFileDataManager::new(root.0.clone(), size, false);
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let summary = super::write_upload(&fm, "bossdb://col/exp/chan", 0, origin, array)?;
+    Ok(status::Created(
+        "/v1/upload".to_string(),
+        Some(Json(summary)),
+    ))
+}
+
+/// Upload a body to a cache under `root`, returning the status and the
+/// JSON body.
+fn upload_body(root: &str, raw: bool, body: Vec<u8>) -> (Status, serde_json::Value) {
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(UploadRoot(root.to_string()))
+        .mount("/v1", routes![upload_to]);
+    let client = Client::new(rocket).unwrap();
+    let mut response = client
+        .post(format!("/v1/upload?raw={}", raw))
+        .body(body)
+        .dispatch();
+    let json = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    (response.status(), json)
+}
+
+#[test]
+fn test_upload_success_body() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_str().unwrap();
+    let (status, body) = upload_body(root, true, vec![1; 32]);
+    assert_eq!(Status::Created, status);
+    assert_eq!(1, body["cuboids"]);
+    // A header and the voxels:
+    assert_eq!(20 + 32, body["bytes"]);
+
+    let blosc: Vec<u8> = blosc::Context::new().compress(&[2u8; 32][..]).into();
+    let (status, body) = upload_body(root, false, blosc);
+    assert_eq!(Status::Created, status);
+    assert_eq!(1, body["cuboids"]);
+}
+
+#[test]
+fn test_upload_failure_bodies() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_str().unwrap();
+
+    let (status, body) = upload_body(root, false, b"not blosc".to_vec());
+    assert_eq!(Status::BadRequest, status);
+    assert_eq!("decompression", body["error"]);
+    assert_eq!("Failed to decompress payload", body["message"]);
+
+    let (status, body) = upload_body(root, true, vec![1; 31]);
+    assert_eq!(Status::BadRequest, status);
+    assert_eq!("shape", body["error"]);
+    assert_eq!(
+        "Payload has 31 voxels but the shape needs 32",
+        body["message"]
+    );
+
+    // The cache root is a file, so no cuboid can be written under it:
+    let file = dir.path().join("file");
+    std::fs::write(&file, b"").unwrap();
+    let (status, body) = upload_body(file.to_str().unwrap(), true, vec![1; 32]);
+    assert_eq!(Status::InternalServerError, status);
+    assert_eq!("io", body["error"]);
+    assert!(body["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Failed to write cuboid"));
+}
+
+/// Reports a fixed number of free bytes on every disk.
+struct FixedFreeSpace(u64);
+
+impl FreeSpace for FixedFreeSpace {
+    fn free_bytes(&self, _path: &Path) -> std::io::Result<u64> {
+        Ok(self.0)
+    }
+}
+
+/// The body of `/health`, with the disk guard seeing `free` bytes, if any.
+fn health_body(free: Option<u64>) -> serde_json::Value {
+    let guard = free.map(|free| {
+        let guard = DiskGuard::with_source(
+            vec!["/cache".to_string()],
+            100,
+            Box::new(FixedFreeSpace(free)),
+        );
+        guard.check();
+        Arc::new(guard)
+    });
+    let rocket = rocket::custom(rocket::Config::development())
+        .mount("/v1", routes![super::health])
+        .manage(guard);
+    let client = Client::new(rocket).unwrap();
+    let mut response = client.get("/v1/health").dispatch();
+    assert_eq!(Status::Ok, response.status());
+    serde_json::from_str(&response.body_string().unwrap()).unwrap()
+}
+
+#[test]
+fn test_health_reports_low_disk() {
+    let body = health_body(None);
+    assert_eq!("ok", body["status"]);
+    assert!(body["disk"].is_null());
+    assert_eq!("off", body["usage_tracker"]);
+
+    let body = health_body(Some(1000));
+    assert_eq!("ok", body["status"]);
+    assert_eq!(false, body["disk"]["caching_paused"]);
+
+    let body = health_body(Some(10));
+    assert_eq!("degraded", body["status"]);
+    assert_eq!(10, body["disk"]["free_bytes"]);
+    assert_eq!(100, body["disk"]["min_free_bytes"]);
+    assert_eq!(true, body["disk"]["caching_paused"]);
+}
+
+/// Serve every request with the same channel metadata, recording each
+/// request's `Authorization` header.  Returns the address to reach it at.
+fn metadata_server(auth: Arc<Mutex<Vec<String>>>) -> SocketAddr {
+    let body = r#"{"datatype": "uint8", "type": "image", "coord_frame": "frame",
+        "x_start": -512, "x_stop": 2048, "y_start": 512, "y_stop": 2048,
+        "z_start": 16, "z_stop": 64, "num_hierarchy_levels": 5,
+        "x_voxel_size": 4.0, "y_voxel_size": 4.0, "z_voxel_size": 40.0}"#;
+    fixed_server("application/json", body.as_bytes().to_vec(), auth)
+}
+
+/// A Boss DB stand-in that answers every request with the same body,
+/// recording the `Authorization` headers it's sent.
+fn fixed_server(
+    content_type: &'static str,
+    body: Vec<u8>,
+    auth: Arc<Mutex<Vec<String>>>,
+) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buf).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            let request = String::from_utf8_lossy(&request).to_string();
+            if let Some(line) = request
+                .lines()
+                .find(|line| line.to_lowercase().starts_with("authorization:"))
+            {
+                auth.lock().unwrap().push(line[14..].trim().to_string());
+            }
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                content_type,
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    });
+    addr
+}
+
+#[test]
+fn test_rotated_token_is_used_upstream() {
+    let auth = Arc::new(Mutex::new(Vec::new()));
+    let addr = metadata_server(Arc::clone(&auth));
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = dir.path().join("cache.db").to_str().unwrap().to_string();
+    let source = BossChannelSource::new("http".to_string(), addr.to_string(), "old".to_string());
+    let channels = Arc::new(ChannelRegistry::new(
+        &db_url,
+        Box::new(source),
+        Vector3 { x: 4, y: 4, z: 2 },
+    ));
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(AdminToken(Some("admin".to_string())))
+        .manage(BossToken::new("old".to_string()))
+        .manage(Arc::clone(&channels))
+        .mount("/v1", routes![super::rotate_token]);
+    let client = Client::new(rocket).unwrap();
+    let rotate = |auth: &str, body: &str| {
+        client
+            .post("/v1/admin/token")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", auth.to_string()))
+            .body(body)
+            .dispatch()
+            .status()
+    };
+
+    assert!(channels.get("col/exp/chan").is_some());
+    assert_eq!(
+        Status::Forbidden,
+        rotate("Token old", r#"{"token": "new"}"#)
+    );
+    assert_eq!(
+        Status::BadRequest,
+        rotate("Token admin", r#"{"token": ""}"#)
+    );
+    assert_eq!(
+        Status::NoContent,
+        rotate("Token admin", r#"{"token": "new"}"#)
+    );
+    assert_eq!("new", client.rocket().state::<BossToken>().unwrap().get());
+
+    // A channel that isn't registered yet is looked up with the new token:
+    assert!(channels.get("col/exp/other").is_some());
+    let auth = auth.lock().unwrap();
+    assert_eq!(Some(&"token old".to_string()), auth.first());
+    assert_eq!(Some(&"token new".to_string()), auth.last());
+}
+
+#[test]
+fn test_purge_requires_admin_token() {
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(AdminToken(Some("admin".to_string())))
+        .mount("/v1", routes![super::purge_cache]);
+    let client = Client::new(rocket).unwrap();
+    let response = client
+        .delete("/v1/cache?older_than=2020-04-19T00:00:00Z")
+        .dispatch();
+    assert_eq!(Status::Forbidden, response.status());
+}
+
+#[test]
+fn test_coord_frame_is_looked_up_once() {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let addr = metadata_server(Arc::clone(&requests));
+    let remote = BossRemote::new("http".to_string(), addr.to_string(), "token".to_string());
+    let frame = remote
+        .get_coord_frame("bossdb://col/exp/chan".to_string())
+        .unwrap();
+    assert_eq!("frame", frame.name);
+    assert_eq!(
+        Coords {
+            x: -512,
+            y: 512,
+            z: 16
+        },
+        frame.start
+    );
+    assert_eq!(
+        Coords {
+            x: 2048,
+            y: 2048,
+            z: 64
+        },
+        frame.stop
+    );
+
+    let source = BossChannelSource::new("http".to_string(), addr.to_string(), "token".to_string());
+    let channels = ChannelRegistry::new(":memory:", Box::new(source), Vector3 { x: 4, y: 4, z: 2 });
+    requests.lock().unwrap().clear();
+    let at_res_1 = (
+        Coords {
+            x: -256,
+            y: 256,
+            z: 16,
+        },
+        Coords {
+            x: 1024,
+            y: 1024,
+            z: 64,
+        },
+    );
+    assert_eq!(Some(at_res_1), channels.extent("col/exp/chan", 1));
+    assert_eq!(Some(at_res_1), channels.extent("col/exp/chan", 1));
+    // The experiment and its frame, once for the extent and once for the
+    // geometry:
+    assert_eq!(4, requests.lock().unwrap().len());
+}
+
+/// A Boss DB host with one channel, `col/exp/chan`, in an isotropic
+/// hierarchy.
+struct IsotropicSource;
+
+impl ChannelSource for IsotropicSource {
+    fn get_datatype(&self, channel: &str) -> Result<String, String> {
+        match channel {
+            "col/exp/chan" => Ok("uint8".to_string()),
+            _ => Err("no such channel".to_string()),
+        }
+    }
+
+    fn get_extent(&self, _channel: &str) -> Result<(Coords, Coords), String> {
+        Ok((
+            Coords { x: 0, y: 0, z: 0 },
+            Coords {
+                x: 1024,
+                y: 1024,
+                z: 64,
+            },
+        ))
+    }
+
+    fn get_geometry(&self, _channel: &str) -> Result<ChannelGeometry, String> {
+        Ok(ChannelGeometry {
+            voxel_size: [4.0, 4.0, 4.0],
+            voxel_unit: "nanometers".to_string(),
+            num_hierarchy_levels: 2,
+            hierarchy_method: "isotropic".to_string(),
+        })
+    }
+}
+
+/// Serves a channel's info the way `get_channel_info` does, with nothing
+/// cached.
+#[get("/info/<channel>")]
+fn info(channel: String, channels: State<Arc<ChannelRegistry>>) -> Json<super::ChannelInfo> {
+    Json(super::channel_info(
+        &channels,
+        &format!("col/exp/{}", channel),
+        vec![],
+    ))
+}
+
+#[test]
+fn test_channel_info() {
+    let size = Vector3 { x: 4, y: 4, z: 2 };
+    let channels = ChannelRegistry::new(":memory:", Box::new(IsotropicSource), size);
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(Arc::new(channels))
+        .mount("/v1", routes![info]);
+    let client = Client::new(rocket).unwrap();
+    let mut response = client.get("/v1/info/chan").dispatch();
+    assert_eq!(Status::Ok, response.status());
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!("uint8", body["datatype"]);
+    // Both the voxel size and the extent are scaled in z:
+    let coords = |x: i64, y: i64, z: i64| serde_json::json!({"x": x, "y": y, "z": z});
+    assert_eq!(
+        serde_json::json!([8.0, 8.0, 8.0]),
+        body["resolutions"][1]["voxel_size"]
+    );
+    assert_eq!(
+        serde_json::json!([coords(0, 0, 0), coords(512, 512, 32)]),
+        body["resolutions"][1]["extent"]
+    );
+
+    // A datatype that can't be looked up is left out, not guessed:
+    let mut response = client.get("/v1/info/unknown").dispatch();
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert!(body["datatype"].is_null());
+}
+
+#[test]
+fn test_cache_miss_is_counted_upstream() {
+    let blosc: Vec<u8> = blosc::Context::new().compress(&[0u8; 32][..]).into();
+    let addr = fixed_server("application/blosc", blosc, Arc::new(Mutex::new(Vec::new())));
+    let stats = Arc::new(UpstreamStats::new());
+    let mut relay =
+        BossDBRelayDataManager::new("http".to_string(), addr.to_string(), "token".to_string());
+    relay.set_upstream_stats(Arc::clone(&stats));
+    let dir = tempfile::tempdir().unwrap();
+    let fm = ChunkedFileDataManager::new_with_layer(
+        dir.path().to_str().unwrap().to_string(),
+        Vector3 { x: 4, y: 4, z: 2 },
+        Box::new(relay),
+        false,
+    );
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 4, y: 4, z: 2 };
+
+    // A miss goes to the Boss DB:
+    fm.get_cutout("bossdb://col/exp/chan".to_string(), 0, origin, destination);
+    let totals = stats.totals();
+    assert_eq!(1, totals.calls);
+    assert_eq!(0, totals.failures);
+    assert_eq!(32, totals.bytes);
+
+    // A hit doesn't:
+    fm.get_cutout("bossdb://col/exp/chan".to_string(), 0, origin, destination);
+    assert_eq!(1, stats.totals().calls);
+
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(ReadKeys(vec![]))
+        .manage(WriteKeys(vec![]))
+        .manage(stats)
+        .mount("/v1", routes![super::upstream_stats, super::metrics]);
+    let client = Client::new(rocket).unwrap();
+    let mut response = client.get("/v1/stats/upstream").dispatch();
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(1, body["calls"]);
+    assert_eq!(32, body["bytes"]);
+    let metrics = client.get("/v1/metrics").dispatch().body_string().unwrap();
+    assert!(metrics.contains("\nbossphorus_upstream_requests_total 1\n"));
+    assert!(metrics.contains("\nbossphorus_upstream_bytes_total 32\n"));
+}
+
+#[test]
+fn test_cuboid_size_override_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_str().unwrap().to_string();
+    let size = Vector3 { x: 4, y: 4, z: 2 };
+    let request = |xs: &str| {
+        CutoutRequest::parse(
+            "col",
+            "exp",
+            "chan",
+            0,
+            xs,
+            "0:64",
+            "0:16",
+            Coords::default(),
+        )
+        .unwrap()
+    };
+    let status = |mut fm: FileManager, xs: &str, cuboid_size: &str| {
+        override_cuboid_size(
+            &mut fm,
+            &request(xs),
+            Some(RawStr::from_str(cuboid_size)),
+            None,
+        )
+        .map_err(|e| e.0)
+    };
+    let relay = || {
+        let relay = BossDBRelayDataManager::new(
+            "http".to_string(),
+            "localhost:1".to_string(),
+            "token".to_string(),
+        );
+        FileManager(ChunkedFileDataManager::new_with_layer(
+            root.clone(),
+            size,
+            Box::new(relay),
+            false,
+        ))
+    };
+
+    assert_eq!(Ok(()), status(relay(), "0:64", "16:16:16"));
+    // No Boss DB host to read from:
+    let standalone = FileManager(ChunkedFileDataManager::new(root.clone(), size, false));
+    assert_eq!(
+        Err(Status::BadRequest),
+        status(standalone, "0:64", "16:16:16")
+    );
+    // Too small:
+    assert_eq!(Err(Status::BadRequest), status(relay(), "0:64", "8:8:8"));
+    // Too many:
+    assert_eq!(
+        Err(Status::BadRequest),
+        status(relay(), "0:65536", "16:16:16")
+    );
+}
+
+/// A standalone cache that records its empty cuboids.
+struct EmptyCache {
+    root: String,
+    empty: Arc<EmptyCuboids>,
+    on_miss: MissPolicy,
+}
+
+/// Serves a 4x4x4 cutout at the origin the way `download_blosc` does, from
+/// an `EmptyCache` with 4x4x2 cuboids.
+#[get("/empty?<compact_zeros>")]
+fn empty_cutout(
+    compact_zeros: bool,
+    cache: State<EmptyCache>,
+    if_none_match: IfNoneMatch,
+    if_modified_since: IfModifiedSince,
+    cache_report: CacheReport,
+) -> Result<ETagged<Stream<Cursor<Vec<u8>>>>, status::Custom<String>> {
+    let size = Vector3 { x: 4, y: 4, z: 2 };
+    let mut fm = ChunkedFileDataManager::new(cache.root.clone(), size, false);
+    fm.set_empty_cuboids(Arc::clone(&cache.empty));
+    fm.set_on_miss(cache.on_miss);
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let destination = Vector3 { x: 4, y: 4, z: 4 };
+    let request = CutoutRequest::new("col", "exp", "chan", 0, origin, destination).unwrap();
+    super::serve_cutout(
+        &request,
+        FileManager(fm),
+        &if_none_match,
+        &if_modified_since,
+        &Prefetcher::new(PrefetchPolicy::None, 0),
+        &cache_report,
+        &CutoutMemory(None),
+        &MaxRequestCuboids(None),
+        compact_zeros,
+        "blosc",
+        encode_blosc,
+    )
+}
+
+#[test]
+fn test_known_empty_cutout_is_not_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let pool = ConnectionPool::new(dir.path().join("cache.db").to_str().unwrap(), 1).unwrap();
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(EmptyCache {
+            root: dir.path().to_str().unwrap().to_string(),
+            empty: Arc::new(EmptyCuboids::new(Arc::new(pool))),
+            on_miss: MissPolicy::Fill,
+        })
+        .mount("/v1", routes![empty_cutout]);
+    let client = Client::new(rocket).unwrap();
+
+    // The first read finds both cuboids missing, and records them as empty:
+    let response = client.get("/v1/empty?compact_zeros=true").dispatch();
+    assert_eq!(Some("true"), response.headers().get_one("X-All-Zeros"));
+    assert_eq!(Some("4,4,4"), response.headers().get_one("X-Shape"));
+    assert!(response.headers().get_one("X-Data-Source").is_some());
+
+    // So the next isn't read at all:
+    let response = client.get("/v1/empty?compact_zeros=true").dispatch();
+    assert_eq!(Some("true"), response.headers().get_one("X-All-Zeros"));
+    assert_eq!(Some("4,4,4"), response.headers().get_one("X-Shape"));
+    assert_eq!(None, response.headers().get_one("X-Data-Source"));
+
+    // Unless the compact form isn't asked for:
+    let mut response = client.get("/v1/empty?compact_zeros=false").dispatch();
+    assert_eq!(None, response.headers().get_one("X-All-Zeros"));
+    let voxels = decompress_voxels(&response.body_bytes().unwrap(), 64).unwrap();
+    assert_eq!(vec![0; 64], voxels);
+}
+
+#[test]
+fn test_missing_cutout_is_not_found() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_str().unwrap().to_string();
+    let pool = ConnectionPool::new(dir.path().join("cache.db").to_str().unwrap(), 1).unwrap();
+    let empty = Arc::new(EmptyCuboids::new(Arc::new(pool)));
+    let rocket = rocket::custom(rocket::Config::development())
+        .manage(EmptyCache {
+            root: root.clone(),
+            empty: Arc::clone(&empty),
+            on_miss: MissPolicy::NotFound,
+        })
+        .mount("/v1", routes![empty_cutout]);
+    let client = Client::new(rocket).unwrap();
+
+    // Misses aren't recorded as empty, so they're refused every time, in
+    // either form:
+    for compact_zeros in &["false", "true", "true"] {
+        let response = client
+            .get(format!("/v1/empty?compact_zeros={}", compact_zeros))
+            .dispatch();
+        assert_eq!(Status::NotFound, response.status());
+    }
+
+    // Until the cutout is cached:
+    let fm = ChunkedFileDataManager::new(root, Vector3 { x: 4, y: 4, z: 2 }, false);
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    let data = Array::from_elem((4, 4, 4), 1);
+    assert!(fm.upload("bossdb://col/exp/chan".to_string(), 0, origin, data));
+    let response = client.get("/v1/empty?compact_zeros=false").dispatch();
+    assert_eq!(Status::Ok, response.status());
+}
@@ -0,0 +1,428 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+/// Upload module.
+///
+/// Helpers for reading request bodies without trusting the client about
+/// their size (or, once decompressed, about their decompressed size), and
+/// for reading streamed uploads a slab at a time (see `SlabReader`).
+use crate::data_manager::Vector3;
+
+use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+use ndarray::{Array, Array3};
+use std::fmt;
+use std::io::{ErrorKind, Read};
+
+#[cfg(test)]
+pub mod tests;
+
+/// Why an upload body couldn't be read.
+#[derive(Debug, PartialEq)]
+pub enum BodyError {
+    /// The body is larger than the limit.
+    TooLarge(u64),
+    /// The body couldn't be read off the wire.
+    Io(String),
+}
+
+/// Why an upload's voxels couldn't be decoded.
+#[derive(Debug, PartialEq)]
+pub enum VoxelError {
+    /// The payload isn't valid blosc.
+    Corrupt,
+    /// The payload holds a different number of voxels than the shape.
+    WrongLength { got: u64, expected: u64 },
+}
+
+impl fmt::Display for VoxelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoxelError::Corrupt => write!(f, "Failed to decompress payload"),
+            VoxelError::WrongLength { got, expected } => write!(
+                f,
+                "Payload has {} voxels but the shape needs {}",
+                got, expected
+            ),
+        }
+    }
+}
+
+/// Read a whole body, giving up as soon as it's known to exceed `limit`
+/// bytes.  At most `limit + 1` bytes are ever buffered, no matter how much
+/// the client sends.
+///
+/// # Arguments
+///
+/// * `reader` - The body stream
+/// * `limit` - Max number of bytes allowed
+///
+pub fn read_limited<R: Read>(reader: R, limit: u64) -> Result<Vec<u8>, BodyError> {
+    let mut buf = Vec::new();
+    reader
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut buf)
+        .map_err(|e| BodyError::Io(e.to_string()))?;
+    if buf.len() as u64 > limit {
+        return Err(BodyError::TooLarge(limit));
+    }
+    Ok(buf)
+}
+
+/// Check a declared cutout shape before anything is allocated for it.
+/// Returns the number of voxels in the shape.
+///
+/// # Arguments
+///
+/// * `shape` - The declared XYZ shape
+/// * `max_voxels` - Max number of voxels allowed in one cutout
+///
+pub fn check_shape(shape: Vector3, max_voxels: u64) -> Result<u64, String> {
+    if shape.x == 0 || shape.y == 0 || shape.z == 0 {
+        return Err(format!("Shape {} must be positive", shape));
+    }
+    let voxels = shape
+        .x
+        .checked_mul(shape.y)
+        .and_then(|v| v.checked_mul(shape.z))
+        .filter(|v| *v <= max_voxels)
+        .ok_or_else(|| format!("Shape {} exceeds the {} voxel limit", shape, max_voxels))?;
+    Ok(voxels)
+}
+
+/// Decompress a blosc payload, but only if its header says it holds
+/// exactly `voxels` bytes.  The header is checked first, so a payload that
+/// claims to be huge is rejected without allocating for it.
+///
+/// # Arguments
+///
+/// * `payload` - The blosc-compressed bytes, straight off the wire
+/// * `voxels` - The number of voxels in the declared shape
+///
+pub fn decompress_voxels(payload: &[u8], voxels: u64) -> Result<Vec<u8>, VoxelError> {
+    let nbytes = blosc::validate(payload).map_err(|_| VoxelError::Corrupt)?;
+    if nbytes as u64 != voxels {
+        return Err(VoxelError::WrongLength {
+            got: nbytes as u64,
+            expected: voxels,
+        });
+    }
+    // This is unsafe because the bytes are coming directly over the wire,
+    // but `validate` has checked that they're well-formed.
+    unsafe { blosc::decompress_bytes(payload) }.map_err(|_| VoxelError::Corrupt)
+}
+
+/// Check that an uncompressed payload holds exactly `voxels` voxels.
+///
+/// # Arguments
+///
+/// * `payload` - The raw `uint8` voxels in ZYX C-order
+/// * `voxels` - The number of voxels in the declared shape
+///
+pub fn raw_voxels(payload: Vec<u8>, voxels: u64) -> Result<Vec<u8>, VoxelError> {
+    if payload.len() as u64 != voxels {
+        return Err(VoxelError::WrongLength {
+            got: payload.len() as u64,
+            expected: voxels,
+        });
+    }
+    Ok(payload)
+}
+
+// Flags of the gzip member header (RFC 1952):
+const GZIP_FHCRC: u8 = 0x02;
+const GZIP_FEXTRA: u8 = 0x04;
+const GZIP_FNAME: u8 = 0x08;
+const GZIP_FCOMMENT: u8 = 0x10;
+
+/// Remove a gzip layer (e.g. from `Content-Encoding: gzip`), giving up as
+/// soon as the decompressed body is known to exceed `limit` bytes.  Only a
+/// single gzip member is supported, which is what browsers produce.
+///
+/// # Arguments
+///
+/// * `body` - The gzip-compressed body
+/// * `limit` - Max number of decompressed bytes allowed
+///
+pub fn gunzip(body: &[u8], limit: u64) -> Result<Vec<u8>, String> {
+    let invalid = || "Invalid gzip body".to_string();
+    if body.len() < 18 || body[0] != 0x1f || body[1] != 0x8b || body[2] != 8 {
+        return Err(invalid());
+    }
+    let flags = body[3];
+
+    // Skip the rest of the header:
+    let mut pos = 10;
+    if flags & GZIP_FEXTRA != 0 {
+        let len = *body.get(pos).ok_or_else(invalid)? as usize
+            | (*body.get(pos + 1).ok_or_else(invalid)? as usize) << 8;
+        pos += 2 + len;
+    }
+    for flag in &[GZIP_FNAME, GZIP_FCOMMENT] {
+        if flags & flag != 0 {
+            let end = body
+                .get(pos..)
+                .and_then(|rest| rest.iter().position(|b| *b == 0))
+                .ok_or_else(invalid)?;
+            pos += end + 1;
+        }
+    }
+    if flags & GZIP_FHCRC != 0 {
+        pos += 2;
+    }
+    if pos + 8 > body.len() {
+        return Err(invalid());
+    }
+
+    let (deflated, trailer) = body[pos..].split_at(body.len() - pos - 8);
+    let inflated =
+        inflate_limited(deflated, limit.min(usize::MAX as u64) as usize).map_err(|status| {
+            match status {
+                TINFLStatus::HasMoreOutput => {
+                    format!("Decompressed upload exceeds the {} byte limit", limit)
+                }
+                _ => invalid(),
+            }
+        })?;
+
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc32fast::hash(&inflated) != crc || inflated.len() as u32 != size {
+        return Err("Corrupt gzip body".to_string());
+    }
+    Ok(inflated)
+}
+
+/// Inflate a raw deflate stream into at most `limit` bytes, growing the
+/// output as needed rather than allocating the limit up front.
+fn inflate_limited(deflated: &[u8], limit: usize) -> Result<Vec<u8>, TINFLStatus> {
+    let flags = inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+    let mut decomp = Box::<DecompressorOxide>::default();
+    let mut out = vec![0; deflated.len().saturating_mul(2).max(64).min(limit)];
+    let mut in_pos = 0;
+    let mut out_pos = 0;
+    loop {
+        let (status, in_consumed, out_consumed) =
+            decompress(&mut decomp, &deflated[in_pos..], &mut out, out_pos, flags);
+        in_pos += in_consumed;
+        out_pos += out_consumed;
+        match status {
+            TINFLStatus::Done => {
+                out.truncate(out_pos);
+                return Ok(out);
+            }
+            TINFLStatus::HasMoreOutput if out.len() < limit => {
+                let len = out.len().saturating_mul(2).min(limit);
+                out.resize(len, 0);
+            }
+            _ => return Err(status),
+        }
+    }
+}
+
+/// A slab of a streamed upload, ready for `put_data`.
+pub struct Slab {
+    /// How far into the upload the slab starts, in z.
+    pub z: u64,
+    pub data: Array3<u8>,
+}
+
+/// Reads a streamed upload one slab at a time, so that memory is bounded by
+/// the slab size however large the upload is.
+///
+/// The body is a sequence of chunks, each a little-endian `u64` byte
+/// length followed by blosc-compressed `uint8` voxels: a whole number of
+/// the upload's z planes, no more than a cuboid deep, each in YX C-order,
+/// in order of z.  Planes are yielded in slabs that end on the cuboid grid
+/// in z, so that each cuboid is written at most once, and at most about two
+/// slabs are held at once.
+///
+/// Once anything is wrong with the stream, an error is yielded and
+/// iteration stops.  The slabs already yielded are valid.
+pub struct SlabReader<R: Read> {
+    reader: R,
+    shape: Vector3,
+    /// Global z of the upload's first plane.
+    origin_z: u64,
+    cuboid_z: u64,
+    /// Planes yielded so far.
+    done_planes: u64,
+    /// Decompressed planes that haven't been yielded yet.
+    pending: Vec<u8>,
+    max_chunk: u64,
+    max_voxels: u64,
+    done: bool,
+}
+
+impl<R: Read> SlabReader<R> {
+    /// Constructor.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The body stream
+    /// * `shape` - The XYZ shape of the whole upload
+    /// * `origin_z` - Where the upload starts in z (global coords)
+    /// * `cuboid_z` - Depth of the channel's cuboids
+    ///
+    pub fn new(reader: R, shape: Vector3, origin_z: u64, cuboid_z: u64) -> SlabReader<R> {
+        SlabReader {
+            reader,
+            shape,
+            origin_z,
+            cuboid_z: cuboid_z.max(1),
+            done_planes: 0,
+            pending: Vec::new(),
+            max_chunk: u64::MAX,
+            max_voxels: u64::MAX,
+            done: false,
+        }
+    }
+
+    /// Reject chunks whose payload is larger than `max` bytes.  Since the
+    /// payload length comes first, this happens before any of it is read.
+    pub fn set_max_chunk(&mut self, max: u64) {
+        self.max_chunk = max;
+    }
+
+    /// Reject chunks of more than `max` voxels.  Since a chunk's voxel count
+    /// is in its header, this happens before it's decompressed.
+    pub fn set_max_voxels(&mut self, max: u64) {
+        self.max_voxels = max;
+    }
+
+    fn plane_len(&self) -> u64 {
+        self.shape.x * self.shape.y
+    }
+
+    /// Read the next chunk onto the pending planes.
+    fn read_chunk(&mut self) -> Result<(), String> {
+        let mut len_buf = [0u8; 8];
+        match self.reader.read_exact(&mut len_buf) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(format!(
+                    "Stream ended after {} of {} planes",
+                    self.done_planes + self.pending.len() as u64 / self.plane_len(),
+                    self.shape.z
+                ))
+            }
+            Err(e) => return Err(format!("Failed to read chunk length: {}", e)),
+        }
+        let len = u64::from_le_bytes(len_buf);
+        if len > self.max_chunk {
+            return Err(format!("Chunk too large: {} bytes", len));
+        }
+        let mut payload = Vec::new();
+        (&mut self.reader)
+            .take(len)
+            .read_to_end(&mut payload)
+            .map_err(|e| format!("Failed to read chunk: {}", e))?;
+        if payload.len() as u64 != len {
+            return Err("Truncated chunk".to_string());
+        }
+
+        // Check the chunk's size before decompressing it:
+        let nbytes = blosc::validate(&payload).map_err(|_| VoxelError::Corrupt.to_string())? as u64;
+        let planes = nbytes / self.plane_len();
+        let max_voxels = self
+            .cuboid_z
+            .saturating_mul(self.plane_len())
+            .min(self.max_voxels);
+        if nbytes > max_voxels {
+            return Err(format!(
+                "Chunk has {} voxels, more than the {} allowed (a cuboid deep at most)",
+                nbytes, max_voxels
+            ));
+        }
+        if nbytes == 0 || nbytes % self.plane_len() != 0 {
+            return Err(format!(
+                "Chunk has {} voxels, which isn't a whole number of {}x{} planes",
+                nbytes, self.shape.x, self.shape.y
+            ));
+        }
+        let held = self.done_planes + self.pending.len() as u64 / self.plane_len();
+        if held + planes > self.shape.z {
+            return Err(format!(
+                "Stream has more than the shape's {} planes",
+                self.shape.z
+            ));
+        }
+        let voxels = decompress_voxels(&payload, nbytes).map_err(|e| e.to_string())?;
+        self.pending.extend_from_slice(&voxels);
+        Ok(())
+    }
+
+    /// Read until the next slab is pending, and split it off.
+    fn read_slab(&mut self) -> Result<Slab, String> {
+        let z = self.origin_z + self.done_planes;
+        let planes = (self.cuboid_z - z % self.cuboid_z).min(self.shape.z - self.done_planes);
+        let len = (planes * self.plane_len()) as usize;
+        while self.pending.len() < len {
+            self.read_chunk()?;
+        }
+        let rest = self.pending.split_off(len);
+        let voxels = std::mem::replace(&mut self.pending, rest);
+        let shape = (
+            planes as usize,
+            self.shape.y as usize,
+            self.shape.x as usize,
+        );
+        let slab = Slab {
+            z: self.done_planes,
+            data: Array::from_shape_vec(shape, voxels).unwrap(),
+        };
+        self.done_planes += planes;
+        Ok(slab)
+    }
+
+    /// Check that nothing follows the last plane.
+    fn check_end(&mut self) -> Result<(), String> {
+        let mut byte = [0u8; 1];
+        match self.reader.read(&mut byte) {
+            Ok(0) => Ok(()),
+            Ok(_) => Err(format!(
+                "Stream has more than the shape's {} planes",
+                self.shape.z
+            )),
+            Err(e) => Err(format!("Failed to read chunk length: {}", e)),
+        }
+    }
+}
+
+impl<R: Read> Iterator for SlabReader<R> {
+    type Item = Result<Slab, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = if self.done_planes < self.shape.z {
+            self.read_slab()
+        } else {
+            self.done = true;
+            match self.check_end() {
+                Ok(_) => return None,
+                Err(e) => Err(e),
+            }
+        };
+        if result.is_err() {
+            self.done = true;
+        }
+        Some(result)
+    }
+}
@@ -0,0 +1,279 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::data_manager::Vector3;
+use crate::upload::{
+    check_shape, decompress_voxels, gunzip, raw_voxels, read_limited, BodyError, SlabReader,
+    VoxelError,
+};
+use miniz_oxide::deflate::compress_to_vec;
+use std::io::{self, Read};
+
+/// An endless body, like one a malicious client might send.
+struct Endless;
+
+impl Read for Endless {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for b in buf.iter_mut() {
+            *b = 1;
+        }
+        Ok(buf.len())
+    }
+}
+
+#[test]
+fn test_read_limited_accepts_body_at_limit() {
+    let body = vec![7u8; 16];
+    assert_eq!(Ok(body.clone()), read_limited(&body[..], 16));
+}
+
+#[test]
+fn test_read_limited_rejects_oversized_body() {
+    let body = vec![7u8; 17];
+    assert_eq!(Err(BodyError::TooLarge(16)), read_limited(&body[..], 16));
+    assert_eq!(Err(BodyError::TooLarge(1024)), read_limited(Endless, 1024));
+}
+
+#[test]
+fn test_check_shape() {
+    assert_eq!(Ok(24), check_shape(Vector3 { x: 4, y: 3, z: 2 }, 24));
+    assert!(check_shape(Vector3 { x: 4, y: 3, z: 2 }, 23).is_err());
+    assert!(check_shape(Vector3 { x: 4, y: 0, z: 2 }, 24).is_err());
+    let huge = Vector3 {
+        x: u64::MAX,
+        y: u64::MAX,
+        z: 2,
+    };
+    assert!(check_shape(huge, u64::MAX).is_err());
+}
+
+#[test]
+fn test_decompress_voxels_checks_length() {
+    let payload: Vec<u8> = blosc::Context::new().compress(&[1u8; 24][..]).into();
+    assert_eq!(Ok(vec![1; 24]), decompress_voxels(&payload, 24));
+    assert_eq!(
+        Err(VoxelError::WrongLength {
+            got: 24,
+            expected: 48
+        }),
+        decompress_voxels(&payload, 48)
+    );
+    assert!(decompress_voxels(&payload, 48)
+        .unwrap_err()
+        .to_string()
+        .contains("needs 48"));
+    assert_eq!(
+        Err(VoxelError::Corrupt),
+        decompress_voxels(b"not blosc", 24)
+    );
+}
+
+/// Wrap bytes in a minimal gzip member, with a file name like browsers
+/// sometimes add.
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut body = vec![0x1f, 0x8b, 8, 0x08, 0, 0, 0, 0, 0, 255];
+    body.extend_from_slice(b"cutout.bin\0");
+    body.extend_from_slice(&compress_to_vec(data, 6));
+    body.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body
+}
+
+#[test]
+fn test_gunzip_round_trip() {
+    let data: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+    assert_eq!(Ok(data.clone()), gunzip(&gzip(&data), 4096));
+}
+
+#[test]
+fn test_gunzip_rejects_bad_bodies() {
+    let data = vec![3u8; 4096];
+    assert!(gunzip(&data, 4096).is_err());
+
+    let mut corrupt = gzip(&data);
+    let len = corrupt.len();
+    corrupt[len - 8] ^= 1;
+    assert!(gunzip(&corrupt, 4096).is_err());
+
+    // Decompresses past the limit:
+    assert!(gunzip(&gzip(&data), 4095).is_err());
+}
+
+#[test]
+fn test_raw_voxels() {
+    assert_eq!(Ok(vec![1; 24]), raw_voxels(vec![1; 24], 24));
+    assert_eq!(
+        Err(VoxelError::WrongLength {
+            got: 23,
+            expected: 24
+        }),
+        raw_voxels(vec![1; 23], 24)
+    );
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    blosc::Context::new().compress(data).into()
+}
+
+/// Append a chunk to a streamed upload.
+fn push_chunk(stream: &mut Vec<u8>, voxels: &[u8]) {
+    let payload = compress(voxels);
+    stream.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    stream.extend_from_slice(&payload);
+}
+
+/// A streamed upload whose chunks are only made as they're read, with
+/// every voxel of plane `z` set to `z % 251`.
+struct PlaneStream {
+    plane_len: usize,
+    planes: u64,
+    chunk_planes: u64,
+    next_plane: u64,
+    buf: io::Cursor<Vec<u8>>,
+}
+
+impl Read for PlaneStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.buf.read(buf)?;
+        if read > 0 || self.next_plane == self.planes {
+            return Ok(read);
+        }
+        let mut chunk = Vec::new();
+        let end = (self.next_plane + self.chunk_planes).min(self.planes);
+        for z in self.next_plane..end {
+            chunk.extend(std::iter::repeat((z % 251) as u8).take(self.plane_len));
+        }
+        self.next_plane = end;
+        let mut stream = Vec::new();
+        push_chunk(&mut stream, &chunk);
+        self.buf = io::Cursor::new(stream);
+        self.buf.read(buf)
+    }
+}
+
+#[test]
+fn test_slab_reader_bounds_memory() {
+    // 64 MiB of voxels, in chunks of 4 planes, starting off the cuboid grid:
+    let shape = Vector3 {
+        x: 256,
+        y: 256,
+        z: 1024,
+    };
+    let plane_len = (shape.x * shape.y) as usize;
+    let (origin_z, cuboid_z, chunk_planes) = (5, 16, 4);
+    let stream = PlaneStream {
+        plane_len,
+        planes: shape.z,
+        chunk_planes,
+        next_plane: 0,
+        buf: io::Cursor::new(Vec::new()),
+    };
+    let mut reader = SlabReader::new(stream, shape, origin_z, cuboid_z);
+
+    let mut planes = 0;
+    while let Some(slab) = reader.next() {
+        let slab = slab.unwrap();
+        assert_eq!(planes, slab.z);
+        let depth = slab.data.dim().0 as u64;
+        // Slabs end on the cuboid grid, or at the end of the upload:
+        let end = origin_z + slab.z + depth;
+        assert!(end % cuboid_z == 0 || slab.z + depth == shape.z);
+        assert!(depth <= cuboid_z);
+        for (i, plane) in slab.data.outer_iter().enumerate() {
+            let z = (slab.z + i as u64) % 251;
+            assert!(plane.iter().all(|v| *v as u64 == z));
+        }
+        // Only what's left of the last chunk is held back:
+        assert!(reader.pending.len() < (chunk_planes as usize) * plane_len);
+        assert!(reader.reader.next_plane <= slab.z + depth + chunk_planes);
+        planes += depth;
+    }
+    assert_eq!(shape.z, planes);
+}
+
+#[test]
+fn test_slab_reader_rejects_bad_streams() {
+    let shape = Vector3 { x: 4, y: 4, z: 4 };
+    let read_all = |stream: Vec<u8>, max_chunk: u64| {
+        let mut reader = SlabReader::new(&stream[..], shape, 0, 2);
+        reader.set_max_chunk(max_chunk);
+        reader.collect::<Vec<_>>()
+    };
+    let plane = [1u8; 16];
+
+    let mut whole = Vec::new();
+    push_chunk(&mut whole, &[1u8; 32]);
+    push_chunk(&mut whole, &[1u8; 32]);
+    let slabs = read_all(whole.clone(), u64::MAX);
+    assert_eq!(2, slabs.len());
+    assert!(slabs.iter().all(|slab| slab.is_ok()));
+
+    // Ends early, after the first slab:
+    let mut short = Vec::new();
+    push_chunk(&mut short, &[1u8; 32]);
+    push_chunk(&mut short, &plane);
+    let slabs = read_all(short, u64::MAX);
+    assert_eq!(2, slabs.len());
+    assert_eq!(
+        "Stream ended after 3 of 4 planes",
+        slabs[1].as_ref().err().unwrap()
+    );
+
+    // Too many planes, in a chunk or after the last one:
+    let mut long = whole.clone();
+    push_chunk(&mut long, &plane);
+    let slabs = read_all(long, u64::MAX);
+    assert_eq!(3, slabs.len());
+    assert!(slabs[2].is_err());
+    let mut long = Vec::new();
+    push_chunk(&mut long, &[1u8; 32]);
+    push_chunk(&mut long, &plane);
+    push_chunk(&mut long, &[1u8; 32]);
+    let slabs = read_all(long, u64::MAX);
+    assert_eq!(2, slabs.len());
+    assert_eq!(
+        "Stream has more than the shape's 4 planes",
+        slabs[1].as_ref().err().unwrap()
+    );
+
+    // Deeper than a cuboid, or over the voxel limit:
+    let mut deep = Vec::new();
+    push_chunk(&mut deep, &[1u8; 48]);
+    assert!(read_all(deep, u64::MAX)[0]
+        .as_ref()
+        .err()
+        .unwrap()
+        .starts_with("Chunk has 48 voxels"));
+    let mut reader = SlabReader::new(&whole[..], shape, 0, 2);
+    reader.set_max_voxels(16);
+    assert!(reader.next().unwrap().is_err());
+
+    // Part of a plane:
+    let mut partial = Vec::new();
+    push_chunk(&mut partial, &[1u8; 20]);
+    assert!(read_all(partial, u64::MAX)[0].is_err());
+
+    // A chunk over the limit:
+    let slabs = read_all(whole, 8);
+    assert_eq!(1, slabs.len());
+    assert!(slabs[0]
+        .as_ref()
+        .err()
+        .unwrap()
+        .starts_with("Chunk too large"));
+}
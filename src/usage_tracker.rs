@@ -16,22 +16,144 @@ limitations under the License.
 
 */
 
-use super::config::{CONSOLE_TRACKER, DB_TRACKER, DB_URL, NONE_TRACKER};
+use super::config::{CONSOLE_TRACKER, DB_TRACKER, DB_URL, EVICT_EMPTY_FIRST_DEFAULT, NONE_TRACKER};
 /// Usage Tracker module.
 ///
 /// Tracks usage of the cached cuboids stored locally on disk.
 ///
 /// A single thread receives keys from the Rocket worker threads as cuboids are
 /// accessed.
-use super::db::{MaxCountLruStrategy, SimpleCacheManager, SqliteCacheInterface};
+use super::db::channel_keys::ChannelKeys;
+use super::db::pool::ConnectionPool;
+use super::db::{
+    CacheStats, CacheStrategy, MaxCountDecayStrategy, MaxCountLruStrategy, PinnedChannels,
+    RemovalRetry, SimpleCacheManager, SqliteCacheInterface,
+};
+use super::write_buffer::WriteBuffer;
+use serde::Serialize;
 use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
 use std::rc::Rc;
 use std::sync;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
-// ToDo: make this configurable.
-const DEFAULT_MAX_CUBOIDS: u32 = 1000;
+#[cfg(test)]
+pub mod tests;
+
+/// How long the tracker thread waits for a key before checking for new
+/// settings.
+const CONTROL_POLL: Duration = Duration::from_secs(1);
+
+/// How the usage tracker picks cuboids to evict.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase", tag = "strategy")]
+pub enum EvictionStrategy {
+    /// Least recently used first.
+    Lru,
+    /// Lowest request count, decayed by time since last access, first.
+    Decay {
+        /// Seconds for a cuboid's score to halve while it goes unused.
+        half_life: u32,
+    },
+}
+
+/// Tunables for evicting cuboids, which can be changed while the tracker
+/// runs (see `reconfigure()`).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EvictionSettings {
+    /// Max number of cuboids kept in the cache.
+    pub max_cuboids: u32,
+    /// Seconds a cuboid is protected from eviction after it's touched.
+    pub min_residency: u32,
+    #[serde(flatten)]
+    pub strategy: EvictionStrategy,
+    /// Max seconds to put off cleaning once the cache is over its limit,
+    /// picked at random each time.
+    pub jitter: u32,
+}
+
+impl Default for EvictionSettings {
+    fn default() -> EvictionSettings {
+        EvictionSettings {
+            max_cuboids: 1000,
+            min_residency: 0,
+            strategy: EvictionStrategy::Lru,
+            jitter: 0,
+        }
+    }
+}
+
+/// Build the cache management strategy for some eviction settings.
+///
+/// # Arguments:
+///
+/// * `settings` - Eviction tunables
+/// * `db` - Where the strategy finds cuboids to evict
+pub fn build_strategy(
+    settings: &EvictionSettings,
+    db: Rc<RefCell<SqliteCacheInterface>>,
+) -> Box<dyn CacheStrategy> {
+    match settings.strategy {
+        EvictionStrategy::Lru => {
+            let mut strategy = MaxCountLruStrategy::new(settings.max_cuboids, db);
+            strategy.set_min_residency(settings.min_residency);
+            Box::new(strategy)
+        }
+        EvictionStrategy::Decay { half_life } => {
+            let mut strategy = MaxCountDecayStrategy::new(settings.max_cuboids, half_life, db);
+            strategy.set_min_residency(settings.min_residency);
+            Box::new(strategy)
+        }
+    }
+}
+
+/// Tunables for the usage tracker's cache management.
+pub struct UsageTrackerConfig {
+    pub eviction: EvictionSettings,
+    /// Connections to share with the rest of the server.  The tracker opens
+    /// its own if not given.
+    pub db_pool: Option<Arc<ConnectionPool>>,
+    /// Channels that are never evicted.
+    pub pinned: PinnedChannels,
+    /// Hashed channel directories, to find pinned channels' cuboids by, if
+    /// cache paths are hashed.
+    pub channel_keys: Option<Arc<ChannelKeys>>,
+    /// Partly written cuboids, dropped when their cuboids are evicted.
+    pub write_buffer: Option<Arc<WriteBuffer>>,
+    /// Cache roots besides CUBOID_ROOT_PATH, e.g. for particular
+    /// resolutions.
+    pub extra_roots: Vec<String>,
+    /// Retries of cuboids that fail to be removed.
+    pub removal_retry: RemovalRetry,
+    /// Evict cuboids whose files are empty before any others.
+    pub evict_empty_first: bool,
+    /// How often to log the size of the cache, if at all.
+    pub size_report: Option<Duration>,
+    /// Times to restart the tracker after it fails before giving up and
+    /// tracking nothing.
+    pub max_restarts: u32,
+}
+
+impl Default for UsageTrackerConfig {
+    fn default() -> UsageTrackerConfig {
+        UsageTrackerConfig {
+            eviction: EvictionSettings::default(),
+            db_pool: None,
+            pinned: PinnedChannels::default(),
+            channel_keys: None,
+            write_buffer: None,
+            extra_roots: vec![],
+            removal_retry: RemovalRetry::default(),
+            evict_empty_first: EVICT_EMPTY_FIRST_DEFAULT,
+            size_report: None,
+            max_restarts: 3,
+        }
+    }
+}
 
 pub enum UsageTrackerType {
     None,
@@ -54,16 +176,35 @@ pub fn get_tracker_type(name: &str) -> UsageTrackerType {
     }
 }
 
-fn usage_tracker_factory(kind: UsageTrackerType) -> Box<dyn UsageTracker> {
+fn usage_tracker_factory(
+    kind: &UsageTrackerType,
+    settings: &UsageTrackerConfig,
+) -> Box<dyn UsageTracker> {
     match kind {
         UsageTrackerType::None => Box::new(NoneTracker {}),
         UsageTrackerType::Console => Box::new(ConsoleUsageTracker {}),
         UsageTrackerType::Sqlite => {
-            let db_interface = SqliteCacheInterface::new(DB_URL);
+            let mut db_interface = match &settings.db_pool {
+                Some(pool) => SqliteCacheInterface::with_pool(Arc::clone(pool)),
+                None => SqliteCacheInterface::new(DB_URL),
+            };
+            db_interface.set_pinned(settings.pinned.clone());
+            if let Some(keys) = &settings.channel_keys {
+                db_interface.set_channel_keys(Arc::clone(keys));
+            }
+            if let Some(buffer) = &settings.write_buffer {
+                db_interface.set_write_buffer(Arc::clone(buffer));
+            }
+            db_interface.set_removal_retry(settings.removal_retry);
+            for root in &settings.extra_roots {
+                db_interface.add_cache_root(root);
+            }
             let rc_db_iface = Rc::new(RefCell::new(db_interface));
-            let clone = Rc::clone(&rc_db_iface);
-            let strategy = MaxCountLruStrategy::new(DEFAULT_MAX_CUBOIDS, rc_db_iface);
-            Box::new(SimpleCacheManager::new(clone, strategy))
+            let strategy = build_strategy(&settings.eviction, Rc::clone(&rc_db_iface));
+            let mut manager = SimpleCacheManager::with_strategy(rc_db_iface, strategy);
+            manager.set_jitter(Duration::from_secs(settings.eviction.jitter as u64));
+            manager.set_evict_empty_first(settings.evict_empty_first);
+            Box::new(manager)
         }
     }
 }
@@ -84,36 +225,283 @@ pub fn get_sender() -> &'static sync::Mutex<mpsc::Sender<String>> {
     }
 }
 
+/// Set once the usage tracker has stopped tracking, see `is_down()`.
+static TRACKER_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Whether the usage tracker has gone down, either by failing more times
+/// than it may be restarted or by its thread exiting.  Requests are still
+/// served, but cuboids are no longer tracked or evicted.
+pub fn is_down() -> bool {
+    TRACKER_DOWN.load(Ordering::SeqCst)
+}
+
+/// Note that the tracker is down, logging it the first time.
+fn mark_down(down: &AtomicBool) {
+    if !down.swap(true, Ordering::SeqCst) {
+        println!(
+            "ERROR: the usage tracker is down; cuboids are no longer tracked or \
+             evicted, so the cache will grow until the server is restarted"
+        );
+    }
+}
+
+/// Send a key to the usage tracker started by `run()`.  If the tracker's
+/// thread is gone, the key is dropped and the tracker marked down rather
+/// than failing the request.
+///
+/// # Arguments:
+///
+/// * `key` - Key of the cuboid that was used
+pub fn record(key: String) {
+    send_key(get_sender(), key, &TRACKER_DOWN);
+}
+
+fn send_key(sender: &sync::Mutex<mpsc::Sender<String>>, key: String, down: &AtomicBool) {
+    let sent = match sender.lock() {
+        Ok(tx) => tx.send(key).is_ok(),
+        Err(_) => false,
+    };
+    if !sent {
+        mark_down(down);
+    }
+}
+
+/// Instructions for the tracker thread besides keys to log.
+enum Control {
+    /// Apply new eviction settings.
+    Reconfigure(EvictionSettings),
+    /// Evict a fraction of the cache now.
+    Shed(f64),
+}
+
+/// Sender for `Control`s, to the same thread as `SENDER_MUTEX`.
+static mut CONTROL_MUTEX: Option<sync::Mutex<mpsc::Sender<Control>>> = None;
+
+/// Send the running usage tracker a `Control`.  Returns false if no usage
+/// tracker is running.
+fn send_control(control: Control) -> bool {
+    unsafe {
+        match &CONTROL_MUTEX {
+            None => false,
+            Some(mutex) => mutex.lock().unwrap().send(control).is_ok(),
+        }
+    }
+}
+
+/// Change the running usage tracker's eviction settings.  They take effect
+/// within a second, even if no cuboids are accessed meanwhile.  Returns
+/// false if no usage tracker is running.
+///
+/// # Arguments:
+///
+/// * `settings` - The new eviction tunables
+pub fn reconfigure(settings: EvictionSettings) -> bool {
+    send_control(Control::Reconfigure(settings))
+}
+
+/// Have the running usage tracker evict a fraction of the cache, least
+/// recently used first, whatever its limit, e.g. to free disk space.
+/// Returns false if no usage tracker is running.
+///
+/// # Arguments:
+///
+/// * `fraction` - Fraction of the cached cuboids to evict, from 0 to 1
+pub fn shed(fraction: f64) -> bool {
+    send_control(Control::Shed(fraction))
+}
+
 /// Start the usage tracker.  This should only be called ONCE.
 ///
 /// # Arguments:
 ///
 /// * `kind` - Which usage tracker to start
-/// * `cuboid_root_path` - Root of cached cuboids
-pub fn run(kind: UsageTrackerType) {
+/// * `settings` - Cache management tunables
+pub fn run(kind: UsageTrackerType, settings: UsageTrackerConfig) {
     if let UsageTrackerType::None = kind {
         return;
     }
 
     let (tx, rx) = mpsc::channel::<String>();
+    let (control_tx, control_rx) = mpsc::channel::<Control>();
     unsafe {
         if SENDER_MUTEX.is_some() {
             panic!("run() may only be called once");
         }
         SENDER_MUTEX = Option::Some(sync::Mutex::new(tx));
+        CONTROL_MUTEX = Option::Some(sync::Mutex::new(control_tx));
     }
 
     thread::spawn(move || {
-        let mut usage_mgr = usage_tracker_factory(kind);
-        for key in rx {
-            usage_mgr.log_request(key);
-        }
+        let size_report = settings
+            .size_report
+            .map(|interval| SizeReport::new(interval, Instant::now()));
+        supervise(
+            || usage_tracker_factory(&kind, &settings),
+            settings.max_restarts,
+            &rx,
+            &control_rx,
+            size_report,
+            &TRACKER_DOWN,
+        );
+        // Only reached once every sender is gone, i.e. on shutdown.
     });
 }
 
+/// Run a tracker built by `build`, building a new one each time it panics,
+/// e.g. on an unexpected DB error, up to `max_restarts` times.  After that,
+/// the tracker is marked down and keys are drained without being tracked,
+/// so that senders never see an error.  Returns once every sender is gone.
+///
+/// # Arguments:
+///
+/// * `build` - Builds the tracker, initially and on each restart
+/// * `max_restarts` - Times the tracker may be rebuilt
+/// * `rx` - Keys of the cuboids used
+/// * `control_rx` - Other instructions for the tracker
+/// * `size_report` - Logs the size of the cache, if given
+/// * `down` - Set once the tracker is given up on
+fn supervise<F>(
+    build: F,
+    max_restarts: u32,
+    rx: &mpsc::Receiver<String>,
+    control_rx: &mpsc::Receiver<Control>,
+    mut size_report: Option<SizeReport>,
+    down: &AtomicBool,
+) where
+    F: Fn() -> Box<dyn UsageTracker>,
+{
+    // Settings changed at runtime outlive a restart.
+    let mut eviction: Option<EvictionSettings> = None;
+    let mut restarts = 0;
+    loop {
+        let served = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut usage_mgr = build();
+            if let Some(settings) = &eviction {
+                usage_mgr.reconfigure(settings);
+            }
+            serve(
+                usage_mgr.as_mut(),
+                rx,
+                control_rx,
+                &mut size_report,
+                &mut eviction,
+            );
+        }));
+        if served.is_ok() {
+            return;
+        }
+        if restarts < max_restarts {
+            restarts += 1;
+            println!(
+                "ERROR: the usage tracker failed; restarting it ({} of {})",
+                restarts, max_restarts
+            );
+        } else {
+            mark_down(down);
+            serve(
+                &mut NoneTracker {},
+                rx,
+                control_rx,
+                &mut None,
+                &mut eviction,
+            );
+            return;
+        }
+    }
+}
+
+/// Feed keys and `Control`s to a tracker until every sender is gone.
+fn serve(
+    usage_mgr: &mut dyn UsageTracker,
+    rx: &mpsc::Receiver<String>,
+    control_rx: &mpsc::Receiver<Control>,
+    size_report: &mut Option<SizeReport>,
+    eviction: &mut Option<EvictionSettings>,
+) {
+    loop {
+        for control in control_rx.try_iter() {
+            match control {
+                Control::Reconfigure(settings) => {
+                    usage_mgr.reconfigure(&settings);
+                    *eviction = Some(settings);
+                }
+                Control::Shed(fraction) => {
+                    let evicted = usage_mgr.shed(fraction);
+                    println!("Shed {} cuboids from the cache", evicted);
+                }
+            }
+        }
+        match rx.recv_timeout(CONTROL_POLL) {
+            Ok(key) => usage_mgr.log_request(key),
+            Err(mpsc::RecvTimeoutError::Timeout) => usage_mgr.idle(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        if let Some(report) = size_report.as_mut() {
+            report.poll(Instant::now(), usage_mgr);
+        }
+    }
+}
+
+/// Logs the size of the cache at an interval, giving a time series of its
+/// growth without anything scraping the server.  Polled by the tracker
+/// thread, so the interval is only as precise as `CONTROL_POLL`.
+pub struct SizeReport {
+    interval: Duration,
+    /// When the next report is due.
+    due: Instant,
+}
+
+impl SizeReport {
+    /// Reports every `interval`, starting one interval after `now`.
+    pub fn new(interval: Duration, now: Instant) -> SizeReport {
+        SizeReport {
+            interval,
+            due: now + interval,
+        }
+    }
+
+    /// Log the size of the tracker's cache if a report is due, returning
+    /// what was logged.  Trackers that don't know the size report nothing.
+    ///
+    /// # Arguments:
+    ///
+    /// * `now` - The current time
+    /// * `tracker` - The running usage tracker
+    pub fn poll(&mut self, now: Instant, tracker: &mut dyn UsageTracker) -> Option<CacheStats> {
+        if now < self.due {
+            return None;
+        }
+        self.due = now + self.interval;
+        let stats = tracker.cache_stats()?;
+        println!(
+            "Cache size: {} cuboids, {} bytes ({} empty cuboids)",
+            stats.cuboids, stats.bytes, stats.empty
+        );
+        Some(stats)
+    }
+}
+
 pub trait UsageTracker {
     /// Log request to console, file, or DB.
     fn log_request(&mut self, key: String);
+
+    /// Called when no key has come in for `CONTROL_POLL`, to do work put off
+    /// until later even if none come in.
+    fn idle(&mut self) {}
+
+    /// Apply new eviction settings.  Trackers that don't evict ignore them.
+    fn reconfigure(&mut self, _settings: &EvictionSettings) {}
+
+    /// Evict a fraction of the cache now, returning how many cuboids were
+    /// evicted.  Trackers that don't evict evict nothing.
+    fn shed(&mut self, _fraction: f64) -> u32 {
+        0
+    }
+
+    /// The current size of the cache, if the tracker keeps track of it.
+    fn cache_stats(&mut self) -> Option<CacheStats> {
+        None
+    }
 }
 
 /// Empty tracker.
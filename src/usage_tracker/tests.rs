@@ -0,0 +1,76 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::usage_tracker::{send_key, supervise, Control, UsageTracker};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Tracker that fails on the key `boom`.
+struct FragileTracker;
+
+impl UsageTracker for FragileTracker {
+    fn log_request(&mut self, key: String) {
+        if key == "boom" {
+            panic!("tracker failed");
+        }
+    }
+}
+
+#[test]
+fn test_failed_send_marks_tracker_down() {
+    let (tx, rx) = mpsc::channel::<String>();
+    drop(rx);
+    let sender = Mutex::new(tx);
+    let down = AtomicBool::new(false);
+    send_key(&sender, "col/exp/chan/0/0_0_0".to_string(), &down);
+    assert!(down.load(Ordering::SeqCst));
+
+    // Later sends are dropped quietly.
+    send_key(&sender, "col/exp/chan/0/1_0_0".to_string(), &down);
+    assert!(down.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_supervise_restarts_then_gives_up() {
+    let (tx, rx) = mpsc::channel::<String>();
+    let (_control_tx, control_rx) = mpsc::channel::<Control>();
+    let builds = Arc::new(AtomicUsize::new(0));
+    let down = Arc::new(AtomicBool::new(false));
+    let supervisor = {
+        let builds = Arc::clone(&builds);
+        let down = Arc::clone(&down);
+        thread::spawn(move || {
+            let build = || -> Box<dyn UsageTracker> {
+                builds.fetch_add(1, Ordering::SeqCst);
+                Box::new(FragileTracker)
+            };
+            supervise(build, 1, &rx, &control_rx, None, &down);
+        })
+    };
+
+    for key in &["a", "boom", "b", "boom", "c"] {
+        // Keys keep being accepted, even once the tracker is given up on.
+        tx.send(key.to_string()).unwrap();
+    }
+    drop(tx);
+    supervisor.join().unwrap();
+
+    assert_eq!(2, builds.load(Ordering::SeqCst));
+    assert!(down.load(Ordering::SeqCst));
+}
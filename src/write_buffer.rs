@@ -0,0 +1,347 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+/// Write buffer module.
+///
+/// Ingest pipelines often upload many small patches of the same cuboid, each
+/// of which would otherwise read, merge and rewrite the whole cuboid file.
+/// A `WriteBuffer` holds partly written cuboids in memory, merging each
+/// patch into its cuboid, and writes each cuboid out once: when it's been
+/// buffered for the window, when the buffer is full, or just before it's
+/// read.  Uploads are acknowledged once buffered, so an acknowledged upload
+/// is lost if the server dies before its cuboids are written out; a server
+/// stopped by SIGINT or SIGTERM closes the buffer, writing them out first,
+/// and writes uploads straight to disk until it exits.  Buffered cuboids
+/// that are removed from the cache (purged, evicted or found corrupt) are
+/// dropped along with their files.
+use crate::data_manager::{CuboidWriter, Vector3};
+use ndarray::{Array3, ArrayView3};
+use std::collections::{HashMap, HashSet};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+pub mod tests;
+
+/// A cuboid waiting to be written out.
+struct Pending {
+    /// The whole cuboid, with every patch so far merged in.
+    voxels: Array3<u8>,
+    writer: CuboidWriter,
+    /// When the cuboid was buffered.
+    since: Instant,
+}
+
+/// What's buffered, behind the buffer's lock.
+#[derive(Default)]
+struct Buffered {
+    /// Buffered cuboids, by filename.
+    pending: HashMap<String, Pending>,
+    /// Cuboids being read in or written out, which are left alone until
+    /// they are, so that nothing reads or buffers a stale copy meanwhile.
+    busy: HashSet<String>,
+    /// Set once the buffer is closed, after which nothing more is buffered.
+    closed: bool,
+}
+
+/// Partly written cuboids, shared by every request's data manager.
+///
+/// Cuboids are read in and written out without holding the lock, so that a
+/// slow disk holds up only the requests for the cuboids involved.
+pub struct WriteBuffer {
+    window: Duration,
+    max_cuboids: usize,
+    buffered: Mutex<Buffered>,
+    /// Signalled whenever cuboids stop being busy.
+    idle: Condvar,
+}
+
+impl WriteBuffer {
+    /// Constructor.
+    ///
+    /// # Arguments:
+    ///
+    /// * `window` - How long a cuboid is buffered before it's written out
+    /// * `max_cuboids` - Max number of cuboids buffered at once; the oldest
+    ///   are written out early to make room
+    pub fn new(window: Duration, max_cuboids: usize) -> WriteBuffer {
+        WriteBuffer {
+            window,
+            max_cuboids: max_cuboids.max(1),
+            buffered: Mutex::new(Buffered::default()),
+            idle: Condvar::new(),
+        }
+    }
+
+    /// How long a cuboid is buffered before it's written out.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Number of cuboids buffered.
+    pub fn len(&self) -> usize {
+        self.buffered.lock().unwrap().pending.len()
+    }
+
+    /// Is nothing buffered?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Merge a patch into a cuboid, buffering the cuboid first if it isn't
+    /// yet.  Its existing voxels, which the patches are merged over, are
+    /// only read then, so many patches cost one read and one write.
+    ///
+    /// # Arguments:
+    ///
+    /// * `filename` - The cuboid's file
+    /// * `writer` - Writes the cuboid out
+    /// * `start` - Where the patch starts in the cuboid
+    /// * `stop` - Where the patch stops (exclusive)
+    /// * `patch` - The voxels, in ZYX order
+    /// * `existing` - Reads the cuboid as it is now, if it isn't buffered
+    ///
+    /// Returns whether the patch was buffered.  Once the buffer is closed
+    /// it isn't, and the caller writes the cuboid itself.
+    pub fn write<F, E>(
+        &self,
+        filename: &str,
+        writer: &CuboidWriter,
+        start: Vector3,
+        stop: Vector3,
+        patch: ArrayView3<u8>,
+        existing: F,
+    ) -> Result<bool, E>
+    where
+        F: FnOnce() -> Result<Array3<u8>, E>,
+    {
+        let slice = Vector3::zyx_slice(start, stop);
+        let mut buffered = self.wait_until_idle(filename);
+        if buffered.closed {
+            return Ok(false);
+        }
+        if let Some(cuboid) = buffered.pending.get_mut(filename) {
+            cuboid.voxels.slice_mut(&slice).assign(&patch);
+            return Ok(true);
+        }
+
+        // Make room, and read the cuboid in, without holding the lock:
+        let mut full = Vec::new();
+        while buffered.pending.len() >= self.max_cuboids {
+            let oldest = buffered
+                .pending
+                .iter()
+                .min_by_key(|(_, cuboid)| cuboid.since)
+                .map(|(filename, _)| filename.clone())
+                .unwrap();
+            full.extend(take(&mut buffered, &oldest));
+        }
+        buffered.busy.insert(filename.to_string());
+        drop(buffered);
+        self.write_out(full);
+        let voxels = existing();
+
+        let mut buffered = self.buffered.lock().unwrap();
+        buffered.busy.remove(filename);
+        self.idle.notify_all();
+        let mut voxels = voxels?;
+        voxels.slice_mut(&slice).assign(&patch);
+        buffered.pending.insert(
+            filename.to_string(),
+            Pending {
+                voxels,
+                writer: writer.clone(),
+                since: Instant::now(),
+            },
+        );
+        Ok(true)
+    }
+
+    /// Write a cuboid out now if it's buffered, e.g. so that it can be
+    /// read.  Returns whether it was.  Returns once it's written, or once
+    /// any write of it under way is done.
+    ///
+    /// # Arguments:
+    ///
+    /// * `filename` - The cuboid's file
+    pub fn flush(&self, filename: &str) -> bool {
+        let mut buffered = self.wait_until_idle(filename);
+        let taken = take(&mut buffered, filename);
+        drop(buffered);
+        let flushed = taken.is_some();
+        self.write_out(taken);
+        flushed
+    }
+
+    /// Drop a buffered cuboid without writing it, e.g. because it's being
+    /// overwritten whole or removed from the cache.
+    ///
+    /// # Arguments:
+    ///
+    /// * `filename` - The cuboid's file
+    pub fn discard(&self, filename: &str) {
+        self.wait_until_idle(filename).pending.remove(filename);
+    }
+
+    /// Write out every cuboid buffered for at least the window.  Returns
+    /// how many were.
+    ///
+    /// # Arguments:
+    ///
+    /// * `now` - The current time
+    pub fn flush_expired(&self, now: Instant) -> usize {
+        let mut buffered = self.buffered.lock().unwrap();
+        let expired: Vec<String> = buffered
+            .pending
+            .iter()
+            .filter(|(_, cuboid)| now.saturating_duration_since(cuboid.since) >= self.window)
+            .map(|(filename, _)| filename.clone())
+            .collect();
+        let taken: Vec<_> = expired
+            .iter()
+            .filter_map(|filename| take(&mut buffered, filename))
+            .collect();
+        drop(buffered);
+        let count = taken.len();
+        self.write_out(taken);
+        count
+    }
+
+    /// Write out every buffered cuboid.  Returns how many there were.
+    pub fn flush_all(&self) -> usize {
+        let mut buffered = self.buffered.lock().unwrap();
+        let all: Vec<String> = buffered.pending.keys().cloned().collect();
+        let taken: Vec<_> = all
+            .iter()
+            .filter_map(|filename| take(&mut buffered, filename))
+            .collect();
+        drop(buffered);
+        let count = taken.len();
+        self.write_out(taken);
+        count
+    }
+
+    /// Stop buffering, then write out every buffered cuboid, including
+    /// those being read in when it was closed.  Later writes are left to
+    /// their callers.  Returns how many cuboids were written out.
+    pub fn close(&self) -> usize {
+        let mut buffered = self.buffered.lock().unwrap();
+        buffered.closed = true;
+        while !buffered.busy.is_empty() {
+            buffered = self.idle.wait(buffered).unwrap();
+        }
+        let all: Vec<String> = buffered.pending.keys().cloned().collect();
+        let taken: Vec<_> = all
+            .iter()
+            .filter_map(|filename| take(&mut buffered, filename))
+            .collect();
+        drop(buffered);
+        let count = taken.len();
+        self.write_out(taken);
+        count
+    }
+
+    /// Lock the buffer once a cuboid isn't being read in or written out.
+    fn wait_until_idle(&self, filename: &str) -> MutexGuard<'_, Buffered> {
+        let mut buffered = self.buffered.lock().unwrap();
+        while buffered.busy.contains(filename) {
+            buffered = self.idle.wait(buffered).unwrap();
+        }
+        buffered
+    }
+
+    /// Write out cuboids taken from the buffer, then mark them idle.
+    fn write_out(&self, taken: impl IntoIterator<Item = (String, Pending)>) {
+        let written: Vec<String> = taken
+            .into_iter()
+            .map(|(filename, cuboid)| {
+                write_out(&filename, cuboid);
+                filename
+            })
+            .collect();
+        if written.is_empty() {
+            return;
+        }
+        let mut buffered = self.buffered.lock().unwrap();
+        for filename in &written {
+            buffered.busy.remove(filename);
+        }
+        self.idle.notify_all();
+    }
+}
+
+/// Take a cuboid out of the buffer to be written out, marking it busy
+/// until it is.
+fn take(buffered: &mut Buffered, filename: &str) -> Option<(String, Pending)> {
+    let cuboid = buffered.pending.remove(filename)?;
+    buffered.busy.insert(filename.to_string());
+    Some((filename.to_string(), cuboid))
+}
+
+/// Write out a cuboid taken from the buffer.  Failures can't be reported to
+/// the uploads that made the cuboid, which have been answered, so they're
+/// logged.
+fn write_out(filename: &str, cuboid: Pending) {
+    if let Err(err) = cuboid.writer.write(filename, &cuboid.voxels.into_raw_vec()) {
+        println!("Failed to write buffered cuboid {}: {}", filename, err);
+    }
+}
+
+/// How often the flushing thread checks whether the server is stopping.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set once the server is asked to stop.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_stop(_signal: libc::c_int) {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+/// Write out buffered cuboids as their windows pass, checking twice per
+/// window.  Takes over SIGINT and SIGTERM, so that the buffer is closed,
+/// and everything in it written out, before the server exits.
+///
+/// # Arguments:
+///
+/// * `buffer` - The shared write buffer
+pub fn run(buffer: Arc<WriteBuffer>) {
+    let interval = (buffer.window() / 2).max(Duration::from_millis(10));
+    // Safety: the handler only sets an atomic flag.
+    unsafe {
+        let handler = on_stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+    thread::spawn(move || {
+        let mut last_flush = Instant::now();
+        loop {
+            thread::sleep(interval.min(STOP_CHECK_INTERVAL));
+            if STOPPING.load(Ordering::SeqCst) {
+                let count = buffer.close();
+                println!("Wrote out {} buffered cuboids before stopping", count);
+                process::exit(0);
+            }
+            if last_flush.elapsed() >= interval {
+                buffer.flush_expired(Instant::now());
+                last_flush = Instant::now();
+            }
+        }
+    });
+}
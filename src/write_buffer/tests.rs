@@ -0,0 +1,186 @@
+/*
+
+Copyright 2020 The Johns Hopkins University Applied Physics Laboratory
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+
+*/
+
+use crate::data_manager::{ChunkedFileDataManager, Vector3};
+use crate::db::pool::ConnectionPool;
+use crate::db::SqliteCacheInterface;
+use crate::write_buffer::WriteBuffer;
+use ndarray::Array;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_write_buffer_is_bounded() {
+    let dir = tempfile::tempdir().unwrap();
+    let size = Vector3 { x: 4, y: 4, z: 2 };
+    let mut fm = ChunkedFileDataManager::new(dir.path().to_str().unwrap().to_string(), size, false);
+    let (tx, rx) = mpsc::channel();
+    fm.set_usage_sender(Arc::new(Mutex::new(tx)));
+    let window = Duration::from_secs(60);
+    let buffer = Arc::new(WriteBuffer::new(window, 2));
+    fm.set_write_buffer(Arc::clone(&buffer));
+
+    // A voxel in each of three cuboids; the oldest is written out to make
+    // room for the third:
+    for x in &[3, 7, 11] {
+        let voxel = Array::from_elem((1, 1, 1), 1);
+        assert!(fm.upload(
+            "bossdb://col/exp/chan".to_string(),
+            0,
+            Vector3 { x: *x, y: 3, z: 1 },
+            voxel
+        ));
+    }
+    assert_eq!(2, buffer.len());
+    assert_eq!(1, rx.try_iter().count());
+
+    // The rest are written out once their window passes:
+    assert_eq!(0, buffer.flush_expired(Instant::now()));
+    assert_eq!(2, buffer.flush_expired(Instant::now() + window));
+    assert!(buffer.is_empty());
+    assert_eq!(2, rx.try_iter().count());
+
+    // Whole cuboids aren't buffered:
+    let cuboid = Array::from_elem((2, 4, 4), 1);
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    assert!(fm.upload("bossdb://col/exp/chan".to_string(), 0, origin, cuboid));
+    assert!(buffer.is_empty());
+    assert_eq!(1, rx.try_iter().count());
+}
+
+#[test]
+fn test_removed_cuboids_are_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("cache").to_str().unwrap().to_string();
+    let size = Vector3 { x: 4, y: 4, z: 2 };
+    let mut fm = ChunkedFileDataManager::new(root.clone(), size, false);
+    let (tx, rx) = mpsc::channel();
+    fm.set_usage_sender(Arc::new(Mutex::new(tx)));
+    let buffer = Arc::new(WriteBuffer::new(Duration::from_secs(3600), 8));
+    fm.set_write_buffer(Arc::clone(&buffer));
+
+    // A cuboid on disk, with a patch of it buffered:
+    let uri = "bossdb://col/exp/chan".to_string();
+    let origin = Vector3 { x: 0, y: 0, z: 0 };
+    assert!(fm.upload(uri.clone(), 0, origin, Array::from_elem((2, 4, 4), 1)));
+    let filename = rx.try_recv().unwrap();
+    let corner = Vector3 { x: 3, y: 3, z: 1 };
+    assert!(fm.upload(uri, 0, corner, Array::from_elem((1, 1, 1), 2)));
+    assert_eq!(1, buffer.len());
+
+    // Evicting it drops the patch, so the file isn't written back:
+    let pool = ConnectionPool::new(dir.path().join("cache.db").to_str().unwrap(), 1).unwrap();
+    let mut db = SqliteCacheInterface::with_pool(Arc::new(pool));
+    db.add_cache_root(&root);
+    db.set_write_buffer(Arc::clone(&buffer));
+    let now = chrono::Utc::now().naive_utc();
+    assert_eq!(Ok(true), db.import_cuboid(&filename, 1, now, now));
+    assert_eq!(1, db.evict_to(0));
+    assert!(buffer.is_empty());
+    assert_eq!(0, buffer.flush_all());
+    assert!(!Path::new(&filename).exists());
+}
+
+#[test]
+fn test_cuboids_are_read_in_without_holding_the_buffer() {
+    let dir = tempfile::tempdir().unwrap();
+    let size = Vector3 { x: 4, y: 4, z: 2 };
+    let mut fm = ChunkedFileDataManager::new(dir.path().to_str().unwrap().to_string(), size, false);
+    let buffer = Arc::new(WriteBuffer::new(Duration::from_secs(3600), 8));
+    fm.set_write_buffer(Arc::clone(&buffer));
+    let writer = fm.cuboid_writer(size);
+    let voxel = Array::from_elem((1, 1, 1), 1);
+    let (start, stop) = (Vector3 { x: 0, y: 0, z: 0 }, Vector3 { x: 1, y: 1, z: 1 });
+
+    // While one cuboid is slow to read in, another can be buffered:
+    let reading = Arc::new(Barrier::new(2));
+    let slow = {
+        let (buffer, writer, reading) = (Arc::clone(&buffer), writer.clone(), Arc::clone(&reading));
+        let voxel = voxel.clone();
+        thread::spawn(move || {
+            buffer.write("slow", &writer, start, stop, voxel.view(), || {
+                reading.wait();
+                reading.wait();
+                Ok::<_, ()>(Array::zeros((2, 4, 4)))
+            })
+        })
+    };
+    reading.wait();
+    let fast = buffer.write("fast", &writer, start, stop, voxel.view(), || {
+        Ok::<_, ()>(Array::zeros((2, 4, 4)))
+    });
+    assert_eq!(Ok(true), fast);
+    assert_eq!(1, buffer.len());
+    reading.wait();
+    assert_eq!(Ok(true), slow.join().unwrap());
+    assert_eq!(2, buffer.len());
+}
+
+#[test]
+fn test_closing_writes_out_everything_buffered() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().to_str().unwrap().to_string();
+    let size = Vector3 { x: 4, y: 4, z: 2 };
+    let mut fm = ChunkedFileDataManager::new(root.clone(), size, false);
+    let (tx, rx) = mpsc::channel();
+    fm.set_usage_sender(Arc::new(Mutex::new(tx)));
+    let buffer = Arc::new(WriteBuffer::new(Duration::from_secs(3600), 8));
+    fm.set_write_buffer(Arc::clone(&buffer));
+    let writer = fm.cuboid_writer(size);
+    let uri = "bossdb://col/exp/chan".to_string();
+    let corner = Vector3 { x: 3, y: 3, z: 1 };
+    assert!(fm.upload(uri.clone(), 0, corner, Array::from_elem((1, 1, 1), 1)));
+    assert_eq!(1, buffer.len());
+
+    // Closing waits for a cuboid being read in, and writes it out too:
+    let slow = format!("{}/slow", root);
+    let reading = Arc::new(Barrier::new(2));
+    let reader = {
+        let (buffer, slow, reading) = (Arc::clone(&buffer), slow.clone(), Arc::clone(&reading));
+        let voxel = Array::from_elem((1, 1, 1), 1);
+        let (start, stop) = (Vector3 { x: 0, y: 0, z: 0 }, Vector3 { x: 1, y: 1, z: 1 });
+        thread::spawn(move || {
+            buffer.write(&slow, &writer, start, stop, voxel.view(), || {
+                reading.wait();
+                reading.wait();
+                Ok::<_, ()>(Array::zeros((2, 4, 4)))
+            })
+        })
+    };
+    reading.wait();
+    let closer = {
+        let buffer = Arc::clone(&buffer);
+        thread::spawn(move || buffer.close())
+    };
+    reading.wait();
+    assert_eq!(Ok(true), reader.join().unwrap());
+    assert_eq!(2, closer.join().unwrap());
+    assert!(buffer.is_empty());
+    let written: Vec<String> = rx.try_iter().collect();
+    assert_eq!(2, written.len());
+    assert!(written.contains(&slow));
+    assert!(written.iter().all(|filename| Path::new(filename).exists()));
+
+    // Later uploads go straight to disk:
+    let next = Vector3 { x: 7, y: 3, z: 1 };
+    assert!(fm.upload(uri, 0, next, Array::from_elem((1, 1, 1), 1)));
+    assert!(buffer.is_empty());
+    assert!(Path::new(&rx.try_recv().unwrap()).exists());
+}